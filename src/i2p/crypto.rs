@@ -1,3 +0,0 @@
-pub fn init_gost() {
-    // TODO: Implement
-}
\ No newline at end of file
@@ -0,0 +1,113 @@
+//! AES-256 in CBC mode, as used for tunnel build replies and by
+//! ElGamal/AES+SessionTags, and in ECB mode, for tunnel message IVs.
+//! There's no padding: the data has to be a whole number of blocks.
+
+use gcrypt::cipher::{Algorithm, Cipher, Mode};
+use i2p::error::Error;
+
+pub const KEY_LENGTH: usize = 32;
+
+pub const BLOCK_LENGTH: usize = 16;
+
+fn check(key: &[u8], data: &[u8]) -> Result<(), Error> {
+    if key.len() != KEY_LENGTH {
+        return Err(Error::Crypto(format!("AES-256 needs a {}-byte key", KEY_LENGTH)));
+    }
+    if !data.len().is_multiple_of(BLOCK_LENGTH) {
+        return Err(Error::Crypto(format!("AES data must be a multiple of {} bytes, not {}",
+                                         BLOCK_LENGTH,
+                                         data.len())));
+    }
+
+    Ok(())
+}
+
+fn cipher(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Cipher, Error> {
+    check(key, data)?;
+    if iv.len() != BLOCK_LENGTH {
+        return Err(Error::Crypto(format!("AES-256 CBC needs a {}-byte IV", BLOCK_LENGTH)));
+    }
+    let mut cipher = Cipher::new(Algorithm::Aes256, Mode::Cbc)?;
+    cipher.set_key(key)?;
+    cipher.set_iv(iv)?;
+
+    Ok(cipher)
+}
+
+fn ecb_cipher(key: &[u8], data: &[u8]) -> Result<Cipher, Error> {
+    check(key, data)?;
+    let mut cipher = Cipher::new(Algorithm::Aes256, Mode::Ecb)?;
+    cipher.set_key(key)?;
+
+    Ok(cipher)
+}
+
+pub fn cbc_encrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = vec![0u8; data.len()];
+    cipher(key, iv, data)?.encrypt(data, &mut output)?;
+
+    Ok(output)
+}
+
+pub fn cbc_decrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = vec![0u8; data.len()];
+    cipher(key, iv, data)?.decrypt(data, &mut output)?;
+
+    Ok(output)
+}
+
+pub fn ecb_encrypt(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = vec![0u8; data.len()];
+    ecb_cipher(key, data)?.encrypt(data, &mut output)?;
+
+    Ok(output)
+}
+
+pub fn ecb_decrypt(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = vec![0u8; data.len()];
+    ecb_cipher(key, data)?.decrypt(data, &mut output)?;
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // NIST SP 800-38A, F.2.5
+    const KEY: [u8; 32] = [0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0,
+                           0x85, 0x7d, 0x77, 0x81, 0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7,
+                           0x2d, 0x98, 0x10, 0xa3, 0x09, 0x14, 0xdf, 0xf4];
+    const PLAINTEXT: [u8; 32] = [0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e,
+                                 0x11, 0x73, 0x93, 0x17, 0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03,
+                                 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf, 0x8e, 0x51];
+    const CIPHERTEXT: [u8; 32] = [0xf5, 0x8c, 0x4c, 0x04, 0xd6, 0xe5, 0xf1, 0xba, 0x77, 0x9e,
+                                  0xab, 0xfb, 0x5f, 0x7b, 0xfb, 0xd6, 0x9c, 0xfc, 0x4e, 0x96,
+                                  0x7e, 0xdb, 0x80, 0x8d, 0x67, 0x9f, 0x77, 0x7b, 0xc6, 0x70,
+                                  0x2c, 0x7d];
+
+    #[test]
+    fn test_cbc() {
+        let iv: Vec<u8> = (0..16).collect();
+        assert_eq!(CIPHERTEXT.to_vec(), cbc_encrypt(&KEY, &iv, &PLAINTEXT).unwrap());
+        assert_eq!(PLAINTEXT.to_vec(), cbc_decrypt(&KEY, &iv, &CIPHERTEXT).unwrap());
+    }
+
+    // NIST SP 800-38A, F.1.5
+    const ECB_CIPHERTEXT: [u8; 32] = [0xf3, 0xee, 0xd1, 0xbd, 0xb5, 0xd2, 0xa0, 0x3c, 0x06, 0x4b,
+                                      0x5a, 0x7e, 0x3d, 0xb1, 0x81, 0xf8, 0x59, 0x1c, 0xcb, 0x10,
+                                      0xd4, 0x10, 0xed, 0x26, 0xdc, 0x5b, 0xa7, 0x4a, 0x31, 0x36,
+                                      0x28, 0x70];
+
+    #[test]
+    fn test_ecb() {
+        assert_eq!(ECB_CIPHERTEXT.to_vec(), ecb_encrypt(&KEY, &PLAINTEXT).unwrap());
+        assert_eq!(PLAINTEXT.to_vec(), ecb_decrypt(&KEY, &ECB_CIPHERTEXT).unwrap());
+        assert!(ecb_encrypt(&KEY[..16], &PLAINTEXT).is_err());
+    }
+
+    #[test]
+    fn test_cbc_partial_block() {
+        assert!(cbc_encrypt(&KEY, &[0u8; 16], &PLAINTEXT[..20]).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! The ChaCha20 stream cipher from RFC 7539, with a 96-bit nonce and a
+//! 32-bit block counter, and the ChaCha20/Poly1305 AEAD built on it
+
+use gcrypt::cipher::{Algorithm, Cipher, Mode};
+use i2p::crypto::poly1305::{self, poly1305, TAG_LENGTH};
+use i2p::error::Error;
+
+pub const KEY_LENGTH: usize = 32;
+
+pub const NONCE_LENGTH: usize = 12;
+
+/// libgcrypt takes the block counter and the nonce together, as the last
+/// four words of the ChaCha20 state
+const COUNTER_LENGTH: usize = 4;
+
+/// Encrypts or decrypts `data`, starting from block `counter`
+pub fn chacha20(key: &[u8], nonce: &[u8], counter: u32, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if key.len() != KEY_LENGTH || nonce.len() != NONCE_LENGTH {
+        return Err(Error::Crypto(format!("ChaCha20 needs a {}-byte key and a {}-byte nonce",
+                                         KEY_LENGTH,
+                                         NONCE_LENGTH)));
+    }
+
+    let mut iv = Vec::with_capacity(COUNTER_LENGTH + NONCE_LENGTH);
+    for i in 0..COUNTER_LENGTH {
+        iv.push((counter >> (8 * i)) as u8);
+    }
+    iv.extend_from_slice(nonce);
+    let mut cipher = Cipher::new(Algorithm::Chacha20, Mode::Stream)?;
+    cipher.set_key(key)?;
+    cipher.set_iv(&iv)?;
+    let mut result = vec![0u8; data.len()];
+    cipher.encrypt(data, &mut result)?;
+
+    Ok(result)
+}
+
+/// The nonce I2P's protocols use: four zero bytes, then a little-endian
+/// counter
+pub fn nonce(counter: u64) -> Vec<u8> {
+    let mut nonce = vec![0u8; NONCE_LENGTH];
+    for i in 0..8 {
+        nonce[4 + i] = (counter >> (8 * i)) as u8;
+    }
+
+    nonce
+}
+
+fn aead_tag(key: &[u8], nonce: &[u8], ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let one_time_key = chacha20(key, nonce, 0, &[0u8; poly1305::KEY_LENGTH])?;
+    let mut data: Vec<u8> = Vec::with_capacity(ad.len() + ciphertext.len() + 48);
+    data.extend_from_slice(ad);
+    data.resize(ad.len().div_ceil(16) * 16, 0);
+    data.extend_from_slice(ciphertext);
+    let padded = data.len() + (16 - ciphertext.len() % 16) % 16;
+    data.resize(padded, 0);
+    for length in &[ad.len() as u64, ciphertext.len() as u64] {
+        for i in 0..8 {
+            data.push((length >> (8 * i)) as u8);
+        }
+    }
+
+    poly1305(&one_time_key, &data)
+}
+
+/// Encrypts `plaintext` and authenticates it along with `ad`. The tag is
+/// appended to the ciphertext.
+pub fn aead_encrypt(key: &[u8],
+                    nonce: &[u8],
+                    ad: &[u8],
+                    plaintext: &[u8])
+                    -> Result<Vec<u8>, Error> {
+    let mut ciphertext = chacha20(key, nonce, 1, plaintext)?;
+    let tag = aead_tag(key, nonce, ad, &ciphertext)?;
+    ciphertext.extend(tag);
+
+    Ok(ciphertext)
+}
+
+/// Checks the tag at the end of `ciphertext` and decrypts the rest
+pub fn aead_decrypt(key: &[u8],
+                    nonce: &[u8],
+                    ad: &[u8],
+                    ciphertext: &[u8])
+                    -> Result<Vec<u8>, Error> {
+    if ciphertext.len() < TAG_LENGTH {
+        return Err(Error::Crypto("ChaCha20/Poly1305 ciphertext too short".to_string()));
+    }
+    let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - TAG_LENGTH);
+    if !poly1305::tags_equal(tag, &aead_tag(key, nonce, ad, ciphertext)?) {
+        return Err(Error::Crypto("ChaCha20/Poly1305 tag doesn't match".to_string()));
+    }
+
+    chacha20(key, nonce, 1, ciphertext)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chacha20() {
+        // RFC 7539, section 2.4.2
+        let key: Vec<u8> = (0..32).collect();
+        let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+                          tip for the future, sunscreen would be it.";
+        let expected =
+            [0x6e, 0x2e, 0x35, 0x9a, 0x25, 0x68, 0xf9, 0x80, 0x41, 0xba, 0x07, 0x28, 0xdd, 0x0d,
+             0x69, 0x81, 0xe9, 0x7e, 0x7a, 0xec, 0x1d, 0x43, 0x60, 0xc2, 0x0a, 0x27, 0xaf, 0xcc,
+             0xfd, 0x9f, 0xae, 0x0b, 0xf9, 0x1b, 0x65, 0xc5, 0x52, 0x47, 0x33, 0xab, 0x8f, 0x59,
+             0x3d, 0xab, 0xcd, 0x62, 0xb3, 0x57, 0x16, 0x39, 0xd6, 0x24, 0xe6, 0x51, 0x52, 0xab,
+             0x8f, 0x53, 0x0c, 0x35, 0x9f, 0x08, 0x61, 0xd8, 0x07, 0xca, 0x0d, 0xbf, 0x50, 0x0d,
+             0x6a, 0x61, 0x56, 0xa3, 0x8e, 0x08, 0x8a, 0x22, 0xb6, 0x5e, 0x52, 0xbc, 0x51, 0x4d,
+             0x16, 0xcc, 0xf8, 0x06, 0x81, 0x8c, 0xe9, 0x1a, 0xb7, 0x79, 0x37, 0x36, 0x5a, 0xf9,
+             0x0b, 0xbf, 0x74, 0xa3, 0x5b, 0xe6, 0xb4, 0x0b, 0x8e, 0xed, 0xf2, 0x78, 0x5e, 0x42,
+             0x87, 0x4d];
+
+        let ciphertext = chacha20(&key, &nonce, 1, plaintext).unwrap();
+        assert_eq!(expected.to_vec(), ciphertext);
+        assert_eq!(plaintext.to_vec(), chacha20(&key, &nonce, 1, &ciphertext).unwrap());
+    }
+
+    #[test]
+    fn test_chacha20_bad_nonce() {
+        assert!(chacha20(&[0u8; 32], &[0u8; 8], 0, b"data").is_err());
+    }
+
+    #[test]
+    fn test_aead() {
+        // RFC 7539, section 2.8.2
+        let key: Vec<u8> = (0x80..0xa0).collect();
+        let nonce = [0x07, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+        let ad = [0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7];
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+                          tip for the future, sunscreen would be it.";
+        let expected_tag = [0x1a, 0xe1, 0x0b, 0x59, 0x4f, 0x09, 0xe2, 0x6a, 0x7e, 0x90, 0x2e,
+                            0xcb, 0xd0, 0x60, 0x06, 0x91];
+
+        let ciphertext = aead_encrypt(&key, &nonce, &ad, plaintext).unwrap();
+        assert_eq!(plaintext.len() + TAG_LENGTH, ciphertext.len());
+        assert_eq!(&[0xd3, 0x1a, 0x8d, 0x34], &ciphertext[..4]);
+        assert_eq!(&expected_tag[..], &ciphertext[plaintext.len()..]);
+        assert_eq!(plaintext.to_vec(),
+                   aead_decrypt(&key, &nonce, &ad, &ciphertext).unwrap());
+
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 0x01;
+        assert!(aead_decrypt(&key, &nonce, &ad, &tampered).is_err());
+        assert!(aead_decrypt(&key, &nonce, b"", &ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_nonce() {
+        assert_eq!(vec![0, 0, 0, 0, 0x02, 0x01, 0, 0, 0, 0, 0, 0], nonce(0x0102));
+    }
+}
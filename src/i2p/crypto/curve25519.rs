@@ -0,0 +1,500 @@
+//! X25519 key agreement, and arithmetic on the twisted Edwards curve behind
+//! Ed25519 for key blinding. libgcrypt handles X25519 and Ed25519
+//! signatures, but doesn't let us get at the group operations.
+//!
+//! Field elements are sixteen 16-bit limbs held in i64s, as in TweetNaCl.
+
+use gcrypt::pkey;
+use i2p::crypto::sexp::SExpressionBuilder;
+use i2p::error::Error;
+
+pub const KEY_LENGTH: usize = 32;
+
+type FieldElement = [i64; 16];
+
+const ZERO: FieldElement = [0; 16];
+
+const ONE: FieldElement = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// The Edwards curve constant -121665/121666
+const D: FieldElement = [0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070, 0xe898,
+                         0x7779, 0x4079, 0x8cc7, 0xfe73, 0x2b6f, 0x6cee, 0x5203];
+
+const D2: FieldElement = [0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0, 0xd130,
+                          0xeef3, 0x80f2, 0x198e, 0xfce7, 0x56df, 0xd9dc, 0x2406];
+
+const BASE_X: FieldElement = [0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c,
+                              0xdc5c, 0xfdd6, 0xe231, 0xc0a4, 0x53fe, 0xcd6e, 0x36d3, 0x2169];
+
+const BASE_Y: FieldElement = [0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+                              0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666];
+
+/// The square root of -1
+const SQRT_M1: FieldElement = [0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43,
+                               0xd7a7, 0x3dfb, 0x0099, 0x2b4d, 0xdf0b, 0x4fc1, 0x2480, 0x2b83];
+
+/// The order of the base point, little-endian
+const ORDER: [i64; 32] = [0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2,
+                          0xde, 0xf9, 0xde, 0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                          0x10];
+
+fn carry(o: &mut FieldElement) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+/// Swaps p and q if b is 1, in constant time
+fn select(p: &mut FieldElement, q: &mut FieldElement, b: i64) {
+    let c = !(b - 1);
+    for i in 0..16 {
+        let t = c & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+fn pack(n: &FieldElement) -> [u8; 32] {
+    let mut t = *n;
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+    for _ in 0..2 {
+        let mut m = ZERO;
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        select(&mut t, &mut m, 1 - b);
+    }
+    let mut o = [0u8; 32];
+    for i in 0..16 {
+        o[2 * i] = (t[i] & 0xff) as u8;
+        o[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+
+    o
+}
+
+fn unpack(n: &[u8]) -> FieldElement {
+    let mut o = ZERO;
+    for i in 0..16 {
+        o[i] = n[2 * i] as i64 + ((n[2 * i + 1] as i64) << 8);
+    }
+    o[15] &= 0x7fff;
+
+    o
+}
+
+fn not_equal(a: &FieldElement, b: &FieldElement) -> bool {
+    pack(a) != pack(b)
+}
+
+fn parity(a: &FieldElement) -> u8 {
+    pack(a)[0] & 1
+}
+
+fn add(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut o = ZERO;
+    for i in 0..16 {
+        o[i] = a[i] + b[i];
+    }
+
+    o
+}
+
+fn sub(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut o = ZERO;
+    for i in 0..16 {
+        o[i] = a[i] - b[i];
+    }
+
+    o
+}
+
+fn mul(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+    let mut o = ZERO;
+    o.copy_from_slice(&t[..16]);
+    carry(&mut o);
+    carry(&mut o);
+
+    o
+}
+
+fn square(a: &FieldElement) -> FieldElement {
+    mul(a, a)
+}
+
+fn invert(i: &FieldElement) -> FieldElement {
+    let mut c = *i;
+    for a in (0..254).rev() {
+        c = square(&c);
+        if a != 2 && a != 4 {
+            c = mul(&c, i);
+        }
+    }
+
+    c
+}
+
+/// Raises to the power (p - 5) / 8, for square roots
+fn pow2523(i: &FieldElement) -> FieldElement {
+    let mut c = *i;
+    for a in (0..251).rev() {
+        c = square(&c);
+        if a != 1 {
+            c = mul(&c, i);
+        }
+    }
+
+    c
+}
+
+/// X25519 Diffie-Hellman, as in RFC 7748. libgcrypt does this as ECDH with
+/// its Curve25519 key type.
+pub fn x25519(scalar: &[u8], point: &[u8]) -> Result<Vec<u8>, Error> {
+    if scalar.len() != KEY_LENGTH || point.len() != KEY_LENGTH {
+        return Err(Error::Crypto(format!("X25519 keys must be {} bytes", KEY_LENGTH)));
+    }
+
+    // The top bit of the u-coordinate is ignored, and libgcrypt wants points
+    // with a 0x40 prefix
+    let mut q = vec![0x40];
+    q.extend_from_slice(point);
+    q[KEY_LENGTH] &= 127;
+    let key = SExpressionBuilder::new()
+        .open()
+        .atom(b"public-key")
+        .open()
+        .atom(b"ecc")
+        .pair("curve", b"Curve25519")
+        .open()
+        .atom(b"flags")
+        .atom(b"djb-tweak")
+        .close()
+        .pair("q", &q)
+        .close()
+        .close()
+        .build()?;
+    // The scalar is read as a big-endian MPI, then clamped
+    let mut k = scalar.to_vec();
+    k.reverse();
+    let data = SExpressionBuilder::new()
+        .open()
+        .atom(b"data")
+        .open()
+        .atom(b"flags")
+        .atom(b"raw")
+        .close()
+        .pair("value", &k)
+        .close()
+        .build()?;
+
+    let result = pkey::encrypt(&key, &data)?;
+    match result.find_token("s").as_ref().and_then(|s| s.get_bytes(1)) {
+        Some(s) if s.len() == KEY_LENGTH + 1 => Ok(s[1..].to_vec()),
+        _ => Err(Error::Crypto("Unexpected X25519 result from libgcrypt".to_string())),
+    }
+}
+
+/// The X25519 public key for a private key
+pub fn x25519_public_key(private_key: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut base = [0u8; 32];
+    base[0] = 9;
+    x25519(private_key, &base)
+}
+
+/// A point on the Ed25519 curve, in extended coordinates
+#[derive(Clone, Copy)]
+pub struct EdwardsPoint {
+    x: FieldElement,
+    y: FieldElement,
+    z: FieldElement,
+    t: FieldElement,
+}
+
+impl EdwardsPoint {
+    fn identity() -> EdwardsPoint {
+        EdwardsPoint {
+            x: ZERO,
+            y: ONE,
+            z: ONE,
+            t: ZERO,
+        }
+    }
+
+    pub fn base() -> EdwardsPoint {
+        EdwardsPoint {
+            x: BASE_X,
+            y: BASE_Y,
+            z: ONE,
+            t: mul(&BASE_X, &BASE_Y),
+        }
+    }
+
+    /// Decodes a 32-byte Ed25519 public key
+    pub fn decompress(data: &[u8]) -> Result<EdwardsPoint, Error> {
+        if data.len() != KEY_LENGTH {
+            return Err(Error::Crypto(format!("Ed25519 points must be {} bytes", KEY_LENGTH)));
+        }
+
+        let y = unpack(data);
+        let num = square(&y);
+        let den = mul(&num, &D);
+        let num = sub(&num, &ONE);
+        let den = add(&ONE, &den);
+
+        let den2 = square(&den);
+        let den4 = square(&den2);
+        let den6 = mul(&den4, &den2);
+        let mut t = mul(&den6, &num);
+        t = mul(&t, &den);
+        t = pow2523(&t);
+        t = mul(&t, &num);
+        t = mul(&t, &den);
+        t = mul(&t, &den);
+        let mut x = mul(&t, &den);
+
+        let mut check = mul(&square(&x), &den);
+        if not_equal(&check, &num) {
+            x = mul(&x, &SQRT_M1);
+        }
+        check = mul(&square(&x), &den);
+        if not_equal(&check, &num) {
+            return Err(Error::Crypto("Not a point on the Ed25519 curve".to_string()));
+        }
+        if parity(&x) != data[31] >> 7 {
+            x = sub(&ZERO, &x);
+        }
+
+        Ok(EdwardsPoint {
+            x,
+            y,
+            z: ONE,
+            t: mul(&x, &y),
+        })
+    }
+
+    pub fn compress(&self) -> Vec<u8> {
+        let zi = invert(&self.z);
+        let tx = mul(&self.x, &zi);
+        let ty = mul(&self.y, &zi);
+        let mut result = pack(&ty);
+        result[31] ^= parity(&tx) << 7;
+
+        result.to_vec()
+    }
+
+    pub fn add(&self, q: &EdwardsPoint) -> EdwardsPoint {
+        let a = mul(&sub(&self.y, &self.x), &sub(&q.y, &q.x));
+        let b = mul(&add(&self.x, &self.y), &add(&q.x, &q.y));
+        let c = mul(&mul(&self.t, &q.t), &D2);
+        let d = mul(&self.z, &q.z);
+        let d = add(&d, &d);
+        let e = sub(&b, &a);
+        let f = sub(&d, &c);
+        let g = add(&d, &c);
+        let h = add(&b, &a);
+
+        EdwardsPoint {
+            x: mul(&e, &f),
+            y: mul(&h, &g),
+            z: mul(&g, &f),
+            t: mul(&e, &h),
+        }
+    }
+
+    /// Multiplies by a 32-byte little-endian scalar
+    pub fn mul(&self, scalar: &[u8]) -> EdwardsPoint {
+        let mut p = EdwardsPoint::identity();
+        let mut q = *self;
+        for i in (0..256).rev() {
+            let b = ((scalar[i / 8] >> (i & 7)) & 1) as i64;
+            p.swap(&mut q, b);
+            q = q.add(&p);
+            p = p.add(&p);
+            p.swap(&mut q, b);
+        }
+
+        p
+    }
+
+    fn swap(&mut self, q: &mut EdwardsPoint, b: i64) {
+        select(&mut self.x, &mut q.x, b);
+        select(&mut self.y, &mut q.y, b);
+        select(&mut self.z, &mut q.z, b);
+        select(&mut self.t, &mut q.t, b);
+    }
+}
+
+/// Reduces a 64-byte little-endian value modulo the group order, as is done
+/// with SHA-512 output to get a scalar
+pub fn reduce_scalar(value: &[u8]) -> Result<Vec<u8>, Error> {
+    if value.len() != 64 {
+        return Err(Error::Crypto(format!("Expected 64 bytes to reduce, got {}", value.len())));
+    }
+
+    let mut x = [0i64; 64];
+    for i in 0..64 {
+        x[i] = value[i] as i64;
+    }
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j = i - 32;
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * ORDER[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j += 1;
+        }
+        x[j] += carry;
+        x[i] = 0;
+    }
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * ORDER[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+    for j in 0..32 {
+        x[j] -= carry * ORDER[j];
+    }
+    let mut result = vec![0u8; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        result[i] = (x[i] & 255) as u8;
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len() / 2)
+            .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_x25519() {
+        // RFC 7748, section 6.1
+        let alice_private =
+            from_hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob_private =
+            from_hex("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let alice_public = x25519_public_key(&alice_private).unwrap();
+        let bob_public = x25519_public_key(&bob_private).unwrap();
+        assert_eq!(from_hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"),
+                   alice_public);
+        assert_eq!(from_hex("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f"),
+                   bob_public);
+
+        let shared = from_hex("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(shared, x25519(&alice_private, &bob_public).unwrap());
+        assert_eq!(shared, x25519(&bob_private, &alice_public).unwrap());
+    }
+
+    #[test]
+    fn test_x25519_vectors() {
+        // RFC 7748, section 5.2. The second u-coordinate has its top bit set,
+        // which has to be ignored.
+        assert_eq!(from_hex("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552"),
+                   x25519(&from_hex("a546e36bf0527c9d3b16154b82465edd\
+                                     62144c0ac1fc5a18506a2244ba449ac4"),
+                          &from_hex("e6db6867583030db3594c1a424b15f7c\
+                                     726624ec26b3353b10a903a6d0ab1c4c"))
+                       .unwrap());
+        assert_eq!(from_hex("95cbde9476e8907d7aade45cb4b873f88b595a68799fa152e6f8f7647aac7957"),
+                   x25519(&from_hex("4b66e9d4d1b4673c5ad22691957d6af5\
+                                     c11b6421e0ea01d42ca4169e7918ba0d"),
+                          &from_hex("e5210f12786811d3f4b7959d0538ae2c\
+                                     31dbe7106fc03c3efc4cd549c715a493"))
+                       .unwrap());
+    }
+
+    #[test]
+    fn test_x25519_iterated() {
+        // RFC 7748, section 5.2: k and u both start as 9, then each result
+        // becomes the next k and the old k the next u
+        let mut k = vec![0u8; 32];
+        k[0] = 9;
+        let mut u = k.clone();
+        for i in 0..1000 {
+            let result = x25519(&k, &u).unwrap();
+            u = k;
+            k = result;
+            if i == 0 {
+                assert_eq!(from_hex("422c8e7a6227d7bca1350b3e2bb7279f\
+                                     7897b87bb6854b783c60e80311ae3079"),
+                           k);
+            }
+        }
+        assert_eq!(from_hex("684cf59ba83309552800ef566f2f4d3c1c3887c49360e3875f2eb94d99532c51"),
+                   k);
+    }
+
+    #[test]
+    fn test_ed25519_public_key() {
+        // RFC 8032, section 7.1, test 1: the clamped scalar from SHA-512 of the seed
+        let scalar = from_hex("307c83864f2833cb427a2ef1c00a013cfdff2768d980c0a3a520f006904de94f");
+        assert_eq!(from_hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"),
+                   EdwardsPoint::base().mul(&scalar).compress());
+    }
+
+    #[test]
+    fn test_decompress_and_add() {
+        let public_key =
+            from_hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let point = EdwardsPoint::decompress(&public_key).unwrap();
+        assert_eq!(public_key, point.compress());
+
+        // 2P computed by addition and by multiplication
+        let mut two = vec![0u8; 32];
+        two[0] = 2;
+        assert_eq!(point.mul(&two).compress(), point.add(&point).compress());
+    }
+
+    #[test]
+    fn test_decompress_invalid_point() {
+        let mut data = vec![0u8; 32];
+        data[0] = 2;
+        assert!(EdwardsPoint::decompress(&data).is_err());
+    }
+
+    #[test]
+    fn test_reduce_scalar() {
+        // The group order plus one reduces to one
+        let mut value = vec![0u8; 64];
+        for i in 0..32 {
+            value[i] = ORDER[i] as u8;
+        }
+        value[0] += 1;
+        let mut one = vec![0u8; 32];
+        one[0] = 1;
+        assert_eq!(one, reduce_scalar(&value).unwrap());
+    }
+}
+
@@ -0,0 +1,157 @@
+use gcrypt::mpi::integer::{Format, Integer};
+use i2p::crypto;
+use i2p::error::Error;
+use rand::{OsRng, Rng};
+
+/// The 2048-bit MODP group from RFC 3526, with generator 2
+pub const ELGAMAL_P: [u8; 256] =
+    [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2, 0x21, 0x68, 0xc2,
+     0x34, 0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1, 0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67,
+     0xcc, 0x74, 0x02, 0x0b, 0xbe, 0xa6, 0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e,
+     0x34, 0x04, 0xdd, 0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d,
+     0xf2, 0x5f, 0x14, 0x37, 0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45, 0xe4, 0x85, 0xb5,
+     0x76, 0x62, 0x5e, 0x7e, 0xc6, 0xf4, 0x4c, 0x42, 0xe9, 0xa6, 0x37, 0xed, 0x6b, 0x0b, 0xff,
+     0x5c, 0xb6, 0xf4, 0x06, 0xb7, 0xed, 0xee, 0x38, 0x6b, 0xfb, 0x5a, 0x89, 0x9f, 0xa5, 0xae,
+     0x9f, 0x24, 0x11, 0x7c, 0x4b, 0x1f, 0xe6, 0x49, 0x28, 0x66, 0x51, 0xec, 0xe4, 0x5b, 0x3d,
+     0xc2, 0x00, 0x7c, 0xb8, 0xa1, 0x63, 0xbf, 0x05, 0x98, 0xda, 0x48, 0x36, 0x1c, 0x55, 0xd3,
+     0x9a, 0x69, 0x16, 0x3f, 0xa8, 0xfd, 0x24, 0xcf, 0x5f, 0x83, 0x65, 0x5d, 0x23, 0xdc, 0xa3,
+     0xad, 0x96, 0x1c, 0x62, 0xf3, 0x56, 0x20, 0x85, 0x52, 0xbb, 0x9e, 0xd5, 0x29, 0x07, 0x70,
+     0x96, 0x96, 0x6d, 0x67, 0x0c, 0x35, 0x4e, 0x4a, 0xbc, 0x98, 0x04, 0xf1, 0x74, 0x6c, 0x08,
+     0xca, 0x18, 0x21, 0x7c, 0x32, 0x90, 0x5e, 0x46, 0x2e, 0x36, 0xce, 0x3b, 0xe3, 0x9e, 0x77,
+     0x2c, 0x18, 0x0e, 0x86, 0x03, 0x9b, 0x27, 0x83, 0xa2, 0xec, 0x07, 0xa2, 0x8f, 0xb5, 0xc5,
+     0x5d, 0xf0, 0x6f, 0x4c, 0x52, 0xc9, 0xde, 0x2b, 0xcb, 0xf6, 0x95, 0x58, 0x17, 0x18, 0x39,
+     0x95, 0x49, 0x7c, 0xea, 0x95, 0x6a, 0xe5, 0x15, 0xd2, 0x26, 0x18, 0x98, 0xfa, 0x05, 0x10,
+     0x15, 0x72, 0x8e, 0x5a, 0x8a, 0xac, 0xaa, 0x68, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+     0xff];
+
+pub const ELGAMAL_G: [u8; 1] = [0x02];
+
+pub const ELGAMAL_KEY_LENGTH: usize = 256;
+
+/// Generates a new ElGamal key pair, returned as (private key, public key)
+pub fn generate_keypair() -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut private_key = vec![0u8; ELGAMAL_KEY_LENGTH];
+    OsRng::new()?.fill_bytes(&mut private_key);
+    let public_key = mod_pow(&ELGAMAL_G, &private_key, &ELGAMAL_P)?;
+
+    Ok((private_key, public_key))
+}
+
+/// Computes base ^ exponent mod modulus on big-endian unsigned integers. The
+/// result is padded to the length of the modulus.
+pub fn mod_pow(base: &[u8], exponent: &[u8], modulus: &[u8]) -> Result<Vec<u8>, Error> {
+    let base = Integer::from_bytes(Format::Unsigned, base)?;
+    let exponent = Integer::from_bytes(Format::Unsigned, exponent)?;
+    let modulus_integer = Integer::from_bytes(Format::Unsigned, modulus)?;
+    let result = base.pow_mod(&exponent, &modulus_integer);
+
+    crypto::left_pad(&result.to_bytes(Format::Unsigned)?, modulus.len())
+}
+
+/// Computes a * b mod modulus, padded like `mod_pow`
+pub fn mod_mul(a: &[u8], b: &[u8], modulus: &[u8]) -> Result<Vec<u8>, Error> {
+    let a = Integer::from_bytes(Format::Unsigned, a)?;
+    let b = Integer::from_bytes(Format::Unsigned, b)?;
+    let modulus_integer = Integer::from_bytes(Format::Unsigned, modulus)?;
+    let result = a.mul_mod(&b, &modulus_integer);
+
+    crypto::left_pad(&result.to_bytes(Format::Unsigned)?, modulus.len())
+}
+
+/// ElGamal encrypts exactly this much data, once the marker byte and hash
+/// are added
+pub const ELGAMAL_DATA_LENGTH: usize = 222;
+
+/// The encrypted block is a and b, each as long as the modulus. With
+/// `zero_padding` each also gets a leading zero byte, as in ElGamal/AES;
+/// tunnel build records leave them out.
+pub fn encrypted_length(zero_padding: bool) -> usize {
+    if zero_padding {
+        2 * ELGAMAL_KEY_LENGTH + 2
+    } else {
+        2 * ELGAMAL_KEY_LENGTH
+    }
+}
+
+/// Encrypts a block of data to `public_key`. The block carries a hash of
+/// the data, which `decrypt` checks.
+pub fn encrypt(public_key: &[u8], data: &[u8], zero_padding: bool) -> Result<Vec<u8>, Error> {
+    if data.len() != ELGAMAL_DATA_LENGTH {
+        return Err(Error::Crypto(format!("ElGamal encrypts {} bytes, not {}",
+                                         ELGAMAL_DATA_LENGTH,
+                                         data.len())));
+    }
+    let mut block = vec![0xff];
+    block.extend(crypto::sha256(data));
+    block.extend_from_slice(data);
+
+    let mut k = vec![0u8; ELGAMAL_KEY_LENGTH];
+    OsRng::new()?.fill_bytes(&mut k);
+    let a = mod_pow(&ELGAMAL_G, &k, &ELGAMAL_P)?;
+    let b = mod_mul(&mod_pow(public_key, &k, &ELGAMAL_P)?, &block, &ELGAMAL_P)?;
+
+    let mut encrypted: Vec<u8> = Vec::with_capacity(encrypted_length(zero_padding));
+    if zero_padding {
+        encrypted.push(0);
+    }
+    encrypted.extend(a);
+    if zero_padding {
+        encrypted.push(0);
+    }
+    encrypted.extend(b);
+
+    Ok(encrypted)
+}
+
+/// Decrypts a block from `encrypt`, returning the data if its hash matches
+pub fn decrypt(private_key: &[u8], encrypted: &[u8], zero_padding: bool) -> Result<Vec<u8>, Error> {
+    if encrypted.len() != encrypted_length(zero_padding) {
+        return Err(Error::Crypto(format!("ElGamal block should be {} bytes, not {}",
+                                         encrypted_length(zero_padding),
+                                         encrypted.len())));
+    }
+    let (a, b) = if zero_padding {
+        if encrypted[0] != 0 || encrypted[ELGAMAL_KEY_LENGTH + 1] != 0 {
+            return Err(Error::Crypto("Bad ElGamal padding".to_string()));
+        }
+        (&encrypted[1..ELGAMAL_KEY_LENGTH + 1], &encrypted[ELGAMAL_KEY_LENGTH + 2..])
+    } else {
+        encrypted.split_at(ELGAMAL_KEY_LENGTH)
+    };
+
+    // By Fermat, the inverse of a^x is (a^x)^(p - 2)
+    let mut p_minus_2 = ELGAMAL_P.to_vec();
+    p_minus_2[ELGAMAL_KEY_LENGTH - 1] -= 2;
+    let shared = mod_pow(a, private_key, &ELGAMAL_P)?;
+    let block = mod_mul(b, &mod_pow(&shared, &p_minus_2, &ELGAMAL_P)?, &ELGAMAL_P)?;
+
+    // The block is one byte shorter than the modulus
+    let (hash, data) = block[2..].split_at(32);
+    if block[0] != 0 || block[1] != 0xff || crypto::sha256(data) != hash {
+        return Err(Error::Crypto("ElGamal decryption failed".to_string()));
+    }
+
+    Ok(data.to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let (private_key, public_key) = generate_keypair().unwrap();
+        let data: Vec<u8> = (0..ELGAMAL_DATA_LENGTH).map(|i| i as u8).collect();
+        for &zero_padding in &[false, true] {
+            let encrypted = encrypt(&public_key, &data, zero_padding).unwrap();
+            assert_eq!(encrypted_length(zero_padding), encrypted.len());
+            assert_eq!(data, decrypt(&private_key, &encrypted, zero_padding).unwrap());
+
+            let mut tampered = encrypted.clone();
+            let last = tampered.len() - 1;
+            tampered[last] ^= 0x01;
+            assert!(decrypt(&private_key, &tampered, zero_padding).is_err());
+        }
+        assert!(encrypt(&public_key, &data[1..], false).is_err());
+    }
+}
@@ -0,0 +1,595 @@
+//! ElGamal/AES+SessionTags, the end-to-end encryption older destinations
+//! and routers use for garlic messages.
+//!
+//! The first message to someone starts a session: an ElGamal block carrying
+//! a new AES session key and a pre-IV, then an AES block. The AES block can
+//! deliver session tags, each good for one later message under the same key,
+//! which then starts with the tag instead of the expensive ElGamal block. We
+//! only use the tags we've sent once the other end has acknowledged them, so
+//! until then every message is ElGamal again.
+//!
+//! The AES block is the tag count and tags, the payload's size and SHA-256
+//! hash, a flag saying whether a new session key follows, the payload, and
+//! random padding to a whole number of AES blocks.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::crypto::{self, aes, elgamal};
+use i2p::data::crypto::{Hash, PrivateKey, PublicKey, SessionKey};
+use i2p::data::date::Date;
+use i2p::error::Error;
+use rand::{thread_rng, OsRng, Rng};
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::Entry;
+use std::io::Read;
+use std::sync::Mutex;
+
+pub const SESSION_TAG_LENGTH: usize = 32;
+
+pub type SessionTag = [u8; SESSION_TAG_LENGTH];
+
+/// The most tags one message can deliver
+pub const MAX_TAGS: usize = 200;
+
+const PRE_IV_LENGTH: usize = 32;
+
+const FLAG_NEW_SESSION_KEY: u8 = 0x01;
+
+/// Tag count, payload size, payload hash and flag
+const MIN_AES_BLOCK_LENGTH: usize = 2 + 4 + 32 + 1;
+
+/// How long tags we've received are kept, in milliseconds
+const TAG_EXPIRATION: u64 = 15 * 60 * 1000;
+
+/// How long we use tags we've sent for. It's shorter than they're kept for,
+/// so a message using one isn't decrypted just after it's thrown away.
+const TAG_LIFETIME: u64 = 12 * 60 * 1000;
+
+/// The most tags we hold, from everyone who's sent us some. Past this the
+/// oldest are dropped, and messages using them take the ElGamal path.
+const MAX_INBOUND_TAGS: usize = 20000;
+
+/// How often received tags are checked for expiry, in milliseconds
+const EXPIRE_INTERVAL: u64 = 60 * 1000;
+
+/// When we're down to this many tags for someone we send them more
+const LOW_TAG_THRESHOLD: usize = 30;
+
+/// How many tags we send at once
+const TAGS_PER_BATCH: usize = 40;
+
+/// What the AES block carries
+#[derive(Clone, Debug, PartialEq)]
+pub struct AesBlock {
+    pub tags: Vec<SessionTag>,
+    /// Replaces the session key, for the tags in this block and after
+    pub new_session_key: Option<SessionKey>,
+    pub payload: Vec<u8>,
+}
+
+fn check_key(key: &SessionKey) -> Result<(), Error> {
+    if key.data().len() != aes::KEY_LENGTH {
+        return Err(Error::Crypto(format!("Session keys must be {} bytes", aes::KEY_LENGTH)));
+    }
+
+    Ok(())
+}
+
+/// The AES IV for a message is the start of the SHA-256 hash of its tag, or
+/// of the pre-IV in its ElGamal block
+fn iv(seed: &[u8]) -> Vec<u8> {
+    crypto::sha256(seed)[..aes::BLOCK_LENGTH].to_vec()
+}
+
+fn read_exactly(reader: &mut &[u8], length: usize) -> Result<Vec<u8>, Error> {
+    if reader.len() < length {
+        return Err(Error::Crypto(format!("AES block truncated: expected {} more bytes, found {}",
+                                         length,
+                                         reader.len())));
+    }
+    let mut data = vec![0u8; length];
+    reader.read_exact(&mut data)?;
+
+    Ok(data)
+}
+
+impl AesBlock {
+    fn encrypt(&self, key: &SessionKey, iv: &[u8]) -> Result<Vec<u8>, Error> {
+        check_key(key)?;
+        if self.tags.len() > MAX_TAGS {
+            return Err(Error::Crypto(format!("Can't send {} session tags, only {}",
+                                             self.tags.len(),
+                                             MAX_TAGS)));
+        }
+        let mut block: Vec<u8> = Vec::new();
+        block.write_u16::<BigEndian>(self.tags.len() as u16)?;
+        for tag in &self.tags {
+            block.extend_from_slice(tag);
+        }
+        block.write_u32::<BigEndian>(self.payload.len() as u32)?;
+        block.extend(crypto::sha256(&self.payload));
+        match self.new_session_key {
+            Some(ref new_key) => {
+                check_key(new_key)?;
+                block.push(FLAG_NEW_SESSION_KEY);
+                block.extend_from_slice(new_key.data());
+            }
+            None => block.push(0),
+        }
+        block.extend_from_slice(&self.payload);
+        let padding = (aes::BLOCK_LENGTH - block.len() % aes::BLOCK_LENGTH) % aes::BLOCK_LENGTH;
+        let start = block.len();
+        block.resize(start + padding, 0);
+        thread_rng().fill_bytes(&mut block[start..]);
+
+        aes::cbc_encrypt(key.data(), iv, &block)
+    }
+
+    fn decrypt(key: &SessionKey, iv: &[u8], data: &[u8]) -> Result<AesBlock, Error> {
+        check_key(key)?;
+        if data.len() < MIN_AES_BLOCK_LENGTH {
+            return Err(Error::Crypto(format!("AES block too short: {}", data.len())));
+        }
+        let block = aes::cbc_decrypt(key.data(), iv, data)?;
+        let mut reader = &block[..];
+
+        let tag_count = reader.read_u16::<BigEndian>()? as usize;
+        if tag_count > MAX_TAGS {
+            return Err(Error::Crypto(format!("AES block has too many session tags: {}",
+                                             tag_count)));
+        }
+        let mut tags: Vec<SessionTag> = Vec::with_capacity(tag_count);
+        for _ in 0..tag_count {
+            let mut tag = [0u8; SESSION_TAG_LENGTH];
+            tag.copy_from_slice(&read_exactly(&mut reader, SESSION_TAG_LENGTH)?);
+            tags.push(tag);
+        }
+        let payload_length = reader.read_u32::<BigEndian>()? as usize;
+        let hash = read_exactly(&mut reader, 32)?;
+        let new_session_key = match reader.read_u8()? {
+            FLAG_NEW_SESSION_KEY => {
+                let new_key = read_exactly(&mut reader, aes::KEY_LENGTH)?;
+                Some(SessionKey::ElGamal(new_key.into_boxed_slice()))
+            }
+            _ => None,
+        };
+        let payload = read_exactly(&mut reader, payload_length)?;
+        if crypto::sha256(&payload) != hash {
+            return Err(Error::Crypto("AES block payload hash doesn't match".to_string()));
+        }
+        // Anything left is padding
+        if reader.len() >= aes::BLOCK_LENGTH {
+            return Err(Error::Crypto(format!("AES block has {} bytes of padding",
+                                             reader.len())));
+        }
+
+        Ok(AesBlock {
+            tags,
+            new_session_key,
+            payload,
+        })
+    }
+}
+
+pub fn new_session_key() -> Result<SessionKey, Error> {
+    let mut key = vec![0u8; aes::KEY_LENGTH];
+    OsRng::new()?.fill_bytes(&mut key);
+
+    Ok(SessionKey::ElGamal(key.into_boxed_slice()))
+}
+
+pub fn new_session_tags(count: usize) -> Result<Vec<SessionTag>, Error> {
+    let mut rng = OsRng::new()?;
+    let mut tags: Vec<SessionTag> = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut tag = [0u8; SESSION_TAG_LENGTH];
+        rng.fill_bytes(&mut tag);
+        tags.push(tag);
+    }
+
+    Ok(tags)
+}
+
+/// Starts a session with `session_key`: an ElGamal block to `public_key`,
+/// then the AES block
+pub fn encrypt_new_session(public_key: &PublicKey,
+                           session_key: &SessionKey,
+                           block: &AesBlock)
+                           -> Result<Vec<u8>, Error> {
+    let public_key = match *public_key {
+        PublicKey::ElGamal(ref data) => data,
+        _ => {
+            return Err(Error::Crypto(format!("Can't use ElGamal/AES with a {:?} key",
+                                             public_key.get_type())))
+        }
+    };
+    check_key(session_key)?;
+    let mut elgamal_data = vec![0u8; elgamal::ELGAMAL_DATA_LENGTH];
+    elgamal_data[..aes::KEY_LENGTH].copy_from_slice(session_key.data());
+    OsRng::new()?.fill_bytes(&mut elgamal_data[aes::KEY_LENGTH..]);
+    let pre_iv = elgamal_data[aes::KEY_LENGTH..aes::KEY_LENGTH + PRE_IV_LENGTH].to_vec();
+
+    let mut encrypted = elgamal::encrypt(public_key, &elgamal_data, true)?;
+    encrypted.extend(block.encrypt(session_key, &iv(&pre_iv))?);
+
+    Ok(encrypted)
+}
+
+/// Sends a message in an existing session, using up one of its tags
+pub fn encrypt_existing_session(session_key: &SessionKey,
+                                tag: &SessionTag,
+                                block: &AesBlock)
+                                -> Result<Vec<u8>, Error> {
+    let mut encrypted = tag.to_vec();
+    encrypted.extend(block.encrypt(session_key, &iv(tag))?);
+
+    Ok(encrypted)
+}
+
+/// Reads a message that starts a session, returning its session key
+pub fn decrypt_new_session(private_key: &PrivateKey,
+                           data: &[u8])
+                           -> Result<(SessionKey, AesBlock), Error> {
+    let private_key = match *private_key {
+        PrivateKey::ElGamal(ref data) => data,
+        _ => {
+            return Err(Error::Crypto(format!("Can't use ElGamal/AES with a {:?} key",
+                                             private_key.get_type())))
+        }
+    };
+    let elgamal_length = elgamal::encrypted_length(true);
+    if data.len() < elgamal_length {
+        return Err(Error::Crypto(format!("ElGamal/AES message too short: {}", data.len())));
+    }
+    let elgamal_data = elgamal::decrypt(private_key, &data[..elgamal_length], true)?;
+    let session_key = SessionKey::ElGamal(elgamal_data[..aes::KEY_LENGTH]
+        .to_vec()
+        .into_boxed_slice());
+    let pre_iv = &elgamal_data[aes::KEY_LENGTH..aes::KEY_LENGTH + PRE_IV_LENGTH];
+    let block = AesBlock::decrypt(&session_key, &iv(pre_iv), &data[elgamal_length..])?;
+
+    Ok((session_key, block))
+}
+
+/// Reads a message that starts with a tag for `session_key`
+pub fn decrypt_existing_session(session_key: &SessionKey, data: &[u8]) -> Result<AesBlock, Error> {
+    if data.len() < SESSION_TAG_LENGTH {
+        return Err(Error::Crypto(format!("ElGamal/AES message too short: {}", data.len())));
+    }
+    let (tag, rest) = data.split_at(SESSION_TAG_LENGTH);
+
+    AesBlock::decrypt(session_key, &iv(tag), rest)
+}
+
+struct InboundState {
+    /// The key each tag is for, and when it expires
+    tags: HashMap<SessionTag, (SessionKey, Date)>,
+    /// The tags in the order they came, with their expiration to tell them
+    /// from the same tag delivered again. Tags that have been used are left
+    /// in until they're evicted or expire.
+    order: VecDeque<(SessionTag, Date)>,
+    last_expired: Date,
+}
+
+impl InboundState {
+    /// Drops the oldest tags until we're holding no more than `max_tags`
+    fn evict(&mut self, max_tags: usize) {
+        while self.tags.len() > max_tags {
+            let (tag, expiration) = match self.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if self.tags.get(&tag).is_some_and(|&(_, current)| current == expiration) {
+                self.tags.remove(&tag);
+            }
+        }
+        // Don't let tags that have been used pile up between expiries
+        if self.order.len() > 2 * max_tags {
+            let tags = &self.tags;
+            self.order.retain(|&(ref tag, expiration)| {
+                tags.get(tag).is_some_and(|&(_, current)| current == expiration)
+            });
+        }
+    }
+}
+
+/// The tags we've been sent, for decrypting what comes to us
+pub struct InboundSessions {
+    state: Mutex<InboundState>,
+    max_tags: usize,
+}
+
+impl InboundSessions {
+    pub fn new() -> InboundSessions {
+        InboundSessions {
+            state: Mutex::new(InboundState {
+                tags: HashMap::new(),
+                order: VecDeque::new(),
+                last_expired: Date::now(),
+            }),
+            max_tags: MAX_INBOUND_TAGS,
+        }
+    }
+
+    /// How many tags we're holding
+    pub fn tag_count(&self) -> usize {
+        self.state.lock().unwrap().tags.len()
+    }
+
+    /// Decrypts a message to us, using up its tag if it has one we know,
+    /// and keeping any tags it delivers. Returns the payload.
+    pub fn decrypt(&self,
+                   private_key: &PrivateKey,
+                   data: &[u8],
+                   now: Date)
+                   -> Result<Vec<u8>, Error> {
+        let tagged = {
+            let mut state = self.state.lock().unwrap();
+            if state.last_expired.millis() + EXPIRE_INTERVAL <= now.millis() {
+                state.tags.retain(|_, &mut (_, expiration)| expiration > now);
+                state.order.retain(|&(_, expiration)| expiration > now);
+                state.last_expired = now;
+            }
+            if data.len() >= SESSION_TAG_LENGTH {
+                let mut tag = [0u8; SESSION_TAG_LENGTH];
+                tag.copy_from_slice(&data[..SESSION_TAG_LENGTH]);
+                match state.tags.remove(&tag) {
+                    Some((key, expiration)) if expiration > now => Some(key),
+                    _ => None,
+                }
+            } else {
+                None
+            }
+        };
+        // Decrypting the ElGamal block is slow, so the lock isn't held
+        let (session_key, block) = match tagged {
+            Some(key) => {
+                let block = decrypt_existing_session(&key, data)?;
+                (key, block)
+            }
+            None => decrypt_new_session(private_key, data)?,
+        };
+
+        let key = block.new_session_key.clone().unwrap_or(session_key);
+        let expiration = Date::from_millis(now.millis() + TAG_EXPIRATION);
+        let mut state = self.state.lock().unwrap();
+        for tag in &block.tags {
+            state.tags.insert(*tag, (key.clone(), expiration));
+            state.order.push_back((*tag, expiration));
+        }
+        state.evict(self.max_tags);
+
+        Ok(block.payload)
+    }
+}
+
+impl Default for InboundSessions {
+    fn default() -> InboundSessions {
+        InboundSessions::new()
+    }
+}
+
+struct OutboundSession {
+    session_key: SessionKey,
+    /// Tags the other end has acknowledged, oldest first, with when they
+    /// were sent
+    tags: Vec<(SessionTag, Date)>,
+    /// Tags we've sent that haven't been acknowledged, by batch
+    unacknowledged: HashMap<u32, (Vec<SessionTag>, Date)>,
+    last_used: Date,
+}
+
+/// Our sessions with everyone we send to, by the hash of who they are
+pub struct OutboundSessions {
+    sessions: Mutex<HashMap<Hash, OutboundSession>>,
+}
+
+impl OutboundSessions {
+    pub fn new() -> OutboundSessions {
+        OutboundSessions { sessions: Mutex::new(HashMap::new()) }
+    }
+
+    /// How many acknowledged tags we have left for someone
+    pub fn tag_count(&self, to: &Hash) -> usize {
+        self.sessions.lock().unwrap().get(to).map_or(0, |session| session.tags.len())
+    }
+
+    /// Encrypts a payload to `to`, using a tag if we have one. If the
+    /// message delivers new tags, also returns the batch they're in: once
+    /// the message is acknowledged, pass it to `tags_delivered()`.
+    pub fn encrypt(&self,
+                   to: &Hash,
+                   public_key: &PublicKey,
+                   payload: &[u8],
+                   now: Date)
+                   -> Result<(Vec<u8>, Option<u32>), Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = match sessions.entry(to.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(OutboundSession {
+                    session_key: new_session_key()?,
+                    tags: Vec::new(),
+                    unacknowledged: HashMap::new(),
+                    last_used: now,
+                })
+            }
+        };
+        session.last_used = now;
+        let fresh = |sent: Date| sent.millis() + TAG_LIFETIME > now.millis();
+        session.tags.retain(|&(_, sent)| fresh(sent));
+        session.unacknowledged.retain(|_, &mut (_, sent)| fresh(sent));
+
+        let tag = if session.tags.is_empty() {
+            None
+        } else {
+            Some(session.tags.remove(0).0)
+        };
+        let mut batch = None;
+        let mut block = AesBlock {
+            tags: Vec::new(),
+            new_session_key: None,
+            payload: payload.to_vec(),
+        };
+        if session.tags.len() < LOW_TAG_THRESHOLD && session.unacknowledged.is_empty() {
+            let id: u32 = thread_rng().gen();
+            block.tags = new_session_tags(TAGS_PER_BATCH)?;
+            session.unacknowledged.insert(id, (block.tags.clone(), now));
+            batch = Some(id);
+        }
+
+        let encrypted = match tag {
+            Some(ref tag) => encrypt_existing_session(&session.session_key, tag, &block)?,
+            None => encrypt_new_session(public_key, &session.session_key, &block)?,
+        };
+
+        Ok((encrypted, batch))
+    }
+
+    /// The message that delivered a batch of tags has been acknowledged, so
+    /// they can be used
+    pub fn tags_delivered(&self, to: &Hash, batch: u32) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(to) {
+            if let Some((tags, sent)) = session.unacknowledged.remove(&batch) {
+                session.tags.extend(tags.into_iter().map(|tag| (tag, sent)));
+            }
+        }
+    }
+
+    /// Drops sessions that haven't been used for as long as their tags last
+    pub fn expire(&self, now: Date) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.last_used.millis() + TAG_LIFETIME > now.millis());
+
+        before - sessions.len()
+    }
+}
+
+impl Default for OutboundSessions {
+    fn default() -> OutboundSessions {
+        OutboundSessions::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keys() -> (PrivateKey, PublicKey) {
+        let (private_key, public_key) = elgamal::generate_keypair().unwrap();
+        (PrivateKey::ElGamal(private_key.into_boxed_slice()),
+         PublicKey::ElGamal(public_key.into_boxed_slice()))
+    }
+
+    fn hash(value: u8) -> Hash {
+        Hash::SHA256(vec![value; 32].into_boxed_slice())
+    }
+
+    #[test]
+    fn test_aes_block() {
+        let key = new_session_key().unwrap();
+        let iv = iv(&[1u8; 32]);
+        for length in 0..40 {
+            let block = AesBlock {
+                tags: new_session_tags(length % 3).unwrap(),
+                new_session_key: if length % 2 == 0 {
+                    Some(new_session_key().unwrap())
+                } else {
+                    None
+                },
+                payload: vec![length as u8; length],
+            };
+            let encrypted = block.encrypt(&key, &iv).unwrap();
+            assert_eq!(0, encrypted.len() % aes::BLOCK_LENGTH);
+            assert_eq!(block, AesBlock::decrypt(&key, &iv, &encrypted).unwrap());
+
+            let mut tampered = encrypted.clone();
+            tampered[0] ^= 0x80;
+            assert!(AesBlock::decrypt(&key, &iv, &tampered).is_err());
+        }
+
+        let too_many = AesBlock {
+            tags: new_session_tags(MAX_TAGS + 1).unwrap(),
+            new_session_key: None,
+            payload: Vec::new(),
+        };
+        assert!(too_many.encrypt(&key, &iv).is_err());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let (private_key, public_key) = keys();
+        let to = hash(1);
+        let outbound = OutboundSessions::new();
+        let inbound = InboundSessions::new();
+        let now = Date::now();
+
+        // Until the first batch of tags is acknowledged, it's all ElGamal
+        let (first, batch) = outbound.encrypt(&to, &public_key, b"first", now).unwrap();
+        assert!(first.len() > elgamal::encrypted_length(true));
+        let batch = batch.unwrap();
+        assert_eq!(b"first".to_vec(), inbound.decrypt(&private_key, &first, now).unwrap());
+        assert_eq!(TAGS_PER_BATCH, inbound.tag_count());
+        let (second, more) = outbound.encrypt(&to, &public_key, b"second", now).unwrap();
+        assert!(more.is_none());
+        assert!(second.len() > elgamal::encrypted_length(true));
+        assert_eq!(b"second".to_vec(), inbound.decrypt(&private_key, &second, now).unwrap());
+
+        // Then each message uses a tag, once
+        outbound.tags_delivered(&to, batch);
+        assert_eq!(TAGS_PER_BATCH, outbound.tag_count(&to));
+        let (tagged, more) = outbound.encrypt(&to, &public_key, b"tagged", now).unwrap();
+        assert!(tagged.len() < elgamal::encrypted_length(true));
+        assert!(more.is_none());
+        assert_eq!(b"tagged".to_vec(), inbound.decrypt(&private_key, &tagged, now).unwrap());
+        assert_eq!(TAGS_PER_BATCH - 1, inbound.tag_count());
+        assert!(inbound.decrypt(&private_key, &tagged, now).is_err());
+
+        // More tags go out once we're running low
+        let mut batches = 0;
+        for _ in 0..TAGS_PER_BATCH - LOW_TAG_THRESHOLD {
+            let (message, batch) = outbound.encrypt(&to, &public_key, b"more", now).unwrap();
+            assert_eq!(b"more".to_vec(), inbound.decrypt(&private_key, &message, now).unwrap());
+            batches += batch.map_or(0, |_| 1);
+        }
+        assert_eq!(1, batches);
+        assert_eq!(LOW_TAG_THRESHOLD - 1, outbound.tag_count(&to));
+
+        // Tags expire at both ends
+        let (tagged, _) = outbound.encrypt(&to, &public_key, b"late", now).unwrap();
+        let later = Date::from_millis(now.millis() + TAG_LIFETIME);
+        let (message, _) = outbound.encrypt(&to, &public_key, b"later", later).unwrap();
+        assert!(message.len() > elgamal::encrypted_length(true));
+        let much_later = Date::from_millis(now.millis() + TAG_EXPIRATION);
+        assert!(inbound.decrypt(&private_key, &tagged, much_later).is_err());
+        assert_eq!(0, inbound.tag_count());
+        assert_eq!(1, outbound.expire(Date::from_millis(later.millis() + TAG_LIFETIME)));
+    }
+
+    #[test]
+    fn test_inbound_tag_limit() {
+        let (private_key, public_key) = keys();
+        let outbound = OutboundSessions::new();
+        let mut inbound = InboundSessions::new();
+        inbound.max_tags = TAGS_PER_BATCH + 10;
+        let now = Date::now();
+
+        let (first, first_batch) = outbound.encrypt(&hash(1), &public_key, b"one", now).unwrap();
+        let (second, _) = outbound.encrypt(&hash(2), &public_key, b"two", now).unwrap();
+        inbound.decrypt(&private_key, &first, now).unwrap();
+        inbound.decrypt(&private_key, &second, now).unwrap();
+        assert_eq!(TAGS_PER_BATCH + 10, inbound.tag_count());
+
+        // The first batch came first, so its oldest tags are gone
+        outbound.tags_delivered(&hash(1), first_batch.unwrap());
+        let (tagged, _) = outbound.encrypt(&hash(1), &public_key, b"tagged", now).unwrap();
+        assert!(tagged.len() < elgamal::encrypted_length(true));
+        assert!(inbound.decrypt(&private_key, &tagged, now).is_err());
+        for _ in 1..30 {
+            outbound.encrypt(&hash(1), &public_key, b"skipped", now).unwrap();
+        }
+        let (kept, _) = outbound.encrypt(&hash(1), &public_key, b"kept", now).unwrap();
+        assert_eq!(b"kept".to_vec(), inbound.decrypt(&private_key, &kept, now).unwrap());
+    }
+}
@@ -0,0 +1,141 @@
+use gcrypt;
+use gcrypt::digest::{self, Algorithm};
+use i2p::error::Error;
+
+pub mod aes;
+pub mod chacha20;
+pub mod curve25519;
+pub mod elgamal;
+pub mod elgamal_aes;
+pub mod noise;
+pub mod poly1305;
+pub mod sexp;
+pub mod signature;
+pub mod siphash;
+
+const HMAC_BLOCK_LENGTH: usize = 64;
+
+/// The length of an HMAC-SHA256 output
+const HMAC_LENGTH: usize = 32;
+
+pub fn token() -> gcrypt::Gcrypt {
+    gcrypt::init(|x| {
+            x.disable_secmem().enable_quick_random();
+            Ok::<(), ()>(())
+        })
+        .unwrap_or_else(|_| gcrypt::init_default())
+}
+
+pub fn init_gost() {
+    // TODO: Implement
+}
+
+pub fn sha1(data: &[u8]) -> Vec<u8> {
+    hash(Algorithm::Sha1, 20, data)
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    hash(Algorithm::Sha256, 32, data)
+}
+
+pub fn sha384(data: &[u8]) -> Vec<u8> {
+    hash(Algorithm::Sha384, 48, data)
+}
+
+pub fn sha512(data: &[u8]) -> Vec<u8> {
+    hash(Algorithm::Sha512, 64, data)
+}
+
+fn hash(algorithm: Algorithm, length: usize, data: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; length];
+    digest::hash(algorithm, data, &mut result);
+    result
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut key = if key.len() > HMAC_BLOCK_LENGTH {
+        sha256(key)
+    } else {
+        key.to_vec()
+    };
+    key.resize(HMAC_BLOCK_LENGTH, 0);
+
+    let mut inner: Vec<u8> = key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend(sha256(&inner));
+
+    sha256(&outer)
+}
+
+/// HKDF with HMAC-SHA256, as in RFC 5869
+pub fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8], length: usize) -> Result<Vec<u8>, Error> {
+    // The block counter is a single byte
+    if length > 255 * HMAC_LENGTH {
+        return Err(Error::Crypto(format!("HKDF can't produce {} bytes", length)));
+    }
+
+    let prk = hmac_sha256(salt, ikm);
+    let mut result: Vec<u8> = Vec::new();
+    let mut block: Vec<u8> = Vec::new();
+    let mut counter: u8 = 0;
+    while result.len() < length {
+        counter += 1;
+        let mut input = block.clone();
+        input.extend_from_slice(info);
+        input.push(counter);
+        block = hmac_sha256(&prk, &input);
+        result.extend_from_slice(&block);
+    }
+    result.truncate(length);
+
+    Ok(result)
+}
+
+/// libgcrypt strips leading zeros from MPIs, but I2P uses fixed-length fields.
+/// It also adds a zero byte to keep values with the top bit set positive, which
+/// is dropped here.
+pub fn left_pad(value: &[u8], length: usize) -> Result<Vec<u8>, Error> {
+    let value = match value.iter().position(|&byte| byte != 0) {
+        Some(start) if value.len() > length => &value[start..],
+        _ => value,
+    };
+    if value.len() > length {
+        return Err(Error::Crypto(format!("Value of length {} doesn't fit in {} bytes",
+                                         value.len(),
+                                         length)));
+    }
+    let mut result = vec![0u8; length - value.len()];
+    result.extend_from_slice(value);
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2
+        let expected = [0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26,
+                        0x08, 0x95, 0x75, 0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83,
+                        0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec, 0x38, 0x43];
+        assert_eq!(expected.to_vec(),
+                   hmac_sha256(b"Jefe", b"what do ya want for nothing?"));
+    }
+
+    #[test]
+    fn test_hkdf() {
+        // RFC 5869, test case 1
+        let salt: Vec<u8> = (0..13).collect();
+        let info: Vec<u8> = (0xf0..0xfa).collect();
+        let expected = [0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a, 0x90, 0x43, 0x4f, 0x64,
+                        0xd0, 0x36, 0x2f, 0x2a, 0x2d, 0x2d, 0x0a, 0x90, 0xcf, 0x1a, 0x5a, 0x4c,
+                        0x5d, 0xb0, 0x2d, 0x56, 0xec, 0xc4, 0xc5, 0xbf, 0x34, 0x00, 0x72, 0x08,
+                        0xd5, 0xb8, 0x87, 0x18, 0x58, 0x65];
+        assert_eq!(expected.to_vec(), hkdf(&salt, &[0x0b; 22], &info, 42).unwrap());
+        assert_eq!(255 * 32, hkdf(&salt, &[0x0b; 22], &info, 255 * 32).unwrap().len());
+        assert!(hkdf(&salt, &[0x0b; 22], &info, 255 * 32 + 1).is_err());
+    }
+}
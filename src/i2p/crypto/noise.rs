@@ -0,0 +1,109 @@
+//! The symmetric state from the Noise protocol framework, which the ECIES
+//! handshakes (tunnel build records, NTCP2 and SSU2) are built from. The
+//! Diffie-Hellman steps are left to the callers, which feed the shared
+//! secrets to `mix_key`.
+
+use i2p::crypto::{self, chacha20};
+use i2p::error::Error;
+
+#[derive(Clone)]
+pub struct SymmetricState {
+    chaining_key: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl SymmetricState {
+    /// Starts a handshake with an empty prologue. Unlike plain Noise, I2P
+    /// always hashes the protocol name, even when it's short enough to use
+    /// as is.
+    pub fn new(protocol_name: &str) -> SymmetricState {
+        let hash = crypto::sha256(protocol_name.as_bytes());
+        let mut state = SymmetricState {
+            chaining_key: hash.clone(),
+            hash,
+        };
+        state.mix_hash(&[]);
+
+        state
+    }
+
+    pub fn chaining_key(&self) -> &[u8] {
+        &self.chaining_key
+    }
+
+    /// The handshake hash, which each message is authenticated against
+    pub fn hash(&self) -> &[u8] {
+        &self.hash
+    }
+
+    pub fn mix_hash(&mut self, data: &[u8]) {
+        let mut input = self.hash.clone();
+        input.extend_from_slice(data);
+        self.hash = crypto::sha256(&input);
+    }
+
+    /// Mixes a Diffie-Hellman result into the chaining key, returning the
+    /// key for the next message
+    pub fn mix_key(&mut self, input_key_material: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut output = crypto::hkdf(&self.chaining_key, input_key_material, &[], 64)?;
+        let key = output.split_off(32);
+        self.chaining_key = output;
+
+        Ok(key)
+    }
+
+    /// Encrypts a handshake payload, authenticated against the handshake so
+    /// far, and mixes the ciphertext into it. The nonce only needs to be
+    /// other than zero when a key is used twice.
+    pub fn encrypt_and_hash(&mut self,
+                            key: &[u8],
+                            nonce: u64,
+                            plaintext: &[u8])
+                            -> Result<Vec<u8>, Error> {
+        let nonce = chacha20::nonce(nonce);
+        let ciphertext = chacha20::aead_encrypt(key, &nonce, &self.hash, plaintext)?;
+        self.mix_hash(&ciphertext);
+
+        Ok(ciphertext)
+    }
+
+    pub fn decrypt_and_hash(&mut self,
+                            key: &[u8],
+                            nonce: u64,
+                            ciphertext: &[u8])
+                            -> Result<Vec<u8>, Error> {
+        let nonce = chacha20::nonce(nonce);
+        let plaintext = chacha20::aead_decrypt(key, &nonce, &self.hash, ciphertext)?;
+        self.mix_hash(ciphertext);
+
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        let mut initiator = SymmetricState::new("Noise_N_25519_ChaChaPoly_SHA256");
+        let mut responder = SymmetricState::new("Noise_N_25519_ChaChaPoly_SHA256");
+        assert_eq!(initiator.hash(), responder.hash());
+        assert_eq!(crypto::sha256(b"Noise_N_25519_ChaChaPoly_SHA256"),
+                   initiator.chaining_key().to_vec());
+
+        initiator.mix_hash(b"static key");
+        responder.mix_hash(b"static key");
+        let key = initiator.mix_key(b"shared secret").unwrap();
+        assert_eq!(key, responder.mix_key(b"shared secret").unwrap());
+        assert_eq!(initiator.chaining_key(), responder.chaining_key());
+
+        let ciphertext = initiator.encrypt_and_hash(&key, 0, b"payload").unwrap();
+        assert_eq!(b"payload".to_vec(),
+                   responder.decrypt_and_hash(&key, 0, &ciphertext).unwrap());
+        assert_eq!(initiator.hash(), responder.hash());
+
+        // The handshake hash has moved on, so the same ciphertext won't do
+        assert!(responder.decrypt_and_hash(&key, 0, &ciphertext).is_err());
+    }
+}
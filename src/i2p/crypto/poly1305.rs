@@ -0,0 +1,142 @@
+//! The Poly1305 one-time authenticator from RFC 7539. The accumulator is
+//! kept in five 26-bit limbs, as in poly1305-donna.
+
+use i2p::error::Error;
+
+pub const KEY_LENGTH: usize = 32;
+
+pub const TAG_LENGTH: usize = 16;
+
+const BLOCK_LENGTH: usize = 16;
+
+const LIMB_MASK: u32 = 0x3ffffff;
+
+fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24
+}
+
+/// Computes the tag for `message`. The key must never be used twice.
+pub fn poly1305(key: &[u8], message: &[u8]) -> Result<Vec<u8>, Error> {
+    if key.len() != KEY_LENGTH {
+        return Err(Error::Crypto(format!("Poly1305 needs a {}-byte key", KEY_LENGTH)));
+    }
+
+    // r is clamped as it's read
+    let r0 = (read_u32(&key[0..]) & 0x3ffffff) as u64;
+    let r1 = ((read_u32(&key[3..]) >> 2) & 0x3ffff03) as u64;
+    let r2 = ((read_u32(&key[6..]) >> 4) & 0x3ffc0ff) as u64;
+    let r3 = ((read_u32(&key[9..]) >> 6) & 0x3f03fff) as u64;
+    let r4 = ((read_u32(&key[12..]) >> 8) & 0x00fffff) as u64;
+    let s1 = r1 * 5;
+    let s2 = r2 * 5;
+    let s3 = r3 * 5;
+    let s4 = r4 * 5;
+
+    let (mut h0, mut h1, mut h2, mut h3, mut h4) = (0u32, 0u32, 0u32, 0u32, 0u32);
+    for chunk in message.chunks(BLOCK_LENGTH) {
+        // Each block has a one appended, which for a full block is bit 128
+        let mut block = [0u8; BLOCK_LENGTH + 1];
+        block[..chunk.len()].copy_from_slice(chunk);
+        block[chunk.len()] = 1;
+
+        h0 += read_u32(&block[0..]) & LIMB_MASK;
+        h1 += (read_u32(&block[3..]) >> 2) & LIMB_MASK;
+        h2 += (read_u32(&block[6..]) >> 4) & LIMB_MASK;
+        h3 += (read_u32(&block[9..]) >> 6) & LIMB_MASK;
+        h4 += (read_u32(&block[12..]) >> 8) | (block[16] as u32) << 24;
+
+        let (g0, g1, g2, g3, g4) = (h0 as u64, h1 as u64, h2 as u64, h3 as u64, h4 as u64);
+        let d0 = g0 * r0 + g1 * s4 + g2 * s3 + g3 * s2 + g4 * s1;
+        let d1 = g0 * r1 + g1 * r0 + g2 * s4 + g3 * s3 + g4 * s2;
+        let d2 = g0 * r2 + g1 * r1 + g2 * r0 + g3 * s4 + g4 * s3;
+        let d3 = g0 * r3 + g1 * r2 + g2 * r1 + g3 * r0 + g4 * s4;
+        let d4 = g0 * r4 + g1 * r3 + g2 * r2 + g3 * r1 + g4 * r0;
+
+        let d1 = d1 + (d0 >> 26);
+        let d2 = d2 + (d1 >> 26);
+        let d3 = d3 + (d2 >> 26);
+        let d4 = d4 + (d3 >> 26);
+        h0 = d0 as u32 & LIMB_MASK;
+        h1 = d1 as u32 & LIMB_MASK;
+        h2 = d2 as u32 & LIMB_MASK;
+        h3 = d3 as u32 & LIMB_MASK;
+        h4 = d4 as u32 & LIMB_MASK;
+        h0 += (d4 >> 26) as u32 * 5;
+        h1 += h0 >> 26;
+        h0 &= LIMB_MASK;
+    }
+
+    // Fully carry h
+    h2 += h1 >> 26;
+    h1 &= LIMB_MASK;
+    h3 += h2 >> 26;
+    h2 &= LIMB_MASK;
+    h4 += h3 >> 26;
+    h3 &= LIMB_MASK;
+    h0 += (h4 >> 26) * 5;
+    h4 &= LIMB_MASK;
+    h1 += h0 >> 26;
+    h0 &= LIMB_MASK;
+
+    // g = h - p, used if h >= p
+    let mut g0 = h0.wrapping_add(5);
+    let mut g1 = h1.wrapping_add(g0 >> 26);
+    g0 &= LIMB_MASK;
+    let mut g2 = h2.wrapping_add(g1 >> 26);
+    g1 &= LIMB_MASK;
+    let mut g3 = h3.wrapping_add(g2 >> 26);
+    g2 &= LIMB_MASK;
+    let g4 = h4.wrapping_add(g3 >> 26).wrapping_sub(1 << 26);
+    g3 &= LIMB_MASK;
+
+    // All ones if g didn't go negative, in constant time
+    let mask = (g4 >> 31).wrapping_sub(1);
+    h0 = (h0 & !mask) | (g0 & mask);
+    h1 = (h1 & !mask) | (g1 & mask);
+    h2 = (h2 & !mask) | (g2 & mask);
+    h3 = (h3 & !mask) | (g3 & mask);
+    h4 = (h4 & !mask) | (g4 & mask);
+
+    // Back to 32-bit words, then add s modulo 2^128
+    let words = [h0 | h1 << 26, h1 >> 6 | h2 << 20, h2 >> 12 | h3 << 14, h3 >> 18 | h4 << 8];
+    let mut tag = Vec::with_capacity(TAG_LENGTH);
+    let mut carry = 0u64;
+    for (i, word) in words.iter().enumerate() {
+        let sum = *word as u64 + read_u32(&key[16 + 4 * i..]) as u64 + carry;
+        carry = sum >> 32;
+        for j in 0..4 {
+            tag.push((sum >> (8 * j)) as u8);
+        }
+    }
+
+    Ok(tag)
+}
+
+/// Compares two tags without giving away where they differ
+pub fn tags_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_poly1305() {
+        // RFC 7539, section 2.5.2
+        let key = [0x85, 0xd6, 0xbe, 0x78, 0x57, 0x55, 0x6d, 0x33, 0x7f, 0x44, 0x52, 0xfe, 0x42,
+                   0xd5, 0x06, 0xa8, 0x01, 0x03, 0x80, 0x8a, 0xfb, 0x0d, 0xb2, 0xfd, 0x4a, 0xbf,
+                   0xf6, 0xaf, 0x41, 0x49, 0xf5, 0x1b];
+        let expected = [0xa8, 0x06, 0x1d, 0xc1, 0x30, 0x51, 0x36, 0xc6, 0xc2, 0x2b, 0x8b, 0xaf,
+                        0x0c, 0x01, 0x27, 0xa9];
+        assert_eq!(expected.to_vec(),
+                   poly1305(&key, b"Cryptographic Forum Research Group").unwrap());
+    }
+
+    #[test]
+    fn test_tags_equal() {
+        assert!(tags_equal(&[1, 2, 3], &[1, 2, 3]));
+        assert!(!tags_equal(&[1, 2, 3], &[1, 2, 4]));
+        assert!(!tags_equal(&[1, 2, 3], &[1, 2]));
+    }
+}
@@ -0,0 +1,45 @@
+use gcrypt::sexp::SExpression;
+use i2p::error::Error;
+
+/// Builds a canonical S-expression, so binary key material can be handed to
+/// libgcrypt without any escaping.
+pub struct SExpressionBuilder {
+    buffer: Vec<u8>,
+}
+
+impl SExpressionBuilder {
+    pub fn new() -> SExpressionBuilder {
+        SExpressionBuilder { buffer: Vec::new() }
+    }
+
+    pub fn open(mut self) -> SExpressionBuilder {
+        self.buffer.push(b'(');
+        self
+    }
+
+    pub fn close(mut self) -> SExpressionBuilder {
+        self.buffer.push(b')');
+        self
+    }
+
+    pub fn atom(mut self, data: &[u8]) -> SExpressionBuilder {
+        self.buffer.extend_from_slice(format!("{}:", data.len()).as_bytes());
+        self.buffer.extend_from_slice(data);
+        self
+    }
+
+    /// Shorthand for `(name value)`
+    pub fn pair(self, name: &str, value: &[u8]) -> SExpressionBuilder {
+        self.open().atom(name.as_bytes()).atom(value).close()
+    }
+
+    pub fn build(self) -> Result<SExpression, Error> {
+        Ok(SExpression::from_bytes(&self.buffer)?)
+    }
+}
+
+impl Default for SExpressionBuilder {
+    fn default() -> SExpressionBuilder {
+        SExpressionBuilder::new()
+    }
+}
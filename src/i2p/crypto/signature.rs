@@ -0,0 +1,754 @@
+use gcrypt;
+use gcrypt::pkey;
+use gcrypt::sexp::SExpression;
+use i2p::crypto;
+use i2p::crypto::sexp::SExpressionBuilder;
+use i2p::data::crypto::{SigningPrivateKey, SigningPublicKey, SigningPublicKeyType};
+use i2p::error::Error;
+
+pub const DSA_P: [u8; 128] =
+    [0x9c, 0x05, 0xb2, 0xaa, 0x96, 0x0d, 0x9b, 0x97, 0xb8, 0x93, 0x19, 0x63, 0xc9, 0xcc, 0x9e,
+     0x8c, 0x30, 0x26, 0xe9, 0xb8, 0xed, 0x92, 0xfa, 0xd0, 0xa6, 0x9c, 0xc8, 0x86, 0xd5, 0xbf,
+     0x80, 0x15, 0xfc, 0xad, 0xae, 0x31, 0xa0, 0xad, 0x18, 0xfa, 0xb3, 0xf0, 0x1b, 0x00, 0xa3,
+     0x58, 0xde, 0x23, 0x76, 0x55, 0xc4, 0x96, 0x4a, 0xfa, 0xa2, 0xb3, 0x37, 0xe9, 0x6a, 0xd3,
+     0x16, 0xb9, 0xfb, 0x1c, 0xc5, 0x64, 0xb5, 0xae, 0xc5, 0xb6, 0x9a, 0x9f, 0xf6, 0xc3, 0xe4,
+     0x54, 0x87, 0x07, 0xfe, 0xf8, 0x50, 0x3d, 0x91, 0xdd, 0x86, 0x02, 0xe8, 0x67, 0xe6, 0xd3,
+     0x5d, 0x22, 0x35, 0xc1, 0x86, 0x9c, 0xe2, 0x47, 0x9c, 0x3b, 0x9d, 0x54, 0x01, 0xde, 0x04,
+     0xe0, 0x72, 0x7f, 0xb3, 0x3d, 0x65, 0x11, 0x28, 0x5d, 0x4c, 0xf2, 0x95, 0x38, 0xd9, 0xe3,
+     0xb6, 0x05, 0x1f, 0x5b, 0x22, 0xcc, 0x1c, 0x93];
+
+pub const DSA_Q: [u8; 20] = [0xa5, 0xdf, 0xc2, 0x8f, 0xef, 0x4c, 0xa1, 0xe2, 0x86, 0x74, 0x4c,
+                             0xd8, 0xee, 0xd9, 0xd2, 0x9d, 0x68, 0x40, 0x46, 0xb7];
+
+pub const DSA_G: [u8; 128] =
+    [0x0c, 0x1f, 0x4d, 0x27, 0xd4, 0x00, 0x93, 0xb4, 0x29, 0xe9, 0x62, 0xd7, 0x22, 0x38, 0x24,
+     0xe0, 0xbb, 0xc4, 0x7e, 0x7c, 0x83, 0x2a, 0x39, 0x23, 0x6f, 0xc6, 0x83, 0xaf, 0x84, 0x88,
+     0x95, 0x81, 0x07, 0x5f, 0xf9, 0x08, 0x2e, 0xd3, 0x23, 0x53, 0xd4, 0x37, 0x4d, 0x73, 0x01,
+     0xcd, 0xa1, 0xd2, 0x3c, 0x43, 0x1f, 0x46, 0x98, 0x59, 0x9d, 0xda, 0x02, 0x45, 0x18, 0x24,
+     0xff, 0x36, 0x97, 0x52, 0x59, 0x36, 0x47, 0xcc, 0x3d, 0xdc, 0x19, 0x7d, 0xe9, 0x85, 0xe4,
+     0x3d, 0x13, 0x6c, 0xdc, 0xfc, 0x6b, 0xd5, 0x40, 0x9c, 0xd2, 0xf4, 0x50, 0x82, 0x11, 0x42,
+     0xa5, 0xe6, 0xf8, 0xeb, 0x1c, 0x3a, 0xb5, 0xd0, 0x48, 0x4b, 0x81, 0x29, 0xfc, 0xf1, 0x7b,
+     0xce, 0x4f, 0x7f, 0x33, 0x32, 0x1c, 0x3c, 0xb3, 0xdb, 0xb1, 0x4a, 0x90, 0x5e, 0x7b, 0x2b,
+     0x3e, 0x93, 0xbe, 0x47, 0x08, 0xcb, 0xcc, 0x82];
+
+const RSA_PUBLIC_EXPONENT: [u8; 3] = [0x01, 0x00, 0x01];
+
+/// Checks signatures made with a particular signing public key
+pub trait Verifier {
+    /// Returns `Ok(false)` if the signature doesn't match, and an error only
+    /// if the signature couldn't be checked at all.
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<bool, Error>;
+}
+
+/// Produces signatures with a particular signing private key
+pub trait Signer {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Digest {
+    SHA1,
+    SHA256,
+    SHA384,
+    SHA512,
+}
+
+impl Digest {
+    fn name(&self) -> &str {
+        match *self {
+            Digest::SHA1 => "sha1",
+            Digest::SHA256 => "sha256",
+            Digest::SHA384 => "sha384",
+            Digest::SHA512 => "sha512",
+        }
+    }
+
+    fn digest(&self, message: &[u8]) -> Vec<u8> {
+        match *self {
+            Digest::SHA1 => crypto::sha1(message),
+            Digest::SHA256 => crypto::sha256(message),
+            Digest::SHA384 => crypto::sha384(message),
+            Digest::SHA512 => crypto::sha512(message),
+        }
+    }
+}
+
+pub fn verifier(key_type: &SigningPublicKeyType, key: &[u8]) -> Result<Box<dyn Verifier>, Error> {
+    if key.len() != SigningPublicKey::length(key_type) {
+        return Err(Error::Crypto(format!("Expected signing public key of length {}, got one of \
+                                          length {}",
+                                         SigningPublicKey::length(key_type),
+                                         key.len())));
+    }
+
+    let verifier: Box<dyn Verifier> = match *key_type {
+        SigningPublicKeyType::DSA_SHA1 => {
+            Box::new(DSAVerifier { public_key: dsa_public_key(key)? })
+        }
+        SigningPublicKeyType::ECDSA_SHA256_P256 => {
+            Box::new(ECDSAVerifier::new("NIST P-256", Digest::SHA256, key)?)
+        }
+        SigningPublicKeyType::ECDSA_SHA384_P384 => {
+            Box::new(ECDSAVerifier::new("NIST P-384", Digest::SHA384, key)?)
+        }
+        SigningPublicKeyType::ECDSA_SHA512_P521 => {
+            Box::new(ECDSAVerifier::new("NIST P-521", Digest::SHA512, key)?)
+        }
+        SigningPublicKeyType::RSA_SHA256_2048 => Box::new(RSAVerifier::new(Digest::SHA256, key)?),
+        SigningPublicKeyType::RSA_SHA384_3072 => Box::new(RSAVerifier::new(Digest::SHA384, key)?),
+        SigningPublicKeyType::RSA_SHA512_4096 => Box::new(RSAVerifier::new(Digest::SHA512, key)?),
+        SigningPublicKeyType::EdDSA_SHA512_Ed25519 => Box::new(EdDSAVerifier::new(false, key)?),
+        SigningPublicKeyType::EdDSA_SHA512_Ed25519ph => Box::new(EdDSAVerifier::new(true, key)?),
+        // RedDSA signatures verify just like Ed25519 ones
+        SigningPublicKeyType::RedDSA_SHA512_Ed25519 => Box::new(EdDSAVerifier::new(false, key)?),
+    };
+
+    Ok(verifier)
+}
+
+/// libgcrypt needs the public half of the key pair to sign, so we take both
+pub fn signer(private_key: &SigningPrivateKey, public_key: &[u8]) -> Result<Box<dyn Signer>, Error> {
+    let key_type = private_key.get_type();
+    if public_key.len() != SigningPublicKey::length(&key_type) {
+        return Err(Error::Crypto(format!("Signing public key doesn't match private key type \
+                                          {:?}",
+                                         key_type)));
+    }
+
+    let key = private_key.data();
+    let signer: Box<dyn Signer> = match key_type {
+        SigningPublicKeyType::DSA_SHA1 => {
+            Box::new(DSASigner { private_key: dsa_private_key(public_key, key)? })
+        }
+        SigningPublicKeyType::ECDSA_SHA256_P256 => {
+            Box::new(ECDSASigner::new("NIST P-256", Digest::SHA256, 32, public_key, key)?)
+        }
+        SigningPublicKeyType::ECDSA_SHA384_P384 => {
+            Box::new(ECDSASigner::new("NIST P-384", Digest::SHA384, 48, public_key, key)?)
+        }
+        SigningPublicKeyType::ECDSA_SHA512_P521 => {
+            Box::new(ECDSASigner::new("NIST P-521", Digest::SHA512, 66, public_key, key)?)
+        }
+        SigningPublicKeyType::RSA_SHA256_2048 => Box::new(RSASigner::new(Digest::SHA256, key)?),
+        SigningPublicKeyType::RSA_SHA384_3072 => Box::new(RSASigner::new(Digest::SHA384, key)?),
+        SigningPublicKeyType::RSA_SHA512_4096 => Box::new(RSASigner::new(Digest::SHA512, key)?),
+        SigningPublicKeyType::EdDSA_SHA512_Ed25519 => {
+            Box::new(EdDSASigner::new(false, public_key, key)?)
+        }
+        SigningPublicKeyType::EdDSA_SHA512_Ed25519ph => {
+            Box::new(EdDSASigner::new(true, public_key, key)?)
+        }
+        // libgcrypt derives the Ed25519 scalar from a seed, but a RedDSA
+        // private key is the scalar itself
+        SigningPublicKeyType::RedDSA_SHA512_Ed25519 => {
+            return Err(Error::Crypto("RedDSA signing isn't supported".to_string()))
+        }
+    };
+
+    Ok(signer)
+}
+
+/// Generates a new signing key pair. We don't generate new DSA keys, since
+/// that type is deprecated.
+pub fn generate_keypair(key_type: &SigningPublicKeyType)
+                        -> Result<(SigningPrivateKey, SigningPublicKey), Error> {
+    let (private_key, public_key) = match *key_type {
+        SigningPublicKeyType::DSA_SHA1 => {
+            return Err(Error::Crypto("Generating DSA signing keys isn't supported".to_string()))
+        }
+        SigningPublicKeyType::ECDSA_SHA256_P256 => generate_ecdsa_keypair("NIST P-256", 32)?,
+        SigningPublicKeyType::ECDSA_SHA384_P384 => generate_ecdsa_keypair("NIST P-384", 48)?,
+        SigningPublicKeyType::ECDSA_SHA512_P521 => generate_ecdsa_keypair("NIST P-521", 66)?,
+        SigningPublicKeyType::RSA_SHA256_2048 => generate_rsa_keypair(2048)?,
+        SigningPublicKeyType::RSA_SHA384_3072 => generate_rsa_keypair(3072)?,
+        SigningPublicKeyType::RSA_SHA512_4096 => generate_rsa_keypair(4096)?,
+        SigningPublicKeyType::EdDSA_SHA512_Ed25519 |
+        SigningPublicKeyType::EdDSA_SHA512_Ed25519ph => generate_eddsa_keypair()?,
+        SigningPublicKeyType::RedDSA_SHA512_Ed25519 => {
+            return Err(Error::Crypto("Generating RedDSA signing keys isn't supported".to_string()))
+        }
+    };
+
+    Ok((SigningPrivateKey::new(key_type, &private_key)?,
+        SigningPublicKey::new(key_type.clone(), &public_key)))
+}
+
+fn generate_ecdsa_keypair(curve: &str, length: usize) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let parameters = SExpressionBuilder::new()
+        .open()
+        .atom(b"genkey")
+        .open()
+        .atom(b"ecc")
+        .pair("curve", curve.as_bytes())
+        .close()
+        .close()
+        .build()?;
+    let key = pkey::generate_key(&parameters)?;
+    // Strip the 0x04 uncompressed point marker
+    let q = find_value(&key, "q")?;
+    let d = find_value(&key, "d")?;
+
+    Ok((crypto::left_pad(&d, length)?, q[1..].to_vec()))
+}
+
+fn generate_rsa_keypair(bits: usize) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let parameters = SExpressionBuilder::new()
+        .open()
+        .atom(b"genkey")
+        .open()
+        .atom(b"rsa")
+        .pair("nbits", bits.to_string().as_bytes())
+        .pair("rsa-use-e", b"65537")
+        .close()
+        .close()
+        .build()?;
+    let key = pkey::generate_key(&parameters)?;
+    let n = crypto::left_pad(&find_value(&key, "n")?, bits / 8)?;
+    let mut private_key = n.clone();
+    private_key.extend(crypto::left_pad(&find_value(&key, "d")?, bits / 8)?);
+
+    Ok((private_key, n))
+}
+
+fn generate_eddsa_keypair() -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let parameters = SExpressionBuilder::new()
+        .open()
+        .atom(b"genkey")
+        .open()
+        .atom(b"ecc")
+        .pair("curve", b"Ed25519")
+        .pair("flags", b"eddsa")
+        .close()
+        .close()
+        .build()?;
+    let key = pkey::generate_key(&parameters)?;
+    let mut q = find_value(&key, "q")?;
+    // libgcrypt may prefix the compressed point with 0x40
+    if q.len() == 33 {
+        q.remove(0);
+    }
+    let d = find_value(&key, "d")?;
+
+    Ok((crypto::left_pad(&d, 32)?, q))
+}
+
+struct DSAVerifier {
+    public_key: SExpression,
+}
+
+impl Verifier for DSAVerifier {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<bool, Error> {
+        check_signature(&self.public_key,
+                        &split_signature("dsa", signature)?,
+                        &raw_data(&Digest::SHA1.digest(message))?)
+    }
+}
+
+struct DSASigner {
+    private_key: SExpression,
+}
+
+impl Signer for DSASigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let signature = pkey::sign(&self.private_key,
+                                   &raw_data(&Digest::SHA1.digest(message))?)?;
+        join_signature(&signature, 20)
+    }
+}
+
+struct ECDSAVerifier {
+    digest: Digest,
+    public_key: SExpression,
+}
+
+impl ECDSAVerifier {
+    fn new(curve: &str, digest: Digest, key: &[u8]) -> Result<ECDSAVerifier, Error> {
+        Ok(ECDSAVerifier {
+            digest,
+            public_key: ecdsa_public_key(curve, key)?,
+        })
+    }
+}
+
+impl Verifier for ECDSAVerifier {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<bool, Error> {
+        check_signature(&self.public_key,
+                        &split_signature("ecdsa", signature)?,
+                        &raw_data(&self.digest.digest(message))?)
+    }
+}
+
+struct ECDSASigner {
+    digest: Digest,
+    component_length: usize,
+    private_key: SExpression,
+}
+
+impl ECDSASigner {
+    fn new(curve: &str,
+           digest: Digest,
+           component_length: usize,
+           public_key: &[u8],
+           key: &[u8])
+           -> Result<ECDSASigner, Error> {
+        Ok(ECDSASigner {
+            digest,
+            component_length,
+            private_key: ecdsa_private_key(curve, public_key, key)?,
+        })
+    }
+}
+
+impl Signer for ECDSASigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let signature = pkey::sign(&self.private_key, &raw_data(&self.digest.digest(message))?)?;
+        join_signature(&signature, self.component_length)
+    }
+}
+
+struct RSAVerifier {
+    digest: Digest,
+    public_key: SExpression,
+}
+
+impl RSAVerifier {
+    fn new(digest: Digest, key: &[u8]) -> Result<RSAVerifier, Error> {
+        Ok(RSAVerifier {
+            digest,
+            public_key: rsa_public_key(key)?,
+        })
+    }
+}
+
+impl Verifier for RSAVerifier {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<bool, Error> {
+        check_signature(&self.public_key,
+                        &rsa_signature(signature)?,
+                        &pkcs1_data(self.digest, message)?)
+    }
+}
+
+struct RSASigner {
+    digest: Digest,
+    length: usize,
+    private_key: SExpression,
+}
+
+impl RSASigner {
+    /// I2P stores RSA private keys as the modulus followed by the private exponent
+    fn new(digest: Digest, key: &[u8]) -> Result<RSASigner, Error> {
+        let (n, d) = key.split_at(key.len() / 2);
+        Ok(RSASigner {
+            digest,
+            length: n.len(),
+            private_key: rsa_private_key(n, d)?,
+        })
+    }
+}
+
+impl Signer for RSASigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let signature = pkey::sign(&self.private_key, &pkcs1_data(self.digest, message)?)?;
+        crypto::left_pad(&find_value(&signature, "s")?, self.length)
+    }
+}
+
+/// I2P's Ed25519ph signs the SHA-512 hash of the message rather than the message
+struct EdDSAVerifier {
+    prehash: bool,
+    public_key: SExpression,
+}
+
+impl EdDSAVerifier {
+    fn new(prehash: bool, key: &[u8]) -> Result<EdDSAVerifier, Error> {
+        Ok(EdDSAVerifier {
+            prehash,
+            public_key: eddsa_public_key(key)?,
+        })
+    }
+}
+
+impl Verifier for EdDSAVerifier {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<bool, Error> {
+        check_signature(&self.public_key,
+                        &split_signature("eddsa", signature)?,
+                        &eddsa_data(self.prehash, message)?)
+    }
+}
+
+struct EdDSASigner {
+    prehash: bool,
+    private_key: SExpression,
+}
+
+impl EdDSASigner {
+    fn new(prehash: bool, public_key: &[u8], key: &[u8]) -> Result<EdDSASigner, Error> {
+        Ok(EdDSASigner {
+            prehash,
+            private_key: eddsa_private_key(public_key, key)?,
+        })
+    }
+}
+
+impl Signer for EdDSASigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let signature = pkey::sign(&self.private_key, &eddsa_data(self.prehash, message)?)?;
+        join_signature(&signature, 32)
+    }
+}
+
+fn check_signature(public_key: &SExpression,
+                   signature: &SExpression,
+                   data: &SExpression)
+                   -> Result<bool, Error> {
+    match pkey::verify(public_key, data, signature) {
+        Ok(()) => Ok(true),
+        Err(ref error) if error.code() == gcrypt::Error::BAD_SIGNATURE.code() => Ok(false),
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn find_value(sexp: &SExpression, name: &str) -> Result<Vec<u8>, Error> {
+    match sexp.find_token(name) {
+        Some(token) => {
+            match token.get_bytes(1) {
+                Some(bytes) => Ok(bytes.to_vec()),
+                None => Err(Error::Crypto(format!("Empty value for {} in S-expression", name))),
+            }
+        }
+        None => Err(Error::Crypto(format!("Value {} not found in S-expression", name))),
+    }
+}
+
+fn join_signature(signature: &SExpression, component_length: usize) -> Result<Vec<u8>, Error> {
+    let mut result = crypto::left_pad(&find_value(signature, "r")?, component_length)?;
+    result.extend(crypto::left_pad(&find_value(signature, "s")?, component_length)?);
+
+    Ok(result)
+}
+
+fn dsa_public_key(y: &[u8]) -> Result<SExpression, Error> {
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"public-key")
+        .open()
+        .atom(b"dsa")
+        .pair("p", &DSA_P)
+        .pair("q", &DSA_Q)
+        .pair("g", &DSA_G)
+        .pair("y", y)
+        .close()
+        .close()
+        .build()
+}
+
+fn dsa_private_key(y: &[u8], x: &[u8]) -> Result<SExpression, Error> {
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"private-key")
+        .open()
+        .atom(b"dsa")
+        .pair("p", &DSA_P)
+        .pair("q", &DSA_Q)
+        .pair("g", &DSA_G)
+        .pair("y", y)
+        .pair("x", x)
+        .close()
+        .close()
+        .build()
+}
+
+fn ecdsa_public_key(curve: &str, key: &[u8]) -> Result<SExpression, Error> {
+    // libgcrypt wants the uncompressed point format
+    let mut q = vec![0x04u8];
+    q.extend_from_slice(key);
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"public-key")
+        .open()
+        .atom(b"ecc")
+        .pair("curve", curve.as_bytes())
+        .pair("q", &q)
+        .close()
+        .close()
+        .build()
+}
+
+fn ecdsa_private_key(curve: &str, public_key: &[u8], d: &[u8]) -> Result<SExpression, Error> {
+    let mut q = vec![0x04u8];
+    q.extend_from_slice(public_key);
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"private-key")
+        .open()
+        .atom(b"ecc")
+        .pair("curve", curve.as_bytes())
+        .pair("q", &q)
+        .pair("d", d)
+        .close()
+        .close()
+        .build()
+}
+
+fn rsa_public_key(n: &[u8]) -> Result<SExpression, Error> {
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"public-key")
+        .open()
+        .atom(b"rsa")
+        .pair("n", n)
+        .pair("e", &RSA_PUBLIC_EXPONENT)
+        .close()
+        .close()
+        .build()
+}
+
+fn rsa_private_key(n: &[u8], d: &[u8]) -> Result<SExpression, Error> {
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"private-key")
+        .open()
+        .atom(b"rsa")
+        .pair("n", n)
+        .pair("e", &RSA_PUBLIC_EXPONENT)
+        .pair("d", d)
+        .close()
+        .close()
+        .build()
+}
+
+fn eddsa_public_key(key: &[u8]) -> Result<SExpression, Error> {
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"public-key")
+        .open()
+        .atom(b"ecc")
+        .pair("curve", b"Ed25519")
+        .pair("flags", b"eddsa")
+        .pair("q", key)
+        .close()
+        .close()
+        .build()
+}
+
+fn eddsa_private_key(public_key: &[u8], d: &[u8]) -> Result<SExpression, Error> {
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"private-key")
+        .open()
+        .atom(b"ecc")
+        .pair("curve", b"Ed25519")
+        .pair("flags", b"eddsa")
+        .pair("q", public_key)
+        .pair("d", d)
+        .close()
+        .close()
+        .build()
+}
+
+/// DSA, ECDSA and EdDSA signatures are all r || s, each half the signature length
+fn split_signature(algorithm: &str, signature: &[u8]) -> Result<SExpression, Error> {
+    let (r, s) = signature.split_at(signature.len() / 2);
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"sig-val")
+        .open()
+        .atom(algorithm.as_bytes())
+        .pair("r", r)
+        .pair("s", s)
+        .close()
+        .close()
+        .build()
+}
+
+fn rsa_signature(signature: &[u8]) -> Result<SExpression, Error> {
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"sig-val")
+        .open()
+        .atom(b"rsa")
+        .pair("s", signature)
+        .close()
+        .close()
+        .build()
+}
+
+fn raw_data(digest: &[u8]) -> Result<SExpression, Error> {
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"data")
+        .pair("flags", b"raw")
+        .pair("value", digest)
+        .close()
+        .build()
+}
+
+fn pkcs1_data(digest: Digest, message: &[u8]) -> Result<SExpression, Error> {
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"data")
+        .pair("flags", b"pkcs1")
+        .open()
+        .atom(b"hash")
+        .atom(digest.name().as_bytes())
+        .atom(&digest.digest(message))
+        .close()
+        .close()
+        .build()
+}
+
+fn eddsa_data(prehash: bool, message: &[u8]) -> Result<SExpression, Error> {
+    let value = if prehash {
+        Digest::SHA512.digest(message)
+    } else {
+        message.to_vec()
+    };
+    SExpressionBuilder::new()
+        .open()
+        .atom(b"data")
+        .pair("flags", b"eddsa")
+        .pair("hash-algo", b"sha512")
+        .pair("value", &value)
+        .close()
+        .build()
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(non_snake_case)]
+
+    use i2p::data::crypto::{SigningPrivateKey, SigningPublicKeyType};
+    use i2p::test_util::read_fixture_lines;
+    use super::*;
+
+    struct SignatureFixture {
+        public_key: Vec<u8>,
+        private_key: Vec<u8>,
+        message: Vec<u8>,
+        signature: Vec<u8>,
+    }
+
+    fn read_signature_fixture(name: &str) -> SignatureFixture {
+        let values = read_fixture_lines(&format!("{}_Signature", name));
+
+        SignatureFixture {
+            public_key: values[0].clone(),
+            private_key: values[1].clone(),
+            message: values[2].clone(),
+            signature: values[3].clone(),
+        }
+    }
+
+    /// Checks the fixture signature, then signs the fixture message ourselves and
+    /// checks that. EdDSA is deterministic, so there we expect the exact signature.
+    ///
+    /// The Ed25519 and ECDSA fixtures are the RFC 8032 and RFC 6979 test
+    /// vectors. Nobody publishes vectors for I2P's DSA group, its Ed25519ph or
+    /// its RSA keys, so those were made with OpenSSL.
+    fn check_signature_fixture(name: &str, key_type: SigningPublicKeyType, deterministic: bool) {
+        let fixture = read_signature_fixture(name);
+
+        let verifier = verifier(&key_type, &fixture.public_key).unwrap();
+        assert!(verifier.verify(&fixture.message, &fixture.signature).unwrap());
+
+        let mut bad_message = fixture.message.clone();
+        bad_message[0] ^= 0x01;
+        assert!(!verifier.verify(&bad_message, &fixture.signature).unwrap());
+
+        let private_key = SigningPrivateKey::new(&key_type, &fixture.private_key).unwrap();
+        let signer = signer(&private_key, &fixture.public_key).unwrap();
+        let signature = signer.sign(&fixture.message).unwrap();
+        assert_eq!(fixture.signature.len(), signature.len());
+        assert!(verifier.verify(&fixture.message, &signature).unwrap());
+        if deterministic {
+            assert_eq!(fixture.signature, signature);
+        }
+    }
+
+    #[test]
+    fn test_DSA_SHA1_signature() {
+        check_signature_fixture("DSA_SHA1", SigningPublicKeyType::DSA_SHA1, false);
+    }
+
+    #[test]
+    fn test_ECDSA_SHA256_P256_signature() {
+        check_signature_fixture("ECDSA_SHA256_P256",
+                                SigningPublicKeyType::ECDSA_SHA256_P256,
+                                false);
+    }
+
+    #[test]
+    fn test_ECDSA_SHA384_P384_signature() {
+        check_signature_fixture("ECDSA_SHA384_P384",
+                                SigningPublicKeyType::ECDSA_SHA384_P384,
+                                false);
+    }
+
+    #[test]
+    fn test_ECDSA_SHA512_P521_signature() {
+        check_signature_fixture("ECDSA_SHA512_P521",
+                                SigningPublicKeyType::ECDSA_SHA512_P521,
+                                false);
+    }
+
+    #[test]
+    fn test_RSA_SHA256_2048_signature() {
+        // PKCS#1 v1.5 signatures are deterministic too
+        check_signature_fixture("RSA_SHA256_2048", SigningPublicKeyType::RSA_SHA256_2048, true);
+    }
+
+    #[test]
+    fn test_RSA_SHA384_3072_signature() {
+        check_signature_fixture("RSA_SHA384_3072", SigningPublicKeyType::RSA_SHA384_3072, true);
+    }
+
+    #[test]
+    fn test_RSA_SHA512_4096_signature() {
+        check_signature_fixture("RSA_SHA512_4096", SigningPublicKeyType::RSA_SHA512_4096, true);
+    }
+
+    #[test]
+    fn test_EdDSA_SHA512_Ed25519_signature() {
+        check_signature_fixture("EdDSA_SHA512_Ed25519",
+                                SigningPublicKeyType::EdDSA_SHA512_Ed25519,
+                                true);
+    }
+
+    #[test]
+    fn test_EdDSA_SHA512_Ed25519ph_signature() {
+        check_signature_fixture("EdDSA_SHA512_Ed25519ph",
+                                SigningPublicKeyType::EdDSA_SHA512_Ed25519ph,
+                                true);
+    }
+
+    #[test]
+    fn test_generated_keypairs() {
+        let key_types = [SigningPublicKeyType::ECDSA_SHA256_P256,
+                         SigningPublicKeyType::ECDSA_SHA512_P521,
+                         SigningPublicKeyType::RSA_SHA256_2048,
+                         SigningPublicKeyType::EdDSA_SHA512_Ed25519,
+                         SigningPublicKeyType::EdDSA_SHA512_Ed25519ph];
+        for key_type in key_types.iter() {
+            let (private_key, public_key) = generate_keypair(key_type).unwrap();
+            let signature = private_key.sign(&public_key, b"message").unwrap();
+            assert!(public_key.verify(b"message", &signature).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_generate_DSA_keypair_unsupported() {
+        assert!(generate_keypair(&SigningPublicKeyType::DSA_SHA1).is_err());
+    }
+
+    #[test]
+    fn test_verify_with_broken_key() {
+        // A failed check is an error, not just a bad signature
+        let verifier = verifier(&SigningPublicKeyType::ECDSA_SHA256_P256, &[0u8; 64]).unwrap();
+        assert!(verifier.verify(b"message", &[1u8; 64]).is_err());
+    }
+
+    #[test]
+    fn test_verifier_rejects_wrong_key_length() {
+        assert!(verifier(&SigningPublicKeyType::EdDSA_SHA512_Ed25519, &[0u8; 31]).is_err());
+    }
+}
@@ -1,47 +1,82 @@
 #![allow(non_camel_case_types)]
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::crypto;
+use i2p::crypto::signature;
+use i2p::data::encoding::B32Address;
 use i2p::error::Error;
 use rand::{OsRng, Rand, Rng};
-use std::io::{self, Read, Write};
+use std::io::{Read, Write};
 use std::str;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum PublicKey {
-    ElGamal(Box<[u8]>),
+    ElGamal(Box<[u8]>), // length = 256
+    X25519(Box<[u8]>), // length = 32
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum PublicKeyType {
-    ElGamal,
+    ElGamal = 0,
+    X25519 = 4,
 }
 
 impl PublicKeyType {
     pub fn from_u16(t: u16) -> Result<PublicKeyType, Error> {
         match t {
             t if t == PublicKeyType::ElGamal as u16 => Ok(PublicKeyType::ElGamal),
-            _ => Err(Error::Crypto(format!("Unknown public key type"))),
+            t if t == PublicKeyType::X25519 as u16 => Ok(PublicKeyType::X25519),
+            _ => Err(Error::Crypto("Unknown public key type".to_string())),
+        }
+    }
+
+    pub fn length(&self) -> usize {
+        match *self {
+            PublicKeyType::ElGamal => 256,
+            PublicKeyType::X25519 => 32,
         }
     }
 }
 
 impl PublicKey {
+    pub fn new(key_type: &PublicKeyType, data: &[u8]) -> Result<PublicKey, Error> {
+        if data.len() != key_type.length() {
+            return Err(Error::Crypto(format!("Expected public key of length {}, got one of \
+                                              length {}",
+                                             key_type.length(),
+                                             data.len())));
+        }
+
+        let data = data.to_vec().into_boxed_slice();
+        Ok(match *key_type {
+            PublicKeyType::ElGamal => PublicKey::ElGamal(data),
+            PublicKeyType::X25519 => PublicKey::X25519(data),
+        })
+    }
+
     pub fn get_type(&self) -> PublicKeyType {
         match *self {
             PublicKey::ElGamal(_) => PublicKeyType::ElGamal,
+            PublicKey::X25519(_) => PublicKeyType::X25519,
         }
     }
 
     pub fn length(&self) -> usize {
-        let PublicKey::ElGamal(ref data) = *self;
-        data.len()
+        self.data().len()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match *self {
+            PublicKey::ElGamal(ref data) |
+            PublicKey::X25519(ref data) => data,
+        }
     }
 
     pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
-        let PublicKey::ElGamal(ref data) = *self;
-        Ok(writer.write(data.as_ref())?)
+        Ok(writer.write(self.data())?)
     }
 
+    /// Reads an ElGamal key, which is the only type that appears in identities
     pub fn deserialize<R: Read>(reader: &mut R) -> Result<PublicKey, Error> {
         let mut buffer = vec![0u8; 256];
         reader.read_exact(buffer.as_mut_slice())?;
@@ -50,15 +85,60 @@ impl PublicKey {
     }
 }
 
+#[derive(Clone)]
 pub enum PrivateKey {
     ElGamal(Box<[u8]>),
+    X25519(Box<[u8]>),
 }
 
+impl PrivateKey {
+    pub fn get_type(&self) -> PublicKeyType {
+        match *self {
+            PrivateKey::ElGamal(_) => PublicKeyType::ElGamal,
+            PrivateKey::X25519(_) => PublicKeyType::X25519,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match *self {
+            PrivateKey::ElGamal(ref data) |
+            PrivateKey::X25519(ref data) => data,
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        Ok(writer.write(self.data())?)
+    }
+
+    pub fn deserialize<R: Read>(key_type: &PublicKeyType,
+                                reader: &mut R)
+                                -> Result<PrivateKey, Error> {
+        let mut buffer = vec![0u8; key_type.length()];
+        reader.read_exact(buffer.as_mut_slice())?;
+
+        let data = buffer.into_boxed_slice();
+        Ok(match *key_type {
+            PublicKeyType::ElGamal => PrivateKey::ElGamal(data),
+            PublicKeyType::X25519 => PrivateKey::X25519(data),
+        })
+    }
+}
+
+/// The AES-256 key of an ElGamal/AES+SessionTags session
+#[derive(Clone, Debug, PartialEq)]
 pub enum SessionKey {
     ElGamal(Box<[u8]>),
 }
 
-#[derive(Debug)]
+impl SessionKey {
+    pub fn data(&self) -> &[u8] {
+        match *self {
+            SessionKey::ElGamal(ref data) => data,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct SigningPublicKey {
     key_type: SigningPublicKeyType,
     data: Vec<u8>,
@@ -75,6 +155,7 @@ pub enum SigningPublicKeyType {
     RSA_SHA512_4096,
     EdDSA_SHA512_Ed25519,
     EdDSA_SHA512_Ed25519ph,
+    RedDSA_SHA512_Ed25519 = 11,
 }
 
 impl SigningPublicKeyType {
@@ -105,7 +186,10 @@ impl SigningPublicKeyType {
             t if t == SigningPublicKeyType::EdDSA_SHA512_Ed25519ph as u16 => {
                 Ok(SigningPublicKeyType::EdDSA_SHA512_Ed25519ph)
             }
-            _ => Err(Error::Crypto(format!("Unknown signing public key type"))),
+            t if t == SigningPublicKeyType::RedDSA_SHA512_Ed25519 as u16 => {
+                Ok(SigningPublicKeyType::RedDSA_SHA512_Ed25519)
+            }
+            _ => Err(Error::Crypto("Unknown signing public key type".to_string())),
         }
     }
 }
@@ -125,17 +209,33 @@ impl SigningPublicKey {
             SigningPublicKeyType::RSA_SHA384_3072 => 384,
             SigningPublicKeyType::RSA_SHA512_4096 => 512,
             SigningPublicKeyType::EdDSA_SHA512_Ed25519 |
-            SigningPublicKeyType::EdDSA_SHA512_Ed25519ph => 32,
+            SigningPublicKeyType::EdDSA_SHA512_Ed25519ph |
+            SigningPublicKeyType::RedDSA_SHA512_Ed25519 => 32,
         }
     }
 
     pub fn new(key_type: SigningPublicKeyType, data: &[u8]) -> SigningPublicKey {
         SigningPublicKey {
-            key_type: key_type,
+            key_type,
             data: data.to_vec(),
         }
     }
 
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> Result<bool, Error> {
+        if signature.get_type() != self.key_type {
+            return Err(Error::Crypto(format!("Signature type {:?} doesn't match signing key \
+                                              type {:?}",
+                                             signature.get_type(),
+                                             self.key_type)));
+        }
+
+        signature::verifier(&self.key_type, &self.data)?.verify(message, signature.data())
+    }
+
     pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
         Ok(writer.write(&self.data)?)
     }
@@ -144,19 +244,17 @@ impl SigningPublicKey {
                                 reader: &mut R)
                                 -> Result<SigningPublicKey, Error> {
         let (padding_size, _) = SigningPublicKey::padding_size(&key_type)?;
-        let result: Result<Vec<u8>, io::Error> =
-            reader.bytes().skip(padding_size).take(SigningPublicKey::length(&key_type)).collect();
-        if result.is_err() {
-            return Err(Error::from(result.unwrap_err()));
-        }
-        let data = result.unwrap();
-        if data.len() != Self::length(&key_type) {
+        let mut data = Vec::new();
+        reader.by_ref()
+            .take((padding_size + Self::length(&key_type)) as u64)
+            .read_to_end(&mut data)?;
+        if data.len() != padding_size + Self::length(&key_type) {
             Err(Error::Crypto(format!("Expected signing public key of length {}, got one of \
                                        length {}",
                                     Self::length(&key_type),
-                                    data.len())))
+                                    data.len().saturating_sub(padding_size))))
         } else {
-            Ok(SigningPublicKey::new(key_type, &data))
+            Ok(SigningPublicKey::new(key_type, &data[padding_size..]))
         }
     }
 
@@ -164,7 +262,7 @@ impl SigningPublicKey {
         let mut size: i32 = 128 - Self::length(key_type) as i32;
         let mut extra_bytes: i32 = 0;
         if size < 0 {
-            extra_bytes = -(size as i32);
+            extra_bytes = -size;
             size = 0;
         }
 
@@ -182,9 +280,111 @@ pub enum SigningPrivateKey {
     RSA_SHA512_4096(Box<[u8]>), // length = 1024
     EdDSA_SHA512_Ed25519(Box<[u8]>), // length = 32
     EdDSA_SHA512_Ed25519ph(Box<[u8]>), // length = 32
+    RedDSA_SHA512_Ed25519(Box<[u8]>), // length = 32
+}
+
+impl SigningPrivateKey {
+    pub fn new(key_type: &SigningPublicKeyType, data: &[u8]) -> Result<SigningPrivateKey, Error> {
+        if data.len() != SigningPrivateKey::length(key_type) {
+            return Err(Error::Crypto(format!("Expected signing private key of length {}, got \
+                                              one of length {}",
+                                             SigningPrivateKey::length(key_type),
+                                             data.len())));
+        }
+
+        let data = data.to_vec().into_boxed_slice();
+        Ok(match *key_type {
+            SigningPublicKeyType::DSA_SHA1 => SigningPrivateKey::DSA_SHA1(data),
+            SigningPublicKeyType::ECDSA_SHA256_P256 => SigningPrivateKey::ECDSA_SHA256_P256(data),
+            SigningPublicKeyType::ECDSA_SHA384_P384 => SigningPrivateKey::ECDSA_SHA384_P384(data),
+            SigningPublicKeyType::ECDSA_SHA512_P521 => SigningPrivateKey::ECDSA_SHA512_P521(data),
+            SigningPublicKeyType::RSA_SHA256_2048 => SigningPrivateKey::RSA_SHA256_2048(data),
+            SigningPublicKeyType::RSA_SHA384_3072 => SigningPrivateKey::RSA_SHA384_3072(data),
+            SigningPublicKeyType::RSA_SHA512_4096 => SigningPrivateKey::RSA_SHA512_4096(data),
+            SigningPublicKeyType::EdDSA_SHA512_Ed25519 => {
+                SigningPrivateKey::EdDSA_SHA512_Ed25519(data)
+            }
+            SigningPublicKeyType::EdDSA_SHA512_Ed25519ph => {
+                SigningPrivateKey::EdDSA_SHA512_Ed25519ph(data)
+            }
+            SigningPublicKeyType::RedDSA_SHA512_Ed25519 => {
+                SigningPrivateKey::RedDSA_SHA512_Ed25519(data)
+            }
+        })
+    }
+
+    pub fn length(key_type: &SigningPublicKeyType) -> usize {
+        match *key_type {
+            SigningPublicKeyType::DSA_SHA1 => 20,
+            SigningPublicKeyType::ECDSA_SHA256_P256 => 32,
+            SigningPublicKeyType::ECDSA_SHA384_P384 => 48,
+            SigningPublicKeyType::ECDSA_SHA512_P521 => 66,
+            SigningPublicKeyType::RSA_SHA256_2048 => 512,
+            SigningPublicKeyType::RSA_SHA384_3072 => 768,
+            SigningPublicKeyType::RSA_SHA512_4096 => 1024,
+            SigningPublicKeyType::EdDSA_SHA512_Ed25519 |
+            SigningPublicKeyType::EdDSA_SHA512_Ed25519ph |
+            SigningPublicKeyType::RedDSA_SHA512_Ed25519 => 32,
+        }
+    }
+
+    pub fn get_type(&self) -> SigningPublicKeyType {
+        match *self {
+            SigningPrivateKey::DSA_SHA1(_) => SigningPublicKeyType::DSA_SHA1,
+            SigningPrivateKey::ECDSA_SHA256_P256(_) => SigningPublicKeyType::ECDSA_SHA256_P256,
+            SigningPrivateKey::ECDSA_SHA384_P384(_) => SigningPublicKeyType::ECDSA_SHA384_P384,
+            SigningPrivateKey::ECDSA_SHA512_P521(_) => SigningPublicKeyType::ECDSA_SHA512_P521,
+            SigningPrivateKey::RSA_SHA256_2048(_) => SigningPublicKeyType::RSA_SHA256_2048,
+            SigningPrivateKey::RSA_SHA384_3072(_) => SigningPublicKeyType::RSA_SHA384_3072,
+            SigningPrivateKey::RSA_SHA512_4096(_) => SigningPublicKeyType::RSA_SHA512_4096,
+            SigningPrivateKey::EdDSA_SHA512_Ed25519(_) => {
+                SigningPublicKeyType::EdDSA_SHA512_Ed25519
+            }
+            SigningPrivateKey::EdDSA_SHA512_Ed25519ph(_) => {
+                SigningPublicKeyType::EdDSA_SHA512_Ed25519ph
+            }
+            SigningPrivateKey::RedDSA_SHA512_Ed25519(_) => {
+                SigningPublicKeyType::RedDSA_SHA512_Ed25519
+            }
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match *self {
+            SigningPrivateKey::DSA_SHA1(ref data) |
+            SigningPrivateKey::ECDSA_SHA256_P256(ref data) |
+            SigningPrivateKey::ECDSA_SHA384_P384(ref data) |
+            SigningPrivateKey::ECDSA_SHA512_P521(ref data) |
+            SigningPrivateKey::RSA_SHA256_2048(ref data) |
+            SigningPrivateKey::RSA_SHA384_3072(ref data) |
+            SigningPrivateKey::RSA_SHA512_4096(ref data) |
+            SigningPrivateKey::EdDSA_SHA512_Ed25519(ref data) |
+            SigningPrivateKey::EdDSA_SHA512_Ed25519ph(ref data) |
+            SigningPrivateKey::RedDSA_SHA512_Ed25519(ref data) => data,
+        }
+    }
+
+    /// Signs `message`, given the matching public key
+    pub fn sign(&self, public_key: &SigningPublicKey, message: &[u8]) -> Result<Signature, Error> {
+        let data = signature::signer(self, &public_key.data)?.sign(message)?;
+        Signature::new(&self.get_type(), &data)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        Ok(writer.write(self.data())?)
+    }
+
+    pub fn deserialize<R: Read>(key_type: &SigningPublicKeyType,
+                                reader: &mut R)
+                                -> Result<SigningPrivateKey, Error> {
+        let mut buffer = vec![0u8; SigningPrivateKey::length(key_type)];
+        reader.read_exact(buffer.as_mut_slice())?;
+
+        SigningPrivateKey::new(key_type, &buffer)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Signature {
     DSA_SHA1(Box<[u8]>), // length = 40
     ECDSA_SHA256_P256(Box<[u8]>), // length = 64
@@ -195,8 +395,95 @@ pub enum Signature {
     RSA_SHA512_4096(Box<[u8]>), // length = 512
     EdDSA_SHA512_Ed25519(Box<[u8]>), // length = 64
     EdDSA_SHA512_Ed25519ph(Box<[u8]>), // length = 64
+    RedDSA_SHA512_Ed25519(Box<[u8]>), // length = 64
 }
 
+impl Signature {
+    pub fn new(key_type: &SigningPublicKeyType, data: &[u8]) -> Result<Signature, Error> {
+        if data.len() != Signature::length(key_type) {
+            return Err(Error::Crypto(format!("Expected signature of length {}, got one of \
+                                              length {}",
+                                             Signature::length(key_type),
+                                             data.len())));
+        }
+
+        let data = data.to_vec().into_boxed_slice();
+        Ok(match *key_type {
+            SigningPublicKeyType::DSA_SHA1 => Signature::DSA_SHA1(data),
+            SigningPublicKeyType::ECDSA_SHA256_P256 => Signature::ECDSA_SHA256_P256(data),
+            SigningPublicKeyType::ECDSA_SHA384_P384 => Signature::ECDSA_SHA384_P384(data),
+            SigningPublicKeyType::ECDSA_SHA512_P521 => Signature::ECDSA_SHA512_P521(data),
+            SigningPublicKeyType::RSA_SHA256_2048 => Signature::RSA_SHA256_2048(data),
+            SigningPublicKeyType::RSA_SHA384_3072 => Signature::RSA_SHA384_3072(data),
+            SigningPublicKeyType::RSA_SHA512_4096 => Signature::RSA_SHA512_4096(data),
+            SigningPublicKeyType::EdDSA_SHA512_Ed25519 => Signature::EdDSA_SHA512_Ed25519(data),
+            SigningPublicKeyType::EdDSA_SHA512_Ed25519ph => {
+                Signature::EdDSA_SHA512_Ed25519ph(data)
+            }
+            SigningPublicKeyType::RedDSA_SHA512_Ed25519 => Signature::RedDSA_SHA512_Ed25519(data),
+        })
+    }
+
+    pub fn length(key_type: &SigningPublicKeyType) -> usize {
+        match *key_type {
+            SigningPublicKeyType::DSA_SHA1 => 40,
+            SigningPublicKeyType::ECDSA_SHA256_P256 => 64,
+            SigningPublicKeyType::ECDSA_SHA384_P384 => 96,
+            SigningPublicKeyType::ECDSA_SHA512_P521 => 132,
+            SigningPublicKeyType::RSA_SHA256_2048 => 256,
+            SigningPublicKeyType::RSA_SHA384_3072 => 384,
+            SigningPublicKeyType::RSA_SHA512_4096 => 512,
+            SigningPublicKeyType::EdDSA_SHA512_Ed25519 |
+            SigningPublicKeyType::EdDSA_SHA512_Ed25519ph |
+            SigningPublicKeyType::RedDSA_SHA512_Ed25519 => 64,
+        }
+    }
+
+    pub fn get_type(&self) -> SigningPublicKeyType {
+        match *self {
+            Signature::DSA_SHA1(_) => SigningPublicKeyType::DSA_SHA1,
+            Signature::ECDSA_SHA256_P256(_) => SigningPublicKeyType::ECDSA_SHA256_P256,
+            Signature::ECDSA_SHA384_P384(_) => SigningPublicKeyType::ECDSA_SHA384_P384,
+            Signature::ECDSA_SHA512_P521(_) => SigningPublicKeyType::ECDSA_SHA512_P521,
+            Signature::RSA_SHA256_2048(_) => SigningPublicKeyType::RSA_SHA256_2048,
+            Signature::RSA_SHA384_3072(_) => SigningPublicKeyType::RSA_SHA384_3072,
+            Signature::RSA_SHA512_4096(_) => SigningPublicKeyType::RSA_SHA512_4096,
+            Signature::EdDSA_SHA512_Ed25519(_) => SigningPublicKeyType::EdDSA_SHA512_Ed25519,
+            Signature::EdDSA_SHA512_Ed25519ph(_) => SigningPublicKeyType::EdDSA_SHA512_Ed25519ph,
+            Signature::RedDSA_SHA512_Ed25519(_) => SigningPublicKeyType::RedDSA_SHA512_Ed25519,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match *self {
+            Signature::DSA_SHA1(ref data) |
+            Signature::ECDSA_SHA256_P256(ref data) |
+            Signature::ECDSA_SHA384_P384(ref data) |
+            Signature::ECDSA_SHA512_P521(ref data) |
+            Signature::RSA_SHA256_2048(ref data) |
+            Signature::RSA_SHA384_3072(ref data) |
+            Signature::RSA_SHA512_4096(ref data) |
+            Signature::EdDSA_SHA512_Ed25519(ref data) |
+            Signature::EdDSA_SHA512_Ed25519ph(ref data) |
+            Signature::RedDSA_SHA512_Ed25519(ref data) => data,
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        Ok(writer.write(self.data())?)
+    }
+
+    pub fn deserialize<R: Read>(key_type: &SigningPublicKeyType,
+                                reader: &mut R)
+                                -> Result<Signature, Error> {
+        let mut buffer = vec![0u8; Signature::length(key_type)];
+        reader.read_exact(buffer.as_mut_slice())?;
+
+        Signature::new(key_type, &buffer)
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Hash {
     SHA256(Box<[u8]>), // length = 32
 }
@@ -210,7 +497,7 @@ pub enum CertificateType {
     Key = 5,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Certificate {
     Null,
     HashCash(String),
@@ -220,7 +507,7 @@ pub enum Certificate {
     Key(KeyCertificate),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct KeyCertificate {
     signing_key_type: SigningPublicKeyType,
     crypto_key_type: PublicKeyType,
@@ -247,7 +534,7 @@ impl KeyCertificate {
         Ok(KeyCertificate {
             signing_key_type: SigningPublicKeyType::from_u16(signing_key_type)?,
             crypto_key_type: PublicKeyType::from_u16(crypto_key_type)?,
-            extra_bytes: extra_bytes,
+            extra_bytes,
         })
     }
 
@@ -267,7 +554,7 @@ impl Certificate {
         match *self {
             Certificate::Null => {
                 writer.write_u8(CertificateType::Null as u8)?;
-                writer.write_u16::<BigEndian>(0 as u16)?;
+                writer.write_u16::<BigEndian>(0_u16)?;
                 written += 3;
             }
             Certificate::HashCash(ref data) => {
@@ -278,7 +565,7 @@ impl Certificate {
             }
             Certificate::Hidden => {
                 writer.write_u8(CertificateType::Hidden as u8)?;
-                writer.write_u16::<BigEndian>(0 as u16)?;
+                writer.write_u16::<BigEndian>(0_u16)?;
                 written += 3;
             }
             Certificate::Signed(ref data) => {
@@ -311,7 +598,7 @@ impl Certificate {
         let cert_type = reader.read_u8()?;
         let length = reader.read_u16::<BigEndian>()?;
         let mut payload = vec![0u8; length as usize];
-        reader.read(payload.as_mut_slice())?;
+        reader.read_exact(payload.as_mut_slice())?;
         match cert_type {
             0 => Ok(Certificate::Null),
             1 => Ok(Certificate::HashCash(str::from_utf8(payload.as_slice())?.to_string())),
@@ -324,9 +611,10 @@ impl Certificate {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct KeysAndCert {
     public_key: PublicKey,
+    padding: Vec<u8>,
     signing_key: SigningPublicKey,
     certificate: Certificate,
 }
@@ -338,7 +626,7 @@ struct Random {
 impl Random {
     pub fn new() -> Result<Random, Error> {
         let rng = OsRng::new()?;
-        Ok(Random { rng: rng })
+        Ok(Random { rng })
     }
 
     fn generate<T: Rand>(&mut self, length: usize) -> Vec<T> {
@@ -346,100 +634,143 @@ impl Random {
     }
 }
 
+/// The space an identity has for its public key
+const PUBLIC_KEY_AREA: usize = 256;
+
 pub type RouterIdentity = KeysAndCert;
 
 impl KeysAndCert {
-    pub fn serialize<W: Write>(&mut self, mut writer: W) -> Result<usize, Error> {
-        let mut written = self.public_key.serialize(&mut writer)?;
+    pub fn new(public_key: PublicKey,
+               signing_key: SigningPublicKey,
+               certificate: Certificate)
+               -> Result<KeysAndCert, Error> {
+        // The padding is part of the identity hash, so it's chosen once and kept.
+        // It fills the rest of the 256 bytes for the public key, then the
+        // space before the signing key.
+        let (padding_size, _) = SigningPublicKey::padding_size(&signing_key.get_type())?;
+        let padding_size = padding_size + PUBLIC_KEY_AREA - public_key.length();
+        Ok(KeysAndCert {
+            public_key,
+            padding: Random::new()?.generate::<u8>(padding_size),
+            signing_key,
+            certificate,
+        })
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    pub fn signing_key(&self) -> &SigningPublicKey {
+        &self.signing_key
+    }
+
+    pub fn certificate(&self) -> &Certificate {
+        &self.certificate
+    }
+
+    /// The SHA-256 hash of the serialized identity, which is how routers and
+    /// destinations are addressed
+    pub fn hash(&self) -> Result<Hash, Error> {
         let mut buffer: Vec<u8> = Vec::new();
-        self.signing_key.serialize(&mut buffer)?;
-        let mut signing_key_type = SigningPublicKeyType::DSA_SHA1;
-        let mut padding_size: usize = 0;
-        let mut extra_bytes: usize = 0;
-        if let Certificate::Key(ref mut key_cert) = self.certificate {
-            signing_key_type = key_cert.signing_key_type.clone();
-            let (pad, extra) = SigningPublicKey::padding_size(&signing_key_type)?;
-            padding_size = pad;
-            extra_bytes = extra;
-            key_cert.extra_bytes = buffer[buffer.len() - extra_bytes..].to_vec();
-        }
-        let padding = Random::new()?.generate::<u8>(padding_size);
-        written += writer.write(padding.as_slice())?;
-        written += writer.write(&buffer[..buffer.len() - extra_bytes])?;
+        self.serialize(&mut buffer)?;
+
+        Ok(Hash::SHA256(crypto::sha256(&buffer).into_boxed_slice()))
+    }
+
+    pub fn serialize<W: Write>(&self, mut writer: W) -> Result<usize, Error> {
+        let mut written = self.public_key.serialize(&mut writer)?;
+        let (_, extra_bytes) = SigningPublicKey::padding_size(&self.signing_key.get_type())?;
+        written += writer.write(&self.padding)?;
+        written += writer.write(&self.signing_key.data[..self.signing_key.data.len() -
+                                                          extra_bytes])?;
         written += self.certificate.serialize(writer)?;
 
         Ok(written)
     }
 
     pub fn deserialize<R: Read>(mut reader: R) -> Result<KeysAndCert, Error> {
-        let mut buffer = vec![0u8; 384];
+        let mut buffer = vec![0u8; PUBLIC_KEY_AREA + 128];
         reader.read_exact(buffer.as_mut_slice())?;
         let certificate = Certificate::deserialize(&mut reader)?;
         let mut signing_key_type = SigningPublicKeyType::DSA_SHA1;
+        let mut public_key_type = PublicKeyType::ElGamal;
         if let Certificate::Key(ref key_cert) = certificate {
             signing_key_type = key_cert.signing_key_type.clone();
+            public_key_type = key_cert.crypto_key_type.clone();
             buffer.extend(key_cert.extra_bytes.clone());
         }
-        let mut reader = buffer.as_slice();
-        let public_key = PublicKey::deserialize(&mut reader)?;
+        // Public keys shorter than the space for them are padded after
+        let public_key = PublicKey::new(&public_key_type, &buffer[..public_key_type.length()])?;
+        let (padding_size, _) = SigningPublicKey::padding_size(&signing_key_type)?;
+        let padding = buffer[public_key_type.length()..PUBLIC_KEY_AREA + padding_size].to_vec();
+        let mut reader = &buffer[PUBLIC_KEY_AREA..];
         let signing_key = SigningPublicKey::deserialize(signing_key_type, &mut reader)?;
         Ok(KeysAndCert {
-            public_key: public_key,
-            signing_key: signing_key,
-            certificate: certificate,
+            public_key,
+            padding,
+            signing_key,
+            certificate,
         })
     }
 }
 
-#[cfg(test)]
-mod test {
-    #![allow(non_camel_case_types)]
+/// A client's identity, which its LeaseSets are published under. It's laid
+/// out just like a RouterIdentity, but the two can't be mixed up.
+#[derive(Clone, Debug)]
+pub struct Destination(KeysAndCert);
 
-    use base64::{decode, encode};
-    use std::error::Error;
-    use std::fs::File;
-    use std::io::{BufRead, BufReader};
-    use super::*;
+impl Destination {
+    pub fn new(identity: KeysAndCert) -> Destination {
+        Destination(identity)
+    }
 
-    fn read_fixture(name: &str) -> Vec<String> {
-        let file_path = format!("fixtures/{}.txt", name);
-        let file = match File::open(&file_path) {
-            Err(why) => panic!("couldn't open {}: {}", file_path, why.description()),
-            Ok(file) => file,
-        };
+    pub fn identity(&self) -> &KeysAndCert {
+        &self.0
+    }
 
-        let mut reader = BufReader::new(file);
-        let mut ret: Vec<String> = Vec::new();
-        let mut buffer = String::new();
-        loop {
-            match reader.read_line(&mut buffer) {
-                Err(why) => panic!("couldn't read {}: {}", file_path, why.description()),
-                Ok(len) => {
-                    if len == 0 {
-                        break;
-                    }
-                    ret.push(buffer);
-                    buffer = String::new();
-                }
-            }
-        }
+    pub fn public_key(&self) -> &PublicKey {
+        self.0.public_key()
+    }
 
-        ret
+    pub fn signing_key(&self) -> &SigningPublicKey {
+        self.0.signing_key()
     }
 
-    fn get_key_and_cert_fixture_data(name: &str) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
-        let values = read_fixture(name);
+    pub fn certificate(&self) -> &Certificate {
+        self.0.certificate()
+    }
+
+    /// The hash the destination's LeaseSets are stored under
+    pub fn hash(&self) -> Result<Hash, Error> {
+        self.0.hash()
+    }
+
+    /// The `.b32.i2p` address for the destination
+    pub fn b32_address(&self) -> Result<B32Address, Error> {
+        B32Address::from_destination(self)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: W) -> Result<usize, Error> {
+        self.0.serialize(writer)
+    }
 
-        let mut decode_result = decode(&values[0].trim_right());
-        let keys_and_cert_data = decode_result.unwrap();
+    pub fn deserialize<R: Read>(reader: R) -> Result<Destination, Error> {
+        Ok(Destination(KeysAndCert::deserialize(reader)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(non_camel_case_types, non_snake_case)]
 
-        decode_result = decode(&values[1].trim_right());
-        let public_key_data = decode_result.unwrap();
+    use i2p::test_util::read_fixture_lines;
+    use super::*;
 
-        decode_result = decode(&values[2].trim_right());
-        let signing_key_data = decode_result.unwrap();
+    fn get_key_and_cert_fixture_data(name: &str) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let values = read_fixture_lines(name);
 
-        (keys_and_cert_data, public_key_data, signing_key_data)
+        (values[0].clone(), values[1].clone(), values[2].clone())
     }
 
     #[test]
@@ -447,14 +778,13 @@ mod test {
         let (keys_and_cert_data, public_key_data, signing_key_data) =
             get_key_and_cert_fixture_data("DSA_SHA1_Keys_and_Cert");
 
-        let mut keys_and_cert = KeysAndCert {
-            public_key: PublicKey::ElGamal(public_key_data.into_boxed_slice()),
-            signing_key: SigningPublicKey {
-                key_type: SigningPublicKeyType::DSA_SHA1,
-                data: signing_key_data,
-            },
-            certificate: Certificate::Null,
-        };
+        let keys_and_cert = KeysAndCert::new(PublicKey::ElGamal(public_key_data.into_boxed_slice()),
+                                             SigningPublicKey {
+                                                 key_type: SigningPublicKeyType::DSA_SHA1,
+                                                 data: signing_key_data,
+                                             },
+                                             Certificate::Null)
+            .unwrap();
 
         let mut buffer: Vec<u8> = Vec::new();
         let size = keys_and_cert.serialize(&mut buffer).unwrap();
@@ -465,7 +795,6 @@ mod test {
     fn test_deserialize_DSA_SHA1_keys_and_cert() {
         let (keys_and_cert_data, public_key_data, signing_key_data) =
             get_key_and_cert_fixture_data("DSA_SHA1_Keys_and_Cert");
-        let values = read_fixture("DSA_SHA1_Keys_and_Cert");
 
         let keys_and_cert_result = KeysAndCert::deserialize(keys_and_cert_data.as_slice());
         assert!(keys_and_cert_result.is_ok());
@@ -475,6 +804,7 @@ mod test {
             PublicKey::ElGamal(data) => {
                 assert_eq!(*public_key_data.as_slice(), *data);
             }
+            _ => panic!("Expected an ElGamal public key"),
         };
         assert_eq!(SigningPublicKeyType::DSA_SHA1,
                    keys_and_cert.signing_key.key_type);
@@ -491,11 +821,8 @@ mod test {
         let signing_key = SigningPublicKey::new(SigningPublicKeyType::ECDSA_SHA256_P256,
                                                 &signing_key_data);
         let key_cert = KeyCertificate::new(&public_key, &signing_key).unwrap();
-        let mut keys_and_cert = KeysAndCert {
-            public_key: public_key,
-            signing_key: signing_key,
-            certificate: Certificate::Key(key_cert),
-        };
+        let keys_and_cert = KeysAndCert::new(public_key, signing_key, Certificate::Key(key_cert))
+            .unwrap();
 
         let mut buffer: Vec<u8> = Vec::new();
         let size = keys_and_cert.serialize(&mut buffer).unwrap();
@@ -519,6 +846,7 @@ mod test {
             PublicKey::ElGamal(data) => {
                 assert_eq!(*public_key_data.as_slice(), *data);
             }
+            _ => panic!("Expected an ElGamal public key"),
         };
         assert_eq!(SigningPublicKeyType::ECDSA_SHA256_P256,
                    keys_and_cert.signing_key.key_type);
@@ -529,7 +857,7 @@ mod test {
                 assert_eq!(key_cert.signing_key_type,
                            SigningPublicKeyType::ECDSA_SHA256_P256);
             }
-            _ => assert!(false),
+            _ => panic!("Expected a key certificate"),
         }
     }
 
@@ -542,11 +870,8 @@ mod test {
         let signing_key = SigningPublicKey::new(SigningPublicKeyType::ECDSA_SHA384_P384,
                                                 &signing_key_data);
         let key_cert = KeyCertificate::new(&public_key, &signing_key).unwrap();
-        let mut keys_and_cert = KeysAndCert {
-            public_key: public_key,
-            signing_key: signing_key,
-            certificate: Certificate::Key(key_cert),
-        };
+        let keys_and_cert = KeysAndCert::new(public_key, signing_key, Certificate::Key(key_cert))
+            .unwrap();
 
         let mut buffer: Vec<u8> = Vec::new();
         let size = keys_and_cert.serialize(&mut buffer).unwrap();
@@ -570,6 +895,7 @@ mod test {
             PublicKey::ElGamal(data) => {
                 assert_eq!(*public_key_data.as_slice(), *data);
             }
+            _ => panic!("Expected an ElGamal public key"),
         };
         assert_eq!(SigningPublicKeyType::ECDSA_SHA384_P384,
                    keys_and_cert.signing_key.key_type);
@@ -580,7 +906,7 @@ mod test {
                 assert_eq!(key_cert.signing_key_type,
                            SigningPublicKeyType::ECDSA_SHA384_P384);
             }
-            _ => assert!(false),
+            _ => panic!("Expected a key certificate"),
         }
     }
 
@@ -593,11 +919,8 @@ mod test {
         let signing_key = SigningPublicKey::new(SigningPublicKeyType::ECDSA_SHA512_P521,
                                                 &signing_key_data);
         let key_cert = KeyCertificate::new(&public_key, &signing_key).unwrap();
-        let mut keys_and_cert = KeysAndCert {
-            public_key: public_key,
-            signing_key: signing_key,
-            certificate: Certificate::Key(key_cert),
-        };
+        let keys_and_cert = KeysAndCert::new(public_key, signing_key, Certificate::Key(key_cert))
+            .unwrap();
 
         let mut buffer: Vec<u8> = Vec::new();
         let size = keys_and_cert.serialize(&mut buffer).unwrap();
@@ -621,6 +944,7 @@ mod test {
             PublicKey::ElGamal(data) => {
                 assert_eq!(*public_key_data.as_slice(), *data);
             }
+            _ => panic!("Expected an ElGamal public key"),
         };
         assert_eq!(SigningPublicKeyType::ECDSA_SHA512_P521,
                    keys_and_cert.signing_key.key_type);
@@ -631,7 +955,7 @@ mod test {
                 assert_eq!(key_cert.signing_key_type,
                            SigningPublicKeyType::ECDSA_SHA512_P521);
             }
-            _ => assert!(false),
+            _ => panic!("Expected a key certificate"),
         }
     }
 
@@ -644,11 +968,8 @@ mod test {
         let signing_key = SigningPublicKey::new(SigningPublicKeyType::EdDSA_SHA512_Ed25519,
                                                 &signing_key_data);
         let key_cert = KeyCertificate::new(&public_key, &signing_key).unwrap();
-        let mut keys_and_cert = KeysAndCert {
-            public_key: public_key,
-            signing_key: signing_key,
-            certificate: Certificate::Key(key_cert),
-        };
+        let keys_and_cert = KeysAndCert::new(public_key, signing_key, Certificate::Key(key_cert))
+            .unwrap();
 
         let mut buffer: Vec<u8> = Vec::new();
         let size = keys_and_cert.serialize(&mut buffer).unwrap();
@@ -672,6 +993,7 @@ mod test {
             PublicKey::ElGamal(data) => {
                 assert_eq!(*public_key_data.as_slice(), *data);
             }
+            _ => panic!("Expected an ElGamal public key"),
         };
         assert_eq!(SigningPublicKeyType::EdDSA_SHA512_Ed25519,
                    keys_and_cert.signing_key.key_type);
@@ -682,7 +1004,7 @@ mod test {
                 assert_eq!(key_cert.signing_key_type,
                            SigningPublicKeyType::EdDSA_SHA512_Ed25519);
             }
-            _ => assert!(false),
+            _ => panic!("Expected a key certificate"),
         }
     }
 
@@ -695,11 +1017,8 @@ mod test {
         let signing_key = SigningPublicKey::new(SigningPublicKeyType::EdDSA_SHA512_Ed25519ph,
                                                 &signing_key_data);
         let key_cert = KeyCertificate::new(&public_key, &signing_key).unwrap();
-        let mut keys_and_cert = KeysAndCert {
-            public_key: public_key,
-            signing_key: signing_key,
-            certificate: Certificate::Key(key_cert),
-        };
+        let keys_and_cert = KeysAndCert::new(public_key, signing_key, Certificate::Key(key_cert))
+            .unwrap();
 
         let mut buffer: Vec<u8> = Vec::new();
         let size = keys_and_cert.serialize(&mut buffer).unwrap();
@@ -723,6 +1042,7 @@ mod test {
             PublicKey::ElGamal(data) => {
                 assert_eq!(*public_key_data.as_slice(), *data);
             }
+            _ => panic!("Expected an ElGamal public key"),
         };
         assert_eq!(SigningPublicKeyType::EdDSA_SHA512_Ed25519ph,
                    keys_and_cert.signing_key.key_type);
@@ -733,7 +1053,7 @@ mod test {
                 assert_eq!(key_cert.signing_key_type,
                            SigningPublicKeyType::EdDSA_SHA512_Ed25519ph);
             }
-            _ => assert!(false),
+            _ => panic!("Expected a key certificate"),
         }
     }
 
@@ -746,11 +1066,8 @@ mod test {
         let signing_key = SigningPublicKey::new(SigningPublicKeyType::RSA_SHA256_2048,
                                                 &signing_key_data);
         let key_cert = KeyCertificate::new(&public_key, &signing_key).unwrap();
-        let mut keys_and_cert = KeysAndCert {
-            public_key: public_key,
-            signing_key: signing_key,
-            certificate: Certificate::Key(key_cert),
-        };
+        let keys_and_cert = KeysAndCert::new(public_key, signing_key, Certificate::Key(key_cert))
+            .unwrap();
 
         let mut buffer: Vec<u8> = Vec::new();
         let size = keys_and_cert.serialize(&mut buffer).unwrap();
@@ -775,6 +1092,7 @@ mod test {
             PublicKey::ElGamal(data) => {
                 assert_eq!(*public_key_data.as_slice(), *data);
             }
+            _ => panic!("Expected an ElGamal public key"),
         };
         assert_eq!(SigningPublicKeyType::RSA_SHA256_2048,
                    keys_and_cert.signing_key.key_type);
@@ -785,7 +1103,7 @@ mod test {
                 assert_eq!(key_cert.signing_key_type,
                            SigningPublicKeyType::RSA_SHA256_2048);
             }
-            _ => assert!(false),
+            _ => panic!("Expected a key certificate"),
         }
     }
 
@@ -798,11 +1116,8 @@ mod test {
         let signing_key = SigningPublicKey::new(SigningPublicKeyType::RSA_SHA384_3072,
                                                 &signing_key_data);
         let key_cert = KeyCertificate::new(&public_key, &signing_key).unwrap();
-        let mut keys_and_cert = KeysAndCert {
-            public_key: public_key,
-            signing_key: signing_key,
-            certificate: Certificate::Key(key_cert),
-        };
+        let keys_and_cert = KeysAndCert::new(public_key, signing_key, Certificate::Key(key_cert))
+            .unwrap();
 
         let mut buffer: Vec<u8> = Vec::new();
         let size = keys_and_cert.serialize(&mut buffer).unwrap();
@@ -826,6 +1141,7 @@ mod test {
             PublicKey::ElGamal(data) => {
                 assert_eq!(*public_key_data.as_slice(), *data);
             }
+            _ => panic!("Expected an ElGamal public key"),
         };
         assert_eq!(SigningPublicKeyType::RSA_SHA384_3072,
                    keys_and_cert.signing_key.key_type);
@@ -836,7 +1152,7 @@ mod test {
                 assert_eq!(key_cert.signing_key_type,
                            SigningPublicKeyType::RSA_SHA384_3072);
             }
-            _ => assert!(false),
+            _ => panic!("Expected a key certificate"),
         }
     }
 
@@ -849,11 +1165,8 @@ mod test {
         let signing_key = SigningPublicKey::new(SigningPublicKeyType::RSA_SHA512_4096,
                                                 &signing_key_data);
         let key_cert = KeyCertificate::new(&public_key, &signing_key).unwrap();
-        let mut keys_and_cert = KeysAndCert {
-            public_key: public_key,
-            signing_key: signing_key,
-            certificate: Certificate::Key(key_cert),
-        };
+        let keys_and_cert = KeysAndCert::new(public_key, signing_key, Certificate::Key(key_cert))
+            .unwrap();
 
         let mut buffer: Vec<u8> = Vec::new();
         let size = keys_and_cert.serialize(&mut buffer).unwrap();
@@ -877,6 +1190,7 @@ mod test {
             PublicKey::ElGamal(data) => {
                 assert_eq!(*public_key_data.as_slice(), *data);
             }
+            _ => panic!("Expected an ElGamal public key"),
         };
         assert_eq!(SigningPublicKeyType::RSA_SHA512_4096,
                    keys_and_cert.signing_key.key_type);
@@ -887,7 +1201,30 @@ mod test {
                 assert_eq!(key_cert.signing_key_type,
                            SigningPublicKeyType::RSA_SHA512_4096);
             }
-            _ => assert!(false),
+            _ => panic!("Expected a key certificate"),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_X25519_keys_and_cert() {
+        let public_key = PublicKey::new(&PublicKeyType::X25519, &[3u8; 32]).unwrap();
+        let signing_key = SigningPublicKey::new(SigningPublicKeyType::EdDSA_SHA512_Ed25519,
+                                                &[7u8; 32]);
+        let certificate =
+            Certificate::Key(KeyCertificate::new(&public_key, &signing_key).unwrap());
+        let keys_and_cert = KeysAndCert::new(public_key.clone(), signing_key, certificate)
+            .unwrap();
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(391, keys_and_cert.serialize(&mut buffer).unwrap());
+        assert_eq!(391, buffer.len());
+        // The key comes first in its 256 bytes, and the padding after it
+        assert_eq!(&[3u8; 32], &buffer[..32]);
+        assert_eq!(&[7u8; 32], &buffer[352..384]);
+
+        let parsed = KeysAndCert::deserialize(buffer.as_slice()).unwrap();
+        assert_eq!(&public_key, parsed.public_key());
+        assert_eq!(SigningPublicKeyType::EdDSA_SHA512_Ed25519,
+                   parsed.signing_key().get_type());
+        assert_eq!(keys_and_cert.hash().unwrap(), parsed.hash().unwrap());
+    }
+}
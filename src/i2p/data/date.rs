@@ -0,0 +1,114 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::error::Error;
+use std::io::{Read, Write};
+use time;
+
+/// An I2P Date: milliseconds since the epoch, as an eight-byte integer.
+/// Zero means the date is undefined or null.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Date {
+    millis: u64,
+}
+
+impl Date {
+    pub fn from_millis(millis: u64) -> Date {
+        Date { millis }
+    }
+
+    /// The newer structures (LeaseSet2 and friends) use four-byte seconds
+    pub fn from_seconds(seconds: u32) -> Date {
+        Date::from_millis(seconds as u64 * 1000)
+    }
+
+    pub fn now() -> Date {
+        let now = time::get_time();
+        Date::from_millis(now.sec as u64 * 1000 + now.nsec as u64 / 1_000_000)
+    }
+
+    pub fn null() -> Date {
+        Date::from_millis(0)
+    }
+
+    pub fn millis(&self) -> u64 {
+        self.millis
+    }
+
+    pub fn seconds(&self) -> u32 {
+        (self.millis / 1000) as u32
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.millis == 0
+    }
+
+    /// The UTC day as "yyyyMMdd", which key blinding and routing keys mix in
+    pub fn date_string(&self) -> String {
+        let tm = time::at_utc(time::Timespec::new((self.millis / 1000) as i64, 0));
+        format!("{:04}{:02}{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        writer.write_u64::<BigEndian>(self.millis)?;
+
+        Ok(8)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<Date, Error> {
+        Ok(Date::from_millis(reader.read_u64::<BigEndian>()?))
+    }
+
+    /// Reads a date where null means "not set"
+    pub fn deserialize_optional<R: Read>(reader: &mut R) -> Result<Option<Date>, Error> {
+        let date = Date::deserialize(reader)?;
+        if date.is_null() { Ok(None) } else { Ok(Some(date)) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DATE_BYTES: [u8; 8] = [0x00, 0x00, 0x01, 0x5a, 0xea, 0xeb, 0xb4, 0x00];
+
+    #[test]
+    fn test_serialize_date() {
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = Date::from_millis(1490000000000).serialize(&mut buffer).unwrap();
+        assert_eq!(8, size);
+        assert_eq!(&DATE_BYTES[..], buffer.as_slice());
+    }
+
+    #[test]
+    fn test_deserialize_date() {
+        let date = Date::deserialize(&mut &DATE_BYTES[..]).unwrap();
+        assert_eq!(1490000000000, date.millis());
+        assert_eq!(Some(date),
+                   Date::deserialize_optional(&mut &DATE_BYTES[..]).unwrap());
+    }
+
+    #[test]
+    fn test_deserialize_null_date() {
+        let data = [0u8; 8];
+        assert_eq!(None, Date::deserialize_optional(&mut &data[..]).unwrap());
+    }
+
+    #[test]
+    fn test_seconds() {
+        let date = Date::from_seconds(1490000000);
+        assert_eq!(1490000000000, date.millis());
+        assert_eq!(1490000000, Date::from_millis(1490000000999).seconds());
+    }
+
+    #[test]
+    fn test_date_string() {
+        assert_eq!("20170320", Date::from_millis(1490000000000).date_string());
+        // Just before and at midnight UTC
+        assert_eq!("20161231", Date::from_millis(1483228799999).date_string());
+        assert_eq!("20170101", Date::from_millis(1483228800000).date_string());
+    }
+
+    #[test]
+    fn test_deserialize_truncated_date() {
+        assert!(Date::deserialize(&mut &DATE_BYTES[..7]).is_err());
+    }
+}
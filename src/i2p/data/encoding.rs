@@ -0,0 +1,386 @@
+use i2p::crypto;
+use i2p::data::crypto::{Destination, Hash, SigningPublicKey, SigningPublicKeyType};
+use i2p::error::Error;
+use std::fmt;
+
+/// I2P uses the standard Base64 alphabet, but with `-` and `~` in place of
+/// `+` and `/` so that the output is safe in URLs and filenames
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-~";
+
+/// Base32 uses the RFC 4648 alphabet, lowercased, with no padding
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+const B32_SUFFIX: &str = ".b32.i2p";
+
+/// A plain `.b32.i2p` address is 52 characters, encoding a 32-byte hash
+const B32_HASH_LENGTH: usize = 52;
+
+/// Extended addresses for encrypted LeaseSets are at least 56 characters
+const B32_EXTENDED_MIN_LENGTH: usize = 56;
+
+const BLINDED_FLAG_TWO_BYTE_SIG_TYPES: u8 = 0x01;
+const BLINDED_FLAG_SECRET_REQUIRED: u8 = 0x02;
+const BLINDED_FLAG_PER_CLIENT_AUTH: u8 = 0x04;
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut result = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b0 = chunk[0] as u32;
+        let b1 = if chunk.len() > 1 { chunk[1] as u32 } else { 0 };
+        let b2 = if chunk.len() > 2 { chunk[2] as u32 } else { 0 };
+        let triple = (b0 << 16) | (b1 << 8) | b2;
+
+        result.push(BASE64_ALPHABET[(triple >> 18) as usize & 0x3f] as char);
+        result.push(BASE64_ALPHABET[(triple >> 12) as usize & 0x3f] as char);
+        if chunk.len() > 1 {
+            result.push(BASE64_ALPHABET[(triple >> 6) as usize & 0x3f] as char);
+        } else {
+            result.push('=');
+        }
+        if chunk.len() > 2 {
+            result.push(BASE64_ALPHABET[triple as usize & 0x3f] as char);
+        } else {
+            result.push('=');
+        }
+    }
+
+    result
+}
+
+pub fn base64_decode(encoded: &str) -> Result<Vec<u8>, Error> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return Err(Error::Serialization(format!("Base64 length {} is not a multiple of 4",
+                                                encoded.len())));
+    }
+
+    let mut result: Vec<u8> = Vec::with_capacity(encoded.len() / 4 * 3);
+    let chunk_count = encoded.len() / 4;
+    for (index, chunk) in encoded.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && index != chunk_count - 1) {
+            return Err(Error::Serialization("Invalid Base64 padding".to_string()));
+        }
+
+        let mut triple: u32 = 0;
+        for &c in &chunk[..4 - padding] {
+            triple = (triple << 6) | base64_value(c)?;
+        }
+        triple <<= 6 * padding as u32;
+
+        result.push((triple >> 16) as u8);
+        if padding < 2 {
+            result.push((triple >> 8) as u8);
+        }
+        if padding < 1 {
+            result.push(triple as u8);
+        }
+    }
+
+    Ok(result)
+}
+
+fn base64_value(c: u8) -> Result<u32, Error> {
+    match c {
+        b'A'..=b'Z' => Ok((c - b'A') as u32),
+        b'a'..=b'z' => Ok((c - b'a') as u32 + 26),
+        b'0'..=b'9' => Ok((c - b'0') as u32 + 52),
+        b'-' => Ok(62),
+        b'~' => Ok(63),
+        _ => Err(Error::Serialization(format!("Invalid Base64 character {:?}", c as char))),
+    }
+}
+
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut result = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut buffer: u32 = 0;
+    let mut bits: u32 = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32_ALPHABET[(buffer >> bits) as usize & 0x1f] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 0x1f] as char);
+    }
+
+    result
+}
+
+/// Decodes unpadded, case-insensitive Base32. Leftover bits that don't make
+/// up a whole byte are dropped.
+pub fn base32_decode(encoded: &str) -> Result<Vec<u8>, Error> {
+    let mut result: Vec<u8> = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits: u32 = 0;
+    for c in encoded.bytes() {
+        let value = match c {
+            b'a'..=b'z' => c - b'a',
+            b'A'..=b'Z' => c - b'A',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => {
+                return Err(Error::Serialization(format!("Invalid Base32 character {:?}",
+                                                        c as char)))
+            }
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+
+    Ok(result)
+}
+
+/// CRC-32 (IEEE), used as the checksum in extended b32 addresses and zip
+/// files
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xffffffff;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+
+    !crc
+}
+
+/// The SHA-256 identity hash of a serialized Destination or RouterIdentity
+pub fn ident_hash(identity: &[u8]) -> Hash {
+    Hash::SHA256(crypto::sha256(identity).into_boxed_slice())
+}
+
+/// A parsed `.b32.i2p` address. The short form is just the hash of the
+/// destination. The extended form, used for encrypted LeaseSets, carries the
+/// destination's signing public key and the blinded signature type instead.
+#[derive(Clone, Debug, PartialEq)]
+pub enum B32Address {
+    Hash(Hash),
+    Blinded {
+        signing_key: SigningPublicKey,
+        blinded_type: SigningPublicKeyType,
+        secret_required: bool,
+        per_client_auth: bool,
+    },
+}
+
+impl B32Address {
+    pub fn from_destination(destination: &Destination) -> Result<B32Address, Error> {
+        Ok(B32Address::Hash(destination.hash()?))
+    }
+
+    pub fn parse(address: &str) -> Result<B32Address, Error> {
+        let lowercase = address.to_lowercase();
+        if !lowercase.ends_with(B32_SUFFIX) {
+            return Err(Error::Serialization(format!("{} is not a .b32.i2p address", address)));
+        }
+        let encoded = &lowercase[..lowercase.len() - B32_SUFFIX.len()];
+
+        if encoded.len() == B32_HASH_LENGTH {
+            let hash = base32_decode(encoded)?;
+            Ok(B32Address::Hash(Hash::SHA256(hash.into_boxed_slice())))
+        } else if encoded.len() >= B32_EXTENDED_MIN_LENGTH {
+            B32Address::parse_blinded(&base32_decode(encoded)?)
+        } else {
+            Err(Error::Serialization(format!("Invalid .b32.i2p address length for {}", address)))
+        }
+    }
+
+    fn parse_blinded(data: &[u8]) -> Result<B32Address, Error> {
+        let mut data = data.to_vec();
+        let checksum = crc32(&data[3..]);
+        data[0] ^= checksum as u8;
+        data[1] ^= (checksum >> 8) as u8;
+        data[2] ^= (checksum >> 16) as u8;
+
+        let flags = data[0];
+        if flags & BLINDED_FLAG_TWO_BYTE_SIG_TYPES != 0 {
+            return Err(Error::Serialization("Two-byte signature types in b32 \
+                                                     addresses are not supported".to_string()));
+        }
+        let key_type = SigningPublicKeyType::from_u16(data[1] as u16)?;
+        let blinded_type = SigningPublicKeyType::from_u16(data[2] as u16)?;
+        let key = &data[3..];
+        if key.len() != SigningPublicKey::length(&key_type) {
+            return Err(Error::Serialization(format!("Expected {} bytes of signing key in b32 \
+                                                     address, got {}",
+                                                    SigningPublicKey::length(&key_type),
+                                                    key.len())));
+        }
+
+        Ok(B32Address::Blinded {
+            signing_key: SigningPublicKey::new(key_type, key),
+            blinded_type,
+            secret_required: flags & BLINDED_FLAG_SECRET_REQUIRED != 0,
+            per_client_auth: flags & BLINDED_FLAG_PER_CLIENT_AUTH != 0,
+        })
+    }
+}
+
+impl fmt::Display for B32Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let encoded = match *self {
+            B32Address::Hash(Hash::SHA256(ref hash)) => base32_encode(hash),
+            B32Address::Blinded { ref signing_key,
+                                  ref blinded_type,
+                                  secret_required,
+                                  per_client_auth } => {
+                let mut flags = 0u8;
+                if secret_required {
+                    flags |= BLINDED_FLAG_SECRET_REQUIRED;
+                }
+                if per_client_auth {
+                    flags |= BLINDED_FLAG_PER_CLIENT_AUTH;
+                }
+                let mut data = vec![flags,
+                                    signing_key.get_type() as u8,
+                                    blinded_type.clone() as u8];
+                data.extend_from_slice(signing_key.data());
+                let checksum = crc32(&data[3..]);
+                data[0] ^= checksum as u8;
+                data[1] ^= (checksum >> 8) as u8;
+                data[2] ^= (checksum >> 16) as u8;
+                base32_encode(&data)
+            }
+        };
+
+        write!(f, "{}{}", encoded, B32_SUFFIX)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::{Hash, SigningPublicKey, SigningPublicKeyType};
+    use super::*;
+
+    const TEST_HASH: [u8; 32] = [0x9f, 0x86, 0xd0, 0x81, 0x88, 0x4c, 0x7d, 0x65, 0x9a, 0x2f,
+                                 0xea, 0xa0, 0xc5, 0x5a, 0xd0, 0x15, 0xa3, 0xbf, 0x4f, 0x1b,
+                                 0x2b, 0x0b, 0x82, 0x2c, 0xd1, 0x5d, 0x6c, 0x15, 0xb0, 0xf0,
+                                 0x0a, 0x08];
+
+    const TEST_B32: &str = "t6dnbamijr6wlgrp5kqmkwwqcwr36ty3fmfyelgrlvwblmhqbiea.b32.i2p";
+
+    const TEST_BLINDED_B32: &str = "rj4s2aabaibqibiga4eascqlbqgq4dyqcejbgfavcylrqgi2dmob2hq7.b32.\
+                                    i2p";
+
+    #[test]
+    fn test_base64_uses_i2p_alphabet() {
+        assert_eq!("-~-~AAE=", base64_encode(&[0xfb, 0xff, 0xbf, 0x00, 0x01]));
+        assert_eq!(vec![0xfb, 0xff, 0xbf, 0x00, 0x01],
+                   base64_decode("-~-~AAE=").unwrap());
+    }
+
+    #[test]
+    fn test_base64_round_trip() {
+        assert_eq!("aGVsbG8=", base64_encode(b"hello"));
+        assert_eq!(b"hello".to_vec(), base64_decode("aGVsbG8=").unwrap());
+        assert_eq!("", base64_encode(&[]));
+        for length in 0..10 {
+            let data: Vec<u8> = (0..length).map(|i| (i * 37) as u8).collect();
+            assert_eq!(data, base64_decode(&base64_encode(&data)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_base64_rejects_standard_alphabet() {
+        assert!(base64_decode("+/+/AAE=").is_err());
+        assert!(base64_decode("aGVsbG8").is_err());
+        assert!(base64_decode("aG==bG8=").is_err());
+    }
+
+    #[test]
+    fn test_base32_round_trip() {
+        assert_eq!("nbswy3dp", base32_encode(b"hello"));
+        assert_eq!(b"hello".to_vec(), base32_decode("nbswy3dp").unwrap());
+        assert_eq!(b"hello".to_vec(), base32_decode("NBSWY3DP").unwrap());
+        assert!(base32_decode("nbswy3d1").is_err());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0xcbf43926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn test_parse_b32_address() {
+        let address = B32Address::parse(TEST_B32).unwrap();
+        assert_eq!(B32Address::Hash(Hash::SHA256(TEST_HASH.to_vec().into_boxed_slice())),
+                   address);
+        assert_eq!(TEST_B32, address.to_string());
+        assert_eq!(address, B32Address::parse(&TEST_B32.to_uppercase()).unwrap());
+    }
+
+    #[test]
+    fn test_ident_hash() {
+        assert_eq!(Hash::SHA256(TEST_HASH.to_vec().into_boxed_slice()),
+                   ident_hash(b"test"));
+    }
+
+    #[test]
+    fn test_parse_blinded_b32_address() {
+        let key: Vec<u8> = (0..32).collect();
+        let address = B32Address::parse(TEST_BLINDED_B32).unwrap();
+        match address {
+            B32Address::Blinded { ref signing_key,
+                                  ref blinded_type,
+                                  secret_required,
+                                  per_client_auth } => {
+                assert_eq!(SigningPublicKeyType::EdDSA_SHA512_Ed25519,
+                           signing_key.get_type());
+                assert_eq!(key.as_slice(), signing_key.data());
+                assert_eq!(SigningPublicKeyType::RedDSA_SHA512_Ed25519, *blinded_type);
+                assert!(!secret_required);
+                assert!(!per_client_auth);
+            }
+            _ => panic!("Expected a blinded address"),
+        }
+        assert_eq!(TEST_BLINDED_B32, address.to_string());
+    }
+
+    #[test]
+    fn test_blinded_b32_address_vector() {
+        // Built by hand from the spec: flags 0x02 (secret required), type 7,
+        // blinded type 11 and the RFC 8032 test 1 public key, with the low
+        // three bytes of the key's CRC-32 (0x3fa206b2) XORed into the first
+        // three
+        let key = [0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3,
+                   0xc9, 0x64, 0x07, 0x3a, 0x0e, 0xe1, 0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25,
+                   0xaf, 0x02, 0x1a, 0x68, 0xf7, 0x07, 0x51, 0x1a];
+        let address = B32Address::Blinded {
+            signing_key: SigningPublicKey::new(SigningPublicKeyType::EdDSA_SHA512_Ed25519, &key),
+            blinded_type: SigningPublicKeyType::RedDSA_SHA512_Ed25519,
+            secret_required: true,
+            per_client_auth: false,
+        };
+        let encoded = "waa2tv22taayfmikw7kux7wtzfsaooqo4fzphwvgems26aq2nd3qoui2.b32.i2p";
+        assert_eq!(encoded, address.to_string());
+        assert_eq!(address, B32Address::parse(encoded).unwrap());
+    }
+
+    #[test]
+    fn test_blinded_b32_address_flags() {
+        let key: Vec<u8> = (0..32).collect();
+        let address = B32Address::Blinded {
+            signing_key: SigningPublicKey::new(SigningPublicKeyType::EdDSA_SHA512_Ed25519, &key),
+            blinded_type: SigningPublicKeyType::RedDSA_SHA512_Ed25519,
+            secret_required: true,
+            per_client_auth: true,
+        };
+        let encoded = address.to_string();
+        assert_eq!(56 + ".b32.i2p".len(), encoded.len());
+        assert_eq!(address, B32Address::parse(&encoded).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_b32_addresses() {
+        assert!(B32Address::parse("example.i2p").is_err());
+        assert!(B32Address::parse("abcdef.b32.i2p").is_err());
+    }
+}
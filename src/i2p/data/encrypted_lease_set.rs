@@ -0,0 +1,493 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::crypto;
+use i2p::crypto::chacha20;
+use i2p::crypto::curve25519::{self, EdwardsPoint};
+use i2p::data::crypto::{Hash, Signature, SigningPublicKey, SigningPublicKeyType};
+use i2p::data::date::Date;
+use i2p::data::lease_set2::{self, FLAG_OFFLINE_KEYS, LEASE_SET2_TYPE, LeaseSet2,
+                            META_LEASE_SET_TYPE, MetaLeaseSet};
+use i2p::data::offline_signature::OfflineSignature;
+use i2p::error::Error;
+use std::io::{Read, Write};
+
+pub const ENCRYPTED_LEASE_SET_TYPE: u8 = 5;
+
+const SALT_LENGTH: usize = 32;
+const AUTH_COOKIE_LENGTH: usize = 32;
+const CLIENT_ID_LENGTH: usize = 8;
+const CLIENT_ENTRY_LENGTH: usize = CLIENT_ID_LENGTH + AUTH_COOKIE_LENGTH;
+
+const PER_CLIENT_AUTH_FLAG: u8 = 0x01;
+const DH_AUTH_SCHEME: u8 = 0;
+const PSK_AUTH_SCHEME: u8 = 1;
+
+/// Our credentials for a LeaseSet that's only readable by some clients
+pub enum ClientAuth {
+    /// Our X25519 private key
+    Dh(Vec<u8>),
+    /// The pre-shared key the destination gave us
+    Psk(Vec<u8>),
+}
+
+/// What's inside an EncryptedLeaseSet
+#[derive(Debug)]
+pub enum DecryptedLeaseSet {
+    LeaseSet2(LeaseSet2),
+    Meta(MetaLeaseSet),
+}
+
+impl DecryptedLeaseSet {
+    pub fn verify(&self) -> Result<bool, Error> {
+        match *self {
+            DecryptedLeaseSet::LeaseSet2(ref lease_set) => lease_set.verify(),
+            DecryptedLeaseSet::Meta(ref lease_set) => lease_set.verify(),
+        }
+    }
+
+    fn signing_key(&self) -> &SigningPublicKey {
+        match *self {
+            DecryptedLeaseSet::LeaseSet2(ref lease_set) => lease_set.destination().signing_key(),
+            DecryptedLeaseSet::Meta(ref lease_set) => lease_set.destination().signing_key(),
+        }
+    }
+}
+
+/// A LeaseSet2 or MetaLeaseSet, encrypted and published under a blinded
+/// key that changes every day, so that only clients who know the
+/// destination (and possibly have its permission) can read it
+#[derive(Debug)]
+pub struct EncryptedLeaseSet {
+    blinded_key: SigningPublicKey,
+    published: Date,
+    expires: Date,
+    flags: u16,
+    offline_signature: Option<OfflineSignature>,
+    encrypted_data: Vec<u8>,
+    signature: Signature,
+}
+
+impl EncryptedLeaseSet {
+    pub fn blinded_key(&self) -> &SigningPublicKey {
+        &self.blinded_key
+    }
+
+    pub fn published(&self) -> Date {
+        self.published
+    }
+
+    pub fn expires(&self) -> Date {
+        self.expires
+    }
+
+    pub fn offline_signature(&self) -> Option<&OfflineSignature> {
+        self.offline_signature.as_ref()
+    }
+
+    pub fn encrypted_data(&self) -> &[u8] {
+        &self.encrypted_data
+    }
+
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    /// The NetDB key, which is the hash of the blinded key and its type
+    pub fn hash(&self) -> Result<Hash, Error> {
+        Ok(Hash::SHA256(crypto::sha256(&blinded_key_data(&self.blinded_key)?)
+            .into_boxed_slice()))
+    }
+
+    pub fn is_expired(&self, now: Date) -> bool {
+        self.expires < now ||
+        self.offline_signature.as_ref().is_some_and(|signature| signature.is_expired(now))
+    }
+
+    fn signing_key(&self) -> &SigningPublicKey {
+        match self.offline_signature {
+            Some(ref offline_signature) => offline_signature.transient_key(),
+            None => &self.blinded_key,
+        }
+    }
+
+    /// Checks the outer signature, which floodfills can do without being
+    /// able to decrypt
+    pub fn verify(&self) -> Result<bool, Error> {
+        if let Some(ref offline_signature) = self.offline_signature {
+            if !offline_signature.verify(&self.blinded_key)? {
+                return Ok(false);
+            }
+        }
+        let mut buffer: Vec<u8> = vec![ENCRYPTED_LEASE_SET_TYPE];
+        self.serialize_unsigned(&mut buffer)?;
+
+        self.signing_key().verify(&buffer, &self.signature)
+    }
+
+    /// Decrypts with the destination's (unblinded) signing key, and our
+    /// credentials if it's only readable by some clients
+    pub fn decrypt(&self,
+                   destination_key: &SigningPublicKey,
+                   auth: Option<&ClientAuth>)
+                   -> Result<DecryptedLeaseSet, Error> {
+        let subcredential = subcredential(destination_key, &self.blinded_key)?;
+        let mut input = subcredential.clone();
+        input.write_u32::<BigEndian>(self.published.seconds())?;
+
+        let outer = decrypt_layer(&self.encrypted_data, &input, b"ELS2_L1K")?;
+        let (auth_cookie, inner) = read_authorization(&outer, &input, auth)?;
+        let mut inner_input = auth_cookie.unwrap_or(Vec::new());
+        inner_input.extend(input);
+        let plaintext = decrypt_layer(inner, &inner_input, b"ELS2_L2K")?;
+
+        let (lease_set_type, mut reader) = match plaintext.split_first() {
+            Some((lease_set_type, rest)) => (*lease_set_type, rest),
+            None => return Err(Error::Crypto("Empty encrypted LeaseSet".to_string())),
+        };
+        let lease_set = match lease_set_type {
+            LEASE_SET2_TYPE => DecryptedLeaseSet::LeaseSet2(LeaseSet2::deserialize(&mut reader)?),
+            META_LEASE_SET_TYPE => DecryptedLeaseSet::Meta(MetaLeaseSet::deserialize(&mut reader)?),
+            _ => {
+                return Err(Error::Serialization(format!("Unexpected LeaseSet type {} in \
+                                                         encrypted LeaseSet",
+                                                        lease_set_type)))
+            }
+        };
+        if lease_set.signing_key() != destination_key {
+            return Err(Error::Crypto("Encrypted LeaseSet is for a different \
+                                              destination".to_string()));
+        }
+
+        Ok(lease_set)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut written = self.serialize_unsigned(writer)?;
+        written += self.signature.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    fn serialize_unsigned<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        if self.encrypted_data.len() > u16::MAX as usize {
+            return Err(Error::Serialization(format!("Encrypted data too long: {}",
+                                                    self.encrypted_data.len())));
+        }
+
+        let mut written = writer.write(&blinded_key_data(&self.blinded_key)?)?;
+        written += lease_set2::write_times(writer, self.published, self.expires)?;
+        writer.write_u16::<BigEndian>(self.flags)?;
+        written += 2;
+        if let Some(ref offline_signature) = self.offline_signature {
+            written += offline_signature.serialize(writer)?;
+        }
+        writer.write_u16::<BigEndian>(self.encrypted_data.len() as u16)?;
+        written += 2;
+        written += writer.write(&self.encrypted_data)?;
+
+        Ok(written)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<EncryptedLeaseSet, Error> {
+        let key_type = SigningPublicKeyType::from_u16(reader.read_u16::<BigEndian>()?)?;
+        let mut key = vec![0u8; SigningPublicKey::length(&key_type)];
+        reader.read_exact(key.as_mut_slice())?;
+        let blinded_key = SigningPublicKey::new(key_type, &key);
+        let (published, expires) = lease_set2::read_times(reader)?;
+        let flags = reader.read_u16::<BigEndian>()?;
+        let offline_signature = if flags & FLAG_OFFLINE_KEYS != 0 {
+            Some(OfflineSignature::deserialize(&blinded_key.get_type(), reader)?)
+        } else {
+            None
+        };
+        let mut encrypted_data = vec![0u8; reader.read_u16::<BigEndian>()? as usize];
+        reader.read_exact(encrypted_data.as_mut_slice())?;
+        let signature_type = match offline_signature {
+            Some(ref offline_signature) => offline_signature.transient_key().get_type(),
+            None => blinded_key.get_type(),
+        };
+        let signature = Signature::deserialize(&signature_type, reader)?;
+
+        Ok(EncryptedLeaseSet {
+            blinded_key,
+            published,
+            expires,
+            flags,
+            offline_signature,
+            encrypted_data,
+            signature,
+        })
+    }
+}
+
+/// Blinds a destination's signing key for the UTC day of `date`. The
+/// optional secret is the lookup password some destinations require.
+pub fn blind_public_key(key: &SigningPublicKey,
+                        date: Date,
+                        secret: Option<&str>)
+                        -> Result<SigningPublicKey, Error> {
+    let blinded_type = blinded_type(&key.get_type())?;
+    let mut input = date.date_string().into_bytes();
+    if let Some(secret) = secret {
+        input.extend_from_slice(secret.as_bytes());
+    }
+    let salt = personalized_hash(b"I2PGenerateAlpha", &key_data(key, &blinded_type)?);
+    let alpha = curve25519::reduce_scalar(&crypto::hkdf(&salt, &input, b"i2pblinding1", 64)?)?;
+    let blinded = EdwardsPoint::decompress(key.data())?.add(&EdwardsPoint::base().mul(&alpha));
+
+    Ok(SigningPublicKey::new(blinded_type, &blinded.compress()))
+}
+
+/// Only Ed25519 keys can be blinded, and the result is a RedDSA key
+fn blinded_type(key_type: &SigningPublicKeyType) -> Result<SigningPublicKeyType, Error> {
+    match *key_type {
+        SigningPublicKeyType::EdDSA_SHA512_Ed25519 |
+        SigningPublicKeyType::RedDSA_SHA512_Ed25519 => {
+            Ok(SigningPublicKeyType::RedDSA_SHA512_Ed25519)
+        }
+        _ => Err(Error::Crypto(format!("Can't blind a signing key of type {:?}", key_type))),
+    }
+}
+
+fn personalized_hash(personalization: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut buffer = personalization.to_vec();
+    buffer.extend_from_slice(data);
+    crypto::sha256(&buffer)
+}
+
+/// The key followed by its type and the blinded type
+fn key_data(key: &SigningPublicKey,
+            blinded_type: &SigningPublicKeyType)
+            -> Result<Vec<u8>, Error> {
+    let mut data = key.data().to_vec();
+    data.write_u16::<BigEndian>(key.get_type() as u16)?;
+    data.write_u16::<BigEndian>(blinded_type.clone() as u16)?;
+
+    Ok(data)
+}
+
+fn blinded_key_data(blinded_key: &SigningPublicKey) -> Result<Vec<u8>, Error> {
+    let mut data: Vec<u8> = Vec::new();
+    data.write_u16::<BigEndian>(blinded_key.get_type() as u16)?;
+    data.extend_from_slice(blinded_key.data());
+
+    Ok(data)
+}
+
+/// Binds the encryption keys to both the destination and the blinded key,
+/// so that only those who know the destination can decrypt
+fn subcredential(destination_key: &SigningPublicKey,
+                 blinded_key: &SigningPublicKey)
+                 -> Result<Vec<u8>, Error> {
+    let credential =
+        personalized_hash(b"credential",
+                          &key_data(destination_key, &blinded_type(&destination_key.get_type())?)?);
+    let mut data = credential;
+    data.extend_from_slice(blinded_key.data());
+
+    Ok(personalized_hash(b"subcredential", &data))
+}
+
+/// Each layer starts with a salt, and is encrypted with ChaCha20 under a key
+/// and nonce derived from it
+fn decrypt_layer(data: &[u8], input: &[u8], info: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.len() < SALT_LENGTH {
+        return Err(Error::Crypto("Encrypted LeaseSet layer too short".to_string()));
+    }
+    let (salt, ciphertext) = data.split_at(SALT_LENGTH);
+    let keys = crypto::hkdf(salt, input, info, chacha20::KEY_LENGTH + chacha20::NONCE_LENGTH)?;
+    let (key, nonce) = keys.split_at(chacha20::KEY_LENGTH);
+
+    chacha20::chacha20(key, nonce, 1, ciphertext)
+}
+
+/// Reads the authorization section at the start of the outer layer. If
+/// there's per-client authorization, finds our entry and decrypts the
+/// cookie the inner layer's keys depend on.
+fn read_authorization<'a>(outer: &'a [u8],
+                          input: &[u8],
+                          auth: Option<&ClientAuth>)
+                          -> Result<(Option<Vec<u8>>, &'a [u8]), Error> {
+    let flags = match outer.first() {
+        Some(flags) => *flags,
+        None => return Err(Error::Crypto("Encrypted LeaseSet layer too short".to_string())),
+    };
+    if flags & PER_CLIENT_AUTH_FLAG == 0 {
+        return Ok((None, &outer[1..]));
+    }
+    if outer.len() < 1 + 32 + 2 {
+        return Err(Error::Crypto("Encrypted LeaseSet authorization too short".to_string()));
+    }
+
+    let scheme = (flags >> 1) & 0x07;
+    let auth_data = &outer[1..33];
+    let (auth_input, salt, info): (Vec<u8>, &[u8], &[u8]) = match (scheme, auth) {
+        (DH_AUTH_SCHEME, Some(ClientAuth::Dh(private_key))) => {
+            // The auth data is the destination's ephemeral public key
+            let mut auth_input = curve25519::x25519(private_key, auth_data)?;
+            auth_input.extend(curve25519::x25519_public_key(private_key)?);
+            auth_input.extend_from_slice(input);
+            (auth_input, auth_data, &b"ELS2_XCA"[..])
+        }
+        (PSK_AUTH_SCHEME, Some(ClientAuth::Psk(key))) => {
+            // The auth data is a salt
+            let mut auth_input = key.clone();
+            auth_input.extend_from_slice(input);
+            (auth_input, auth_data, &b"ELS2PSKA"[..])
+        }
+        (_, None) => {
+            return Err(Error::Crypto("Encrypted LeaseSet requires client \
+                                              authorization".to_string()))
+        }
+        _ => {
+            return Err(Error::Crypto(format!("Client authorization doesn't match scheme {}",
+                                             scheme)))
+        }
+    };
+    let keys = crypto::hkdf(salt,
+                            &auth_input,
+                            info,
+                            chacha20::KEY_LENGTH + chacha20::NONCE_LENGTH + CLIENT_ID_LENGTH)?;
+    let (key, rest) = keys.split_at(chacha20::KEY_LENGTH);
+    let (nonce, client_id) = rest.split_at(chacha20::NONCE_LENGTH);
+
+    let mut reader = &outer[33..];
+    let client_count = reader.read_u16::<BigEndian>()? as usize;
+    if reader.len() < client_count * CLIENT_ENTRY_LENGTH {
+        return Err(Error::Crypto("Encrypted LeaseSet authorization too short".to_string()));
+    }
+    let (entries, inner) = reader.split_at(client_count * CLIENT_ENTRY_LENGTH);
+    for entry in entries.chunks(CLIENT_ENTRY_LENGTH) {
+        let (entry_id, cookie) = entry.split_at(CLIENT_ID_LENGTH);
+        if entry_id == client_id {
+            return Ok((Some(chacha20::chacha20(key, nonce, 1, cookie)?), inner));
+        }
+    }
+
+    Err(Error::Crypto("Not authorized for encrypted LeaseSet".to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::encoding::base32_encode;
+    use i2p::data::private_keys::PrivateKeys;
+    use i2p::test_util::read_fixture;
+    use std::path::Path;
+    use super::*;
+
+    fn destination_key() -> SigningPublicKey {
+        let path = Path::new("fixtures/Destination_EdDSA_SHA512_Ed25519.dat");
+        PrivateKeys::load(path).unwrap().identity().signing_key().clone()
+    }
+
+    fn check_decrypted(lease_set: DecryptedLeaseSet) {
+        assert!(lease_set.verify().unwrap());
+        match lease_set {
+            DecryptedLeaseSet::LeaseSet2(lease_set) => {
+                let Hash::SHA256(ref hash) = lease_set.hash().unwrap();
+                assert_eq!("t2hdypd4sjazudv4tr3gtieu72wvygesoeqdpyp3nk6cpzskijaa",
+                           base32_encode(hash));
+                assert_eq!(2, lease_set.leases().len());
+            }
+            DecryptedLeaseSet::Meta(_) => panic!("Expected a LeaseSet2"),
+        }
+    }
+
+    #[test]
+    fn test_blind_public_key() {
+        let data = read_fixture("EncryptedLeaseSet_NoAuth");
+        let lease_set = EncryptedLeaseSet::deserialize(&mut data.as_slice()).unwrap();
+        let blinded = blind_public_key(&destination_key(), Date::from_seconds(1490000000), None)
+            .unwrap();
+        assert_eq!(SigningPublicKeyType::RedDSA_SHA512_Ed25519, blinded.get_type());
+        assert_eq!(lease_set.blinded_key(), &blinded);
+
+        // The blinded key changes every day, and with the secret
+        let tomorrow = blind_public_key(&destination_key(), Date::from_seconds(1490086400), None)
+            .unwrap();
+        assert!(tomorrow != blinded);
+        let with_secret = blind_public_key(&destination_key(),
+                                           Date::from_seconds(1490000000),
+                                           Some("password"))
+            .unwrap();
+        assert!(with_secret != blinded);
+    }
+
+    #[test]
+    fn test_blind_unsupported_key() {
+        let key = SigningPublicKey::new(SigningPublicKeyType::ECDSA_SHA256_P256, &[1u8; 64]);
+        assert!(blind_public_key(&key, Date::from_seconds(1490000000), None).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_encrypted_lease_set() {
+        let data = read_fixture("EncryptedLeaseSet_NoAuth");
+        let lease_set = EncryptedLeaseSet::deserialize(&mut data.as_slice()).unwrap();
+        assert_eq!(Date::from_seconds(1490000000), lease_set.published());
+        assert_eq!(Date::from_seconds(1490000600), lease_set.expires());
+        assert!(lease_set.verify().unwrap());
+
+        let Hash::SHA256(ref hash) = lease_set.hash().unwrap();
+        assert_eq!("ehijxdseic7ijf57isodkdaa4xxh2sbpieqp3zsgn3b7tf4naisa",
+                   base32_encode(hash));
+
+        let mut buffer: Vec<u8> = Vec::new();
+        lease_set.serialize(&mut buffer).unwrap();
+        assert_eq!(data, buffer);
+    }
+
+    #[test]
+    fn test_verify_tampered_encrypted_lease_set() {
+        let mut data = read_fixture("EncryptedLeaseSet_NoAuth");
+        // A byte of the encrypted data
+        data[50] ^= 0x01;
+        let lease_set = EncryptedLeaseSet::deserialize(&mut data.as_slice()).unwrap();
+        assert!(!lease_set.verify().unwrap());
+    }
+
+    #[test]
+    fn test_decrypt_without_auth() {
+        let data = read_fixture("EncryptedLeaseSet_NoAuth");
+        let lease_set = EncryptedLeaseSet::deserialize(&mut data.as_slice()).unwrap();
+        check_decrypted(lease_set.decrypt(&destination_key(), None).unwrap());
+    }
+
+    #[test]
+    fn test_decrypt_with_dh_auth() {
+        let data = read_fixture("EncryptedLeaseSet_DHAuth");
+        let lease_set = EncryptedLeaseSet::deserialize(&mut data.as_slice()).unwrap();
+        assert!(lease_set.verify().unwrap());
+
+        let auth = ClientAuth::Dh(vec![0x55; 32]);
+        check_decrypted(lease_set.decrypt(&destination_key(), Some(&auth)).unwrap());
+
+        assert!(lease_set.decrypt(&destination_key(), None).is_err());
+        let wrong_key = ClientAuth::Dh(vec![0x56; 32]);
+        assert!(lease_set.decrypt(&destination_key(), Some(&wrong_key)).is_err());
+        let wrong_scheme = ClientAuth::Psk(vec![0x55; 32]);
+        assert!(lease_set.decrypt(&destination_key(), Some(&wrong_scheme)).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_with_psk_auth() {
+        let data = read_fixture("EncryptedLeaseSet_PSKAuth");
+        let lease_set = EncryptedLeaseSet::deserialize(&mut data.as_slice()).unwrap();
+        assert!(lease_set.verify().unwrap());
+
+        let auth = ClientAuth::Psk(vec![0x66; 32]);
+        check_decrypted(lease_set.decrypt(&destination_key(), Some(&auth)).unwrap());
+
+        let other_client = ClientAuth::Psk(vec![0x77; 32]);
+        check_decrypted(lease_set.decrypt(&destination_key(), Some(&other_client)).unwrap());
+        let wrong_key = ClientAuth::Psk(vec![0x67; 32]);
+        assert!(lease_set.decrypt(&destination_key(), Some(&wrong_key)).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_with_wrong_destination() {
+        let data = read_fixture("EncryptedLeaseSet_NoAuth");
+        let lease_set = EncryptedLeaseSet::deserialize(&mut data.as_slice()).unwrap();
+        let other = SigningPublicKey::new(SigningPublicKeyType::EdDSA_SHA512_Ed25519,
+                                          &EdwardsPoint::base().compress());
+        assert!(lease_set.decrypt(&other, None).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::data::crypto::{Destination, Hash, PublicKey, Signature, SigningPublicKey};
+use i2p::data::date::Date;
+use i2p::data::private_keys::PrivateKeys;
+use i2p::error::Error;
+use std::io::{Read, Write};
+
+pub const MAX_LEASES: usize = 16;
+
+/// An inbound tunnel a destination can be reached through
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lease {
+    pub gateway: Hash,
+    pub tunnel_id: u32,
+    pub end_date: Date,
+}
+
+impl Lease {
+    pub fn new(gateway: Hash, tunnel_id: u32, end_date: Date) -> Lease {
+        Lease {
+            gateway,
+            tunnel_id,
+            end_date,
+        }
+    }
+
+    pub fn is_expired(&self, now: Date) -> bool {
+        self.end_date < now
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let Hash::SHA256(ref data) = self.gateway;
+        let mut written = writer.write(data)?;
+        writer.write_u32::<BigEndian>(self.tunnel_id)?;
+        written += 4;
+        written += self.end_date.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<Lease, Error> {
+        let mut gateway = vec![0u8; 32];
+        reader.read_exact(gateway.as_mut_slice())?;
+        let tunnel_id = reader.read_u32::<BigEndian>()?;
+        let end_date = Date::deserialize(reader)?;
+
+        Ok(Lease {
+            gateway: Hash::SHA256(gateway.into_boxed_slice()),
+            tunnel_id,
+            end_date,
+        })
+    }
+}
+
+/// The original (type 1) LeaseSet. The signing key was meant for revocation
+/// and has never been used, but it's still on the wire, sized for the
+/// destination's signing key type.
+#[derive(Debug)]
+pub struct LeaseSet {
+    destination: Destination,
+    encryption_key: PublicKey,
+    signing_key: SigningPublicKey,
+    leases: Vec<Lease>,
+    signature: Signature,
+}
+
+impl LeaseSet {
+    /// Builds a LeaseSet for one of our own destinations, signed with its keys
+    pub fn new(keys: &PrivateKeys,
+               encryption_key: PublicKey,
+               leases: Vec<Lease>)
+               -> Result<LeaseSet, Error> {
+        if keys.is_offline() {
+            return Err(Error::Crypto("LeaseSets can't be signed with offline keys".to_string()));
+        }
+        let key_type = keys.identity().signing_key().get_type();
+        let mut lease_set = LeaseSet {
+            destination: keys.destination(),
+            encryption_key,
+            signing_key: SigningPublicKey::new(key_type.clone(),
+                                               &vec![0u8; SigningPublicKey::length(&key_type)]),
+            leases,
+            signature: Signature::new(&key_type, &vec![0u8; Signature::length(&key_type)])?,
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        lease_set.serialize_unsigned(&mut buffer)?;
+        lease_set.signature = keys.sign(&buffer)?;
+
+        Ok(lease_set)
+    }
+
+    pub fn destination(&self) -> &Destination {
+        &self.destination
+    }
+
+    pub fn encryption_key(&self) -> &PublicKey {
+        &self.encryption_key
+    }
+
+    pub fn leases(&self) -> &[Lease] {
+        &self.leases
+    }
+
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    /// The NetDB key, which is the hash of the destination
+    pub fn hash(&self) -> Result<Hash, Error> {
+        self.destination.hash()
+    }
+
+    /// When the last lease runs out, or None if there aren't any
+    pub fn expires(&self) -> Option<Date> {
+        self.leases.iter().map(|lease| lease.end_date).max()
+    }
+
+    pub fn non_expired_leases(&self, now: Date) -> Vec<&Lease> {
+        self.leases.iter().filter(|lease| !lease.is_expired(now)).collect()
+    }
+
+    /// Checks the trailing signature against the destination's signing key
+    pub fn verify(&self) -> Result<bool, Error> {
+        let mut buffer: Vec<u8> = Vec::new();
+        self.serialize_unsigned(&mut buffer)?;
+
+        self.destination.signing_key().verify(&buffer, &self.signature)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut written = self.serialize_unsigned(writer)?;
+        written += self.signature.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    /// Writes everything the signature covers, i.e. all but the signature itself
+    fn serialize_unsigned<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        if self.leases.len() > MAX_LEASES {
+            return Err(Error::Serialization(format!("Too many leases: {}", self.leases.len())));
+        }
+        let mut written = self.destination.serialize(&mut *writer)?;
+        written += self.encryption_key.serialize(writer)?;
+        written += self.signing_key.serialize(writer)?;
+        writer.write_u8(self.leases.len() as u8)?;
+        written += 1;
+        for lease in &self.leases {
+            written += lease.serialize(writer)?;
+        }
+
+        Ok(written)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<LeaseSet, Error> {
+        let destination = Destination::deserialize(&mut *reader)?;
+        let encryption_key = PublicKey::deserialize(reader)?;
+        // Unlike in an identity, the signing key here isn't padded
+        let key_type = destination.signing_key().get_type();
+        let mut signing_key = vec![0u8; SigningPublicKey::length(&key_type)];
+        reader.read_exact(signing_key.as_mut_slice())?;
+        let lease_count = reader.read_u8()? as usize;
+        if lease_count > MAX_LEASES {
+            return Err(Error::Serialization(format!("Too many leases: {}", lease_count)));
+        }
+        let mut leases: Vec<Lease> = Vec::new();
+        for _ in 0..lease_count {
+            leases.push(Lease::deserialize(reader)?);
+        }
+        let signature = Signature::deserialize(&key_type, reader)?;
+
+        Ok(LeaseSet {
+            destination,
+            encryption_key,
+            signing_key: SigningPublicKey::new(key_type, &signing_key),
+            leases,
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::{PublicKeyType, SigningPublicKeyType};
+    use i2p::data::encoding::base32_encode;
+    use i2p::test_util::read_fixture;
+    use super::*;
+
+    fn gateway(byte: u8) -> Hash {
+        Hash::SHA256(vec![byte; 32].into_boxed_slice())
+    }
+
+    #[test]
+    fn test_deserialize_lease_set() {
+        let data = read_fixture("LeaseSet_EdDSA_SHA512_Ed25519");
+        let lease_set = LeaseSet::deserialize(&mut data.as_slice()).unwrap();
+
+        let Hash::SHA256(ref hash) = lease_set.hash().unwrap();
+        assert_eq!("t2hdypd4sjazudv4tr3gtieu72wvygesoeqdpyp3nk6cpzskijaa",
+                   base32_encode(hash));
+        assert_eq!(2, lease_set.leases().len());
+        assert_eq!(Lease::new(gateway(0x11), 1234, Date::from_millis(1490000600000)),
+                   lease_set.leases()[0]);
+        assert_eq!(Lease::new(gateway(0x22), 5678, Date::from_millis(1490000660000)),
+                   lease_set.leases()[1]);
+        assert_eq!(Some(Date::from_millis(1490000660000)), lease_set.expires());
+        assert_eq!(1,
+                   lease_set.non_expired_leases(Date::from_millis(1490000630000)).len());
+    }
+
+    #[test]
+    fn test_serialize_lease_set() {
+        let data = read_fixture("LeaseSet_EdDSA_SHA512_Ed25519");
+        let lease_set = LeaseSet::deserialize(&mut data.as_slice()).unwrap();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = lease_set.serialize(&mut buffer).unwrap();
+        assert_eq!(data.len(), size);
+        assert_eq!(data, buffer);
+    }
+
+    #[test]
+    fn test_verify_lease_set() {
+        let data = read_fixture("LeaseSet_EdDSA_SHA512_Ed25519");
+        let lease_set = LeaseSet::deserialize(&mut data.as_slice()).unwrap();
+        assert!(lease_set.verify().unwrap());
+    }
+
+    #[test]
+    fn test_verify_tampered_lease_set() {
+        let mut data = read_fixture("LeaseSet_EdDSA_SHA512_Ed25519");
+        // Change the first lease's tunnel id
+        data[715] ^= 0x01;
+        let lease_set = LeaseSet::deserialize(&mut data.as_slice()).unwrap();
+        assert_eq!(1235, lease_set.leases()[0].tunnel_id);
+        assert!(!lease_set.verify().unwrap());
+    }
+
+    #[test]
+    fn test_new_lease_set() {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let encryption_key = keys.identity().public_key().clone();
+        let leases = vec![Lease::new(gateway(0x33), 42, Date::from_millis(1490000600000))];
+        let lease_set = LeaseSet::new(&keys, encryption_key, leases).unwrap();
+        assert!(lease_set.verify().unwrap());
+
+        let mut buffer: Vec<u8> = Vec::new();
+        lease_set.serialize(&mut buffer).unwrap();
+        let loaded = LeaseSet::deserialize(&mut buffer.as_slice()).unwrap();
+        assert!(loaded.verify().unwrap());
+        assert_eq!(keys.identity().hash().unwrap(), loaded.hash().unwrap());
+        assert_eq!(42, loaded.leases()[0].tunnel_id);
+    }
+
+    #[test]
+    fn test_too_many_leases() {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let encryption_key = keys.identity().public_key().clone();
+        let leases = vec![Lease::new(gateway(0x33), 42, Date::from_millis(1490000600000)); 17];
+        assert!(LeaseSet::new(&keys, encryption_key, leases).is_err());
+    }
+}
@@ -0,0 +1,681 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::data::crypto::{Destination, Hash, PublicKey, PublicKeyType, Signature,
+                        SigningPublicKey};
+use i2p::data::date::Date;
+use i2p::data::lease_set::MAX_LEASES;
+use i2p::data::mapping::Mapping;
+use i2p::data::offline_signature::OfflineSignature;
+use i2p::data::private_keys::PrivateKeys;
+use i2p::error::Error;
+use std::io::{Read, Write};
+
+/// The DatabaseStore types, which are also prepended to the signed data
+pub const LEASE_SET2_TYPE: u8 = 3;
+pub const META_LEASE_SET_TYPE: u8 = 7;
+
+pub const FLAG_OFFLINE_KEYS: u16 = 0x0001;
+pub const FLAG_UNPUBLISHED: u16 = 0x0002;
+pub const FLAG_BLINDED: u16 = 0x0004;
+
+/// The header shared by LeaseSet2 and MetaLeaseSet. Dates are in seconds on
+/// the wire, and the expiry is an offset from the published date.
+#[derive(Clone, Debug)]
+pub struct LeaseSet2Header {
+    destination: Destination,
+    published: Date,
+    expires: Date,
+    flags: u16,
+    offline_signature: Option<OfflineSignature>,
+}
+
+impl LeaseSet2Header {
+    /// Builds a header for one of our own destinations, carrying the offline
+    /// signature if the keys have one
+    pub fn new(keys: &PrivateKeys, published: Date, expires: Date) -> LeaseSet2Header {
+        let (flags, offline_signature) = match keys.offline_keys() {
+            Some(offline_keys) => (FLAG_OFFLINE_KEYS, Some(offline_keys.signature().clone())),
+            None => (0, None),
+        };
+
+        LeaseSet2Header {
+            destination: keys.destination(),
+            published: Date::from_seconds(published.seconds()),
+            expires: Date::from_seconds(expires.seconds()),
+            flags,
+            offline_signature,
+        }
+    }
+
+    pub fn destination(&self) -> &Destination {
+        &self.destination
+    }
+
+    pub fn published(&self) -> Date {
+        self.published
+    }
+
+    pub fn expires(&self) -> Date {
+        self.expires
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
+    pub fn offline_signature(&self) -> Option<&OfflineSignature> {
+        self.offline_signature.as_ref()
+    }
+
+    /// Unpublished LeaseSets are sent to peers directly, and floodfills
+    /// shouldn't flood them
+    pub fn is_unpublished(&self) -> bool {
+        self.flags & FLAG_UNPUBLISHED != 0
+    }
+
+    /// Whether this was published encrypted, in an EncryptedLeaseSet
+    pub fn is_blinded(&self) -> bool {
+        self.flags & FLAG_BLINDED != 0
+    }
+
+    pub fn is_expired(&self, now: Date) -> bool {
+        self.expires < now ||
+        self.offline_signature.as_ref().is_some_and(|signature| signature.is_expired(now))
+    }
+
+    /// The key the LeaseSet is signed with, which is the transient key if
+    /// the destination's key is offline
+    pub fn signing_key(&self) -> &SigningPublicKey {
+        match self.offline_signature {
+            Some(ref offline_signature) => offline_signature.transient_key(),
+            None => self.destination.signing_key(),
+        }
+    }
+
+    /// Checks the offline signature, if there is one
+    pub fn verify_offline_signature(&self) -> Result<bool, Error> {
+        match self.offline_signature {
+            Some(ref offline_signature) => {
+                offline_signature.verify(self.destination.signing_key())
+            }
+            None => Ok(true),
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut written = self.destination.serialize(&mut *writer)?;
+        written += write_times(writer, self.published, self.expires)?;
+        writer.write_u16::<BigEndian>(self.flags)?;
+        written += 2;
+        if let Some(ref offline_signature) = self.offline_signature {
+            written += offline_signature.serialize(writer)?;
+        }
+
+        Ok(written)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<LeaseSet2Header, Error> {
+        let destination = Destination::deserialize(&mut *reader)?;
+        let (published, expires) = read_times(reader)?;
+        let flags = reader.read_u16::<BigEndian>()?;
+        let offline_signature = if flags & FLAG_OFFLINE_KEYS != 0 {
+            Some(OfflineSignature::deserialize(&destination.signing_key().get_type(), reader)?)
+        } else {
+            None
+        };
+
+        Ok(LeaseSet2Header {
+            destination,
+            published,
+            expires,
+            flags,
+            offline_signature,
+        })
+    }
+}
+
+/// Writes the four-byte published date and two-byte expiry offset
+pub fn write_times<W: Write>(writer: &mut W,
+                             published: Date,
+                             expires: Date)
+                             -> Result<usize, Error> {
+    if expires < published || expires.seconds() - published.seconds() > u16::MAX as u32 {
+        return Err(Error::Serialization(format!("Expiry {:?} can't be encoded relative to {:?}",
+                                                expires,
+                                                published)));
+    }
+    writer.write_u32::<BigEndian>(published.seconds())?;
+    writer.write_u16::<BigEndian>((expires.seconds() - published.seconds()) as u16)?;
+
+    Ok(6)
+}
+
+pub fn read_times<R: Read>(reader: &mut R) -> Result<(Date, Date), Error> {
+    let published = reader.read_u32::<BigEndian>()?;
+    let expires = reader.read_u16::<BigEndian>()?;
+    let expires = published.checked_add(expires as u32)
+        .ok_or_else(|| {
+            Error::Serialization(format!("Expiration overflows: published {} + {} seconds",
+                                         published,
+                                         expires))
+        })?;
+
+    Ok((Date::from_seconds(published), Date::from_seconds(expires)))
+}
+
+/// Like a Lease, but the end date is in seconds
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lease2 {
+    pub gateway: Hash,
+    pub tunnel_id: u32,
+    pub end_date: Date,
+}
+
+impl Lease2 {
+    pub fn new(gateway: Hash, tunnel_id: u32, end_date: Date) -> Lease2 {
+        Lease2 {
+            gateway,
+            tunnel_id,
+            end_date: Date::from_seconds(end_date.seconds()),
+        }
+    }
+
+    pub fn is_expired(&self, now: Date) -> bool {
+        self.end_date < now
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let Hash::SHA256(ref data) = self.gateway;
+        let written = writer.write(data)?;
+        writer.write_u32::<BigEndian>(self.tunnel_id)?;
+        writer.write_u32::<BigEndian>(self.end_date.seconds())?;
+
+        Ok(written + 8)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<Lease2, Error> {
+        let gateway = read_hash(reader)?;
+        let tunnel_id = reader.read_u32::<BigEndian>()?;
+        let end_date = Date::from_seconds(reader.read_u32::<BigEndian>()?);
+
+        Ok(Lease2 {
+            gateway,
+            tunnel_id,
+            end_date,
+        })
+    }
+}
+
+/// A LeaseSet with typed encryption keys and options, which replaces the
+/// original LeaseSet
+#[derive(Debug)]
+pub struct LeaseSet2 {
+    header: LeaseSet2Header,
+    options: Mapping,
+    encryption_keys: Vec<PublicKey>,
+    leases: Vec<Lease2>,
+    signature: Signature,
+}
+
+impl LeaseSet2 {
+    /// Builds a LeaseSet2 for one of our own destinations, signed with its
+    /// keys, or with the transient key if they're offline
+    pub fn new(keys: &PrivateKeys,
+               published: Date,
+               expires: Date,
+               options: Mapping,
+               encryption_keys: Vec<PublicKey>,
+               leases: Vec<Lease2>)
+               -> Result<LeaseSet2, Error> {
+        let header = LeaseSet2Header::new(keys, published, expires);
+        let key_type = header.signing_key().get_type();
+        let mut lease_set = LeaseSet2 {
+            header,
+            options,
+            encryption_keys,
+            leases,
+            signature: Signature::new(&key_type, &vec![0u8; Signature::length(&key_type)])?,
+        };
+        let mut buffer: Vec<u8> = vec![LEASE_SET2_TYPE];
+        lease_set.serialize_unsigned(&mut buffer)?;
+        lease_set.signature = keys.sign(&buffer)?;
+
+        Ok(lease_set)
+    }
+
+    pub fn header(&self) -> &LeaseSet2Header {
+        &self.header
+    }
+
+    pub fn destination(&self) -> &Destination {
+        self.header.destination()
+    }
+
+    pub fn options(&self) -> &Mapping {
+        &self.options
+    }
+
+    pub fn encryption_keys(&self) -> &[PublicKey] {
+        &self.encryption_keys
+    }
+
+    /// The first key of the given type, in the order the destination prefers
+    pub fn encryption_key(&self, key_type: &PublicKeyType) -> Option<&PublicKey> {
+        self.encryption_keys.iter().find(|key| key.get_type() == *key_type)
+    }
+
+    pub fn leases(&self) -> &[Lease2] {
+        &self.leases
+    }
+
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    /// The NetDB key, which is the hash of the destination
+    pub fn hash(&self) -> Result<Hash, Error> {
+        self.header.destination().hash()
+    }
+
+    pub fn non_expired_leases(&self, now: Date) -> Vec<&Lease2> {
+        self.leases.iter().filter(|lease| !lease.is_expired(now)).collect()
+    }
+
+    /// Checks the offline signature, if any, and then the LeaseSet's own
+    pub fn verify(&self) -> Result<bool, Error> {
+        if !self.header.verify_offline_signature()? {
+            return Ok(false);
+        }
+        let mut buffer: Vec<u8> = vec![LEASE_SET2_TYPE];
+        self.serialize_unsigned(&mut buffer)?;
+
+        self.header.signing_key().verify(&buffer, &self.signature)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut written = self.serialize_unsigned(writer)?;
+        written += self.signature.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    fn serialize_unsigned<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        if self.encryption_keys.len() > u8::MAX as usize {
+            return Err(Error::Serialization(format!("Too many encryption keys: {}",
+                                                    self.encryption_keys.len())));
+        }
+        if self.leases.len() > MAX_LEASES {
+            return Err(Error::Serialization(format!("Too many leases: {}", self.leases.len())));
+        }
+
+        let mut written = self.header.serialize(writer)?;
+        written += self.options.serialize(writer)?;
+        writer.write_u8(self.encryption_keys.len() as u8)?;
+        written += 1;
+        for key in &self.encryption_keys {
+            writer.write_u16::<BigEndian>(key.get_type() as u16)?;
+            writer.write_u16::<BigEndian>(key.length() as u16)?;
+            written += 4;
+            written += key.serialize(writer)?;
+        }
+        writer.write_u8(self.leases.len() as u8)?;
+        written += 1;
+        for lease in &self.leases {
+            written += lease.serialize(writer)?;
+        }
+
+        Ok(written)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<LeaseSet2, Error> {
+        let header = LeaseSet2Header::deserialize(reader)?;
+        let options = Mapping::deserialize_sorted(reader)?;
+        let key_count = reader.read_u8()?;
+        let mut encryption_keys: Vec<PublicKey> = Vec::new();
+        for _ in 0..key_count {
+            let key_type = PublicKeyType::from_u16(reader.read_u16::<BigEndian>()?)?;
+            let mut key = vec![0u8; reader.read_u16::<BigEndian>()? as usize];
+            reader.read_exact(key.as_mut_slice())?;
+            encryption_keys.push(PublicKey::new(&key_type, &key)?);
+        }
+        let lease_count = reader.read_u8()? as usize;
+        if lease_count > MAX_LEASES {
+            return Err(Error::Serialization(format!("Too many leases: {}", lease_count)));
+        }
+        let mut leases: Vec<Lease2> = Vec::new();
+        for _ in 0..lease_count {
+            leases.push(Lease2::deserialize(reader)?);
+        }
+        let signature = Signature::deserialize(&header.signing_key().get_type(), reader)?;
+
+        Ok(LeaseSet2 {
+            header,
+            options,
+            encryption_keys,
+            leases,
+            signature,
+        })
+    }
+}
+
+/// Points at another LeaseSet (or MetaLeaseSet) in the NetDB, for
+/// destinations hosted on several routers
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetaLease {
+    pub hash: Hash,
+    /// Three bytes on the wire. The low four bits are the DatabaseStore type
+    /// of the entry this points at.
+    pub flags: u32,
+    pub cost: u8,
+    pub end_date: Date,
+}
+
+impl MetaLease {
+    pub fn entry_type(&self) -> u8 {
+        (self.flags & 0x0f) as u8
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let Hash::SHA256(ref data) = self.hash;
+        let written = writer.write(data)?;
+        writer.write_uint::<BigEndian>(self.flags as u64, 3)?;
+        writer.write_u8(self.cost)?;
+        writer.write_u32::<BigEndian>(self.end_date.seconds())?;
+
+        Ok(written + 8)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<MetaLease, Error> {
+        let hash = read_hash(reader)?;
+        let flags = reader.read_uint::<BigEndian>(3)? as u32;
+        let cost = reader.read_u8()?;
+        let end_date = Date::from_seconds(reader.read_u32::<BigEndian>()?);
+
+        Ok(MetaLease {
+            hash,
+            flags,
+            cost,
+            end_date,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct MetaLeaseSet {
+    header: LeaseSet2Header,
+    options: Mapping,
+    leases: Vec<MetaLease>,
+    revocations: Vec<Hash>,
+    signature: Signature,
+}
+
+impl MetaLeaseSet {
+    pub fn header(&self) -> &LeaseSet2Header {
+        &self.header
+    }
+
+    pub fn destination(&self) -> &Destination {
+        self.header.destination()
+    }
+
+    pub fn options(&self) -> &Mapping {
+        &self.options
+    }
+
+    pub fn leases(&self) -> &[MetaLease] {
+        &self.leases
+    }
+
+    pub fn revocations(&self) -> &[Hash] {
+        &self.revocations
+    }
+
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    pub fn hash(&self) -> Result<Hash, Error> {
+        self.header.destination().hash()
+    }
+
+    pub fn verify(&self) -> Result<bool, Error> {
+        if !self.header.verify_offline_signature()? {
+            return Ok(false);
+        }
+        let mut buffer: Vec<u8> = vec![META_LEASE_SET_TYPE];
+        self.serialize_unsigned(&mut buffer)?;
+
+        self.header.signing_key().verify(&buffer, &self.signature)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut written = self.serialize_unsigned(writer)?;
+        written += self.signature.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    fn serialize_unsigned<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        if self.leases.len() > u8::MAX as usize ||
+           self.revocations.len() > u8::MAX as usize {
+            return Err(Error::Serialization("Too many meta leases or revocations".to_string()));
+        }
+
+        let mut written = self.header.serialize(writer)?;
+        written += self.options.serialize(writer)?;
+        writer.write_u8(self.leases.len() as u8)?;
+        written += 1;
+        for lease in &self.leases {
+            written += lease.serialize(writer)?;
+        }
+        writer.write_u8(self.revocations.len() as u8)?;
+        written += 1;
+        for revocation in &self.revocations {
+            let Hash::SHA256(ref data) = *revocation;
+            written += writer.write(data)?;
+        }
+
+        Ok(written)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<MetaLeaseSet, Error> {
+        let header = LeaseSet2Header::deserialize(reader)?;
+        let options = Mapping::deserialize_sorted(reader)?;
+        let lease_count = reader.read_u8()?;
+        let mut leases: Vec<MetaLease> = Vec::new();
+        for _ in 0..lease_count {
+            leases.push(MetaLease::deserialize(reader)?);
+        }
+        let revocation_count = reader.read_u8()?;
+        let mut revocations: Vec<Hash> = Vec::new();
+        for _ in 0..revocation_count {
+            revocations.push(read_hash(reader)?);
+        }
+        let signature = Signature::deserialize(&header.signing_key().get_type(), reader)?;
+
+        Ok(MetaLeaseSet {
+            header,
+            options,
+            leases,
+            revocations,
+            signature,
+        })
+    }
+}
+
+fn read_hash<R: Read>(reader: &mut R) -> Result<Hash, Error> {
+    let mut buffer = vec![0u8; 32];
+    reader.read_exact(buffer.as_mut_slice())?;
+
+    Ok(Hash::SHA256(buffer.into_boxed_slice()))
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::SigningPublicKeyType;
+    use i2p::test_util::read_fixture;
+    use super::*;
+
+    fn hash(byte: u8) -> Hash {
+        Hash::SHA256(vec![byte; 32].into_boxed_slice())
+    }
+
+    #[test]
+    fn test_read_times() {
+        let data = [0x58, 0xcf, 0x98, 0x80, 0x02, 0x58];
+        let (published, expires) = read_times(&mut &data[..]).unwrap();
+        assert_eq!(Date::from_seconds(1490000000), published);
+        assert_eq!(Date::from_seconds(1490000600), expires);
+
+        let data = [0xff, 0xff, 0xff, 0xff, 0x00, 0x01];
+        assert!(read_times(&mut &data[..]).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_lease_set2() {
+        let data = read_fixture("LeaseSet2_EdDSA_SHA512_Ed25519");
+        let lease_set = LeaseSet2::deserialize(&mut data.as_slice()).unwrap();
+
+        assert_eq!(Date::from_seconds(1490000000), lease_set.header().published());
+        assert_eq!(Date::from_seconds(1490000600), lease_set.header().expires());
+        assert!(lease_set.header().offline_signature().is_none());
+        assert!(!lease_set.header().is_unpublished());
+        assert_eq!("_smtp._tcp=1 86400 0 0 25 dest",
+                   *lease_set.options().get("s").unwrap());
+        assert_eq!(2, lease_set.encryption_keys().len());
+        assert_eq!(PublicKeyType::X25519, lease_set.encryption_keys()[0].get_type());
+        assert_eq!(256,
+                   lease_set.encryption_key(&PublicKeyType::ElGamal).unwrap().length());
+        assert_eq!(Lease2::new(hash(0x11), 1234, Date::from_seconds(1490000540)),
+                   lease_set.leases()[0]);
+        assert_eq!(1,
+                   lease_set.non_expired_leases(Date::from_seconds(1490000570)).len());
+    }
+
+    #[test]
+    fn test_serialize_lease_set2() {
+        let data = read_fixture("LeaseSet2_EdDSA_SHA512_Ed25519");
+        let lease_set = LeaseSet2::deserialize(&mut data.as_slice()).unwrap();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = lease_set.serialize(&mut buffer).unwrap();
+        assert_eq!(data.len(), size);
+        assert_eq!(data, buffer);
+    }
+
+    #[test]
+    fn test_verify_lease_set2() {
+        let data = read_fixture("LeaseSet2_EdDSA_SHA512_Ed25519");
+        let lease_set = LeaseSet2::deserialize(&mut data.as_slice()).unwrap();
+        assert!(lease_set.verify().unwrap());
+
+        let mut tampered = data.clone();
+        // The low byte of the expiry offset
+        tampered[396] ^= 0x01;
+        let lease_set = LeaseSet2::deserialize(&mut tampered.as_slice()).unwrap();
+        assert!(!lease_set.verify().unwrap());
+    }
+
+    #[test]
+    fn test_verify_offline_lease_set2() {
+        let data = read_fixture("LeaseSet2_Offline_EdDSA_SHA512_Ed25519");
+        let lease_set = LeaseSet2::deserialize(&mut data.as_slice()).unwrap();
+        {
+            let offline_signature = lease_set.header().offline_signature().unwrap();
+            assert_eq!(1490086400, offline_signature.expires());
+            assert_eq!(offline_signature.transient_key(), lease_set.header().signing_key());
+        }
+        assert!(lease_set.verify().unwrap());
+        assert!(!lease_set.header().is_expired(Date::from_seconds(1490000300)));
+        assert!(lease_set.header().is_expired(Date::from_seconds(1490000601)));
+
+        let mut buffer: Vec<u8> = Vec::new();
+        lease_set.serialize(&mut buffer).unwrap();
+        assert_eq!(data, buffer);
+
+        // Break the destination's signature over the transient key
+        let mut tampered = data.clone();
+        tampered[402] ^= 0x01;
+        let lease_set = LeaseSet2::deserialize(&mut tampered.as_slice()).unwrap();
+        assert!(!lease_set.verify().unwrap());
+    }
+
+    #[test]
+    fn test_new_lease_set2() {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let encryption_keys = vec![PublicKey::new(&PublicKeyType::X25519, &[9u8; 32]).unwrap()];
+        let leases = vec![Lease2::new(hash(0x33), 42, Date::from_seconds(1490000600))];
+        let lease_set = LeaseSet2::new(&keys,
+                                       Date::from_seconds(1490000000),
+                                       Date::from_seconds(1490000600),
+                                       Mapping::new(),
+                                       encryption_keys,
+                                       leases)
+            .unwrap();
+        assert!(lease_set.verify().unwrap());
+
+        let mut buffer: Vec<u8> = Vec::new();
+        lease_set.serialize(&mut buffer).unwrap();
+        let loaded = LeaseSet2::deserialize(&mut buffer.as_slice()).unwrap();
+        assert!(loaded.verify().unwrap());
+        assert_eq!(keys.identity().hash().unwrap(), loaded.hash().unwrap());
+        assert_eq!(42, loaded.leases()[0].tunnel_id);
+    }
+
+    #[test]
+    fn test_new_offline_lease_set2() {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap()
+            .create_offline_keys(1490086400, &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let lease_set = LeaseSet2::new(&keys,
+                                       Date::from_seconds(1490000000),
+                                       Date::from_seconds(1490000600),
+                                       Mapping::new(),
+                                       vec![],
+                                       vec![])
+            .unwrap();
+        assert_eq!(FLAG_OFFLINE_KEYS, lease_set.header().flags());
+
+        let mut buffer: Vec<u8> = Vec::new();
+        lease_set.serialize(&mut buffer).unwrap();
+        let loaded = LeaseSet2::deserialize(&mut buffer.as_slice()).unwrap();
+        assert!(loaded.verify().unwrap());
+    }
+
+    #[test]
+    fn test_expiry_too_far_from_published() {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        assert!(LeaseSet2::new(&keys,
+                               Date::from_seconds(1490000000),
+                               Date::from_seconds(1490070000),
+                               Mapping::new(),
+                               vec![],
+                               vec![])
+            .is_err());
+    }
+
+    #[test]
+    fn test_meta_lease_set() {
+        let data = read_fixture("MetaLeaseSet_EdDSA_SHA512_Ed25519");
+        let lease_set = MetaLeaseSet::deserialize(&mut data.as_slice()).unwrap();
+        assert!(lease_set.verify().unwrap());
+
+        assert_eq!(2, lease_set.leases().len());
+        let lease = &lease_set.leases()[0];
+        assert_eq!(hash(0x33), lease.hash);
+        assert_eq!(LEASE_SET2_TYPE, lease.entry_type());
+        assert_eq!(5, lease.cost);
+        assert_eq!(Date::from_seconds(1490000600), lease.end_date);
+        assert_eq!(META_LEASE_SET_TYPE, lease_set.leases()[1].entry_type());
+        assert_eq!(vec![hash(0x55)], lease_set.revocations().to_vec());
+
+        let mut buffer: Vec<u8> = Vec::new();
+        lease_set.serialize(&mut buffer).unwrap();
+        assert_eq!(data, buffer);
+    }
+}
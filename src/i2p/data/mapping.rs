@@ -0,0 +1,217 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::error::Error;
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::io::{Read, Write};
+use std::str;
+
+/// An I2P Mapping: a set of `key=value;` pairs, prefixed with a two-byte
+/// length. Keys are kept sorted, since signed structures require the
+/// canonical ordering.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mapping {
+    entries: BTreeMap<String, String>,
+}
+
+impl Mapping {
+    pub fn new() -> Mapping {
+        Default::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.entries.get(key)
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) -> Option<String> {
+        self.entries.insert(key.to_string(), value.to_string())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.entries.remove(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, String, String> {
+        self.entries.iter()
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut buffer: Vec<u8> = Vec::new();
+        for (key, value) in &self.entries {
+            write_string(&mut buffer, key)?;
+            buffer.write_u8(b'=')?;
+            write_string(&mut buffer, value)?;
+            buffer.write_u8(b';')?;
+        }
+        if buffer.len() > u16::MAX as usize {
+            return Err(Error::Serialization(format!("Mapping too large: {} bytes",
+                                                    buffer.len())));
+        }
+        writer.write_u16::<BigEndian>(buffer.len() as u16)?;
+
+        Ok(2 + writer.write(&buffer)?)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<Mapping, Error> {
+        Mapping::read(reader, false)
+    }
+
+    /// Deserializes a mapping from a signed structure, such as a RouterInfo
+    /// or LeaseSet, whose keys must be sorted for it to serialize back the
+    /// same
+    pub fn deserialize_sorted<R: Read>(reader: &mut R) -> Result<Mapping, Error> {
+        Mapping::read(reader, true)
+    }
+
+    fn read<R: Read>(reader: &mut R, sorted: bool) -> Result<Mapping, Error> {
+        let length = reader.read_u16::<BigEndian>()?;
+        let mut buffer = vec![0u8; length as usize];
+        reader.read_exact(buffer.as_mut_slice())?;
+
+        let mut mapping = Mapping::new();
+        let mut reader = buffer.as_slice();
+        while !reader.is_empty() {
+            let key = read_string(&mut reader)?;
+            expect_byte(&mut reader, b'=', &key)?;
+            let value = read_string(&mut reader)?;
+            expect_byte(&mut reader, b';', &key)?;
+            if mapping.entries.contains_key(&key) {
+                return Err(Error::Serialization(format!("Duplicate mapping key {}", key)));
+            }
+            if let Some(last) = mapping.entries.keys().next_back() {
+                if sorted && key < *last {
+                    return Err(Error::Serialization(format!("Mapping key {} is out of order",
+                                                            key)));
+                }
+            }
+            mapping.entries.insert(key, value);
+        }
+
+        Ok(mapping)
+    }
+}
+
+impl<'a> IntoIterator for &'a Mapping {
+    type Item = (&'a String, &'a String);
+    type IntoIter = btree_map::Iter<'a, String, String>;
+
+    fn into_iter(self) -> btree_map::Iter<'a, String, String> {
+        self.entries.iter()
+    }
+}
+
+fn expect_byte<R: Read>(reader: &mut R, expected: u8, key: &str) -> Result<(), Error> {
+    let found = reader.read_u8()?;
+    if found != expected {
+        return Err(Error::Serialization(format!("Expected '{}' in mapping entry {}, found \
+                                                 0x{:02x}",
+                                                expected as char,
+                                                key,
+                                                found)));
+    }
+
+    Ok(())
+}
+
+/// Reads an I2P String: a one-byte length followed by UTF-8 bytes
+pub fn read_string<R: Read>(reader: &mut R) -> Result<String, Error> {
+    let length = reader.read_u8()?;
+    let mut buffer = vec![0u8; length as usize];
+    reader.read_exact(buffer.as_mut_slice())?;
+
+    Ok(str::from_utf8(&buffer)?.to_string())
+}
+
+pub fn write_string<W: Write>(writer: &mut W, value: &str) -> Result<usize, Error> {
+    if value.len() > u8::MAX as usize {
+        return Err(Error::Serialization(format!("String too long: {}", value)));
+    }
+    writer.write_u8(value.len() as u8)?;
+
+    Ok(1 + writer.write(value.as_bytes())?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAPPING_BYTES: [u8; 16] = [0x00, 0x0e, 0x01, b'a', b'=', 0x01, b'b', b';', 0x02, b'c',
+                                     b'c', b'=', 0x02, b'd', b'd', b';'];
+
+    #[test]
+    fn test_serialize_mapping_sorts_keys() {
+        let mut mapping = Mapping::new();
+        mapping.insert("cc", "dd");
+        mapping.insert("a", "b");
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = mapping.serialize(&mut buffer).unwrap();
+        assert_eq!(MAPPING_BYTES.len(), size);
+        assert_eq!(&MAPPING_BYTES[..], buffer.as_slice());
+    }
+
+    #[test]
+    fn test_deserialize_mapping() {
+        let mapping = Mapping::deserialize(&mut &MAPPING_BYTES[..]).unwrap();
+        assert_eq!(2, mapping.len());
+        assert_eq!(Some(&"b".to_string()), mapping.get("a"));
+        assert_eq!(Some(&"dd".to_string()), mapping.get("cc"));
+    }
+
+    #[test]
+    fn test_empty_mapping() {
+        let mut buffer: Vec<u8> = Vec::new();
+        Mapping::new().serialize(&mut buffer).unwrap();
+        assert_eq!(vec![0x00, 0x00], buffer);
+        assert!(Mapping::deserialize(&mut buffer.as_slice()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deserialize_duplicate_key() {
+        let data = [0x00, 0x0c, 0x01, b'a', b'=', 0x01, b'b', b';', 0x01, b'a', b'=', 0x01, b'c',
+                    b';'];
+        assert!(Mapping::deserialize(&mut &data[..]).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_unsorted_keys() {
+        let data = [0x00, 0x0c, 0x01, b'b', b'=', 0x01, b'c', b';', 0x01, b'a', b'=', 0x01, b'd',
+                    b';'];
+        let mapping = Mapping::deserialize(&mut &data[..]).unwrap();
+        assert_eq!(Some(&"d".to_string()), mapping.get("a"));
+        assert_eq!(Some(&"c".to_string()), mapping.get("b"));
+        assert!(Mapping::deserialize_sorted(&mut &data[..]).is_err());
+        assert!(Mapping::deserialize_sorted(&mut &MAPPING_BYTES[..]).is_ok());
+    }
+
+    #[test]
+    fn test_deserialize_bad_separator() {
+        let data = [0x00, 0x06, 0x01, b'a', b':', 0x01, b'b', b';'];
+        assert!(Mapping::deserialize(&mut &data[..]).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_truncated_mapping() {
+        assert!(Mapping::deserialize(&mut &MAPPING_BYTES[..MAPPING_BYTES.len() - 1]).is_err());
+        // Length prefix runs past the entries
+        let data = [0x00, 0x07, 0x01, b'a', b'=', 0x01, b'b', b';', 0x00];
+        assert!(Mapping::deserialize(&mut &data[..]).is_err());
+    }
+
+    #[test]
+    fn test_write_string_too_long() {
+        let value: String = vec!['x'; 256].into_iter().collect();
+        assert!(write_string(&mut Vec::new(), &value).is_err());
+    }
+}
@@ -1,3 +1,11 @@
 pub mod crypto;
+pub mod date;
+pub mod encoding;
+pub mod encrypted_lease_set;
+pub mod lease_set;
+pub mod lease_set2;
+pub mod mapping;
 pub mod netdb;
+pub mod offline_signature;
+pub mod private_keys;
 pub mod router_info;
\ No newline at end of file
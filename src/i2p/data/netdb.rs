@@ -1,26 +1,753 @@
 use i2p::config::Config;
+use i2p::crypto;
+use i2p::data::crypto::Hash;
+use i2p::data::date::Date;
+use i2p::data::encoding::base64_encode;
+use i2p::data::router_info::{RouterInfo, SupportedTransports};
 use i2p::error::Error;
 use i2p::fs::hashed_storage::HashedStorage;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
+/// How long a RouterInfo is kept after it was published
+const ROUTER_INFO_EXPIRATION: u64 = 27 * 60 * 60 * 1000;
+
+/// How far ahead of our clock a RouterInfo can claim to have been published
+const MAX_CLOCK_SKEW: u64 = 2 * 60 * 1000;
+
+const DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// How close to midnight UTC we also use the other day's routing key, to
+/// allow for routers whose clocks are a little out
+const ROUTING_KEY_ROTATION_MARGIN: u64 = 10 * 60 * 1000;
+
+/// Where RouterInfos used to be kept, under the data directory. Nothing was
+/// ever written into the files there, so there's nothing to carry over.
+const LEGACY_APP_DIRECTORY: &str = "i2pd-rs";
+const LEGACY_STORE_DIRECTORY: &str = "routerinfo";
+
+const FLUSH_INTERVAL_CONFIG: &str = "router.netDbFlushInterval";
+const DEFAULT_FLUSH_INTERVAL: i64 = 60;
+
+/// Selects RouterInfos by what they advertise. The default filter matches
+/// everything.
 #[derive(Debug, Default)]
+pub struct RouterFilter {
+    /// Caps flags the router must have all of
+    pub caps: u8,
+    /// Caps flags the router must have none of
+    pub excluded_caps: u8,
+    pub transport: Option<SupportedTransports>,
+    pub reachable: Option<bool>,
+}
+
+impl RouterFilter {
+    pub fn matches(&self, router_info: &RouterInfo) -> bool {
+        let caps = router_info.caps();
+        let transport_matches = match self.transport {
+            Some(ref transport) => router_info.supports_transport(transport),
+            None => true,
+        };
+        caps & self.caps == self.caps && caps & self.excluded_caps == 0 && transport_matches &&
+        self.reachable.is_none_or(|reachable| router_info.is_reachable() == reachable)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    router_infos: HashMap<Hash, Arc<RouterInfo>>,
+    /// Added or updated since the last flush
+    dirty: HashSet<Hash>,
+    /// Removed since the last flush
+    removed: HashSet<Hash>,
+}
+
+/// What the maintenance thread is told to do between its own rounds
+#[derive(Debug)]
+enum Maintenance {
+    /// Run a round now, and say when it's done
+    Run(Sender<()>),
+    Stop,
+}
+
+/// The RouterInfos we know about, indexed in memory by identity hash and
+/// written back to disk in the background
+#[derive(Debug)]
 pub struct NetDB {
-    router_info_store: HashedStorage,
+    store: Arc<HashedStorage<RouterInfo>>,
+    index: Arc<RwLock<Index>>,
+    flush_interval: Duration,
+    maintenance_sender: Mutex<Option<Sender<Maintenance>>>,
+    maintenance_thread: Mutex<Option<JoinHandle<()>>>,
+    /// Stands in for the system clock when set
+    clock_override: Arc<RwLock<Option<Date>>>,
+}
+
+/// Where a key lives in the Kademlia keyspace on a given day: the SHA-256 of
+/// the key followed by the UTC date as "yyyyMMdd". It changes at midnight UTC,
+/// so that which floodfills are closest to a key can't be picked in advance.
+pub fn routing_key(key: &Hash, date: Date) -> Hash {
+    let Hash::SHA256(ref data) = *key;
+    let mut buffer: Vec<u8> = data.to_vec();
+    buffer.extend(date.date_string().as_bytes());
+
+    Hash::SHA256(crypto::sha256(&buffer).into_boxed_slice())
+}
+
+/// The Kademlia XOR distance between two hashes. Distances compare as
+/// big-endian numbers, which is how byte vectors compare anyway.
+pub fn xor_distance(a: &Hash, b: &Hash) -> Vec<u8> {
+    let (Hash::SHA256(a), Hash::SHA256(b)) = (a, b);
+    a.iter().zip(b.iter()).map(|(a, b)| a ^ b).collect()
+}
+
+/// The key a RouterInfo is stored under on disk
+/// Removes the old store directories, leaving anything else in them alone
+fn remove_legacy_store(data_dir: &Path) {
+    let app_dir = data_dir.join(LEGACY_APP_DIRECTORY);
+    let store = app_dir.join(LEGACY_STORE_DIRECTORY);
+    if store.is_dir() {
+        info!("NetDB: removing the old RouterInfo store {:?}", store);
+        if let Err(error) = fs::remove_dir_all(&store) {
+            warn!("NetDB: error removing {:?}: {}", store, error);
+        }
+    }
+    // Only goes if it's empty now
+    let _ = fs::remove_dir(&app_dir);
+}
+
+fn storage_key(hash: &Hash) -> String {
+    let Hash::SHA256(ref data) = *hash;
+    base64_encode(data)
+}
+
+fn is_expired(router_info: &RouterInfo, now: Date) -> bool {
+    router_info.published().millis() + ROUTER_INFO_EXPIRATION < now.millis()
+}
+
+/// Checks that a RouterInfo is one we'd want to keep, returning its hash
+fn validate(router_info: &RouterInfo, now: Date) -> Result<Hash, Error> {
+    let hash = router_info.hash()?;
+    if !router_info.verify()? {
+        return Err(Error::Crypto(format!("Bad signature on RouterInfo {}", storage_key(&hash))));
+    }
+    if is_expired(router_info, now) {
+        return Err(Error::Serialization(format!("RouterInfo {} expired", storage_key(&hash))));
+    }
+    if router_info.published().millis() > now.millis() + MAX_CLOCK_SKEW {
+        return Err(Error::Serialization(format!("RouterInfo {} published in the future",
+                                                storage_key(&hash))));
+    }
+
+    Ok(hash)
 }
 
 impl NetDB {
-    pub fn new(config: &Config, data_dir: &PathBuf) -> Result<NetDB, Error> {
-        let netdb: NetDB = NetDB {
-            router_info_store: HashedStorage::new(data_dir, "i2pd-rs", "routerinfo", false)?,
-        };
+    pub fn new(config: &Config, data_dir: &Path) -> Result<NetDB, Error> {
+        let flush_interval = config.i64_value(FLUSH_INTERVAL_CONFIG, Some(DEFAULT_FLUSH_INTERVAL))
+            .unwrap();
+        if flush_interval <= 0 {
+            return Err(Error::Configuration(format!("{} must be positive",
+                                                    FLUSH_INTERVAL_CONFIG)));
+        }
+
+        NetDB::with_flush_interval(data_dir, Duration::from_secs(flush_interval as u64))
+    }
 
-        Ok(netdb)
+    fn with_flush_interval(data_dir: &Path, flush_interval: Duration) -> Result<NetDB, Error> {
+        remove_legacy_store(data_dir);
+        Ok(NetDB {
+            store: Arc::new(HashedStorage::new(data_dir, "netDb", "routerInfo", false)?),
+            index: Arc::new(RwLock::new(Default::default())),
+            flush_interval,
+            maintenance_sender: Mutex::new(None),
+            maintenance_thread: Mutex::new(None),
+            clock_override: Arc::new(RwLock::new(None)),
+        })
     }
 
+    /// Loads the stored RouterInfos and starts flushing and expiring them in
+    /// the background
     pub fn start(&self) -> Result<(), Error> {
+        let now = self.now();
+        self.load(now)?;
+
+        let (sender, receiver) = mpsc::channel::<Maintenance>();
+        let store = self.store.clone();
+        let index = self.index.clone();
+        let clock_override = self.clock_override.clone();
+        let flush_interval = self.flush_interval;
+        let handle = thread::Builder::new().name("netdb".to_string()).spawn(move || {
+            loop {
+                let done = match receiver.recv_timeout(flush_interval) {
+                    Ok(Maintenance::Run(done)) => Some(done),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Ok(Maintenance::Stop) |
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                let now = clock_override.read().unwrap().unwrap_or_else(Date::now);
+                expire(&index, now);
+                if let Err(error) = flush(&store, &index) {
+                    error!("NetDB: error flushing to disk: {}", error);
+                }
+                if let Some(done) = done {
+                    let _ = done.send(());
+                }
+            }
+        })?;
+        *self.maintenance_sender.lock().unwrap() = Some(sender);
+        *self.maintenance_thread.lock().unwrap() = Some(handle);
+
         Ok(())
     }
 
+    /// Stops the background thread and writes out anything not yet flushed
     pub fn stop(&self) {
+        if let Some(sender) = self.maintenance_sender.lock().unwrap().take() {
+            let _ = sender.send(Maintenance::Stop);
+        }
+        if let Some(handle) = self.maintenance_thread.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+        if let Err(error) = self.flush() {
+            error!("NetDB: error flushing to disk: {}", error);
+        }
+    }
+
+    /// Has the background thread expire and flush now, rather than waiting
+    /// for the flush interval, and waits for it to finish. Does nothing if
+    /// the NetDB isn't running.
+    pub fn maintain(&self) {
+        let sender = match *self.maintenance_sender.lock().unwrap() {
+            Some(ref sender) => sender.clone(),
+            None => return,
+        };
+        let (done_sender, done) = mpsc::channel();
+        if sender.send(Maintenance::Run(done_sender)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+
+    /// Reads in everything on disk, deleting whatever is expired or invalid
+    fn load(&self, now: Date) -> Result<(), Error> {
+        let mut index = self.index.write().unwrap();
+        let mut discarded: Vec<String> = Vec::new();
+        for result in self.store.iter() {
+            let (key, router_info) = match result {
+                Ok(entry) => entry,
+                Err(error) => {
+                    warn!("NetDB: skipping unreadable RouterInfo: {}", error);
+                    continue;
+                }
+            };
+            match validate(&router_info, now) {
+                Ok(ref hash) if storage_key(hash) == key => {
+                    index.router_infos.insert(hash.clone(), Arc::new(router_info));
+                }
+                Ok(_) => {
+                    warn!("NetDB: RouterInfo {} is stored under the wrong hash", key);
+                    discarded.push(key);
+                }
+                Err(error) => {
+                    info!("NetDB: discarding RouterInfo {}: {}", key, error);
+                    discarded.push(key);
+                }
+            }
+        }
+        for key in discarded {
+            self.store.remove(&key)?;
+        }
+        info!("NetDB: loaded {} RouterInfos", index.router_infos.len());
+
+        Ok(())
+    }
+
+    /// Makes the NetDB think it's `now`, or goes back to the system clock
+    /// given None
+    pub fn override_clock(&self, now: Option<Date>) {
+        *self.clock_override.write().unwrap() = now;
+    }
+
+    pub fn now(&self) -> Date {
+        self.clock_override.read().unwrap().unwrap_or_else(Date::now)
+    }
+
+    /// The routing key for `key` today
+    pub fn routing_key(&self, key: &Hash) -> Hash {
+        routing_key(key, self.now())
+    }
+
+    /// Today's routing key for `key`, followed by the previous or next day's
+    /// if we're close enough to midnight that other routers may still be, or
+    /// already be, using it. Stores and lookups should target all of them.
+    pub fn routing_keys(&self, key: &Hash) -> Vec<Hash> {
+        let now = self.now().millis();
+        let since_midnight = now % DAY;
+        let mut keys = vec![routing_key(key, Date::from_millis(now))];
+        let other_day = if since_midnight < ROUTING_KEY_ROTATION_MARGIN {
+            now.checked_sub(DAY)
+        } else if DAY - since_midnight <= ROUTING_KEY_ROTATION_MARGIN {
+            now.checked_add(DAY)
+        } else {
+            None
+        };
+        if let Some(other_day) = other_day {
+            keys.push(routing_key(key, Date::from_millis(other_day)));
+        }
+
+        keys
+    }
+
+    /// Up to `count` floodfills, closest first to the given routing key,
+    /// leaving out any in `exclude`
+    pub fn closest_floodfills(&self,
+                              routing_key: &Hash,
+                              count: usize,
+                              exclude: &HashSet<Hash>)
+                              -> Vec<Arc<RouterInfo>> {
+        let index = self.index.read().unwrap();
+        let mut floodfills: Vec<(Vec<u8>, &Arc<RouterInfo>)> = index.router_infos
+            .iter()
+            .filter(|&(hash, router_info)| router_info.is_floodfill() && !exclude.contains(hash))
+            .map(|(hash, router_info)| (xor_distance(routing_key, hash), router_info))
+            .collect();
+        floodfills.sort_by(|a, b| a.0.cmp(&b.0));
+
+        floodfills.into_iter().take(count).map(|(_, router_info)| router_info.clone()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.read().unwrap().router_infos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn lookup(&self, hash: &Hash) -> Option<Arc<RouterInfo>> {
+        self.index.read().unwrap().router_infos.get(hash).cloned()
+    }
+
+    /// Adds a RouterInfo, or replaces an older one for the same router.
+    /// Returns false if we already had one at least as new. Anything that
+    /// doesn't verify, or is expired, is an error.
+    pub fn insert(&self, router_info: RouterInfo) -> Result<bool, Error> {
+        let hash = validate(&router_info, self.now())?;
+        let mut index = self.index.write().unwrap();
+        if let Some(existing) = index.router_infos.get(&hash) {
+            if existing.published() >= router_info.published() {
+                return Ok(false);
+            }
+        }
+        index.router_infos.insert(hash.clone(), Arc::new(router_info));
+        index.removed.remove(&hash);
+        index.dirty.insert(hash);
+
+        Ok(true)
+    }
+
+    pub fn remove(&self, hash: &Hash) -> Option<Arc<RouterInfo>> {
+        let mut index = self.index.write().unwrap();
+        let removed = index.router_infos.remove(hash);
+        if removed.is_some() {
+            index.dirty.remove(hash);
+            index.removed.insert(hash.clone());
+        }
+
+        removed
+    }
+
+    pub fn router_infos(&self) -> Vec<Arc<RouterInfo>> {
+        self.index.read().unwrap().router_infos.values().cloned().collect()
+    }
+
+    pub fn find(&self, filter: &RouterFilter) -> Vec<Arc<RouterInfo>> {
+        self.index
+            .read()
+            .unwrap()
+            .router_infos
+            .values()
+            .filter(|router_info| filter.matches(router_info))
+            .cloned()
+            .collect()
+    }
+
+    /// Drops everything published too long ago, returning how many went
+    pub fn expire(&self) -> usize {
+        expire(&self.index, self.now())
+    }
+
+    /// Writes out what's changed since the last flush
+    pub fn flush(&self) -> Result<(), Error> {
+        flush(&self.store, &self.index)
+    }
+}
+
+fn expire(index: &RwLock<Index>, now: Date) -> usize {
+    let mut index = index.write().unwrap();
+    let expired: Vec<Hash> = index.router_infos
+        .iter()
+        .filter(|&(_, router_info)| is_expired(router_info, now))
+        .map(|(hash, _)| hash.clone())
+        .collect();
+    for hash in &expired {
+        index.router_infos.remove(hash);
+        index.dirty.remove(hash);
+        index.removed.insert(hash.clone());
+    }
+    if !expired.is_empty() {
+        info!("NetDB: expired {} RouterInfos", expired.len());
+    }
+
+    expired.len()
+}
+
+/// Takes what needs writing while holding the lock, then does the disk work
+/// without it. If that fails, whatever wasn't written is put back for the
+/// next flush.
+fn flush(store: &HashedStorage<RouterInfo>, index: &RwLock<Index>) -> Result<(), Error> {
+    let (dirty, removed) = {
+        let mut index = index.write().unwrap();
+        let dirty_hashes: Vec<Hash> = index.dirty.drain().collect();
+        let dirty: Vec<(Hash, Arc<RouterInfo>)> = dirty_hashes.into_iter()
+            .filter_map(|hash| {
+                index.router_infos.get(&hash).cloned().map(|router_info| (hash, router_info))
+            })
+            .collect();
+        let removed: Vec<Hash> = index.removed.drain().collect();
+        (dirty, removed)
+    };
+
+    let mut stored = 0;
+    let mut deleted = 0;
+    let mut result = Ok(());
+    for (hash, router_info) in &dirty {
+        if let Err(error) = store.store(&storage_key(hash), router_info) {
+            result = Err(error);
+            break;
+        }
+        stored += 1;
+    }
+    if result.is_ok() {
+        for hash in &removed {
+            if let Err(error) = store.remove(&storage_key(hash)) {
+                result = Err(error);
+                break;
+            }
+            deleted += 1;
+        }
+    }
+
+    if result.is_err() {
+        let mut index = index.write().unwrap();
+        index.dirty.extend(dirty.into_iter().skip(stored).map(|(hash, _)| hash));
+        for hash in removed.into_iter().skip(deleted) {
+            // Unless it's come back since
+            if !index.router_infos.contains_key(&hash) {
+                index.removed.insert(hash);
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::{PublicKeyType, SigningPublicKeyType};
+    use i2p::data::mapping::Mapping;
+    use i2p::data::private_keys::PrivateKeys;
+    use i2p::data::router_info::{Caps, RouterAddress};
+    use i2p::test_util::read_fixture;
+    use std::fs::{self, File};
+    use super::*;
+    use tempdir::TempDir;
+
+    fn router_info(caps: &str, published: Date, transport: Option<SupportedTransports>)
+                   -> RouterInfo {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let mut options = Mapping::new();
+        options.insert("caps", caps);
+        let addresses = transport.into_iter()
+            .map(|transport| {
+                RouterAddress {
+                    cost: 10,
+                    expiration: None,
+                    transport_style: transport,
+                    options: Mapping::new(),
+                }
+            })
+            .collect();
+
+        RouterInfo::new(&keys, published, addresses, options).unwrap()
+    }
+
+    fn netdb(data_dir: &TempDir) -> NetDB {
+        NetDB::with_flush_interval(data_dir.path(), Duration::from_secs(60))
+            .unwrap()
+    }
+
+    fn hash(byte: u8) -> Hash {
+        Hash::SHA256(vec![byte; 32].into_boxed_slice())
+    }
+
+    #[test]
+    fn test_legacy_store_removed() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let shard = data_dir.path().join("i2pd-rs/routerinfo/A");
+        fs::create_dir_all(&shard).unwrap();
+        File::create(shard.join("AAAA")).unwrap();
+
+        netdb(&data_dir);
+        assert!(!data_dir.path().join("i2pd-rs").exists());
+        assert!(data_dir.path().join("netDb").is_dir());
+    }
+
+    #[test]
+    fn test_insert_lookup_remove() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let router_info = router_info("LR", Date::now(), None);
+        let hash = router_info.hash().unwrap();
+
+        assert!(netdb.insert(router_info).unwrap());
+        assert_eq!(1, netdb.len());
+        assert_eq!(hash, netdb.lookup(&hash).unwrap().hash().unwrap());
+        assert!(netdb.remove(&hash).is_some());
+        assert!(netdb.lookup(&hash).is_none());
+        assert!(netdb.is_empty());
+    }
+
+    #[test]
+    fn test_insert_keeps_newest() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let now = Date::now();
+        let older = Date::from_millis(now.millis() - 60000);
+        let newer = RouterInfo::new(&keys, now, vec![], Mapping::new()).unwrap();
+
+        assert!(netdb.insert(newer).unwrap());
+        let older = RouterInfo::new(&keys, older, vec![], Mapping::new()).unwrap();
+        assert!(!netdb.insert(older).unwrap());
+        let hash = keys.identity().hash().unwrap();
+        assert_eq!(now, netdb.lookup(&hash).unwrap().published());
+    }
+
+    #[test]
+    fn test_insert_rejects_invalid() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let expired = Date::from_millis(Date::now().millis() - ROUTER_INFO_EXPIRATION - 1000);
+        assert!(netdb.insert(router_info("LR", expired, None)).is_err());
+        let future = Date::from_millis(Date::now().millis() + 10 * 60 * 1000);
+        assert!(netdb.insert(router_info("LR", future, None)).is_err());
+        assert!(netdb.is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_find() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        netdb.insert(router_info("fOR", Date::now(), Some(SupportedTransports::NTCPV4)))
+            .unwrap();
+        netdb.insert(router_info("LR", Date::now(), Some(SupportedTransports::SSUV4))).unwrap();
+        netdb.insert(router_info("LU", Date::now(), Some(SupportedTransports::NTCPV4))).unwrap();
+
+        assert_eq!(3, netdb.find(&Default::default()).len());
+        let floodfills = RouterFilter { caps: Caps::FloodFill as u8, ..Default::default() };
+        assert_eq!(1, netdb.find(&floodfills).len());
+        let ntcp = RouterFilter {
+            transport: Some(SupportedTransports::NTCPV4),
+            ..Default::default()
+        };
+        assert_eq!(2, netdb.find(&ntcp).len());
+        let reachable = RouterFilter { reachable: Some(true), ..Default::default() };
+        assert_eq!(2, netdb.find(&reachable).len());
+        let not_floodfill = RouterFilter {
+            excluded_caps: Caps::FloodFill as u8,
+            reachable: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(1, netdb.find(&not_floodfill).len());
+    }
+
+    #[test]
+    fn test_flush_and_reload() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let kept = router_info("LR", Date::now(), None);
+        let kept_hash = kept.hash().unwrap();
+        let removed = router_info("LR", Date::now(), None);
+        let removed_hash = removed.hash().unwrap();
+        netdb.insert(kept).unwrap();
+        netdb.insert(removed).unwrap();
+        netdb.flush().unwrap();
+        netdb.remove(&removed_hash);
+        netdb.flush().unwrap();
+
+        let reloaded = self::netdb(&data_dir);
+        reloaded.load(Date::now()).unwrap();
+        assert_eq!(1, reloaded.len());
+        assert!(reloaded.lookup(&kept_hash).is_some());
+    }
+
+    #[test]
+    fn test_failed_flush_retried() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let router_info = router_info("LR", Date::now(), None);
+        let hash = router_info.hash().unwrap();
+        netdb.insert(router_info).unwrap();
+
+        // With a file where the storage directory should be, nothing can
+        // be written
+        let directory = data_dir.path().join("netDb");
+        fs::remove_dir_all(&directory).unwrap();
+        File::create(&directory).unwrap();
+        assert!(netdb.flush().is_err());
+        assert!(netdb.index.read().unwrap().dirty.contains(&hash));
+
+        fs::remove_file(&directory).unwrap();
+        fs::create_dir(&directory).unwrap();
+        netdb.flush().unwrap();
+        assert!(netdb.store.contains(&storage_key(&hash)).unwrap());
+    }
+
+    #[test]
+    fn test_load_discards_expired() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let data = read_fixture("RouterInfo_EdDSA_SHA512_Ed25519");
+        let router_info = RouterInfo::deserialize(&mut data.as_slice()).unwrap();
+        let key = storage_key(&router_info.hash().unwrap());
+        netdb.store.store(&key, &router_info).unwrap();
+
+        // Still current a minute after it was published...
+        netdb.load(Date::from_millis(1490000060000)).unwrap();
+        assert_eq!(1, netdb.len());
+
+        // ...but not once it's expired, when it's deleted from disk too
+        let reloaded = self::netdb(&data_dir);
+        reloaded.load(Date::from_millis(1490000000000 + ROUTER_INFO_EXPIRATION + 1)).unwrap();
+        assert!(reloaded.is_empty());
+        assert!(!reloaded.store.contains(&key).unwrap());
+    }
+
+    #[test]
+    fn test_expire() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let router_info = router_info("LR", Date::now(), None);
+        let hash = router_info.hash().unwrap();
+        netdb.insert(router_info).unwrap();
+        netdb.flush().unwrap();
+
+        assert_eq!(0, netdb.expire());
+        let later = Date::from_millis(Date::now().millis() + ROUTER_INFO_EXPIRATION + 1000);
+        netdb.override_clock(Some(later));
+        assert_eq!(1, netdb.expire());
+        assert!(netdb.lookup(&hash).is_none());
+        netdb.flush().unwrap();
+        assert!(!netdb.store.contains(&storage_key(&hash)).unwrap());
+    }
+
+    #[test]
+    fn test_maintenance_uses_clock() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        netdb.start().unwrap();
+        let router_info = router_info("LR", Date::now(), None);
+        let hash = router_info.hash().unwrap();
+        netdb.insert(router_info).unwrap();
+        netdb.maintain();
+        assert!(netdb.store.contains(&storage_key(&hash)).unwrap());
+
+        let later = Date::from_millis(Date::now().millis() + ROUTER_INFO_EXPIRATION + 1000);
+        netdb.override_clock(Some(later));
+        netdb.maintain();
+        assert!(netdb.is_empty());
+        assert!(!netdb.store.contains(&storage_key(&hash)).unwrap());
+        netdb.stop();
+    }
+
+    #[test]
+    fn test_routing_key() {
+        let key = routing_key(&hash(0x11), Date::from_millis(1490000000000));
+        let Hash::SHA256(ref data) = key;
+        assert_eq!(vec![0x79, 0xf2, 0x6d, 0x7a, 0x51, 0x13, 0x5c, 0x7c], data[..8].to_vec());
+        // Same all day, different the next
+        assert_eq!(key, routing_key(&hash(0x11), Date::from_millis(1490054399999)));
+        assert!(key != routing_key(&hash(0x11), Date::from_millis(1490054400000)));
+    }
+
+    #[test]
+    fn test_xor_distance() {
+        assert_eq!(vec![0u8; 32], xor_distance(&hash(0x5a), &hash(0x5a)));
+        assert_eq!(vec![0x0f; 32], xor_distance(&hash(0xf0), &hash(0xff)));
+        assert!(xor_distance(&hash(0x00), &hash(0x01)) < xor_distance(&hash(0x00), &hash(0x80)));
+    }
+
+    #[test]
+    fn test_routing_keys_around_midnight() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let key = hash(0x11);
+        let march_20 = routing_key(&key, Date::from_millis(1490000000000));
+        let march_21 = routing_key(&key, Date::from_millis(1490054400000));
+
+        netdb.override_clock(Some(Date::from_millis(1490000000000)));
+        assert_eq!(vec![march_20.clone()], netdb.routing_keys(&key));
+        assert_eq!(march_20, netdb.routing_key(&key));
+
+        // 23:55 UTC
+        netdb.override_clock(Some(Date::from_millis(1490054100000)));
+        assert_eq!(vec![march_20.clone(), march_21.clone()], netdb.routing_keys(&key));
+
+        // 00:05 UTC the next day
+        netdb.override_clock(Some(Date::from_millis(1490054700000)));
+        assert_eq!(vec![march_21, march_20], netdb.routing_keys(&key));
+
+        // Just after the epoch there's no day before
+        netdb.override_clock(Some(Date::from_millis(1000)));
+        assert_eq!(vec![routing_key(&key, Date::from_millis(0))], netdb.routing_keys(&key));
+    }
+
+    #[test]
+    fn test_closest_floodfills() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let mut floodfills: Vec<Hash> = Vec::new();
+        for _ in 0..5 {
+            let router_info = router_info("fR", Date::now(), None);
+            floodfills.push(router_info.hash().unwrap());
+            netdb.insert(router_info).unwrap();
+        }
+        netdb.insert(router_info("R", Date::now(), None)).unwrap();
+
+        let key = netdb.routing_key(&hash(0x11));
+        floodfills.sort_by_key(|hash| xor_distance(&key, hash));
+        let closest: Vec<Hash> = netdb.closest_floodfills(&key, 3, &HashSet::new())
+            .iter()
+            .map(|router_info| router_info.hash().unwrap())
+            .collect();
+        assert_eq!(floodfills[..3].to_vec(), closest);
+
+        let mut exclude = HashSet::new();
+        exclude.insert(floodfills[0].clone());
+        let closest: Vec<Hash> = netdb.closest_floodfills(&key, 10, &exclude)
+            .iter()
+            .map(|router_info| router_info.hash().unwrap())
+            .collect();
+        assert_eq!(floodfills[1..].to_vec(), closest);
+    }
+}
@@ -0,0 +1,88 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::data::crypto::{Signature, SigningPublicKey, SigningPublicKeyType};
+use i2p::data::date::Date;
+use i2p::error::Error;
+use std::io::{Read, Write};
+
+/// Lets a destination sign with a short-lived transient key while the
+/// long-term signing key stays offline. The long-term key signs the expiry,
+/// transient key type and transient public key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OfflineSignature {
+    /// Seconds since the epoch, not milliseconds like a Date
+    expires: u32,
+    transient_key: SigningPublicKey,
+    signature: Signature,
+}
+
+impl OfflineSignature {
+    pub fn new(expires: u32,
+               transient_key: SigningPublicKey,
+               signature: Signature)
+               -> OfflineSignature {
+        OfflineSignature {
+            expires,
+            transient_key,
+            signature,
+        }
+    }
+
+    pub fn expires(&self) -> u32 {
+        self.expires
+    }
+
+    pub fn transient_key(&self) -> &SigningPublicKey {
+        &self.transient_key
+    }
+
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    pub fn is_expired(&self, now: Date) -> bool {
+        (self.expires as u64) * 1000 < now.millis()
+    }
+
+    /// The bytes the long-term signing key signs
+    pub fn signed_data(expires: u32, transient_key: &SigningPublicKey) -> Result<Vec<u8>, Error> {
+        let mut buffer: Vec<u8> = Vec::new();
+        buffer.write_u32::<BigEndian>(expires)?;
+        buffer.write_u16::<BigEndian>(transient_key.get_type() as u16)?;
+        transient_key.serialize(&mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    /// Checks the signature against the destination's long-term signing key
+    pub fn verify(&self, signing_key: &SigningPublicKey) -> Result<bool, Error> {
+        let data = OfflineSignature::signed_data(self.expires, &self.transient_key)?;
+        signing_key.verify(&data, &self.signature)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        writer.write_u32::<BigEndian>(self.expires)?;
+        writer.write_u16::<BigEndian>(self.transient_key.get_type() as u16)?;
+        let mut written: usize = 6;
+        written += self.transient_key.serialize(writer)?;
+        written += self.signature.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    /// The signature type is the destination's, which isn't part of the block
+    pub fn deserialize<R: Read>(signature_type: &SigningPublicKeyType,
+                                reader: &mut R)
+                                -> Result<OfflineSignature, Error> {
+        let expires = reader.read_u32::<BigEndian>()?;
+        let key_type = SigningPublicKeyType::from_u16(reader.read_u16::<BigEndian>()?)?;
+        let mut key = vec![0u8; SigningPublicKey::length(&key_type)];
+        reader.read_exact(key.as_mut_slice())?;
+        let signature = Signature::deserialize(signature_type, reader)?;
+
+        Ok(OfflineSignature {
+            expires,
+            transient_key: SigningPublicKey::new(key_type, &key),
+            signature,
+        })
+    }
+}
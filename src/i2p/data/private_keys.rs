@@ -0,0 +1,397 @@
+use i2p::crypto::{curve25519, elgamal, signature};
+use i2p::data::crypto::{Certificate, Destination, KeyCertificate, KeysAndCert, PrivateKey,
+                        PublicKey, PublicKeyType, Signature, SigningPrivateKey,
+                        SigningPublicKeyType};
+use i2p::data::offline_signature::OfflineSignature;
+use i2p::error::Error;
+use rand::{OsRng, Rng};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// A public identity along with the private keys that go with it. This is
+/// the layout i2pd and Java I2P use for `router.keys` and destination `.dat`
+/// files: the identity, then the encryption private key, then the signing
+/// private key.
+///
+/// When the long-term signing key is kept offline, the signing private key
+/// is all zeros, and an offline signature block follows with the transient
+/// signing private key at the end.
+pub struct PrivateKeys {
+    identity: KeysAndCert,
+    private_key: PrivateKey,
+    signing_private_key: SigningPrivateKey,
+    offline_keys: Option<OfflineKeys>,
+}
+
+pub struct OfflineKeys {
+    signature: OfflineSignature,
+    signing_private_key: SigningPrivateKey,
+}
+
+impl OfflineKeys {
+    pub fn signature(&self) -> &OfflineSignature {
+        &self.signature
+    }
+
+    pub fn signing_private_key(&self) -> &SigningPrivateKey {
+        &self.signing_private_key
+    }
+}
+
+impl PrivateKeys {
+    pub fn generate(crypto_key_type: &PublicKeyType,
+                    signing_key_type: &SigningPublicKeyType)
+                    -> Result<PrivateKeys, Error> {
+        let (private_key, public_key) = match *crypto_key_type {
+            PublicKeyType::ElGamal => {
+                let (private_key, public_key) = elgamal::generate_keypair()?;
+                (PrivateKey::ElGamal(private_key.into_boxed_slice()),
+                 PublicKey::ElGamal(public_key.into_boxed_slice()))
+            }
+            PublicKeyType::X25519 => {
+                let mut private_key = vec![0u8; curve25519::KEY_LENGTH];
+                OsRng::new()?.fill_bytes(&mut private_key);
+                let public_key = curve25519::x25519_public_key(&private_key)?;
+                (PrivateKey::X25519(private_key.into_boxed_slice()),
+                 PublicKey::X25519(public_key.into_boxed_slice()))
+            }
+        };
+        let (signing_private_key, signing_key) = signature::generate_keypair(signing_key_type)?;
+        // Only the original ElGamal/DSA identity can do without a key certificate
+        let certificate = match (crypto_key_type, signing_key_type) {
+            (&PublicKeyType::ElGamal, &SigningPublicKeyType::DSA_SHA1) => Certificate::Null,
+            _ => Certificate::Key(KeyCertificate::new(&public_key, &signing_key)?),
+        };
+
+        Ok(PrivateKeys {
+            identity: KeysAndCert::new(public_key, signing_key, certificate)?,
+            private_key,
+            signing_private_key,
+            offline_keys: None,
+        })
+    }
+
+    /// Creates a copy of these keys which signs with a new transient key,
+    /// valid until `expires` (in seconds since the epoch). The copy doesn't
+    /// contain our long-term signing private key.
+    pub fn create_offline_keys(&self,
+                               expires: u32,
+                               transient_key_type: &SigningPublicKeyType)
+                               -> Result<PrivateKeys, Error> {
+        if self.is_offline() {
+            return Err(Error::Crypto("Keys are already offline".to_string()));
+        }
+
+        let (transient_private_key, transient_key) =
+            signature::generate_keypair(transient_key_type)?;
+        let signature = self.sign(&OfflineSignature::signed_data(expires, &transient_key)?)?;
+        let key_type = self.signing_private_key.get_type();
+        let zeros = vec![0u8; SigningPrivateKey::length(&key_type)];
+
+        Ok(PrivateKeys {
+            identity: self.identity.clone(),
+            private_key: self.private_key.clone(),
+            signing_private_key: SigningPrivateKey::new(&key_type, &zeros)?,
+            offline_keys: Some(OfflineKeys {
+                signature: OfflineSignature::new(expires, transient_key, signature),
+                signing_private_key: transient_private_key,
+            }),
+        })
+    }
+
+    pub fn identity(&self) -> &KeysAndCert {
+        &self.identity
+    }
+
+    /// The identity, for keys that belong to a destination rather than a
+    /// router
+    pub fn destination(&self) -> Destination {
+        Destination::new(self.identity.clone())
+    }
+
+    pub fn private_key(&self) -> &PrivateKey {
+        &self.private_key
+    }
+
+    pub fn signing_private_key(&self) -> &SigningPrivateKey {
+        &self.signing_private_key
+    }
+
+    pub fn offline_keys(&self) -> Option<&OfflineKeys> {
+        self.offline_keys.as_ref()
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline_keys.is_some()
+    }
+
+    /// Signs with the transient key if we're offline, otherwise with our
+    /// long-term key
+    pub fn sign(&self, message: &[u8]) -> Result<Signature, Error> {
+        match self.offline_keys {
+            Some(ref offline_keys) => {
+                offline_keys.signing_private_key
+                    .sign(offline_keys.signature.transient_key(), message)
+            }
+            None => self.signing_private_key.sign(self.identity.signing_key(), message),
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut written = self.identity.serialize(&mut *writer)?;
+        written += self.private_key.serialize(writer)?;
+        written += self.signing_private_key.serialize(writer)?;
+        if let Some(ref offline_keys) = self.offline_keys {
+            written += offline_keys.signature.serialize(writer)?;
+            written += offline_keys.signing_private_key.serialize(writer)?;
+        }
+
+        Ok(written)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<PrivateKeys, Error> {
+        let identity = KeysAndCert::deserialize(&mut *reader)?;
+        let private_key = PrivateKey::deserialize(&identity.public_key().get_type(), reader)?;
+        let key_type = identity.signing_key().get_type();
+        let signing_private_key = SigningPrivateKey::deserialize(&key_type, reader)?;
+
+        // The file ends here unless the offline section follows
+        let mut rest: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut rest)?;
+        let offline_keys = if rest.is_empty() {
+            None
+        } else {
+            let mut rest = rest.as_slice();
+            let signature = OfflineSignature::deserialize(&key_type, &mut rest)?;
+            let transient_key_type = signature.transient_key().get_type();
+            let transient_private_key = SigningPrivateKey::deserialize(&transient_key_type,
+                                                                       &mut rest)?;
+            if !rest.is_empty() {
+                return Err(Error::Serialization(format!("{} unexpected bytes at the end of \
+                                                         the keys",
+                                                        rest.len())));
+            }
+            Some(OfflineKeys {
+                signature,
+                signing_private_key: transient_private_key,
+            })
+        };
+        let blank = signing_private_key.data().iter().all(|&b| b == 0);
+        match (blank, offline_keys.is_some()) {
+            (true, false) => {
+                return Err(Error::Serialization("Keys have no signing private key".to_string()))
+            }
+            (false, true) => {
+                return Err(Error::Serialization("Offline keys include the long-term signing \
+                                                 private key"
+                    .to_string()))
+            }
+            _ => {}
+        }
+
+        Ok(PrivateKeys {
+            identity,
+            private_key,
+            signing_private_key,
+            offline_keys,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<PrivateKeys, Error> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) => {
+                return Err(Error::IO {
+                    message: Some(format!("Error opening keys file {:?}", path)),
+                    error,
+                })
+            }
+        };
+
+        PrivateKeys::deserialize(&mut file)
+    }
+
+    /// Writes to a temporary file first, so a crash can't leave us with half
+    /// a keys file and a new identity on the next start
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut buffer: Vec<u8> = Vec::new();
+        self.serialize(&mut buffer)?;
+
+        let temp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(&buffer)?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, path)?;
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PrivateKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "PrivateKeys {{ identity: {:?}, offline: {} }}",
+               self.identity,
+               self.is_offline())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::SigningPublicKeyType;
+    use std::path::Path;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_load_destination_keys_file() {
+        let path = Path::new("fixtures/Destination_EdDSA_SHA512_Ed25519.dat");
+        let keys = PrivateKeys::load(path).unwrap();
+        assert!(!keys.is_offline());
+        assert_eq!(SigningPublicKeyType::EdDSA_SHA512_Ed25519,
+                   keys.identity().signing_key().get_type());
+        assert_eq!("t2hdypd4sjazudv4tr3gtieu72wvygesoeqdpyp3nk6cpzskijaa.b32.i2p",
+                   keys.destination().b32_address().unwrap().to_string());
+
+        let signature = keys.sign(b"message").unwrap();
+        assert!(keys.identity().signing_key().verify(b"message", &signature).unwrap());
+
+        let mut original: Vec<u8> = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut original).unwrap();
+        let mut buffer: Vec<u8> = Vec::new();
+        keys.serialize(&mut buffer).unwrap();
+        assert_eq!(original, buffer);
+    }
+
+    #[test]
+    fn test_offline_keys() {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let offline = keys.create_offline_keys(1600000000,
+                               &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        assert!(offline.is_offline());
+        assert!(offline.signing_private_key().data().iter().all(|&b| b == 0));
+
+        let mut buffer: Vec<u8> = Vec::new();
+        offline.serialize(&mut buffer).unwrap();
+        let loaded = PrivateKeys::deserialize(&mut buffer.as_slice()).unwrap();
+        let offline_signature = loaded.offline_keys().unwrap().signature();
+        assert_eq!(1600000000, offline_signature.expires());
+        assert!(offline_signature.verify(keys.identity().signing_key()).unwrap());
+        assert_eq!(keys.identity().hash().unwrap(), loaded.identity().hash().unwrap());
+
+        // Signatures come from the transient key, not the long-term one
+        let signature = loaded.sign(b"message").unwrap();
+        assert!(offline_signature.transient_key().verify(b"message", &signature).unwrap());
+        assert!(!keys.identity().signing_key().verify(b"message", &signature).unwrap());
+    }
+
+    #[test]
+    fn test_offline_keys_layout() {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let mut online: Vec<u8> = Vec::new();
+        keys.serialize(&mut online).unwrap();
+        let mut offline: Vec<u8> = Vec::new();
+        keys.create_offline_keys(1600000000, &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap()
+            .serialize(&mut offline)
+            .unwrap();
+
+        // A blank signing key without the offline section after it
+        assert!(PrivateKeys::deserialize(&mut &offline[..online.len()]).is_err());
+        // An offline section after a real signing key
+        let mut both = online.clone();
+        both.extend_from_slice(&offline[online.len()..]);
+        assert!(PrivateKeys::deserialize(&mut both.as_slice()).is_err());
+        // Anything after the keys
+        let mut trailing = offline.clone();
+        trailing.push(0);
+        assert!(PrivateKeys::deserialize(&mut trailing.as_slice()).is_err());
+        online.push(0);
+        assert!(PrivateKeys::deserialize(&mut online.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_generate_and_sign() {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let signature = keys.sign(b"message").unwrap();
+        assert!(keys.identity().signing_key().verify(b"message", &signature).unwrap());
+        assert!(!keys.identity().signing_key().verify(b"massage", &signature).unwrap());
+    }
+
+    #[test]
+    fn test_serialize_and_deserialize() {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = keys.serialize(&mut buffer).unwrap();
+        // 391-byte identity, 256-byte ElGamal key, 32-byte Ed25519 key
+        assert_eq!(679, size);
+
+        let loaded = PrivateKeys::deserialize(&mut buffer.as_slice()).unwrap();
+        assert_eq!(keys.identity().hash().unwrap(), loaded.identity().hash().unwrap());
+        assert_eq!(keys.private_key().data(), loaded.private_key().data());
+        assert_eq!(keys.signing_private_key().data(),
+                   loaded.signing_private_key().data());
+    }
+
+    #[test]
+    fn test_generate_x25519() {
+        let keys = PrivateKeys::generate(&PublicKeyType::X25519,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        assert_eq!(PublicKeyType::X25519, keys.identity().public_key().get_type());
+        assert_eq!(curve25519::x25519_public_key(keys.private_key().data()).unwrap(),
+                   keys.identity().public_key().data());
+
+        // The identity is still 391 bytes, ending in a key certificate for
+        // Ed25519 and X25519, with a 32-byte private key after it
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(455, keys.serialize(&mut buffer).unwrap());
+        assert_eq!(&[5, 0, 4, 0, 7, 0, 4], &buffer[384..391]);
+        let loaded = PrivateKeys::deserialize(&mut buffer.as_slice()).unwrap();
+        assert_eq!(keys.identity().hash().unwrap(), loaded.identity().hash().unwrap());
+        assert_eq!(PublicKeyType::X25519, loaded.private_key().get_type());
+        assert_eq!(keys.private_key().data(), loaded.private_key().data());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = TempDir::new("i2pd-test").unwrap();
+        let path = dir.path().join("router.keys");
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        keys.save(&path).unwrap();
+
+        let loaded = PrivateKeys::load(&path).unwrap();
+        assert_eq!(keys.identity().hash().unwrap(), loaded.identity().hash().unwrap());
+        let signature = loaded.sign(b"message").unwrap();
+        assert!(keys.identity().signing_key().verify(b"message", &signature).unwrap());
+    }
+
+    #[test]
+    fn test_load_truncated_file() {
+        let dir = TempDir::new("i2pd-test").unwrap();
+        let path = dir.path().join("router.keys");
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let mut buffer: Vec<u8> = Vec::new();
+        keys.serialize(&mut buffer).unwrap();
+        File::create(&path).unwrap().write_all(&buffer[..600]).unwrap();
+
+        assert!(PrivateKeys::load(&path).is_err());
+    }
+}
@@ -1,39 +1,266 @@
-use i2p::data::crypto;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use i2p::data::crypto::{self, Hash, Signature};
+use i2p::data::date::Date;
+use i2p::data::mapping::{self, Mapping};
+use i2p::data::private_keys::PrivateKeys;
+use i2p::error::Error;
+use i2p::fs::hashed_storage::Storable;
+use std::io::{Read, Write};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct RouterAddress {
     pub cost: u8,
-    pub expiration: Option<u64>,
+    pub expiration: Option<Date>,
     pub transport_style: SupportedTransports,
-    pub options: HashMap<String, String>,
+    pub options: Mapping,
 }
 
-#[derive(Debug)]
+impl RouterAddress {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        writer.write_u8(self.cost)?;
+        let mut written: usize = 1;
+        written += self.expiration.unwrap_or(Date::null()).serialize(writer)?;
+        written += mapping::write_string(writer, self.transport_style.style())?;
+        written += self.options.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<RouterAddress, Error> {
+        let cost = reader.read_u8()?;
+        let expiration = Date::deserialize_optional(reader)?;
+        let style = mapping::read_string(reader)?;
+        let options = Mapping::deserialize_sorted(reader)?;
+        let transport_style = SupportedTransports::from_style(&style, &options);
+
+        Ok(RouterAddress {
+            cost,
+            expiration,
+            transport_style,
+            options,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct RouterInfo {
     identity: crypto::RouterIdentity,
-    published: u64,
+    published: Date,
     addresses: Vec<RouterAddress>,
-    options: HashMap<String, String>,
+    peers: Vec<Hash>,
+    options: Mapping,
     signature: crypto::Signature,
 }
 
-#[derive(Debug)]
+impl RouterInfo {
+    /// Builds a RouterInfo for our own router, signed with our keys
+    pub fn new(keys: &PrivateKeys,
+               published: Date,
+               addresses: Vec<RouterAddress>,
+               options: Mapping)
+               -> Result<RouterInfo, Error> {
+        let key_type = keys.identity().signing_key().get_type();
+        let mut router_info = RouterInfo {
+            identity: keys.identity().clone(),
+            published,
+            addresses,
+            peers: Vec::new(),
+            options,
+            signature: Signature::new(&key_type, &vec![0u8; Signature::length(&key_type)])?,
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        router_info.serialize_unsigned(&mut buffer)?;
+        router_info.signature = keys.sign(&buffer)?;
+
+        Ok(router_info)
+    }
+
+    pub fn identity(&self) -> &crypto::RouterIdentity {
+        &self.identity
+    }
+
+    pub fn published(&self) -> Date {
+        self.published
+    }
+
+    pub fn addresses(&self) -> &[RouterAddress] {
+        &self.addresses
+    }
+
+    pub fn options(&self) -> &Mapping {
+        &self.options
+    }
+
+    pub fn signature(&self) -> &crypto::Signature {
+        &self.signature
+    }
+
+    /// The identity hash, which is what the NetDB is keyed by
+    pub fn hash(&self) -> Result<Hash, Error> {
+        self.identity.hash()
+    }
+
+    /// The `caps` option as a set of Caps flags. Unknown letters, such as
+    /// the bandwidth classes below O, are ignored.
+    pub fn caps(&self) -> u8 {
+        self.options.get("caps").map_or(0, |caps| {
+            caps.chars()
+                .filter_map(Caps::from_char)
+                .fold(0, |flags, cap| flags | cap as u8)
+        })
+    }
+
+    pub fn has_cap(&self, cap: Caps) -> bool {
+        self.caps() & cap as u8 != 0
+    }
+
+    pub fn is_floodfill(&self) -> bool {
+        self.has_cap(Caps::FloodFill)
+    }
+
+    /// Whether other routers can connect to this one directly
+    pub fn is_reachable(&self) -> bool {
+        let caps = self.caps();
+        caps & Caps::Reachable as u8 != 0 && caps & Caps::Unreachable as u8 == 0 &&
+        !self.addresses.is_empty()
+    }
+
+    pub fn supports_transport(&self, transport: &SupportedTransports) -> bool {
+        self.addresses.iter().any(|address| address.transport_style == *transport)
+    }
+
+    /// Checks the trailing signature against the signing key in our identity
+    pub fn verify(&self) -> Result<bool, Error> {
+        let mut buffer: Vec<u8> = Vec::new();
+        self.serialize_unsigned(&mut buffer)?;
+
+        self.identity.signing_key().verify(&buffer, &self.signature)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut written = self.serialize_unsigned(writer)?;
+        written += self.signature.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    /// Writes everything the signature covers, i.e. all but the signature itself
+    fn serialize_unsigned<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut written = self.identity.serialize(&mut *writer)?;
+        written += self.published.serialize(writer)?;
+        if self.addresses.len() > u8::MAX as usize {
+            return Err(Error::Serialization(format!("Too many router addresses: {}",
+                                                    self.addresses.len())));
+        }
+        writer.write_u8(self.addresses.len() as u8)?;
+        written += 1;
+        for address in &self.addresses {
+            written += address.serialize(writer)?;
+        }
+        if self.peers.len() > u8::MAX as usize {
+            return Err(Error::Serialization(format!("Too many peers: {}", self.peers.len())));
+        }
+        writer.write_u8(self.peers.len() as u8)?;
+        written += 1;
+        for peer in &self.peers {
+            let Hash::SHA256(ref data) = *peer;
+            written += writer.write(data)?;
+        }
+        written += self.options.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<RouterInfo, Error> {
+        let identity = crypto::RouterIdentity::deserialize(&mut *reader)?;
+        let published = Date::deserialize(reader)?;
+        let address_count = reader.read_u8()?;
+        let mut addresses: Vec<RouterAddress> = Vec::new();
+        for _ in 0..address_count {
+            addresses.push(RouterAddress::deserialize(reader)?);
+        }
+        let peer_count = reader.read_u8()?;
+        let mut peers: Vec<Hash> = Vec::new();
+        for _ in 0..peer_count {
+            let mut buffer = vec![0u8; 32];
+            reader.read_exact(buffer.as_mut_slice())?;
+            peers.push(Hash::SHA256(buffer.into_boxed_slice()));
+        }
+        let options = Mapping::deserialize_sorted(reader)?;
+        let signature = Signature::deserialize(&identity.signing_key().get_type(), reader)?;
+
+        Ok(RouterInfo {
+            identity,
+            published,
+            addresses,
+            peers,
+            options,
+            signature,
+        })
+    }
+}
+
+impl Storable for RouterInfo {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        RouterInfo::serialize(self, writer)
+    }
+
+    fn deserialize<R: Read>(reader: &mut R) -> Result<RouterInfo, Error> {
+        RouterInfo::deserialize(reader)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Default)]
 pub enum SupportedTransports {
+    #[default]
     NTCPV4,
     NTCPV6,
+    NTCP2V4,
+    NTCP2V6,
     SSUV4,
     SSUV6,
+    SSU2V4,
+    SSU2V6,
+    /// A style we don't speak. We keep it so the RouterInfo still
+    /// serializes the way it was signed, but never connect with it.
+    Unknown(String),
 }
 
-impl Default for SupportedTransports {
-    fn default() -> SupportedTransports {
-        SupportedTransports::NTCPV4
+impl SupportedTransports {
+    pub fn style(&self) -> &str {
+        match *self {
+            SupportedTransports::NTCPV4 |
+            SupportedTransports::NTCPV6 => "NTCP",
+            SupportedTransports::NTCP2V4 |
+            SupportedTransports::NTCP2V6 => "NTCP2",
+            SupportedTransports::SSUV4 |
+            SupportedTransports::SSUV6 => "SSU",
+            SupportedTransports::SSU2V4 |
+            SupportedTransports::SSU2V6 => "SSU2",
+            SupportedTransports::Unknown(ref style) => style,
+        }
+    }
+
+    /// The IP version isn't on the wire, so we work it out from the host option
+    fn from_style(style: &str, options: &Mapping) -> SupportedTransports {
+        let is_v6 = options.get("host").is_some_and(|host| host.contains(':'));
+        match (style, is_v6) {
+            ("NTCP", false) => SupportedTransports::NTCPV4,
+            ("NTCP", true) => SupportedTransports::NTCPV6,
+            ("NTCP2", false) => SupportedTransports::NTCP2V4,
+            ("NTCP2", true) => SupportedTransports::NTCP2V6,
+            ("SSU", false) => SupportedTransports::SSUV4,
+            ("SSU", true) => SupportedTransports::SSUV6,
+            ("SSU2", false) => SupportedTransports::SSU2V4,
+            ("SSU2", true) => SupportedTransports::SSU2V6,
+            _ => SupportedTransports::Unknown(style.to_string()),
+        }
     }
 }
 
-enum Caps {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Caps {
     FloodFill = 0x01,
     HighBandwidth = 0x02,
     ExtraBandwidth = 0x04,
@@ -44,8 +271,146 @@ enum Caps {
     Unreachable = 0x80,
 }
 
-enum TransportStyle {
-    Unknown = 0,
-    NTCP,
-    SSU,
+impl Caps {
+    fn from_char(cap: char) -> Option<Caps> {
+        match cap {
+            'f' => Some(Caps::FloodFill),
+            'O' => Some(Caps::HighBandwidth),
+            'P' | 'X' => Some(Caps::ExtraBandwidth),
+            'R' => Some(Caps::Reachable),
+            'B' => Some(Caps::SSUTesting),
+            'C' => Some(Caps::SSUIntroducer),
+            'H' => Some(Caps::Hidden),
+            'U' => Some(Caps::Unreachable),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::test_util::read_fixture;
+    use super::*;
+
+    #[test]
+    fn test_deserialize_router_info() {
+        let data = read_fixture("RouterInfo_EdDSA_SHA512_Ed25519");
+        let router_info = RouterInfo::deserialize(&mut data.as_slice()).unwrap();
+
+        assert_eq!(1490000000000, router_info.published().millis());
+        assert_eq!(2, router_info.addresses().len());
+        let ntcp = &router_info.addresses()[0];
+        assert_eq!(10, ntcp.cost);
+        assert_eq!(None, ntcp.expiration);
+        assert_eq!(SupportedTransports::NTCPV4, ntcp.transport_style);
+        assert_eq!("12345", *ntcp.options.get("port").unwrap());
+        assert_eq!(SupportedTransports::SSUV6,
+                   router_info.addresses()[1].transport_style);
+        assert_eq!("LR", *router_info.options().get("caps").unwrap());
+        assert_eq!(Caps::Reachable as u8, router_info.caps());
+        assert!(router_info.is_reachable());
+        assert!(!router_info.is_floodfill());
+        assert!(router_info.supports_transport(&SupportedTransports::SSUV6));
+        assert!(!router_info.supports_transport(&SupportedTransports::SSUV4));
+        assert_eq!("0.9.29", *router_info.options().get("router.version").unwrap());
+    }
+
+    #[test]
+    fn test_serialize_router_info() {
+        let data = read_fixture("RouterInfo_EdDSA_SHA512_Ed25519");
+        let router_info = RouterInfo::deserialize(&mut data.as_slice()).unwrap();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = router_info.serialize(&mut buffer).unwrap();
+        assert_eq!(data.len(), size);
+        assert_eq!(data, buffer);
+    }
+
+    #[test]
+    fn test_verify_router_info() {
+        let data = read_fixture("RouterInfo_EdDSA_SHA512_Ed25519");
+        let router_info = RouterInfo::deserialize(&mut data.as_slice()).unwrap();
+        assert!(router_info.verify().unwrap());
+    }
+
+    #[test]
+    fn test_deserialize_ntcp2_ssu2_router_info() {
+        let data = read_fixture("RouterInfo_NTCP2_SSU2_EdDSA_SHA512_Ed25519");
+        let router_info = RouterInfo::deserialize(&mut data.as_slice()).unwrap();
+        assert!(router_info.verify().unwrap());
+
+        assert_eq!(2, router_info.addresses().len());
+        let ntcp2 = &router_info.addresses()[0];
+        assert_eq!(SupportedTransports::NTCP2V4, ntcp2.transport_style);
+        assert_eq!("NTCP2", ntcp2.transport_style.style());
+        assert_eq!("23456", *ntcp2.options.get("port").unwrap());
+        assert_eq!(SupportedTransports::SSU2V6,
+                   router_info.addresses()[1].transport_style);
+        assert!(router_info.is_floodfill());
+
+        let mut buffer: Vec<u8> = Vec::new();
+        router_info.serialize(&mut buffer).unwrap();
+        assert_eq!(data, buffer);
+    }
+
+    #[test]
+    fn test_verify_tampered_router_info() {
+        let data = read_fixture("RouterInfo_NTCP2_SSU2_EdDSA_SHA512_Ed25519");
+        let mut router_info = RouterInfo::deserialize(&mut data.as_slice()).unwrap();
+        router_info.addresses[0].options.insert("port", "23457");
+        assert!(!router_info.verify().unwrap());
+    }
+
+    #[test]
+    fn test_unknown_transport_style() {
+        let mut options = Mapping::new();
+        options.insert("host", "203.0.113.7");
+        let address = RouterAddress {
+            cost: 10,
+            expiration: None,
+            transport_style: SupportedTransports::Unknown("XYZ".to_string()),
+            options,
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        address.serialize(&mut buffer).unwrap();
+
+        let loaded = RouterAddress::deserialize(&mut buffer.as_slice()).unwrap();
+        assert_eq!(SupportedTransports::Unknown("XYZ".to_string()), loaded.transport_style);
+        let mut reserialized: Vec<u8> = Vec::new();
+        loaded.serialize(&mut reserialized).unwrap();
+        assert_eq!(buffer, reserialized);
+    }
+
+    #[test]
+    fn test_new_router_info() {
+        let keys = PrivateKeys::generate(&crypto::PublicKeyType::ElGamal,
+                                         &crypto::SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let mut options = Mapping::new();
+        options.insert("netId", "2");
+        let router_info = RouterInfo::new(&keys, Date::from_millis(1490000000000), vec![], options)
+            .unwrap();
+        assert!(router_info.verify().unwrap());
+
+        let mut buffer: Vec<u8> = Vec::new();
+        router_info.serialize(&mut buffer).unwrap();
+        let loaded = RouterInfo::deserialize(&mut buffer.as_slice()).unwrap();
+        assert!(loaded.verify().unwrap());
+        assert_eq!(keys.identity().hash().unwrap(), loaded.identity().hash().unwrap());
+        assert_eq!(*loaded.options().get("netId").unwrap(), "2");
+    }
+
+    #[test]
+    fn test_serialize_too_many_peers() {
+        let data = read_fixture("RouterInfo_EdDSA_SHA512_Ed25519");
+        let mut router_info = RouterInfo::deserialize(&mut data.as_slice()).unwrap();
+        router_info.peers = vec![Hash::SHA256(vec![0u8; 32].into_boxed_slice()); 256];
+        assert!(router_info.serialize(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_truncated_router_info() {
+        let data = read_fixture("RouterInfo_EdDSA_SHA512_Ed25519");
+        assert!(RouterInfo::deserialize(&mut &data[..data.len() - 1]).is_err());
+    }
 }
@@ -1,8 +1,9 @@
+use gcrypt;
 use log;
 use log4rs;
+use native_tls;
 use serde_yaml;
 use std::error;
-use std::error::Error as StdError;
 use std::fmt;
 use std::io;
 use std::num;
@@ -16,15 +17,7 @@ pub enum ParseError {
 }
 
 impl error::Error for ParseError {
-    fn description(&self) -> &str {
-        match *self {
-            ParseError::Bool(ref error) => error.description(),
-            ParseError::Int(ref error) => error.description(),
-            ParseError::Float(ref error) => error.description(),
-        }
-    }
-
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
             ParseError::Bool(ref error) => Some(error),
             ParseError::Int(ref error) => Some(error),
@@ -44,6 +37,7 @@ impl fmt::Display for ParseError {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum LogError {
     LogConfig(log4rs::config::Error),
     LogConfigErrors(log4rs::config::Errors),
@@ -55,20 +49,11 @@ pub enum LogError {
 }
 
 impl error::Error for LogError {
-    fn description(&self) -> &str {
-        match *self {
-            LogError::LogConfig(ref error) => error.description(),
-            LogError::LogError { message: ref message, error: ref error } => error.description(),
-            LogError::LogConfigErrors(ref errors) => errors.description(),
-            LogError::SetLogger(ref error) => error.description(),
-        }
-    }
-
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
             LogError::LogConfig(ref error) => Some(error),
-            LogError::LogError { message: ref message, error: ref error } => Some(error),
-            LogError::LogConfigErrors(ref errors) => None,
+            LogError::LogError { ref error, .. } => Some(error),
+            LogError::LogConfigErrors(_) => None,
             LogError::SetLogger(ref error) => Some(error),
         }
     }
@@ -80,7 +65,7 @@ impl fmt::Display for LogError {
             LogError::LogConfig(ref error) => {
                 write!(f, "Error in logging configuration: {}", error)
             }
-            LogError::LogError { message: ref message, error: ref error } => {
+            LogError::LogError { ref message, ref error } => {
                 write!(f, "{}: {}", message, error)
             }
             LogError::LogConfigErrors(ref errors) => write!(f, "Logging errors: {}", errors),
@@ -90,6 +75,7 @@ impl fmt::Display for LogError {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     ConfigFile(serde_yaml::Error),
     IO {
@@ -101,6 +87,8 @@ pub enum Error {
     Logging(LogError),
     Serialization(String),
     Transport(String),
+    Tunnel(String),
+    State(String),
     ConvertString(str::Utf8Error),
     Crypto(String),
 }
@@ -115,7 +103,7 @@ impl From<io::Error> for Error {
     fn from(error: io::Error) -> Error {
         Error::IO {
             message: None,
-            error: error,
+            error,
         }
     }
 }
@@ -138,6 +126,18 @@ impl From<log4rs::config::Errors> for Error {
     }
 }
 
+impl From<gcrypt::Error> for Error {
+    fn from(error: gcrypt::Error) -> Error {
+        Error::Crypto(format!("{}", error))
+    }
+}
+
+impl From<native_tls::Error> for Error {
+    fn from(error: native_tls::Error) -> Error {
+        Error::Transport(format!("TLS error: {}", error))
+    }
+}
+
 impl From<str::Utf8Error> for Error {
     fn from(error: str::Utf8Error) -> Error {
         Error::ConvertString(error)
@@ -148,19 +148,21 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::ConfigFile(ref err) => write!(f, "Error reading configuration file: {}", err),
-            Error::ParseError { value: ref value, error: ref error } => {
+            Error::ParseError { ref value, ref error } => {
                 write!(f, "Error parsing value {}: {}", value, error)
             }
             Error::Configuration(ref err) => write!(f, "Configuration error: {}", err),
             Error::Logging(ref err) => write!(f, "Logging error: {}", err),
             Error::Serialization(ref err) => write!(f, "Serialization error: {}", err),
             Error::Transport(ref err) => write!(f, "Transport error: {}", err),
+            Error::Tunnel(ref err) => write!(f, "Tunnel error: {}", err),
+            Error::State(ref err) => write!(f, "Router state error: {}", err),
             Error::ConvertString(ref err) => write!(f, "String conversion error: {}", err),
             Error::Crypto(ref err) => write!(f, "Crypto error: {}", err),
-            Error::IO { message: ref message, error: ref error } => {
+            Error::IO { ref message, ref error } => {
                 write!(f,
                        "{}: {}",
-                       message.clone().unwrap_or(error.description().to_string()),
+                       message.clone().unwrap_or(error.to_string()),
                        error)
             }
         }
@@ -168,36 +170,19 @@ impl fmt::Display for Error {
 }
 
 impl error::Error for Error {
-    fn description(&self) -> &str {
-        match *self {
-            Error::ConfigFile(ref err) => err.description(),
-            Error::ParseError { value: _, error: ref error } => error.description(),
-            Error::Logging(ref err) => err.description(),
-            Error::Configuration(ref err) => err.as_str(),
-            Error::Serialization(ref err) => err,
-            Error::Transport(ref err) => err,
-            Error::Crypto(ref err) => err,
-            Error::ConvertString(ref err) => err.description(),
-            Error::IO { message: ref message, error: ref error } => {
-                match *message {
-                    Some(ref msg) => msg.as_str(),
-                    None => error.description(),
-                }
-            }
-        }
-    }
-
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
             Error::ConfigFile(ref e) => Some(e),
-            Error::ParseError { value: _, error: ref error } => Some(error),
+            Error::ParseError { value: _, ref error } => Some(error),
             Error::Logging(ref e) => Some(e),
             Error::Configuration(_) |
             Error::Serialization(_) |
             Error::Crypto(_) |
-            Error::Transport(_) => None,
+            Error::Transport(_) |
+            Error::Tunnel(_) |
+            Error::State(_) => None,
             Error::ConvertString(ref err) => Some(err),
-            Error::IO { message: ref message, error: ref error } => Some(error),
+            Error::IO { ref error, .. } => Some(error),
         }
     }
 }
\ No newline at end of file
@@ -5,10 +5,10 @@ pub struct EventLog {
 }
 
 impl EventLog {
-    pub fn new(config: &Config) -> EventLog {
+    pub fn new(_config: &Config) -> EventLog {
         EventLog {}
     }
 
-    pub fn add_event(&mut self, event: &str, info: Option<&str>) {
+    pub fn add_event(&mut self, _event: &str, _info: Option<&str>) {
     }
 }
\ No newline at end of file
@@ -1,142 +1,364 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::crypto;
+use i2p::data::encoding::base64_encode;
 use i2p::error::Error;
-use std::collections::HashMap;
 use std::fs;
 use std::io::{self, Read, Write};
+use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use std::str;
+use walkdir::{self, DirEntry, WalkDir};
 
-#[derive(Debug, Default)]
-pub struct HashedStorage {
-    directory: PathBuf,
-    hash_keys: bool,
+const SHARD_PREFIX: &str = "r";
+
+const FILE_SUFFIX: &str = ".dat";
+
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Anything that can be written to and read back from a HashedStorage
+pub trait Storable: Sized {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error>;
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error>;
 }
 
-trait Storage<T> {
-    fn store(&self, key: &str, value: &T) -> Result<(), Error>;
-    fn load(&self) -> Result<HashMap<String, T>, Error>;
+/// A directory of values, one file per key, laid out the same way as the
+/// i2pd netDb: `<name>/r<first char of key>/<kind>-<key>.dat`. With
+/// `hash_keys` set, the key used on disk is the Base64 SHA-256 of the one
+/// given, so any string can be used as a key, and each file starts with the
+/// original key (two-byte length, then UTF-8) so it can be read back.
+#[derive(Debug)]
+pub struct HashedStorage<T: Storable> {
+    directory: PathBuf,
+    kind: String,
+    hash_keys: bool,
+    phantom: PhantomData<T>,
 }
 
-impl HashedStorage {
+impl<T: Storable> HashedStorage<T> {
     pub fn new(data_dir: &Path,
-               app: &str,
+               name: &str,
                kind: &str,
                hash_keys: bool)
-               -> Result<HashedStorage, Error> {
-        let mut hashed_storage: HashedStorage = Default::default();
-        hashed_storage.initialize(data_dir, app, kind, hash_keys)?;
+               -> Result<HashedStorage<T>, Error> {
+        let mut directory = PathBuf::from(data_dir);
+        directory.push(name);
+        info!("Creating storage directory {:?}", directory);
+        if !directory.exists() {
+            fs::create_dir_all(&directory)?;
+        } else if !directory.is_dir() {
+            return Err(Error::Configuration(format!("Path {:?} exists but is not a directory",
+                                                    directory)));
+        }
 
-        Ok(hashed_storage)
+        Ok(HashedStorage {
+            directory,
+            kind: kind.to_owned(),
+            hash_keys,
+            phantom: PhantomData,
+        })
     }
 
-    fn initialize(&mut self,
-                  data_dir: &Path,
-                  app: &str,
-                  kind: &str,
-                  hash_keys: bool)
-                  -> Result<(), Error> {
-        self.directory = self.create_storage_directory(data_dir, app, kind)?;
-        self.hash_keys = hash_keys;
-
-        Ok(())
+    pub fn directory(&self) -> &Path {
+        &self.directory
     }
 
-    fn create_storage_directory(&self,
-                                data_dir: &Path,
-                                app: &str,
-                                kind: &str)
-                                -> Result<PathBuf, Error> {
-        let mut dir = PathBuf::new();
-        dir.push(data_dir);
-        dir.push(app);
-        dir.push(kind);
-        info!("Creating storage directory {:?}", dir);
-        if !dir.exists() {
-            fs::create_dir_all(dir.to_owned())?;
-        } else if !dir.is_dir() {
-            return Err(Error::Configuration(format!("Path {} exists but is not a directory",
-                                           dir.to_str().unwrap())));
+    /// The key as it appears in the file name
+    fn get_filename_key(&self, key: &str) -> Result<String, Error> {
+        if self.hash_keys {
+            return Ok(base64_encode(&crypto::sha256(key.as_bytes())));
+        }
+        if key.is_empty() || key.starts_with('.') || key.contains('/') || key.contains('\\') {
+            return Err(Error::Configuration(format!("Invalid storage key {:?}", key)));
         }
 
-        Ok(dir)
+        Ok(key.to_owned())
     }
 
-    fn get_filename(&self, key: &str) -> Result<String, Error> {
-        if self.hash_keys {
-            unimplemented!()
+    fn get_path(&self, key: &str) -> Result<PathBuf, Error> {
+        let filename_key = self.get_filename_key(key)?;
+        let mut path = self.directory.to_owned();
+        path.push(format!("{}{}", SHARD_PREFIX, filename_key.chars().nth(0).unwrap()));
+        path.push(format!("{}-{}{}", self.kind, filename_key, FILE_SUFFIX));
+
+        Ok(path)
+    }
+
+    /// Pulls the key back out of a file name, if it's one of ours
+    fn parse_filename(&self, filename: &str) -> Option<String> {
+        let prefix = format!("{}-", self.kind);
+        if filename.starts_with(&prefix) && filename.ends_with(FILE_SUFFIX) &&
+           filename.len() > prefix.len() + FILE_SUFFIX.len() {
+            Some(filename[prefix.len()..filename.len() - FILE_SUFFIX.len()].to_owned())
         } else {
-            Ok(key.to_owned())
+            None
         }
     }
 
-    fn create_file(&self, filename: &str) -> Result<fs::File, Error> {
-        let mut file_path = PathBuf::new();
-        file_path.push(self.directory.to_owned());
-        file_path.push(filename.chars().nth(0).unwrap().to_string());
-        file_path.push(filename);
-        if let Some(parent) = file_path.parent() {
+    /// Writes the value to a temporary file and renames it into place, so a
+    /// crash part way through never leaves a truncated value behind
+    pub fn store(&self, key: &str, value: &T) -> Result<(), Error> {
+        let path = self.get_path(key)?;
+        if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
-        Ok(fs::File::create(file_path)?)
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(TEMP_SUFFIX);
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        if self.hash_keys {
+            if key.len() > u16::MAX as usize {
+                return Err(Error::Configuration(format!("Storage key too long: {} bytes",
+                                                        key.len())));
+            }
+            buffer.write_u16::<BigEndian>(key.len() as u16)?;
+            buffer.write_all(key.as_bytes())?;
+        }
+        value.serialize(&mut buffer)?;
+        let result = fs::File::create(&temp_path).and_then(|mut file| {
+            file.write_all(&buffer)?;
+            file.sync_all()
+        });
+        if let Err(error) = result.and_then(|_| fs::rename(&temp_path, &path)) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(Error::IO {
+                message: Some(format!("Error writing {:?}", path)),
+                error,
+            });
+        }
+
+        Ok(())
     }
 
-    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+    /// Loads a single value, or None if there's nothing stored under the key
+    pub fn load(&self, key: &str) -> Result<Option<T>, Error> {
+        let path = self.get_path(key)?;
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let (_, value) = self.read(&path, &self.get_filename_key(key)?)?;
+
+        Ok(Some(value))
+    }
+
+    pub fn contains(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.get_path(key)?.is_file())
+    }
+
+    /// Removes the value stored under the key. Removing a key that isn't
+    /// there isn't an error.
+    pub fn remove(&self, key: &str) -> Result<(), Error> {
+        match fs::remove_file(self.get_path(key)?) {
+            Ok(()) => Ok(()),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(Error::from(error)),
+        }
+    }
+
+    /// Walks the storage directory, reading each value only when the
+    /// iterator gets to it. Keys come back as they were stored, even for
+    /// hashed storage. A value that can't be read comes back as an error, and
+    /// iteration carries on past it.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            storage: self,
+            entries: Box::new(WalkDir::new(&self.directory).min_depth(2).max_depth(2).into_iter()),
+        }
+    }
+
+    /// Reads the key and value from the file for the key in its name
+    fn read(&self, path: &Path, filename_key: &str) -> Result<(String, T), Error> {
         let mut buffer: Vec<u8> = Vec::new();
         fs::File::open(path)?.read_to_end(&mut buffer)?;
+        let mut reader = buffer.as_slice();
+        let key = if self.hash_keys {
+            let length = reader.read_u16::<BigEndian>()? as usize;
+            if reader.len() < length {
+                return Err(Error::Serialization(format!("Truncated key in {:?}", path)));
+            }
+            let key = str::from_utf8(&reader[..length])?.to_owned();
+            reader = &reader[length..];
+            if self.get_filename_key(&key)? != filename_key {
+                return Err(Error::Serialization(format!("{:?} holds the value for another key",
+                                                        path)));
+            }
+            key
+        } else {
+            filename_key.to_owned()
+        };
 
-        Ok(buffer)
+        Ok((key, T::deserialize(&mut reader)?))
     }
 
-    pub fn remove(&self, key: &str) {
-        unimplemented!()
+    fn is_shard(&self, entry: &DirEntry) -> bool {
+        entry.path()
+            .parent()
+            .and_then(|parent| parent.file_name())
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with(SHARD_PREFIX))
+            .unwrap_or(false)
     }
 }
 
-impl<T> Storage<T> for HashedStorage {
-    fn store(&self, key: &str, value: &T) -> Result<(), Error> {
-        let mut file: fs::File = self.create_file(&self.get_filename(key)?)?;
-        file.sync_all()?;
+pub struct Iter<'a, T: 'a + Storable> {
+    storage: &'a HashedStorage<T>,
+    entries: Box<dyn Iterator<Item = Result<DirEntry, walkdir::Error>>>,
+}
 
-        Ok(())
-    }
+impl<'a, T: Storable> Iterator for Iter<'a, T> {
+    type Item = Result<(String, T), Error>;
 
-    fn load(&self) -> Result<HashMap<String, T>, Error> {
-        let mut map: HashMap<String, T> = HashMap::new();
-        for result in WalkDir::new(self.directory.to_owned()) {
-            match result {
-                Ok(entry) => {
-                    if entry.path().is_file() {
-                        unimplemented!()
-                        // map.insert(entry.file_name().to_str().unwrap().to_string(),
-                        //            &self.read(entry.path())?[..]);
-                    }
-                }
-                Err(error) => return Err(Error::from(io::Error::from(error))),
+    fn next(&mut self) -> Option<Result<(String, T), Error>> {
+        loop {
+            let entry = match self.entries.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(error)) => return Some(Err(Error::from(io::Error::from(error)))),
+                None => return None,
+            };
+            if !entry.file_type().is_file() || !self.storage.is_shard(&entry) {
+                continue;
             }
-        }
+            let filename_key = match entry.file_name().to_str().and_then(|name| {
+                self.storage.parse_filename(name)
+            }) {
+                Some(key) => key,
+                None => continue,
+            };
 
-        Ok(map)
+            return Some(self.storage.read(entry.path(), &filename_key));
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use i2p::data::router_info::{RouterAddress, SupportedTransports};
-    use i2p::fs::hashed_storage::{HashedStorage, Storage};
-    use std::collections::HashMap;
-    use std::env;
+    use i2p::error::Error;
+    use std::fs;
+    use std::io::{Read, Write};
+    use super::*;
     use tempdir::TempDir;
 
+    #[derive(Debug, PartialEq)]
+    struct Value(Vec<u8>);
+
+    impl Storable for Value {
+        fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+            if self.0.is_empty() {
+                return Err(Error::Serialization("Empty value".to_string()));
+            }
+            writer.write_all(&self.0)?;
+            Ok(self.0.len())
+        }
+
+        fn deserialize<R: Read>(reader: &mut R) -> Result<Value, Error> {
+            let mut data: Vec<u8> = Vec::new();
+            reader.read_to_end(&mut data)?;
+            if data.is_empty() {
+                return Err(Error::Serialization("Empty value".to_string()));
+            }
+            Ok(Value(data))
+        }
+    }
+
+    fn storage(data_dir: &TempDir, hash_keys: bool) -> HashedStorage<Value> {
+        HashedStorage::new(data_dir.path(), "netDb", "value", hash_keys).unwrap()
+    }
+
     #[test]
     fn test_store_and_load() {
         let data_dir = TempDir::new("i2pd-test").unwrap();
-        let mut hashed_storage = HashedStorage::new(data_dir.path(), "test", "router-info", false)
-            .unwrap();
-        // let mut address: RouterAddress = Default::default();
-        // address.cost = 100;
-        // address.expiration = None;
-        // address.transport_style = SupportedTransports::SSUV4;
-        // hashed_storage.store("foo", &address);
-        // let data: HashMap<String, RouterAddress> = hashed_storage.load().unwrap();
-        // assert_eq!(address, *data.get("foo").unwrap());
-    }
-}
\ No newline at end of file
+        let storage = storage(&data_dir, false);
+        storage.store("foo", &Value(vec![1, 2, 3])).unwrap();
+
+        assert!(data_dir.path().join("netDb/rf/value-foo.dat").is_file());
+        assert!(!data_dir.path().join("netDb/rf/value-foo.dat.tmp").exists());
+        assert_eq!(Some(Value(vec![1, 2, 3])), storage.load("foo").unwrap());
+        assert_eq!(None, storage.load("bar").unwrap());
+
+        storage.store("foo", &Value(vec![4])).unwrap();
+        assert_eq!(Some(Value(vec![4])), storage.load("foo").unwrap());
+    }
+
+    #[test]
+    fn test_failed_store_keeps_old_value() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let storage = storage(&data_dir, false);
+        storage.store("foo", &Value(vec![1, 2, 3])).unwrap();
+        assert!(storage.store("foo", &Value(vec![])).is_err());
+        assert_eq!(Some(Value(vec![1, 2, 3])), storage.load("foo").unwrap());
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let storage = storage(&data_dir, false);
+        assert!(storage.store("", &Value(vec![1])).is_err());
+        assert!(storage.store("../foo", &Value(vec![1])).is_err());
+    }
+
+    #[test]
+    fn test_hashed_keys() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let storage = storage(&data_dir, true);
+        storage.store("../not a/path", &Value(vec![1])).unwrap();
+        assert!(storage.contains("../not a/path").unwrap());
+        assert_eq!(Some(Value(vec![1])), storage.load("../not a/path").unwrap());
+
+        let hashed = base64_encode(&crypto::sha256(b"../not a/path"));
+        let path = data_dir.path()
+            .join("netDb")
+            .join(format!("r{}", &hashed[..1]))
+            .join(format!("value-{}.dat", hashed));
+        assert!(path.is_file());
+        let (key, value) = storage.iter().next().unwrap().unwrap();
+        assert_eq!("../not a/path", key);
+        assert_eq!(Value(vec![1]), value);
+
+        // A file copied in under another key's name doesn't load
+        let other = storage.get_path("foo").unwrap();
+        fs::create_dir_all(other.parent().unwrap()).unwrap();
+        fs::copy(&path, &other).unwrap();
+        assert!(storage.load("foo").is_err());
+    }
+
+    #[test]
+    fn test_remove() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let storage = storage(&data_dir, false);
+        storage.store("foo", &Value(vec![1])).unwrap();
+        storage.remove("foo").unwrap();
+        assert!(!storage.contains("foo").unwrap());
+        storage.remove("foo").unwrap();
+    }
+
+    #[test]
+    fn test_iter() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let storage = storage(&data_dir, false);
+        storage.store("foo", &Value(vec![1])).unwrap();
+        storage.store("bar", &Value(vec![2])).unwrap();
+        storage.store("baz", &Value(vec![3])).unwrap();
+        // Neither of these should show up
+        fs::File::create(data_dir.path().join("netDb/rf/value-foo.dat.tmp")).unwrap();
+        fs::File::create(data_dir.path().join("netDb/rb/other-bar.dat")).unwrap();
+        // An unreadable value comes back as an error without stopping the rest
+        fs::create_dir(data_dir.path().join("netDb/rq")).unwrap();
+        fs::File::create(data_dir.path().join("netDb/rq/value-qux.dat")).unwrap();
+
+        let mut values: Vec<(String, Value)> = Vec::new();
+        let mut errors = 0;
+        for result in storage.iter() {
+            match result {
+                Ok(entry) => values.push(entry),
+                Err(_) => errors += 1,
+            }
+        }
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(vec![("bar".to_string(), Value(vec![2])),
+                        ("baz".to_string(), Value(vec![3])),
+                        ("foo".to_string(), Value(vec![1]))],
+                   values);
+        assert_eq!(1, errors);
+    }
+}
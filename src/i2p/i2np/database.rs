@@ -0,0 +1,560 @@
+//! The NetDB messages: storing RouterInfos and LeaseSets, looking them up,
+//! and the reply when a floodfill doesn't have what was asked for
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use i2p::data::crypto::Hash;
+use i2p::data::encrypted_lease_set::{ENCRYPTED_LEASE_SET_TYPE, EncryptedLeaseSet};
+use i2p::data::lease_set::LeaseSet;
+use i2p::data::lease_set2::{LEASE_SET2_TYPE, LeaseSet2, META_LEASE_SET_TYPE, MetaLeaseSet};
+use i2p::data::router_info::RouterInfo;
+use i2p::error::Error;
+use i2p::i2np::{check_consumed, read_bytes, read_hash, write_hash};
+use std::io::{Read, Write};
+
+pub const ROUTER_INFO_TYPE: u8 = 0;
+pub const LEASE_SET_TYPE: u8 = 1;
+
+/// A RouterInfo is a few kilobytes, even with lots of addresses
+const MAX_ROUTER_INFO_LENGTH: u64 = 64 * 1024;
+
+/// More excluded peers than this in a lookup isn't reasonable
+pub const MAX_EXCLUDED_PEERS: usize = 512;
+
+/// How many session tags a lookup can give for the reply
+pub const MAX_REPLY_TAGS: usize = 32;
+
+const FLAG_TUNNEL_DELIVERY: u8 = 0x01;
+const FLAG_ENCRYPTION: u8 = 0x02;
+const FLAG_LOOKUP_TYPE_MASK: u8 = 0x0c;
+const FLAG_LOOKUP_TYPE_SHIFT: u8 = 2;
+const FLAG_ECIES: u8 = 0x10;
+
+const REPLY_KEY_LENGTH: usize = 32;
+const SESSION_TAG_LENGTH: usize = 32;
+const RATCHET_TAG_LENGTH: usize = 8;
+
+/// What a DatabaseStore holds. RouterInfos are gzipped on the wire.
+#[derive(Debug)]
+pub enum StoreData {
+    RouterInfo(RouterInfo),
+    LeaseSet(LeaseSet),
+    LeaseSet2(LeaseSet2),
+    EncryptedLeaseSet(EncryptedLeaseSet),
+    MetaLeaseSet(MetaLeaseSet),
+}
+
+impl StoreData {
+    pub fn store_type(&self) -> u8 {
+        match *self {
+            StoreData::RouterInfo(_) => ROUTER_INFO_TYPE,
+            StoreData::LeaseSet(_) => LEASE_SET_TYPE,
+            StoreData::LeaseSet2(_) => LEASE_SET2_TYPE,
+            StoreData::EncryptedLeaseSet(_) => ENCRYPTED_LEASE_SET_TYPE,
+            StoreData::MetaLeaseSet(_) => META_LEASE_SET_TYPE,
+        }
+    }
+
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        match *self {
+            StoreData::RouterInfo(ref router_info) => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+                router_info.serialize(&mut encoder)?;
+                let compressed = encoder.finish()?;
+                if compressed.len() > u16::MAX as usize {
+                    return Err(Error::Serialization(format!("Compressed RouterInfo too long: {}",
+                                                            compressed.len())));
+                }
+                writer.write_u16::<BigEndian>(compressed.len() as u16)?;
+                writer.write_all(&compressed)?;
+                Ok(2 + compressed.len())
+            }
+            StoreData::LeaseSet(ref lease_set) => lease_set.serialize(writer),
+            StoreData::LeaseSet2(ref lease_set) => lease_set.serialize(writer),
+            StoreData::EncryptedLeaseSet(ref lease_set) => lease_set.serialize(writer),
+            StoreData::MetaLeaseSet(ref lease_set) => lease_set.serialize(writer),
+        }
+    }
+
+    fn parse(store_type: u8, reader: &mut &[u8]) -> Result<StoreData, Error> {
+        Ok(match store_type {
+            ROUTER_INFO_TYPE => {
+                let length = reader.read_u16::<BigEndian>()? as usize;
+                let compressed = read_bytes(reader, length)?;
+                let mut data: Vec<u8> = Vec::new();
+                GzDecoder::new(compressed)?
+                    .take(MAX_ROUTER_INFO_LENGTH + 1)
+                    .read_to_end(&mut data)?;
+                if data.len() as u64 > MAX_ROUTER_INFO_LENGTH {
+                    return Err(Error::Serialization("Stored RouterInfo too long".to_string()));
+                }
+                let mut data_reader = data.as_slice();
+                let router_info = RouterInfo::deserialize(&mut data_reader)?;
+                check_consumed(data_reader, "RouterInfo")?;
+                StoreData::RouterInfo(router_info)
+            }
+            LEASE_SET_TYPE => StoreData::LeaseSet(LeaseSet::deserialize(reader)?),
+            LEASE_SET2_TYPE => StoreData::LeaseSet2(LeaseSet2::deserialize(reader)?),
+            ENCRYPTED_LEASE_SET_TYPE => {
+                StoreData::EncryptedLeaseSet(EncryptedLeaseSet::deserialize(reader)?)
+            }
+            META_LEASE_SET_TYPE => StoreData::MetaLeaseSet(MetaLeaseSet::deserialize(reader)?),
+            _ => {
+                return Err(Error::Serialization(format!("Unknown DatabaseStore type {}",
+                                                        store_type)))
+            }
+        })
+    }
+}
+
+/// Where to send the DeliveryStatus acknowledging a store. A tunnel ID of
+/// zero means straight to the gateway router.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplyPath {
+    pub token: u32,
+    pub tunnel_id: u32,
+    pub gateway: Hash,
+}
+
+#[derive(Debug)]
+pub struct DatabaseStore {
+    pub key: Hash,
+    pub reply: Option<ReplyPath>,
+    pub data: StoreData,
+}
+
+impl DatabaseStore {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut written = write_hash(writer, &self.key)?;
+        writer.write_u8(self.data.store_type())?;
+        written += 1;
+        match self.reply {
+            Some(ref reply) => {
+                if reply.token == 0 {
+                    return Err(Error::Serialization("DatabaseStore reply token is zero".to_string()));
+                }
+                writer.write_u32::<BigEndian>(reply.token)?;
+                writer.write_u32::<BigEndian>(reply.tunnel_id)?;
+                written += 8 + write_hash(writer, &reply.gateway)?;
+            }
+            None => {
+                writer.write_u32::<BigEndian>(0)?;
+                written += 4;
+            }
+        }
+        written += self.data.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<DatabaseStore, Error> {
+        let mut reader = data;
+        let key = read_hash(&mut reader)?;
+        let store_type = reader.read_u8()?;
+        let token = reader.read_u32::<BigEndian>()?;
+        let reply = if token != 0 {
+            Some(ReplyPath {
+                token,
+                tunnel_id: reader.read_u32::<BigEndian>()?,
+                gateway: read_hash(&mut reader)?,
+            })
+        } else {
+            None
+        };
+        let store_data = StoreData::parse(store_type, &mut reader)?;
+        check_consumed(reader, "DatabaseStore")?;
+
+        Ok(DatabaseStore {
+            key,
+            reply,
+            data: store_data,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LookupType {
+    /// Whatever's stored under the key
+    Any = 0,
+    LeaseSet = 1,
+    RouterInfo = 2,
+    /// Asks for floodfills' non-floodfill neighbours of the key, to find
+    /// new routers
+    Exploration = 3,
+}
+
+impl LookupType {
+    fn from_u8(t: u8) -> LookupType {
+        match t & 0x03 {
+            0 => LookupType::Any,
+            1 => LookupType::LeaseSet,
+            2 => LookupType::RouterInfo,
+            _ => LookupType::Exploration,
+        }
+    }
+}
+
+/// How the reply to a lookup down a tunnel should be encrypted, so the
+/// tunnel's endpoint can't read it
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplyEncryption {
+    /// An AES key and session tags, for ElGamal/AES+SessionTags
+    SessionTags { reply_key: Vec<u8>, tags: Vec<Vec<u8>> },
+    /// A ChaCha20/Poly1305 key and a single ratchet tag, for ECIES-X25519
+    Ratchet { reply_key: Vec<u8>, tag: Vec<u8> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabaseLookup {
+    pub key: Hash,
+    /// The router to reply to, or the gateway of the reply tunnel
+    pub from: Hash,
+    pub reply_tunnel_id: Option<u32>,
+    pub lookup_type: LookupType,
+    /// Peers the floodfill shouldn't suggest, usually ones already asked
+    pub excluded: Vec<Hash>,
+    pub reply_encryption: Option<ReplyEncryption>,
+}
+
+impl DatabaseLookup {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        if self.excluded.len() > MAX_EXCLUDED_PEERS {
+            return Err(Error::Serialization(format!("Too many excluded peers: {}",
+                                                    self.excluded.len())));
+        }
+        let mut flags = (self.lookup_type as u8) << FLAG_LOOKUP_TYPE_SHIFT;
+        if self.reply_tunnel_id.is_some() {
+            flags |= FLAG_TUNNEL_DELIVERY;
+        }
+        match self.reply_encryption {
+            Some(ReplyEncryption::SessionTags { .. }) => flags |= FLAG_ENCRYPTION,
+            Some(ReplyEncryption::Ratchet { .. }) => flags |= FLAG_ECIES,
+            None => {}
+        }
+
+        let mut written = write_hash(writer, &self.key)?;
+        written += write_hash(writer, &self.from)?;
+        writer.write_u8(flags)?;
+        written += 1;
+        if let Some(tunnel_id) = self.reply_tunnel_id {
+            writer.write_u32::<BigEndian>(tunnel_id)?;
+            written += 4;
+        }
+        writer.write_u16::<BigEndian>(self.excluded.len() as u16)?;
+        written += 2;
+        for peer in &self.excluded {
+            written += write_hash(writer, peer)?;
+        }
+        match self.reply_encryption {
+            Some(ReplyEncryption::SessionTags { ref reply_key, ref tags }) => {
+                if tags.is_empty() || tags.len() > MAX_REPLY_TAGS {
+                    return Err(Error::Serialization(format!("Bad reply tag count {}",
+                                                            tags.len())));
+                }
+                written += write_fixed(writer, reply_key, REPLY_KEY_LENGTH, "reply key")?;
+                writer.write_u8(tags.len() as u8)?;
+                written += 1;
+                for tag in tags {
+                    written += write_fixed(writer, tag, SESSION_TAG_LENGTH, "session tag")?;
+                }
+            }
+            Some(ReplyEncryption::Ratchet { ref reply_key, ref tag }) => {
+                written += write_fixed(writer, reply_key, REPLY_KEY_LENGTH, "reply key")?;
+                writer.write_u8(1)?;
+                written += 1 + write_fixed(writer, tag, RATCHET_TAG_LENGTH, "ratchet tag")?;
+            }
+            None => {}
+        }
+
+        Ok(written)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<DatabaseLookup, Error> {
+        let mut reader = data;
+        let key = read_hash(&mut reader)?;
+        let from = read_hash(&mut reader)?;
+        let flags = reader.read_u8()?;
+        let reply_tunnel_id = if flags & FLAG_TUNNEL_DELIVERY != 0 {
+            Some(reader.read_u32::<BigEndian>()?)
+        } else {
+            None
+        };
+        let excluded_count = reader.read_u16::<BigEndian>()? as usize;
+        if excluded_count > MAX_EXCLUDED_PEERS {
+            return Err(Error::Serialization(format!("Too many excluded peers: {}",
+                                                    excluded_count)));
+        }
+        let mut excluded: Vec<Hash> = Vec::with_capacity(excluded_count);
+        for _ in 0..excluded_count {
+            excluded.push(read_hash(&mut reader)?);
+        }
+
+        let reply_encryption = match (flags & FLAG_ENCRYPTION != 0, flags & FLAG_ECIES != 0) {
+            (false, false) => None,
+            (true, false) => {
+                let reply_key = read_bytes(&mut reader, REPLY_KEY_LENGTH)?.to_vec();
+                let tag_count = reader.read_u8()? as usize;
+                if tag_count == 0 || tag_count > MAX_REPLY_TAGS {
+                    return Err(Error::Serialization(format!("Bad reply tag count {}",
+                                                            tag_count)));
+                }
+                let mut tags: Vec<Vec<u8>> = Vec::with_capacity(tag_count);
+                for _ in 0..tag_count {
+                    tags.push(read_bytes(&mut reader, SESSION_TAG_LENGTH)?.to_vec());
+                }
+                Some(ReplyEncryption::SessionTags {
+                    reply_key,
+                    tags,
+                })
+            }
+            (false, true) => {
+                let reply_key = read_bytes(&mut reader, REPLY_KEY_LENGTH)?.to_vec();
+                let tag_count = reader.read_u8()?;
+                if tag_count != 1 {
+                    return Err(Error::Serialization(format!("Bad ratchet tag count {}",
+                                                            tag_count)));
+                }
+                Some(ReplyEncryption::Ratchet {
+                    reply_key,
+                    tag: read_bytes(&mut reader, RATCHET_TAG_LENGTH)?.to_vec(),
+                })
+            }
+            (true, true) => {
+                return Err(Error::Serialization(format!("Unsupported lookup flags {:#x}", flags)))
+            }
+        };
+        check_consumed(reader, "DatabaseLookup")?;
+
+        Ok(DatabaseLookup {
+            key,
+            from,
+            reply_tunnel_id,
+            lookup_type: LookupType::from_u8((flags & FLAG_LOOKUP_TYPE_MASK) >>
+                                             FLAG_LOOKUP_TYPE_SHIFT),
+            excluded,
+            reply_encryption,
+        })
+    }
+}
+
+/// Sent by a floodfill that doesn't have the key, suggesting peers closer
+/// to it
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabaseSearchReply {
+    pub key: Hash,
+    pub peers: Vec<Hash>,
+    pub from: Hash,
+}
+
+impl DatabaseSearchReply {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        if self.peers.len() > u8::MAX as usize {
+            return Err(Error::Serialization(format!("Too many peers in search reply: {}",
+                                                    self.peers.len())));
+        }
+        let mut written = write_hash(writer, &self.key)?;
+        writer.write_u8(self.peers.len() as u8)?;
+        written += 1;
+        for peer in &self.peers {
+            written += write_hash(writer, peer)?;
+        }
+        written += write_hash(writer, &self.from)?;
+
+        Ok(written)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<DatabaseSearchReply, Error> {
+        let mut reader = data;
+        let key = read_hash(&mut reader)?;
+        let count = reader.read_u8()? as usize;
+        let mut peers: Vec<Hash> = Vec::with_capacity(count);
+        for _ in 0..count {
+            peers.push(read_hash(&mut reader)?);
+        }
+        let from = read_hash(&mut reader)?;
+        check_consumed(reader, "DatabaseSearchReply")?;
+
+        Ok(DatabaseSearchReply {
+            key,
+            peers,
+            from,
+        })
+    }
+}
+
+fn write_fixed<W: Write>(writer: &mut W,
+                         data: &[u8],
+                         length: usize,
+                         name: &str)
+                         -> Result<usize, Error> {
+    if data.len() != length {
+        return Err(Error::Serialization(format!("Expected a {}-byte {}, got {} bytes",
+                                                length,
+                                                name,
+                                                data.len())));
+    }
+    writer.write_all(data)?;
+
+    Ok(length)
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::test_util::read_fixture;
+    use super::*;
+
+    fn hash(byte: u8) -> Hash {
+        Hash::SHA256(vec![byte; 32].into_boxed_slice())
+    }
+
+    fn lookup() -> DatabaseLookup {
+        DatabaseLookup {
+            key: hash(1),
+            from: hash(2),
+            reply_tunnel_id: None,
+            lookup_type: LookupType::Any,
+            excluded: vec![],
+            reply_encryption: None,
+        }
+    }
+
+    fn round_trip(lookup: &DatabaseLookup) -> DatabaseLookup {
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = lookup.serialize(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), size);
+        DatabaseLookup::parse(&buffer).unwrap()
+    }
+
+    #[test]
+    fn test_store_router_info() {
+        let data = read_fixture("RouterInfo_EdDSA_SHA512_Ed25519");
+        let router_info = RouterInfo::deserialize(&mut data.as_slice()).unwrap();
+        let store = DatabaseStore {
+            key: router_info.hash().unwrap(),
+            reply: Some(ReplyPath {
+                token: 7,
+                tunnel_id: 0,
+                gateway: hash(3),
+            }),
+            data: StoreData::RouterInfo(router_info),
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = store.serialize(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), size);
+        assert_eq!(ROUTER_INFO_TYPE, buffer[32]);
+
+        let parsed = DatabaseStore::parse(&buffer).unwrap();
+        assert_eq!(store.key, parsed.key);
+        assert_eq!(store.reply, parsed.reply);
+        match parsed.data {
+            StoreData::RouterInfo(ref router_info) => {
+                let mut serialized: Vec<u8> = Vec::new();
+                router_info.serialize(&mut serialized).unwrap();
+                assert_eq!(data, serialized);
+            }
+            ref data => panic!("Unexpected store data {:?}", data),
+        }
+
+        assert!(DatabaseStore::parse(&buffer[..buffer.len() - 1]).is_err());
+        buffer.push(0);
+        assert!(DatabaseStore::parse(&buffer).is_err());
+    }
+
+    #[test]
+    fn test_store_lease_set() {
+        let data = read_fixture("LeaseSet_EdDSA_SHA512_Ed25519");
+        let lease_set = LeaseSet::deserialize(&mut data.as_slice()).unwrap();
+        let store = DatabaseStore {
+            key: lease_set.hash().unwrap(),
+            reply: None,
+            data: StoreData::LeaseSet(lease_set),
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(37 + data.len(), store.serialize(&mut buffer).unwrap());
+        assert_eq!(&data[..], &buffer[37..]);
+
+        let parsed = DatabaseStore::parse(&buffer).unwrap();
+        assert_eq!(None, parsed.reply);
+        assert_eq!(LEASE_SET_TYPE, parsed.data.store_type());
+
+        // Unknown store type
+        buffer[32] = 2;
+        assert!(DatabaseStore::parse(&buffer).is_err());
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup(), round_trip(&lookup()));
+
+        let mut exploration = lookup();
+        exploration.lookup_type = LookupType::Exploration;
+        exploration.reply_tunnel_id = Some(1234);
+        exploration.excluded = vec![hash(3), hash(4)];
+        assert_eq!(exploration, round_trip(&exploration));
+
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(135, exploration.serialize(&mut buffer).unwrap());
+        assert_eq!(0x0d, buffer[64]);
+    }
+
+    #[test]
+    fn test_lookup_reply_encryption() {
+        let mut session_tags = lookup();
+        session_tags.reply_encryption = Some(ReplyEncryption::SessionTags {
+            reply_key: vec![5; 32],
+            tags: vec![vec![6; 32], vec![7; 32]],
+        });
+        assert_eq!(session_tags, round_trip(&session_tags));
+
+        let mut ratchet = lookup();
+        ratchet.lookup_type = LookupType::LeaseSet;
+        ratchet.reply_encryption = Some(ReplyEncryption::Ratchet {
+            reply_key: vec![5; 32],
+            tag: vec![6; 8],
+        });
+        assert_eq!(ratchet, round_trip(&ratchet));
+
+        ratchet.reply_encryption = Some(ReplyEncryption::Ratchet {
+            reply_key: vec![5; 32],
+            tag: vec![6; 32],
+        });
+        assert!(ratchet.serialize(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_bad_lookup() {
+        let mut buffer: Vec<u8> = Vec::new();
+        lookup().serialize(&mut buffer).unwrap();
+        // Claims an excluded peer that isn't there
+        buffer[66] = 1;
+        assert!(DatabaseLookup::parse(&buffer).is_err());
+        // Too many excluded peers
+        buffer[65] = 0x02;
+        buffer[66] = 0x01;
+        assert!(DatabaseLookup::parse(&buffer).is_err());
+        // Both kinds of encryption
+        buffer[65] = 0;
+        buffer[66] = 0;
+        buffer[64] = FLAG_ENCRYPTION | FLAG_ECIES;
+        assert!(DatabaseLookup::parse(&buffer).is_err());
+    }
+
+    #[test]
+    fn test_search_reply() {
+        let reply = DatabaseSearchReply {
+            key: hash(1),
+            peers: vec![hash(2), hash(3), hash(4)],
+            from: hash(5),
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(161, reply.serialize(&mut buffer).unwrap());
+        assert_eq!(reply, DatabaseSearchReply::parse(&buffer).unwrap());
+
+        buffer[32] = 4;
+        assert!(DatabaseSearchReply::parse(&buffer).is_err());
+        buffer[32] = 2;
+        assert!(DatabaseSearchReply::parse(&buffer).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//! What's inside a Garlic message once it's decrypted: cloves, each an I2NP
+//! message with instructions saying where it goes
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::data::crypto::{Certificate, Hash};
+use i2p::data::date::Date;
+use i2p::error::Error;
+use i2p::i2np::{check_consumed, read_bytes, read_hash, write_hash, I2NPMessage};
+use rand::{thread_rng, Rng};
+use std::io::Write;
+
+const FLAG_ENCRYPTED: u8 = 0x80;
+const FLAG_DELIVERY_TYPE_MASK: u8 = 0x60;
+const FLAG_DELIVERY_TYPE_SHIFT: u8 = 5;
+const FLAG_DELAY: u8 = 0x10;
+
+const DELIVERY_LOCAL: u8 = 0;
+const DELIVERY_DESTINATION: u8 = 1;
+const DELIVERY_ROUTER: u8 = 2;
+const DELIVERY_TUNNEL: u8 = 3;
+
+/// Where a clove goes
+#[derive(Clone, Debug, PartialEq)]
+pub enum CloveDelivery {
+    /// To whoever decrypted the garlic
+    Local,
+    /// To one of that router's client destinations
+    Destination(Hash),
+    Router(Hash),
+    /// To a tunnel's gateway, and the tunnel ID there
+    Tunnel(Hash, u32),
+}
+
+impl CloveDelivery {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let (delivery_type, to) = match *self {
+            CloveDelivery::Local => (DELIVERY_LOCAL, None),
+            CloveDelivery::Destination(ref hash) => (DELIVERY_DESTINATION, Some(hash)),
+            CloveDelivery::Router(ref hash) => (DELIVERY_ROUTER, Some(hash)),
+            CloveDelivery::Tunnel(ref hash, _) => (DELIVERY_TUNNEL, Some(hash)),
+        };
+        writer.write_u8(delivery_type << FLAG_DELIVERY_TYPE_SHIFT)?;
+        let mut written = 1;
+        if let Some(to) = to {
+            written += write_hash(writer, to)?;
+        }
+        if let CloveDelivery::Tunnel(_, tunnel_id) = *self {
+            writer.write_u32::<BigEndian>(tunnel_id)?;
+            written += 4;
+        }
+
+        Ok(written)
+    }
+
+    fn parse(reader: &mut &[u8]) -> Result<CloveDelivery, Error> {
+        let flags = reader.read_u8()?;
+        // Nothing has ever used encrypted cloves
+        if flags & FLAG_ENCRYPTED != 0 {
+            return Err(Error::Serialization("Encrypted garlic cloves aren't supported".to_string()));
+        }
+        let delivery = match (flags & FLAG_DELIVERY_TYPE_MASK) >> FLAG_DELIVERY_TYPE_SHIFT {
+            DELIVERY_LOCAL => CloveDelivery::Local,
+            DELIVERY_DESTINATION => CloveDelivery::Destination(read_hash(reader)?),
+            DELIVERY_ROUTER => CloveDelivery::Router(read_hash(reader)?),
+            _ => {
+                let gateway = read_hash(reader)?;
+                let tunnel_id = reader.read_u32::<BigEndian>()?;
+                if tunnel_id == 0 {
+                    return Err(Error::Serialization("Tunnel ID can't be zero".to_string()));
+                }
+                CloveDelivery::Tunnel(gateway, tunnel_id)
+            }
+        };
+        // The delay was never implemented, so it's skipped
+        if flags & FLAG_DELAY != 0 {
+            read_bytes(reader, 4)?;
+        }
+
+        Ok(delivery)
+    }
+}
+
+/// Certificates are a type, a two-byte length, and that much data
+fn read_certificate(reader: &mut &[u8]) -> Result<Certificate, Error> {
+    if reader.len() < 3 {
+        return Err(Error::Serialization("Truncated certificate".to_string()));
+    }
+    let length = 3 + ((reader[1] as usize) << 8 | reader[2] as usize);
+    let mut data = read_bytes(reader, length)?;
+
+    Certificate::deserialize(&mut data)
+}
+
+#[derive(Debug)]
+pub struct Clove {
+    pub delivery: CloveDelivery,
+    pub message: I2NPMessage,
+    pub clove_id: u32,
+    pub expiration: Date,
+    /// Always null in practice
+    pub certificate: Certificate,
+}
+
+impl Clove {
+    /// A clove with a random ID, expiring with its message
+    pub fn new(delivery: CloveDelivery, message: I2NPMessage) -> Clove {
+        Clove {
+            delivery,
+            expiration: message.expiration,
+            message,
+            clove_id: thread_rng().gen(),
+            certificate: Certificate::Null,
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut written = self.delivery.serialize(writer)?;
+        written += self.message.serialize(writer)?;
+        writer.write_u32::<BigEndian>(self.clove_id)?;
+        written += 4;
+        written += self.expiration.serialize(writer)?;
+        written += self.certificate.serialize(&mut *writer)?;
+
+        Ok(written)
+    }
+
+    fn parse(reader: &mut &[u8]) -> Result<Clove, Error> {
+        Ok(Clove {
+            delivery: CloveDelivery::parse(reader)?,
+            message: I2NPMessage::deserialize(reader)?,
+            clove_id: reader.read_u32::<BigEndian>()?,
+            expiration: Date::deserialize(reader)?,
+            certificate: read_certificate(reader)?,
+        })
+    }
+}
+
+/// A decrypted Garlic message
+#[derive(Debug)]
+pub struct Garlic {
+    pub cloves: Vec<Clove>,
+    pub certificate: Certificate,
+    pub message_id: u32,
+    pub expiration: Date,
+}
+
+impl Garlic {
+    /// Garlic with a random ID, expiring with the last of its cloves
+    pub fn new(cloves: Vec<Clove>) -> Garlic {
+        let expiration = cloves.iter()
+            .map(|clove| clove.expiration)
+            .max()
+            .unwrap_or_else(Date::now);
+        Garlic {
+            cloves,
+            certificate: Certificate::Null,
+            message_id: thread_rng().gen(),
+            expiration,
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        if self.cloves.len() > u8::MAX as usize {
+            return Err(Error::Serialization(format!("Too many garlic cloves: {}",
+                                                    self.cloves.len())));
+        }
+        writer.write_u8(self.cloves.len() as u8)?;
+        let mut written = 1;
+        for clove in &self.cloves {
+            written += clove.serialize(writer)?;
+        }
+        written += self.certificate.serialize(&mut *writer)?;
+        writer.write_u32::<BigEndian>(self.message_id)?;
+        written += 4;
+        written += self.expiration.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Garlic, Error> {
+        let mut reader = data;
+        let count = reader.read_u8()?;
+        let mut cloves: Vec<Clove> = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            cloves.push(Clove::parse(&mut reader)?);
+        }
+        let certificate = read_certificate(&mut reader)?;
+        let message_id = reader.read_u32::<BigEndian>()?;
+        let expiration = Date::deserialize(&mut reader)?;
+        check_consumed(reader, "Garlic")?;
+
+        Ok(Garlic {
+            cloves,
+            certificate,
+            message_id,
+            expiration,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::i2np::{DeliveryStatus, MessageBody};
+    use super::*;
+
+    fn hash(value: u8) -> Hash {
+        Hash::SHA256(vec![value; 32].into_boxed_slice())
+    }
+
+    fn status(message_id: u32) -> I2NPMessage {
+        I2NPMessage {
+            message_id,
+            expiration: Date::from_millis(1490000000000),
+            body: MessageBody::DeliveryStatus(DeliveryStatus {
+                message_id: message_id + 1,
+                timestamp: Date::from_millis(1490000000000),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_garlic() {
+        let deliveries = [CloveDelivery::Local,
+                          CloveDelivery::Destination(hash(1)),
+                          CloveDelivery::Router(hash(2)),
+                          CloveDelivery::Tunnel(hash(3), 1234)];
+        let cloves: Vec<Clove> = deliveries.iter()
+            .enumerate()
+            .map(|(i, delivery)| Clove::new(delivery.clone(), status(i as u32 * 10)))
+            .collect();
+        let garlic = Garlic::new(cloves);
+        assert_eq!(Date::from_millis(1490000000000), garlic.expiration);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = garlic.serialize(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), size);
+        let parsed = Garlic::parse(&buffer).unwrap();
+        assert_eq!(garlic.message_id, parsed.message_id);
+        assert_eq!(garlic.expiration, parsed.expiration);
+        assert_eq!(Certificate::Null, parsed.certificate);
+        assert_eq!(4, parsed.cloves.len());
+        for (i, clove) in parsed.cloves.iter().enumerate() {
+            assert_eq!(deliveries[i], clove.delivery);
+            assert_eq!(garlic.cloves[i].clove_id, clove.clove_id);
+            assert_eq!(i as u32 * 10, clove.message.message_id);
+            assert_eq!(Certificate::Null, clove.certificate);
+        }
+
+        for length in 0..buffer.len() {
+            assert!(Garlic::parse(&buffer[..length]).is_err());
+        }
+        buffer.push(0);
+        assert!(Garlic::parse(&buffer).is_err());
+    }
+
+    #[test]
+    fn test_delivery_flags() {
+        // A delay is skipped over
+        let mut buffer = vec![(DELIVERY_ROUTER << FLAG_DELIVERY_TYPE_SHIFT) | FLAG_DELAY];
+        buffer.extend_from_slice(&[2u8; 32]);
+        buffer.extend_from_slice(&[0, 0, 0, 5]);
+        let mut reader = &buffer[..];
+        assert_eq!(CloveDelivery::Router(hash(2)), CloveDelivery::parse(&mut reader).unwrap());
+        assert!(reader.is_empty());
+
+        buffer[0] |= FLAG_ENCRYPTED;
+        assert!(CloveDelivery::parse(&mut &buffer[..]).is_err());
+
+        let mut tunnel = vec![DELIVERY_TUNNEL << FLAG_DELIVERY_TYPE_SHIFT];
+        tunnel.extend_from_slice(&[3u8; 32]);
+        tunnel.extend_from_slice(&[0, 0, 0, 0]);
+        assert!(CloveDelivery::parse(&mut &tunnel[..]).is_err());
+    }
+}
@@ -0,0 +1,544 @@
+//! I2NP, the messages routers exchange with each other. Every message has a
+//! header giving its type, ID and expiration; the transports carry either
+//! the standard 16-byte header or, in NTCP2 and SSU2, a shorter one.
+//!
+//! The parsers are written to be run on untrusted input: lengths are checked
+//! against what's actually there before anything is allocated, and a body
+//! has to be used up exactly.
+
+pub mod database;
+pub mod garlic;
+pub mod tunnel;
+pub mod tunnel_build;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::crypto;
+use i2p::data::crypto::Hash;
+use i2p::data::date::Date;
+use i2p::error::Error;
+use i2p::i2np::database::{DatabaseLookup, DatabaseSearchReply, DatabaseStore};
+use i2p::i2np::tunnel::{TunnelData, TunnelGateway};
+use i2p::i2np::tunnel_build::{BuildRecords, RecordLayout};
+use rand::{thread_rng, Rng};
+use std::io::{Read, Write};
+
+/// Type, message ID, expiration in milliseconds, size and checksum
+pub const HEADER_LENGTH: usize = 16;
+
+/// Type, message ID and expiration in seconds. The transport frames the
+/// message, so there's no size, and it checks integrity, so no checksum.
+pub const SHORT_HEADER_LENGTH: usize = 9;
+
+/// The size field is two bytes
+pub const MAX_BODY_LENGTH: usize = 65535;
+
+/// How long the messages we create are good for
+const DEFAULT_EXPIRATION: u64 = 60 * 1000;
+
+/// How long after it expires a message is still accepted, since the
+/// sender's clock may be behind ours
+const EXPIRATION_GRACE: u64 = 2 * 60 * 1000;
+
+/// How far in the future a message can expire. Anything further out is
+/// either bogus or from a router whose clock is way off.
+const MAX_EXPIRATION: u64 = 5 * 60 * 1000 + EXPIRATION_GRACE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageType {
+    DatabaseStore = 1,
+    DatabaseLookup = 2,
+    DatabaseSearchReply = 3,
+    DeliveryStatus = 10,
+    Garlic = 11,
+    TunnelData = 18,
+    TunnelGateway = 19,
+    Data = 20,
+    TunnelBuild = 21,
+    TunnelBuildReply = 22,
+    VariableTunnelBuild = 23,
+    VariableTunnelBuildReply = 24,
+    ShortTunnelBuild = 25,
+    OutboundTunnelBuildReply = 26,
+}
+
+impl MessageType {
+    pub fn from_u8(t: u8) -> Result<MessageType, Error> {
+        match t {
+            1 => Ok(MessageType::DatabaseStore),
+            2 => Ok(MessageType::DatabaseLookup),
+            3 => Ok(MessageType::DatabaseSearchReply),
+            10 => Ok(MessageType::DeliveryStatus),
+            11 => Ok(MessageType::Garlic),
+            18 => Ok(MessageType::TunnelData),
+            19 => Ok(MessageType::TunnelGateway),
+            20 => Ok(MessageType::Data),
+            21 => Ok(MessageType::TunnelBuild),
+            22 => Ok(MessageType::TunnelBuildReply),
+            23 => Ok(MessageType::VariableTunnelBuild),
+            24 => Ok(MessageType::VariableTunnelBuildReply),
+            25 => Ok(MessageType::ShortTunnelBuild),
+            26 => Ok(MessageType::OutboundTunnelBuildReply),
+            _ => Err(Error::Serialization(format!("Unknown I2NP message type {}", t))),
+        }
+    }
+}
+
+/// Acknowledges a message, usually one sent down a tunnel to test it
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeliveryStatus {
+    pub message_id: u32,
+    pub timestamp: Date,
+}
+
+impl DeliveryStatus {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        writer.write_u32::<BigEndian>(self.message_id)?;
+        let written = self.timestamp.serialize(writer)?;
+
+        Ok(4 + written)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<DeliveryStatus, Error> {
+        let mut reader = data;
+        let message_id = reader.read_u32::<BigEndian>()?;
+        let timestamp = Date::deserialize(&mut reader)?;
+        check_consumed(reader, "DeliveryStatus")?;
+
+        Ok(DeliveryStatus {
+            message_id,
+            timestamp,
+        })
+    }
+}
+
+// Each message is built once and moved along, so the larger bodies aren't boxed
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum MessageBody {
+    DatabaseStore(DatabaseStore),
+    DatabaseLookup(DatabaseLookup),
+    DatabaseSearchReply(DatabaseSearchReply),
+    DeliveryStatus(DeliveryStatus),
+    /// Encrypted cloves, which only the recipient can read
+    Garlic(Vec<u8>),
+    TunnelData(TunnelData),
+    TunnelGateway(TunnelGateway),
+    /// An opaque payload for a client
+    Data(Vec<u8>),
+    TunnelBuild(BuildRecords),
+    TunnelBuildReply(BuildRecords),
+    VariableTunnelBuild(BuildRecords),
+    VariableTunnelBuildReply(BuildRecords),
+    ShortTunnelBuild(BuildRecords),
+    /// The reply to a ShortTunnelBuild for an outbound tunnel, which the
+    /// endpoint sends back to the creator
+    OutboundTunnelBuildReply(BuildRecords),
+}
+
+impl MessageBody {
+    pub fn message_type(&self) -> MessageType {
+        match *self {
+            MessageBody::DatabaseStore(_) => MessageType::DatabaseStore,
+            MessageBody::DatabaseLookup(_) => MessageType::DatabaseLookup,
+            MessageBody::DatabaseSearchReply(_) => MessageType::DatabaseSearchReply,
+            MessageBody::DeliveryStatus(_) => MessageType::DeliveryStatus,
+            MessageBody::Garlic(_) => MessageType::Garlic,
+            MessageBody::TunnelData(_) => MessageType::TunnelData,
+            MessageBody::TunnelGateway(_) => MessageType::TunnelGateway,
+            MessageBody::Data(_) => MessageType::Data,
+            MessageBody::TunnelBuild(_) => MessageType::TunnelBuild,
+            MessageBody::TunnelBuildReply(_) => MessageType::TunnelBuildReply,
+            MessageBody::VariableTunnelBuild(_) => MessageType::VariableTunnelBuild,
+            MessageBody::VariableTunnelBuildReply(_) => MessageType::VariableTunnelBuildReply,
+            MessageBody::ShortTunnelBuild(_) => MessageType::ShortTunnelBuild,
+            MessageBody::OutboundTunnelBuildReply(_) => MessageType::OutboundTunnelBuildReply,
+        }
+    }
+
+    /// The build records, if this is one of the tunnel build messages
+    pub fn build_records(&self) -> Option<(&BuildRecords, RecordLayout)> {
+        match *self {
+            MessageBody::TunnelBuild(ref records) |
+            MessageBody::TunnelBuildReply(ref records) => Some((records, RecordLayout::Fixed)),
+            MessageBody::VariableTunnelBuild(ref records) |
+            MessageBody::VariableTunnelBuildReply(ref records) => {
+                Some((records, RecordLayout::Variable))
+            }
+            MessageBody::ShortTunnelBuild(ref records) |
+            MessageBody::OutboundTunnelBuildReply(ref records) => {
+                Some((records, RecordLayout::Short))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        match *self {
+            MessageBody::DatabaseStore(ref store) => store.serialize(writer),
+            MessageBody::DatabaseLookup(ref lookup) => lookup.serialize(writer),
+            MessageBody::DatabaseSearchReply(ref reply) => reply.serialize(writer),
+            MessageBody::DeliveryStatus(ref status) => status.serialize(writer),
+            MessageBody::Garlic(ref data) |
+            MessageBody::Data(ref data) => write_length_prefixed(writer, data),
+            MessageBody::TunnelData(ref tunnel_data) => tunnel_data.serialize(writer),
+            MessageBody::TunnelGateway(ref gateway) => gateway.serialize(writer),
+            MessageBody::TunnelBuild(ref records) |
+            MessageBody::TunnelBuildReply(ref records) => {
+                records.serialize(writer, RecordLayout::Fixed)
+            }
+            MessageBody::VariableTunnelBuild(ref records) |
+            MessageBody::VariableTunnelBuildReply(ref records) => {
+                records.serialize(writer, RecordLayout::Variable)
+            }
+            MessageBody::ShortTunnelBuild(ref records) |
+            MessageBody::OutboundTunnelBuildReply(ref records) => {
+                records.serialize(writer, RecordLayout::Short)
+            }
+        }
+    }
+
+    /// Parses a body of the given type, which has to take up all of `data`
+    pub fn parse(message_type: MessageType, data: &[u8]) -> Result<MessageBody, Error> {
+        Ok(match message_type {
+            MessageType::DatabaseStore => MessageBody::DatabaseStore(DatabaseStore::parse(data)?),
+            MessageType::DatabaseLookup => {
+                MessageBody::DatabaseLookup(DatabaseLookup::parse(data)?)
+            }
+            MessageType::DatabaseSearchReply => {
+                MessageBody::DatabaseSearchReply(DatabaseSearchReply::parse(data)?)
+            }
+            MessageType::DeliveryStatus => {
+                MessageBody::DeliveryStatus(DeliveryStatus::parse(data)?)
+            }
+            MessageType::Garlic => MessageBody::Garlic(read_length_prefixed(data, "Garlic")?),
+            MessageType::TunnelData => MessageBody::TunnelData(TunnelData::parse(data)?),
+            MessageType::TunnelGateway => MessageBody::TunnelGateway(TunnelGateway::parse(data)?),
+            MessageType::Data => MessageBody::Data(read_length_prefixed(data, "Data")?),
+            MessageType::TunnelBuild => {
+                MessageBody::TunnelBuild(BuildRecords::parse(data, RecordLayout::Fixed)?)
+            }
+            MessageType::TunnelBuildReply => {
+                MessageBody::TunnelBuildReply(BuildRecords::parse(data, RecordLayout::Fixed)?)
+            }
+            MessageType::VariableTunnelBuild => {
+                let records = BuildRecords::parse(data, RecordLayout::Variable)?;
+                MessageBody::VariableTunnelBuild(records)
+            }
+            MessageType::VariableTunnelBuildReply => {
+                let records = BuildRecords::parse(data, RecordLayout::Variable)?;
+                MessageBody::VariableTunnelBuildReply(records)
+            }
+            MessageType::ShortTunnelBuild => {
+                MessageBody::ShortTunnelBuild(BuildRecords::parse(data, RecordLayout::Short)?)
+            }
+            MessageType::OutboundTunnelBuildReply => {
+                let records = BuildRecords::parse(data, RecordLayout::Short)?;
+                MessageBody::OutboundTunnelBuildReply(records)
+            }
+        })
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buffer: Vec<u8> = Vec::new();
+        self.serialize(&mut buffer)?;
+        if buffer.len() > MAX_BODY_LENGTH {
+            return Err(Error::Serialization(format!("{:?} message too long: {}",
+                                                    self.message_type(),
+                                                    buffer.len())));
+        }
+
+        Ok(buffer)
+    }
+}
+
+#[derive(Debug)]
+pub struct I2NPMessage {
+    pub message_id: u32,
+    pub expiration: Date,
+    pub body: MessageBody,
+}
+
+impl I2NPMessage {
+    /// A new message with a random ID, expiring in a minute
+    pub fn new(body: MessageBody) -> I2NPMessage {
+        I2NPMessage {
+            message_id: thread_rng().gen(),
+            expiration: Date::from_millis(Date::now().millis() + DEFAULT_EXPIRATION),
+            body,
+        }
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        self.body.message_type()
+    }
+
+    pub fn is_expired(&self, now: Date) -> bool {
+        self.expiration.millis().saturating_add(EXPIRATION_GRACE) < now.millis()
+    }
+
+    /// Messages are dropped if they've expired or claim to be good for
+    /// longer than any router would make them
+    pub fn check_expiration(&self, now: Date) -> Result<(), Error> {
+        if self.is_expired(now) {
+            return Err(Error::Serialization(format!("{:?} message {} expired at {}",
+                                                    self.message_type(),
+                                                    self.message_id,
+                                                    self.expiration.millis())));
+        }
+        if self.expiration.millis() > now.millis().saturating_add(MAX_EXPIRATION) {
+            return Err(Error::Serialization(format!("{:?} message {} expires too far in the \
+                                                     future: {}",
+                                                    self.message_type(),
+                                                    self.message_id,
+                                                    self.expiration.millis())));
+        }
+
+        Ok(())
+    }
+
+    /// Writes the message with the standard header
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let body = self.body.to_bytes()?;
+        writer.write_u8(self.message_type() as u8)?;
+        writer.write_u32::<BigEndian>(self.message_id)?;
+        self.expiration.serialize(writer)?;
+        writer.write_u16::<BigEndian>(body.len() as u16)?;
+        writer.write_u8(checksum(&body))?;
+        writer.write_all(&body)?;
+
+        Ok(HEADER_LENGTH + body.len())
+    }
+
+    /// Reads a message with the standard header
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<I2NPMessage, Error> {
+        let message_type = MessageType::from_u8(reader.read_u8()?)?;
+        let message_id = reader.read_u32::<BigEndian>()?;
+        let expiration = Date::deserialize(reader)?;
+        let size = reader.read_u16::<BigEndian>()? as usize;
+        let expected_checksum = reader.read_u8()?;
+        let mut body = vec![0u8; size];
+        reader.read_exact(body.as_mut_slice())?;
+        if checksum(&body) != expected_checksum {
+            return Err(Error::Serialization(format!("Bad checksum on {:?} message {}",
+                                                    message_type,
+                                                    message_id)));
+        }
+
+        Ok(I2NPMessage {
+            message_id,
+            expiration,
+            body: MessageBody::parse(message_type, &body)?,
+        })
+    }
+
+    /// Writes the message with the short header NTCP2 and SSU2 use. The
+    /// expiration loses its milliseconds.
+    pub fn serialize_short<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let body = self.body.to_bytes()?;
+        writer.write_u8(self.message_type() as u8)?;
+        writer.write_u32::<BigEndian>(self.message_id)?;
+        writer.write_u32::<BigEndian>(self.expiration.seconds())?;
+        writer.write_all(&body)?;
+
+        Ok(SHORT_HEADER_LENGTH + body.len())
+    }
+
+    /// How long `serialize_short` would make the message
+    pub fn short_length(&self) -> Result<usize, Error> {
+        Ok(SHORT_HEADER_LENGTH + self.body.to_bytes()?.len())
+    }
+
+    /// Reads a message with the short header. The transport's framing gives
+    /// the length, so `data` has to be exactly one message.
+    pub fn deserialize_short(data: &[u8]) -> Result<I2NPMessage, Error> {
+        if data.len() > SHORT_HEADER_LENGTH + MAX_BODY_LENGTH {
+            return Err(Error::Serialization(format!("I2NP message too long: {}", data.len())));
+        }
+        let mut reader = data;
+        let message_type = MessageType::from_u8(reader.read_u8()?)?;
+        let message_id = reader.read_u32::<BigEndian>()?;
+        let expiration = Date::from_seconds(reader.read_u32::<BigEndian>()?);
+
+        Ok(I2NPMessage {
+            message_id,
+            expiration,
+            body: MessageBody::parse(message_type, reader)?,
+        })
+    }
+}
+
+/// The first byte of the body's SHA-256 hash
+fn checksum(body: &[u8]) -> u8 {
+    crypto::sha256(body)[0]
+}
+
+/// Parsers hand what's left of their input here once they're done
+pub fn check_consumed(rest: &[u8], name: &str) -> Result<(), Error> {
+    if !rest.is_empty() {
+        return Err(Error::Serialization(format!("{} trailing bytes after {}", rest.len(), name)));
+    }
+
+    Ok(())
+}
+
+pub fn read_hash(reader: &mut &[u8]) -> Result<Hash, Error> {
+    if reader.len() < 32 {
+        return Err(Error::Serialization("Truncated hash".to_string()));
+    }
+    let (hash, rest) = reader.split_at(32);
+    *reader = rest;
+
+    Ok(Hash::SHA256(hash.to_vec().into_boxed_slice()))
+}
+
+pub fn write_hash<W: Write>(writer: &mut W, hash: &Hash) -> Result<usize, Error> {
+    let Hash::SHA256(ref data) = *hash;
+    writer.write_all(data)?;
+
+    Ok(data.len())
+}
+
+/// Splits `length` bytes off the front of `reader`, without trusting the
+/// length to allocate
+pub fn read_bytes<'a>(reader: &mut &'a [u8], length: usize) -> Result<&'a [u8], Error> {
+    if reader.len() < length {
+        return Err(Error::Serialization(format!("Expected {} bytes, only {} left",
+                                                length,
+                                                reader.len())));
+    }
+    let (bytes, rest) = reader.split_at(length);
+    *reader = rest;
+
+    Ok(bytes)
+}
+
+fn write_length_prefixed<W: Write>(writer: &mut W, data: &[u8]) -> Result<usize, Error> {
+    writer.write_u32::<BigEndian>(data.len() as u32)?;
+    writer.write_all(data)?;
+
+    Ok(4 + data.len())
+}
+
+fn read_length_prefixed(data: &[u8], name: &str) -> Result<Vec<u8>, Error> {
+    let mut reader = data;
+    let length = reader.read_u32::<BigEndian>()? as usize;
+    let contents = read_bytes(&mut reader, length)?.to_vec();
+    check_consumed(reader, name)?;
+
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn data_message() -> I2NPMessage {
+        I2NPMessage {
+            message_id: 0x01020304,
+            expiration: Date::from_millis(1490000000000),
+            body: MessageBody::Data(b"data".to_vec()),
+        }
+    }
+
+    const DATA_MESSAGE: [u8; 24] = [0x14, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x01, 0x5a, 0xea,
+                                    0xeb, 0xb4, 0x00, 0x00, 0x08, 0x3c, 0x00, 0x00, 0x00, 0x04,
+                                    0x64, 0x61, 0x74, 0x61];
+
+    #[test]
+    fn test_serialize_message() {
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = data_message().serialize(&mut buffer).unwrap();
+        assert_eq!(24, size);
+        assert_eq!(&DATA_MESSAGE[..], buffer.as_slice());
+    }
+
+    #[test]
+    fn test_deserialize_message() {
+        let message = I2NPMessage::deserialize(&mut &DATA_MESSAGE[..]).unwrap();
+        assert_eq!(MessageType::Data, message.message_type());
+        assert_eq!(0x01020304, message.message_id);
+        assert_eq!(Date::from_millis(1490000000000), message.expiration);
+        match message.body {
+            MessageBody::Data(ref data) => assert_eq!(b"data".to_vec(), *data),
+            ref body => panic!("Unexpected body {:?}", body),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_bad_message() {
+        // Truncated
+        assert!(I2NPMessage::deserialize(&mut &DATA_MESSAGE[..23]).is_err());
+        // Bad checksum
+        let mut data = DATA_MESSAGE.to_vec();
+        data[15] ^= 0x01;
+        assert!(I2NPMessage::deserialize(&mut data.as_slice()).is_err());
+        // Unknown type
+        let mut data = DATA_MESSAGE.to_vec();
+        data[0] = 0xff;
+        assert!(I2NPMessage::deserialize(&mut data.as_slice()).is_err());
+        // Body length that doesn't agree with the size
+        let mut data = DATA_MESSAGE.to_vec();
+        data[19] = 0x03;
+        data[15] = checksum(&data[16..]);
+        assert!(I2NPMessage::deserialize(&mut data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_short_header() {
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = data_message().serialize_short(&mut buffer).unwrap();
+        assert_eq!(17, size);
+        assert_eq!(&DATA_MESSAGE[..5], &buffer[..5]);
+        assert_eq!(&[0x58, 0xcf, 0x98, 0x80], &buffer[5..9]);
+        assert_eq!(&DATA_MESSAGE[16..], &buffer[9..]);
+
+        let message = I2NPMessage::deserialize_short(&buffer).unwrap();
+        assert_eq!(0x01020304, message.message_id);
+        assert_eq!(Date::from_millis(1490000000000), message.expiration);
+        assert!(I2NPMessage::deserialize_short(&buffer[..16]).is_err());
+        buffer.push(0);
+        assert!(I2NPMessage::deserialize_short(&buffer).is_err());
+    }
+
+    #[test]
+    fn test_check_expiration() {
+        let message = data_message();
+        assert!(message.check_expiration(Date::from_millis(1490000000000)).is_ok());
+        assert!(message.check_expiration(Date::from_millis(1490000000000 + EXPIRATION_GRACE))
+            .is_ok());
+        assert!(message.check_expiration(Date::from_millis(1490000000001 + EXPIRATION_GRACE))
+            .is_err());
+        assert!(message.check_expiration(Date::from_millis(1490000000000 - MAX_EXPIRATION))
+            .is_ok());
+        assert!(message.check_expiration(Date::from_millis(1489999999999 - MAX_EXPIRATION))
+            .is_err());
+
+        // Expirations at the end of time don't overflow
+        let mut message = data_message();
+        message.expiration = Date::from_millis(u64::MAX);
+        assert!(!message.is_expired(Date::now()));
+        assert!(!message.is_expired(Date::from_millis(u64::MAX)));
+        assert!(message.check_expiration(Date::now()).is_err());
+        assert!(message.check_expiration(Date::from_millis(u64::MAX)).is_ok());
+    }
+
+    #[test]
+    fn test_delivery_status() {
+        let status = DeliveryStatus {
+            message_id: 42,
+            timestamp: Date::from_millis(1490000000000),
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(12, status.serialize(&mut buffer).unwrap());
+        assert_eq!(status, DeliveryStatus::parse(&buffer).unwrap());
+        assert!(DeliveryStatus::parse(&buffer[..11]).is_err());
+    }
+
+    #[test]
+    fn test_body_too_long() {
+        let message = I2NPMessage::new(MessageBody::Data(vec![0u8; MAX_BODY_LENGTH]));
+        let mut buffer: Vec<u8> = Vec::new();
+        assert!(message.serialize(&mut buffer).is_err());
+        assert!(message.serialize_short(&mut buffer).is_err());
+    }
+}
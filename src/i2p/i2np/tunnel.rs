@@ -0,0 +1,159 @@
+//! The messages that carry traffic through tunnels
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::error::Error;
+use i2p::i2np::{check_consumed, read_bytes, I2NPMessage};
+use std::io::Write;
+
+/// Tunnel messages are always this long, padded if need be, so that their
+/// size gives nothing away
+pub const TUNNEL_DATA_LENGTH: usize = 1024;
+
+fn check_tunnel_id(tunnel_id: u32) -> Result<(), Error> {
+    if tunnel_id == 0 {
+        return Err(Error::Serialization("Tunnel ID can't be zero".to_string()));
+    }
+
+    Ok(())
+}
+
+/// One encrypted 1024-byte tunnel message, on its way from one hop to the
+/// next
+#[derive(Clone, Debug, PartialEq)]
+pub struct TunnelData {
+    pub tunnel_id: u32,
+    pub data: Vec<u8>,
+}
+
+impl TunnelData {
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        check_tunnel_id(self.tunnel_id)?;
+        if self.data.len() != TUNNEL_DATA_LENGTH {
+            return Err(Error::Serialization(format!("Tunnel data must be {} bytes, not {}",
+                                                    TUNNEL_DATA_LENGTH,
+                                                    self.data.len())));
+        }
+        writer.write_u32::<BigEndian>(self.tunnel_id)?;
+        writer.write_all(&self.data)?;
+
+        Ok(4 + TUNNEL_DATA_LENGTH)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<TunnelData, Error> {
+        let mut reader = data;
+        let tunnel_id = reader.read_u32::<BigEndian>()?;
+        check_tunnel_id(tunnel_id)?;
+        let tunnel_data = read_bytes(&mut reader, TUNNEL_DATA_LENGTH)?.to_vec();
+        check_consumed(reader, "TunnelData")?;
+
+        Ok(TunnelData {
+            tunnel_id,
+            data: tunnel_data,
+        })
+    }
+}
+
+/// A message handed to a tunnel's gateway to send through the tunnel. The
+/// message is kept as it came, with its standard header, since the gateway
+/// just fragments it; use `message()` to look inside.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TunnelGateway {
+    pub tunnel_id: u32,
+    pub data: Vec<u8>,
+}
+
+impl TunnelGateway {
+    /// Wraps a message to be sent through the tunnel
+    pub fn new(tunnel_id: u32, message: &I2NPMessage) -> Result<TunnelGateway, Error> {
+        let mut data: Vec<u8> = Vec::new();
+        message.serialize(&mut data)?;
+
+        Ok(TunnelGateway {
+            tunnel_id,
+            data,
+        })
+    }
+
+    pub fn message(&self) -> Result<I2NPMessage, Error> {
+        let mut reader = self.data.as_slice();
+        let message = I2NPMessage::deserialize(&mut reader)?;
+        check_consumed(reader, "TunnelGateway message")?;
+
+        Ok(message)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        check_tunnel_id(self.tunnel_id)?;
+        if self.data.len() > u16::MAX as usize {
+            return Err(Error::Serialization(format!("TunnelGateway message too long: {}",
+                                                    self.data.len())));
+        }
+        writer.write_u32::<BigEndian>(self.tunnel_id)?;
+        writer.write_u16::<BigEndian>(self.data.len() as u16)?;
+        writer.write_all(&self.data)?;
+
+        Ok(6 + self.data.len())
+    }
+
+    pub fn parse(data: &[u8]) -> Result<TunnelGateway, Error> {
+        let mut reader = data;
+        let tunnel_id = reader.read_u32::<BigEndian>()?;
+        check_tunnel_id(tunnel_id)?;
+        let length = reader.read_u16::<BigEndian>()? as usize;
+        let message = read_bytes(&mut reader, length)?.to_vec();
+        check_consumed(reader, "TunnelGateway")?;
+
+        Ok(TunnelGateway {
+            tunnel_id,
+            data: message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::date::Date;
+    use i2p::i2np::{DeliveryStatus, MessageBody, MessageType};
+    use super::*;
+
+    #[test]
+    fn test_tunnel_data() {
+        let tunnel_data = TunnelData {
+            tunnel_id: 1234,
+            data: vec![7; TUNNEL_DATA_LENGTH],
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(1028, tunnel_data.serialize(&mut buffer).unwrap());
+        assert_eq!(tunnel_data, TunnelData::parse(&buffer).unwrap());
+
+        assert!(TunnelData::parse(&buffer[..1027]).is_err());
+        buffer.push(0);
+        assert!(TunnelData::parse(&buffer).is_err());
+        assert!(TunnelData::parse(&[0u8; 1028]).is_err());
+    }
+
+    #[test]
+    fn test_tunnel_gateway() {
+        let status = I2NPMessage {
+            message_id: 1,
+            expiration: Date::from_millis(1490000000000),
+            body: MessageBody::DeliveryStatus(DeliveryStatus {
+                message_id: 2,
+                timestamp: Date::from_millis(1490000000000),
+            }),
+        };
+        let gateway = TunnelGateway::new(1234, &status).unwrap();
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(34, gateway.serialize(&mut buffer).unwrap());
+
+        let parsed = TunnelGateway::parse(&buffer).unwrap();
+        assert_eq!(gateway, parsed);
+        let message = parsed.message().unwrap();
+        assert_eq!(MessageType::DeliveryStatus, message.message_type());
+        assert_eq!(1, message.message_id);
+
+        // The length says there's more than there is
+        buffer[5] += 1;
+        assert!(TunnelGateway::parse(&buffer).is_err());
+    }
+}
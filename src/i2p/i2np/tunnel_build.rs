@@ -0,0 +1,139 @@
+//! The tunnel build messages and their replies. Each carries one record per
+//! hop, which only that hop can read, plus any filler records; see
+//! `i2p::tunnel::build` for what's inside them.
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use i2p::error::Error;
+use i2p::i2np::{check_consumed, read_bytes};
+use std::io::Write;
+
+/// ElGamal and ECIES long records
+pub const RECORD_LENGTH: usize = 528;
+
+/// ShortTunnelBuild records
+pub const SHORT_RECORD_LENGTH: usize = 218;
+
+pub const MAX_RECORDS: usize = 8;
+
+/// How the records are laid out in the different build messages
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordLayout {
+    /// TunnelBuild and TunnelBuildReply: always eight long records, with no
+    /// count
+    Fixed,
+    /// VariableTunnelBuild and VariableTunnelBuildReply: a count, then up to
+    /// eight long records
+    Variable,
+    /// ShortTunnelBuild and OutboundTunnelBuildReply: a count, then up to
+    /// eight short records
+    Short,
+}
+
+impl RecordLayout {
+    pub fn record_length(&self) -> usize {
+        match *self {
+            RecordLayout::Fixed | RecordLayout::Variable => RECORD_LENGTH,
+            RecordLayout::Short => SHORT_RECORD_LENGTH,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuildRecords {
+    pub records: Vec<Vec<u8>>,
+}
+
+impl BuildRecords {
+    fn check(&self, layout: RecordLayout) -> Result<(), Error> {
+        let count = self.records.len();
+        let count_ok = match layout {
+            RecordLayout::Fixed => count == MAX_RECORDS,
+            _ => count > 0 && count <= MAX_RECORDS,
+        };
+        if !count_ok {
+            return Err(Error::Serialization(format!("Can't have {} {:?} build records",
+                                                    count,
+                                                    layout)));
+        }
+        if self.records.iter().any(|record| record.len() != layout.record_length()) {
+            return Err(Error::Serialization(format!("{:?} build records must be {} bytes",
+                                                    layout,
+                                                    layout.record_length())));
+        }
+
+        Ok(())
+    }
+
+    pub fn serialize<W: Write>(&self,
+                               writer: &mut W,
+                               layout: RecordLayout)
+                               -> Result<usize, Error> {
+        self.check(layout)?;
+        let mut written = 0;
+        if layout != RecordLayout::Fixed {
+            writer.write_u8(self.records.len() as u8)?;
+            written += 1;
+        }
+        for record in &self.records {
+            writer.write_all(record)?;
+            written += record.len();
+        }
+
+        Ok(written)
+    }
+
+    pub fn parse(data: &[u8], layout: RecordLayout) -> Result<BuildRecords, Error> {
+        let mut reader = data;
+        let count = match layout {
+            RecordLayout::Fixed => MAX_RECORDS,
+            _ => reader.read_u8()? as usize,
+        };
+        if count == 0 || count > MAX_RECORDS {
+            return Err(Error::Serialization(format!("Can't have {} build records", count)));
+        }
+        let mut records: Vec<Vec<u8>> = Vec::with_capacity(count);
+        for _ in 0..count {
+            records.push(read_bytes(&mut reader, layout.record_length())?.to_vec());
+        }
+        check_consumed(reader, "build records")?;
+
+        Ok(BuildRecords { records })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_variable_records() {
+        let records = BuildRecords {
+            records: vec![vec![1; RECORD_LENGTH], vec![2; RECORD_LENGTH]],
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(1057, records.serialize(&mut buffer, RecordLayout::Variable).unwrap());
+        assert_eq!(2, buffer[0]);
+        assert_eq!(records, BuildRecords::parse(&buffer, RecordLayout::Variable).unwrap());
+
+        // Fixed messages need all eight, short ones need short records
+        assert!(records.serialize(&mut Vec::new(), RecordLayout::Fixed).is_err());
+        assert!(records.serialize(&mut Vec::new(), RecordLayout::Short).is_err());
+
+        assert!(BuildRecords::parse(&buffer[..1056], RecordLayout::Variable).is_err());
+        buffer[0] = 9;
+        assert!(BuildRecords::parse(&buffer, RecordLayout::Variable).is_err());
+    }
+
+    #[test]
+    fn test_fixed_and_short_records() {
+        let fixed = BuildRecords { records: vec![vec![3; RECORD_LENGTH]; MAX_RECORDS] };
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(4224, fixed.serialize(&mut buffer, RecordLayout::Fixed).unwrap());
+        assert_eq!(fixed, BuildRecords::parse(&buffer, RecordLayout::Fixed).unwrap());
+
+        let short = BuildRecords { records: vec![vec![4; SHORT_RECORD_LENGTH]; 3] };
+        let mut buffer: Vec<u8> = Vec::new();
+        assert_eq!(655, short.serialize(&mut buffer, RecordLayout::Short).unwrap());
+        assert_eq!(short, BuildRecords::parse(&buffer, RecordLayout::Short).unwrap());
+    }
+}
@@ -0,0 +1,393 @@
+//! The router's state machine, and the subsystems it starts and stops.
+//!
+//! Startup goes INITIALIZED, STARTING_1, STARTING_2, STARTING_3, and then
+//! NETDB_READY or EXPL_TUNNELS_READY depending on which is ready first,
+//! and RUNNING once both are. Shutdown goes FINAL_SHUTDOWN_1 through 3 to
+//! STOPPED, and can begin from any state.
+
+use i2p::error::Error;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// The states keep the names the Java router gives them
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum RouterState {
+    /** constructor complete */
+    INITIALIZED,
+    /** runRouter() called */
+    STARTING_1,
+    /** startupStuff() complete, most of the time here is NTP */
+    STARTING_2,
+    /** NTP done, Job queue started, StartupJob queued, runRouter() returned */
+    STARTING_3,
+    /** RIs loaded. From STARTING_3 */
+    NETDB_READY,
+    /** Non-zero-hop expl. tunnels built. From STARTING_3 */
+    EXPL_TUNNELS_READY,
+    /** from NETDB_READY or EXPL_TUNNELS_READY */
+    RUNNING,
+    /**
+        *  A "soft" restart, primarily of the comm system, after
+        *  a port change or large step-change in system time.
+        *  Does not stop the whole JVM, so it is safe even in the absence
+        *  of the wrapper.
+        *  This is not a graceful restart - all peer connections are dropped immediately.
+        */
+    RESTARTING,
+    /** cancellable shutdown has begun */
+    GRACEFUL_SHUTDOWN,
+    /** In shutdown(). Non-cancellable shutdown has begun */
+    FINAL_SHUTDOWN_1,
+    /** In shutdown2(). Killing everything */
+    FINAL_SHUTDOWN_2,
+    /** In finalShutdown(). Final cleanup */
+    FINAL_SHUTDOWN_3,
+    /** all done */
+    STOPPED
+}
+
+impl RouterState {
+    /// Whether the non-cancellable shutdown has begun
+    pub fn is_shutting_down(&self) -> bool {
+        matches!(*self,
+                 RouterState::FINAL_SHUTDOWN_1 |
+                 RouterState::FINAL_SHUTDOWN_2 |
+                 RouterState::FINAL_SHUTDOWN_3 |
+                 RouterState::STOPPED)
+    }
+
+    /// Whether the router can go straight from this state to `to`
+    pub fn can_become(&self, to: RouterState) -> bool {
+        use self::RouterState::*;
+
+        if to == FINAL_SHUTDOWN_1 {
+            return !self.is_shutting_down();
+        }
+        matches!((*self, to),
+                 (INITIALIZED, STARTING_1) |
+                 (STARTING_1, STARTING_2) |
+                 (STARTING_2, STARTING_3) |
+                 (STARTING_3, NETDB_READY) |
+                 (STARTING_3, EXPL_TUNNELS_READY) |
+                 (NETDB_READY, RUNNING) |
+                 (EXPL_TUNNELS_READY, RUNNING) |
+                 (RUNNING, RESTARTING) |
+                 (RESTARTING, RUNNING) |
+                 (RUNNING, GRACEFUL_SHUTDOWN) |
+                 (GRACEFUL_SHUTDOWN, RUNNING) |
+                 (FINAL_SHUTDOWN_1, FINAL_SHUTDOWN_2) |
+                 (FINAL_SHUTDOWN_2, FINAL_SHUTDOWN_3) |
+                 (FINAL_SHUTDOWN_3, STOPPED))
+    }
+}
+
+/// The router's current state, which anyone holding it can watch
+pub struct Lifecycle {
+    state: Mutex<RouterState>,
+    changed: Condvar,
+    subscribers: Mutex<Vec<Sender<RouterState>>>,
+}
+
+impl Lifecycle {
+    pub fn new() -> Lifecycle {
+        Lifecycle {
+            state: Mutex::new(RouterState::INITIALIZED),
+            changed: Condvar::new(),
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn state(&self) -> RouterState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Gets every state the router goes into from now on, in order
+    pub fn subscribe(&self) -> Receiver<RouterState> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Moves to a new state, if the router can get there from where it is
+    pub fn transition(&self, to: RouterState) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        self.set(&mut state, to)
+    }
+
+    /// Tells the router the NetDB is loaded, making it RUNNING if the
+    /// exploratory tunnels are already built
+    pub fn netdb_ready(&self) -> Result<(), Error> {
+        self.ready(RouterState::NETDB_READY, RouterState::EXPL_TUNNELS_READY)
+    }
+
+    /// Tells the router the exploratory tunnels are built, making it RUNNING
+    /// if the NetDB is already loaded
+    pub fn exploratory_tunnels_ready(&self) -> Result<(), Error> {
+        self.ready(RouterState::EXPL_TUNNELS_READY, RouterState::NETDB_READY)
+    }
+
+    /// Waits until the router is in `expected`, returning false if it isn't
+    /// by the timeout
+    pub fn wait_for(&self, expected: RouterState, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while *state != expected {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+
+        true
+    }
+
+    fn ready(&self, this: RouterState, other: RouterState) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if *state == RouterState::STARTING_3 {
+            self.set(&mut state, this)
+        } else if *state == other {
+            self.set(&mut state, RouterState::RUNNING)
+        } else {
+            debug!("Router: {:?} while {:?}, ignoring", this, *state);
+            Ok(())
+        }
+    }
+
+    fn set(&self, state: &mut RouterState, to: RouterState) -> Result<(), Error> {
+        if !state.can_become(to) {
+            return Err(Error::State(format!("Can't go from {:?} to {:?}", *state, to)));
+        }
+        info!("Router: {:?} -> {:?}", *state, to);
+        *state = to;
+        self.changed.notify_all();
+        self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(to).is_ok());
+
+        Ok(())
+    }
+}
+
+impl Default for Lifecycle {
+    fn default() -> Lifecycle {
+        Lifecycle::new()
+    }
+}
+
+/// A part of the router that's started when it starts and stopped when it
+/// stops
+pub trait Subsystem: Send {
+    /// The subsystem's name, as used in logs
+    fn name(&self) -> &'static str;
+
+    /// Starts the subsystem. Subsystems the router waits on before it's
+    /// RUNNING tell `lifecycle` when they're ready, which can be later and
+    /// from another thread.
+    fn start(&mut self, lifecycle: &Arc<Lifecycle>) -> Result<(), Error>;
+
+    fn stop(&mut self);
+}
+
+/// The router's subsystems, started in the order they're added and
+/// stopped in the reverse order
+pub struct Subsystems {
+    subsystems: Vec<Box<dyn Subsystem>>,
+    started: usize,
+}
+
+impl Subsystems {
+    pub fn new() -> Subsystems {
+        Subsystems {
+            subsystems: Vec::new(),
+            started: 0,
+        }
+    }
+
+    pub fn add(&mut self, subsystem: Box<dyn Subsystem>) {
+        self.subsystems.push(subsystem);
+    }
+
+    /// Starts each subsystem in turn. If one fails to start, the ones
+    /// before it are stopped again.
+    pub fn start(&mut self, lifecycle: &Arc<Lifecycle>) -> Result<(), Error> {
+        while self.started < self.subsystems.len() {
+            let result = {
+                let subsystem = &mut self.subsystems[self.started];
+                info!("Router: starting {}", subsystem.name());
+                subsystem.start(lifecycle)
+            };
+            if let Err(error) = result {
+                error!("Router: error starting {}: {}",
+                       self.subsystems[self.started].name(),
+                       error);
+                self.stop();
+                return Err(error);
+            }
+            self.started += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Stops whatever's been started, newest first
+    pub fn stop(&mut self) {
+        while self.started > 0 {
+            self.started -= 1;
+            let subsystem = &mut self.subsystems[self.started];
+            info!("Router: stopping {}", subsystem.name());
+            subsystem.stop();
+        }
+    }
+}
+
+impl Default for Subsystems {
+    fn default() -> Subsystems {
+        Subsystems::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::RouterState::*;
+
+    struct FakeSubsystem {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        fail: bool,
+    }
+
+    impl Subsystem for FakeSubsystem {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn start(&mut self, lifecycle: &Arc<Lifecycle>) -> Result<(), Error> {
+            self.log.lock().unwrap().push(format!("start {}", self.name));
+            if self.fail {
+                return Err(Error::State(format!("{} failed", self.name)));
+            }
+            match self.name {
+                "netdb" => lifecycle.netdb_ready(),
+                "tunnels" => lifecycle.exploratory_tunnels_ready(),
+                _ => Ok(()),
+            }
+        }
+
+        fn stop(&mut self) {
+            self.log.lock().unwrap().push(format!("stop {}", self.name));
+        }
+    }
+
+    fn subsystems(names: &[&'static str],
+                  failing: Option<&str>,
+                  log: &Arc<Mutex<Vec<String>>>)
+                  -> Subsystems {
+        let mut subsystems = Subsystems::new();
+        for &name in names {
+            subsystems.add(Box::new(FakeSubsystem {
+                name,
+                log: log.clone(),
+                fail: failing == Some(name),
+            }));
+        }
+        subsystems
+    }
+
+    fn start_router(lifecycle: &Lifecycle) {
+        lifecycle.transition(STARTING_1).unwrap();
+        lifecycle.transition(STARTING_2).unwrap();
+        lifecycle.transition(STARTING_3).unwrap();
+    }
+
+    #[test]
+    fn test_startup_and_shutdown() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut subsystems = subsystems(&["netdb", "transports", "tunnels", "console"],
+                                        None,
+                                        &log);
+        let lifecycle = Arc::new(Lifecycle::new());
+        let states = lifecycle.subscribe();
+
+        start_router(&lifecycle);
+        subsystems.start(&lifecycle).unwrap();
+        assert!(lifecycle.wait_for(RUNNING, Duration::from_secs(1)));
+
+        lifecycle.transition(FINAL_SHUTDOWN_1).unwrap();
+        subsystems.stop();
+        lifecycle.transition(FINAL_SHUTDOWN_2).unwrap();
+        lifecycle.transition(FINAL_SHUTDOWN_3).unwrap();
+        lifecycle.transition(STOPPED).unwrap();
+
+        assert_eq!(*log.lock().unwrap(),
+                   vec!["start netdb",
+                        "start transports",
+                        "start tunnels",
+                        "start console",
+                        "stop console",
+                        "stop tunnels",
+                        "stop transports",
+                        "stop netdb"]);
+        assert_eq!(states.try_iter().collect::<Vec<RouterState>>(),
+                   vec![STARTING_1,
+                        STARTING_2,
+                        STARTING_3,
+                        NETDB_READY,
+                        RUNNING,
+                        FINAL_SHUTDOWN_1,
+                        FINAL_SHUTDOWN_2,
+                        FINAL_SHUTDOWN_3,
+                        STOPPED]);
+    }
+
+    #[test]
+    fn test_tunnels_ready_first() {
+        let lifecycle = Lifecycle::new();
+        start_router(&lifecycle);
+
+        lifecycle.exploratory_tunnels_ready().unwrap();
+        assert_eq!(lifecycle.state(), EXPL_TUNNELS_READY);
+        lifecycle.netdb_ready().unwrap();
+        assert_eq!(lifecycle.state(), RUNNING);
+    }
+
+    #[test]
+    fn test_startup_failure() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut subsystems = subsystems(&["netdb", "transports", "tunnels", "console"],
+                                        Some("tunnels"),
+                                        &log);
+        let lifecycle = Arc::new(Lifecycle::new());
+
+        start_router(&lifecycle);
+        assert!(subsystems.start(&lifecycle).is_err());
+        assert_eq!(lifecycle.state(), NETDB_READY);
+        assert!(!lifecycle.wait_for(RUNNING, Duration::from_millis(10)));
+
+        // Stopping again does nothing, since nothing's running
+        subsystems.stop();
+        assert_eq!(*log.lock().unwrap(),
+                   vec!["start netdb",
+                        "start transports",
+                        "start tunnels",
+                        "stop transports",
+                        "stop netdb"]);
+    }
+
+    #[test]
+    fn test_invalid_transitions() {
+        let lifecycle = Lifecycle::new();
+        assert!(lifecycle.transition(RUNNING).is_err());
+        assert!(lifecycle.transition(STOPPED).is_err());
+        assert_eq!(lifecycle.state(), INITIALIZED);
+
+        // NetDB being ready before startup gets that far is ignored
+        lifecycle.netdb_ready().unwrap();
+        assert_eq!(lifecycle.state(), INITIALIZED);
+
+        lifecycle.transition(FINAL_SHUTDOWN_1).unwrap();
+        assert!(lifecycle.transition(FINAL_SHUTDOWN_1).is_err());
+        assert!(lifecycle.transition(RUNNING).is_err());
+    }
+}
@@ -11,7 +11,7 @@ pub fn initialize(config_dir: &PathBuf) -> Result<(), Error> {
         Err(error) => {
             Err(Error::Logging(LogError::LogError {
                 message: format!("Error opening logging config file {:?}", config_path),
-                error: error,
+                error,
             }))
         }
     }
@@ -5,7 +5,13 @@ pub mod error;
 pub mod event_log;
 pub mod fs;
 pub mod http;
+pub mod i2np;
+pub mod lifecycle;
 pub mod logging;
+pub mod reseed;
 pub mod router;
 pub mod router_context;
-pub mod transport;
\ No newline at end of file
+#[cfg(test)]
+pub mod test_util;
+pub mod transport;
+pub mod tunnel;
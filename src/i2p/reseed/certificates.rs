@@ -0,0 +1,246 @@
+use i2p::data::crypto::{SigningPublicKey, SigningPublicKeyType};
+use i2p::data::encoding::base64_decode;
+use i2p::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OBJECT_IDENTIFIER: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_EXPLICIT_VERSION: u8 = 0xa0;
+
+/// 1.2.840.113549.1.1.1
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+/// 1.2.840.10045.2.1
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+
+/// I2P's RSA signatures always use this public exponent
+const RSA_PUBLIC_EXPONENT: &[u8] = &[0x01, 0x00, 0x01];
+
+/// An uncompressed elliptic curve point starts with this byte
+const EC_POINT_UNCOMPRESSED: u8 = 0x04;
+
+/// The X.509 certificates of the people allowed to sign reseed files, one
+/// file per signer, named after the signer ID with `@` spelled out as `_at_`
+#[derive(Debug)]
+pub struct SignerCertificates {
+    directory: PathBuf,
+}
+
+impl SignerCertificates {
+    pub fn new(directory: &Path) -> SignerCertificates {
+        SignerCertificates { directory: PathBuf::from(directory) }
+    }
+
+    pub fn path(&self, signer_id: &str) -> Result<PathBuf, Error> {
+        if signer_id.is_empty() || signer_id.contains('/') || signer_id.contains('\\') ||
+           signer_id.starts_with('.') {
+            return Err(Error::Crypto(format!("Invalid signer ID {:?}", signer_id)));
+        }
+        let mut path = self.directory.to_owned();
+        path.push(format!("{}.crt", signer_id.replace("@", "_at_")));
+
+        Ok(path)
+    }
+
+    /// Reads the signer's public key out of their certificate
+    pub fn signing_key(&self,
+                       signer_id: &str,
+                       key_type: &SigningPublicKeyType)
+                       -> Result<SigningPublicKey, Error> {
+        let path = self.path(signer_id)?;
+        if !path.is_file() {
+            return Err(Error::Crypto(format!("No certificate for signer {}", signer_id)));
+        }
+        let mut pem = String::new();
+        File::open(&path)?.read_to_string(&mut pem)?;
+
+        public_key_from_certificate(&pem_decode(&pem)?, key_type)
+    }
+}
+
+/// Pulls the DER out of a PEM certificate. PEM uses the standard Base64
+/// alphabet, so it's translated to I2P's first.
+pub fn pem_decode(pem: &str) -> Result<Vec<u8>, Error> {
+    let start = match pem.find(PEM_BEGIN) {
+        Some(start) => start + PEM_BEGIN.len(),
+        None => return Err(Error::Crypto("No certificate found".to_string())),
+    };
+    let end = match pem[start..].find(PEM_END) {
+        Some(end) => start + end,
+        None => return Err(Error::Crypto("Certificate isn't terminated".to_string())),
+    };
+    let encoded: String = pem[start..end]
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '~',
+            c => c,
+        })
+        .collect();
+
+    base64_decode(&encoded)
+}
+
+/// Splits a DER element off the front of `data`, returning its tag, its
+/// contents and whatever follows it
+fn read_element(data: &[u8]) -> Result<(u8, &[u8], &[u8]), Error> {
+    if data.len() < 2 {
+        return Err(Error::Crypto("Truncated certificate".to_string()));
+    }
+    let tag = data[0];
+    let (length, header_length) = if data[1] & 0x80 == 0 {
+        (data[1] as usize, 2)
+    } else {
+        let length_bytes = (data[1] & 0x7f) as usize;
+        if length_bytes == 0 || length_bytes > 3 || data.len() < 2 + length_bytes {
+            return Err(Error::Crypto("Bad length in certificate".to_string()));
+        }
+        let length = data[2..2 + length_bytes]
+            .iter()
+            .fold(0, |length, &b| length << 8 | b as usize);
+        (length, 2 + length_bytes)
+    };
+    if data.len() - header_length < length {
+        return Err(Error::Crypto("Truncated certificate".to_string()));
+    }
+
+    Ok((tag, &data[header_length..header_length + length], &data[header_length + length..]))
+}
+
+fn read_expected(data: &[u8], expected_tag: u8) -> Result<(&[u8], &[u8]), Error> {
+    let (tag, contents, rest) = read_element(data)?;
+    if tag != expected_tag {
+        return Err(Error::Crypto(format!("Expected tag {:#x} in certificate, found {:#x}",
+                                         expected_tag,
+                                         tag)));
+    }
+
+    Ok((contents, rest))
+}
+
+/// DER integers are signed, so positive ones can have a leading zero
+fn strip_leading_zeros(value: &[u8]) -> &[u8] {
+    let zeros = value.iter().take_while(|&&b| b == 0).count();
+    &value[zeros..]
+}
+
+/// Finds the subject public key in an X.509 certificate and converts it to
+/// an I2P signing key of the given type. Only RSA and ECDSA keys are
+/// supported, which is all reseed signers use.
+pub fn public_key_from_certificate(der: &[u8],
+                                   key_type: &SigningPublicKeyType)
+                                   -> Result<SigningPublicKey, Error> {
+    let (certificate, _) = read_expected(der, TAG_SEQUENCE)?;
+    let (tbs_certificate, _) = read_expected(certificate, TAG_SEQUENCE)?;
+    let mut rest = tbs_certificate;
+    if rest.first() == Some(&TAG_EXPLICIT_VERSION) {
+        rest = read_element(rest)?.2;
+    }
+    // Skip the serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        rest = read_element(rest)?.2;
+    }
+    let (public_key_info, _) = read_expected(rest, TAG_SEQUENCE)?;
+    let (algorithm, rest) = read_expected(public_key_info, TAG_SEQUENCE)?;
+    let (oid, _) = read_expected(algorithm, TAG_OBJECT_IDENTIFIER)?;
+    let (bit_string, _) = read_expected(rest, TAG_BIT_STRING)?;
+    if bit_string.first() != Some(&0) {
+        return Err(Error::Crypto("Bad public key in certificate".to_string()));
+    }
+    let public_key = &bit_string[1..];
+    let length = SigningPublicKey::length(key_type);
+
+    match *key_type {
+        SigningPublicKeyType::RSA_SHA256_2048 |
+        SigningPublicKeyType::RSA_SHA384_3072 |
+        SigningPublicKeyType::RSA_SHA512_4096 => {
+            if oid != OID_RSA_ENCRYPTION {
+                return Err(Error::Crypto("Certificate doesn't hold an RSA key".to_string()));
+            }
+            let (rsa_key, _) = read_expected(public_key, TAG_SEQUENCE)?;
+            let (modulus, rest) = read_expected(rsa_key, TAG_INTEGER)?;
+            let (exponent, _) = read_expected(rest, TAG_INTEGER)?;
+            if strip_leading_zeros(exponent) != RSA_PUBLIC_EXPONENT {
+                return Err(Error::Crypto("Unsupported RSA public exponent".to_string()));
+            }
+            let modulus = strip_leading_zeros(modulus);
+            if modulus.len() != length {
+                return Err(Error::Crypto(format!("Expected a {}-bit RSA key, got a {}-bit one",
+                                                 length * 8,
+                                                 modulus.len() * 8)));
+            }
+            Ok(SigningPublicKey::new(key_type.clone(), modulus))
+        }
+        SigningPublicKeyType::ECDSA_SHA256_P256 |
+        SigningPublicKeyType::ECDSA_SHA384_P384 |
+        SigningPublicKeyType::ECDSA_SHA512_P521 => {
+            if oid != OID_EC_PUBLIC_KEY {
+                return Err(Error::Crypto("Certificate doesn't hold an EC key".to_string()));
+            }
+            if public_key.first() != Some(&EC_POINT_UNCOMPRESSED) ||
+               public_key.len() != length + 1 {
+                return Err(Error::Crypto(format!("Certificate doesn't hold a {:?} key",
+                                                 key_type)));
+            }
+            Ok(SigningPublicKey::new(key_type.clone(), &public_key[1..]))
+        }
+        _ => {
+            Err(Error::Crypto(format!("{:?} keys aren't supported in certificates", key_type)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::reseed::su3::Su3File;
+    use i2p::test_util::read_fixture;
+    use super::*;
+
+    fn certificates() -> SignerCertificates {
+        SignerCertificates::new(Path::new("fixtures/certificates/reseed"))
+    }
+
+    #[test]
+    fn test_certificate_path() {
+        assert_eq!(PathBuf::from("fixtures/certificates/reseed/test_at_mail.i2p.crt"),
+                   certificates().path("test@mail.i2p").unwrap());
+        assert!(certificates().path("../../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_verify_with_certificate() {
+        let mut data = read_fixture("Reseed_RSA_SHA512_4096");
+        let su3 = Su3File::deserialize(&mut data.as_slice()).unwrap();
+
+        let key = certificates()
+            .signing_key(su3.signer_id(), &su3.signature_type())
+            .unwrap();
+        assert_eq!(512, key.data().len());
+        assert!(su3.verify(&key).unwrap());
+
+        // Change a byte of the content
+        data[100] ^= 0x01;
+        let su3 = Su3File::deserialize(&mut data.as_slice()).unwrap();
+        assert!(!su3.verify(&key).unwrap());
+    }
+
+    #[test]
+    fn test_wrong_key_type() {
+        assert!(certificates()
+            .signing_key("test@mail.i2p", &SigningPublicKeyType::ECDSA_SHA256_P256)
+            .is_err());
+        assert!(certificates()
+            .signing_key("test@mail.i2p", &SigningPublicKeyType::RSA_SHA256_2048)
+            .is_err());
+        assert!(certificates()
+            .signing_key("nobody@mail.i2p", &SigningPublicKeyType::RSA_SHA512_4096)
+            .is_err());
+    }
+}
@@ -0,0 +1,268 @@
+//! A minimal HTTPS client, enough to download su3 files from reseed hosts
+
+use i2p::error::Error;
+use i2p::reseed::certificates::pem_decode;
+use i2p::reseed::su3::MAX_CONTENT_LENGTH;
+use native_tls::{Certificate, TlsConnector};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::str;
+use std::time::Duration;
+
+const HTTPS_SCHEME: &str = "https://";
+const DEFAULT_PORT: u16 = 443;
+
+/// Reseed hosts expect the same User-Agent as the Java router sends
+const USER_AGENT: &str = "Wget/1.11.4";
+
+/// Room for the response headers on top of the content
+const MAX_HEADER_LENGTH: u64 = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    /// Only https URLs are accepted
+    pub fn parse(url: &str) -> Result<Url, Error> {
+        if !url.starts_with(HTTPS_SCHEME) {
+            return Err(Error::Configuration(format!("Not an https URL: {}", url)));
+        }
+        let rest = &url[HTTPS_SCHEME.len()..];
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(index) if !authority.ends_with(']') => {
+                match authority[index + 1..].parse::<u16>() {
+                    Ok(port) => (&authority[..index], port),
+                    Err(_) => return Err(Error::Configuration(format!("Bad port in {}", url))),
+                }
+            }
+            _ => (authority, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(Error::Configuration(format!("No host in {}", url)));
+        }
+
+        Ok(Url {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Builds a TLS connector that trusts the system's roots along with any
+/// certificates in `certificates_dir`, which is how self-signed reseed hosts
+/// are supported
+pub fn connector(certificates_dir: &Path) -> Result<TlsConnector, Error> {
+    let mut builder = TlsConnector::builder();
+    if certificates_dir.is_dir() {
+        for entry in fs::read_dir(certificates_dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "crt") {
+                continue;
+            }
+            let mut pem = String::new();
+            File::open(&path)?.read_to_string(&mut pem)?;
+            builder.add_root_certificate(Certificate::from_der(&pem_decode(&pem)?)?);
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Connects to each of the host's addresses in turn, giving each `timeout`
+fn connect(url: &Url, timeout: Duration) -> Result<TcpStream, Error> {
+    let mut last_error = None;
+    for address in (url.host.as_str(), url.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last_error = Some(error),
+        }
+    }
+
+    match last_error {
+        Some(error) => {
+            Err(Error::IO {
+                message: Some(format!("Error connecting to {}", url.host)),
+                error,
+            })
+        }
+        None => Err(Error::Transport(format!("No addresses for {}", url.host))),
+    }
+}
+
+/// Downloads `url`, returning the body of a 200 response. Anything else,
+/// including a redirect, is an error.
+pub fn get(connector: &TlsConnector, url: &Url, timeout: Duration) -> Result<Vec<u8>, Error> {
+    let stream = connect(url, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut stream = match connector.connect(&url.host, stream) {
+        Ok(stream) => stream,
+        Err(error) => {
+            return Err(Error::Transport(format!("TLS handshake with {} failed: {}",
+                                                url.host,
+                                                error)))
+        }
+    };
+
+    // HTTP/1.0, so that the body isn't chunked
+    write!(stream,
+           "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: {}\r\nConnection: close\r\n\r\n",
+           url.path,
+           url.host,
+           USER_AGENT)?;
+    stream.flush()?;
+
+    let mut response: Vec<u8> = Vec::new();
+    (&mut stream).take(MAX_CONTENT_LENGTH + MAX_HEADER_LENGTH).read_to_end(&mut response)?;
+
+    parse_response(&response)
+}
+
+fn parse_response(response: &[u8]) -> Result<Vec<u8>, Error> {
+    let header_end = match response.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(index) => index,
+        None => return Err(Error::Transport("Incomplete HTTP response".to_string())),
+    };
+    let headers = str::from_utf8(&response[..header_end])?;
+    let mut body = response[header_end + 4..].to_vec();
+
+    let mut lines = headers.split("\r\n");
+    let status_line = lines.next().unwrap_or("");
+    let status = status_line.split(' ').nth(1).unwrap_or("");
+    if !status_line.starts_with("HTTP/1.") || status != "200" {
+        return Err(Error::Transport(format!("HTTP request failed: {}", status_line)));
+    }
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        if name.to_lowercase() == "content-length" {
+            let length = match value.parse::<usize>() {
+                Ok(length) => length,
+                Err(_) => return Err(Error::Transport(format!("Bad Content-Length {}", value))),
+            };
+            if body.len() < length {
+                return Err(Error::Transport(format!("Response truncated at {} of {} bytes",
+                                                    body.len(),
+                                                    length)));
+            }
+            body.truncate(length);
+        }
+    }
+
+    Ok(body)
+}
+
+/// A stand-in HTTPS server for tests, using the `localhost` certificate in
+/// the fixtures
+#[cfg(test)]
+pub mod test_server {
+    use native_tls::{Identity, TlsAcceptor};
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answers `count` requests with the given status and body, returning
+    /// the port it's listening on
+    pub fn serve(count: usize, status: &'static str, body: Vec<u8>) -> u16 {
+        let mut identity: Vec<u8> = Vec::new();
+        File::open("fixtures/localhost.p12").unwrap().read_to_end(&mut identity).unwrap();
+        let acceptor = TlsAcceptor::new(Identity::from_pkcs12(&identity, "test").unwrap())
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || for stream in listener.incoming().take(count) {
+            let mut stream = match acceptor.accept(stream.unwrap()) {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let mut request: Vec<u8> = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+            let _ = write!(stream,
+                           "HTTP/1.0 {}\r\nContent-Length: {}\r\n\r\n",
+                           status,
+                           body.len());
+            let _ = stream.write_all(&body);
+        });
+
+        port
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use super::*;
+    use super::test_server::serve;
+
+    fn url(port: u16, path: &str) -> Url {
+        Url {
+            host: "localhost".to_string(),
+            port,
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(url(443, "/"), Url::parse("https://localhost").unwrap());
+        assert_eq!(url(8443, "/i2pseeds.su3"),
+                   Url::parse("https://localhost:8443/i2pseeds.su3").unwrap());
+        assert!(Url::parse("http://localhost/").is_err());
+        assert!(Url::parse("https://localhost:port/").is_err());
+        assert!(Url::parse("https:///").is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(b"data".to_vec(),
+                   parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndata").unwrap());
+        assert_eq!(b"data".to_vec(),
+                   parse_response(b"HTTP/1.0 200 OK\r\n\r\ndata").unwrap());
+        assert!(parse_response(b"HTTP/1.1 302 Found\r\nLocation: /\r\n\r\n").is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\ndata").is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+
+    #[test]
+    fn test_get() {
+        let port = serve(1, "200 OK", b"i2pseeds".to_vec());
+        let connector = connector(Path::new("fixtures/certificates/ssl")).unwrap();
+        let body = get(&connector, &url(port, "/i2pseeds.su3"), Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(b"i2pseeds".to_vec(), body);
+    }
+
+    #[test]
+    fn test_get_not_found() {
+        let port = serve(1, "404 Not Found", vec![]);
+        let connector = connector(Path::new("fixtures/certificates/ssl")).unwrap();
+        assert!(get(&connector, &url(port, "/i2pseeds.su3"), Duration::from_secs(10)).is_err());
+    }
+
+    #[test]
+    fn test_get_untrusted_certificate() {
+        let port = serve(1, "200 OK", b"i2pseeds".to_vec());
+        let connector = connector(Path::new("fixtures/certificates/none")).unwrap();
+        assert!(get(&connector, &url(port, "/i2pseeds.su3"), Duration::from_secs(10)).is_err());
+    }
+}
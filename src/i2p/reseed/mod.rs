@@ -0,0 +1,5 @@
+pub mod certificates;
+pub mod https;
+pub mod reseeder;
+pub mod su3;
+pub mod zip;
@@ -0,0 +1,353 @@
+use i2p::config::Config;
+use i2p::data::netdb::NetDB;
+use i2p::data::router_info::RouterInfo;
+use i2p::error::Error;
+use i2p::reseed::certificates::SignerCertificates;
+use i2p::reseed::https::{self, Url};
+use i2p::reseed::su3::{ContentType, FileType, Su3File};
+use i2p::reseed::zip::read_zip;
+use std::fs::File;
+use rand::{thread_rng, Rng};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const RESEED_FILE_CONFIG: &str = "reseed.file";
+const CERTIFICATES_DIR_CONFIG: &str = "reseed.certificatesDir";
+const SSL_CERTIFICATES_DIR_CONFIG: &str = "reseed.sslCertificatesDir";
+const URLS_CONFIG: &str = "reseed.urls";
+const THRESHOLD_CONFIG: &str = "reseed.threshold";
+const MIN_SUCCESSES_CONFIG: &str = "reseed.minSuccesses";
+const TIMEOUT_CONFIG: &str = "reseed.timeout";
+
+/// Below this many RouterInfos the NetDB needs reseeding
+const DEFAULT_THRESHOLD: i64 = 25;
+/// How many hosts have to reseed us, so that no single one controls our
+/// view of the network
+const DEFAULT_MIN_SUCCESSES: i64 = 2;
+/// In seconds
+const DEFAULT_TIMEOUT: i64 = 30;
+
+/// Appended to a reseed URL that doesn't name a file
+const SU3_FILE: &str = "i2pseeds.su3";
+
+const ROUTER_INFO_PREFIX: &str = "routerInfo-";
+const ROUTER_INFO_SUFFIX: &str = ".dat";
+
+/// Bootstraps an empty NetDB from a bundle of RouterInfos
+#[derive(Debug)]
+pub struct Reseeder {
+    certificates: SignerCertificates,
+    file: Option<PathBuf>,
+    urls: Vec<String>,
+    ssl_certificates_dir: PathBuf,
+    threshold: usize,
+    min_successes: usize,
+    timeout: Duration,
+}
+
+impl Reseeder {
+    pub fn new(config: &Config, config_dir: &Path) -> Result<Reseeder, Error> {
+        let default_certificates_dir = config_dir.join("certificates").join("reseed");
+        let certificates_dir =
+            config.path_value(CERTIFICATES_DIR_CONFIG, Some(&default_certificates_dir)).unwrap();
+        let default_ssl_certificates_dir = config_dir.join("certificates").join("ssl");
+        let ssl_certificates_dir =
+            config.path_value(SSL_CERTIFICATES_DIR_CONFIG, Some(&default_ssl_certificates_dir))
+                .unwrap();
+        let urls = config.string_value(URLS_CONFIG, Some("")).unwrap();
+        let threshold = config.i64_value(THRESHOLD_CONFIG, Some(DEFAULT_THRESHOLD)).unwrap();
+        let min_successes =
+            config.i64_value(MIN_SUCCESSES_CONFIG, Some(DEFAULT_MIN_SUCCESSES)).unwrap();
+        let timeout = config.i64_value(TIMEOUT_CONFIG, Some(DEFAULT_TIMEOUT)).unwrap();
+        for &(key, value) in &[(THRESHOLD_CONFIG, threshold),
+                               (MIN_SUCCESSES_CONFIG, min_successes)] {
+            if value < 0 {
+                return Err(Error::Configuration(format!("{} can't be negative", key)));
+            }
+        }
+        if timeout <= 0 {
+            return Err(Error::Configuration(format!("{} must be positive", TIMEOUT_CONFIG)));
+        }
+
+        Ok(Reseeder {
+            certificates: SignerCertificates::new(&certificates_dir),
+            file: config.path_value(RESEED_FILE_CONFIG, None),
+            urls: urls.split(',')
+                .map(|url| url.trim().to_string())
+                .filter(|url| !url.is_empty())
+                .collect(),
+            ssl_certificates_dir,
+            threshold: threshold as usize,
+            min_successes: min_successes as usize,
+            timeout: Duration::from_secs(timeout as u64),
+        })
+    }
+
+    /// Reseeds if the NetDB knows too few routers to build tunnels with,
+    /// returning how many RouterInfos were added
+    pub fn reseed_if_needed(&self, netdb: &NetDB) -> Result<usize, Error> {
+        if netdb.len() >= self.threshold {
+            return Ok(0);
+        }
+        info!("Reseeder: only {} RouterInfos in the NetDB, reseeding", netdb.len());
+
+        self.reseed(netdb)
+    }
+
+    /// Reseeds from whatever's configured, returning how many RouterInfos
+    /// were added. A reseed file takes precedence over the reseed hosts.
+    pub fn reseed(&self, netdb: &NetDB) -> Result<usize, Error> {
+        match self.file {
+            Some(ref file) => self.reseed_from_file(file, netdb),
+            None if !self.urls.is_empty() => self.reseed_from_urls(netdb),
+            None => Err(Error::Configuration("No reseed source configured".to_string())),
+        }
+    }
+
+    /// Tries the reseed hosts in random order until enough of them have
+    /// given us a valid reseed bundle
+    pub fn reseed_from_urls(&self, netdb: &NetDB) -> Result<usize, Error> {
+        let connector = https::connector(&self.ssl_certificates_dir)?;
+        let mut urls = self.urls.clone();
+        thread_rng().shuffle(&mut urls);
+
+        let mut imported = 0;
+        let mut successes = 0;
+        for url in urls {
+            if successes >= self.min_successes {
+                break;
+            }
+            let url = if url.ends_with(".su3") {
+                url
+            } else if url.ends_with('/') {
+                format!("{}{}", url, SU3_FILE)
+            } else {
+                format!("{}/{}", url, SU3_FILE)
+            };
+            info!("Reseeder: reseeding from {}", url);
+            let result = Url::parse(&url)
+                .and_then(|url| https::get(&connector, &url, self.timeout))
+                .and_then(|data| self.import_su3(&data, netdb));
+            match result {
+                Ok(count) => {
+                    imported += count;
+                    successes += 1;
+                }
+                Err(error) => warn!("Reseeder: reseed from {} failed: {}", url, error),
+            }
+        }
+
+        if successes < self.min_successes {
+            return Err(Error::Transport(format!("Only {} of {} reseeds succeeded, {} RouterInfos \
+                                                 imported",
+                                                successes,
+                                                self.min_successes,
+                                                imported)));
+        }
+
+        Ok(imported)
+    }
+
+    /// Reads either a signed su3 file or, going by the extension, a plain
+    /// zip file of RouterInfos
+    pub fn reseed_from_file(&self, path: &Path, netdb: &NetDB) -> Result<usize, Error> {
+        info!("Reseeder: reseeding from {:?}", path);
+        let mut data: Vec<u8> = Vec::new();
+        if let Err(error) = File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+            return Err(Error::IO {
+                message: Some(format!("Error reading reseed file {:?}", path)),
+                error,
+            });
+        }
+
+        let is_zip = path.extension().is_some_and(|extension| extension == "zip");
+        if is_zip {
+            import_zip(&data, netdb)
+        } else {
+            self.import_su3(&data, netdb)
+        }
+    }
+
+    /// Checks an su3 reseed bundle is signed by a known signer, then imports
+    /// the RouterInfos in it
+    pub fn import_su3(&self, data: &[u8], netdb: &NetDB) -> Result<usize, Error> {
+        let su3 = Su3File::deserialize(&mut &data[..])?;
+        if su3.content_type() != ContentType::Reseed {
+            return Err(Error::Serialization(format!("su3 file isn't a reseed bundle: {:?}",
+                                                    su3.content_type())));
+        }
+        if su3.file_type() != FileType::Zip {
+            return Err(Error::Serialization(format!("Reseed bundle isn't a zip file: {:?}",
+                                                    su3.file_type())));
+        }
+        let signing_key = self.certificates.signing_key(su3.signer_id(), &su3.signature_type())?;
+        if !su3.verify(&signing_key)? {
+            return Err(Error::Crypto(format!("Bad signature on reseed bundle from {}",
+                                             su3.signer_id())));
+        }
+        info!("Reseeder: reseed bundle version {} signed by {}",
+              su3.version()?,
+              su3.signer_id());
+
+        import_zip(su3.content(), netdb)
+    }
+}
+
+/// Adds every RouterInfo in the zip file to the NetDB. Ones that don't
+/// verify or are out of date are skipped, as is anything else in the file.
+fn import_zip(data: &[u8], netdb: &NetDB) -> Result<usize, Error> {
+    let mut imported = 0;
+    for entry in read_zip(data)? {
+        if !entry.name.starts_with(ROUTER_INFO_PREFIX) ||
+           !entry.name.ends_with(ROUTER_INFO_SUFFIX) {
+            debug!("Reseeder: skipping {}", entry.name);
+            continue;
+        }
+        let result = RouterInfo::deserialize(&mut entry.data.as_slice())
+            .and_then(|router_info| netdb.insert(router_info));
+        match result {
+            Ok(true) => imported += 1,
+            Ok(false) => {}
+            Err(error) => warn!("Reseeder: skipping {}: {}", entry.name, error),
+        }
+    }
+    info!("Reseeder: imported {} RouterInfos", imported);
+
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::date::Date;
+    use i2p::reseed::https::test_server::serve;
+    use i2p::test_util::read_fixture;
+    use std::io::Write;
+    use super::*;
+    use tempdir::TempDir;
+
+    fn read_reseed_fixture() -> Vec<u8> {
+        read_fixture("Reseed_RSA_SHA512_4096")
+    }
+
+    fn reseeder(file: Option<PathBuf>) -> Reseeder {
+        Reseeder {
+            certificates: SignerCertificates::new(Path::new("fixtures/certificates/reseed")),
+            file,
+            urls: vec![],
+            ssl_certificates_dir: PathBuf::from("fixtures/certificates/ssl"),
+            threshold: DEFAULT_THRESHOLD as usize,
+            min_successes: 1,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    fn url_reseeder(urls: Vec<String>, min_successes: usize) -> Reseeder {
+        Reseeder {
+            urls,
+            min_successes,
+            ..reseeder(None)
+        }
+    }
+
+    fn reseed_url(port: u16) -> String {
+        format!("https://localhost:{}/", port)
+    }
+
+    fn netdb(data_dir: &TempDir) -> NetDB {
+        let netdb = NetDB::new(&Config::default(), data_dir.path()).unwrap();
+        // The fixture RouterInfos were published then
+        netdb.override_clock(Some(Date::from_millis(1490000060000)));
+        netdb
+    }
+
+    #[test]
+    fn test_reseed_from_su3_file() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let path = data_dir.path().join("i2pseeds.su3");
+        File::create(&path).unwrap().write_all(&read_reseed_fixture()).unwrap();
+        let netdb = netdb(&data_dir);
+
+        assert_eq!(3, reseeder(Some(path)).reseed(&netdb).unwrap());
+        assert_eq!(3, netdb.len());
+        // Nothing new the second time around
+        assert_eq!(0, reseeder(None).import_su3(&read_reseed_fixture(), &netdb).unwrap());
+    }
+
+    #[test]
+    fn test_reseed_from_zip_file() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let path = data_dir.path().join("i2pseeds.zip");
+        let su3 = Su3File::deserialize(&mut read_reseed_fixture().as_slice()).unwrap();
+        File::create(&path).unwrap().write_all(su3.content()).unwrap();
+        let netdb = netdb(&data_dir);
+
+        assert_eq!(3, reseeder(Some(path)).reseed(&netdb).unwrap());
+    }
+
+    #[test]
+    fn test_reseed_rejects_bad_signature() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let mut data = read_reseed_fixture();
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+
+        assert!(reseeder(None).import_su3(&data, &netdb).is_err());
+        assert!(netdb.is_empty());
+    }
+
+    #[test]
+    fn test_reseed_rejects_unknown_signer() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let reseeder = Reseeder {
+            certificates: SignerCertificates::new(data_dir.path()),
+            ..reseeder(None)
+        };
+
+        assert!(reseeder.import_su3(&read_reseed_fixture(), &netdb).is_err());
+    }
+
+    #[test]
+    fn test_reseed_not_configured() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        assert!(reseeder(None).reseed(&netdb(&data_dir)).is_err());
+    }
+
+    #[test]
+    fn test_reseed_from_urls() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let port = serve(1, "200 OK", read_reseed_fixture());
+
+        assert_eq!(3, url_reseeder(vec![reseed_url(port)], 1).reseed(&netdb).unwrap());
+        assert_eq!(3, netdb.len());
+    }
+
+    #[test]
+    fn test_reseed_from_urls_needs_enough_successes() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let good = serve(1, "200 OK", read_reseed_fixture());
+        let bad = serve(1, "404 Not Found", vec![]);
+
+        let reseeder = url_reseeder(vec![reseed_url(good), reseed_url(bad)], 2);
+        assert!(reseeder.reseed(&netdb).is_err());
+        // What the good host sent is kept all the same
+        assert_eq!(3, netdb.len());
+    }
+
+    #[test]
+    fn test_reseed_if_needed() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let netdb = netdb(&data_dir);
+        let port = serve(1, "200 OK", read_reseed_fixture());
+        let mut reseeder = url_reseeder(vec![reseed_url(port)], 1);
+
+        reseeder.threshold = 1;
+        assert_eq!(3, reseeder.reseed_if_needed(&netdb).unwrap());
+        // The server's gone, so this would fail if it tried
+        assert_eq!(0, reseeder.reseed_if_needed(&netdb).unwrap());
+    }
+}
@@ -0,0 +1,257 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::data::crypto::{Signature, SigningPublicKey, SigningPublicKeyType};
+use i2p::error::Error;
+use std::io::{Read, Write};
+use std::str;
+
+const MAGIC: &[u8; 6] = b"I2Psu3";
+
+const FORMAT_VERSION: u8 = 0;
+
+/// The version field is padded with zeros to at least this length
+const MIN_VERSION_LENGTH: usize = 16;
+
+/// Reseed bundles are a few megabytes at most
+pub const MAX_CONTENT_LENGTH: u64 = 20 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileType {
+    Zip = 0,
+    Xml = 1,
+    Html = 2,
+    XmlGz = 3,
+    TxtGz = 4,
+    Dmg = 5,
+    Exe = 6,
+}
+
+impl FileType {
+    fn from_u8(t: u8) -> Result<FileType, Error> {
+        match t {
+            0 => Ok(FileType::Zip),
+            1 => Ok(FileType::Xml),
+            2 => Ok(FileType::Html),
+            3 => Ok(FileType::XmlGz),
+            4 => Ok(FileType::TxtGz),
+            5 => Ok(FileType::Dmg),
+            6 => Ok(FileType::Exe),
+            _ => Err(Error::Serialization(format!("Unknown su3 file type {}", t))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentType {
+    Unknown = 0,
+    RouterUpdate = 1,
+    Plugin = 2,
+    Reseed = 3,
+    News = 4,
+    Blocklist = 5,
+}
+
+impl ContentType {
+    fn from_u8(t: u8) -> Result<ContentType, Error> {
+        match t {
+            0 => Ok(ContentType::Unknown),
+            1 => Ok(ContentType::RouterUpdate),
+            2 => Ok(ContentType::Plugin),
+            3 => Ok(ContentType::Reseed),
+            4 => Ok(ContentType::News),
+            5 => Ok(ContentType::Blocklist),
+            _ => Err(Error::Serialization(format!("Unknown su3 content type {}", t))),
+        }
+    }
+}
+
+/// A signed update file. Reseed bundles, router updates and news are all
+/// distributed this way.
+#[derive(Debug)]
+pub struct Su3File {
+    signature_type: SigningPublicKeyType,
+    version: Vec<u8>,
+    signer_id: String,
+    file_type: FileType,
+    content_type: ContentType,
+    content: Vec<u8>,
+    signature: Signature,
+}
+
+fn skip<R: Read>(reader: &mut R, length: usize) -> Result<(), Error> {
+    let mut buffer = vec![0u8; length];
+    reader.read_exact(buffer.as_mut_slice())?;
+
+    Ok(())
+}
+
+impl Su3File {
+    pub fn signature_type(&self) -> SigningPublicKeyType {
+        self.signature_type.clone()
+    }
+
+    /// The version, without the zero padding
+    pub fn version(&self) -> Result<&str, Error> {
+        let end = self.version.iter().position(|&b| b == 0).unwrap_or(self.version.len());
+        Ok(str::from_utf8(&self.version[..end])?)
+    }
+
+    /// Who signed the file, usually an email address
+    pub fn signer_id(&self) -> &str {
+        &self.signer_id
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        self.content_type
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    /// Checks the signature, which covers everything before it, against the
+    /// signer's key
+    pub fn verify(&self, signing_key: &SigningPublicKey) -> Result<bool, Error> {
+        if signing_key.get_type() != self.signature_type {
+            return Err(Error::Crypto(format!("su3 is signed with {:?}, but the key is {:?}",
+                                             self.signature_type,
+                                             signing_key.get_type())));
+        }
+        let mut buffer: Vec<u8> = Vec::new();
+        self.serialize_unsigned(&mut buffer)?;
+
+        signing_key.verify(&buffer, &self.signature)
+    }
+
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut written = self.serialize_unsigned(writer)?;
+        written += self.signature.serialize(writer)?;
+
+        Ok(written)
+    }
+
+    fn serialize_unsigned<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        writer.write_all(MAGIC)?;
+        writer.write_u8(0)?;
+        writer.write_u8(FORMAT_VERSION)?;
+        writer.write_u16::<BigEndian>(self.signature_type.clone() as u16)?;
+        writer.write_u16::<BigEndian>(Signature::length(&self.signature_type) as u16)?;
+        writer.write_u8(0)?;
+        writer.write_u8(self.version.len() as u8)?;
+        writer.write_u8(0)?;
+        writer.write_u8(self.signer_id.len() as u8)?;
+        writer.write_u64::<BigEndian>(self.content.len() as u64)?;
+        writer.write_u8(0)?;
+        writer.write_u8(self.file_type as u8)?;
+        writer.write_u8(0)?;
+        writer.write_u8(self.content_type as u8)?;
+        writer.write_all(&[0u8; 12])?;
+        writer.write_all(&self.version)?;
+        writer.write_all(self.signer_id.as_bytes())?;
+        writer.write_all(&self.content)?;
+
+        Ok(40 + self.version.len() + self.signer_id.len() + self.content.len())
+    }
+
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<Su3File, Error> {
+        let mut magic = [0u8; 6];
+        reader.read_exact(&mut magic)?;
+        if magic != *MAGIC {
+            return Err(Error::Serialization("Not an su3 file".to_string()));
+        }
+        skip(reader, 1)?;
+        let format_version = reader.read_u8()?;
+        if format_version != FORMAT_VERSION {
+            return Err(Error::Serialization(format!("Unknown su3 format version {}",
+                                                    format_version)));
+        }
+        let signature_type = SigningPublicKeyType::from_u16(reader.read_u16::<BigEndian>()?)?;
+        let signature_length = reader.read_u16::<BigEndian>()? as usize;
+        if signature_length != Signature::length(&signature_type) {
+            return Err(Error::Serialization(format!("Bad su3 signature length {} for {:?}",
+                                                    signature_length,
+                                                    signature_type)));
+        }
+        skip(reader, 1)?;
+        let version_length = reader.read_u8()? as usize;
+        if version_length < MIN_VERSION_LENGTH {
+            return Err(Error::Serialization(format!("su3 version field too short: {}",
+                                                    version_length)));
+        }
+        skip(reader, 1)?;
+        let signer_id_length = reader.read_u8()? as usize;
+        let content_length = reader.read_u64::<BigEndian>()?;
+        if content_length > MAX_CONTENT_LENGTH {
+            return Err(Error::Serialization(format!("su3 content too long: {}", content_length)));
+        }
+        skip(reader, 1)?;
+        let file_type = FileType::from_u8(reader.read_u8()?)?;
+        skip(reader, 1)?;
+        let content_type = ContentType::from_u8(reader.read_u8()?)?;
+        skip(reader, 12)?;
+
+        let mut version = vec![0u8; version_length];
+        reader.read_exact(version.as_mut_slice())?;
+        let mut signer_id = vec![0u8; signer_id_length];
+        reader.read_exact(signer_id.as_mut_slice())?;
+        let mut content = vec![0u8; content_length as usize];
+        reader.read_exact(content.as_mut_slice())?;
+        let signature = Signature::deserialize(&signature_type, reader)?;
+
+        Ok(Su3File {
+            signature_type,
+            version,
+            signer_id: str::from_utf8(&signer_id)?.to_string(),
+            file_type,
+            content_type,
+            content,
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::test_util::read_fixture;
+    use super::*;
+
+    #[test]
+    fn test_deserialize_su3() {
+        let data = read_fixture("Reseed_RSA_SHA512_4096");
+        let su3 = Su3File::deserialize(&mut data.as_slice()).unwrap();
+
+        assert_eq!(SigningPublicKeyType::RSA_SHA512_4096, su3.signature_type());
+        assert_eq!("1490000000", su3.version().unwrap());
+        assert_eq!("test@mail.i2p", su3.signer_id());
+        assert_eq!(FileType::Zip, su3.file_type());
+        assert_eq!(ContentType::Reseed, su3.content_type());
+        assert_eq!(2405, su3.content().len());
+        assert_eq!(b"PK", &su3.content()[..2]);
+    }
+
+    #[test]
+    fn test_serialize_su3() {
+        let data = read_fixture("Reseed_RSA_SHA512_4096");
+        let su3 = Su3File::deserialize(&mut data.as_slice()).unwrap();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let size = su3.serialize(&mut buffer).unwrap();
+        assert_eq!(data.len(), size);
+        assert_eq!(data, buffer);
+    }
+
+    #[test]
+    fn test_deserialize_bad_su3() {
+        let mut data = read_fixture("Reseed_RSA_SHA512_4096");
+        assert!(Su3File::deserialize(&mut &data[..data.len() - 1]).is_err());
+        data[0] = b'X';
+        assert!(Su3File::deserialize(&mut data.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Just enough of the zip format to unpack reseed bundles: stored and
+//! deflated entries, with no encryption, zip64 or multi-disk archives
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use flate2::read::DeflateDecoder;
+use i2p::data::encoding::crc32;
+use i2p::error::Error;
+use std::io::Read;
+use std::str;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+
+const LOCAL_HEADER_LENGTH: usize = 30;
+const CENTRAL_HEADER_LENGTH: usize = 46;
+const END_OF_CENTRAL_DIRECTORY_LENGTH: usize = 22;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// Stops a small archive claiming to unpack to something huge
+const MAX_ENTRY_LENGTH: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct ZipEntry {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+fn slice(data: &[u8], offset: usize, length: usize) -> Result<&[u8], Error> {
+    if offset > data.len() || data.len() - offset < length {
+        return Err(Error::Serialization("Truncated zip file".to_string()));
+    }
+
+    Ok(&data[offset..offset + length])
+}
+
+/// The end of central directory record is at the end of the file, followed
+/// only by a comment of up to 64k
+fn find_end_of_central_directory(data: &[u8]) -> Result<usize, Error> {
+    if data.len() < END_OF_CENTRAL_DIRECTORY_LENGTH {
+        return Err(Error::Serialization("Truncated zip file".to_string()));
+    }
+    let last = data.len() - END_OF_CENTRAL_DIRECTORY_LENGTH;
+    let first = last.saturating_sub(u16::MAX as usize);
+    for offset in (first..last + 1).rev() {
+        if (&data[offset..]).read_u32::<LittleEndian>()? == END_OF_CENTRAL_DIRECTORY_SIGNATURE {
+            return Ok(offset);
+        }
+    }
+
+    Err(Error::Serialization("Not a zip file".to_string()))
+}
+
+fn read_entry(data: &[u8], mut header: &[u8]) -> Result<(ZipEntry, usize), Error> {
+    if header.read_u32::<LittleEndian>()? != CENTRAL_HEADER_SIGNATURE {
+        return Err(Error::Serialization("Bad zip central directory entry".to_string()));
+    }
+    let mut fields = &header[4..];
+    let flags = fields.read_u16::<LittleEndian>()?;
+    let method = fields.read_u16::<LittleEndian>()?;
+    let mut fields = &header[12..];
+    let crc = fields.read_u32::<LittleEndian>()?;
+    let compressed_length = fields.read_u32::<LittleEndian>()? as usize;
+    let length = fields.read_u32::<LittleEndian>()? as usize;
+    let name_length = fields.read_u16::<LittleEndian>()? as usize;
+    let extra_length = fields.read_u16::<LittleEndian>()? as usize;
+    let comment_length = fields.read_u16::<LittleEndian>()? as usize;
+    let mut fields = &header[38..];
+    let local_header_offset = fields.read_u32::<LittleEndian>()? as usize;
+    let name = str::from_utf8(slice(header, CENTRAL_HEADER_LENGTH - 4, name_length)?)?
+        .to_string();
+
+    if flags & 0x01 != 0 {
+        return Err(Error::Serialization(format!("Zip entry {} is encrypted", name)));
+    }
+    if length > MAX_ENTRY_LENGTH {
+        return Err(Error::Serialization(format!("Zip entry {} is too long: {}", name, length)));
+    }
+
+    let mut local_header = slice(data, local_header_offset, LOCAL_HEADER_LENGTH)?;
+    if local_header.read_u32::<LittleEndian>()? != LOCAL_HEADER_SIGNATURE {
+        return Err(Error::Serialization(format!("Bad zip local header for {}", name)));
+    }
+    let mut fields = &local_header[22..];
+    let local_name_length = fields.read_u16::<LittleEndian>()? as usize;
+    let local_extra_length = fields.read_u16::<LittleEndian>()? as usize;
+    let compressed = slice(data,
+                           local_header_offset + LOCAL_HEADER_LENGTH + local_name_length +
+                           local_extra_length,
+                           compressed_length)?;
+
+    let contents = match method {
+        METHOD_STORED => compressed.to_vec(),
+        METHOD_DEFLATED => {
+            let mut contents: Vec<u8> = Vec::with_capacity(length);
+            DeflateDecoder::new(compressed)
+                .take(MAX_ENTRY_LENGTH as u64 + 1)
+                .read_to_end(&mut contents)?;
+            contents
+        }
+        _ => {
+            return Err(Error::Serialization(format!("Unsupported zip compression method {} \
+                                                     for {}",
+                                                    method,
+                                                    name)))
+        }
+    };
+    if contents.len() != length || crc32(&contents) != crc {
+        return Err(Error::Serialization(format!("Zip entry {} is corrupt", name)));
+    }
+
+    Ok((ZipEntry {
+            name,
+            data: contents,
+        },
+        CENTRAL_HEADER_LENGTH + name_length + extra_length + comment_length))
+}
+
+/// Unpacks every file in the archive
+pub fn read_zip(data: &[u8]) -> Result<Vec<ZipEntry>, Error> {
+    let end = find_end_of_central_directory(data)?;
+    let mut fields = &data[end + 10..];
+    let entry_count = fields.read_u16::<LittleEndian>()? as usize;
+    let _ = fields.read_u32::<LittleEndian>()?;
+    let mut offset = fields.read_u32::<LittleEndian>()? as usize;
+
+    let mut entries: Vec<ZipEntry> = Vec::with_capacity(entry_count);
+    for _ in 0..entry_count {
+        slice(data, offset, CENTRAL_HEADER_LENGTH)?;
+        let (entry, length) = read_entry(data, &data[offset..])?;
+        entries.push(entry);
+        offset += length;
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use base64::decode;
+    use i2p::reseed::su3::Su3File;
+    use i2p::test_util::read_fixture;
+    use super::*;
+
+    fn read_reseed_zip() -> Vec<u8> {
+        let data = read_fixture("Reseed_RSA_SHA512_4096");
+        Su3File::deserialize(&mut data.as_slice()).unwrap().content().to_vec()
+    }
+
+    #[test]
+    fn test_read_zip() {
+        let entries = read_zip(&read_reseed_zip()).unwrap();
+        assert_eq!(4, entries.len());
+        assert!(entries[0].name.starts_with("routerInfo-"));
+        assert!(entries[0].name.ends_with(".dat"));
+        assert_eq!("README.txt", entries[3].name);
+        assert_eq!(b"Not a RouterInfo\n".to_vec(), entries[3].data);
+    }
+
+    #[test]
+    fn test_read_stored_zip() {
+        // A single uncompressed file, "a.txt", containing "hello\n"
+        let data = decode("UEsDBBQAAAAAAAAAIUogMDo2BgAAAAYAAAAFAAAAYS50eHRoZWxsbwpQSwEC\
+                           FAMUAAAAAAAAACFKIDA6NgYAAAAGAAAABQAAAAAAAAAAAAAAgAEAAAAAYS50\
+                           eHRQSwUGAAAAAAEAAQAzAAAAKQAAAAAA")
+            .unwrap();
+        let entries = read_zip(&data).unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!("a.txt", entries[0].name);
+        assert_eq!(b"hello\n".to_vec(), entries[0].data);
+    }
+
+    #[test]
+    fn test_read_corrupt_zip() {
+        let mut data = read_reseed_zip();
+        assert!(read_zip(&data[..data.len() - 30]).is_err());
+        // Flip a bit in the first entry's compressed data
+        data[100] ^= 0x01;
+        assert!(read_zip(&data).is_err());
+    }
+}
@@ -1,63 +1,651 @@
 use gcrypt;
 use i2p::config::Config;
-use i2p::data::router_info::RouterInfo;
+use i2p::crypto;
+use i2p::crypto::elgamal_aes::InboundSessions;
+use i2p::data::crypto::{Hash, PrivateKey};
+use i2p::data::date::Date;
+use i2p::data::mapping::Mapping;
+use i2p::data::netdb::{NetDB, RouterFilter};
+use i2p::data::router_info::{RouterAddress, RouterInfo};
 use i2p::error::{Error, ParseError};
 use i2p::event_log::EventLog;
+use i2p::http::http_server::HTTPServer;
+use i2p::i2np::{DeliveryStatus, I2NPMessage, MessageBody};
+use i2p::i2np::database::{DatabaseLookup, DatabaseSearchReply, DatabaseStore, LookupType,
+                          StoreData};
+use i2p::i2np::garlic::{Clove, CloveDelivery, Garlic};
+use i2p::i2np::tunnel::TunnelGateway;
+use i2p::lifecycle::{Lifecycle, RouterState, Subsystem, Subsystems};
+use i2p::reseed::reseeder::Reseeder;
 use i2p::router_context::RouterContext;
+use i2p::transport::ReceivedMessage;
+use i2p::transport::transports::Transports;
+use i2p::tunnel::fragment::DeliveryInstructions;
+use i2p::tunnel::participating::{Delivery, ParticipatingTunnels};
+use i2p::tunnel::pool::{BuildMessage, Direction, PoolSettings, Tunnel, TunnelPools};
+use i2p::tunnel::profile::{Profiles, TieredPeers};
 use libc;
-use std::fs::{File, OpenOptions};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
 use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::process::exit;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 const DEFAULT_NETWORK_ID: u32 = 2;
 const NETWORK_ID_CONFIG: &str = "router.networkID";
+const ROUTER_VERSION: &str = "0.9.29";
+const NTCP_ENABLE_CONFIG: &str = "i2np.ntcp.enable";
+const SSU_ENABLE_CONFIG: &str = "i2np.udp.enable";
+const CONSOLE_HOST_CONFIG: &str = "routerconsole.host";
+const DEFAULT_CONSOLE_HOST: &str = "127.0.0.1";
+const CONSOLE_PORT_CONFIG: &str = "routerconsole.port";
+const DEFAULT_CONSOLE_PORT: i64 = 7657;
+
+/// How often the tunnel pools are topped up, in seconds
+const TUNNEL_MAINTENANCE_INTERVAL: u64 = 5;
+
+/// How often changed peer profiles are written out, in milliseconds
+const PROFILE_SAVE_INTERVAL: u64 = 10 * 60 * 1000;
+
+/// How deeply messages can be wrapped in one another, such as a clove in
+/// garlic that came out of a tunnel. A peer could otherwise nest messages
+/// until the dispatch thread runs out of stack.
+const MAX_DISPATCH_DEPTH: usize = 8;
+
+/// How many floodfills we suggest when we don't have what was looked up
+const SEARCH_REPLY_PEERS: usize = 3;
+
+/// How many of the peers a floodfill suggests we look up in turn
+const MAX_SEARCH_REPLY_LOOKUPS: usize = 3;
 
-#[derive(Debug)]
 pub struct Router {
-    router_context: RouterContext,
+    router_context: Arc<RouterContext>,
     event_log: EventLog,
     network_id: u32,
-    state: RouterState,
-    config: Config,
-    token: gcrypt::Token,
-    router_info: Option<RouterInfo>,
-}
-
-#[derive(Debug)]
-enum RouterState {
-    /** constructor complete */
-    INITIALIZED,
-    /** runRouter() called */
-    STARTING_1,
-    /** startupStuff() complete, most of the time here is NTP */
-    STARTING_2,
-    /** NTP done, Job queue started, StartupJob queued, runRouter() returned */
-    STARTING_3,
-    /** RIs loaded. From STARTING_3 */
-    NETDB_READY,
-    /** Non-zero-hop expl. tunnels built. From STARTING_3 */
-    EXPL_TUNNELS_READY,
-    /** from NETDB_READY or EXPL_TUNNELS_READY */
-    RUNNING,
-    /**
-        *  A "soft" restart, primarily of the comm system, after
-        *  a port change or large step-change in system time.
-        *  Does not stop the whole JVM, so it is safe even in the absence
-        *  of the wrapper.
-        *  This is not a graceful restart - all peer connections are dropped immediately.
-        */
-    RESTARTING,
-    /** cancellable shutdown has begun */
-    GRACEFUL_SHUTDOWN,
-    /** In shutdown(). Non-cancellable shutdown has begun */
-    FINAL_SHUTDOWN_1,
-    /** In shutdown2(). Killing everything */
-    FINAL_SHUTDOWN_2,
-    /** In finalShutdown(). Final cleanup */
-    FINAL_SHUTDOWN_3,
-    /** all done */
-    STOPPED
+    lifecycle: Arc<Lifecycle>,
+    subsystems: Subsystems,
+    config: Arc<Config>,
+    // Keeps libgcrypt initialized while the router runs
+    #[allow(dead_code)]
+    token: gcrypt::Gcrypt,
+    netdb: Arc<NetDB>,
+    transports: Arc<RwLock<Transports>>,
+    participating: Arc<ParticipatingTunnels>,
+    pools: Arc<TunnelPools>,
+    profiles: Arc<Profiles>,
+    router_info: Arc<RwLock<Option<RouterInfo>>>,
+}
+
+/// Hands the messages the transports receive to whatever deals with them
+struct Dispatcher {
+    ident: Hash,
+    private_key: PrivateKey,
+    /// Session tags for garlic sent to the router
+    garlic: InboundSessions,
+    netdb: Arc<NetDB>,
+    transports: Arc<RwLock<Transports>>,
+    participating: Arc<ParticipatingTunnels>,
+    pools: Arc<TunnelPools>,
+}
+
+impl Dispatcher {
+    fn dispatch(&self, peer: Hash, message: I2NPMessage) {
+        self.dispatch_nested(peer, message, 0)
+    }
+
+    /// Handles a message that was wrapped `depth` deep in the one a peer
+    /// sent us
+    fn dispatch_nested(&self, peer: Hash, message: I2NPMessage, depth: usize) {
+        if depth > MAX_DISPATCH_DEPTH {
+            debug!("Router: dropping {:?} message {} from {:?}, nested too deeply",
+                   message.message_type(),
+                   message.message_id,
+                   peer);
+            return;
+        }
+        let now = Date::now();
+        // Expired messages may be replays, so nothing gets to handle them
+        if let Err(error) = message.check_expiration(now) {
+            debug!("Router: dropping message from {:?}: {}", peer, error);
+            return;
+        }
+        let result = match message.body {
+            // Our inbound tunnels' last hops send the build message back to
+            // us; anything else is a request to join a tunnel
+            MessageBody::TunnelBuild(_) |
+            MessageBody::VariableTunnelBuild(_) |
+            MessageBody::ShortTunnelBuild(_) => {
+                match self.pools.handle_build_reply(&message, now) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => {
+                        self.participating
+                            .handle_build(&message, now)
+                            .and_then(|(next_ident, next)| self.send(&next_ident, next))
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            MessageBody::TunnelBuildReply(_) |
+            MessageBody::VariableTunnelBuildReply(_) |
+            MessageBody::OutboundTunnelBuildReply(_) => {
+                self.pools.handle_build_reply(&message, now).map(|ours| if !ours {
+                    debug!("Router: build reply {} isn't for any of our tunnels",
+                           message.message_id);
+                })
+            }
+            MessageBody::TunnelGateway(ref gateway) => {
+                match self.pools.handle_tunnel_gateway(gateway) {
+                    Ok(Some(unwrapped)) => {
+                        self.dispatch_nested(peer.clone(), unwrapped, depth + 1);
+                        Ok(())
+                    }
+                    Ok(None) => {
+                        self.participating
+                            .handle_tunnel_gateway(gateway, now)
+                            .and_then(|(next_ident, next)| self.send_all(&next_ident, next))
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            MessageBody::TunnelData(ref tunnel_data) => {
+                match self.pools.handle_tunnel_data(tunnel_data, now) {
+                    Ok(Some(messages)) => {
+                        for message in messages {
+                            self.dispatch_nested(peer.clone(), message, depth + 1);
+                        }
+                        Ok(())
+                    }
+                    Ok(None) => {
+                        match self.participating.handle_tunnel_data(tunnel_data, now) {
+                            Ok(Delivery::Forward(next_ident, next)) => {
+                                self.send(&next_ident, next)
+                            }
+                            Ok(Delivery::Endpoint(messages)) => {
+                                for (instructions, message) in messages {
+                                    if let Err(error) =
+                                        self.deliver(instructions, message, depth + 1) {
+                                        debug!("Router: error delivering from tunnel {}: {}",
+                                               tunnel_data.tunnel_id,
+                                               error);
+                                    }
+                                }
+                                Ok(())
+                            }
+                            Err(error) => Err(error),
+                        }
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            MessageBody::Garlic(ref data) => {
+                self.garlic
+                    .decrypt(&self.private_key, data, now)
+                    .and_then(|payload| Garlic::parse(&payload))
+                    .map(|garlic| {
+                        for clove in garlic.cloves {
+                            let clove_id = clove.clove_id;
+                            if let Err(error) = self.deliver_clove(clove, now, depth + 1) {
+                                debug!("Router: error delivering garlic clove {}: {}",
+                                       clove_id,
+                                       error);
+                            }
+                        }
+                    })
+            }
+            MessageBody::DatabaseStore(ref store) => self.handle_store(store, depth),
+            MessageBody::DatabaseLookup(ref lookup) => self.handle_lookup(lookup, depth),
+            MessageBody::DatabaseSearchReply(ref reply) => self.handle_search_reply(reply),
+            MessageBody::DeliveryStatus(ref status) => {
+                if !self.pools.handle_delivery_status(status, now) {
+                    debug!("Router: DeliveryStatus {} isn't for any of our tests",
+                           status.message_id);
+                }
+                Ok(())
+            }
+            _ => {
+                debug!("Router: dropping {:?} message {} from {:?}",
+                       message.message_type(),
+                       message.message_id,
+                       peer);
+                Ok(())
+            }
+        };
+        if let Err(error) = result {
+            debug!("Router: error handling {:?} message {} from {:?}: {}",
+                   message.message_type(),
+                   message.message_id,
+                   peer,
+                   error);
+        }
+    }
+
+    /// Sends a message to a router we have the RouterInfo for
+    fn send(&self, to: &Hash, message: I2NPMessage) -> Result<(), Error> {
+        let peer = match self.netdb.lookup(to) {
+            Some(peer) => peer,
+            None => return Err(Error::Transport(format!("No RouterInfo for {:?}", to))),
+        };
+
+        self.transports.read().unwrap().send(&peer, vec![message])
+    }
+
+    fn send_all(&self, to: &Hash, messages: Vec<I2NPMessage>) -> Result<(), Error> {
+        for message in messages {
+            self.send(to, message)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a message where a tunnel endpoint's delivery instructions say.
+    /// Messages for us are handled at the given depth.
+    fn deliver(&self,
+               instructions: DeliveryInstructions,
+               message: I2NPMessage,
+               depth: usize)
+               -> Result<(), Error> {
+        match instructions {
+            DeliveryInstructions::Local => {
+                self.dispatch_nested(self.ident.clone(), message, depth);
+                Ok(())
+            }
+            DeliveryInstructions::Router(ref to) if *to == self.ident => {
+                self.dispatch_nested(self.ident.clone(), message, depth);
+                Ok(())
+            }
+            DeliveryInstructions::Router(to) => self.send(&to, message),
+            DeliveryInstructions::Tunnel(gateway, tunnel_id) => {
+                let gateway_message = TunnelGateway::new(tunnel_id, &message)?;
+                let message = I2NPMessage::new(MessageBody::TunnelGateway(gateway_message));
+                if gateway == self.ident {
+                    self.dispatch_nested(self.ident.clone(), message, depth);
+                    return Ok(());
+                }
+                self.send(&gateway, message)
+            }
+        }
+    }
+
+    /// Sends a clove from garlic to us where its instructions say. We have
+    /// no client destinations to deliver to.
+    fn deliver_clove(&self, clove: Clove, now: Date, depth: usize) -> Result<(), Error> {
+        clove.message.check_expiration(now)?;
+        let instructions = match clove.delivery {
+            CloveDelivery::Local => DeliveryInstructions::Local,
+            CloveDelivery::Router(to) => DeliveryInstructions::Router(to),
+            CloveDelivery::Tunnel(gateway, tunnel_id) => {
+                DeliveryInstructions::Tunnel(gateway, tunnel_id)
+            }
+            CloveDelivery::Destination(to) => {
+                return Err(Error::State(format!("No client destination {:?}", to)))
+            }
+        };
+
+        self.deliver(instructions, clove.message, depth)
+    }
+
+    /// Adds a RouterInfo a peer sent us to the NetDB, acknowledging it if
+    /// asked to. We don't keep LeaseSets, as there are no clients to look
+    /// them up for.
+    fn handle_store(&self, store: &DatabaseStore, depth: usize) -> Result<(), Error> {
+        let router_info = match store.data {
+            StoreData::RouterInfo(ref router_info) => router_info,
+            _ => {
+                debug!("Router: not storing {:?} LeaseSet", store.key);
+                return Ok(());
+            }
+        };
+        if router_info.hash()? != store.key {
+            return Err(Error::Serialization(format!("RouterInfo stored under {:?}, not its hash",
+                                                    store.key)));
+        }
+        if self.netdb.insert(router_info.clone())? {
+            debug!("Router: stored RouterInfo {:?}", store.key);
+        }
+
+        match store.reply {
+            Some(ref reply) => {
+                let status = DeliveryStatus {
+                    message_id: reply.token,
+                    timestamp: Date::now(),
+                };
+                let tunnel_id = if reply.tunnel_id == 0 {
+                    None
+                } else {
+                    Some(reply.tunnel_id)
+                };
+                self.deliver(reply_instructions(&reply.gateway, tunnel_id),
+                             I2NPMessage::new(MessageBody::DeliveryStatus(status)),
+                             depth + 1)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Answers a lookup with the RouterInfo if we have it, or the floodfills
+    /// we know closest to the key if we don't
+    fn handle_lookup(&self, lookup: &DatabaseLookup, depth: usize) -> Result<(), Error> {
+        // We'd have to garlic-encrypt the reply, which we can't yet
+        if lookup.reply_encryption.is_some() {
+            return Err(Error::State("Encrypted lookup replies aren't supported".to_string()));
+        }
+        let found = match lookup.lookup_type {
+            LookupType::Any | LookupType::RouterInfo => self.netdb.lookup(&lookup.key),
+            LookupType::LeaseSet | LookupType::Exploration => None,
+        };
+        let body = match found {
+            Some(router_info) => {
+                MessageBody::DatabaseStore(DatabaseStore {
+                    key: lookup.key.clone(),
+                    reply: None,
+                    data: StoreData::RouterInfo((*router_info).clone()),
+                })
+            }
+            None => {
+                let exclude: HashSet<Hash> = lookup.excluded.iter().cloned().collect();
+                let peers = self.netdb
+                    .closest_floodfills(&self.netdb.routing_key(&lookup.key),
+                                        SEARCH_REPLY_PEERS,
+                                        &exclude)
+                    .iter()
+                    .map(|router_info| router_info.hash())
+                    .collect::<Result<Vec<Hash>, Error>>()?;
+                MessageBody::DatabaseSearchReply(DatabaseSearchReply {
+                    key: lookup.key.clone(),
+                    peers,
+                    from: self.ident.clone(),
+                })
+            }
+        };
+
+        self.deliver(reply_instructions(&lookup.from, lookup.reply_tunnel_id),
+                     I2NPMessage::new(body),
+                     depth + 1)
+    }
+
+    /// A floodfill that didn't have a key suggests other peers. We ask it
+    /// for the RouterInfos of the ones we don't know, so the NetDB grows.
+    fn handle_search_reply(&self, reply: &DatabaseSearchReply) -> Result<(), Error> {
+        let unknown = reply.peers
+            .iter()
+            .filter(|peer| **peer != self.ident && self.netdb.lookup(peer).is_none())
+            .take(MAX_SEARCH_REPLY_LOOKUPS);
+        for peer in unknown {
+            let lookup = DatabaseLookup {
+                key: peer.clone(),
+                from: self.ident.clone(),
+                reply_tunnel_id: None,
+                lookup_type: LookupType::RouterInfo,
+                excluded: Vec::new(),
+                reply_encryption: None,
+            };
+            self.send(&reply.from, I2NPMessage::new(MessageBody::DatabaseLookup(lookup)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a message out through one of our outbound tunnels
+    fn send_through(&self,
+                    tunnel: &Tunnel,
+                    instructions: DeliveryInstructions,
+                    message: I2NPMessage)
+                    -> Result<(), Error> {
+        if tunnel.is_zero_hop() {
+            return self.deliver(instructions, message, 0);
+        }
+        let (first_hop, messages) = tunnel.tunnel_messages(&[(instructions, message)])?;
+        let bytes = messages.iter()
+            .map(|message| match message.body {
+                MessageBody::TunnelData(ref tunnel_data) => tunnel_data.data.len() as u64,
+                _ => 0,
+            })
+            .sum();
+        self.pools.record_sent(tunnel.id, bytes);
+
+        self.send_all(&first_hop, messages)
+    }
+}
+
+/// Where to reply to a NetDB message: straight to the router, or down the
+/// tunnel it's the gateway of
+fn reply_instructions(to: &Hash, tunnel_id: Option<u32>) -> DeliveryInstructions {
+    match tunnel_id {
+        Some(tunnel_id) => DeliveryInstructions::Tunnel(to.clone(), tunnel_id),
+        None => DeliveryInstructions::Router(to.clone()),
+    }
+}
+
+/// Loads the NetDB, reseeding if it knows too few routers
+struct NetDBSubsystem {
+    netdb: Arc<NetDB>,
+    reseeder: Reseeder,
+}
+
+impl Subsystem for NetDBSubsystem {
+    fn name(&self) -> &'static str {
+        "NetDB"
+    }
+
+    fn start(&mut self, lifecycle: &Arc<Lifecycle>) -> Result<(), Error> {
+        self.netdb.start()?;
+        // Without a reseed we carry on with whatever routers we know, even
+        // none, and learn about others as they contact us
+        match self.reseeder.reseed_if_needed(&self.netdb) {
+            Ok(0) => {}
+            Ok(count) => info!("Router: reseeded with {} RouterInfos", count),
+            Err(error) if !self.netdb.is_empty() => warn!("Router: error reseeding: {}", error),
+            Err(error) => warn!("Router: error reseeding, starting without peers: {}", error),
+        }
+
+        lifecycle.netdb_ready()
+    }
+
+    fn stop(&mut self) {
+        self.netdb.stop();
+    }
+}
+
+/// Starts the transports, and publishes the addresses they're listening on
+/// in our RouterInfo
+struct TransportsSubsystem {
+    config: Arc<Config>,
+    context: Arc<RouterContext>,
+    network_id: u32,
+    transports: Arc<RwLock<Transports>>,
+    router_info: Arc<RwLock<Option<RouterInfo>>>,
+    dispatcher: Arc<Dispatcher>,
+}
+
+impl TransportsSubsystem {
+    fn publish(&self) -> Result<(), Error> {
+        let transports = self.transports.read().unwrap();
+        let router_info =
+            create_router_info(&self.context, self.network_id, transports.addresses())?;
+        transports.set_router_info(&router_info)?;
+        *self.router_info.write().unwrap() = Some(router_info);
+
+        Ok(())
+    }
+}
+
+impl Subsystem for TransportsSubsystem {
+    fn name(&self) -> &'static str {
+        "transports"
+    }
+
+    fn start(&mut self, _lifecycle: &Arc<Lifecycle>) -> Result<(), Error> {
+        let use_ntcp = self.config.bool_value(NTCP_ENABLE_CONFIG, Some(true)).unwrap();
+        let use_ssu = self.config.bool_value(SSU_ENABLE_CONFIG, Some(true)).unwrap();
+        let (sender, receiver) = mpsc::channel::<ReceivedMessage>();
+
+        let result = self.transports
+            .write()
+            .unwrap()
+            .start(&self.config, &self.context, self.network_id, sender, use_ntcp, use_ssu)
+            .and_then(|_| self.publish());
+        if let Err(error) = result {
+            self.transports.write().unwrap().stop();
+            return Err(error);
+        }
+
+        let dispatcher = self.dispatcher.clone();
+        thread::Builder::new().name("router dispatch".to_string()).spawn(move || {
+            for (peer, message) in receiver.iter() {
+                dispatcher.dispatch(peer, message);
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.transports.write().unwrap().stop();
+    }
+}
+
+/// The tunnels other routers have us in
+struct ParticipatingSubsystem {
+    participating: Arc<ParticipatingTunnels>,
+    transports: Arc<RwLock<Transports>>,
+}
+
+impl Subsystem for ParticipatingSubsystem {
+    fn name(&self) -> &'static str {
+        "participating tunnels"
+    }
+
+    fn start(&mut self, _lifecycle: &Arc<Lifecycle>) -> Result<(), Error> {
+        let bandwidth = self.transports.read().unwrap().bandwidth();
+        self.participating.start(bandwidth)
+    }
+
+    fn stop(&mut self) {
+        self.participating.stop();
+    }
+}
+
+/// Keeps the tunnel pools topped up, sends their tests, and looks after
+/// the peer profiles
+struct TunnelsSubsystem {
+    pools: Arc<TunnelPools>,
+    profiles: Arc<Profiles>,
+    dispatcher: Arc<Dispatcher>,
+    stop_sender: Option<Sender<()>>,
+    maintenance_thread: Option<JoinHandle<()>>,
+}
+
+impl Subsystem for TunnelsSubsystem {
+    fn name(&self) -> &'static str {
+        "tunnel pools"
+    }
+
+    fn start(&mut self, _lifecycle: &Arc<Lifecycle>) -> Result<(), Error> {
+        match self.profiles.load(Date::now()) {
+            Ok(count) => info!("Router: loaded {} peer profiles", count),
+            Err(error) => warn!("Router: can't load peer profiles: {}", error),
+        }
+        let (sender, receiver) = mpsc::channel::<()>();
+        let pools = self.pools.clone();
+        let profiles = self.profiles.clone();
+        let dispatcher = self.dispatcher.clone();
+        let handle = thread::Builder::new().name("tunnel pools".to_string()).spawn(move || {
+            let mut last_save = Date::now();
+            loop {
+                let now = Date::now();
+                profiles.reorganize(now);
+                if last_save.millis() + PROFILE_SAVE_INTERVAL <= now.millis() {
+                    if let Err(error) = profiles.save(now) {
+                        warn!("Router: can't save peer profiles: {}", error);
+                    }
+                    last_save = now;
+                }
+                for BuildMessage { to, outbound, message } in pools.maintain(now) {
+                    let result = match outbound {
+                        Some(tunnel) => {
+                            to.hash().and_then(|hash| {
+                                dispatcher.send_through(&tunnel,
+                                                        DeliveryInstructions::Router(hash),
+                                                        message)
+                            })
+                        }
+                        None => dispatcher.transports.read().unwrap().send(&to, vec![message]),
+                    };
+                    if let Err(error) = result {
+                        debug!("Router: can't send tunnel build to {:?}: {}", to.hash(), error);
+                    }
+                }
+                for test in pools.tests(now) {
+                    let to = DeliveryInstructions::Tunnel(test.gateway, test.tunnel_id);
+                    if let Err(error) = dispatcher.send_through(&test.outbound, to, test.message) {
+                        debug!("Router: can't test tunnel {}: {}", test.outbound.id, error);
+                    }
+                }
+                match receiver.recv_timeout(Duration::from_secs(TUNNEL_MAINTENANCE_INTERVAL)) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+            }
+        })?;
+        self.stop_sender = Some(sender);
+        self.maintenance_thread = Some(handle);
+
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(sender) = self.stop_sender.take() {
+            let _ = sender.send(());
+        }
+        if let Some(handle) = self.maintenance_thread.take() {
+            let _ = handle.join();
+        }
+        self.pools.clear();
+        if let Err(error) = self.profiles.save(Date::now()) {
+            warn!("Router: can't save peer profiles: {}", error);
+        }
+    }
+}
+
+/// Local clients, which connect over I2CP. There's no I2CP server yet, so
+/// this only holds the clients' place in the startup order.
+struct ClientsSubsystem;
+
+impl Subsystem for ClientsSubsystem {
+    fn name(&self) -> &'static str {
+        "clients"
+    }
+
+    fn start(&mut self, _lifecycle: &Arc<Lifecycle>) -> Result<(), Error> {
+        info!("Router: I2CP isn't supported yet, so no clients can connect");
+        Ok(())
+    }
+
+    fn stop(&mut self) {}
+}
+
+/// The router console
+struct ConsoleSubsystem {
+    server: HTTPServer,
+}
+
+impl Subsystem for ConsoleSubsystem {
+    fn name(&self) -> &'static str {
+        "router console"
+    }
+
+    fn start(&mut self, _lifecycle: &Arc<Lifecycle>) -> Result<(), Error> {
+        self.server.start();
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.server.stop();
+    }
 }
 
 fn is_another_router_running(pid_dir: &PathBuf) -> Result<bool, Error> {
@@ -73,7 +661,7 @@ fn is_another_router_running(pid_dir: &PathBuf) -> Result<bool, Error> {
 fn is_process_running(pid: u32) -> bool {
     unsafe {
         let ret = libc::kill(pid as i32, 0);
-        return ret == libc::ESRCH;
+        ret == libc::ESRCH
     }
 }
 
@@ -96,7 +684,7 @@ fn read_pid_file(pid_dir: &PathBuf) -> Result<Option<u32>, Error> {
         Err(error) => {
             return Err(Error::IO {
                 message: Some(format!("Error opening PID file {:?}", pid_filename)),
-                error: error,
+                error,
             })
         }
     };
@@ -105,7 +693,7 @@ fn read_pid_file(pid_dir: &PathBuf) -> Result<Option<u32>, Error> {
     if let Err(error) = pid_file.read_to_string(&mut pid_string) {
         return Err(Error::IO {
             message: Some(format!("Error reading from PID file {:?}", pid_filename)),
-            error: error,
+            error,
         });
     }
 
@@ -123,18 +711,36 @@ fn read_pid_file(pid_dir: &PathBuf) -> Result<Option<u32>, Error> {
 fn write_pid_file(pid_dir: &PathBuf) -> Result<(), Error> {
     let pid_filename = pid_filename(pid_dir);
     info!("Writing pid file {:?}", pid_filename);
-    let mut pid_file = match OpenOptions::new().write(true).create(true).open(&pid_filename) {
+    let mut pid_file = match OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&pid_filename) {
         Ok(file) => file,
         Err(error) => {
             return Err(Error::IO {
                 message: Some(format!("Error opening PID file {:?}", pid_filename)),
-                error: error,
+                error,
             })
         }
     };
-    match pid_file.write(get_process_id().to_string().as_bytes()) {
+    match pid_file.write_all(get_process_id().to_string().as_bytes()) {
         Ok(_) => Ok(()),
-        Err(error) => Err(Error::IO { message: Some(format!("Error writing pid file")), error: error })
+        Err(error) => Err(Error::IO { message: Some("Error writing pid file".to_string()), error })
+    }
+}
+
+fn remove_pid_file(pid_dir: &PathBuf) -> Result<(), Error> {
+    let pid_filename = pid_filename(pid_dir);
+    info!("Removing pid file {:?}", pid_filename);
+    match fs::remove_file(&pid_filename) {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            Err(Error::IO {
+                message: Some(format!("Error removing PID file {:?}", pid_filename)),
+                error,
+            })
+        }
     }
 }
 
@@ -144,10 +750,20 @@ fn get_process_id() -> u32 {
     }
 }
 
-fn init_gcrypt() -> gcrypt::Token {
-    gcrypt::init(|mut x| {
-        x.disable_secmem().enable_quick_random();
-    })
+fn create_router_info(context: &RouterContext,
+                      network_id: u32,
+                      addresses: Vec<RouterAddress>)
+                      -> Result<RouterInfo, Error> {
+    let mut options = Mapping::new();
+    options.insert("caps", "L");
+    options.insert("netId", &network_id.to_string());
+    options.insert("router.version", ROUTER_VERSION);
+
+    let router_info = RouterInfo::new(&context.keys, Date::now(), addresses, options)?;
+    info!("Created router info for router {:?}",
+          context.keys.identity().hash()?);
+
+    Ok(router_info)
 }
 
 impl Router {
@@ -160,23 +776,277 @@ impl Router {
             exit(1);
         }
 
-        write_pid_file(&context.pid_dir);
+        write_pid_file(&context.pid_dir)?;
 
-        let network_id = config.i64_value(NETWORK_ID_CONFIG, Some(DEFAULT_NETWORK_ID as i64)).unwrap() as u32;
+        let network_id =
+            config.i64_value(NETWORK_ID_CONFIG, Some(DEFAULT_NETWORK_ID as i64)).unwrap();
+        if !(1..=255).contains(&network_id) {
+            return Err(Error::Configuration(format!("{} must be between 1 and 255",
+                                                    NETWORK_ID_CONFIG)));
+        }
+        let netdb = Arc::new(NetDB::new(&config, &context.router_dir)?);
+        let lifecycle = Arc::new(Lifecycle::new());
+        let ident = context.keys.identity().hash()?;
+        let participating = ParticipatingTunnels::new(&config,
+                                                      ident.clone(),
+                                                      context.keys.private_key().clone(),
+                                                      lifecycle.clone())?;
+        let profiles = Arc::new(Profiles::new(&context.router_dir)?);
+        let router_info = Arc::new(RwLock::new(None));
+        let known = netdb.clone();
+        let filter = RouterFilter { reachable: Some(true), ..Default::default() };
+        let selector = TieredPeers::new(profiles.clone(),
+                                        ident.clone(),
+                                        router_info.clone(),
+                                        Box::new(move || known.find(&filter)));
+        let pools = TunnelPools::new(ident,
+                                     PoolSettings::from_config(&config, Direction::Inbound)?,
+                                     PoolSettings::from_config(&config, Direction::Outbound)?,
+                                     Box::new(selector),
+                                     lifecycle.clone());
 
         Ok(Router {
-            router_context: context,
+            router_context: Arc::new(context),
             event_log: EventLog::new(&config),
-            network_id: network_id,
-            state: RouterState::INITIALIZED,
-            config: config,
-            token: init_gcrypt(),
-            router_info: None,
+            network_id: network_id as u32,
+            lifecycle,
+            subsystems: Subsystems::new(),
+            config: Arc::new(config),
+            token: crypto::token(),
+            netdb,
+            transports: Arc::new(RwLock::new(Transports::new())),
+            participating: Arc::new(participating),
+            pools: Arc::new(pools),
+            profiles,
+            router_info,
         })
     }
 
-    pub fn run(&mut self) {
-        self.state = RouterState::STARTING_1;
+    /// The router's state, for anything that wants to watch it
+    pub fn lifecycle(&self) -> Arc<Lifecycle> {
+        self.lifecycle.clone()
+    }
+
+    pub fn state(&self) -> RouterState {
+        self.lifecycle.state()
+    }
+
+    /// Our RouterInfo, once the transports have started
+    pub fn router_info(&self) -> Option<RouterInfo> {
+        self.router_info.read().unwrap().clone()
+    }
+
+    /// Starts the NetDB, transports, participating tunnels, tunnel pools,
+    /// clients and router console, in that order. The router is RUNNING once the NetDB
+    /// is loaded and the exploratory tunnels are built.
+    pub fn run(&mut self) -> Result<(), Error> {
+        self.lifecycle.transition(RouterState::STARTING_1)?;
         self.event_log.add_event("started", None);
+        // There's no NTP or job queue to start
+        self.lifecycle.transition(RouterState::STARTING_2)?;
+        self.lifecycle.transition(RouterState::STARTING_3)?;
+
+        let dispatcher = Arc::new(Dispatcher {
+            ident: self.router_context.keys.identity().hash()?,
+            private_key: self.router_context.keys.private_key().clone(),
+            garlic: InboundSessions::new(),
+            netdb: self.netdb.clone(),
+            transports: self.transports.clone(),
+            participating: self.participating.clone(),
+            pools: self.pools.clone(),
+        });
+        let netdb = NetDBSubsystem {
+            netdb: self.netdb.clone(),
+            reseeder: Reseeder::new(&self.config, &self.router_context.config_dir)?,
+        };
+        self.subsystems.add(Box::new(netdb));
+        let transports = TransportsSubsystem {
+            config: self.config.clone(),
+            context: self.router_context.clone(),
+            network_id: self.network_id,
+            transports: self.transports.clone(),
+            router_info: self.router_info.clone(),
+            dispatcher: dispatcher.clone(),
+        };
+        self.subsystems.add(Box::new(transports));
+        let participating = ParticipatingSubsystem {
+            participating: self.participating.clone(),
+            transports: self.transports.clone(),
+        };
+        self.subsystems.add(Box::new(participating));
+        let tunnels = TunnelsSubsystem {
+            pools: self.pools.clone(),
+            profiles: self.profiles.clone(),
+            dispatcher,
+            stop_sender: None,
+            maintenance_thread: None,
+        };
+        self.subsystems.add(Box::new(tunnels));
+        self.subsystems.add(Box::new(ClientsSubsystem));
+        let host = self.config.string_value(CONSOLE_HOST_CONFIG, Some(DEFAULT_CONSOLE_HOST))
+            .unwrap();
+        let port = self.config.i64_value(CONSOLE_PORT_CONFIG, Some(DEFAULT_CONSOLE_PORT))
+            .unwrap();
+        if !(1..=65535).contains(&port) {
+            return Err(Error::Configuration(format!("{} must be between 1 and 65535",
+                                                    CONSOLE_PORT_CONFIG)));
+        }
+        let console = ConsoleSubsystem { server: HTTPServer::new(&host, port as u32)? };
+        self.subsystems.add(Box::new(console));
+
+        if let Err(error) = self.subsystems.start(&self.lifecycle) {
+            self.shutdown();
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Stops everything that was started, newest first, and cleans up.
+    /// Does nothing if the router's already shutting down.
+    pub fn shutdown(&mut self) {
+        if let Err(error) = self.lifecycle.transition(RouterState::FINAL_SHUTDOWN_1) {
+            warn!("Router: not shutting down: {}", error);
+            return;
+        }
+        self.subsystems.stop();
+
+        self.shutdown_transition(RouterState::FINAL_SHUTDOWN_2);
+        if let Err(error) = remove_pid_file(&self.router_context.pid_dir) {
+            error!("Router: {}", error);
+        }
+        self.event_log.add_event("stopped", None);
+
+        self.shutdown_transition(RouterState::FINAL_SHUTDOWN_3);
+        self.shutdown_transition(RouterState::STOPPED);
+    }
+
+    /// Once shutdown has started it goes on to the end, whatever state the
+    /// lifecycle is left in
+    fn shutdown_transition(&self, state: RouterState) {
+        if let Err(error) = self.lifecycle.transition(state) {
+            error!("Router: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::{PublicKeyType, SigningPublicKeyType};
+    use i2p::data::private_keys::PrivateKeys;
+    use i2p::tunnel::pool::{PeerSelector, PoolId};
+    use tempdir::TempDir;
+    use super::*;
+
+    struct NoPeers;
+
+    impl PeerSelector for NoPeers {
+        fn select(&self, _pool: &PoolId, _count: usize) -> Vec<Arc<RouterInfo>> {
+            Vec::new()
+        }
+    }
+
+    fn dispatcher(data_dir: &TempDir) -> Dispatcher {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let ident = keys.identity().hash().unwrap();
+        let config = Config::default();
+        let lifecycle = Arc::new(Lifecycle::new());
+        let participating = ParticipatingTunnels::new(&config,
+                                                      ident.clone(),
+                                                      keys.private_key().clone(),
+                                                      lifecycle.clone())
+            .unwrap();
+        let pools = TunnelPools::new(ident.clone(),
+                                     PoolSettings::from_config(&config, Direction::Inbound)
+                                         .unwrap(),
+                                     PoolSettings::from_config(&config, Direction::Outbound)
+                                         .unwrap(),
+                                     Box::new(NoPeers),
+                                     lifecycle);
+
+        Dispatcher {
+            ident,
+            private_key: keys.private_key().clone(),
+            garlic: InboundSessions::new(),
+            netdb: Arc::new(NetDB::new(&config, data_dir.path()).unwrap()),
+            transports: Arc::new(RwLock::new(Transports::new())),
+            participating: Arc::new(participating),
+            pools: Arc::new(pools),
+        }
+    }
+
+    /// A DatabaseStore of a new router's RouterInfo
+    fn store() -> (Hash, I2NPMessage) {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let router_info = RouterInfo::new(&keys, Date::now(), Vec::new(), Mapping::new())
+            .unwrap();
+        let hash = router_info.hash().unwrap();
+        let store = DatabaseStore {
+            key: hash.clone(),
+            reply: None,
+            data: StoreData::RouterInfo(router_info),
+        };
+
+        (hash, I2NPMessage::new(MessageBody::DatabaseStore(store)))
+    }
+
+    fn peer() -> Hash {
+        Hash::SHA256(vec![1u8; 32].into_boxed_slice())
+    }
+
+    #[test]
+    fn test_store_router_info() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let dispatcher = dispatcher(&data_dir);
+        let (hash, message) = store();
+        dispatcher.dispatch(peer(), message);
+        assert!(dispatcher.netdb.lookup(&hash).is_some());
+    }
+
+    #[test]
+    fn test_store_under_wrong_key() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let dispatcher = dispatcher(&data_dir);
+        let (hash, mut message) = store();
+        if let MessageBody::DatabaseStore(ref mut store) = message.body {
+            store.key = peer();
+        }
+        dispatcher.dispatch(peer(), message);
+        assert!(dispatcher.netdb.lookup(&hash).is_none());
+        assert!(dispatcher.netdb.is_empty());
+    }
+
+    #[test]
+    fn test_expired_message_dropped() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let dispatcher = dispatcher(&data_dir);
+
+        let (hash, mut message) = store();
+        message.expiration = Date::from_millis(Date::now().millis() - 10 * 60 * 1000);
+        dispatcher.dispatch(peer(), message);
+        assert!(dispatcher.netdb.lookup(&hash).is_none());
+
+        let (hash, mut message) = store();
+        message.expiration = Date::from_millis(Date::now().millis() + 60 * 60 * 1000);
+        dispatcher.dispatch(peer(), message);
+        assert!(dispatcher.netdb.lookup(&hash).is_none());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_nesting_limit() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let dispatcher = dispatcher(&data_dir);
+
+        let (hash, message) = store();
+        dispatcher.dispatch_nested(peer(), message, MAX_DISPATCH_DEPTH + 1);
+        assert!(dispatcher.netdb.lookup(&hash).is_none());
+
+        let (hash, message) = store();
+        dispatcher.dispatch_nested(peer(), message, MAX_DISPATCH_DEPTH);
+        assert!(dispatcher.netdb.lookup(&hash).is_some());
+    }
+}
@@ -1,8 +1,13 @@
 use i2p::config::Config;
+use i2p::data::crypto::{PublicKeyType, SigningPublicKeyType};
+use i2p::data::private_keys::PrivateKeys;
 use i2p::error::Error;
 use std::env;
+use std::fs;
 use std::path::PathBuf;
 
+const ROUTER_KEYS_FILE: &str = "router.keys";
+
 #[derive(Debug)]
 pub struct RouterContext {
     pub config_dir: PathBuf,
@@ -10,10 +15,41 @@ pub struct RouterContext {
     pub pid_dir: PathBuf,
     pub log_dir: PathBuf,
     pub app_dir: PathBuf,
+    pub keys: PrivateKeys,
+}
+
+fn make_dir(dir: &PathBuf) -> Result<(), Error> {
+    if !dir.exists() {
+        if let Err(error) = fs::create_dir_all(dir) {
+            return Err(Error::IO {
+                message: Some(format!("Error creating directory {:?}", dir)),
+                error,
+            });
+        }
+    } else if !dir.is_dir() {
+        return Err(Error::Configuration(format!("Path {:?} exists but is not a directory", dir)));
+    }
+
+    Ok(())
 }
 
-fn make_dir(dir: &PathBuf) {
-    // TODO: Implement
+/// Loads our router keys, or generates and saves new ones if this is a new
+/// router. The keys determine our router hash, so they have to be stable.
+fn load_router_keys(router_dir: &PathBuf) -> Result<PrivateKeys, Error> {
+    let mut keys_path = PathBuf::from(router_dir);
+    keys_path.push(ROUTER_KEYS_FILE);
+
+    if keys_path.exists() {
+        info!("RouterContext: loading router keys from {:?}", keys_path);
+        return PrivateKeys::load(&keys_path);
+    }
+
+    info!("RouterContext: generating new router keys in {:?}", keys_path);
+    let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                     &SigningPublicKeyType::EdDSA_SHA512_Ed25519)?;
+    keys.save(&keys_path)?;
+
+    Ok(keys)
 }
 
 impl RouterContext {
@@ -29,26 +65,47 @@ impl RouterContext {
         info!("RouterContext: initializing configuration paths");
 
         let config_dir = config.path_value("i2p.dir.config", Some(&cwd)).unwrap();
-        make_dir(&config_dir);
+        make_dir(&config_dir)?;
 
         let router_dir = config.path_value("i2p.dir.router", Some(&config_dir)).unwrap();
-        make_dir(&router_dir);
+        make_dir(&router_dir)?;
 
         let pid_dir = config.path_value("i2p.dir.pid", Some(&router_dir)).unwrap();
-        make_dir(&pid_dir);
+        make_dir(&pid_dir)?;
 
         let log_dir = config.path_value("i2p.dir.log", Some(&router_dir)).unwrap();
-        make_dir(&log_dir);
+        make_dir(&log_dir)?;
 
         let app_dir = config.path_value("i2p.dir.app", Some(&router_dir)).unwrap();
-        make_dir(&app_dir);
+        make_dir(&app_dir)?;
+
+        let keys = load_router_keys(&router_dir)?;
 
         Ok(RouterContext {
-            config_dir: config_dir,
-            router_dir: router_dir,
-            pid_dir: pid_dir,
-            log_dir: log_dir,
-            app_dir: app_dir,
+            config_dir,
+            router_dir,
+            pid_dir,
+            log_dir,
+            app_dir,
+            keys,
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::load_router_keys;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_router_keys_stable_across_restarts() {
+        let temp_dir = TempDir::new("i2pd-test").unwrap();
+        let router_dir = temp_dir.path().to_path_buf();
+
+        let keys = load_router_keys(&router_dir).unwrap();
+        assert!(router_dir.join("router.keys").exists());
+        let reloaded = load_router_keys(&router_dir).unwrap();
+        assert_eq!(keys.identity().hash().unwrap(),
+                   reloaded.identity().hash().unwrap());
+    }
+}
@@ -0,0 +1,33 @@
+//! Helpers shared by the unit tests
+
+use base64::decode;
+use i2p::data::crypto::Hash;
+use rand::{thread_rng, Rng};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+pub fn random_hash() -> Hash {
+    let mut hash = vec![0u8; 32];
+    thread_rng().fill_bytes(&mut hash);
+    Hash::SHA256(hash.into_boxed_slice())
+}
+
+/// Reads `fixtures/<name>.txt`, which holds a single Base64 value
+pub fn read_fixture(name: &str) -> Vec<u8> {
+    let mut contents = String::new();
+    File::open(format!("fixtures/{}.txt", name))
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+
+    decode(contents.trim_end()).unwrap()
+}
+
+/// Reads `fixtures/<name>.txt`, which holds one Base64 value per line
+pub fn read_fixture_lines(name: &str) -> Vec<Vec<u8>> {
+    let file = File::open(format!("fixtures/{}.txt", name)).unwrap();
+    BufReader::new(file)
+        .lines()
+        .map(|line| decode(line.unwrap().trim_end()).unwrap())
+        .collect()
+}
@@ -0,0 +1,433 @@
+//! The bandwidth limiter every transport shares. Each direction has a token
+//! bucket: it holds up to the burst size, in bytes, and refills at the
+//! configured rate, so we can go over the rate for a while after being
+//! quiet but average no more than it. A second, smaller bucket refilled at
+//! the burst rate caps how fast we go even then.
+//!
+//! Tunnel build messages get priority: data can't take the last tenth of
+//! the bucket, and data waiting for bandwidth waits behind any tunnel
+//! builds that are.
+
+use i2p::config::Config;
+use i2p::error::Error;
+use i2p::i2np::{I2NPMessage, MessageBody};
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+const INBOUND_RATE_CONFIG: &str = "i2np.bandwidth.inboundKBytesPerSecond";
+const INBOUND_BURST_RATE_CONFIG: &str = "i2np.bandwidth.inboundBurstKBytesPerSecond";
+const INBOUND_BURST_SIZE_CONFIG: &str = "i2np.bandwidth.inboundBurstKBytes";
+const OUTBOUND_RATE_CONFIG: &str = "i2np.bandwidth.outboundKBytesPerSecond";
+const OUTBOUND_BURST_RATE_CONFIG: &str = "i2np.bandwidth.outboundBurstKBytesPerSecond";
+const OUTBOUND_BURST_SIZE_CONFIG: &str = "i2np.bandwidth.outboundBurstKBytes";
+
+const DEFAULT_RATE: i64 = 128;
+const DEFAULT_BURST_SIZE: i64 = 22520;
+
+/// The share of the bucket only tunnel builds can use
+const RESERVE_DIVISOR: f64 = 10.0;
+
+/// How far back the average rate goes
+const AVERAGE_PERIOD: u64 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    /// Tunnel build requests and replies, which fail if they're held up
+    TunnelBuild,
+    Data,
+}
+
+impl Priority {
+    /// Tunnel build messages' priority, or data's
+    pub fn of(message: &I2NPMessage) -> Priority {
+        match message.body {
+            MessageBody::TunnelBuild(_) |
+            MessageBody::TunnelBuildReply(_) |
+            MessageBody::VariableTunnelBuild(_) |
+            MessageBody::VariableTunnelBuildReply(_) |
+            MessageBody::ShortTunnelBuild(_) |
+            MessageBody::OutboundTunnelBuildReply(_) => Priority::TunnelBuild,
+            _ => Priority::Data,
+        }
+    }
+
+    /// The highest priority of a batch of messages
+    pub fn of_all(messages: &[I2NPMessage]) -> Priority {
+        if messages.iter().any(|message| Priority::of(message) == Priority::TunnelBuild) {
+            Priority::TunnelBuild
+        } else {
+            Priority::Data
+        }
+    }
+}
+
+/// The limits for one direction, in bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    /// What we average, per second
+    pub rate: u64,
+    /// The fastest we go, per second, while we have burst left
+    pub burst_rate: u64,
+    /// How much we can save up while we're under the rate
+    pub burst_size: u64,
+}
+
+impl Limits {
+    fn from_config(config: &Config,
+                   rate_key: &str,
+                   burst_rate_key: &str,
+                   burst_size_key: &str)
+                   -> Result<Limits, Error> {
+        let rate = config.i64_value(rate_key, Some(DEFAULT_RATE)).unwrap();
+        let burst_rate = config.i64_value(burst_rate_key, Some(rate)).unwrap();
+        let burst_size = config.i64_value(burst_size_key, Some(DEFAULT_BURST_SIZE)).unwrap();
+        let values = [(rate_key, rate), (burst_rate_key, burst_rate), (burst_size_key, burst_size)];
+        for &(key, value) in &values {
+            if value <= 0 {
+                return Err(Error::Configuration(format!("{} must be positive", key)));
+            }
+        }
+
+        // A burst rate under the rate would make it the rate
+        Ok(Limits {
+            rate: rate as u64 * 1024,
+            burst_rate: burst_rate.max(rate) as u64 * 1024,
+            burst_size: burst_size as u64 * 1024,
+        })
+    }
+}
+
+/// How much has gone through in one direction
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateStats {
+    /// Bytes per second over the last second
+    pub current: u64,
+    /// Bytes per second over the last minute, or since we started
+    pub average: u64,
+    pub total: u64,
+}
+
+struct Bucket {
+    limits: Limits,
+    /// What we can use now, up to the burst size. It goes below zero after
+    /// a request bigger than the bucket.
+    tokens: f64,
+    /// The same, for the burst rate, which holds a second's worth
+    burst_tokens: f64,
+    refilled: Instant,
+    /// Tunnel builds waiting for bandwidth, which data waits behind
+    builds_waiting: usize,
+    started: Instant,
+    /// When bytes went through over the last minute, and how many
+    history: VecDeque<(Instant, u64)>,
+    total: u64,
+}
+
+impl Bucket {
+    fn new(limits: Limits, now: Instant) -> Bucket {
+        Bucket {
+            limits,
+            tokens: limits.burst_size as f64,
+            burst_tokens: limits.burst_rate as f64,
+            refilled: now,
+            builds_waiting: 0,
+            started: now,
+            history: VecDeque::new(),
+            total: 0,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.refilled {
+            return;
+        }
+        let elapsed = now.duration_since(self.refilled);
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + seconds * self.limits.rate as f64)
+            .min(self.limits.burst_size as f64);
+        self.burst_tokens = (self.burst_tokens + seconds * self.limits.burst_rate as f64)
+            .min(self.limits.burst_rate as f64);
+        self.refilled = now;
+    }
+
+    /// How long until there's room for a request, which is zero if there is
+    /// now. Requests bigger than a bucket go through once it's full.
+    fn wait_time(&mut self, bytes: u64, priority: Priority, now: Instant) -> Duration {
+        self.refill(now);
+        let reserve = match priority {
+            Priority::TunnelBuild => 0.0,
+            Priority::Data => self.limits.burst_size as f64 / RESERVE_DIVISOR,
+        };
+        let needed = (bytes as f64).min(self.limits.burst_size as f64 - reserve) + reserve;
+        let burst_needed = (bytes as f64).min(self.limits.burst_rate as f64);
+        let seconds = ((needed - self.tokens) / self.limits.rate as f64)
+            .max((burst_needed - self.burst_tokens) / self.limits.burst_rate as f64);
+        if seconds <= 0.0 {
+            return Duration::from_secs(0);
+        }
+
+        // Round up, so that there's room when we look again
+        Duration::new(seconds as u64, (seconds.fract() * 1e9) as u32 + 1)
+    }
+
+    fn take(&mut self, bytes: u64, now: Instant) {
+        self.tokens -= bytes as f64;
+        self.burst_tokens -= bytes as f64;
+        self.total += bytes;
+        self.history.push_back((now, bytes));
+        self.forget(now);
+    }
+
+    fn forget(&mut self, now: Instant) {
+        let period = Duration::from_secs(AVERAGE_PERIOD);
+        while self.history.front().is_some_and(|&(when, _)| now.duration_since(when) >= period) {
+            self.history.pop_front();
+        }
+    }
+
+    fn stats(&mut self, now: Instant) -> RateStats {
+        self.forget(now);
+        let second = Duration::from_secs(1);
+        let current = self.history
+            .iter()
+            .filter(|&&(when, _)| now.duration_since(when) < second)
+            .map(|&(_, bytes)| bytes)
+            .sum();
+        let in_period: u64 = self.history.iter().map(|&(_, bytes)| bytes).sum();
+        let seconds = now.duration_since(self.started).as_secs().clamp(1, AVERAGE_PERIOD);
+
+        RateStats {
+            current,
+            average: in_period / seconds,
+            total: self.total,
+        }
+    }
+}
+
+struct Limiter {
+    bucket: Mutex<Bucket>,
+    /// Signalled when a tunnel build stops waiting, so data can go
+    ready: Condvar,
+}
+
+pub struct BandwidthLimiter {
+    inbound: Limiter,
+    outbound: Limiter,
+}
+
+impl BandwidthLimiter {
+    pub fn new(inbound: Limits, outbound: Limits) -> BandwidthLimiter {
+        let now = Instant::now();
+        BandwidthLimiter {
+            inbound: Limiter {
+                bucket: Mutex::new(Bucket::new(inbound, now)),
+                ready: Condvar::new(),
+            },
+            outbound: Limiter {
+                bucket: Mutex::new(Bucket::new(outbound, now)),
+                ready: Condvar::new(),
+            },
+        }
+    }
+
+    /// Uses the `i2np.bandwidth.*` settings, which are in KBytes
+    pub fn from_config(config: &Config) -> Result<BandwidthLimiter, Error> {
+        let inbound = Limits::from_config(config,
+                                          INBOUND_RATE_CONFIG,
+                                          INBOUND_BURST_RATE_CONFIG,
+                                          INBOUND_BURST_SIZE_CONFIG)?;
+        let outbound = Limits::from_config(config,
+                                           OUTBOUND_RATE_CONFIG,
+                                           OUTBOUND_BURST_RATE_CONFIG,
+                                           OUTBOUND_BURST_SIZE_CONFIG)?;
+        info!("Bandwidth: limiting inbound to {:?} and outbound to {:?}", inbound, outbound);
+
+        Ok(BandwidthLimiter::new(inbound, outbound))
+    }
+
+    fn limiter(&self, direction: Direction) -> &Limiter {
+        match direction {
+            Direction::Inbound => &self.inbound,
+            Direction::Outbound => &self.outbound,
+        }
+    }
+
+    /// Waits until there's bandwidth for `bytes`, and uses it
+    pub fn request(&self, direction: Direction, bytes: usize, priority: Priority) {
+        let limiter = self.limiter(direction);
+        let mut bucket = limiter.bucket.lock().unwrap();
+        if priority == Priority::TunnelBuild {
+            bucket.builds_waiting += 1;
+        }
+        loop {
+            let now = Instant::now();
+            let wait = bucket.wait_time(bytes as u64, priority, now);
+            let behind_builds = priority == Priority::Data && bucket.builds_waiting > 0;
+            if wait == Duration::from_secs(0) && !behind_builds {
+                bucket.take(bytes as u64, now);
+                break;
+            }
+            // Data behind tunnel builds is woken when they're done, but
+            // looks again anyway in case they never get there
+            let wait = if behind_builds && wait == Duration::from_secs(0) {
+                Duration::from_millis(10)
+            } else {
+                wait
+            };
+            bucket = limiter.ready.wait_timeout(bucket, wait).unwrap().0;
+        }
+        if priority == Priority::TunnelBuild {
+            bucket.builds_waiting -= 1;
+            limiter.ready.notify_all();
+        }
+    }
+
+    /// Uses bandwidth for `bytes` if there is some now, without waiting
+    pub fn try_request(&self, direction: Direction, bytes: usize, priority: Priority) -> bool {
+        let mut bucket = self.limiter(direction).bucket.lock().unwrap();
+        let now = Instant::now();
+        if priority == Priority::Data && bucket.builds_waiting > 0 {
+            return false;
+        }
+        if bucket.wait_time(bytes as u64, priority, now) > Duration::from_secs(0) {
+            return false;
+        }
+        bucket.take(bytes as u64, now);
+
+        true
+    }
+
+    pub fn limits(&self, direction: Direction) -> Limits {
+        self.limiter(direction).bucket.lock().unwrap().limits
+    }
+
+    pub fn stats(&self, direction: Direction) -> RateStats {
+        self.limiter(direction).bucket.lock().unwrap().stats(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits(rate: u64, burst_rate: u64, burst_size: u64) -> Limits {
+        Limits {
+            rate,
+            burst_rate,
+            burst_size,
+        }
+    }
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(limits(1000, 1000, 1000), start);
+        assert_eq!(millis(0), bucket.wait_time(1000, Priority::TunnelBuild, start));
+        bucket.take(1000, start);
+
+        // Empty, it refills at the rate
+        let wait = bucket.wait_time(500, Priority::TunnelBuild, start);
+        assert!(wait > millis(499) && wait < millis(501));
+        assert_eq!(millis(0),
+                   bucket.wait_time(500, Priority::TunnelBuild, start + millis(500)));
+        // but never past the burst size
+        assert!(bucket.wait_time(1001, Priority::TunnelBuild, start + millis(5000)) ==
+                millis(0));
+        bucket.take(1001, start + millis(5000));
+        let wait = bucket.wait_time(1, Priority::TunnelBuild, start + millis(5000));
+        assert!(wait > millis(1) && wait < millis(3));
+    }
+
+    #[test]
+    fn test_burst() {
+        // Saved up burst goes no faster than the burst rate
+        let start = Instant::now();
+        let mut bucket = Bucket::new(limits(1000, 4000, 100000), start);
+        assert_eq!(millis(0), bucket.wait_time(4000, Priority::TunnelBuild, start));
+        bucket.take(4000, start);
+        let wait = bucket.wait_time(2000, Priority::TunnelBuild, start);
+        assert!(wait > millis(499) && wait < millis(501));
+        bucket.take(2000, start + wait);
+        assert!(bucket.tokens > 90000.0);
+    }
+
+    #[test]
+    fn test_priority() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(limits(1000, 10000, 10000), start);
+        bucket.take(9000, start);
+
+        // Data can't have the last thousand bytes, but tunnel builds can
+        assert!(bucket.wait_time(100, Priority::Data, start) > millis(0));
+        assert_eq!(millis(0), bucket.wait_time(100, Priority::TunnelBuild, start));
+
+        let limiter = BandwidthLimiter::new(limits(1000, 10000, 10000),
+                                            limits(1000, 10000, 10000));
+        assert!(limiter.try_request(Direction::Outbound, 9000, Priority::Data));
+        assert!(!limiter.try_request(Direction::Outbound, 100, Priority::Data));
+        assert!(limiter.try_request(Direction::Outbound, 100, Priority::TunnelBuild));
+        // The directions are separate
+        assert!(limiter.try_request(Direction::Inbound, 100, Priority::Data));
+    }
+
+    #[test]
+    fn test_request_waits() {
+        let limiter = BandwidthLimiter::new(limits(10000, 10000, 1000),
+                                            limits(10000, 10000, 1000));
+        let start = Instant::now();
+        limiter.request(Direction::Outbound, 1000, Priority::TunnelBuild);
+        limiter.request(Direction::Outbound, 1000, Priority::TunnelBuild);
+        assert!(start.elapsed() >= millis(100));
+        assert!(start.elapsed() < millis(1000));
+    }
+
+    #[test]
+    fn test_stats() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(limits(1000, 1000, 100000), start);
+        bucket.take(3000, start);
+        bucket.take(500, start + millis(1500));
+
+        assert_eq!(RateStats {
+                       current: 500,
+                       average: 3500,
+                       total: 3500,
+                   },
+                   bucket.stats(start + millis(1600)));
+        assert_eq!(RateStats {
+                       current: 0,
+                       average: 350,
+                       total: 3500,
+                   },
+                   bucket.stats(start + millis(10000)));
+        // Only the last minute counts towards the average
+        assert_eq!(RateStats {
+                       current: 0,
+                       average: 0,
+                       total: 3500,
+                   },
+                   bucket.stats(start + millis(62000)));
+    }
+
+    #[test]
+    fn test_priority_of() {
+        use i2p::i2np::tunnel_build::BuildRecords;
+        let records = BuildRecords { records: vec![vec![0; 528]] };
+        let build = I2NPMessage::new(MessageBody::VariableTunnelBuild(records));
+        let data = I2NPMessage::new(MessageBody::Data(vec![1, 2, 3]));
+        assert_eq!(Priority::TunnelBuild, Priority::of(&build));
+        assert_eq!(Priority::Data, Priority::of(&data));
+        assert_eq!(Priority::TunnelBuild, Priority::of_all(&[data, build]));
+    }
+}
@@ -1 +1,75 @@
-pub mod transports;
\ No newline at end of file
+use i2p::data::crypto::Hash;
+use i2p::data::router_info::{RouterAddress, RouterInfo};
+use i2p::error::Error;
+use i2p::i2np::I2NPMessage;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub mod bandwidth;
+pub mod ntcp2;
+pub mod ssu2;
+pub mod transports;
+
+/// An I2NP message a peer sent us, and which peer it was
+pub type ReceivedMessage = (Hash, I2NPMessage);
+
+/// The most sessions the transports have between them. Each transport
+/// reports how many it has whenever that changes, and checks there's room
+/// before accepting a session a router opens with us.
+pub struct SessionLimit {
+    max: usize,
+    counts: Mutex<HashMap<&'static str, usize>>,
+}
+
+impl SessionLimit {
+    pub fn new(max: usize) -> SessionLimit {
+        SessionLimit {
+            max,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn set_count(&self, transport: &'static str, count: usize) {
+        self.counts.lock().unwrap().insert(transport, count);
+    }
+
+    /// How many sessions all the transports have
+    pub fn count(&self) -> usize {
+        self.counts.lock().unwrap().values().sum()
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.count() < self.max
+    }
+}
+
+/// What `Transports` needs from each transport it runs
+pub trait Transport: Send + Sync {
+    /// The transport's name, as used in logs and connection events
+    fn name(&self) -> &'static str;
+
+    /// The address to publish in our RouterInfo
+    fn address(&self) -> RouterAddress;
+
+    /// Sets the RouterInfo we send when we connect
+    fn set_router_info(&self, router_info: &RouterInfo) -> Result<(), Error>;
+
+    /// Which of a router's published addresses we'd connect to, if we can
+    /// reach any of them
+    fn peer_address<'a>(&self, router_info: &'a RouterInfo) -> Option<&'a RouterAddress>;
+
+    fn is_connected(&self, peer: &Hash) -> bool;
+
+    /// How many routers we have sessions with
+    fn connection_count(&self) -> usize;
+
+    /// Sends messages to a router, connecting first if we aren't already
+    fn send(&self, peer: &RouterInfo, messages: Vec<I2NPMessage>) -> Result<(), Error>;
+
+    /// Closes every session and stops listening
+    fn stop(&self);
+}
@@ -0,0 +1,317 @@
+//! NTCP2's data phase. Everything after the handshake is sent as frames of
+//! blocks, each frame encrypted with ChaCha20/Poly1305 and preceded by its
+//! length, which is XORed with a SipHash-generated mask so that nothing on
+//! the wire is in the clear.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::crypto::{chacha20, siphash};
+use i2p::crypto::poly1305::TAG_LENGTH;
+use i2p::data::date::Date;
+use i2p::error::Error;
+use i2p::i2np::{read_bytes, I2NPMessage};
+use rand::{thread_rng, Rng};
+use std::io::{Read, Write};
+
+/// The most a frame can hold, leaving room for the MAC in the two-byte
+/// length
+pub const MAX_FRAME_PAYLOAD: usize = 65535 - TAG_LENGTH;
+
+/// Type and size
+pub const BLOCK_HEADER_LENGTH: usize = 3;
+
+const DATE_TIME_BLOCK: u8 = 0;
+const OPTIONS_BLOCK: u8 = 1;
+const ROUTER_INFO_BLOCK: u8 = 2;
+const I2NP_BLOCK: u8 = 3;
+const TERMINATION_BLOCK: u8 = 4;
+const PADDING_BLOCK: u8 = 254;
+
+/// Set in a RouterInfo block when the receiver, a floodfill, should flood it
+const ROUTER_INFO_FLOOD: u8 = 0x01;
+
+/// Reasons for a Termination block
+pub const TERMINATION_NORMAL: u8 = 0;
+pub const TERMINATION_RECEIVED: u8 = 1;
+pub const TERMINATION_IDLE_TIMEOUT: u8 = 2;
+pub const TERMINATION_ROUTER_SHUTDOWN: u8 = 3;
+pub const TERMINATION_AEAD_FAILURE: u8 = 4;
+pub const TERMINATION_PAYLOAD_FORMAT_ERROR: u8 = 10;
+
+// Blocks are handled as soon as a frame is parsed, so messages stay inline
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Block {
+    DateTime(Date),
+    /// Padding and traffic shaping parameters, which we don't act on yet
+    Options(Vec<u8>),
+    /// A serialized RouterInfo, kept as it came so that it can be stored
+    /// or flooded without being re-serialized
+    RouterInfo { flood: bool, data: Vec<u8> },
+    I2NP(I2NPMessage),
+    Termination { frames_received: u64, reason: u8 },
+    /// Random bytes of the given length
+    Padding(usize),
+}
+
+impl Block {
+    fn write(&self, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        let mut data: Vec<u8> = Vec::new();
+        let block_type = match *self {
+            Block::DateTime(date) => {
+                data.write_u32::<BigEndian>(date.seconds())?;
+                DATE_TIME_BLOCK
+            }
+            Block::Options(ref options) => {
+                data.extend_from_slice(options);
+                OPTIONS_BLOCK
+            }
+            Block::RouterInfo { flood, data: ref router_info } => {
+                data.write_u8(if flood { ROUTER_INFO_FLOOD } else { 0 })?;
+                data.extend_from_slice(router_info);
+                ROUTER_INFO_BLOCK
+            }
+            Block::I2NP(ref message) => {
+                message.serialize_short(&mut data)?;
+                I2NP_BLOCK
+            }
+            Block::Termination { frames_received, reason } => {
+                data.write_u64::<BigEndian>(frames_received)?;
+                data.write_u8(reason)?;
+                TERMINATION_BLOCK
+            }
+            Block::Padding(length) => {
+                data.resize(length, 0);
+                thread_rng().fill_bytes(&mut data);
+                PADDING_BLOCK
+            }
+        };
+        if data.len() > u16::MAX as usize {
+            return Err(Error::Serialization(format!("NTCP2 block too long: {}", data.len())));
+        }
+        buffer.write_u8(block_type)?;
+        buffer.write_u16::<BigEndian>(data.len() as u16)?;
+        buffer.extend(data);
+
+        Ok(())
+    }
+}
+
+/// Lays out the blocks for one frame, checking that they fit
+pub fn write_blocks(blocks: &[Block]) -> Result<Vec<u8>, Error> {
+    let mut buffer: Vec<u8> = Vec::new();
+    for block in blocks {
+        block.write(&mut buffer)?;
+    }
+    if buffer.len() > MAX_FRAME_PAYLOAD {
+        return Err(Error::Serialization(format!("NTCP2 frame too long: {}", buffer.len())));
+    }
+
+    Ok(buffer)
+}
+
+/// Parses a frame's blocks. Unknown blocks are skipped, as the spec says,
+/// but padding has to come last.
+pub fn parse_blocks(data: &[u8]) -> Result<Vec<Block>, Error> {
+    let mut reader = data;
+    let mut blocks: Vec<Block> = Vec::new();
+    while !reader.is_empty() {
+        let block_type = reader.read_u8()?;
+        let size = reader.read_u16::<BigEndian>()? as usize;
+        let mut contents = read_bytes(&mut reader, size)?;
+        let block = match block_type {
+            DATE_TIME_BLOCK => {
+                Block::DateTime(Date::from_seconds(contents.read_u32::<BigEndian>()?))
+            }
+            OPTIONS_BLOCK => Block::Options(contents.to_vec()),
+            ROUTER_INFO_BLOCK => {
+                let flags = contents.read_u8()?;
+                Block::RouterInfo {
+                    flood: flags & ROUTER_INFO_FLOOD != 0,
+                    data: contents.to_vec(),
+                }
+            }
+            I2NP_BLOCK => Block::I2NP(I2NPMessage::deserialize_short(contents)?),
+            TERMINATION_BLOCK => {
+                let frames_received = contents.read_u64::<BigEndian>()?;
+                Block::Termination {
+                    frames_received,
+                    reason: contents.read_u8()?,
+                }
+            }
+            PADDING_BLOCK => {
+                if !reader.is_empty() {
+                    return Err(Error::Serialization("NTCP2 padding isn't the last \
+                                                             block".to_string()));
+                }
+                Block::Padding(size)
+            }
+            _ => {
+                debug!("NTCP2: skipping unknown block type {}", block_type);
+                continue;
+            }
+        };
+        blocks.push(block);
+    }
+
+    Ok(blocks)
+}
+
+/// One direction of a session's data phase: the frame key and nonce, and
+/// the SipHash key and IV for the length masks
+pub struct FrameCipher {
+    key: Vec<u8>,
+    nonce: u64,
+    sip_k0: u64,
+    sip_k1: u64,
+    sip_iv: u64,
+}
+
+fn read_u64_le(data: &[u8]) -> u64 {
+    data[..8].iter().rev().fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+impl FrameCipher {
+    /// `sip_keys` is the two SipHash keys and the IV, eight bytes each
+    pub fn new(key: Vec<u8>, sip_keys: &[u8]) -> FrameCipher {
+        FrameCipher {
+            key,
+            nonce: 0,
+            sip_k0: read_u64_le(&sip_keys[0..]),
+            sip_k1: read_u64_le(&sip_keys[8..]),
+            sip_iv: read_u64_le(&sip_keys[16..]),
+        }
+    }
+
+    /// Each frame's length is masked with the first two bytes of the next
+    /// IV, which is the SipHash of the one before
+    fn next_mask(&mut self) -> u16 {
+        let mut iv = [0u8; 8];
+        for (i, byte) in iv.iter_mut().enumerate() {
+            *byte = (self.sip_iv >> (8 * i)) as u8;
+        }
+        self.sip_iv = siphash::siphash24(self.sip_k0, self.sip_k1, &iv);
+
+        (self.sip_iv as u16).swap_bytes()
+    }
+
+    fn next_nonce(&mut self) -> Result<Vec<u8>, Error> {
+        if self.nonce == u64::MAX {
+            return Err(Error::Crypto("NTCP2 session has run out of nonces".to_string()));
+        }
+        self.nonce += 1;
+
+        Ok(chacha20::nonce(self.nonce - 1))
+    }
+
+    pub fn write_frame<W: Write>(&mut self, writer: &mut W, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() > MAX_FRAME_PAYLOAD {
+            return Err(Error::Serialization(format!("NTCP2 frame too long: {}", payload.len())));
+        }
+        let nonce = self.next_nonce()?;
+        let mut frame: Vec<u8> = Vec::with_capacity(2 + payload.len() + TAG_LENGTH);
+        let length = (payload.len() + TAG_LENGTH) as u16;
+        frame.write_u16::<BigEndian>(length ^ self.next_mask())?;
+        frame.extend(chacha20::aead_encrypt(&self.key, &nonce, &[], payload)?);
+        writer.write_all(&frame)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn read_frame<R: Read>(&mut self, reader: &mut R) -> Result<Vec<u8>, Error> {
+        let length = (reader.read_u16::<BigEndian>()? ^ self.next_mask()) as usize;
+        if length < TAG_LENGTH {
+            return Err(Error::Serialization(format!("NTCP2 frame too short: {}", length)));
+        }
+        let mut frame = vec![0u8; length];
+        reader.read_exact(&mut frame)?;
+        let nonce = self.next_nonce()?;
+
+        chacha20::aead_decrypt(&self.key, &nonce, &[], &frame)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::i2np::MessageBody;
+    use super::*;
+
+    #[test]
+    fn test_blocks() {
+        let blocks = vec![Block::DateTime(Date::from_seconds(1490000000)),
+                          Block::RouterInfo {
+                              flood: true,
+                              data: vec![1, 2, 3],
+                          },
+                          Block::I2NP(I2NPMessage {
+                              message_id: 1234,
+                              expiration: Date::from_seconds(1490000060),
+                              body: MessageBody::Data(b"data".to_vec()),
+                          }),
+                          Block::Termination {
+                              frames_received: 5,
+                              reason: TERMINATION_NORMAL,
+                          },
+                          Block::Padding(10)];
+        let data = write_blocks(&blocks).unwrap();
+        // Five headers, then 4 + 4 + 17 + 9 + 10
+        assert_eq!(59, data.len());
+
+        let parsed = parse_blocks(&data).unwrap();
+        assert_eq!(5, parsed.len());
+        match parsed[1] {
+            Block::RouterInfo { flood, ref data } => {
+                assert!(flood);
+                assert_eq!(vec![1, 2, 3], *data);
+            }
+            ref block => panic!("Unexpected block {:?}", block),
+        }
+        match parsed[2] {
+            Block::I2NP(ref message) => assert_eq!(1234, message.message_id),
+            ref block => panic!("Unexpected block {:?}", block),
+        }
+        match parsed[4] {
+            Block::Padding(10) => (),
+            ref block => panic!("Unexpected block {:?}", block),
+        }
+    }
+
+    #[test]
+    fn test_bad_blocks() {
+        // Unknown blocks are skipped
+        let blocks = parse_blocks(&[0x10, 0x00, 0x01, 0xff, 0x00, 0x00, 0x04, 0, 0, 0, 1])
+            .unwrap();
+        assert_eq!(1, blocks.len());
+
+        // Padding has to be last
+        let mut data = write_blocks(&[Block::Padding(1)]).unwrap();
+        data.extend(write_blocks(&[Block::DateTime(Date::from_seconds(1))]).unwrap());
+        assert!(parse_blocks(&data).is_err());
+
+        // Truncated
+        assert!(parse_blocks(&[0x00, 0x00, 0x04, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_frames() {
+        let sip_keys: Vec<u8> = (0..24).collect();
+        let mut sender = FrameCipher::new(vec![1; 32], &sip_keys);
+        let mut receiver = FrameCipher::new(vec![1; 32], &sip_keys);
+
+        let mut stream: Vec<u8> = Vec::new();
+        sender.write_frame(&mut stream, b"first").unwrap();
+        sender.write_frame(&mut stream, b"second").unwrap();
+        assert_eq!(2 + 5 + 16 + 2 + 6 + 16, stream.len());
+        // The lengths are masked
+        assert!(stream[0] != 0 || stream[1] != 21);
+
+        let mut reader = stream.as_slice();
+        assert_eq!(b"first".to_vec(), receiver.read_frame(&mut reader).unwrap());
+        assert_eq!(b"second".to_vec(), receiver.read_frame(&mut reader).unwrap());
+
+        // A frame out of order fails
+        let mut receiver = FrameCipher::new(vec![1; 32], &sip_keys);
+        let mut reader = &stream[23..];
+        assert!(receiver.read_frame(&mut reader).is_err());
+    }
+}
@@ -0,0 +1,344 @@
+//! The NTCP2 handshake: Noise XK, with the ephemeral keys AES-encrypted to
+//! the responder's router hash so that the first bytes on the wire look
+//! random too. Alice, who connects, already knows Bob's static key and IV
+//! from his RouterInfo; Bob learns Alice's static key in the third message,
+//! along with her RouterInfo, which has to publish the same key.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::crypto::{self, aes, curve25519};
+use i2p::crypto::noise::SymmetricState;
+use i2p::crypto::poly1305::TAG_LENGTH;
+use i2p::data::crypto::Hash;
+use i2p::data::date::Date;
+use i2p::data::encoding::base64_decode;
+use i2p::data::router_info::{RouterAddress, RouterInfo};
+use i2p::error::Error;
+use i2p::transport::ntcp2::frame::{self, Block, FrameCipher, MAX_FRAME_PAYLOAD};
+use rand::{thread_rng, OsRng, Rng};
+use std::io::{Read, Write};
+
+const PROTOCOL_NAME: &str = "Noise_XKaesobfse+hs2+hs3_25519_ChaChaPoly_SHA256";
+
+const PROTOCOL_VERSION: u8 = 2;
+
+/// The encrypted ephemeral key, then the encrypted options
+const MESSAGE_LENGTH: usize = 64;
+
+const OPTIONS_LENGTH: usize = 16;
+
+/// Alice's encrypted static key, in the first part of the third message
+const STATIC_KEY_PART_LENGTH: usize = curve25519::KEY_LENGTH + TAG_LENGTH;
+
+/// Most padding we add to the first two messages
+const MAX_PADDING: usize = 32;
+
+/// How far apart, in seconds, the two routers' clocks can be
+const MAX_CLOCK_SKEW: u32 = 60;
+
+/// Our long-term NTCP2 key, published in our RouterInfo along with the IV
+/// that Alice uses to encrypt her ephemeral key to us
+pub struct StaticKeys {
+    pub private_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub iv: Vec<u8>,
+}
+
+impl StaticKeys {
+    pub fn new(private_key: Vec<u8>, iv: Vec<u8>) -> Result<StaticKeys, Error> {
+        if private_key.len() != curve25519::KEY_LENGTH || iv.len() != aes::BLOCK_LENGTH {
+            return Err(Error::Crypto("Bad NTCP2 static key or IV length".to_string()));
+        }
+
+        Ok(StaticKeys {
+            public_key: curve25519::x25519_public_key(&private_key)?,
+            private_key,
+            iv,
+        })
+    }
+
+    pub fn generate() -> Result<StaticKeys, Error> {
+        let mut rng = OsRng::new()?;
+        let mut private_key = vec![0u8; curve25519::KEY_LENGTH];
+        rng.fill_bytes(&mut private_key);
+        let mut iv = vec![0u8; aes::BLOCK_LENGTH];
+        rng.fill_bytes(&mut iv);
+
+        StaticKeys::new(private_key, iv)
+    }
+}
+
+/// Both directions of an established session
+pub struct SessionKeys {
+    pub send: FrameCipher,
+    pub receive: FrameCipher,
+}
+
+pub fn is_ntcp2(address: &RouterAddress) -> bool {
+    address.transport_style.style() == "NTCP2"
+}
+
+/// The static key a router publishes in its NTCP2 address, if it has one
+pub fn published_static_key(router_info: &RouterInfo) -> Option<Vec<u8>> {
+    router_info.addresses()
+        .iter()
+        .filter(|address| is_ntcp2(address))
+        .filter_map(|address| address.options.get("s"))
+        .filter_map(|key| base64_decode(key).ok())
+        .next()
+}
+
+fn hash_bytes(hash: &Hash) -> &[u8] {
+    let Hash::SHA256(ref data) = *hash;
+    data
+}
+
+fn ephemeral_keys() -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut private_key = vec![0u8; curve25519::KEY_LENGTH];
+    OsRng::new()?.fill_bytes(&mut private_key);
+    let public_key = curve25519::x25519_public_key(&private_key)?;
+
+    Ok((private_key, public_key))
+}
+
+fn random_padding() -> Vec<u8> {
+    let mut rng = thread_rng();
+    let mut padding = vec![0u8; rng.gen_range(0, MAX_PADDING + 1)];
+    rng.fill_bytes(&mut padding);
+
+    padding
+}
+
+/// Reads the padding after the first two messages, which is authenticated
+/// by being mixed into the handshake hash
+fn read_padding<S: Read>(stream: &mut S,
+                         state: &mut SymmetricState,
+                         length: usize)
+                         -> Result<(), Error> {
+    if length > 0 {
+        let mut padding = vec![0u8; length];
+        stream.read_exact(&mut padding)?;
+        state.mix_hash(&padding);
+    }
+
+    Ok(())
+}
+
+fn check_clock_skew(timestamp: u32) -> Result<(), Error> {
+    let now = Date::now().seconds();
+    let skew = timestamp.abs_diff(now);
+    if skew > MAX_CLOCK_SKEW {
+        return Err(Error::Transport(format!("NTCP2 peer's clock is {} seconds out", skew)));
+    }
+
+    Ok(())
+}
+
+/// The data phase keys, from the final chaining key and handshake hash
+fn split(state: &SymmetricState, initiator: bool) -> SessionKeys {
+    let hmac = crypto::hmac_sha256;
+    let concat = |a: &[u8], b: &[u8]| -> Vec<u8> {
+        let mut result = a.to_vec();
+        result.extend_from_slice(b);
+        result
+    };
+
+    let temp_key = hmac(state.chaining_key(), &[]);
+    let key_ab = hmac(&temp_key, &[1]);
+    let key_ba = hmac(&temp_key, &concat(&key_ab, &[2]));
+
+    let ask_master = hmac(&temp_key, b"ask\x01");
+    let temp_key = hmac(&ask_master, &concat(state.hash(), b"siphash"));
+    let sip_master = hmac(&temp_key, &[1]);
+    let temp_key = hmac(&sip_master, &[]);
+    let sip_keys_ab = hmac(&temp_key, &[1]);
+    let sip_keys_ba = hmac(&temp_key, &concat(&sip_keys_ab, &[2]));
+
+    let alice_to_bob = FrameCipher::new(key_ab, &sip_keys_ab);
+    let bob_to_alice = FrameCipher::new(key_ba, &sip_keys_ba);
+    if initiator {
+        SessionKeys {
+            send: alice_to_bob,
+            receive: bob_to_alice,
+        }
+    } else {
+        SessionKeys {
+            send: bob_to_alice,
+            receive: alice_to_bob,
+        }
+    }
+}
+
+/// What Alice needs to know about the router she's connecting to
+pub struct RemoteRouter<'a> {
+    pub hash: &'a Hash,
+    pub static_key: &'a [u8],
+    pub iv: &'a [u8],
+}
+
+/// Runs the handshake as Alice, sending our RouterInfo in the last message
+pub fn initiate<S: Read + Write>(stream: &mut S,
+                                 keys: &StaticKeys,
+                                 router_info: &[u8],
+                                 remote: &RemoteRouter,
+                                 network_id: u8)
+                                 -> Result<SessionKeys, Error> {
+    let mut state = SymmetricState::new(PROTOCOL_NAME);
+    state.mix_hash(remote.static_key);
+
+    // SessionRequest
+    let (ephemeral_private_key, ephemeral_public_key) = ephemeral_keys()?;
+    let encrypted_x = aes::cbc_encrypt(hash_bytes(remote.hash), remote.iv, &ephemeral_public_key)?;
+    state.mix_hash(&ephemeral_public_key);
+    let key = state.mix_key(&curve25519::x25519(&ephemeral_private_key, remote.static_key)?)?;
+
+    let payload = frame::write_blocks(&[Block::RouterInfo {
+                                            flood: false,
+                                            data: router_info.to_vec(),
+                                        },
+                                        Block::Padding(thread_rng().gen_range(0, MAX_PADDING))])?;
+    let padding = random_padding();
+    let mut options: Vec<u8> = Vec::with_capacity(OPTIONS_LENGTH);
+    options.write_u8(network_id)?;
+    options.write_u8(PROTOCOL_VERSION)?;
+    options.write_u16::<BigEndian>(padding.len() as u16)?;
+    options.write_u16::<BigEndian>((payload.len() + TAG_LENGTH) as u16)?;
+    options.write_u16::<BigEndian>(0)?;
+    options.write_u32::<BigEndian>(Date::now().seconds())?;
+    options.write_u32::<BigEndian>(0)?;
+
+    let mut message = encrypted_x.clone();
+    message.extend(state.encrypt_and_hash(&key, 0, &options)?);
+    message.extend_from_slice(&padding);
+    stream.write_all(&message)?;
+    if !padding.is_empty() {
+        state.mix_hash(&padding);
+    }
+
+    // SessionCreated, whose ephemeral key carries on the AES-CBC state from
+    // ours
+    let mut message = [0u8; MESSAGE_LENGTH];
+    stream.read_exact(&mut message)?;
+    let remote_ephemeral_key = aes::cbc_decrypt(hash_bytes(remote.hash),
+                                                &encrypted_x[16..],
+                                                &message[..32])?;
+    state.mix_hash(&remote_ephemeral_key);
+    let key = state.mix_key(&curve25519::x25519(&ephemeral_private_key, &remote_ephemeral_key)?)?;
+    let options = state.decrypt_and_hash(&key, 0, &message[32..])?;
+    let mut reader = &options[2..];
+    let padding_length = reader.read_u16::<BigEndian>()? as usize;
+    reader.read_u32::<BigEndian>()?;
+    check_clock_skew(reader.read_u32::<BigEndian>()?)?;
+    read_padding(stream, &mut state, padding_length)?;
+
+    // SessionConfirmed
+    let mut message = state.encrypt_and_hash(&key, 1, &keys.public_key)?;
+    let key = state.mix_key(&curve25519::x25519(&keys.private_key, &remote_ephemeral_key)?)?;
+    message.extend(state.encrypt_and_hash(&key, 0, &payload)?);
+    stream.write_all(&message)?;
+    stream.flush()?;
+
+    Ok(split(&state, true))
+}
+
+/// Runs the handshake as Bob. Alice's RouterInfo comes back checked: it's
+/// signed, on our network, not ours and publishes the static key she used.
+pub fn accept<S: Read + Write>(stream: &mut S,
+                               keys: &StaticKeys,
+                               our_hash: &Hash,
+                               network_id: u8)
+                               -> Result<(SessionKeys, RouterInfo), Error> {
+    let mut state = SymmetricState::new(PROTOCOL_NAME);
+    state.mix_hash(&keys.public_key);
+
+    // SessionRequest
+    let mut message = [0u8; MESSAGE_LENGTH];
+    stream.read_exact(&mut message)?;
+    let encrypted_x = &message[..32];
+    let remote_ephemeral_key = aes::cbc_decrypt(hash_bytes(our_hash), &keys.iv, encrypted_x)?;
+    state.mix_hash(&remote_ephemeral_key);
+    let key = state.mix_key(&curve25519::x25519(&keys.private_key, &remote_ephemeral_key)?)?;
+    let options = state.decrypt_and_hash(&key, 0, &message[32..])?;
+    let mut reader = options.as_slice();
+    let remote_network_id = reader.read_u8()?;
+    let version = reader.read_u8()?;
+    if remote_network_id != network_id || version != PROTOCOL_VERSION {
+        return Err(Error::Transport(format!("NTCP2 peer is on network {} with version {}",
+                                            remote_network_id,
+                                            version)));
+    }
+    let padding_length = reader.read_u16::<BigEndian>()? as usize;
+    let confirmed_length = reader.read_u16::<BigEndian>()? as usize;
+    if confirmed_length <= TAG_LENGTH || confirmed_length > MAX_FRAME_PAYLOAD + TAG_LENGTH {
+        return Err(Error::Transport(format!("Bad NTCP2 SessionConfirmed length {}",
+                                            confirmed_length)));
+    }
+    reader.read_u16::<BigEndian>()?;
+    check_clock_skew(reader.read_u32::<BigEndian>()?)?;
+    read_padding(stream, &mut state, padding_length)?;
+
+    // SessionCreated
+    let (ephemeral_private_key, ephemeral_public_key) = ephemeral_keys()?;
+    let padding = random_padding();
+    let mut options: Vec<u8> = Vec::with_capacity(OPTIONS_LENGTH);
+    options.write_u16::<BigEndian>(0)?;
+    options.write_u16::<BigEndian>(padding.len() as u16)?;
+    options.write_u32::<BigEndian>(0)?;
+    options.write_u32::<BigEndian>(Date::now().seconds())?;
+    options.write_u32::<BigEndian>(0)?;
+
+    let mut reply = aes::cbc_encrypt(hash_bytes(our_hash),
+                                     &encrypted_x[16..],
+                                     &ephemeral_public_key)?;
+    state.mix_hash(&ephemeral_public_key);
+    let key = state.mix_key(&curve25519::x25519(&ephemeral_private_key, &remote_ephemeral_key)?)?;
+    reply.extend(state.encrypt_and_hash(&key, 0, &options)?);
+    reply.extend_from_slice(&padding);
+    stream.write_all(&reply)?;
+    stream.flush()?;
+    if !padding.is_empty() {
+        state.mix_hash(&padding);
+    }
+
+    // SessionConfirmed
+    let mut message = vec![0u8; STATIC_KEY_PART_LENGTH + confirmed_length];
+    stream.read_exact(&mut message)?;
+    let remote_static_key = state.decrypt_and_hash(&key, 1, &message[..STATIC_KEY_PART_LENGTH])?;
+    let key = state.mix_key(&curve25519::x25519(&ephemeral_private_key, &remote_static_key)?)?;
+    let payload = state.decrypt_and_hash(&key, 0, &message[STATIC_KEY_PART_LENGTH..])?;
+
+    let mut router_info = None;
+    for block in frame::parse_blocks(&payload)? {
+        if let Block::RouterInfo { ref data, .. } = block {
+            router_info = Some(RouterInfo::deserialize(&mut data.as_slice())?);
+        }
+    }
+    let router_info = match router_info {
+        Some(router_info) => router_info,
+        None => return Err(Error::Transport("No RouterInfo in NTCP2 SessionConfirmed".to_string())),
+    };
+    check_router_info(&router_info, &remote_static_key, our_hash, network_id)?;
+
+    Ok((split(&state, false), router_info))
+}
+
+fn check_router_info(router_info: &RouterInfo,
+                     static_key: &[u8],
+                     our_hash: &Hash,
+                     network_id: u8)
+                     -> Result<(), Error> {
+    if !router_info.verify()? {
+        return Err(Error::Transport("Bad signature on NTCP2 peer's RouterInfo".to_string()));
+    }
+    if router_info.hash()? == *our_hash {
+        return Err(Error::Transport("NTCP2 connection from ourselves".to_string()));
+    }
+    if router_info.options().get("netId").is_none_or(|id| *id != network_id.to_string()) {
+        return Err(Error::Transport("NTCP2 peer's RouterInfo is for another network".to_string()));
+    }
+    if published_static_key(router_info).is_none_or(|key| key != static_key) {
+        return Err(Error::Transport("NTCP2 peer's static key isn't the one it \
+                                             publishes".to_string()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,608 @@
+//! NTCP2, the TCP transport. A session is a TCP connection that starts
+//! with the handshake in `handshake` and then carries frames of blocks, as
+//! in `frame`. Each session has a thread reading from it, which hands the
+//! I2NP messages it receives to the channel the transport was started with;
+//! sending writes straight to the connection.
+
+pub mod frame;
+pub mod handshake;
+
+use i2p::config::Config;
+use i2p::data::crypto::Hash;
+use i2p::data::encoding::{base64_decode, base64_encode};
+use i2p::data::mapping::Mapping;
+use i2p::data::router_info::{RouterAddress, RouterInfo, SupportedTransports};
+use i2p::error::Error;
+use i2p::i2np::I2NPMessage;
+use i2p::router_context::RouterContext;
+use i2p::transport::bandwidth::{BandwidthLimiter, Direction, Priority};
+use i2p::transport::{ReceivedMessage, SessionLimit, Transport};
+use i2p::transport::ntcp2::frame::{Block, FrameCipher, BLOCK_HEADER_LENGTH, MAX_FRAME_PAYLOAD};
+use i2p::transport::ntcp2::handshake::{RemoteRouter, StaticKeys};
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+const STATIC_KEYS_FILE: &str = "ntcp2.keys";
+
+const HOSTNAME_CONFIG: &str = "i2np.ntcp.hostname";
+const PORT_CONFIG: &str = "i2np.ntcp.port";
+
+/// Where a port is picked from when none is configured
+const MIN_RANDOM_PORT: u16 = 9111;
+const MAX_RANDOM_PORT: u16 = 30777;
+
+/// i2pd's cost for NTCP2, which is preferred over SSU
+const ADDRESS_COST: u8 = 3;
+
+const HANDSHAKE_TIMEOUT: u64 = 15;
+
+/// A session's connection, and the cipher for what we send on it
+struct Session {
+    stream: TcpStream,
+    send: Mutex<FrameCipher>,
+}
+
+impl Session {
+    fn send(&self, blocks: &[Block]) -> Result<(), Error> {
+        let payload = frame::write_blocks(blocks)?;
+        self.send.lock().unwrap().write_frame(&mut &self.stream, &payload)
+    }
+
+    fn close(&self, reason: u8) {
+        let _ = self.send(&[Block::Termination {
+                                frames_received: 0,
+                                reason,
+                            }]);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// What the listener and session threads share with the transport
+struct Shared {
+    keys: StaticKeys,
+    hash: Hash,
+    network_id: u8,
+    /// Our RouterInfo, serialized, as sent to the routers we connect to
+    router_info: Mutex<Vec<u8>>,
+    sessions: Mutex<HashMap<Hash, Arc<Session>>>,
+    messages: Mutex<Sender<ReceivedMessage>>,
+    bandwidth: Arc<BandwidthLimiter>,
+    limit: Arc<SessionLimit>,
+    running: AtomicBool,
+}
+
+impl Shared {
+    fn report_count(&self, sessions: &HashMap<Hash, Arc<Session>>) {
+        self.limit.set_count("NTCP2", sessions.len());
+    }
+}
+
+pub struct Ntcp2 {
+    shared: Arc<Shared>,
+    local_address: SocketAddr,
+    /// The host we publish, if we're reachable
+    hostname: Option<String>,
+}
+
+/// Loads our static key and IV, or generates and saves new ones. They're
+/// in our RouterInfo, so like the router keys they have to be stable.
+fn load_static_keys(router_dir: &Path) -> Result<StaticKeys, Error> {
+    let path = router_dir.join(STATIC_KEYS_FILE);
+    if path.exists() {
+        info!("NTCP2: loading static keys from {:?}", path);
+        let mut data: Vec<u8> = Vec::new();
+        if let Err(error) = File::open(&path).and_then(|mut file| file.read_to_end(&mut data)) {
+            return Err(Error::IO {
+                message: Some(format!("Error reading NTCP2 keys file {:?}", path)),
+                error,
+            });
+        }
+        if data.len() != 48 {
+            return Err(Error::Crypto(format!("NTCP2 keys file {:?} is {} bytes, not 48",
+                                             path,
+                                             data.len())));
+        }
+        let iv = data.split_off(32);
+        return StaticKeys::new(data, iv);
+    }
+
+    info!("NTCP2: generating new static keys in {:?}", path);
+    let keys = StaticKeys::generate()?;
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&keys.private_key)?;
+        file.write_all(&keys.iv)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, &path)?;
+
+    Ok(keys)
+}
+
+/// The socket address, static key and IV of a published NTCP2 address, if
+/// it's one we can connect to
+fn parse_address(address: &RouterAddress) -> Option<(SocketAddr, Vec<u8>, Vec<u8>)> {
+    let options = &address.options;
+    let (host, port, key, iv) = match (options.get("host"),
+                                       options.get("port"),
+                                       options.get("s"),
+                                       options.get("i")) {
+        (Some(host), Some(port), Some(key), Some(iv)) => (host, port, key, iv),
+        _ => return None,
+    };
+    match (host.parse::<IpAddr>(), port.parse::<u16>(), base64_decode(key), base64_decode(iv)) {
+        (Ok(ip), Ok(port), Ok(key), Ok(iv)) => Some((SocketAddr::new(ip, port), key, iv)),
+        _ => None,
+    }
+}
+
+/// The address, static key and IV of a router's published NTCP2 address
+fn remote_address(router_info: &RouterInfo) -> Result<(SocketAddr, Vec<u8>, Vec<u8>), Error> {
+    match router_info.addresses()
+        .iter()
+        .filter(|address| handshake::is_ntcp2(address))
+        .filter_map(parse_address)
+        .next() {
+        Some(address) => Ok(address),
+        None => {
+            Err(Error::Transport(format!("No usable NTCP2 address for {:?}", router_info.hash()?)))
+        }
+    }
+}
+
+impl Ntcp2 {
+    /// Starts listening on the configured port, or a random one. We only
+    /// publish a host if `i2np.ntcp.hostname` is set.
+    pub fn new(config: &Config,
+               context: &RouterContext,
+               network_id: u32,
+               messages: Sender<ReceivedMessage>,
+               bandwidth: Arc<BandwidthLimiter>,
+               limit: Arc<SessionLimit>)
+               -> Result<Ntcp2, Error> {
+        let random_port = thread_rng().gen_range(MIN_RANDOM_PORT, MAX_RANDOM_PORT);
+        let port = config.i64_value(PORT_CONFIG, Some(random_port as i64)).unwrap();
+        if port <= 0 || port > u16::MAX as i64 {
+            return Err(Error::Configuration(format!("{} must be a port number", PORT_CONFIG)));
+        }
+        let listen_address = SocketAddr::new("0.0.0.0".parse().unwrap(), port as u16);
+
+        Ntcp2::start(load_static_keys(&context.router_dir)?,
+                     context.keys.identity().hash()?,
+                     listen_address,
+                     config.string_value(HOSTNAME_CONFIG, None),
+                     network_id as u8,
+                     messages,
+                     bandwidth,
+                     limit)
+    }
+
+    // Everything the listener thread owns is handed over here
+    #[allow(clippy::too_many_arguments)]
+    fn start(keys: StaticKeys,
+             hash: Hash,
+             listen_address: SocketAddr,
+             hostname: Option<String>,
+             network_id: u8,
+             messages: Sender<ReceivedMessage>,
+             bandwidth: Arc<BandwidthLimiter>,
+             limit: Arc<SessionLimit>)
+             -> Result<Ntcp2, Error> {
+        let listener = TcpListener::bind(listen_address)?;
+        let local_address = listener.local_addr()?;
+        info!("NTCP2: listening on {}", local_address);
+
+        let shared = Arc::new(Shared {
+            keys,
+            hash,
+            network_id,
+            router_info: Mutex::new(Vec::new()),
+            sessions: Mutex::new(HashMap::new()),
+            messages: Mutex::new(messages),
+            bandwidth,
+            limit,
+            running: AtomicBool::new(true),
+        });
+        let listener_shared = shared.clone();
+        thread::Builder::new().name("ntcp2".to_string()).spawn(move || {
+            for stream in listener.incoming() {
+                if !listener_shared.running.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => accept(&listener_shared, stream),
+                    Err(error) => warn!("NTCP2: error accepting connection: {}", error),
+                }
+            }
+        })?;
+
+        Ok(Ntcp2 {
+            shared,
+            local_address,
+            hostname,
+        })
+    }
+
+    pub fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+}
+
+impl Transport for Ntcp2 {
+    fn name(&self) -> &'static str {
+        "NTCP2"
+    }
+
+    /// The address to publish in our RouterInfo. Without a host it just
+    /// gives our static key, so that routers we connect to can check it.
+    fn address(&self) -> RouterAddress {
+        let mut options = Mapping::new();
+        options.insert("s", &base64_encode(&self.shared.keys.public_key));
+        options.insert("v", "2");
+        let mut transport_style = SupportedTransports::NTCP2V4;
+        if let Some(ref host) = self.hostname {
+            if host.contains(':') {
+                transport_style = SupportedTransports::NTCP2V6;
+            }
+            options.insert("host", host);
+            options.insert("port", &self.local_address.port().to_string());
+            options.insert("i", &base64_encode(&self.shared.keys.iv));
+        }
+
+        RouterAddress {
+            cost: ADDRESS_COST,
+            expiration: None,
+            transport_style,
+            options,
+        }
+    }
+
+    /// Sets the RouterInfo we send when we connect; it has to include our
+    /// address
+    fn set_router_info(&self, router_info: &RouterInfo) -> Result<(), Error> {
+        let mut data: Vec<u8> = Vec::new();
+        router_info.serialize(&mut data)?;
+        *self.shared.router_info.lock().unwrap() = data;
+
+        Ok(())
+    }
+
+    fn peer_address<'a>(&self, router_info: &'a RouterInfo) -> Option<&'a RouterAddress> {
+        router_info.addresses()
+            .iter()
+            .find(|address| handshake::is_ntcp2(address) && parse_address(address).is_some())
+    }
+
+    fn is_connected(&self, peer: &Hash) -> bool {
+        self.shared.sessions.lock().unwrap().contains_key(peer)
+    }
+
+    fn connection_count(&self) -> usize {
+        self.shared.sessions.lock().unwrap().len()
+    }
+
+    /// Sends messages to a router, connecting first if we aren't already.
+    /// The messages go in as few frames as they fit in, each waiting for
+    /// bandwidth before it's added.
+    fn send(&self, peer: &RouterInfo, messages: Vec<I2NPMessage>) -> Result<(), Error> {
+        let hash = peer.hash()?;
+        let existing = self.shared.sessions.lock().unwrap().get(&hash).cloned();
+        let session = match existing {
+            Some(session) => session,
+            None => connect(&self.shared, peer, &hash)?,
+        };
+
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut frame_length = 0;
+        for message in messages {
+            let length = BLOCK_HEADER_LENGTH + message.short_length()?;
+            self.shared.bandwidth.request(Direction::Outbound, length, Priority::of(&message));
+            if frame_length + length > MAX_FRAME_PAYLOAD && !blocks.is_empty() {
+                session.send(&blocks)?;
+                blocks.clear();
+                frame_length = 0;
+            }
+            blocks.push(Block::I2NP(message));
+            frame_length += length;
+        }
+        if !blocks.is_empty() {
+            session.send(&blocks)?;
+        }
+
+        Ok(())
+    }
+
+    /// Closes every session and stops listening
+    fn stop(&self) {
+        self.shared.running.store(false, Ordering::SeqCst);
+        // Wake the listener up so that it sees we've stopped
+        let _ = TcpStream::connect(("127.0.0.1", self.local_address.port()));
+        let mut sessions = self.shared.sessions.lock().unwrap();
+        for (_, session) in sessions.drain() {
+            session.close(frame::TERMINATION_ROUTER_SHUTDOWN);
+        }
+        self.shared.report_count(&sessions);
+    }
+}
+
+fn connect(shared: &Arc<Shared>, peer: &RouterInfo, hash: &Hash) -> Result<Arc<Session>, Error> {
+    let (address, static_key, iv) = remote_address(peer)?;
+    debug!("NTCP2: connecting to {:?} at {}", hash, address);
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)))?;
+    let router_info = shared.router_info.lock().unwrap().clone();
+    let remote = RemoteRouter {
+        hash,
+        static_key: &static_key,
+        iv: &iv,
+    };
+    let keys = handshake::initiate(&mut stream,
+                                   &shared.keys,
+                                   &router_info,
+                                   &remote,
+                                   shared.network_id)?;
+
+    start_session(shared, stream, hash.clone(), keys)
+}
+
+fn accept(shared: &Arc<Shared>, stream: TcpStream) {
+    // There are no keys to send a termination with before the handshake,
+    // so over the limit the connection is just closed
+    if !shared.limit.has_room() {
+        debug!("NTCP2: at the limit of {} sessions, closing connection from {:?}",
+               shared.limit.max(),
+               stream.peer_addr());
+        let _ = stream.shutdown(Shutdown::Both);
+        return;
+    }
+    let shared = shared.clone();
+    // The handshake gets its own thread, so a slow peer can't hold up others
+    let result = thread::Builder::new().name("ntcp2 session".to_string()).spawn(move || {
+        let peer = stream.peer_addr();
+        if let Err(error) = accept_session(&shared, stream) {
+            debug!("NTCP2: handshake with {:?} failed: {}", peer, error);
+        }
+    });
+    if let Err(error) = result {
+        error!("NTCP2: error starting session thread: {}", error);
+    }
+}
+
+fn accept_session(shared: &Arc<Shared>, mut stream: TcpStream) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)))?;
+    let (keys, router_info) =
+        handshake::accept(&mut stream, &shared.keys, &shared.hash, shared.network_id)?;
+    start_session(shared, stream, router_info.hash()?, keys)?;
+
+    Ok(())
+}
+
+/// Registers an established session and starts reading from it
+fn start_session(shared: &Arc<Shared>,
+                 stream: TcpStream,
+                 peer: Hash,
+                 keys: handshake::SessionKeys)
+                 -> Result<Arc<Session>, Error> {
+    stream.set_read_timeout(None)?;
+    let mut reader = stream.try_clone()?;
+    let session = Arc::new(Session {
+        stream,
+        send: Mutex::new(keys.send),
+    });
+    info!("NTCP2: established session with {:?}", peer);
+    {
+        let mut sessions = shared.sessions.lock().unwrap();
+        if let Some(old) = sessions.insert(peer.clone(), session.clone()) {
+            old.close(frame::TERMINATION_NORMAL);
+        }
+        shared.report_count(&sessions);
+    }
+
+    let shared = shared.clone();
+    let reader_session = session.clone();
+    let mut receive = keys.receive;
+    thread::Builder::new().name("ntcp2 session".to_string()).spawn(move || {
+        let messages = shared.messages.lock().unwrap().clone();
+        let reason = loop {
+            let payload = match receive.read_frame(&mut reader) {
+                Ok(payload) => payload,
+                Err(Error::Crypto(_)) => break frame::TERMINATION_AEAD_FAILURE,
+                Err(_) => break frame::TERMINATION_NORMAL,
+            };
+            let blocks = match frame::parse_blocks(&payload) {
+                Ok(blocks) => blocks,
+                Err(_) => break frame::TERMINATION_PAYLOAD_FORMAT_ERROR,
+            };
+            // Holding off reading slows the peer down through TCP
+            let build = blocks.iter().any(|block| match *block {
+                Block::I2NP(ref message) => Priority::of(message) == Priority::TunnelBuild,
+                _ => false,
+            });
+            let priority = if build { Priority::TunnelBuild } else { Priority::Data };
+            shared.bandwidth.request(Direction::Inbound, payload.len(), priority);
+            let mut terminated = false;
+            for block in blocks {
+                match block {
+                    Block::I2NP(message) => {
+                        terminated |= messages.send((peer.clone(), message)).is_err();
+                    }
+                    Block::Termination { reason, .. } => {
+                        debug!("NTCP2: {:?} closed the session, reason {}", peer, reason);
+                        terminated = true;
+                    }
+                    _ => (),
+                }
+            }
+            if terminated {
+                break frame::TERMINATION_RECEIVED;
+            }
+        };
+
+        // Only forget the session if it hasn't been replaced
+        let mut sessions = shared.sessions.lock().unwrap();
+        if sessions.get(&peer).is_some_and(|current| Arc::ptr_eq(current, &reader_session)) {
+            sessions.remove(&peer);
+            shared.report_count(&sessions);
+        }
+        reader_session.close(reason);
+        debug!("NTCP2: session with {:?} closed", peer);
+    })?;
+
+    Ok(session)
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::{PublicKeyType, SigningPublicKeyType};
+    use i2p::data::date::Date;
+    use i2p::data::private_keys::PrivateKeys;
+    use i2p::i2np::MessageBody;
+    use i2p::test_util::read_fixture;
+    use i2p::transport::bandwidth::Limits;
+    use std::sync::mpsc::{channel, Receiver};
+    use super::*;
+
+    /// A router listening on loopback, with a RouterInfo publishing its
+    /// NTCP2 address
+    fn router(network_id: u8) -> (Ntcp2, RouterInfo, Receiver<ReceivedMessage>) {
+        limited_router(network_id, 250)
+    }
+
+    fn limited_router(network_id: u8,
+                      max_sessions: usize)
+                      -> (Ntcp2, RouterInfo, Receiver<ReceivedMessage>) {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let (sender, receiver) = channel();
+        let limits = Limits {
+            rate: 1 << 30,
+            burst_rate: 1 << 30,
+            burst_size: 1 << 30,
+        };
+        let ntcp2 = Ntcp2::start(StaticKeys::generate().unwrap(),
+                                 keys.identity().hash().unwrap(),
+                                 "127.0.0.1:0".parse().unwrap(),
+                                 Some("127.0.0.1".to_string()),
+                                 network_id,
+                                 sender,
+                                 Arc::new(BandwidthLimiter::new(limits, limits)),
+                                 Arc::new(SessionLimit::new(max_sessions)))
+            .unwrap();
+        let mut options = Mapping::new();
+        options.insert("caps", "LR");
+        options.insert("netId", &network_id.to_string());
+        let router_info = RouterInfo::new(&keys, Date::now(), vec![ntcp2.address()], options)
+            .unwrap();
+        ntcp2.set_router_info(&router_info).unwrap();
+
+        (ntcp2, router_info, receiver)
+    }
+
+    fn data(contents: &[u8]) -> I2NPMessage {
+        I2NPMessage::new(MessageBody::Data(contents.to_vec()))
+    }
+
+    fn received_data(receiver: &Receiver<ReceivedMessage>) -> (Hash, Vec<u8>) {
+        let (peer, message) = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        match message.body {
+            MessageBody::Data(contents) => (peer, contents),
+            body => panic!("Unexpected message {:?}", body),
+        }
+    }
+
+    #[test]
+    fn test_loopback() {
+        let (alice, alice_info, alice_messages) = router(2);
+        let (bob, bob_info, bob_messages) = router(2);
+
+        alice.send(&bob_info, vec![data(b"hello"), data(b"bob")]).unwrap();
+        assert_eq!((alice_info.hash().unwrap(), b"hello".to_vec()),
+                   received_data(&bob_messages));
+        assert_eq!((alice_info.hash().unwrap(), b"bob".to_vec()),
+                   received_data(&bob_messages));
+
+        // Bob replies over the session Alice opened
+        assert!(bob.is_connected(&alice_info.hash().unwrap()));
+        bob.send(&alice_info, vec![data(b"hello alice")]).unwrap();
+        assert_eq!((bob_info.hash().unwrap(), b"hello alice".to_vec()),
+                   received_data(&alice_messages));
+
+        // A message that needs a frame of its own
+        alice.send(&bob_info, vec![data(b"small"), data(&vec![7; 60000])]).unwrap();
+        assert_eq!(b"small".to_vec(), received_data(&bob_messages).1);
+        assert_eq!(60000, received_data(&bob_messages).1.len());
+
+        alice.stop();
+        bob.stop();
+    }
+
+    #[test]
+    fn test_published_address() {
+        let (alice, alice_info, _) = router(2);
+        let address = &alice_info.addresses()[0];
+        assert_eq!("NTCP2", address.transport_style.style());
+        assert!(alice.peer_address(&alice_info).is_some());
+        alice.stop();
+
+        // A RouterInfo as other routers publish it
+        let data = read_fixture("RouterInfo_NTCP2_SSU2_EdDSA_SHA512_Ed25519");
+        let router_info = RouterInfo::deserialize(&mut data.as_slice()).unwrap();
+        let (socket_address, key, iv) = remote_address(&router_info).unwrap();
+        assert_eq!("203.0.113.7:23456".parse::<SocketAddr>().unwrap(), socket_address);
+        assert_eq!(32, key.len());
+        assert_eq!(16, iv.len());
+        assert_eq!(Some(key), handshake::published_static_key(&router_info));
+    }
+
+    #[test]
+    fn test_other_network() {
+        let (alice, _, _) = router(2);
+        let (bob, bob_info, bob_messages) = router(3);
+
+        assert!(alice.send(&bob_info, vec![data(b"hello")]).is_err());
+        assert!(bob_messages.recv_timeout(Duration::from_millis(500)).is_err());
+
+        alice.stop();
+        bob.stop();
+    }
+
+    #[test]
+    fn test_session_limit() {
+        let (alice, alice_info, _) = router(2);
+        let (carol, _, _) = router(2);
+        let (bob, bob_info, bob_messages) = limited_router(2, 1);
+
+        alice.send(&bob_info, vec![data(b"hello")]).unwrap();
+        assert_eq!((alice_info.hash().unwrap(), b"hello".to_vec()),
+                   received_data(&bob_messages));
+        assert_eq!(1, bob.shared.limit.count());
+
+        // Bob's full, so Carol doesn't get a session
+        assert!(carol.send(&bob_info, vec![data(b"hello")]).is_err());
+        assert!(bob_messages.recv_timeout(Duration::from_millis(500)).is_err());
+
+        alice.stop();
+        bob.stop();
+        carol.stop();
+    }
+
+    #[test]
+    fn test_static_keys_stable_across_restarts() {
+        let temp_dir = ::tempdir::TempDir::new("i2pd-test").unwrap();
+        let keys = load_static_keys(temp_dir.path()).unwrap();
+        let reloaded = load_static_keys(temp_dir.path()).unwrap();
+        assert_eq!(keys.public_key, reloaded.public_key);
+        assert_eq!(keys.iv, reloaded.iv);
+    }
+}
@@ -0,0 +1,527 @@
+//! The blocks SSU2 payloads are made of. They're laid out like NTCP2's, but
+//! there are more of them: UDP has no streams, so messages too big for a
+//! packet are split into fragments, and packets are acknowledged with ACK
+//! blocks.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::data::crypto::Hash;
+use i2p::data::date::Date;
+use i2p::error::Error;
+use i2p::i2np::{read_bytes, read_hash, write_hash, I2NPMessage};
+use rand::{thread_rng, Rng};
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Type and size
+pub const BLOCK_HEADER_LENGTH: usize = 3;
+
+/// A follow-on fragment's number and message ID
+pub const FOLLOW_ON_HEADER_LENGTH: usize = 5;
+
+/// Most fragments after the first, since their number is seven bits
+pub const MAX_FOLLOW_ON_FRAGMENTS: usize = 127;
+
+const DATE_TIME_BLOCK: u8 = 0;
+const OPTIONS_BLOCK: u8 = 1;
+const ROUTER_INFO_BLOCK: u8 = 2;
+const I2NP_BLOCK: u8 = 3;
+const FIRST_FRAGMENT_BLOCK: u8 = 4;
+const FOLLOW_ON_FRAGMENT_BLOCK: u8 = 5;
+const TERMINATION_BLOCK: u8 = 6;
+const RELAY_REQUEST_BLOCK: u8 = 7;
+const RELAY_RESPONSE_BLOCK: u8 = 8;
+const RELAY_INTRO_BLOCK: u8 = 9;
+const PEER_TEST_BLOCK: u8 = 10;
+const ACK_BLOCK: u8 = 12;
+const ADDRESS_BLOCK: u8 = 13;
+const RELAY_TAG_REQUEST_BLOCK: u8 = 15;
+const RELAY_TAG_BLOCK: u8 = 16;
+const NEW_TOKEN_BLOCK: u8 = 17;
+const PATH_CHALLENGE_BLOCK: u8 = 18;
+const PATH_RESPONSE_BLOCK: u8 = 19;
+const PADDING_BLOCK: u8 = 254;
+
+/// Set in a RouterInfo block when the receiver, a floodfill, should flood it
+const ROUTER_INFO_FLOOD: u8 = 0x01;
+const ROUTER_INFO_GZIP: u8 = 0x02;
+
+/// A RouterInfo in one piece: fragment 0 of 1
+const ROUTER_INFO_UNFRAGMENTED: u8 = 0x01;
+
+/// Reasons for a Termination block
+pub const TERMINATION_NORMAL: u8 = 0;
+pub const TERMINATION_RECEIVED: u8 = 1;
+pub const TERMINATION_IDLE_TIMEOUT: u8 = 2;
+pub const TERMINATION_ROUTER_SHUTDOWN: u8 = 3;
+pub const TERMINATION_AEAD_FAILURE: u8 = 4;
+pub const TERMINATION_CLOCK_SKEW: u8 = 7;
+pub const TERMINATION_PAYLOAD_FORMAT_ERROR: u8 = 10;
+pub const TERMINATION_TIMEOUT: u8 = 14;
+pub const TERMINATION_BAD_TOKEN: u8 = 18;
+pub const TERMINATION_CONNECTION_LIMITS: u8 = 19;
+pub const TERMINATION_WRONG_NETWORK: u8 = 21;
+
+/// Most NACK/ACK ranges we put in an ACK block
+const MAX_ACK_RANGES: usize = 32;
+
+/// An ACK block: `through` and the `count` packets before it, then going
+/// down from there, alternating runs of packets not received and received
+#[derive(Debug, PartialEq)]
+pub struct Ack {
+    pub through: u32,
+    pub count: u8,
+    pub ranges: Vec<(u8, u8)>,
+}
+
+impl Ack {
+    /// Acknowledges what we've received, or as much of it as fits
+    pub fn new(received: &BTreeSet<u32>) -> Option<Ack> {
+        let mut packets = received.iter().rev().cloned();
+        let through = packets.next()?;
+        let mut ack = Ack {
+            through,
+            count: 0,
+            ranges: Vec::new(),
+        };
+        let mut previous = through;
+        for packet in packets {
+            let gap = previous - packet - 1;
+            if ack.ranges.is_empty() && gap == 0 && ack.count < u8::MAX {
+                ack.count += 1;
+            } else if gap == 0 && !ack.ranges.is_empty() &&
+                      ack.ranges.last().unwrap().1 < u8::MAX {
+                ack.ranges.last_mut().unwrap().1 += 1;
+            } else if gap <= u8::MAX as u32 && ack.ranges.len() < MAX_ACK_RANGES {
+                ack.ranges.push((gap as u8, 1));
+            } else {
+                break;
+            }
+            previous = packet;
+        }
+
+        Some(ack)
+    }
+
+    /// The packet numbers it acknowledges
+    pub fn acked(&self) -> Vec<u32> {
+        let mut acked: Vec<u32> = Vec::new();
+        let mut packet = self.through as i64;
+        for _ in 0..self.count as u32 + 1 {
+            if packet < 0 {
+                break;
+            }
+            acked.push(packet as u32);
+            packet -= 1;
+        }
+        for &(nacks, acks) in &self.ranges {
+            packet -= nacks as i64;
+            for _ in 0..acks {
+                if packet < 0 {
+                    break;
+                }
+                acked.push(packet as u32);
+                packet -= 1;
+            }
+        }
+
+        acked
+    }
+}
+
+// Blocks are handled as soon as a packet is parsed, so messages stay inline
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Block {
+    DateTime(Date),
+    Options(Vec<u8>),
+    /// A serialized RouterInfo, kept as it came
+    RouterInfo { flood: bool, data: Vec<u8> },
+    I2NP(I2NPMessage),
+    /// The start of a message that didn't fit in a packet: its short header
+    /// and as much of the body as fitted
+    FirstFragment(Vec<u8>),
+    /// The rest of a fragmented message, in order from fragment 1
+    FollowOnFragment {
+        fragment: u8,
+        last: bool,
+        message_id: u32,
+        data: Vec<u8>,
+    },
+    Termination { packets_received: u64, reason: u8 },
+    /// Introductions, for firewalled routers. We don't act as an introducer
+    /// or use one yet, so past the flags these are kept as they came.
+    RelayRequest { flags: u8, data: Vec<u8> },
+    RelayResponse { flags: u8, code: u8, data: Vec<u8> },
+    RelayIntro {
+        flags: u8,
+        router_hash: Hash,
+        data: Vec<u8>,
+    },
+    /// Reachability tests, which we don't take part in yet either
+    PeerTest {
+        message: u8,
+        code: u8,
+        flags: u8,
+        data: Vec<u8>,
+    },
+    Ack(Ack),
+    /// The address the packet was seen to come from
+    Address(SocketAddr),
+    RelayTagRequest,
+    RelayTag(u32),
+    /// A token to use in the next SessionRequest instead of asking for one
+    NewToken { expiration: Date, token: u64 },
+    PathChallenge(Vec<u8>),
+    PathResponse(Vec<u8>),
+    Padding(usize),
+}
+
+impl Block {
+    /// Everything but ACKs and padding has to be acknowledged
+    pub fn is_ack_eliciting(&self) -> bool {
+        !matches!(*self, Block::Ack(_) | Block::Padding(_))
+    }
+
+    fn write(&self, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        let mut data: Vec<u8> = Vec::new();
+        let block_type = match *self {
+            Block::DateTime(date) => {
+                data.write_u32::<BigEndian>(date.seconds())?;
+                DATE_TIME_BLOCK
+            }
+            Block::Options(ref options) => {
+                data.extend_from_slice(options);
+                OPTIONS_BLOCK
+            }
+            Block::RouterInfo { flood, data: ref router_info } => {
+                data.write_u8(if flood { ROUTER_INFO_FLOOD } else { 0 })?;
+                data.write_u8(ROUTER_INFO_UNFRAGMENTED)?;
+                data.extend_from_slice(router_info);
+                ROUTER_INFO_BLOCK
+            }
+            Block::I2NP(ref message) => {
+                message.serialize_short(&mut data)?;
+                I2NP_BLOCK
+            }
+            Block::FirstFragment(ref fragment) => {
+                data.extend_from_slice(fragment);
+                FIRST_FRAGMENT_BLOCK
+            }
+            Block::FollowOnFragment { fragment, last, message_id, data: ref fragment_data } => {
+                data.write_u8(fragment << 1 | if last { 1 } else { 0 })?;
+                data.write_u32::<BigEndian>(message_id)?;
+                data.extend_from_slice(fragment_data);
+                FOLLOW_ON_FRAGMENT_BLOCK
+            }
+            Block::Termination { packets_received, reason } => {
+                data.write_u64::<BigEndian>(packets_received)?;
+                data.write_u8(reason)?;
+                TERMINATION_BLOCK
+            }
+            Block::RelayRequest { flags, data: ref request } => {
+                data.write_u8(flags)?;
+                data.extend_from_slice(request);
+                RELAY_REQUEST_BLOCK
+            }
+            Block::RelayResponse { flags, code, data: ref response } => {
+                data.write_u8(flags)?;
+                data.write_u8(code)?;
+                data.extend_from_slice(response);
+                RELAY_RESPONSE_BLOCK
+            }
+            Block::RelayIntro { flags, ref router_hash, data: ref intro } => {
+                data.write_u8(flags)?;
+                write_hash(&mut data, router_hash)?;
+                data.extend_from_slice(intro);
+                RELAY_INTRO_BLOCK
+            }
+            Block::PeerTest { message, code, flags, data: ref test } => {
+                data.write_u8(message)?;
+                data.write_u8(code)?;
+                data.write_u8(flags)?;
+                data.extend_from_slice(test);
+                PEER_TEST_BLOCK
+            }
+            Block::Ack(ref ack) => {
+                data.write_u32::<BigEndian>(ack.through)?;
+                data.write_u8(ack.count)?;
+                for &(nacks, acks) in &ack.ranges {
+                    data.write_u8(nacks)?;
+                    data.write_u8(acks)?;
+                }
+                ACK_BLOCK
+            }
+            Block::Address(address) => {
+                data.write_u16::<BigEndian>(address.port())?;
+                match address.ip() {
+                    IpAddr::V4(ip) => data.write_all(&ip.octets())?,
+                    IpAddr::V6(ip) => data.write_all(&ip.octets())?,
+                }
+                ADDRESS_BLOCK
+            }
+            Block::RelayTagRequest => RELAY_TAG_REQUEST_BLOCK,
+            Block::RelayTag(tag) => {
+                data.write_u32::<BigEndian>(tag)?;
+                RELAY_TAG_BLOCK
+            }
+            Block::NewToken { expiration, token } => {
+                data.write_u32::<BigEndian>(expiration.seconds())?;
+                data.write_u64::<BigEndian>(token)?;
+                NEW_TOKEN_BLOCK
+            }
+            Block::PathChallenge(ref challenge) => {
+                data.extend_from_slice(challenge);
+                PATH_CHALLENGE_BLOCK
+            }
+            Block::PathResponse(ref response) => {
+                data.extend_from_slice(response);
+                PATH_RESPONSE_BLOCK
+            }
+            Block::Padding(length) => {
+                data.resize(length, 0);
+                thread_rng().fill_bytes(&mut data);
+                PADDING_BLOCK
+            }
+        };
+        if data.len() > u16::MAX as usize {
+            return Err(Error::Serialization(format!("SSU2 block too long: {}", data.len())));
+        }
+        buffer.write_u8(block_type)?;
+        buffer.write_u16::<BigEndian>(data.len() as u16)?;
+        buffer.extend(data);
+
+        Ok(())
+    }
+}
+
+pub fn write_blocks(blocks: &[Block]) -> Result<Vec<u8>, Error> {
+    let mut buffer: Vec<u8> = Vec::new();
+    for block in blocks {
+        block.write(&mut buffer)?;
+    }
+
+    Ok(buffer)
+}
+
+fn read_address(mut data: &[u8]) -> Result<SocketAddr, Error> {
+    let port = data.read_u16::<BigEndian>()?;
+    let ip = match data.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(data);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        length => {
+            return Err(Error::Serialization(format!("Bad SSU2 address length {}", length)));
+        }
+    };
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Parses a payload's blocks. Unknown blocks are skipped, but padding has
+/// to come last.
+pub fn parse_blocks(data: &[u8]) -> Result<Vec<Block>, Error> {
+    let mut reader = data;
+    let mut blocks: Vec<Block> = Vec::new();
+    while !reader.is_empty() {
+        let block_type = reader.read_u8()?;
+        let size = reader.read_u16::<BigEndian>()? as usize;
+        let mut contents = read_bytes(&mut reader, size)?;
+        let block = match block_type {
+            DATE_TIME_BLOCK => {
+                Block::DateTime(Date::from_seconds(contents.read_u32::<BigEndian>()?))
+            }
+            OPTIONS_BLOCK => Block::Options(contents.to_vec()),
+            ROUTER_INFO_BLOCK => {
+                let flags = contents.read_u8()?;
+                if contents.read_u8()? != ROUTER_INFO_UNFRAGMENTED ||
+                   flags & ROUTER_INFO_GZIP != 0 {
+                    return Err(Error::Transport("Fragmented and compressed SSU2 \
+                                                         RouterInfos aren't supported".to_string()));
+                }
+                Block::RouterInfo {
+                    flood: flags & ROUTER_INFO_FLOOD != 0,
+                    data: contents.to_vec(),
+                }
+            }
+            I2NP_BLOCK => Block::I2NP(I2NPMessage::deserialize_short(contents)?),
+            FIRST_FRAGMENT_BLOCK => Block::FirstFragment(contents.to_vec()),
+            FOLLOW_ON_FRAGMENT_BLOCK => {
+                let fragment = contents.read_u8()?;
+                Block::FollowOnFragment {
+                    fragment: fragment >> 1,
+                    last: fragment & 0x01 != 0,
+                    message_id: contents.read_u32::<BigEndian>()?,
+                    data: contents.to_vec(),
+                }
+            }
+            TERMINATION_BLOCK => {
+                let packets_received = contents.read_u64::<BigEndian>()?;
+                Block::Termination {
+                    packets_received,
+                    reason: contents.read_u8()?,
+                }
+            }
+            RELAY_REQUEST_BLOCK => {
+                let flags = contents.read_u8()?;
+                Block::RelayRequest {
+                    flags,
+                    data: contents.to_vec(),
+                }
+            }
+            RELAY_RESPONSE_BLOCK => {
+                let flags = contents.read_u8()?;
+                let code = contents.read_u8()?;
+                Block::RelayResponse {
+                    flags,
+                    code,
+                    data: contents.to_vec(),
+                }
+            }
+            RELAY_INTRO_BLOCK => {
+                let flags = contents.read_u8()?;
+                let router_hash = read_hash(&mut contents)?;
+                Block::RelayIntro {
+                    flags,
+                    router_hash,
+                    data: contents.to_vec(),
+                }
+            }
+            PEER_TEST_BLOCK => {
+                let message = contents.read_u8()?;
+                let code = contents.read_u8()?;
+                let flags = contents.read_u8()?;
+                Block::PeerTest {
+                    message,
+                    code,
+                    flags,
+                    data: contents.to_vec(),
+                }
+            }
+            ACK_BLOCK => {
+                let through = contents.read_u32::<BigEndian>()?;
+                let count = contents.read_u8()?;
+                if contents.len() % 2 != 0 {
+                    return Err(Error::Serialization(format!("Bad SSU2 ACK block length {}",
+                                                            size)));
+                }
+                Block::Ack(Ack {
+                    through,
+                    count,
+                    ranges: contents.chunks(2).map(|range| (range[0], range[1])).collect(),
+                })
+            }
+            ADDRESS_BLOCK => Block::Address(read_address(contents)?),
+            RELAY_TAG_REQUEST_BLOCK => Block::RelayTagRequest,
+            RELAY_TAG_BLOCK => Block::RelayTag(contents.read_u32::<BigEndian>()?),
+            NEW_TOKEN_BLOCK => {
+                let expiration = Date::from_seconds(contents.read_u32::<BigEndian>()?);
+                Block::NewToken {
+                    expiration,
+                    token: contents.read_u64::<BigEndian>()?,
+                }
+            }
+            PATH_CHALLENGE_BLOCK => Block::PathChallenge(contents.to_vec()),
+            PATH_RESPONSE_BLOCK => Block::PathResponse(contents.to_vec()),
+            PADDING_BLOCK => {
+                if !reader.is_empty() {
+                    return Err(Error::Serialization("SSU2 padding isn't the last \
+                                                             block".to_string()));
+                }
+                Block::Padding(size)
+            }
+            _ => {
+                debug!("SSU2: skipping unknown block type {}", block_type);
+                continue;
+            }
+        };
+        blocks.push(block);
+    }
+
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::i2np::MessageBody;
+    use super::*;
+
+    #[test]
+    fn test_blocks() {
+        let blocks = vec![Block::DateTime(Date::from_seconds(1490000000)),
+                          Block::I2NP(I2NPMessage {
+                              message_id: 1234,
+                              expiration: Date::from_seconds(1490000060),
+                              body: MessageBody::Data(b"data".to_vec()),
+                          }),
+                          Block::FollowOnFragment {
+                              fragment: 3,
+                              last: true,
+                              message_id: 1234,
+                              data: vec![1, 2, 3],
+                          },
+                          Block::Address("127.0.0.1:9000".parse().unwrap()),
+                          Block::NewToken {
+                              expiration: Date::from_seconds(1490003600),
+                              token: 42,
+                          },
+                          Block::Ack(Ack {
+                              through: 10,
+                              count: 2,
+                              ranges: vec![(1, 3)],
+                          }),
+                          Block::Padding(5)];
+        let data = write_blocks(&blocks).unwrap();
+        // Seven headers, then 4 + 17 + 8 + 6 + 12 + 7 + 5
+        assert_eq!(80, data.len());
+
+        let parsed = parse_blocks(&data).unwrap();
+        assert_eq!(7, parsed.len());
+        match parsed[2] {
+            Block::FollowOnFragment { fragment: 3, last: true, message_id: 1234, ref data } => {
+                assert_eq!(vec![1, 2, 3], *data)
+            }
+            ref block => panic!("Unexpected block {:?}", block),
+        }
+        match parsed[3] {
+            Block::Address(address) => assert_eq!("127.0.0.1:9000", address.to_string()),
+            ref block => panic!("Unexpected block {:?}", block),
+        }
+        match parsed[5] {
+            Block::Ack(ref ack) => assert_eq!(vec![10, 9, 8, 6, 5, 4], ack.acked()),
+            ref block => panic!("Unexpected block {:?}", block),
+        }
+
+        // Padding has to be last
+        let mut data = write_blocks(&[Block::Padding(1)]).unwrap();
+        data.extend(write_blocks(&[Block::RelayTagRequest]).unwrap());
+        assert!(parse_blocks(&data).is_err());
+    }
+
+    #[test]
+    fn test_ack() {
+        assert_eq!(None, Ack::new(&BTreeSet::new()));
+
+        let received: BTreeSet<u32> = vec![0, 1, 2, 5, 6, 9, 10, 11, 12].into_iter().collect();
+        let ack = Ack::new(&received).unwrap();
+        assert_eq!(Ack {
+                       through: 12,
+                       count: 3,
+                       ranges: vec![(2, 2), (2, 3)],
+                   },
+                   ack);
+        let mut acked = ack.acked();
+        acked.sort();
+        assert_eq!(received.into_iter().collect::<Vec<u32>>(), acked);
+
+        // Runs longer than a byte carry on in another range
+        let received: BTreeSet<u32> = (0..300).collect();
+        let ack = Ack::new(&received).unwrap();
+        assert_eq!(255, ack.count);
+        assert_eq!(vec![(0, 44)], ack.ranges);
+        assert_eq!(300, ack.acked().len());
+    }
+}
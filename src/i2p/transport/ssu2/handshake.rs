@@ -0,0 +1,711 @@
+//! The SSU2 handshake: Noise XK, like NTCP2's, but over datagrams. Alice
+//! first asks for a token with TokenRequest, unless an earlier session gave
+//! her one, and Bob sends it in a Retry; SessionRequest has to carry a valid
+//! token, which keeps Bob from doing Diffie-Hellman for spoofed addresses.
+//! SessionCreated and SessionConfirmed follow, the last with Alice's static
+//! key and RouterInfo. Packet loss is left to the caller, which resends the
+//! last packet until it gets an answer.
+
+use i2p::crypto::{self, chacha20, curve25519};
+use i2p::crypto::noise::SymmetricState;
+use i2p::crypto::poly1305::TAG_LENGTH;
+use i2p::data::crypto::Hash;
+use i2p::data::date::Date;
+use i2p::data::encoding::base64_decode;
+use i2p::data::router_info::{RouterAddress, RouterInfo};
+use i2p::error::Error;
+use i2p::transport::ssu2::block::{self, Block};
+use i2p::transport::ssu2::header::{self, LongHeader, ShortHeader, LONG_HEADER_LENGTH,
+                                   SHORT_HEADER_LENGTH};
+use rand::{thread_rng, OsRng, Rng};
+use std::net::SocketAddr;
+
+const PROTOCOL_NAME: &str = "Noise_XKchaobfse+hs1+hs2+hs3_25519_ChaChaPoly_SHA256";
+
+pub const INTRO_KEY_LENGTH: usize = 32;
+
+/// Where the ephemeral key after a long header ends
+const EPHEMERAL_KEY_END: usize = LONG_HEADER_LENGTH + curve25519::KEY_LENGTH;
+
+/// Alice's encrypted static key, in the first part of SessionConfirmed
+const STATIC_KEY_PART_END: usize = SHORT_HEADER_LENGTH + curve25519::KEY_LENGTH + TAG_LENGTH;
+
+/// SessionConfirmed in a single packet: fragment 0 of 1
+const UNFRAGMENTED: u8 = 0x01;
+
+/// Most padding we add to handshake packets
+const MAX_PADDING: usize = 32;
+
+/// How far apart, in seconds, the two routers' clocks can be
+const MAX_CLOCK_SKEW: u32 = 60;
+
+/// Our long-term SSU2 keys. The static key and the intro key, which masks
+/// the headers of packets sent to us, are both published in our RouterInfo.
+pub struct StaticKeys {
+    pub private_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub intro_key: Vec<u8>,
+}
+
+impl StaticKeys {
+    pub fn new(private_key: Vec<u8>, intro_key: Vec<u8>) -> Result<StaticKeys, Error> {
+        if private_key.len() != curve25519::KEY_LENGTH || intro_key.len() != INTRO_KEY_LENGTH {
+            return Err(Error::Crypto("Bad SSU2 static or intro key length".to_string()));
+        }
+
+        Ok(StaticKeys {
+            public_key: curve25519::x25519_public_key(&private_key)?,
+            private_key,
+            intro_key,
+        })
+    }
+
+    pub fn generate() -> Result<StaticKeys, Error> {
+        let mut rng = OsRng::new()?;
+        let mut private_key = vec![0u8; curve25519::KEY_LENGTH];
+        rng.fill_bytes(&mut private_key);
+        let mut intro_key = vec![0u8; INTRO_KEY_LENGTH];
+        rng.fill_bytes(&mut intro_key);
+
+        StaticKeys::new(private_key, intro_key)
+    }
+}
+
+/// The data phase keys for both directions
+pub struct SessionKeys {
+    pub send_key: Vec<u8>,
+    pub send_header_key: Vec<u8>,
+    pub receive_key: Vec<u8>,
+    pub receive_header_key: Vec<u8>,
+}
+
+pub fn is_ssu2(address: &RouterAddress) -> bool {
+    address.transport_style.style() == "SSU2"
+}
+
+/// The static key a router publishes in its SSU2 address, if it has one
+pub fn published_static_key(router_info: &RouterInfo) -> Option<Vec<u8>> {
+    router_info.addresses()
+        .iter()
+        .filter(|address| is_ssu2(address))
+        .filter_map(|address| address.options.get("s"))
+        .filter_map(|key| base64_decode(key).ok())
+        .next()
+}
+
+/// The intro key a router publishes in its SSU2 address, which the headers
+/// of the packets we send it are masked with
+pub fn published_intro_key(router_info: &RouterInfo) -> Result<Vec<u8>, Error> {
+    let key = router_info.addresses()
+        .iter()
+        .filter(|address| is_ssu2(address))
+        .filter_map(|address| address.options.get("i"))
+        .next();
+    match key {
+        Some(key) => base64_decode(key),
+        None => Err(Error::Transport("RouterInfo has no SSU2 intro key".to_string())),
+    }
+}
+
+fn ephemeral_keys() -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut private_key = vec![0u8; curve25519::KEY_LENGTH];
+    OsRng::new()?.fill_bytes(&mut private_key);
+    let public_key = curve25519::x25519_public_key(&private_key)?;
+
+    Ok((private_key, public_key))
+}
+
+fn random_connection_id() -> u64 {
+    thread_rng().gen()
+}
+
+/// A handshake packet's payload: the time, whatever else is given and
+/// some padding
+fn payload(mut blocks: Vec<Block>) -> Result<Vec<u8>, Error> {
+    blocks.insert(0, Block::DateTime(Date::now()));
+    blocks.push(Block::Padding(thread_rng().gen_range(0, MAX_PADDING + 1)));
+
+    block::write_blocks(&blocks)
+}
+
+fn check_clock_skew(blocks: &[Block]) -> Result<(), Error> {
+    for block in blocks {
+        if let Block::DateTime(date) = *block {
+            let now = Date::now().seconds();
+            let timestamp = date.seconds();
+            let skew = timestamp.abs_diff(now);
+            if skew > MAX_CLOCK_SKEW {
+                return Err(Error::Transport(format!("SSU2 peer's clock is {} seconds out",
+                                                    skew)));
+            }
+            return Ok(());
+        }
+    }
+
+    Err(Error::Transport("No DateTime in SSU2 handshake".to_string()))
+}
+
+/// Encrypts the long header, then masks the first 16 bytes. The header key
+/// doubles as the type's mask key.
+fn seal_long(mut packet: Vec<u8>,
+             intro_key: &[u8],
+             header_key: &[u8],
+             end: usize)
+             -> Result<Vec<u8>, Error> {
+    header::crypt_long_header(&mut packet, header_key, end)?;
+    header::mask_destination(&mut packet, intro_key)?;
+    header::mask_type(&mut packet, header_key)?;
+
+    Ok(packet)
+}
+
+/// Decrypts the rest of a long header whose first 16 bytes have been
+/// unmasked. SessionRequest and SessionCreated have the ephemeral key
+/// encrypted along with it.
+pub fn open_long_header(packet: &mut [u8], header_key: &[u8]) -> Result<LongHeader, Error> {
+    let end = match header::message_type(packet) {
+        header::SESSION_REQUEST |
+        header::SESSION_CREATED => EPHEMERAL_KEY_END,
+        _ => LONG_HEADER_LENGTH,
+    };
+    header::crypt_long_header(packet, header_key, end)?;
+
+    LongHeader::parse(packet)
+}
+
+/// TokenRequest and Retry are only encrypted with the intro key, with the
+/// header as associated data
+fn seal_with_intro_key(header: &LongHeader,
+                       intro_key: &[u8],
+                       blocks: Vec<Block>)
+                       -> Result<Vec<u8>, Error> {
+    let mut packet = header.to_bytes()?;
+    let nonce = chacha20::nonce(header.packet_number as u64);
+    let ciphertext = chacha20::aead_encrypt(intro_key, &nonce, &packet, &payload(blocks)?)?;
+    packet.extend(ciphertext);
+
+    seal_long(packet, intro_key, intro_key, LONG_HEADER_LENGTH)
+}
+
+fn open_with_intro_key(packet: &[u8],
+                       header: &LongHeader,
+                       intro_key: &[u8])
+                       -> Result<Vec<Block>, Error> {
+    let nonce = chacha20::nonce(header.packet_number as u64);
+    let payload = chacha20::aead_decrypt(intro_key,
+                                         &nonce,
+                                         &packet[..LONG_HEADER_LENGTH],
+                                         &packet[LONG_HEADER_LENGTH..])?;
+
+    block::parse_blocks(&payload)
+}
+
+/// Reads a TokenRequest whose first 16 bytes have been unmasked with our
+/// intro key
+pub fn read_token_request(packet: &mut [u8], intro_key: &[u8]) -> Result<LongHeader, Error> {
+    let header = open_long_header(packet, intro_key)?;
+    open_with_intro_key(packet, &header, intro_key)?;
+
+    Ok(header)
+}
+
+/// Bob's answer to a TokenRequest, or to a SessionRequest without a valid
+/// token. With a reason, it turns Alice away instead.
+pub fn retry(request: &LongHeader,
+             intro_key: &[u8],
+             network_id: u8,
+             address: SocketAddr,
+             token: u64,
+             reason: Option<u8>)
+             -> Result<Vec<u8>, Error> {
+    let header = LongHeader {
+        destination: request.source,
+        packet_number: thread_rng().gen(),
+        message_type: header::RETRY,
+        network_id,
+        source: request.destination,
+        token,
+    };
+    let mut blocks = vec![Block::Address(address)];
+    if let Some(reason) = reason {
+        blocks.push(Block::Termination {
+            packets_received: 0,
+            reason,
+        });
+    }
+
+    seal_with_intro_key(&header, intro_key, blocks)
+}
+
+/// The data phase keys, from the final chaining key
+fn split(state: &SymmetricState, initiator: bool) -> Result<SessionKeys, Error> {
+    let keys = crypto::hkdf(state.chaining_key(), &[], &[], 64)?;
+    let alice_to_bob = crypto::hkdf(&keys[..32], &[], b"HKDFSSU2DataKeys", 64)?;
+    let bob_to_alice = crypto::hkdf(&keys[32..], &[], b"HKDFSSU2DataKeys", 64)?;
+    let (send, receive) = if initiator {
+        (alice_to_bob, bob_to_alice)
+    } else {
+        (bob_to_alice, alice_to_bob)
+    };
+
+    Ok(SessionKeys {
+        send_key: send[..32].to_vec(),
+        send_header_key: send[32..].to_vec(),
+        receive_key: receive[..32].to_vec(),
+        receive_header_key: receive[32..].to_vec(),
+    })
+}
+
+/// What Bob sent back to Alice
+pub enum Reply {
+    /// A token to start the handshake again with
+    Retry(u64),
+    /// Bob turned us away, for the given reason
+    Rejected(u8),
+    /// The handshake is done, once SessionConfirmed has been sent
+    Created {
+        session_confirmed: Vec<u8>,
+        keys: SessionKeys,
+    },
+}
+
+/// Alice's side of the handshake
+pub struct OutboundHandshake {
+    /// The connection ID Bob sends to
+    pub source: u64,
+    /// The connection ID we send to
+    pub destination: u64,
+    remote_static_key: Vec<u8>,
+    remote_intro_key: Vec<u8>,
+    network_id: u8,
+    state: SymmetricState,
+    ephemeral_private_key: Vec<u8>,
+    ephemeral_public_key: Vec<u8>,
+    /// Set once SessionRequest has been sent
+    created_header_key: Option<Vec<u8>>,
+}
+
+impl OutboundHandshake {
+    pub fn new(remote_static_key: &[u8],
+               remote_intro_key: &[u8],
+               network_id: u8)
+               -> Result<OutboundHandshake, Error> {
+        if remote_static_key.len() != curve25519::KEY_LENGTH ||
+           remote_intro_key.len() != INTRO_KEY_LENGTH {
+            return Err(Error::Transport("Bad SSU2 static or intro key length".to_string()));
+        }
+        let (ephemeral_private_key, ephemeral_public_key) = ephemeral_keys()?;
+
+        Ok(OutboundHandshake {
+            source: random_connection_id(),
+            destination: random_connection_id(),
+            remote_static_key: remote_static_key.to_vec(),
+            remote_intro_key: remote_intro_key.to_vec(),
+            network_id,
+            state: SymmetricState::new(PROTOCOL_NAME),
+            ephemeral_private_key,
+            ephemeral_public_key,
+            created_header_key: None,
+        })
+    }
+
+    fn long_header(&self, message_type: u8, packet_number: u32, token: u64) -> LongHeader {
+        LongHeader {
+            destination: self.destination,
+            packet_number,
+            message_type,
+            network_id: self.network_id,
+            source: self.source,
+            token,
+        }
+    }
+
+    pub fn token_request(&self) -> Result<Vec<u8>, Error> {
+        let header = self.long_header(header::TOKEN_REQUEST, thread_rng().gen(), 0);
+
+        seal_with_intro_key(&header, &self.remote_intro_key, Vec::new())
+    }
+
+    /// Starts the handshake proper, from scratch if there's been a Retry
+    pub fn session_request(&mut self, token: u64) -> Result<Vec<u8>, Error> {
+        let header = self.long_header(header::SESSION_REQUEST, 0, token).to_bytes()?;
+        self.state = SymmetricState::new(PROTOCOL_NAME);
+        self.state.mix_hash(&self.remote_static_key);
+        self.state.mix_hash(&header);
+        self.state.mix_hash(&self.ephemeral_public_key);
+        let key = self.state.mix_key(&curve25519::x25519(&self.ephemeral_private_key,
+                                                         &self.remote_static_key)?)?;
+
+        let mut packet = header;
+        packet.extend_from_slice(&self.ephemeral_public_key);
+        packet.extend(self.state.encrypt_and_hash(&key, 0, &payload(Vec::new())?)?);
+        let created_header_key =
+            crypto::hkdf(self.state.chaining_key(), &[], b"SessCreateHeader", 32)?;
+        let packet = seal_long(packet,
+                               &self.remote_intro_key,
+                               &self.remote_intro_key,
+                               EPHEMERAL_KEY_END)?;
+        self.created_header_key = Some(created_header_key);
+
+        Ok(packet)
+    }
+
+    /// Handles a packet from Bob, which has to be a Retry or, once we've
+    /// sent SessionRequest, SessionCreated. A Retry can look like
+    /// SessionCreated, so it's only taken for one if it decrypts.
+    pub fn receive(&mut self,
+                   packet: &mut [u8],
+                   keys: &StaticKeys,
+                   router_info: &[u8])
+                   -> Result<Reply, Error> {
+        header::mask_destination(packet, &self.remote_intro_key)?;
+        if ShortHeader::parse(packet)?.destination != self.source {
+            return Err(Error::Transport("SSU2 packet isn't for this handshake".to_string()));
+        }
+
+        let mut created_error = None;
+        if let Some(created_header_key) = self.created_header_key.clone() {
+            let mut copy = packet.to_vec();
+            header::mask_type(&mut copy, &created_header_key)?;
+            if header::message_type(&copy) == header::SESSION_CREATED {
+                match self.session_created(&mut copy, &created_header_key, keys, router_info) {
+                    Ok(reply) => return Ok(reply),
+                    Err(error) => created_error = Some(error),
+                }
+            }
+        }
+
+        header::mask_type(packet, &self.remote_intro_key)?;
+        if header::message_type(packet) != header::RETRY {
+            return Err(created_error.unwrap_or_else(|| {
+                Error::Transport("Unexpected SSU2 handshake packet".to_string())
+            }));
+        }
+        let header = open_long_header(packet, &self.remote_intro_key)?;
+        for block in open_with_intro_key(packet, &header, &self.remote_intro_key)? {
+            if let Block::Termination { reason, .. } = block {
+                return Ok(Reply::Rejected(reason));
+            }
+        }
+
+        Ok(Reply::Retry(header.token))
+    }
+
+    fn session_created(&mut self,
+                       packet: &mut [u8],
+                       created_header_key: &[u8],
+                       keys: &StaticKeys,
+                       router_info: &[u8])
+                       -> Result<Reply, Error> {
+        open_long_header(packet, created_header_key)?;
+        // Only take the packet on once it's authenticated
+        let mut state = self.state.clone();
+        state.mix_hash(&packet[..LONG_HEADER_LENGTH]);
+        let remote_ephemeral_key = &packet[LONG_HEADER_LENGTH..EPHEMERAL_KEY_END];
+        state.mix_hash(remote_ephemeral_key);
+        let key = state.mix_key(&curve25519::x25519(&self.ephemeral_private_key,
+                                                    remote_ephemeral_key)?)?;
+        let payload = state.decrypt_and_hash(&key, 0, &packet[EPHEMERAL_KEY_END..])?;
+        check_clock_skew(&block::parse_blocks(&payload)?)?;
+        let confirmed_header_key =
+            crypto::hkdf(state.chaining_key(), &[], b"SessionConfirmed", 32)?;
+
+        let header = ShortHeader {
+            destination: self.destination,
+            packet_number: 0,
+            message_type: header::SESSION_CONFIRMED,
+            flags: UNFRAGMENTED,
+        };
+        let mut session_confirmed = header.to_bytes()?;
+        state.mix_hash(&session_confirmed);
+        session_confirmed.extend(state.encrypt_and_hash(&key, 1, &keys.public_key)?);
+        let key = state.mix_key(&curve25519::x25519(&keys.private_key, remote_ephemeral_key)?)?;
+        let payload = block::write_blocks(&[Block::RouterInfo {
+                                                flood: false,
+                                                data: router_info.to_vec(),
+                                            }])?;
+        session_confirmed.extend(state.encrypt_and_hash(&key, 0, &payload)?);
+        header::mask_destination(&mut session_confirmed, &self.remote_intro_key)?;
+        header::mask_type(&mut session_confirmed, &confirmed_header_key)?;
+
+        Ok(Reply::Created {
+            session_confirmed,
+            keys: split(&state, true)?,
+        })
+    }
+}
+
+/// Bob's side of the handshake, from SessionRequest on
+pub struct InboundHandshake {
+    /// The connection ID Alice sends to
+    pub source: u64,
+    /// The connection ID we send to
+    pub destination: u64,
+    /// Kept to be sent again if SessionRequest is
+    pub session_created: Vec<u8>,
+    state: SymmetricState,
+    ephemeral_private_key: Vec<u8>,
+    key: Vec<u8>,
+    confirmed_header_key: Vec<u8>,
+}
+
+impl InboundHandshake {
+    /// Handles a SessionRequest whose header has been opened with
+    /// `open_long_header`, and whose token has been checked
+    pub fn accept(packet: &[u8],
+                  header: &LongHeader,
+                  keys: &StaticKeys,
+                  address: SocketAddr,
+                  network_id: u8)
+                  -> Result<InboundHandshake, Error> {
+        if packet.len() < EPHEMERAL_KEY_END {
+            return Err(Error::Transport("SSU2 SessionRequest too short".to_string()));
+        }
+        let mut state = SymmetricState::new(PROTOCOL_NAME);
+        state.mix_hash(&keys.public_key);
+        state.mix_hash(&packet[..LONG_HEADER_LENGTH]);
+        let remote_ephemeral_key = &packet[LONG_HEADER_LENGTH..EPHEMERAL_KEY_END];
+        state.mix_hash(remote_ephemeral_key);
+        let key = state.mix_key(&curve25519::x25519(&keys.private_key, remote_ephemeral_key)?)?;
+        let request = state.decrypt_and_hash(&key, 0, &packet[EPHEMERAL_KEY_END..])?;
+        check_clock_skew(&block::parse_blocks(&request)?)?;
+        let created_header_key = crypto::hkdf(state.chaining_key(), &[], b"SessCreateHeader", 32)?;
+
+        // SessionCreated
+        let (ephemeral_private_key, ephemeral_public_key) = ephemeral_keys()?;
+        let mut session_created = LongHeader {
+                destination: header.source,
+                packet_number: 0,
+                message_type: header::SESSION_CREATED,
+                network_id,
+                source: header.destination,
+                token: 0,
+            }
+            .to_bytes()?;
+        state.mix_hash(&session_created);
+        state.mix_hash(&ephemeral_public_key);
+        let key = state.mix_key(&curve25519::x25519(&ephemeral_private_key,
+                                                    remote_ephemeral_key)?)?;
+        session_created.extend_from_slice(&ephemeral_public_key);
+        session_created.extend(state.encrypt_and_hash(&key,
+                                                      0,
+                                                      &payload(vec![Block::Address(address)])?)?);
+        let confirmed_header_key =
+            crypto::hkdf(state.chaining_key(), &[], b"SessionConfirmed", 32)?;
+
+        Ok(InboundHandshake {
+            source: header.destination,
+            destination: header.source,
+            session_created: seal_long(session_created,
+                                       &keys.intro_key,
+                                       &created_header_key,
+                                       EPHEMERAL_KEY_END)?,
+            state,
+            ephemeral_private_key,
+            key,
+            confirmed_header_key,
+        })
+    }
+
+    /// Handles SessionConfirmed, whose destination has been unmasked. Alice's
+    /// RouterInfo comes back checked: it's signed, on our network, not ours
+    /// and publishes the static key she used.
+    pub fn confirm(&self,
+                   packet: &mut [u8],
+                   our_hash: &Hash,
+                   network_id: u8)
+                   -> Result<(SessionKeys, RouterInfo), Error> {
+        header::mask_type(packet, &self.confirmed_header_key)?;
+        let header = ShortHeader::parse(packet)?;
+        if header.message_type != header::SESSION_CONFIRMED {
+            return Err(Error::Transport(format!("Expected SSU2 SessionConfirmed, got type {}",
+                                                header.message_type)));
+        }
+        if header.flags != UNFRAGMENTED || packet.len() < STATIC_KEY_PART_END {
+            return Err(Error::Transport("Fragmented SSU2 SessionConfirmed isn't \
+                                                 supported".to_string()));
+        }
+
+        let mut state = self.state.clone();
+        state.mix_hash(&packet[..SHORT_HEADER_LENGTH]);
+        let remote_static_key = state.decrypt_and_hash(&self.key,
+                                 1,
+                                 &packet[SHORT_HEADER_LENGTH..STATIC_KEY_PART_END])?;
+        let key = state.mix_key(&curve25519::x25519(&self.ephemeral_private_key,
+                                                    &remote_static_key)?)?;
+        let payload = state.decrypt_and_hash(&key, 0, &packet[STATIC_KEY_PART_END..])?;
+
+        let mut router_info = None;
+        for block in block::parse_blocks(&payload)? {
+            if let Block::RouterInfo { ref data, .. } = block {
+                router_info = Some(RouterInfo::deserialize(&mut data.as_slice())?);
+            }
+        }
+        let router_info = match router_info {
+            Some(router_info) => router_info,
+            None => return Err(Error::Transport("No RouterInfo in SSU2 SessionConfirmed".to_string())),
+        };
+        check_router_info(&router_info, &remote_static_key, our_hash, network_id)?;
+
+        Ok((split(&state, false)?, router_info))
+    }
+}
+
+fn check_router_info(router_info: &RouterInfo,
+                     static_key: &[u8],
+                     our_hash: &Hash,
+                     network_id: u8)
+                     -> Result<(), Error> {
+    if !router_info.verify()? {
+        return Err(Error::Transport("Bad signature on SSU2 peer's RouterInfo".to_string()));
+    }
+    if router_info.hash()? == *our_hash {
+        return Err(Error::Transport("SSU2 connection from ourselves".to_string()));
+    }
+    if router_info.options().get("netId").is_none_or(|id| *id != network_id.to_string()) {
+        return Err(Error::Transport("SSU2 peer's RouterInfo is for another network".to_string()));
+    }
+    if published_static_key(router_info).is_none_or(|key| key != static_key) {
+        return Err(Error::Transport("SSU2 peer's static key isn't the one it \
+                                             publishes".to_string()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::{PublicKeyType, SigningPublicKeyType};
+    use i2p::data::encoding::base64_encode;
+    use i2p::data::mapping::Mapping;
+    use i2p::data::private_keys::PrivateKeys;
+    use i2p::data::router_info::SupportedTransports;
+    use super::*;
+
+    /// A serialized RouterInfo publishing an SSU2 address with `static_key`
+    fn router_info(static_key: &[u8], network_id: u8) -> Vec<u8> {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let mut address_options = Mapping::new();
+        address_options.insert("s", &base64_encode(static_key));
+        address_options.insert("v", "2");
+        let address = RouterAddress {
+            cost: 8,
+            expiration: None,
+            transport_style: SupportedTransports::SSU2V4,
+            options: address_options,
+        };
+        let mut options = Mapping::new();
+        options.insert("netId", &network_id.to_string());
+        let mut data: Vec<u8> = Vec::new();
+        RouterInfo::new(&keys, Date::now(), vec![address], options)
+            .unwrap()
+            .serialize(&mut data)
+            .unwrap();
+
+        data
+    }
+
+    /// Unmasks a packet to Bob the way the transport does for a connection
+    /// it doesn't know
+    fn unmask(packet: &mut [u8], keys: &StaticKeys) -> u8 {
+        header::mask_destination(packet, &keys.intro_key).unwrap();
+        header::mask_type(packet, &keys.intro_key).unwrap();
+        header::message_type(packet)
+    }
+
+    /// Runs the handshake up to SessionCreated, returning SessionConfirmed
+    /// unmasked for Bob, and both sides' keys
+    fn handshake(alice_router_info: &[u8],
+                 alice_keys: &StaticKeys,
+                 bob_keys: &StaticKeys)
+                 -> (Vec<u8>, SessionKeys, InboundHandshake) {
+        let address = "127.0.0.1:9000".parse().unwrap();
+        let mut alice = OutboundHandshake::new(&bob_keys.public_key, &bob_keys.intro_key, 2)
+            .unwrap();
+
+        let mut packet = alice.token_request().unwrap();
+        assert_eq!(header::TOKEN_REQUEST, unmask(&mut packet, bob_keys));
+        let request = read_token_request(&mut packet, &bob_keys.intro_key).unwrap();
+        assert_eq!(alice.source, request.source);
+        let mut packet = retry(&request, &bob_keys.intro_key, 2, address, 1234, None).unwrap();
+        let token = match alice.receive(&mut packet, alice_keys, alice_router_info).unwrap() {
+            Reply::Retry(token) => token,
+            _ => panic!("Expected a Retry"),
+        };
+        assert_eq!(1234, token);
+
+        let mut packet = alice.session_request(token).unwrap();
+        assert_eq!(header::SESSION_REQUEST, unmask(&mut packet, bob_keys));
+        let header = open_long_header(&mut packet, &bob_keys.intro_key).unwrap();
+        assert_eq!(1234, header.token);
+        let bob = InboundHandshake::accept(&packet, &header, bob_keys, address, 2).unwrap();
+
+        let mut packet = bob.session_created.clone();
+        match alice.receive(&mut packet, alice_keys, alice_router_info).unwrap() {
+            Reply::Created { mut session_confirmed, keys } => {
+                header::mask_destination(&mut session_confirmed, &bob_keys.intro_key).unwrap();
+                assert_eq!(bob.source, ShortHeader::parse(&session_confirmed).unwrap().destination);
+                (session_confirmed, keys, bob)
+            }
+            _ => panic!("Expected SessionCreated"),
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let alice_keys = StaticKeys::generate().unwrap();
+        let bob_keys = StaticKeys::generate().unwrap();
+        let bob_hash = Hash::SHA256(vec![0; 32].into_boxed_slice());
+        let alice_router_info = router_info(&alice_keys.public_key, 2);
+
+        let (mut session_confirmed, alice, bob) =
+            handshake(&alice_router_info, &alice_keys, &bob_keys);
+        let (bob, router_info) = bob.confirm(&mut session_confirmed, &bob_hash, 2).unwrap();
+        assert_eq!(Some(alice_keys.public_key.clone()),
+                   published_static_key(&router_info));
+        assert_eq!(alice.send_key, bob.receive_key);
+        assert_eq!(alice.send_header_key, bob.receive_header_key);
+        assert_eq!(alice.receive_key, bob.send_key);
+        assert_eq!(alice.receive_header_key, bob.send_header_key);
+        assert!(alice.send_key != alice.receive_key);
+    }
+
+    #[test]
+    fn test_rejected() {
+        let alice_keys = StaticKeys::generate().unwrap();
+        let bob_keys = StaticKeys::generate().unwrap();
+        let bob_hash = Hash::SHA256(vec![0; 32].into_boxed_slice());
+
+        // Alice's RouterInfo has to publish the key she used
+        let other_keys = StaticKeys::generate().unwrap();
+        let (mut session_confirmed, _, bob) =
+            handshake(&router_info(&other_keys.public_key, 2), &alice_keys, &bob_keys);
+        assert!(bob.confirm(&mut session_confirmed, &bob_hash, 2).is_err());
+
+        // And be on our network
+        let (mut session_confirmed, _, bob) =
+            handshake(&router_info(&alice_keys.public_key, 3), &alice_keys, &bob_keys);
+        assert!(bob.confirm(&mut session_confirmed, &bob_hash, 2).is_err());
+
+        // A Retry with a Termination block turns Alice away
+        let mut alice = OutboundHandshake::new(&bob_keys.public_key, &bob_keys.intro_key, 3)
+            .unwrap();
+        let mut packet = alice.token_request().unwrap();
+        unmask(&mut packet, &bob_keys);
+        let request = read_token_request(&mut packet, &bob_keys.intro_key).unwrap();
+        assert_eq!(3, request.network_id);
+        let mut packet = retry(&request,
+                               &bob_keys.intro_key,
+                               2,
+                               "127.0.0.1:9000".parse().unwrap(),
+                               0,
+                               Some(block::TERMINATION_WRONG_NETWORK))
+            .unwrap();
+        match alice.receive(&mut packet, &alice_keys, &[]).unwrap() {
+            Reply::Rejected(reason) => assert_eq!(block::TERMINATION_WRONG_NETWORK, reason),
+            _ => panic!("Expected a rejection"),
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! SSU2 packet headers. Handshake packets start with a 32-byte long header
+//! and everything else with a 16-byte short one. The first 16 bytes of any
+//! packet are masked with ChaCha20 streams keyed on the receiver's intro key
+//! and a header key, using nonces from the end of the packet, and the rest
+//! of a long header is encrypted with the header key, so that nothing in a
+//! header is in the clear.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::crypto::chacha20;
+use i2p::error::Error;
+use std::io::Write;
+
+pub const SHORT_HEADER_LENGTH: usize = 16;
+pub const LONG_HEADER_LENGTH: usize = 32;
+
+/// What the masks' nonces are taken from: the last 24 bytes, which mustn't
+/// overlap the header they mask
+pub const MIN_PACKET_LENGTH: usize = SHORT_HEADER_LENGTH + 24;
+
+pub const SESSION_REQUEST: u8 = 0;
+pub const SESSION_CREATED: u8 = 1;
+pub const SESSION_CONFIRMED: u8 = 2;
+pub const DATA: u8 = 6;
+pub const RETRY: u8 = 9;
+pub const TOKEN_REQUEST: u8 = 10;
+
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// The header of a data phase packet, or of SessionConfirmed
+#[derive(Debug, PartialEq)]
+pub struct ShortHeader {
+    pub destination: u64,
+    pub packet_number: u32,
+    pub message_type: u8,
+    /// SessionConfirmed's fragment number and count; unused in data packets
+    pub flags: u8,
+}
+
+impl ShortHeader {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut data: Vec<u8> = Vec::with_capacity(SHORT_HEADER_LENGTH);
+        data.write_u64::<BigEndian>(self.destination)?;
+        data.write_u32::<BigEndian>(self.packet_number)?;
+        data.write_u8(self.message_type)?;
+        data.write_all(&[self.flags, 0, 0])?;
+
+        Ok(data)
+    }
+
+    pub fn parse(packet: &[u8]) -> Result<ShortHeader, Error> {
+        if packet.len() < SHORT_HEADER_LENGTH {
+            return Err(Error::Serialization(format!("SSU2 packet too short: {}", packet.len())));
+        }
+        let mut reader = packet;
+
+        Ok(ShortHeader {
+            destination: reader.read_u64::<BigEndian>()?,
+            packet_number: reader.read_u32::<BigEndian>()?,
+            message_type: reader.read_u8()?,
+            flags: reader.read_u8()?,
+        })
+    }
+}
+
+/// The header of TokenRequest, Retry, SessionRequest and SessionCreated
+#[derive(Debug, PartialEq)]
+pub struct LongHeader {
+    pub destination: u64,
+    pub packet_number: u32,
+    pub message_type: u8,
+    pub network_id: u8,
+    pub source: u64,
+    pub token: u64,
+}
+
+impl LongHeader {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut data: Vec<u8> = Vec::with_capacity(LONG_HEADER_LENGTH);
+        data.write_u64::<BigEndian>(self.destination)?;
+        data.write_u32::<BigEndian>(self.packet_number)?;
+        data.write_u8(self.message_type)?;
+        data.write_u8(PROTOCOL_VERSION)?;
+        data.write_u8(self.network_id)?;
+        data.write_u8(0)?;
+        data.write_u64::<BigEndian>(self.source)?;
+        data.write_u64::<BigEndian>(self.token)?;
+
+        Ok(data)
+    }
+
+    pub fn parse(packet: &[u8]) -> Result<LongHeader, Error> {
+        if packet.len() < LONG_HEADER_LENGTH {
+            return Err(Error::Serialization(format!("SSU2 packet too short for a long header: \
+                                                     {}",
+                                                    packet.len())));
+        }
+        let mut reader = packet;
+        let destination = reader.read_u64::<BigEndian>()?;
+        let packet_number = reader.read_u32::<BigEndian>()?;
+        let message_type = reader.read_u8()?;
+        let version = reader.read_u8()?;
+        if version != PROTOCOL_VERSION {
+            return Err(Error::Transport(format!("Unsupported SSU2 version {}", version)));
+        }
+        let network_id = reader.read_u8()?;
+        reader.read_u8()?;
+
+        Ok(LongHeader {
+            destination,
+            packet_number,
+            message_type,
+            network_id,
+            source: reader.read_u64::<BigEndian>()?,
+            token: reader.read_u64::<BigEndian>()?,
+        })
+    }
+}
+
+fn mask(packet: &mut [u8], start: usize, key: &[u8], nonce_start: usize) -> Result<(), Error> {
+    if packet.len() < MIN_PACKET_LENGTH {
+        return Err(Error::Serialization(format!("SSU2 packet too short: {}", packet.len())));
+    }
+    let nonce_start = packet.len() - nonce_start;
+    let mask = chacha20::chacha20(key,
+                                  &packet[nonce_start..nonce_start + chacha20::NONCE_LENGTH],
+                                  0,
+                                  &[0u8; 8])?;
+    for (byte, mask_byte) in packet[start..start + 8].iter_mut().zip(mask) {
+        *byte ^= mask_byte;
+    }
+
+    Ok(())
+}
+
+/// Masks or unmasks the destination connection ID, which is always keyed
+/// on the receiver's intro key so that it can find the session
+pub fn mask_destination(packet: &mut [u8], intro_key: &[u8]) -> Result<(), Error> {
+    mask(packet, 0, intro_key, 24)
+}
+
+/// Masks or unmasks the packet number, type and flags
+pub fn mask_type(packet: &mut [u8], header_key: &[u8]) -> Result<(), Error> {
+    mask(packet, 8, header_key, 12)
+}
+
+/// The type of a packet whose type has been unmasked
+pub fn message_type(packet: &[u8]) -> u8 {
+    packet[12]
+}
+
+/// Encrypts or decrypts the rest of a long header, and for SessionRequest
+/// and SessionCreated the ephemeral key after it, up to `end`
+pub fn crypt_long_header(packet: &mut [u8], header_key: &[u8], end: usize) -> Result<(), Error> {
+    if packet.len() < end {
+        return Err(Error::Serialization(format!("SSU2 packet too short: {}", packet.len())));
+    }
+    let encrypted = chacha20::chacha20(header_key,
+                                       &[0u8; chacha20::NONCE_LENGTH],
+                                       0,
+                                       &packet[SHORT_HEADER_LENGTH..end])?;
+    packet[SHORT_HEADER_LENGTH..end].copy_from_slice(&encrypted);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_masks() {
+        let header = LongHeader {
+            destination: 0x0102030405060708,
+            packet_number: 7,
+            message_type: SESSION_REQUEST,
+            network_id: 2,
+            source: 0x1112131415161718,
+            token: 0x2122232425262728,
+        };
+        let mut packet = header.to_bytes().unwrap();
+        packet.extend((0..40).collect::<Vec<u8>>());
+        let plain = packet.clone();
+
+        crypt_long_header(&mut packet, &[2; 32], 64).unwrap();
+        mask_destination(&mut packet, &[1; 32]).unwrap();
+        mask_type(&mut packet, &[2; 32]).unwrap();
+        assert!(packet[..64] != plain[..64]);
+        assert_eq!(plain[64..], packet[64..]);
+
+        // Each part can be unmasked on its own
+        mask_destination(&mut packet, &[1; 32]).unwrap();
+        assert_eq!(header.destination, ShortHeader::parse(&packet).unwrap().destination);
+        mask_type(&mut packet, &[2; 32]).unwrap();
+        assert_eq!(SESSION_REQUEST, message_type(&packet));
+        crypt_long_header(&mut packet, &[2; 32], 64).unwrap();
+        assert_eq!(plain, packet);
+        assert_eq!(header, LongHeader::parse(&packet).unwrap());
+
+        assert!(mask_destination(&mut [0; 39], &[1; 32]).is_err());
+    }
+}
@@ -0,0 +1,1037 @@
+//! SSU2, the UDP transport. Everything goes through one socket, read by one
+//! thread, which runs the handshakes, hands the I2NP messages it receives to
+//! the channel the transport was started with, and sends again whatever
+//! hasn't been acknowledged. Packets are matched to sessions by the
+//! destination connection ID in their header.
+//!
+//! We only talk to routers directly: relay and peer test blocks are parsed
+//! but not acted on, so we don't introduce firewalled routers, use
+//! introducers or test our own reachability yet.
+
+pub mod block;
+pub mod handshake;
+pub mod header;
+pub mod session;
+
+use i2p::config::Config;
+use i2p::data::crypto::Hash;
+use i2p::data::date::Date;
+use i2p::data::encoding::{base64_decode, base64_encode};
+use i2p::data::mapping::Mapping;
+use i2p::data::router_info::{RouterAddress, RouterInfo, SupportedTransports};
+use i2p::error::Error;
+use i2p::i2np::I2NPMessage;
+use i2p::router_context::RouterContext;
+use i2p::transport::bandwidth::{BandwidthLimiter, Direction, Priority};
+use i2p::transport::{ReceivedMessage, SessionLimit, Transport};
+use i2p::transport::ssu2::handshake::{InboundHandshake, OutboundHandshake, Reply, StaticKeys};
+use i2p::transport::ssu2::header::{ShortHeader, MIN_PACKET_LENGTH, SHORT_HEADER_LENGTH};
+use i2p::transport::ssu2::session::Session;
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STATIC_KEYS_FILE: &str = "ssu2.keys";
+
+const HOST_CONFIG: &str = "i2np.udp.host";
+const PORT_CONFIG: &str = "i2np.udp.port";
+
+/// Where a port is picked from when none is configured
+const MIN_RANDOM_PORT: u16 = 9111;
+const MAX_RANDOM_PORT: u16 = 30777;
+
+/// i2pd's cost for a direct SSU2 address, so that NTCP2 is preferred
+const ADDRESS_COST: u8 = 8;
+
+/// The largest packets we send: the minimum MTUs of 1500 for IPv4 and
+/// 1280 for IPv6, less the IP and UDP headers
+const MAX_PACKET_V4: usize = 1500 - 28;
+const MAX_PACKET_V6: usize = 1280 - 48;
+
+/// What a data packet has room for besides its header and MAC
+const PACKET_OVERHEAD: usize = SHORT_HEADER_LENGTH + 16;
+
+/// How often the socket thread stops waiting for packets to send things
+/// again and expire sessions
+const TICK_MILLIS: u64 = 100;
+
+/// How long we wait before sending a handshake packet again, and how many
+/// times we do
+const HANDSHAKE_RESEND_TIMEOUT: u64 = 1;
+const MAX_HANDSHAKE_RESENDS: u32 = 3;
+
+const HANDSHAKE_TIMEOUT: u64 = 15;
+
+/// Sessions we've heard nothing on for this long are closed
+const IDLE_TIMEOUT: u64 = 300;
+
+/// How long the tokens we give out are good for: a Retry's only has to last
+/// the handshake, but one sent in a session is for the next connection
+const RETRY_TOKEN_LIFETIME: u64 = 60;
+const NEW_TOKEN_LIFETIME: u64 = 3600;
+
+/// A handshake we started, with the callers waiting on it
+struct Outbound {
+    handshake: OutboundHandshake,
+    peer: Hash,
+    intro_key: Vec<u8>,
+    last_packet: Vec<u8>,
+    sent: Instant,
+    resends: u32,
+    waiters: Vec<Sender<Result<(), String>>>,
+}
+
+/// A handshake we're answering, waiting for SessionConfirmed
+struct Inbound {
+    handshake: InboundHandshake,
+    address: SocketAddr,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    /// Established sessions, by the connection ID the peer sends to
+    sessions: HashMap<u64, Session>,
+    peers: HashMap<Hash, u64>,
+    /// Our handshakes, by the address of the router we're connecting to
+    outbound: HashMap<SocketAddr, Outbound>,
+    /// Handshakes with us, by the connection ID Alice sends to
+    inbound: HashMap<u64, Inbound>,
+    /// Tokens we've given out, by the address they were given to
+    issued_tokens: HashMap<SocketAddr, (u64, Instant)>,
+    /// Tokens routers have given us, by their address
+    tokens: HashMap<SocketAddr, (u64, Date)>,
+}
+
+/// What the socket thread shares with the transport
+struct Shared {
+    keys: StaticKeys,
+    hash: Hash,
+    network_id: u8,
+    socket: UdpSocket,
+    max_payload: usize,
+    /// Our RouterInfo, serialized, as sent in SessionConfirmed
+    router_info: Mutex<Vec<u8>>,
+    state: Mutex<State>,
+    bandwidth: Arc<BandwidthLimiter>,
+    limit: Arc<SessionLimit>,
+    running: AtomicBool,
+}
+
+pub struct Ssu2 {
+    shared: Arc<Shared>,
+    local_address: SocketAddr,
+    /// The host we publish, if we're reachable
+    hostname: Option<String>,
+}
+
+/// Loads our static and intro keys, or generates and saves new ones
+fn load_static_keys(router_dir: &Path) -> Result<StaticKeys, Error> {
+    let path = router_dir.join(STATIC_KEYS_FILE);
+    if path.exists() {
+        info!("SSU2: loading static keys from {:?}", path);
+        let mut data: Vec<u8> = Vec::new();
+        if let Err(error) = File::open(&path).and_then(|mut file| file.read_to_end(&mut data)) {
+            return Err(Error::IO {
+                message: Some(format!("Error reading SSU2 keys file {:?}", path)),
+                error,
+            });
+        }
+        if data.len() != 64 {
+            return Err(Error::Crypto(format!("SSU2 keys file {:?} is {} bytes, not 64",
+                                             path,
+                                             data.len())));
+        }
+        let intro_key = data.split_off(32);
+        return StaticKeys::new(data, intro_key);
+    }
+
+    info!("SSU2: generating new static keys in {:?}", path);
+    let keys = StaticKeys::generate()?;
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&keys.private_key)?;
+        file.write_all(&keys.intro_key)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, &path)?;
+
+    Ok(keys)
+}
+
+/// The socket address, static key and intro key of a published SSU2
+/// address, if it's one we can connect to
+fn parse_address(address: &RouterAddress) -> Option<(SocketAddr, Vec<u8>, Vec<u8>)> {
+    let options = &address.options;
+    let (host, port, key, intro_key) = match (options.get("host"),
+                                              options.get("port"),
+                                              options.get("s"),
+                                              options.get("i")) {
+        (Some(host), Some(port), Some(key), Some(intro_key)) => (host, port, key, intro_key),
+        _ => return None,
+    };
+    match (host.parse::<IpAddr>(),
+           port.parse::<u16>(),
+           base64_decode(key),
+           base64_decode(intro_key)) {
+        (Ok(ip), Ok(port), Ok(key), Ok(intro_key)) => {
+            Some((SocketAddr::new(ip, port), key, intro_key))
+        }
+        _ => None,
+    }
+}
+
+fn send_to(shared: &Shared, packet: &[u8], address: SocketAddr) {
+    if let Err(error) = shared.socket.send_to(packet, address) {
+        debug!("SSU2: error sending to {}: {}", address, error);
+    }
+}
+
+impl Ssu2 {
+    /// Starts listening on the configured port, or a random one. We only
+    /// publish a host if `i2np.udp.host` is set, and listen on IPv6 if it's
+    /// an IPv6 address.
+    pub fn new(config: &Config,
+               context: &RouterContext,
+               network_id: u32,
+               messages: Sender<ReceivedMessage>,
+               bandwidth: Arc<BandwidthLimiter>,
+               limit: Arc<SessionLimit>)
+               -> Result<Ssu2, Error> {
+        let random_port = thread_rng().gen_range(MIN_RANDOM_PORT, MAX_RANDOM_PORT);
+        let port = config.i64_value(PORT_CONFIG, Some(random_port as i64)).unwrap();
+        if port <= 0 || port > u16::MAX as i64 {
+            return Err(Error::Configuration(format!("{} must be a port number", PORT_CONFIG)));
+        }
+        let hostname = config.string_value(HOST_CONFIG, None);
+        let any = if hostname.as_ref().is_some_and(|host| host.contains(':')) {
+            "::"
+        } else {
+            "0.0.0.0"
+        };
+        let listen_address = SocketAddr::new(any.parse().unwrap(), port as u16);
+
+        Ssu2::start(load_static_keys(&context.router_dir)?,
+                    context.keys.identity().hash()?,
+                    listen_address,
+                    hostname,
+                    network_id as u8,
+                    messages,
+                    bandwidth,
+                    limit)
+    }
+
+    // Everything the listener thread owns is handed over here
+    #[allow(clippy::too_many_arguments)]
+    fn start(keys: StaticKeys,
+             hash: Hash,
+             listen_address: SocketAddr,
+             hostname: Option<String>,
+             network_id: u8,
+             messages: Sender<ReceivedMessage>,
+             bandwidth: Arc<BandwidthLimiter>,
+             limit: Arc<SessionLimit>)
+             -> Result<Ssu2, Error> {
+        let socket = UdpSocket::bind(listen_address)?;
+        socket.set_read_timeout(Some(Duration::from_millis(TICK_MILLIS)))?;
+        let local_address = socket.local_addr()?;
+        info!("SSU2: listening on {}", local_address);
+        let max_packet = if local_address.is_ipv4() {
+            MAX_PACKET_V4
+        } else {
+            MAX_PACKET_V6
+        };
+
+        let shared = Arc::new(Shared {
+            keys,
+            hash,
+            network_id,
+            socket,
+            max_payload: max_packet - PACKET_OVERHEAD,
+            router_info: Mutex::new(Vec::new()),
+            state: Mutex::new(State::default()),
+            bandwidth,
+            limit,
+            running: AtomicBool::new(true),
+        });
+        let thread_shared = shared.clone();
+        thread::Builder::new().name("ssu2".to_string()).spawn(move || {
+            run(&thread_shared, messages);
+        })?;
+
+        Ok(Ssu2 {
+            shared,
+            local_address,
+            hostname,
+        })
+    }
+
+    pub fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    /// The addresses we can reach: IPv4 ones from an IPv4 socket and IPv6
+    /// ones from an IPv6 socket
+    fn reachable_style(&self) -> SupportedTransports {
+        if self.local_address.is_ipv4() {
+            SupportedTransports::SSU2V4
+        } else {
+            SupportedTransports::SSU2V6
+        }
+    }
+
+    /// The address, static key and intro key of a router's published SSU2
+    /// address, if it has one we can reach
+    fn remote_address(&self,
+                      router_info: &RouterInfo)
+                      -> Result<(SocketAddr, Vec<u8>, Vec<u8>), Error> {
+        match self.peer_address(router_info).and_then(parse_address) {
+            Some(address) => Ok(address),
+            None => {
+                Err(Error::Transport(format!("No usable {:?} address for {:?}",
+                                             self.reachable_style(),
+                                             router_info.hash()?)))
+            }
+        }
+    }
+
+    /// Starts a handshake, or joins one already under way, and waits for it
+    fn connect(&self, peer: &RouterInfo, hash: &Hash) -> Result<(), Error> {
+        let (address, static_key, intro_key) = self.remote_address(peer)?;
+        let (sender, receiver) = channel();
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if let Some(outbound) = state.outbound.get_mut(&address) {
+                outbound.waiters.push(sender);
+            } else {
+                debug!("SSU2: connecting to {:?} at {}", hash, address);
+                let mut handshake =
+                    OutboundHandshake::new(&static_key, &intro_key, self.shared.network_id)?;
+                let token = match state.tokens.get(&address) {
+                    Some(&(token, expiration)) if expiration > Date::now() => Some(token),
+                    _ => None,
+                };
+                let packet = match token {
+                    Some(token) => handshake.session_request(token)?,
+                    None => handshake.token_request()?,
+                };
+                send_to(&self.shared, &packet, address);
+                state.outbound.insert(address,
+                                      Outbound {
+                                          handshake,
+                                          peer: hash.clone(),
+                                          intro_key,
+                                          last_packet: packet,
+                                          sent: Instant::now(),
+                                          resends: 0,
+                                          waiters: vec![sender],
+                                      });
+            }
+        }
+
+        match receiver.recv_timeout(Duration::from_secs(HANDSHAKE_TIMEOUT)) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(message)) => Err(Error::Transport(message)),
+            Err(_) => Err(Error::Transport(format!("SSU2 handshake with {:?} timed out", hash))),
+        }
+    }
+}
+
+impl Transport for Ssu2 {
+    fn name(&self) -> &'static str {
+        "SSU2"
+    }
+
+    /// The address to publish in our RouterInfo. Without a host it just
+    /// gives our keys, so that routers we connect to can check them.
+    fn address(&self) -> RouterAddress {
+        let mut options = Mapping::new();
+        options.insert("s", &base64_encode(&self.shared.keys.public_key));
+        options.insert("i", &base64_encode(&self.shared.keys.intro_key));
+        options.insert("v", "2");
+        let mut transport_style = SupportedTransports::SSU2V4;
+        if let Some(ref host) = self.hostname {
+            if host.contains(':') {
+                transport_style = SupportedTransports::SSU2V6;
+            }
+            options.insert("host", host);
+            options.insert("port", &self.local_address.port().to_string());
+        }
+
+        RouterAddress {
+            cost: ADDRESS_COST,
+            expiration: None,
+            transport_style,
+            options,
+        }
+    }
+
+    /// Sets the RouterInfo we send when we connect; it has to include our
+    /// address
+    fn set_router_info(&self, router_info: &RouterInfo) -> Result<(), Error> {
+        let mut data: Vec<u8> = Vec::new();
+        router_info.serialize(&mut data)?;
+        *self.shared.router_info.lock().unwrap() = data;
+
+        Ok(())
+    }
+
+    fn peer_address<'a>(&self, router_info: &'a RouterInfo) -> Option<&'a RouterAddress> {
+        let style = self.reachable_style();
+        router_info.addresses().iter().find(|address| {
+            address.transport_style == style && handshake::is_ssu2(address) &&
+            parse_address(address).is_some()
+        })
+    }
+
+    fn is_connected(&self, peer: &Hash) -> bool {
+        self.shared.state.lock().unwrap().peers.contains_key(peer)
+    }
+
+    fn connection_count(&self) -> usize {
+        self.shared.state.lock().unwrap().peers.len()
+    }
+
+    fn send(&self, peer: &RouterInfo, messages: Vec<I2NPMessage>) -> Result<(), Error> {
+        let hash = peer.hash()?;
+        if !self.is_connected(&hash) {
+            self.connect(peer, &hash)?;
+        }
+        for message in &messages {
+            self.shared
+                .bandwidth
+                .request(Direction::Outbound, message.short_length()?, Priority::of(message));
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        let id = match state.peers.get(&hash) {
+            Some(id) => *id,
+            None => return Err(Error::Transport(format!("SSU2 session with {:?} closed", hash))),
+        };
+        let session = state.sessions.get_mut(&id).unwrap();
+        for packet in session.send(messages, Instant::now())? {
+            send_to(&self.shared, &packet, session.address);
+        }
+
+        Ok(())
+    }
+
+    /// Closes every session and stops the socket thread
+    fn stop(&self) {
+        self.shared.running.store(false, Ordering::SeqCst);
+        let mut state = self.shared.state.lock().unwrap();
+        for (_, mut session) in state.sessions.drain() {
+            if let Ok(packet) = session.terminate(block::TERMINATION_ROUTER_SHUTDOWN) {
+                send_to(&self.shared, &packet, session.address);
+            }
+        }
+        state.peers.clear();
+        self.shared.limit.set_count("SSU2", 0);
+    }
+}
+
+/// The socket thread
+fn run(shared: &Shared, messages: Sender<ReceivedMessage>) {
+    let mut buffer = [0u8; MAX_PACKET_V4];
+    let mut last_tick = Instant::now();
+    while shared.running.load(Ordering::SeqCst) {
+        match shared.socket.recv_from(&mut buffer) {
+            Ok((length, from)) => {
+                let mut state = shared.state.lock().unwrap();
+                handle_packet(shared, &mut state, &mut buffer[..length], from, &messages);
+            }
+            Err(ref error) if error.kind() == ErrorKind::WouldBlock ||
+                              error.kind() == ErrorKind::TimedOut => (),
+            Err(error) => debug!("SSU2: error receiving: {}", error),
+        }
+
+        if last_tick.elapsed() >= Duration::from_millis(TICK_MILLIS) {
+            last_tick = Instant::now();
+            tick(shared, &mut shared.state.lock().unwrap(), last_tick);
+        }
+    }
+}
+
+/// Handshake packets are charged as tunnel builds are, so that they get the
+/// capacity kept back from data. Over the limit they're dropped, and sent
+/// again.
+fn charge_handshake(shared: &Shared, length: usize) -> bool {
+    if shared.bandwidth.try_request(Direction::Inbound, length, Priority::TunnelBuild) {
+        return true;
+    }
+    debug!("SSU2: over the inbound bandwidth limit, dropping a handshake packet");
+    false
+}
+
+fn handle_packet(shared: &Shared,
+                 state: &mut State,
+                 packet: &mut [u8],
+                 from: SocketAddr,
+                 messages: &Sender<ReceivedMessage>) {
+    if packet.len() < MIN_PACKET_LENGTH {
+        return;
+    }
+
+    // Replies to our handshakes come from the address we sent to, masked
+    // with that router's intro key
+    let mut charged = false;
+    if state.outbound.contains_key(&from) {
+        if !charge_handshake(shared, packet.len()) {
+            return;
+        }
+        charged = true;
+        let mut copy = packet.to_vec();
+        let result = {
+            let outbound = state.outbound.get_mut(&from).unwrap();
+            let router_info = shared.router_info.lock().unwrap();
+            outbound.handshake.receive(&mut copy, &shared.keys, &router_info)
+        };
+        match result {
+            Ok(reply) => return handle_reply(shared, state, from, reply),
+            Err(error) => debug!("SSU2: packet from {} isn't a handshake reply: {}", from, error),
+        }
+    }
+
+    if let Err(error) = header::mask_destination(packet, &shared.keys.intro_key) {
+        debug!("SSU2: bad packet from {}: {}", from, error);
+        return;
+    }
+    let id = match ShortHeader::parse(packet) {
+        Ok(header) => header.destination,
+        Err(_) => return,
+    };
+    let result = if state.sessions.contains_key(&id) {
+        handle_session_packet(shared, state, id, packet, messages)
+    } else if !charged && !charge_handshake(shared, packet.len()) {
+        return;
+    } else if state.inbound.contains_key(&id) {
+        handle_session_confirmed(shared, state, id, packet, from)
+    } else {
+        handle_new_packet(shared, state, packet, from)
+    };
+    if let Err(error) = result {
+        debug!("SSU2: error handling packet from {}: {}", from, error);
+    }
+}
+
+/// Carries on with the handshake once Bob has answered
+fn handle_reply(shared: &Shared, state: &mut State, address: SocketAddr, reply: Reply) {
+    match reply {
+        Reply::Retry(token) => {
+            let outbound = state.outbound.get_mut(&address).unwrap();
+            match outbound.handshake.session_request(token) {
+                Ok(packet) => {
+                    send_to(shared, &packet, address);
+                    outbound.last_packet = packet;
+                    outbound.sent = Instant::now();
+                    outbound.resends = 0;
+                }
+                Err(error) => debug!("SSU2: error building SessionRequest: {}", error),
+            }
+        }
+        Reply::Rejected(reason) => {
+            let outbound = state.outbound.remove(&address).unwrap();
+            let message = format!("SSU2 connection to {:?} rejected, reason {}",
+                                  outbound.peer,
+                                  reason);
+            for waiter in outbound.waiters {
+                let _ = waiter.send(Err(message.clone()));
+            }
+        }
+        Reply::Created { session_confirmed, keys } => {
+            let outbound = state.outbound.remove(&address).unwrap();
+            send_to(shared, &session_confirmed, address);
+            let session = Session::new(outbound.peer.clone(),
+                                       address,
+                                       outbound.handshake.destination,
+                                       &outbound.intro_key,
+                                       keys,
+                                       shared.max_payload,
+                                       Some(session_confirmed),
+                                       Instant::now());
+            info!("SSU2: established session with {:?}", outbound.peer);
+            add_session(shared, state, outbound.handshake.source, session);
+            for waiter in outbound.waiters {
+                let _ = waiter.send(Ok(()));
+            }
+        }
+    }
+}
+
+fn add_session(shared: &Shared, state: &mut State, id: u64, session: Session) {
+    if let Some(old_id) = state.peers.insert(session.peer.clone(), id) {
+        if let Some(mut old) = state.sessions.remove(&old_id) {
+            if let Ok(packet) = old.terminate(block::TERMINATION_NORMAL) {
+                send_to(shared, &packet, old.address);
+            }
+        }
+    }
+    state.sessions.insert(id, session);
+    shared.limit.set_count("SSU2", state.peers.len());
+}
+
+fn remove_session(shared: &Shared, state: &mut State, id: u64, reason: Option<u8>) {
+    if let Some(mut session) = state.sessions.remove(&id) {
+        if state.peers.get(&session.peer) == Some(&id) {
+            state.peers.remove(&session.peer);
+            shared.limit.set_count("SSU2", state.peers.len());
+        }
+        if let Some(reason) = reason {
+            if let Ok(packet) = session.terminate(reason) {
+                send_to(shared, &packet, session.address);
+            }
+        }
+        debug!("SSU2: session with {:?} closed", session.peer);
+    }
+}
+
+fn handle_session_packet(shared: &Shared,
+                         state: &mut State,
+                         id: u64,
+                         packet: &mut [u8],
+                         messages: &Sender<ReceivedMessage>)
+                         -> Result<(), Error> {
+    let length = packet.len();
+    let (received, peer, address) = {
+        let session = state.sessions.get_mut(&id).unwrap();
+        (session.receive(packet, Instant::now())?, session.peer.clone(), session.address)
+    };
+    for reply in &received.replies {
+        send_to(shared, reply, address);
+    }
+    // Only now we've decrypted the packet do we know what it carries. Over
+    // the limit its messages are dropped; they've been acknowledged, so
+    // they're lost, as on any congested hop.
+    let priority = Priority::of_all(&received.messages);
+    if shared.bandwidth.try_request(Direction::Inbound, length, priority) {
+        for message in received.messages {
+            let _ = messages.send((peer.clone(), message));
+        }
+    } else if !received.messages.is_empty() {
+        debug!("SSU2: over the inbound bandwidth limit, dropping {} messages from {:?}",
+               received.messages.len(),
+               peer);
+    }
+    if let Some((token, expiration)) = received.token {
+        state.tokens.insert(address, (token, expiration));
+    }
+    if let Some(reason) = received.terminated {
+        debug!("SSU2: {:?} closed the session, reason {}", peer, reason);
+        let reply = if reason == block::TERMINATION_RECEIVED {
+            None
+        } else {
+            Some(block::TERMINATION_RECEIVED)
+        };
+        remove_session(shared, state, id, reply);
+    }
+
+    Ok(())
+}
+
+/// Handles a packet for a handshake we're answering: SessionConfirmed, or
+/// SessionRequest again if SessionCreated got lost. Their types are masked
+/// with different keys, so either can look like the other; only decrypting
+/// SessionConfirmed tells them apart.
+fn handle_session_confirmed(shared: &Shared,
+                            state: &mut State,
+                            id: u64,
+                            packet: &mut [u8],
+                            from: SocketAddr)
+                            -> Result<(), Error> {
+    let mut copy = packet.to_vec();
+    let (keys, router_info) =
+        match state.inbound[&id].handshake.confirm(&mut copy, &shared.hash, shared.network_id) {
+            Ok(confirmed) => confirmed,
+            Err(error) => {
+                header::mask_type(packet, &shared.keys.intro_key)?;
+                if header::message_type(packet) == header::SESSION_REQUEST {
+                    send_to(shared, &state.inbound[&id].handshake.session_created, from);
+                    return Ok(());
+                }
+                return Err(error);
+            }
+        };
+    let inbound = state.inbound.remove(&id).unwrap();
+    let peer = router_info.hash()?;
+    let now = Instant::now();
+    let mut session = Session::new(peer.clone(),
+                                   inbound.address,
+                                   inbound.handshake.destination,
+                                   &handshake::published_intro_key(&router_info)?,
+                                   keys,
+                                   shared.max_payload,
+                                   None,
+                                   now);
+    info!("SSU2: established session with {:?}", peer);
+
+    // Acknowledge SessionConfirmed, with a token for next time
+    let token: u64 = thread_rng().gen();
+    let expiration = Date::from_millis(Date::now().millis() + NEW_TOKEN_LIFETIME * 1000);
+    let packet = session.send_blocks(&[block::Block::Ack(block::Ack {
+                                           through: 0,
+                                           count: 0,
+                                           ranges: Vec::new(),
+                                       }),
+                                       block::Block::NewToken {
+                                           expiration,
+                                           token,
+                                       }],
+                                     now)?;
+    send_to(shared, &packet, inbound.address);
+    state.issued_tokens.insert(inbound.address,
+                               (token, now + Duration::from_secs(NEW_TOKEN_LIFETIME)));
+    add_session(shared, state, id, session);
+
+    Ok(())
+}
+
+/// Handles a TokenRequest or SessionRequest from a router we don't know
+fn handle_new_packet(shared: &Shared,
+                     state: &mut State,
+                     packet: &mut [u8],
+                     from: SocketAddr)
+                     -> Result<(), Error> {
+    let intro_key = &shared.keys.intro_key;
+    header::mask_type(packet, intro_key)?;
+    let message_type = header::message_type(packet);
+    let request = match message_type {
+        header::TOKEN_REQUEST => handshake::read_token_request(packet, intro_key)?,
+        header::SESSION_REQUEST => handshake::open_long_header(packet, intro_key)?,
+        _ => {
+            return Err(Error::Transport(format!("Unexpected SSU2 packet type {}", message_type)))
+        }
+    };
+
+    let now = Instant::now();
+    if request.network_id != shared.network_id {
+        let packet = handshake::retry(&request,
+                                      intro_key,
+                                      shared.network_id,
+                                      from,
+                                      0,
+                                      Some(block::TERMINATION_WRONG_NETWORK))?;
+        send_to(shared, &packet, from);
+        return Ok(());
+    }
+    if !shared.limit.has_room() {
+        debug!("SSU2: at the limit of {} sessions, turning away {}",
+               shared.limit.max(),
+               from);
+        let packet = handshake::retry(&request,
+                                      intro_key,
+                                      shared.network_id,
+                                      from,
+                                      0,
+                                      Some(block::TERMINATION_CONNECTION_LIMITS))?;
+        send_to(shared, &packet, from);
+        return Ok(());
+    }
+    let valid_token = match state.issued_tokens.get(&from) {
+        Some(&(token, expiration)) => token == request.token && expiration > now,
+        None => false,
+    };
+    if message_type == header::TOKEN_REQUEST || !valid_token {
+        let token: u64 = thread_rng().gen();
+        state.issued_tokens
+            .insert(from, (token, now + Duration::from_secs(RETRY_TOKEN_LIFETIME)));
+        let packet = handshake::retry(&request, intro_key, shared.network_id, from, token, None)?;
+        send_to(shared, &packet, from);
+        return Ok(());
+    }
+
+    state.issued_tokens.remove(&from);
+    let handshake =
+        InboundHandshake::accept(packet, &request, &shared.keys, from, shared.network_id)?;
+    send_to(shared, &handshake.session_created, from);
+    state.inbound.insert(handshake.source,
+                         Inbound {
+                             handshake,
+                             address: from,
+                             started: now,
+                         });
+
+    Ok(())
+}
+
+/// Sends again what hasn't been answered, and gives up on handshakes and
+/// sessions that have taken too long
+fn tick(shared: &Shared, state: &mut State, now: Instant) {
+    let resend_timeout = Duration::from_secs(HANDSHAKE_RESEND_TIMEOUT);
+    let mut failed: Vec<SocketAddr> = Vec::new();
+    for (address, outbound) in &mut state.outbound {
+        if now.duration_since(outbound.sent) < resend_timeout * (1 << outbound.resends) {
+            continue;
+        }
+        if outbound.resends == MAX_HANDSHAKE_RESENDS {
+            failed.push(*address);
+            continue;
+        }
+        outbound.resends += 1;
+        outbound.sent = now;
+        send_to(shared, &outbound.last_packet, *address);
+    }
+    for address in failed {
+        let outbound = state.outbound.remove(&address).unwrap();
+        for waiter in outbound.waiters {
+            let _ = waiter.send(Err(format!("No answer to SSU2 handshake with {:?}",
+                                            outbound.peer)));
+        }
+    }
+
+    let handshake_timeout = Duration::from_secs(HANDSHAKE_TIMEOUT);
+    state.inbound.retain(|_, inbound| now.duration_since(inbound.started) < handshake_timeout);
+    state.issued_tokens.retain(|_, &mut (_, expiration)| expiration > now);
+
+    let idle_timeout = Duration::from_secs(IDLE_TIMEOUT);
+    let mut closed: Vec<(u64, u8)> = Vec::new();
+    for (id, session) in &mut state.sessions {
+        if session.idle_time(now) >= idle_timeout {
+            closed.push((*id, block::TERMINATION_IDLE_TIMEOUT));
+            continue;
+        }
+        match session.tick(now) {
+            Ok(packets) => {
+                for packet in packets {
+                    send_to(shared, &packet, session.address);
+                }
+            }
+            Err(error) => {
+                debug!("SSU2: {}", error);
+                closed.push((*id, block::TERMINATION_TIMEOUT));
+            }
+        }
+    }
+    for (id, reason) in closed {
+        remove_session(shared, state, id, Some(reason));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::{PublicKeyType, SigningPublicKeyType};
+    use i2p::data::private_keys::PrivateKeys;
+    use i2p::i2np::MessageBody;
+    use i2p::i2np::tunnel_build::BuildRecords;
+    use i2p::test_util::read_fixture;
+    use i2p::transport::bandwidth::Limits;
+    use std::sync::mpsc::Receiver;
+    use super::*;
+
+    /// A router listening on loopback, with a RouterInfo publishing its
+    /// SSU2 address
+    fn router(network_id: u8) -> (Ssu2, RouterInfo, Receiver<ReceivedMessage>) {
+        limited_router(network_id, 250)
+    }
+
+    fn limited_router(network_id: u8,
+                      max_sessions: usize)
+                      -> (Ssu2, RouterInfo, Receiver<ReceivedMessage>) {
+        let limits = Limits {
+            rate: 1 << 30,
+            burst_rate: 1 << 30,
+            burst_size: 1 << 30,
+        };
+        start_router(network_id, limits, max_sessions)
+    }
+
+    fn start_router(network_id: u8,
+                    inbound: Limits,
+                    max_sessions: usize)
+                    -> (Ssu2, RouterInfo, Receiver<ReceivedMessage>) {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let (sender, receiver) = channel();
+        let outbound = Limits {
+            rate: 1 << 30,
+            burst_rate: 1 << 30,
+            burst_size: 1 << 30,
+        };
+        let ssu2 = Ssu2::start(StaticKeys::generate().unwrap(),
+                               keys.identity().hash().unwrap(),
+                               "127.0.0.1:0".parse().unwrap(),
+                               Some("127.0.0.1".to_string()),
+                               network_id,
+                               sender,
+                               Arc::new(BandwidthLimiter::new(inbound, outbound)),
+                               Arc::new(SessionLimit::new(max_sessions)))
+            .unwrap();
+        let mut options = Mapping::new();
+        options.insert("caps", "LR");
+        options.insert("netId", &network_id.to_string());
+        let router_info = RouterInfo::new(&keys, Date::now(), vec![ssu2.address()], options)
+            .unwrap();
+        ssu2.set_router_info(&router_info).unwrap();
+
+        (ssu2, router_info, receiver)
+    }
+
+    fn data(contents: &[u8]) -> I2NPMessage {
+        I2NPMessage::new(MessageBody::Data(contents.to_vec()))
+    }
+
+    fn received_data(receiver: &Receiver<ReceivedMessage>) -> (Hash, Vec<u8>) {
+        let (peer, message) = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        match message.body {
+            MessageBody::Data(contents) => (peer, contents),
+            body => panic!("Unexpected message {:?}", body),
+        }
+    }
+
+    #[test]
+    fn test_loopback() {
+        let (alice, alice_info, alice_messages) = router(2);
+        let (bob, bob_info, bob_messages) = router(2);
+
+        alice.send(&bob_info, vec![data(b"hello"), data(b"bob")]).unwrap();
+        assert_eq!((alice_info.hash().unwrap(), b"hello".to_vec()),
+                   received_data(&bob_messages));
+        assert_eq!((alice_info.hash().unwrap(), b"bob".to_vec()),
+                   received_data(&bob_messages));
+
+        // Bob replies over the session Alice opened
+        assert!(bob.is_connected(&alice_info.hash().unwrap()));
+        bob.send(&alice_info, vec![data(b"hello alice")]).unwrap();
+        assert_eq!((bob_info.hash().unwrap(), b"hello alice".to_vec()),
+                   received_data(&alice_messages));
+
+        // A message that has to be fragmented
+        let big: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        alice.send(&bob_info, vec![data(&big), data(b"small")]).unwrap();
+        assert_eq!(big, received_data(&bob_messages).1);
+        assert_eq!(b"small".to_vec(), received_data(&bob_messages).1);
+
+        // Bob gave Alice a token for next time
+        let bob_address = bob.local_address();
+        assert!(alice.shared.state.lock().unwrap().tokens.contains_key(&bob_address));
+
+        // Once Alice has gone, Bob has to connect to her himself
+        alice.stop();
+        thread::sleep(Duration::from_millis(500));
+        assert!(!bob.is_connected(&alice_info.hash().unwrap()));
+        bob.stop();
+    }
+
+    #[test]
+    fn test_other_network() {
+        let (alice, _, _) = router(2);
+        let (bob, bob_info, bob_messages) = router(3);
+
+        match alice.send(&bob_info, vec![data(b"hello")]) {
+            Err(Error::Transport(message)) => assert!(message.contains("rejected")),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert!(bob_messages.recv_timeout(Duration::from_millis(500)).is_err());
+
+        alice.stop();
+        bob.stop();
+    }
+
+    #[test]
+    fn test_session_limit() {
+        let (alice, alice_info, _) = router(2);
+        let (carol, _, _) = router(2);
+        let (bob, bob_info, bob_messages) = limited_router(2, 1);
+
+        alice.send(&bob_info, vec![data(b"hello")]).unwrap();
+        assert_eq!((alice_info.hash().unwrap(), b"hello".to_vec()),
+                   received_data(&bob_messages));
+        assert_eq!(1, bob.shared.limit.count());
+
+        // Bob's full, so he turns Carol away
+        match carol.send(&bob_info, vec![data(b"hello")]) {
+            Err(Error::Transport(message)) => assert!(message.contains("rejected")),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert!(bob_messages.recv_timeout(Duration::from_millis(500)).is_err());
+
+        alice.stop();
+        bob.stop();
+        carol.stop();
+    }
+
+    #[test]
+    fn test_inbound_bandwidth() {
+        let (alice, _, _) = router(2);
+        let limits = Limits {
+            rate: 1,
+            burst_rate: 1 << 30,
+            burst_size: 100000,
+        };
+        let (bob, bob_info, bob_messages) = start_router(2, limits, 250);
+        alice.send(&bob_info, vec![data(b"hello")]).unwrap();
+        assert_eq!(b"hello".to_vec(), received_data(&bob_messages).1);
+
+        // Use up all but what's kept back for tunnel builds
+        for &step in &[1000, 10] {
+            while bob.shared.bandwidth.try_request(Direction::Inbound, step, Priority::Data) {}
+        }
+        alice.send(&bob_info, vec![data(b"dropped")]).unwrap();
+        assert!(bob_messages.recv_timeout(Duration::from_millis(500)).is_err());
+
+        let build = BuildRecords { records: vec![vec![0; 218]] };
+        alice.send(&bob_info, vec![I2NPMessage::new(MessageBody::ShortTunnelBuild(build))])
+            .unwrap();
+        let (_, message) = bob_messages.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(Priority::TunnelBuild, Priority::of(&message));
+
+        alice.stop();
+        bob.stop();
+    }
+
+    #[test]
+    fn test_address_family() {
+        let (alice, _, _) = router(2);
+        let (bob, _, _) = router(2);
+
+        // Bob only publishes an IPv6 address, which Alice can't reach
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let mut address = bob.address();
+        address.transport_style = SupportedTransports::SSU2V6;
+        address.options.insert("host", "::1");
+        let bob_info = RouterInfo::new(&keys, Date::now(), vec![address], Mapping::new()).unwrap();
+        assert!(alice.peer_address(&bob_info).is_none());
+        assert!(alice.send(&bob_info, vec![data(b"hello")]).is_err());
+
+        alice.stop();
+        bob.stop();
+    }
+
+    #[test]
+    fn test_published_address() {
+        let (alice, alice_info, _) = router(2);
+        assert_eq!("SSU2", alice_info.addresses()[0].transport_style.style());
+        assert!(alice.peer_address(&alice_info).is_some());
+        alice.stop();
+
+        // A RouterInfo as other routers publish it
+        let data = read_fixture("RouterInfo_NTCP2_SSU2_EdDSA_SHA512_Ed25519");
+        let router_info = RouterInfo::deserialize(&mut data.as_slice()).unwrap();
+        let address = &router_info.addresses()[1];
+        assert!(handshake::is_ssu2(address));
+        let (socket_address, key, intro_key) = parse_address(address).unwrap();
+        assert_eq!("[2001:db8::7]:23456".parse::<SocketAddr>().unwrap(), socket_address);
+        assert_eq!(Some(key), handshake::published_static_key(&router_info));
+        assert_eq!(intro_key, handshake::published_intro_key(&router_info).unwrap());
+    }
+
+    #[test]
+    fn test_static_keys_stable_across_restarts() {
+        let temp_dir = ::tempdir::TempDir::new("i2pd-test").unwrap();
+        let keys = load_static_keys(temp_dir.path()).unwrap();
+        let reloaded = load_static_keys(temp_dir.path()).unwrap();
+        assert_eq!(keys.public_key, reloaded.public_key);
+        assert_eq!(keys.intro_key, reloaded.intro_key);
+    }
+}
@@ -0,0 +1,573 @@
+//! An established SSU2 session. Every packet has a new packet number, which
+//! is also its nonce, and everything but ACKs has to be acknowledged: what
+//! isn't within the resend timeout is sent again in a new packet. Messages
+//! too big for a packet go as a first fragment and numbered follow-ons,
+//! which are put back together here.
+
+use i2p::crypto::chacha20;
+use i2p::crypto::poly1305::TAG_LENGTH;
+use i2p::data::crypto::Hash;
+use i2p::data::date::Date;
+use i2p::error::Error;
+use i2p::i2np::I2NPMessage;
+use i2p::transport::ssu2::block::{self, Ack, Block, BLOCK_HEADER_LENGTH,
+                                  FOLLOW_ON_HEADER_LENGTH, MAX_FOLLOW_ON_FRAGMENTS};
+use i2p::transport::ssu2::handshake::SessionKeys;
+use i2p::transport::ssu2::header::{self, ShortHeader, SHORT_HEADER_LENGTH};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The smallest payload that leaves enough after the header for its masks
+const MIN_PAYLOAD: usize = header::MIN_PACKET_LENGTH - SHORT_HEADER_LENGTH - TAG_LENGTH;
+
+/// How long we wait for an ACK before sending a packet's contents again
+const RESEND_TIMEOUT: u64 = 1;
+
+/// How many times we send something again before giving up on the session
+const MAX_RESENDS: u32 = 5;
+
+/// How long we keep the fragments of a message we haven't got all of
+const FRAGMENT_TIMEOUT: u64 = 10;
+
+/// How many received packet numbers we remember, for ACKs and duplicates
+const RECEIVED_WINDOW: usize = 512;
+
+/// How many delivered message IDs we remember, so that messages sent again
+/// after a lost ACK aren't delivered twice
+const DELIVERED_WINDOW: usize = 1024;
+
+/// What a packet from the peer gave us
+#[derive(Default)]
+pub struct Received {
+    pub messages: Vec<I2NPMessage>,
+    /// Packets to send back: ACKs and path responses
+    pub replies: Vec<Vec<u8>>,
+    /// A token for our next connection to the peer
+    pub token: Option<(u64, Date)>,
+    /// Set when the peer has closed the session
+    pub terminated: Option<u8>,
+}
+
+struct Unacked {
+    payload: Vec<u8>,
+    sent: Instant,
+    resends: u32,
+}
+
+struct PartialMessage {
+    first: Option<Vec<u8>>,
+    follow_on: BTreeMap<u8, Vec<u8>>,
+    last: Option<u8>,
+    started: Instant,
+}
+
+impl PartialMessage {
+    fn is_complete(&self) -> bool {
+        self.first.is_some() &&
+        self.last.is_some_and(|last| self.follow_on.len() == last as usize)
+    }
+}
+
+pub struct Session {
+    pub peer: Hash,
+    pub address: SocketAddr,
+    /// The connection ID the peer receives on
+    destination: u64,
+    remote_intro_key: Vec<u8>,
+    keys: SessionKeys,
+    max_payload: usize,
+    next_packet_number: u32,
+    unacked: BTreeMap<u32, Unacked>,
+    received: BTreeSet<u32>,
+    partial_messages: HashMap<u32, PartialMessage>,
+    delivered: VecDeque<u32>,
+    /// Alice's SessionConfirmed, sent again until a packet from Bob shows
+    /// that he got it
+    session_confirmed: Option<Unacked>,
+    last_received: Instant,
+}
+
+impl Session {
+    /// Alice's SessionConfirmed was packet 0, so her data packets start at
+    /// 1 and Bob has packet 0 to acknowledge
+    // Everything here comes out of the handshake that set the session up
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(peer: Hash,
+               address: SocketAddr,
+               destination: u64,
+               remote_intro_key: &[u8],
+               keys: SessionKeys,
+               max_payload: usize,
+               session_confirmed: Option<Vec<u8>>,
+               now: Instant)
+               -> Session {
+        let mut received = BTreeSet::new();
+        if session_confirmed.is_none() {
+            received.insert(0);
+        }
+
+        Session {
+            peer,
+            address,
+            destination,
+            remote_intro_key: remote_intro_key.to_vec(),
+            keys,
+            max_payload,
+            next_packet_number: if session_confirmed.is_some() { 1 } else { 0 },
+            unacked: BTreeMap::new(),
+            received,
+            partial_messages: HashMap::new(),
+            delivered: VecDeque::new(),
+            session_confirmed: session_confirmed.map(|packet| {
+                Unacked {
+                    payload: packet,
+                    sent: now,
+                    resends: 0,
+                }
+            }),
+            last_received: now,
+        }
+    }
+
+    pub fn idle_time(&self, now: Instant) -> Duration {
+        now.duration_since(self.last_received)
+    }
+
+    fn seal(&mut self, mut payload: Vec<u8>) -> Result<(u32, Vec<u8>), Error> {
+        if payload.len() < MIN_PAYLOAD {
+            payload.extend(block::write_blocks(&[Block::Padding(MIN_PAYLOAD)])?);
+        }
+        let packet_number = self.next_packet_number;
+        if packet_number == u32::MAX {
+            return Err(Error::Crypto("SSU2 session has run out of packet numbers".to_string()));
+        }
+        self.next_packet_number += 1;
+
+        let mut packet = ShortHeader {
+                destination: self.destination,
+                packet_number,
+                message_type: header::DATA,
+                flags: 0,
+            }
+            .to_bytes()?;
+        let nonce = chacha20::nonce(packet_number as u64);
+        let ciphertext = chacha20::aead_encrypt(&self.keys.send_key, &nonce, &packet, &payload)?;
+        packet.extend(ciphertext);
+        header::mask_destination(&mut packet, &self.remote_intro_key)?;
+        header::mask_type(&mut packet, &self.keys.send_header_key)?;
+
+        Ok((packet_number, packet))
+    }
+
+    /// Seals blocks that have to be acknowledged, keeping them to be sent
+    /// again if they aren't
+    fn send_payload(&mut self, payload: Vec<u8>, now: Instant) -> Result<Vec<u8>, Error> {
+        let (packet_number, packet) = self.seal(payload.clone())?;
+        self.unacked.insert(packet_number,
+                            Unacked {
+                                payload,
+                                sent: now,
+                                resends: 0,
+                            });
+
+        Ok(packet)
+    }
+
+    pub fn send_blocks(&mut self, blocks: &[Block], now: Instant) -> Result<Vec<u8>, Error> {
+        let payload = block::write_blocks(blocks)?;
+        self.send_payload(payload, now)
+    }
+
+    /// Packs messages into as few packets as they fit in, fragmenting any
+    /// that are too big for a packet of their own
+    pub fn send(&mut self,
+                messages: Vec<I2NPMessage>,
+                now: Instant)
+                -> Result<Vec<Vec<u8>>, Error> {
+        let mut payloads: Vec<Vec<u8>> = Vec::new();
+        let mut current: Vec<u8> = Vec::new();
+        for message in messages {
+            let message_block = block::write_blocks(&[Block::I2NP(message)])?;
+            if current.len() + message_block.len() > self.max_payload && !current.is_empty() {
+                payloads.push(current);
+                current = Vec::new();
+            }
+            if message_block.len() <= self.max_payload {
+                current.extend(message_block);
+                continue;
+            }
+
+            // The block's contents are the message with its short header
+            let data = &message_block[BLOCK_HEADER_LENGTH..];
+            let message_id = read_message_id(data)?;
+            let first_length = self.max_payload - BLOCK_HEADER_LENGTH;
+            payloads.push(block::write_blocks(&[Block::FirstFragment(data[..first_length]
+                                                    .to_vec())])?);
+            let chunks: Vec<&[u8]> = data[first_length..]
+                .chunks(self.max_payload - BLOCK_HEADER_LENGTH - FOLLOW_ON_HEADER_LENGTH)
+                .collect();
+            if chunks.len() > MAX_FOLLOW_ON_FRAGMENTS {
+                return Err(Error::Transport(format!("I2NP message too big for SSU2: {}",
+                                                    data.len())));
+            }
+            for (i, chunk) in chunks.iter().enumerate() {
+                payloads.push(block::write_blocks(&[Block::FollowOnFragment {
+                                                        fragment: i as u8 + 1,
+                                                        last: i + 1 == chunks.len(),
+                                                        message_id,
+                                                        data: chunk.to_vec(),
+                                                    }])?);
+            }
+        }
+        if !current.is_empty() {
+            payloads.push(current);
+        }
+
+        payloads.into_iter().map(|payload| self.send_payload(payload, now)).collect()
+    }
+
+    /// A packet telling the peer we're closing the session. It isn't sent
+    /// again.
+    pub fn terminate(&mut self, reason: u8) -> Result<Vec<u8>, Error> {
+        let payload = block::write_blocks(&[Block::Termination {
+                                                packets_received: self.received.len() as u64,
+                                                reason,
+                                            }])?;
+        Ok(self.seal(payload)?.1)
+    }
+
+    fn ack(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match Ack::new(&self.received) {
+            Some(ack) => Ok(Some(self.seal(block::write_blocks(&[Block::Ack(ack)])?)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    /// Handles a packet whose destination has been unmasked
+    pub fn receive(&mut self, packet: &mut [u8], now: Instant) -> Result<Received, Error> {
+        let mut received = Received::default();
+        header::mask_type(packet, &self.keys.receive_header_key)?;
+        let header = ShortHeader::parse(packet)?;
+        if header.message_type != header::DATA {
+            // Only a SessionConfirmed we've had before, sent again because
+            // our ACK got lost, can come here with another type. Its type is
+            // masked with a key we no longer use, so it won't look like one.
+            debug!("SSU2: acknowledging packet of unknown type from {:?} again",
+                   self.peer);
+            received.replies.extend(self.ack()?);
+            return Ok(received);
+        }
+        if self.received.contains(&header.packet_number) {
+            received.replies.extend(self.ack()?);
+            return Ok(received);
+        }
+        let nonce = chacha20::nonce(header.packet_number as u64);
+        let payload = chacha20::aead_decrypt(&self.keys.receive_key,
+                                             &nonce,
+                                             &packet[..SHORT_HEADER_LENGTH],
+                                             &packet[SHORT_HEADER_LENGTH..])?;
+        let blocks = block::parse_blocks(&payload)?;
+
+        self.received.insert(header.packet_number);
+        while self.received.len() > RECEIVED_WINDOW {
+            let oldest = *self.received.iter().next().unwrap();
+            self.received.remove(&oldest);
+        }
+        self.last_received = now;
+        self.session_confirmed = None;
+
+        let ack_eliciting = blocks.iter().any(|block| block.is_ack_eliciting());
+        for block in blocks {
+            match block {
+                Block::I2NP(message)
+                    if self.is_new_message(message.message_id) => {
+                        received.messages.push(message);
+                    }
+                Block::FirstFragment(data) => {
+                    let message_id = read_message_id(&data)?;
+                    received.messages.extend(self.add_fragment(message_id, 0, false, data, now)?);
+                }
+                Block::FollowOnFragment { fragment, last, message_id, data } => {
+                    received.messages
+                        .extend(self.add_fragment(message_id, fragment, last, data, now)?);
+                }
+                Block::Ack(ack) => {
+                    for packet_number in ack.acked() {
+                        self.unacked.remove(&packet_number);
+                    }
+                }
+                Block::Termination { reason, .. } => received.terminated = Some(reason),
+                Block::NewToken { expiration, token } => received.token = Some((token, expiration)),
+                Block::PathChallenge(data) => {
+                    let response = self.send_blocks(&[Block::PathResponse(data)], now)?;
+                    received.replies.push(response);
+                }
+                Block::RelayRequest { .. } |
+                Block::RelayResponse { .. } |
+                Block::RelayIntro { .. } |
+                Block::RelayTagRequest |
+                Block::PeerTest { .. } => {
+                    debug!("SSU2: ignoring relay or peer test block from {:?}", self.peer);
+                }
+                _ => (),
+            }
+        }
+        if ack_eliciting && received.terminated.is_none() {
+            received.replies.extend(self.ack()?);
+        }
+
+        Ok(received)
+    }
+
+    fn is_new_message(&mut self, message_id: u32) -> bool {
+        if self.delivered.contains(&message_id) {
+            return false;
+        }
+        self.delivered.push_back(message_id);
+        if self.delivered.len() > DELIVERED_WINDOW {
+            self.delivered.pop_front();
+        }
+
+        true
+    }
+
+    fn add_fragment(&mut self,
+                    message_id: u32,
+                    fragment: u8,
+                    last: bool,
+                    data: Vec<u8>,
+                    now: Instant)
+                    -> Result<Option<I2NPMessage>, Error> {
+        if self.delivered.contains(&message_id) {
+            return Ok(None);
+        }
+        let complete = {
+            let partial = self.partial_messages.entry(message_id).or_insert_with(|| {
+                PartialMessage {
+                    first: None,
+                    follow_on: BTreeMap::new(),
+                    last: None,
+                    started: now,
+                }
+            });
+            if fragment == 0 {
+                partial.first = Some(data);
+            } else {
+                partial.follow_on.insert(fragment, data);
+                if last {
+                    partial.last = Some(fragment);
+                }
+            }
+            if partial.last.is_some_and(|last| partial.follow_on.keys().any(|&f| f > last)) {
+                return Err(Error::Transport(format!("SSU2 fragment past the last of message {}",
+                                                    message_id)));
+            }
+            partial.is_complete()
+        };
+        if !complete {
+            return Ok(None);
+        }
+
+        let partial = self.partial_messages.remove(&message_id).unwrap();
+        let mut data = partial.first.unwrap();
+        for (_, fragment) in partial.follow_on {
+            data.extend(fragment);
+        }
+        self.is_new_message(message_id);
+
+        Ok(Some(I2NPMessage::deserialize_short(&data)?))
+    }
+
+    /// Sends again whatever hasn't been acknowledged in time, and forgets
+    /// incomplete messages that have been waiting too long. Fails once
+    /// something has been sent too many times without an ACK.
+    pub fn tick(&mut self, now: Instant) -> Result<Vec<Vec<u8>>, Error> {
+        let timeout = Duration::from_secs(RESEND_TIMEOUT);
+        let mut packets: Vec<Vec<u8>> = Vec::new();
+
+        if let Some(ref mut session_confirmed) = self.session_confirmed {
+            if now.duration_since(session_confirmed.sent) >= timeout {
+                if session_confirmed.resends == MAX_RESENDS {
+                    return Err(Error::Transport("SSU2 SessionConfirmed not \
+                                                         acknowledged".to_string()));
+                }
+                session_confirmed.resends += 1;
+                session_confirmed.sent = now;
+                packets.push(session_confirmed.payload.clone());
+            }
+        }
+
+        let expired: Vec<u32> = self.unacked
+            .iter()
+            .filter(|&(_, unacked)| now.duration_since(unacked.sent) >= timeout)
+            .map(|(packet_number, _)| *packet_number)
+            .collect();
+        for packet_number in expired {
+            let unacked = self.unacked.remove(&packet_number).unwrap();
+            if unacked.resends == MAX_RESENDS {
+                return Err(Error::Transport(format!("SSU2 packet {} to {:?} not acknowledged",
+                                                    packet_number,
+                                                    self.peer)));
+            }
+            let (packet_number, packet) = self.seal(unacked.payload.clone())?;
+            self.unacked.insert(packet_number,
+                                Unacked {
+                                    payload: unacked.payload,
+                                    sent: now,
+                                    resends: unacked.resends + 1,
+                                });
+            packets.push(packet);
+        }
+
+        let fragment_timeout = Duration::from_secs(FRAGMENT_TIMEOUT);
+        self.partial_messages
+            .retain(|_, partial| now.duration_since(partial.started) < fragment_timeout);
+
+        Ok(packets)
+    }
+}
+
+/// The message ID in a message's short header
+fn read_message_id(data: &[u8]) -> Result<u32, Error> {
+    if data.len() < 5 {
+        return Err(Error::Serialization("SSU2 first fragment too short".to_string()));
+    }
+
+    Ok((data[1] as u32) << 24 | (data[2] as u32) << 16 | (data[3] as u32) << 8 | data[4] as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::i2np::MessageBody;
+    use super::*;
+
+    const MAX_PAYLOAD: usize = 1200;
+
+    fn keys(a: u8, b: u8) -> SessionKeys {
+        SessionKeys {
+            send_key: vec![a; 32],
+            send_header_key: vec![a + 1; 32],
+            receive_key: vec![b; 32],
+            receive_header_key: vec![b + 1; 32],
+        }
+    }
+
+    /// Alice and Bob, as they are after the handshake
+    fn sessions(now: Instant) -> (Session, Session) {
+        let hash = Hash::SHA256(vec![0; 32].into_boxed_slice());
+        let address = "127.0.0.1:9000".parse().unwrap();
+        let alice = Session::new(hash.clone(),
+                                 address,
+                                 2,
+                                 &[30; 32],
+                                 keys(1, 10),
+                                 MAX_PAYLOAD,
+                                 Some(vec![0; 64]),
+                                 now);
+        let bob = Session::new(hash, address, 1, &[20; 32], keys(10, 1), MAX_PAYLOAD, None, now);
+
+        (alice, bob)
+    }
+
+    /// Delivers a packet the way the transport does
+    fn deliver(packet: &[u8], intro_key: &[u8], to: &mut Session, now: Instant) -> Received {
+        let mut packet = packet.to_vec();
+        header::mask_destination(&mut packet, intro_key).unwrap();
+        to.receive(&mut packet, now).unwrap()
+    }
+
+    fn data(contents: &[u8]) -> I2NPMessage {
+        I2NPMessage::new(MessageBody::Data(contents.to_vec()))
+    }
+
+    fn contents(message: &I2NPMessage) -> &[u8] {
+        match message.body {
+            MessageBody::Data(ref contents) => contents,
+            ref body => panic!("Unexpected message {:?}", body),
+        }
+    }
+
+    #[test]
+    fn test_messages_and_acks() {
+        let now = Instant::now();
+        let (mut alice, mut bob) = sessions(now);
+
+        // Bob acknowledges the SessionConfirmed first thing
+        let ack = bob.ack().unwrap().unwrap();
+        let received = deliver(&ack, &[20; 32], &mut alice, now);
+        assert!(received.replies.is_empty());
+        assert!(alice.session_confirmed.is_none());
+
+        let packets = alice.send(vec![data(b"one"), data(b"two")], now).unwrap();
+        assert_eq!(1, packets.len());
+        let received = deliver(&packets[0], &[30; 32], &mut bob, now);
+        assert_eq!(vec![b"one".to_vec(), b"two".to_vec()],
+                   received.messages.iter().map(|m| contents(m).to_vec()).collect::<Vec<_>>());
+        assert_eq!(1, received.replies.len());
+        assert_eq!(1, alice.unacked.len());
+        deliver(&received.replies[0], &[20; 32], &mut alice, now);
+        assert!(alice.unacked.is_empty());
+
+        // The same packet again is only acknowledged again
+        let received = deliver(&packets[0], &[30; 32], &mut bob, now);
+        assert!(received.messages.is_empty());
+        assert_eq!(1, received.replies.len());
+    }
+
+    #[test]
+    fn test_fragments() {
+        let now = Instant::now();
+        let (mut alice, mut bob) = sessions(now);
+
+        let big: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let mut packets = alice.send(vec![data(b"small"), data(&big), data(b"after")], now)
+            .unwrap();
+        // The small message, five fragments, then the last one
+        assert_eq!(7, packets.len());
+        assert!(packets.iter().all(|packet| packet.len() <= MAX_PAYLOAD + 32));
+
+        // Out of order, with one lost
+        packets.swap(2, 4);
+        let lost = packets.remove(3);
+        let mut messages: Vec<I2NPMessage> = Vec::new();
+        for packet in &packets {
+            let received = deliver(packet, &[30; 32], &mut bob, now);
+            messages.extend(received.messages);
+            for reply in received.replies {
+                deliver(&reply, &[20; 32], &mut alice, now);
+            }
+        }
+        assert_eq!(2, messages.len());
+        assert_eq!(1, alice.unacked.len());
+
+        // The lost fragment is sent again when it isn't acknowledged
+        let later = now + Duration::from_secs(RESEND_TIMEOUT);
+        let resent = alice.tick(later).unwrap();
+        assert_eq!(1, resent.len());
+        assert!(resent[0] != lost);
+        let received = deliver(&resent[0], &[30; 32], &mut bob, later);
+        assert_eq!(big, contents(&received.messages[0]));
+
+        // It isn't delivered twice if Bob's ACK is lost and it's sent again
+        let resent = alice.tick(later + Duration::from_secs(RESEND_TIMEOUT)).unwrap();
+        assert_eq!(1, resent.len());
+        assert!(deliver(&resent[0], &[30; 32], &mut bob, later).messages.is_empty());
+    }
+
+    #[test]
+    fn test_resend_limit() {
+        let now = Instant::now();
+        let (mut alice, _) = sessions(now);
+        alice.send(vec![data(b"lost")], now).unwrap();
+        alice.session_confirmed = None;
+
+        let mut time = now;
+        for _ in 0..MAX_RESENDS {
+            time += Duration::from_secs(RESEND_TIMEOUT);
+            assert_eq!(1, alice.tick(time).unwrap().len());
+        }
+        time += Duration::from_secs(RESEND_TIMEOUT);
+        assert!(alice.tick(time).is_err());
+    }
+}
@@ -1,35 +1,686 @@
+//! The transport manager. It runs every enabled transport and picks one for
+//! each router we send to: one we already have a session on if there is
+//! one, and otherwise the one whose address the router published with the
+//! lowest cost, as routers publish lower costs for the transports they
+//! prefer. Ties go to the transport started first, NTCP2. Connecting
+//! happens on its own thread, and whatever is sent to the router meanwhile
+//! is queued and sent in order once the session is up.
+
+use i2p::config::Config;
+use i2p::data::crypto::Hash;
+use i2p::data::router_info::{RouterAddress, RouterInfo};
 use i2p::error::Error;
+use i2p::i2np::I2NPMessage;
+use i2p::router_context::RouterContext;
+use i2p::transport::{ReceivedMessage, SessionLimit, Transport};
+use i2p::transport::bandwidth::BandwidthLimiter;
+use i2p::transport::ntcp2::Ntcp2;
+use i2p::transport::ssu2::Ssu2;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+const MAX_CONNECTIONS_CONFIG: &str = "i2np.maxConnections";
+const DEFAULT_MAX_CONNECTIONS: usize = 250;
+
+/// How many messages are queued for a router we're connecting to. Past
+/// this the oldest are dropped, as a connection that slow is likely to fail.
+const MAX_PENDING_MESSAGES: usize = 100;
+
+/// What a transport offers for reaching a router
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bid {
+    pub transport: &'static str,
+    /// The cost the router published for the address we'd connect to, or
+    /// `None` if this transport can't reach it
+    pub cost: Option<u8>,
+    pub connected: bool,
+}
+
+/// The transport picked for a router, and why
+#[derive(Clone, Debug, PartialEq)]
+pub struct Choice {
+    pub transport: &'static str,
+    pub reason: String,
+    index: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    /// We connected to a router with the transport chosen for it
+    Connected { peer: Hash, choice: Choice },
+    /// We couldn't connect; the messages queued for the router are dropped
+    Failed {
+        peer: Hash,
+        transport: &'static str,
+        error: String,
+    },
+    /// We didn't try, as we're at the connection limit
+    Refused { peer: Hash },
+}
+
+/// What the connecting threads share with the manager
+struct Shared {
+    transports: Mutex<Vec<Arc<dyn Transport>>>,
+    /// What's been sent to routers we're connecting to, in order
+    pending: Mutex<HashMap<Hash, Vec<I2NPMessage>>>,
+    subscribers: Mutex<Vec<Sender<ConnectionEvent>>>,
+}
+
+impl Shared {
+    fn notify(&self, event: ConnectionEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
 
-#[derive(Debug, Default)]
 pub struct Transports {
     is_online: bool,
-    is_running: bool,
+    /// How many sessions, including ones being set up, we'll have at once
+    max_connections: usize,
+    /// Shared by the transports, once they're started
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+    shared: Arc<Shared>,
+}
+
+fn describe(bids: &[Bid]) -> String {
+    bids.iter()
+        .map(|bid| match bid.cost {
+            Some(cost) => format!("{} cost {}", bid.transport, cost),
+            None => format!("{} unreachable", bid.transport),
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn bids(transports: &[Arc<dyn Transport>], peer: &RouterInfo, hash: &Hash) -> Vec<Bid> {
+    transports.iter()
+        .map(|transport| {
+            Bid {
+                transport: transport.name(),
+                cost: transport.peer_address(peer).map(|address: &RouterAddress| address.cost),
+                connected: transport.is_connected(hash),
+            }
+        })
+        .collect()
+}
+
+fn choose(bids: &[Bid]) -> Option<Choice> {
+    if let Some(index) = bids.iter().position(|bid| bid.connected) {
+        return Some(Choice {
+            transport: bids[index].transport,
+            reason: "already connected".to_string(),
+            index,
+        });
+    }
+
+    let mut best: Option<(usize, u8)> = None;
+    for (index, bid) in bids.iter().enumerate() {
+        if let Some(cost) = bid.cost {
+            if best.is_none_or(|(_, best_cost)| cost < best_cost) {
+                best = Some((index, cost));
+            }
+        }
+    }
+    best.map(|(index, _)| {
+        let reachable = bids.iter().filter(|bid| bid.cost.is_some()).count();
+        let reason = if reachable == 1 {
+            format!("the only reachable transport ({})", describe(bids))
+        } else {
+            format!("lowest published cost ({})", describe(bids))
+        };
+        Choice {
+            transport: bids[index].transport,
+            reason,
+            index,
+        }
+    })
 }
 
 impl Transports {
     pub fn new() -> Transports {
         Transports {
             is_online: true,
-            is_running: false,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            bandwidth: None,
+            shared: Arc::new(Shared {
+                transports: Mutex::new(Vec::new()),
+                pending: Mutex::new(HashMap::new()),
+                subscribers: Mutex::new(Vec::new()),
+            }),
         }
     }
 
-    pub fn start(&self, use_ntcp: bool, use_ssu: bool) -> Result<(), Error> {
-        if !use_ssu {
-            info!("Transports: ssu disabled");
+    /// Starts the enabled transports. Whatever they receive is sent to
+    /// `messages`.
+    pub fn start(&mut self,
+                 config: &Config,
+                 context: &RouterContext,
+                 network_id: u32,
+                 messages: Sender<ReceivedMessage>,
+                 use_ntcp: bool,
+                 use_ssu: bool)
+                 -> Result<(), Error> {
+        let max_connections =
+            config.i64_value(MAX_CONNECTIONS_CONFIG, Some(DEFAULT_MAX_CONNECTIONS as i64))
+                .unwrap();
+        if max_connections <= 0 {
+            return Err(Error::Configuration(format!("{} must be positive",
+                                                    MAX_CONNECTIONS_CONFIG)));
         }
+        self.max_connections = max_connections as usize;
+        let bandwidth = Arc::new(BandwidthLimiter::from_config(config)?);
+        self.bandwidth = Some(bandwidth.clone());
+        let limit = Arc::new(SessionLimit::new(self.max_connections));
 
-        if !use_ntcp {
+        if use_ntcp {
+            self.add(Arc::new(Ntcp2::new(config,
+                                         context,
+                                         network_id,
+                                         messages.clone(),
+                                         bandwidth.clone(),
+                                         limit.clone())?));
+        } else {
             info!("Transports: ntcp disabled");
         }
 
-        unimplemented!()
+        if use_ssu {
+            self.add(Arc::new(Ssu2::new(config,
+                                        context,
+                                        network_id,
+                                        messages,
+                                        bandwidth,
+                                        limit)?));
+        } else {
+            info!("Transports: ssu disabled");
+        }
+
+        Ok(())
+    }
+
+    fn add(&self, transport: Arc<dyn Transport>) {
+        self.shared.transports.lock().unwrap().push(transport);
+    }
+
+    fn transports(&self) -> Vec<Arc<dyn Transport>> {
+        self.shared.transports.lock().unwrap().clone()
+    }
+
+    /// The addresses to publish in our RouterInfo
+    pub fn addresses(&self) -> Vec<RouterAddress> {
+        self.transports().iter().map(|transport| transport.address()).collect()
+    }
+
+    pub fn set_router_info(&self, router_info: &RouterInfo) -> Result<(), Error> {
+        for transport in self.transports() {
+            transport.set_router_info(router_info)?;
+        }
+
+        Ok(())
+    }
+
+    /// The limiter the transports share, for its limits and rates
+    pub fn bandwidth(&self) -> Option<Arc<BandwidthLimiter>> {
+        self.bandwidth.clone()
+    }
+
+    /// Receives an event for each connection we make or fail to make
+    pub fn subscribe(&self) -> Receiver<ConnectionEvent> {
+        let (sender, receiver) = channel();
+        self.shared.subscribers.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    /// What each transport offers for reaching a router
+    pub fn bids(&self, peer: &RouterInfo) -> Result<Vec<Bid>, Error> {
+        Ok(bids(&self.transports(), peer, &peer.hash()?))
+    }
+
+    /// Which transport we'd use for a router, and why
+    pub fn choose(&self, peer: &RouterInfo) -> Result<Choice, Error> {
+        match choose(&self.bids(peer)?) {
+            Some(choice) => Ok(choice),
+            None => Err(Error::Transport(format!("No transport can reach {:?}", peer.hash()?))),
+        }
+    }
+
+    /// How many routers we have sessions with or are connecting to
+    pub fn connection_count(&self) -> usize {
+        let connected: usize =
+            self.transports().iter().map(|transport| transport.connection_count()).sum();
+
+        connected + self.shared.pending.lock().unwrap().len()
+    }
+
+    /// Sends messages to a router. If we have to connect to it first, they
+    /// are queued, and this returns without waiting for the connection.
+    pub fn send(&self, peer: &RouterInfo, messages: Vec<I2NPMessage>) -> Result<(), Error> {
+        let hash = peer.hash()?;
+        let transports = self.transports();
+        if transports.is_empty() {
+            return Err(Error::Transport("No transports are running".to_string()));
+        }
+
+        let mut pending = self.shared.pending.lock().unwrap();
+        // While we're connecting, or sending what was queued, messages go to
+        // the back of the queue so that they're sent in order
+        if let Some(queue) = pending.get_mut(&hash) {
+            queue.extend(messages);
+            limit_queue(queue, &hash);
+            return Ok(());
+        }
+        if let Some(transport) = transports.iter().find(|transport| transport.is_connected(&hash)) {
+            drop(pending);
+            return transport.send(peer, messages);
+        }
+
+        let connected: usize =
+            transports.iter().map(|transport| transport.connection_count()).sum();
+        if connected + pending.len() >= self.max_connections {
+            self.shared.notify(ConnectionEvent::Refused { peer: hash.clone() });
+            return Err(Error::Transport(format!("Not connecting to {:?}, already at the limit of \
+                                                 {} connections",
+                                                hash,
+                                                self.max_connections)));
+        }
+        let choice = match choose(&bids(&transports, peer, &hash)) {
+            Some(choice) => choice,
+            None => return Err(Error::Transport(format!("No transport can reach {:?}", hash))),
+        };
+        info!("Transports: connecting to {:?} with {}: {}",
+              hash,
+              choice.transport,
+              choice.reason);
+        let mut messages = messages;
+        limit_queue(&mut messages, &hash);
+        pending.insert(hash.clone(), messages);
+
+        let shared = self.shared.clone();
+        let transport = transports[choice.index].clone();
+        let peer = peer.clone();
+        let thread_hash = hash.clone();
+        let result = thread::Builder::new().name("transports connect".to_string()).spawn(move || {
+            connect(&shared, transport, &peer, thread_hash, choice)
+        });
+        if let Err(error) = result {
+            pending.remove(&hash);
+            return Err(Error::from(error));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.is_online
     }
 
     pub fn is_running(&self) -> bool {
-        true
+        !self.shared.transports.lock().unwrap().is_empty()
+    }
+
+    pub fn stop(&mut self) {
+        for transport in self.shared.transports.lock().unwrap().drain(..) {
+            transport.stop();
+        }
+    }
+}
+
+impl Default for Transports {
+    fn default() -> Transports {
+        Transports::new()
+    }
+}
+
+/// Drops the oldest messages queued for a router once there are too many
+fn limit_queue(queue: &mut Vec<I2NPMessage>, hash: &Hash) {
+    if queue.len() > MAX_PENDING_MESSAGES {
+        let dropped = queue.len() - MAX_PENDING_MESSAGES;
+        queue.drain(..dropped);
+        debug!("Transports: too many messages queued for {:?}, dropped the oldest {}",
+               hash,
+               dropped);
+    }
+}
+
+/// Connects to a router, then sends what's been queued for it until the
+/// queue is empty
+fn connect(shared: &Shared,
+           transport: Arc<dyn Transport>,
+           peer: &RouterInfo,
+           hash: Hash,
+           choice: Choice) {
+    let mut connected = false;
+    loop {
+        let messages = {
+            let mut pending = shared.pending.lock().unwrap();
+            let empty = pending.get(&hash).is_none_or(|queue| queue.is_empty());
+            if connected && empty {
+                pending.remove(&hash);
+                return;
+            }
+            match pending.get_mut(&hash) {
+                Some(queue) => std::mem::take(queue),
+                None => Vec::new(),
+            }
+        };
+
+        let count = messages.len();
+        if let Err(error) = transport.send(peer, messages) {
+            let dropped =
+                shared.pending.lock().unwrap().remove(&hash).map_or(0, |queue| queue.len());
+            warn!("Transports: {} couldn't send to {:?}, dropping {} messages: {}",
+                  transport.name(),
+                  hash,
+                  count + dropped,
+                  error);
+            if !connected {
+                shared.notify(ConnectionEvent::Failed {
+                    peer: hash,
+                    transport: transport.name(),
+                    error: error.to_string(),
+                });
+            }
+            return;
+        }
+
+        if !connected {
+            connected = true;
+            debug!("Transports: connected to {:?} with {}", hash, transport.name());
+            shared.notify(ConnectionEvent::Connected {
+                peer: hash.clone(),
+                choice: choice.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::{PublicKeyType, SigningPublicKeyType};
+    use i2p::data::date::Date;
+    use i2p::data::mapping::Mapping;
+    use i2p::data::private_keys::PrivateKeys;
+    use i2p::data::router_info::SupportedTransports;
+    use i2p::i2np::MessageBody;
+    use std::collections::HashSet;
+    use std::time::Duration;
+    use super::*;
+
+    /// A transport that reaches routers by publishing an address with its
+    /// style, and connects to them after a delay unless it's told to fail
+    struct TestTransport {
+        name: &'static str,
+        style: SupportedTransports,
+        connect_delay: Duration,
+        connected: Mutex<HashSet<Hash>>,
+        connects: Mutex<usize>,
+        sent: Mutex<Vec<(Hash, Vec<u8>)>>,
+    }
+
+    impl TestTransport {
+        fn new(name: &'static str, style: SupportedTransports) -> Arc<TestTransport> {
+            Arc::new(TestTransport {
+                name,
+                style,
+                connect_delay: Duration::from_millis(200),
+                connected: Mutex::new(HashSet::new()),
+                connects: Mutex::new(0),
+                sent: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn sent(&self) -> Vec<(Hash, Vec<u8>)> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    impl Transport for TestTransport {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn address(&self) -> RouterAddress {
+            address(self.style.clone(), 10)
+        }
+
+        fn set_router_info(&self, _: &RouterInfo) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn peer_address<'a>(&self, router_info: &'a RouterInfo) -> Option<&'a RouterAddress> {
+            router_info.addresses().iter().find(|address| address.transport_style == self.style)
+        }
+
+        fn is_connected(&self, peer: &Hash) -> bool {
+            self.connected.lock().unwrap().contains(peer)
+        }
+
+        fn connection_count(&self) -> usize {
+            self.connected.lock().unwrap().len()
+        }
+
+        fn send(&self, peer: &RouterInfo, messages: Vec<I2NPMessage>) -> Result<(), Error> {
+            let hash = peer.hash()?;
+            if !self.is_connected(&hash) {
+                thread::sleep(self.connect_delay);
+                if self.peer_address(peer).unwrap().options.get("fail").is_some() {
+                    return Err(Error::Transport("Connection refused".to_string()));
+                }
+                *self.connects.lock().unwrap() += 1;
+                self.connected.lock().unwrap().insert(hash.clone());
+            }
+            for message in messages {
+                match message.body {
+                    MessageBody::Data(data) => self.sent.lock().unwrap().push((hash.clone(), data)),
+                    body => panic!("Unexpected message {:?}", body),
+                }
+            }
+
+            Ok(())
+        }
+
+        fn stop(&self) {
+            self.connected.lock().unwrap().clear();
+        }
+    }
+
+    fn address(style: SupportedTransports, cost: u8) -> RouterAddress {
+        RouterAddress {
+            cost,
+            expiration: None,
+            transport_style: style,
+            options: Mapping::new(),
+        }
+    }
+
+    fn router_info(addresses: Vec<RouterAddress>) -> RouterInfo {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        RouterInfo::new(&keys, Date::now(), addresses, Mapping::new()).unwrap()
+    }
+
+    fn data(contents: &[u8]) -> Vec<I2NPMessage> {
+        vec![I2NPMessage::new(MessageBody::Data(contents.to_vec()))]
+    }
+
+    fn transports() -> (Transports, Arc<TestTransport>, Arc<TestTransport>) {
+        let transports = Transports::new();
+        let ntcp2 = TestTransport::new("NTCP2", SupportedTransports::NTCP2V4);
+        let ssu2 = TestTransport::new("SSU2", SupportedTransports::SSU2V4);
+        transports.add(ntcp2.clone());
+        transports.add(ssu2.clone());
+
+        (transports, ntcp2, ssu2)
+    }
+
+    fn next_event(events: &Receiver<ConnectionEvent>) -> ConnectionEvent {
+        events.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn test_choice() {
+        let (transports, ntcp2, _) = transports();
+
+        let both = router_info(vec![address(SupportedTransports::NTCP2V4, 10),
+                                    address(SupportedTransports::SSU2V4, 5)]);
+        let choice = transports.choose(&both).unwrap();
+        assert_eq!("SSU2", choice.transport);
+        assert_eq!("lowest published cost (NTCP2 cost 10, SSU2 cost 5)", choice.reason);
+
+        // Ties go to NTCP2
+        let tied = router_info(vec![address(SupportedTransports::SSU2V4, 5),
+                                    address(SupportedTransports::NTCP2V4, 5)]);
+        assert_eq!("NTCP2", transports.choose(&tied).unwrap().transport);
+
+        let ntcp_only = router_info(vec![address(SupportedTransports::NTCP2V4, 10),
+                                         address(SupportedTransports::SSU2V6, 5)]);
+        let choice = transports.choose(&ntcp_only).unwrap();
+        assert_eq!("NTCP2", choice.transport);
+        assert_eq!("the only reachable transport (NTCP2 cost 10, SSU2 unreachable)",
+                   choice.reason);
+
+        let unreachable = router_info(vec![address(SupportedTransports::NTCP2V6, 10)]);
+        assert!(transports.choose(&unreachable).is_err());
+        assert!(transports.send(&unreachable, data(b"hello")).is_err());
+
+        // A session we already have wins
+        ntcp2.connected.lock().unwrap().insert(both.hash().unwrap());
+        assert_eq!(vec![Bid {
+                            transport: "NTCP2",
+                            cost: Some(10),
+                            connected: true,
+                        },
+                        Bid {
+                            transport: "SSU2",
+                            cost: Some(5),
+                            connected: false,
+                        }],
+                   transports.bids(&both).unwrap());
+        let choice = transports.choose(&both).unwrap();
+        assert_eq!("NTCP2", choice.transport);
+        assert_eq!("already connected", choice.reason);
+    }
+
+    #[test]
+    fn test_queue_while_connecting() {
+        let (transports, ntcp2, ssu2) = transports();
+        let events = transports.subscribe();
+        let peer = router_info(vec![address(SupportedTransports::SSU2V4, 5)]);
+        let hash = peer.hash().unwrap();
+
+        transports.send(&peer, data(b"one")).unwrap();
+        transports.send(&peer, data(b"two")).unwrap();
+        transports.send(&peer, data(b"three")).unwrap();
+        assert_eq!(1, transports.connection_count());
+
+        match next_event(&events) {
+            ConnectionEvent::Connected { peer, choice } => {
+                assert_eq!(hash, peer);
+                assert_eq!("SSU2", choice.transport);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        while transports.shared.pending.lock().unwrap().contains_key(&hash) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        transports.send(&peer, data(b"four")).unwrap();
+
+        let sent: Vec<Vec<u8>> = ssu2.sent().into_iter().map(|(_, data)| data).collect();
+        assert_eq!(vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec(), b"four".to_vec()],
+                   sent);
+        assert_eq!(1, *ssu2.connects.lock().unwrap());
+        assert!(ntcp2.sent().is_empty());
+        assert_eq!(1, transports.connection_count());
     }
 
-    pub fn stop(&self) {
+    #[test]
+    fn test_queue_limit() {
+        let (transports, _, ssu2) = transports();
+        let events = transports.subscribe();
+        let peer = router_info(vec![address(SupportedTransports::SSU2V4, 5)]);
+        let hash = peer.hash().unwrap();
+
+        // Only the newest messages are queued while we connect
+        let messages: Vec<I2NPMessage> = (0..(MAX_PENDING_MESSAGES + 10))
+            .map(|i| I2NPMessage::new(MessageBody::Data(vec![i as u8])))
+            .collect();
+        transports.send(&peer, messages).unwrap();
+
+        match next_event(&events) {
+            ConnectionEvent::Connected { .. } => (),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        while transports.shared.pending.lock().unwrap().contains_key(&hash) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let sent: Vec<Vec<u8>> = ssu2.sent().into_iter().map(|(_, data)| data).collect();
+        let expected: Vec<Vec<u8>> =
+            (10..(MAX_PENDING_MESSAGES + 10)).map(|i| vec![i as u8]).collect();
+        assert_eq!(expected, sent);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_connection_limit() {
+        let (mut transports, ntcp2, _) = transports();
+        transports.max_connections = 1;
+        let events = transports.subscribe();
+        let first = router_info(vec![address(SupportedTransports::NTCP2V4, 10)]);
+        let second = router_info(vec![address(SupportedTransports::NTCP2V4, 10)]);
+
+        transports.send(&first, data(b"first")).unwrap();
+        // Connections being set up count towards the limit
+        assert!(transports.send(&second, data(b"second")).is_err());
+        assert_eq!(ConnectionEvent::Refused { peer: second.hash().unwrap() },
+                   next_event(&events));
+        match next_event(&events) {
+            ConnectionEvent::Connected { .. } => (),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        assert!(transports.send(&second, data(b"second")).is_err());
+        assert_eq!(ConnectionEvent::Refused { peer: second.hash().unwrap() },
+                   next_event(&events));
+
+        // Once the first session has closed there's room
+        while transports.shared.pending.lock().unwrap().contains_key(&first.hash().unwrap()) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        ntcp2.stop();
+        transports.send(&second, data(b"second")).unwrap();
+        match next_event(&events) {
+            ConnectionEvent::Connected { peer, .. } => assert_eq!(second.hash().unwrap(), peer),
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_failed_connection() {
+        let (transports, ntcp2, _) = transports();
+        let events = transports.subscribe();
+        let mut failing = address(SupportedTransports::NTCP2V4, 10);
+        failing.options.insert("fail", "true");
+        let peer = router_info(vec![failing]);
+
+        transports.send(&peer, data(b"one")).unwrap();
+        transports.send(&peer, data(b"two")).unwrap();
+        assert_eq!(ConnectionEvent::Failed {
+                       peer: peer.hash().unwrap(),
+                       transport: "NTCP2",
+                       error: Error::Transport("Connection refused".to_string()).to_string(),
+                   },
+                   next_event(&events));
+
+        // The queue is dropped, and the next message starts a new attempt
+        while transports.connection_count() > 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(ntcp2.sent().is_empty());
+        transports.send(&peer, data(b"three")).unwrap();
+        assert_eq!(1, transports.connection_count());
+    }
+}
@@ -0,0 +1,799 @@
+//! Tunnel build request records, and the layers of encryption they pick up
+//! on their way through a tunnel.
+//!
+//! The creator writes one record for each hop, encrypted to that hop's key:
+//! with ElGamal, or with a Noise N handshake for ECIES hops, whose records
+//! come in a long form and, in ShortTunnelBuild messages, a short one. Each
+//! hop finds its record by the start of its router hash, decrypts it, puts
+//! its reply in its place and encrypts every other record with its reply
+//! key. The creator strips the earlier hops' layers off each record in
+//! advance, so that every hop sees its own record as it was written, and
+//! strips the later hops' layers off the replies when the message comes
+//! back.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::crypto::{self, aes, chacha20, curve25519, elgamal};
+use i2p::crypto::noise::SymmetricState;
+use i2p::data::crypto::{Hash, PrivateKey, PublicKey, PublicKeyType};
+use i2p::data::date::Date;
+use i2p::data::mapping::Mapping;
+use i2p::error::Error;
+use i2p::i2np::{read_bytes, read_hash, write_hash};
+use i2p::i2np::tunnel_build::{BuildRecords, RecordLayout, MAX_RECORDS, RECORD_LENGTH};
+use rand::{thread_rng, OsRng, Rng};
+
+/// The hop is the inbound gateway: it gets messages from anywhere
+pub const FLAG_INBOUND_GATEWAY: u8 = 0x80;
+
+/// The hop is the outbound endpoint: it sends messages anywhere, and sends
+/// the build reply on to the reply tunnel
+pub const FLAG_OUTBOUND_ENDPOINT: u8 = 0x40;
+
+/// Reply codes. Anything other than zero is a rejection, and routers
+/// generally say it's bandwidth whatever the real reason.
+pub const REPLY_ACCEPT: u8 = 0;
+pub const REPLY_REJECT_PROBABILISTIC: u8 = 10;
+pub const REPLY_REJECT_TRANSIENT_OVERLOAD: u8 = 20;
+pub const REPLY_REJECT_BANDWIDTH: u8 = 30;
+pub const REPLY_REJECT_CRITICAL: u8 = 50;
+
+/// How long tunnels last, in seconds. ECIES requests say so; ElGamal ones
+/// can't, so their tunnels always last this long.
+pub const DEFAULT_TUNNEL_LIFETIME: u32 = 10 * 60;
+
+const PROTOCOL_NAME: &str = "Noise_N_25519_ChaChaPoly_SHA256";
+
+/// The start of the hop's router hash, which each record begins with
+const TO_PEER_LENGTH: usize = 16;
+
+const LONG_CLEARTEXT_LENGTH: usize = 464;
+
+const SHORT_CLEARTEXT_LENGTH: usize = 154;
+
+const LONG_REPLY_LENGTH: usize = 512;
+
+const SHORT_REPLY_LENGTH: usize = 202;
+
+const KEY_LENGTH: usize = 32;
+
+const IV_LENGTH: usize = 16;
+
+const MILLIS_PER_MINUTE: u64 = 60 * 1000;
+
+const MILLIS_PER_HOUR: u64 = 60 * MILLIS_PER_MINUTE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordFormat {
+    ElGamal,
+    /// ECIES records in TunnelBuild and VariableTunnelBuild messages
+    Long,
+    /// ECIES records in ShortTunnelBuild messages
+    Short,
+}
+
+impl RecordFormat {
+    /// Which format a hop with the given type of key gets in a message
+    pub fn new(key_type: PublicKeyType, layout: RecordLayout) -> Result<RecordFormat, Error> {
+        match (key_type, layout) {
+            (PublicKeyType::X25519, RecordLayout::Short) => Ok(RecordFormat::Short),
+            (PublicKeyType::X25519, _) => Ok(RecordFormat::Long),
+            (PublicKeyType::ElGamal, RecordLayout::Short) => {
+                Err(Error::Crypto("ElGamal hops can't be in short tunnel builds".to_string()))
+            }
+            (PublicKeyType::ElGamal, _) => Ok(RecordFormat::ElGamal),
+        }
+    }
+}
+
+/// What the creator asks of one hop
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuildRequest {
+    pub receive_tunnel: u32,
+    /// The hop's own router hash, which only ElGamal records carry
+    pub our_ident: Hash,
+    pub next_tunnel: u32,
+    pub next_ident: Hash,
+    pub layer_key: Vec<u8>,
+    pub iv_key: Vec<u8>,
+    pub reply_key: Vec<u8>,
+    /// Unused in short records, whose replies are encrypted with ChaCha20
+    pub reply_iv: Vec<u8>,
+    pub flags: u8,
+    /// Rounded down to the hour in ElGamal records and to the minute in
+    /// ECIES ones
+    pub request_time: Date,
+    /// Seconds after the request time that the tunnel expires
+    pub expiration: u32,
+    /// The ID for the message the hop sends on to the next one
+    pub send_message_id: u32,
+    pub options: Mapping,
+}
+
+impl BuildRequest {
+    /// A request with fresh random keys, made now
+    pub fn new(receive_tunnel: u32,
+               our_ident: Hash,
+               next_tunnel: u32,
+               next_ident: Hash,
+               flags: u8)
+               -> Result<BuildRequest, Error> {
+        let mut rng = OsRng::new()?;
+        let mut random_key = |length: usize| {
+            let mut key = vec![0u8; length];
+            rng.fill_bytes(&mut key);
+            key
+        };
+
+        Ok(BuildRequest {
+            receive_tunnel,
+            our_ident,
+            next_tunnel,
+            next_ident,
+            layer_key: random_key(KEY_LENGTH),
+            iv_key: random_key(KEY_LENGTH),
+            reply_key: random_key(KEY_LENGTH),
+            reply_iv: random_key(IV_LENGTH),
+            flags,
+            request_time: Date::now(),
+            expiration: DEFAULT_TUNNEL_LIFETIME,
+            send_message_id: thread_rng().gen(),
+            options: Mapping::new(),
+        })
+    }
+
+    pub fn is_inbound_gateway(&self) -> bool {
+        self.flags & FLAG_INBOUND_GATEWAY != 0
+    }
+
+    pub fn is_outbound_endpoint(&self) -> bool {
+        self.flags & FLAG_OUTBOUND_ENDPOINT != 0
+    }
+
+    fn check_keys(&self) -> Result<(), Error> {
+        if self.layer_key.len() != KEY_LENGTH || self.iv_key.len() != KEY_LENGTH ||
+           self.reply_key.len() != KEY_LENGTH || self.reply_iv.len() != IV_LENGTH {
+            return Err(Error::Crypto("Bad key length in build request".to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn write_keys(&self, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        self.check_keys()?;
+        buffer.extend_from_slice(&self.layer_key);
+        buffer.extend_from_slice(&self.iv_key);
+        buffer.extend_from_slice(&self.reply_key);
+        buffer.extend_from_slice(&self.reply_iv);
+
+        Ok(())
+    }
+
+    fn read_keys(&mut self, reader: &mut &[u8]) -> Result<(), Error> {
+        self.layer_key = read_bytes(reader, KEY_LENGTH)?.to_vec();
+        self.iv_key = read_bytes(reader, KEY_LENGTH)?.to_vec();
+        self.reply_key = read_bytes(reader, KEY_LENGTH)?.to_vec();
+        self.reply_iv = read_bytes(reader, IV_LENGTH)?.to_vec();
+
+        Ok(())
+    }
+
+    /// The options and random padding that finish off ECIES records
+    fn write_options(&self, buffer: &mut Vec<u8>, length: usize) -> Result<(), Error> {
+        self.options.serialize(buffer)?;
+        if buffer.len() > length {
+            return Err(Error::Serialization("Build request options too long".to_string()));
+        }
+        pad(buffer, length);
+
+        Ok(())
+    }
+
+    fn serialize_elgamal(&self) -> Result<Vec<u8>, Error> {
+        let mut buffer: Vec<u8> = Vec::with_capacity(elgamal::ELGAMAL_DATA_LENGTH);
+        buffer.write_u32::<BigEndian>(self.receive_tunnel)?;
+        write_hash(&mut buffer, &self.our_ident)?;
+        buffer.write_u32::<BigEndian>(self.next_tunnel)?;
+        write_hash(&mut buffer, &self.next_ident)?;
+        self.write_keys(&mut buffer)?;
+        buffer.write_u8(self.flags)?;
+        buffer.write_u32::<BigEndian>((self.request_time.millis() / MILLIS_PER_HOUR) as u32)?;
+        buffer.write_u32::<BigEndian>(self.send_message_id)?;
+        pad(&mut buffer, elgamal::ELGAMAL_DATA_LENGTH);
+
+        Ok(buffer)
+    }
+
+    fn parse_elgamal(data: &[u8]) -> Result<BuildRequest, Error> {
+        let mut reader = data;
+        let mut request = BuildRequest::empty(reader.read_u32::<BigEndian>()?,
+                                              read_hash(&mut reader)?);
+        request.next_tunnel = reader.read_u32::<BigEndian>()?;
+        request.next_ident = read_hash(&mut reader)?;
+        request.read_keys(&mut reader)?;
+        request.flags = reader.read_u8()?;
+        let hours = reader.read_u32::<BigEndian>()? as u64;
+        request.request_time = Date::from_millis(hours * MILLIS_PER_HOUR);
+        request.send_message_id = reader.read_u32::<BigEndian>()?;
+
+        Ok(request)
+    }
+
+    fn serialize_long(&self) -> Result<Vec<u8>, Error> {
+        let mut buffer: Vec<u8> = Vec::with_capacity(LONG_CLEARTEXT_LENGTH);
+        buffer.write_u32::<BigEndian>(self.receive_tunnel)?;
+        buffer.write_u32::<BigEndian>(self.next_tunnel)?;
+        write_hash(&mut buffer, &self.next_ident)?;
+        self.write_keys(&mut buffer)?;
+        buffer.write_u8(self.flags)?;
+        // More flags, all unused
+        buffer.extend_from_slice(&[0u8; 3]);
+        self.write_times(&mut buffer)?;
+        self.write_options(&mut buffer, LONG_CLEARTEXT_LENGTH)?;
+
+        Ok(buffer)
+    }
+
+    fn parse_long(data: &[u8], our_ident: &Hash) -> Result<BuildRequest, Error> {
+        let mut reader = data;
+        let mut request = BuildRequest::empty(reader.read_u32::<BigEndian>()?, our_ident.clone());
+        request.next_tunnel = reader.read_u32::<BigEndian>()?;
+        request.next_ident = read_hash(&mut reader)?;
+        request.read_keys(&mut reader)?;
+        request.flags = reader.read_u8()?;
+        read_bytes(&mut reader, 3)?;
+        request.read_times(&mut reader)?;
+        request.options = Mapping::deserialize(&mut reader)?;
+
+        Ok(request)
+    }
+
+    fn serialize_short(&self) -> Result<Vec<u8>, Error> {
+        let mut buffer: Vec<u8> = Vec::with_capacity(SHORT_CLEARTEXT_LENGTH);
+        buffer.write_u32::<BigEndian>(self.receive_tunnel)?;
+        buffer.write_u32::<BigEndian>(self.next_tunnel)?;
+        write_hash(&mut buffer, &self.next_ident)?;
+        buffer.write_u8(self.flags)?;
+        // More flags, then the layer encryption type, which is always AES
+        buffer.extend_from_slice(&[0u8; 3]);
+        self.write_times(&mut buffer)?;
+        self.write_options(&mut buffer, SHORT_CLEARTEXT_LENGTH)?;
+
+        Ok(buffer)
+    }
+
+    fn parse_short(data: &[u8], our_ident: &Hash) -> Result<BuildRequest, Error> {
+        let mut reader = data;
+        let mut request = BuildRequest::empty(reader.read_u32::<BigEndian>()?, our_ident.clone());
+        request.next_tunnel = reader.read_u32::<BigEndian>()?;
+        request.next_ident = read_hash(&mut reader)?;
+        request.flags = reader.read_u8()?;
+        let layer_encryption = read_bytes(&mut reader, 3)?[2];
+        if layer_encryption != 0 {
+            return Err(Error::Crypto(format!("Unknown tunnel layer encryption type {}",
+                                             layer_encryption)));
+        }
+        request.read_times(&mut reader)?;
+        request.options = Mapping::deserialize(&mut reader)?;
+
+        Ok(request)
+    }
+
+    fn write_times(&self, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        buffer.write_u32::<BigEndian>((self.request_time.millis() / MILLIS_PER_MINUTE) as u32)?;
+        buffer.write_u32::<BigEndian>(self.expiration)?;
+        buffer.write_u32::<BigEndian>(self.send_message_id)?;
+
+        Ok(())
+    }
+
+    fn read_times(&mut self, reader: &mut &[u8]) -> Result<(), Error> {
+        let minutes = reader.read_u32::<BigEndian>()? as u64;
+        self.request_time = Date::from_millis(minutes * MILLIS_PER_MINUTE);
+        self.expiration = reader.read_u32::<BigEndian>()?;
+        self.send_message_id = reader.read_u32::<BigEndian>()?;
+
+        Ok(())
+    }
+
+    /// Somewhere for the parsers to fill in
+    fn empty(receive_tunnel: u32, our_ident: Hash) -> BuildRequest {
+        BuildRequest {
+            receive_tunnel,
+            our_ident,
+            next_tunnel: 0,
+            next_ident: Hash::SHA256(vec![0u8; 32].into_boxed_slice()),
+            layer_key: Vec::new(),
+            iv_key: Vec::new(),
+            reply_key: Vec::new(),
+            reply_iv: Vec::new(),
+            flags: 0,
+            request_time: Date::null(),
+            expiration: DEFAULT_TUNNEL_LIFETIME,
+            send_message_id: 0,
+            options: Mapping::new(),
+        }
+    }
+
+    /// Short records don't carry keys: both ends derive them from the
+    /// handshake's chaining key instead
+    fn derive_short_keys(&mut self, chaining_key: &[u8]) -> Result<(), Error> {
+        let mut keydata = crypto::hkdf(chaining_key, &[], b"SMTunnelReplyKey", 64)?;
+        self.reply_key = keydata.split_off(KEY_LENGTH);
+        let mut keydata = crypto::hkdf(&keydata, &[], b"SMTunnelLayerKey", 64)?;
+        self.layer_key = keydata.split_off(KEY_LENGTH);
+        self.iv_key = if self.is_outbound_endpoint() {
+            crypto::hkdf(&keydata, &[], b"TunnelLayerIVKey", 64)?.split_off(KEY_LENGTH)
+        } else {
+            keydata
+        };
+        self.reply_iv = Vec::new();
+
+        Ok(())
+    }
+}
+
+/// Fills the rest of `buffer` up to `length` with random bytes
+fn pad(buffer: &mut Vec<u8>, length: usize) {
+    let start = buffer.len();
+    buffer.resize(length, 0);
+    thread_rng().fill_bytes(&mut buffer[start..]);
+}
+
+fn to_peer(ident: &Hash) -> &[u8] {
+    let Hash::SHA256(ref hash) = *ident;
+    &hash[..TO_PEER_LENGTH]
+}
+
+/// What both ends need to encrypt and decrypt one hop's reply and layers
+#[derive(Clone)]
+struct ReplyKeys {
+    format: RecordFormat,
+    reply_key: Vec<u8>,
+    reply_iv: Vec<u8>,
+    /// For ECIES records, the handshake state once the request was read:
+    /// the long reply is encrypted with the chaining key, and both kinds
+    /// are authenticated against the hash
+    chaining_key: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl ReplyKeys {
+    fn new(format: RecordFormat,
+           request: &BuildRequest,
+           state: Option<&SymmetricState>)
+           -> ReplyKeys {
+        ReplyKeys {
+            format,
+            reply_key: request.reply_key.clone(),
+            reply_iv: request.reply_iv.clone(),
+            chaining_key: state.map(|s| s.chaining_key().to_vec()).unwrap_or_default(),
+            hash: state.map(|s| s.hash().to_vec()).unwrap_or_default(),
+        }
+    }
+
+    /// The layer a hop adds to the record at `index` when it isn't its own
+    fn encrypt_layer(&self, index: usize, record: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.format {
+            RecordFormat::Short => {
+                chacha20::chacha20(&self.reply_key, &chacha20::nonce(index as u64), 1, record)
+            }
+            _ => aes::cbc_encrypt(&self.reply_key, &self.reply_iv, record),
+        }
+    }
+
+    fn decrypt_layer(&self, index: usize, record: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.format {
+            RecordFormat::Short => self.encrypt_layer(index, record),
+            _ => aes::cbc_decrypt(&self.reply_key, &self.reply_iv, record),
+        }
+    }
+
+    fn encrypt_reply(&self, index: usize, options: &Mapping, reply: u8) -> Result<Vec<u8>, Error> {
+        match self.format {
+            RecordFormat::ElGamal => {
+                // The hash covers the padding and the reply code
+                let mut rest: Vec<u8> = Vec::with_capacity(RECORD_LENGTH - 32);
+                pad(&mut rest, RECORD_LENGTH - 33);
+                rest.push(reply);
+                let mut cleartext = crypto::sha256(&rest);
+                cleartext.extend(rest);
+                aes::cbc_encrypt(&self.reply_key, &self.reply_iv, &cleartext)
+            }
+            RecordFormat::Long => {
+                let cleartext = reply_cleartext(options, reply, LONG_REPLY_LENGTH)?;
+                chacha20::aead_encrypt(&self.chaining_key,
+                                       &chacha20::nonce(0),
+                                       &self.hash,
+                                       &cleartext)
+            }
+            RecordFormat::Short => {
+                let cleartext = reply_cleartext(options, reply, SHORT_REPLY_LENGTH)?;
+                chacha20::aead_encrypt(&self.reply_key,
+                                       &chacha20::nonce(index as u64),
+                                       &self.hash,
+                                       &cleartext)
+            }
+        }
+    }
+
+    /// Decrypts a hop's reply, once the later hops' layers are off it, and
+    /// returns its reply code
+    fn decrypt_reply(&self, index: usize, record: &[u8]) -> Result<u8, Error> {
+        let cleartext = match self.format {
+            RecordFormat::ElGamal => {
+                let cleartext = aes::cbc_decrypt(&self.reply_key, &self.reply_iv, record)?;
+                let (hash, rest) = cleartext.split_at(32);
+                if crypto::sha256(rest) != hash {
+                    return Err(Error::Crypto("Bad hash in tunnel build reply".to_string()));
+                }
+                cleartext
+            }
+            RecordFormat::Long => {
+                chacha20::aead_decrypt(&self.chaining_key,
+                                       &chacha20::nonce(0),
+                                       &self.hash,
+                                       record)?
+            }
+            RecordFormat::Short => {
+                chacha20::aead_decrypt(&self.reply_key,
+                                       &chacha20::nonce(index as u64),
+                                       &self.hash,
+                                       record)?
+            }
+        };
+
+        Ok(cleartext[cleartext.len() - 1])
+    }
+}
+
+/// ECIES replies are options and padding, with the reply code last
+fn reply_cleartext(options: &Mapping, reply: u8, length: usize) -> Result<Vec<u8>, Error> {
+    let mut cleartext: Vec<u8> = Vec::with_capacity(length);
+    options.serialize(&mut cleartext)?;
+    if cleartext.len() >= length {
+        return Err(Error::Serialization("Build reply options too long".to_string()));
+    }
+    pad(&mut cleartext, length - 1);
+    cleartext.push(reply);
+
+    Ok(cleartext)
+}
+
+/// The Noise N handshake's state once both public keys are in, and the key
+/// for the request
+fn handshake(hop_public_key: &[u8],
+             ephemeral_public_key: &[u8],
+             shared_secret: &[u8])
+             -> Result<(SymmetricState, Vec<u8>), Error> {
+    let mut state = SymmetricState::new(PROTOCOL_NAME);
+    state.mix_hash(hop_public_key);
+    state.mix_hash(ephemeral_public_key);
+    let key = state.mix_key(shared_secret)?;
+
+    Ok((state, key))
+}
+
+/// A hop the creator is asking to join a tunnel
+#[derive(Clone, Debug)]
+pub struct HopConfig {
+    pub ident: Hash,
+    /// The hop's encryption key from its RouterInfo, which decides the
+    /// record format
+    pub encryption_key: PublicKey,
+    pub request: BuildRequest,
+}
+
+impl HopConfig {
+    fn encrypt_request(&mut self, layout: RecordLayout) -> Result<(Vec<u8>, ReplyKeys), Error> {
+        let format = RecordFormat::new(self.encryption_key.get_type(), layout)?;
+        let mut record = to_peer(&self.ident).to_vec();
+        if format == RecordFormat::ElGamal {
+            let cleartext = self.request.serialize_elgamal()?;
+            record.extend(elgamal::encrypt(self.encryption_key.data(), &cleartext, false)?);
+            return Ok((record, ReplyKeys::new(format, &self.request, None)));
+        }
+
+        let mut ephemeral_private_key = vec![0u8; curve25519::KEY_LENGTH];
+        OsRng::new()?.fill_bytes(&mut ephemeral_private_key);
+        let ephemeral_public_key = curve25519::x25519_public_key(&ephemeral_private_key)?;
+        let shared_secret = curve25519::x25519(&ephemeral_private_key,
+                                               self.encryption_key.data())?;
+        let (mut state, key) = handshake(self.encryption_key.data(),
+                                         &ephemeral_public_key,
+                                         &shared_secret)?;
+        let cleartext = if format == RecordFormat::Short {
+            self.request.derive_short_keys(state.chaining_key())?;
+            self.request.serialize_short()?
+        } else {
+            self.request.serialize_long()?
+        };
+        record.extend(ephemeral_public_key);
+        record.extend(state.encrypt_and_hash(&key, 0, &cleartext)?);
+
+        Ok((record, ReplyKeys::new(format, &self.request, Some(&state))))
+    }
+}
+
+/// The creator's side of a build that's been sent, for reading the replies
+pub struct PendingBuild {
+    /// Where each hop's record is, and its keys, in tunnel order
+    hops: Vec<(usize, ReplyKeys)>,
+    record_count: usize,
+}
+
+impl PendingBuild {
+    /// Writes the records for a build message, one for each hop in tunnel
+    /// order, at random places among `record_count` records. The rest are
+    /// random filler. Short records' keys come from the handshake, so for
+    /// those the hops' requests get their keys filled in here.
+    pub fn new(hops: &mut [HopConfig],
+               layout: RecordLayout,
+               record_count: usize)
+               -> Result<(PendingBuild, BuildRecords), Error> {
+        let count_ok = match layout {
+            RecordLayout::Fixed => record_count == MAX_RECORDS,
+            _ => record_count <= MAX_RECORDS,
+        };
+        if hops.is_empty() || hops.len() > record_count || !count_ok {
+            return Err(Error::Serialization(format!("Can't build {} hops in {} {:?} records",
+                                                    hops.len(),
+                                                    record_count,
+                                                    layout)));
+        }
+
+        let mut positions: Vec<usize> = (0..record_count).collect();
+        thread_rng().shuffle(&mut positions);
+        let mut records: Vec<Vec<u8>> = Vec::with_capacity(record_count);
+        for _ in 0..record_count {
+            let mut record = Vec::new();
+            pad(&mut record, layout.record_length());
+            records.push(record);
+        }
+
+        let mut pending: Vec<(usize, ReplyKeys)> = Vec::with_capacity(hops.len());
+        for (hop, &index) in hops.iter_mut().zip(positions.iter()) {
+            let (mut record, keys) = hop.encrypt_request(layout)?;
+            // Undo the layers the hops before this one will add
+            for (_, earlier) in pending.iter().rev() {
+                record = earlier.decrypt_layer(index, &record)?;
+            }
+            records[index] = record;
+            pending.push((index, keys));
+        }
+
+        let pending = PendingBuild {
+            hops: pending,
+            record_count,
+        };
+
+        Ok((pending, BuildRecords { records }))
+    }
+
+    /// Reads the reply codes from the returned records, in tunnel order
+    pub fn decrypt_replies(&self, records: &BuildRecords) -> Result<Vec<u8>, Error> {
+        if records.records.len() != self.record_count {
+            return Err(Error::Crypto(format!("Expected {} build reply records, got {}",
+                                             self.record_count,
+                                             records.records.len())));
+        }
+
+        let mut replies: Vec<u8> = Vec::with_capacity(self.hops.len());
+        for (hop, &(index, ref keys)) in self.hops.iter().enumerate() {
+            let mut record = records.records[index].clone();
+            for (_, later) in self.hops[hop + 1..].iter().rev() {
+                record = later.decrypt_layer(index, &record)?;
+            }
+            replies.push(keys.decrypt_reply(index, &record)?);
+        }
+
+        Ok(replies)
+    }
+}
+
+/// A hop's side of a build: the request it found for itself
+pub struct ReceivedRequest {
+    pub request: BuildRequest,
+    /// Where our record is in the message
+    pub index: usize,
+    keys: ReplyKeys,
+}
+
+impl ReceivedRequest {
+    /// Finds our record in a build message and decrypts it
+    pub fn decrypt(records: &BuildRecords,
+                   layout: RecordLayout,
+                   ident: &Hash,
+                   private_key: &PrivateKey)
+                   -> Result<ReceivedRequest, Error> {
+        let format = RecordFormat::new(private_key.get_type(), layout)?;
+        let to_peer = to_peer(ident);
+        let index = match records.records.iter().position(|record| record.starts_with(to_peer)) {
+            Some(index) => index,
+            None => return Err(Error::Crypto("No build record for us".to_string())),
+        };
+        let mut encrypted = &records.records[index][TO_PEER_LENGTH..];
+
+        if format == RecordFormat::ElGamal {
+            let cleartext = elgamal::decrypt(private_key.data(), encrypted, false)?;
+            let request = BuildRequest::parse_elgamal(&cleartext)?;
+            if request.our_ident != *ident {
+                return Err(Error::Crypto("Build record isn't for us".to_string()));
+            }
+            let keys = ReplyKeys::new(format, &request, None);
+            return Ok(ReceivedRequest {
+                request,
+                index,
+                keys,
+            });
+        }
+
+        let ephemeral_public_key = read_bytes(&mut encrypted, curve25519::KEY_LENGTH)?;
+        let shared_secret = curve25519::x25519(private_key.data(), ephemeral_public_key)?;
+        let (mut state, key) = handshake(&curve25519::x25519_public_key(private_key.data())?,
+                                         ephemeral_public_key,
+                                         &shared_secret)?;
+        let cleartext = state.decrypt_and_hash(&key, 0, encrypted)?;
+        let request = if format == RecordFormat::Short {
+            let mut request = BuildRequest::parse_short(&cleartext, ident)?;
+            request.derive_short_keys(state.chaining_key())?;
+            request
+        } else {
+            BuildRequest::parse_long(&cleartext, ident)?
+        };
+        let keys = ReplyKeys::new(format, &request, Some(&state));
+
+        Ok(ReceivedRequest {
+            request,
+            index,
+            keys,
+        })
+    }
+
+    /// Replaces our record with our reply and adds our layer to every other
+    /// record, ready to send on to the next hop
+    pub fn encrypt_reply(&self, records: &mut BuildRecords, reply: u8) -> Result<(), Error> {
+        for (index, record) in records.records.iter_mut().enumerate() {
+            *record = if index == self.index {
+                self.keys.encrypt_reply(index, &Mapping::new(), reply)?
+            } else {
+                self.keys.encrypt_layer(index, record)?
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::i2np::tunnel_build::{RECORD_LENGTH, SHORT_RECORD_LENGTH};
+    use i2p::test_util::random_hash;
+    use super::*;
+
+    /// A hop's router hash and private key, and the creator's config for it
+    fn hop(elgamal_keys: bool, receive_tunnel: u32) -> (Hash, PrivateKey, HopConfig) {
+        let ident = random_hash();
+        let (private_key, public_key) = if elgamal_keys {
+            let (private_key, public_key) = elgamal::generate_keypair().unwrap();
+            (PrivateKey::ElGamal(private_key.into_boxed_slice()),
+             PublicKey::ElGamal(public_key.into_boxed_slice()))
+        } else {
+            let mut private_key = vec![0u8; 32];
+            thread_rng().fill_bytes(&mut private_key);
+            let public_key = curve25519::x25519_public_key(&private_key).unwrap();
+            (PrivateKey::X25519(private_key.into_boxed_slice()),
+             PublicKey::X25519(public_key.into_boxed_slice()))
+        };
+        let mut request = BuildRequest::new(receive_tunnel,
+                                            ident.clone(),
+                                            receive_tunnel + 1,
+                                            random_hash(),
+                                            0)
+            .unwrap();
+        // On the hour, so that it survives being rounded in every format
+        request.request_time = Date::from_millis(1490000400000);
+        request.options.insert("k", "v");
+        let config = HopConfig {
+            ident: ident.clone(),
+            encryption_key: public_key,
+            request,
+        };
+
+        (ident, private_key, config)
+    }
+
+    /// Passes the records down the tunnel, each hop checking its request
+    /// and replying with the given code
+    fn build(hops: &[(Hash, PrivateKey)],
+             configs: &[HopConfig],
+             layout: RecordLayout,
+             records: &mut BuildRecords,
+             replies: &[u8]) {
+        for (i, (ident, private_key)) in hops.iter().enumerate() {
+            let received = ReceivedRequest::decrypt(records, layout, ident, private_key).unwrap();
+            let mut expected = configs[i].request.clone();
+            if RecordFormat::new(private_key.get_type(), layout).unwrap() == RecordFormat::ElGamal {
+                expected.options = Mapping::new();
+            }
+            assert_eq!(expected, received.request);
+            received.encrypt_reply(records, replies[i]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_short_build() {
+        let mut hops: Vec<(Hash, PrivateKey)> = Vec::new();
+        let mut configs: Vec<HopConfig> = Vec::new();
+        for i in 0..3 {
+            let (ident, private_key, config) = hop(false, 100 * (i + 1));
+            hops.push((ident, private_key));
+            configs.push(config);
+        }
+        configs[2].request.flags = FLAG_OUTBOUND_ENDPOINT;
+
+        let (pending, mut records) = PendingBuild::new(&mut configs, RecordLayout::Short, 4)
+            .unwrap();
+        assert_eq!(4, records.records.len());
+        assert!(records.records.iter().all(|record| record.len() == SHORT_RECORD_LENGTH));
+        // The keys came from the handshakes
+        assert_eq!(32, configs[0].request.layer_key.len());
+        assert!(configs[0].request.reply_iv.is_empty());
+
+        let replies = [REPLY_ACCEPT, REPLY_REJECT_BANDWIDTH, REPLY_ACCEPT];
+        build(&hops, &configs, RecordLayout::Short, &mut records, &replies);
+        assert_eq!(replies.to_vec(), pending.decrypt_replies(&records).unwrap());
+    }
+
+    #[test]
+    fn test_variable_build() {
+        let mut hops: Vec<(Hash, PrivateKey)> = Vec::new();
+        let mut configs: Vec<HopConfig> = Vec::new();
+        for (i, &elgamal_keys) in [false, true, false].iter().enumerate() {
+            let (ident, private_key, config) = hop(elgamal_keys, 100 * (i as u32 + 1));
+            hops.push((ident, private_key));
+            configs.push(config);
+        }
+        configs[0].request.flags = FLAG_INBOUND_GATEWAY;
+
+        let (pending, mut records) = PendingBuild::new(&mut configs, RecordLayout::Variable, 3)
+            .unwrap();
+        assert!(records.records.iter().all(|record| record.len() == RECORD_LENGTH));
+
+        let replies = [REPLY_ACCEPT, REPLY_ACCEPT, REPLY_REJECT_CRITICAL];
+        build(&hops, &configs, RecordLayout::Variable, &mut records, &replies);
+        assert_eq!(replies.to_vec(), pending.decrypt_replies(&records).unwrap());
+    }
+
+    #[test]
+    fn test_bad_records() {
+        let (ident, private_key, config) = hop(false, 100);
+        let mut configs = vec![config];
+        let (pending, mut records) = PendingBuild::new(&mut configs, RecordLayout::Short, 2)
+            .unwrap();
+
+        // Nothing for a router that isn't in the tunnel
+        let (other_ident, other_key, _) = hop(false, 200);
+        assert!(ReceivedRequest::decrypt(&records, RecordLayout::Short, &other_ident, &other_key)
+            .is_err());
+
+        // Tampered records and replies don't authenticate
+        let mut tampered = records.clone();
+        for record in &mut tampered.records {
+            record[100] ^= 1;
+        }
+        assert!(ReceivedRequest::decrypt(&tampered, RecordLayout::Short, &ident, &private_key)
+            .is_err());
+
+        let received = ReceivedRequest::decrypt(&records, RecordLayout::Short, &ident, &private_key)
+            .unwrap();
+        received.encrypt_reply(&mut records, REPLY_ACCEPT).unwrap();
+        records.records[received.index][0] ^= 1;
+        assert!(pending.decrypt_replies(&records).is_err());
+
+        // ElGamal hops can't be in short builds, and the records have to fit
+        let (_, _, config) = hop(true, 300);
+        assert!(PendingBuild::new(&mut [config], RecordLayout::Short, 1).is_err());
+        assert!(PendingBuild::new(&mut configs, RecordLayout::Fixed, 4).is_err());
+        assert!(PendingBuild::new(&mut configs, RecordLayout::Variable, 9).is_err());
+    }
+}
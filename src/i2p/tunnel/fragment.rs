@@ -0,0 +1,614 @@
+//! Splitting I2NP messages into tunnel messages, and putting them back
+//! together.
+//!
+//! Every tunnel message is 1024 bytes in the clear: a 16-byte IV, the first
+//! four bytes of the SHA-256 of everything after the padding followed by the
+//! IV, nonzero padding, a zero byte, and then one or more fragments. A
+//! message that fits goes in whole; one that doesn't is split, with its
+//! first fragment saying where it's going and up to 63 follow-on fragments
+//! numbered after it. The gateway fragments and the endpoint reassembles,
+//! which means holding on to partial messages until the rest arrives or
+//! they time out, and ignoring fragments it's already seen.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::crypto;
+use i2p::data::crypto::Hash;
+use i2p::data::date::Date;
+use i2p::error::Error;
+use i2p::i2np::{read_bytes, read_hash, write_hash, I2NPMessage};
+use i2p::i2np::tunnel::TUNNEL_DATA_LENGTH;
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+const IV_LENGTH: usize = 16;
+
+const CHECKSUM_LENGTH: usize = 4;
+
+/// Room for fragments in a tunnel message with no padding
+const MAX_PAYLOAD_LENGTH: usize = TUNNEL_DATA_LENGTH - IV_LENGTH - CHECKSUM_LENGTH - 1;
+
+/// The first fragment and 63 follow-on fragments
+pub const MAX_FRAGMENTS: usize = 64;
+
+/// The flag byte, message ID and size
+const FOLLOW_ON_HEADER_LENGTH: usize = 7;
+
+const FLAG_FOLLOW_ON: u8 = 0x80;
+const FLAG_DELAY: u8 = 0x10;
+const FLAG_FRAGMENTED: u8 = 0x08;
+const FLAG_EXTENDED_OPTIONS: u8 = 0x04;
+const FLAG_LAST_FRAGMENT: u8 = 0x01;
+
+const DELIVERY_LOCAL: u8 = 0;
+const DELIVERY_TUNNEL: u8 = 1;
+const DELIVERY_ROUTER: u8 = 2;
+
+/// How long the endpoint waits for the rest of a message, in milliseconds
+pub const REASSEMBLY_TIMEOUT: u64 = 45 * 1000;
+
+/// How many partial messages the endpoint holds on to. Past this the
+/// oldest is dropped, so a gateway sending first fragments that never get
+/// finished can't use up our memory.
+const MAX_PARTIAL_MESSAGES: usize = 100;
+
+/// Where the endpoint sends a message
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeliveryInstructions {
+    /// To the endpoint itself. Inbound tunnels' messages are always local,
+    /// since the endpoint is the tunnel's creator.
+    Local,
+    /// To a router's gateway for a tunnel, in a TunnelGateway
+    Tunnel(Hash, u32),
+    /// Straight to a router
+    Router(Hash),
+}
+
+impl DeliveryInstructions {
+    /// How long a first fragment's header is with these instructions
+    fn header_length(&self, fragmented: bool) -> usize {
+        let to = match *self {
+            DeliveryInstructions::Local => 0,
+            DeliveryInstructions::Tunnel(..) => 4 + 32,
+            DeliveryInstructions::Router(_) => 32,
+        };
+        let message_id = if fragmented { 4 } else { 0 };
+
+        1 + to + message_id + 2
+    }
+
+    fn write_header(&self,
+                    writer: &mut Vec<u8>,
+                    fragmented: Option<u32>,
+                    size: usize)
+                    -> Result<(), Error> {
+        let delivery = match *self {
+            DeliveryInstructions::Local => DELIVERY_LOCAL,
+            DeliveryInstructions::Tunnel(..) => DELIVERY_TUNNEL,
+            DeliveryInstructions::Router(_) => DELIVERY_ROUTER,
+        };
+        let flags = if fragmented.is_some() {
+            FLAG_FRAGMENTED
+        } else {
+            0
+        };
+        writer.write_u8(delivery << 5 | flags)?;
+        match *self {
+            DeliveryInstructions::Local => {}
+            DeliveryInstructions::Tunnel(ref gateway, tunnel_id) => {
+                writer.write_u32::<BigEndian>(tunnel_id)?;
+                write_hash(writer, gateway)?;
+            }
+            DeliveryInstructions::Router(ref to) => {
+                write_hash(writer, to)?;
+            }
+        }
+        if let Some(message_id) = fragmented {
+            writer.write_u32::<BigEndian>(message_id)?;
+        }
+        writer.write_u16::<BigEndian>(size as u16)?;
+
+        Ok(())
+    }
+}
+
+/// Pads the fragments out into a tunnel message, IV and checksum first
+fn tunnel_message(payload: &[u8]) -> Vec<u8> {
+    let mut iv = [0u8; IV_LENGTH];
+    thread_rng().fill_bytes(&mut iv);
+    let mut checked = payload.to_vec();
+    checked.extend_from_slice(&iv);
+    let checksum = crypto::sha256(&checked);
+
+    let mut message = Vec::with_capacity(TUNNEL_DATA_LENGTH);
+    message.extend_from_slice(&iv);
+    message.extend_from_slice(&checksum[..CHECKSUM_LENGTH]);
+    for _ in 0..(MAX_PAYLOAD_LENGTH - payload.len()) {
+        message.push(thread_rng().gen_range(1, 256) as u8);
+    }
+    message.push(0);
+    message.extend_from_slice(payload);
+
+    message
+}
+
+/// Packs messages into as few tunnel messages as they'll fit in, in the
+/// clear. This is the gateway's half of the work.
+pub fn fragment(messages: &[(DeliveryInstructions, I2NPMessage)]) -> Result<Vec<Vec<u8>>, Error> {
+    let mut tunnel_messages: Vec<Vec<u8>> = Vec::new();
+    let mut payload: Vec<u8> = Vec::with_capacity(MAX_PAYLOAD_LENGTH);
+    for (instructions, message) in messages {
+        let mut data: Vec<u8> = Vec::new();
+        message.serialize(&mut data)?;
+
+        let mut offset = 0;
+        let mut number = 0;
+        while offset < data.len() {
+            let space = MAX_PAYLOAD_LENGTH - payload.len();
+            let remaining = data.len() - offset;
+            let size = if number == 0 {
+                let header_length = instructions.header_length(false);
+                if header_length + remaining <= space {
+                    instructions.write_header(&mut payload, None, remaining)?;
+                    remaining
+                } else if instructions.header_length(true) < space {
+                    let size = space - instructions.header_length(true);
+                    instructions.write_header(&mut payload, Some(message.message_id), size)?;
+                    size
+                } else {
+                    tunnel_messages.push(tunnel_message(&payload));
+                    payload.clear();
+                    continue;
+                }
+            } else {
+                if number >= MAX_FRAGMENTS {
+                    return Err(Error::Tunnel(format!("Message {} is too big for a tunnel: {} \
+                                                      bytes",
+                                                     message.message_id,
+                                                     data.len())));
+                }
+                if FOLLOW_ON_HEADER_LENGTH >= space {
+                    tunnel_messages.push(tunnel_message(&payload));
+                    payload.clear();
+                    continue;
+                }
+                let size = remaining.min(space - FOLLOW_ON_HEADER_LENGTH);
+                let last = if size == remaining {
+                    FLAG_LAST_FRAGMENT
+                } else {
+                    0
+                };
+                payload.write_u8(FLAG_FOLLOW_ON | (number as u8) << 1 | last)?;
+                payload.write_u32::<BigEndian>(message.message_id)?;
+                payload.write_u16::<BigEndian>(size as u16)?;
+                size
+            };
+            payload.extend_from_slice(&data[offset..offset + size]);
+            offset += size;
+            number += 1;
+        }
+    }
+    if !payload.is_empty() {
+        tunnel_messages.push(tunnel_message(&payload));
+    }
+
+    Ok(tunnel_messages)
+}
+
+struct Partial {
+    /// From the first fragment, once it's here
+    instructions: Option<DeliveryInstructions>,
+    fragments: Vec<Option<Vec<u8>>>,
+    /// The last fragment's number, once it's here
+    last: Option<usize>,
+    started: Date,
+}
+
+impl Partial {
+    /// The whole message, once every fragment is here
+    fn complete(&self) -> Option<Vec<u8>> {
+        let last = match (&self.instructions, self.last) {
+            (&Some(_), Some(last)) => last,
+            _ => return None,
+        };
+        let mut data: Vec<u8> = Vec::new();
+        for fragment in &self.fragments[..last + 1] {
+            match *fragment {
+                Some(ref fragment) => data.extend_from_slice(fragment),
+                None => return None,
+            }
+        }
+
+        Some(data)
+    }
+}
+
+/// Puts the messages coming out of one tunnel back together. This is the
+/// endpoint's half of the work.
+pub struct Reassembler {
+    partial: HashMap<u32, Partial>,
+    /// Messages we've finished with, by message ID, and when, so fragments
+    /// that turn up again are ignored
+    done: HashMap<u32, Date>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler {
+            partial: HashMap::new(),
+            done: HashMap::new(),
+        }
+    }
+
+    /// How many messages we're waiting on the rest of
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Checks a tunnel message in the clear and reads its fragments,
+    /// returning the messages they complete
+    pub fn receive(&mut self,
+                   tunnel_message: &[u8],
+                   now: Date)
+                   -> Result<Vec<(DeliveryInstructions, I2NPMessage)>, Error> {
+        if tunnel_message.len() != TUNNEL_DATA_LENGTH {
+            return Err(Error::Tunnel(format!("Tunnel message must be {} bytes, not {}",
+                                             TUNNEL_DATA_LENGTH,
+                                             tunnel_message.len())));
+        }
+        let start = IV_LENGTH + CHECKSUM_LENGTH;
+        let zero = match tunnel_message[start..].iter().position(|&byte| byte == 0) {
+            Some(zero) => start + zero,
+            None => return Err(Error::Tunnel("Tunnel message has no fragments".to_string())),
+        };
+        let payload = &tunnel_message[zero + 1..];
+        let mut checked = payload.to_vec();
+        checked.extend_from_slice(&tunnel_message[..IV_LENGTH]);
+        if crypto::sha256(&checked)[..CHECKSUM_LENGTH] != tunnel_message[IV_LENGTH..start] {
+            return Err(Error::Tunnel("Bad tunnel message checksum".to_string()));
+        }
+
+        let mut messages: Vec<(DeliveryInstructions, I2NPMessage)> = Vec::new();
+        let mut reader = payload;
+        while !reader.is_empty() {
+            let flag = reader.read_u8()?;
+            if flag & FLAG_FOLLOW_ON != 0 {
+                let number = (flag >> 1 & 0x3f) as usize;
+                let message_id = reader.read_u32::<BigEndian>()?;
+                let size = reader.read_u16::<BigEndian>()? as usize;
+                let data = read_bytes(&mut reader, size)?;
+                let last = flag & FLAG_LAST_FRAGMENT != 0;
+                if let Some(message) = self.add(message_id, number, None, last, data, now)? {
+                    messages.push(message);
+                }
+                continue;
+            }
+
+            if flag & FLAG_DELAY != 0 {
+                return Err(Error::Tunnel("Delayed delivery isn't supported".to_string()));
+            }
+            let instructions = match flag >> 5 & 0x03 {
+                DELIVERY_LOCAL => DeliveryInstructions::Local,
+                DELIVERY_TUNNEL => {
+                    let tunnel_id = reader.read_u32::<BigEndian>()?;
+                    DeliveryInstructions::Tunnel(read_hash(&mut reader)?, tunnel_id)
+                }
+                DELIVERY_ROUTER => DeliveryInstructions::Router(read_hash(&mut reader)?),
+                delivery => {
+                    return Err(Error::Tunnel(format!("Unknown delivery type {}", delivery)))
+                }
+            };
+            let message_id = if flag & FLAG_FRAGMENTED != 0 {
+                Some(reader.read_u32::<BigEndian>()?)
+            } else {
+                None
+            };
+            if flag & FLAG_EXTENDED_OPTIONS != 0 {
+                let length = reader.read_u8()? as usize;
+                read_bytes(&mut reader, length)?;
+            }
+            let size = reader.read_u16::<BigEndian>()? as usize;
+            let data = read_bytes(&mut reader, size)?;
+            match message_id {
+                Some(message_id) => {
+                    if let Some(message) =
+                        self.add(message_id, 0, Some(instructions), false, data, now)? {
+                        messages.push(message);
+                    }
+                }
+                None => {
+                    let message = I2NPMessage::deserialize(&mut &data[..])?;
+                    if let Entry::Vacant(entry) = self.done.entry(message.message_id) {
+                        entry.insert(now);
+                        messages.push((instructions, message));
+                    }
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Adds a fragment to its message, returning the message if that was
+    /// the last one missing
+    fn add(&mut self,
+           message_id: u32,
+           number: usize,
+           instructions: Option<DeliveryInstructions>,
+           last: bool,
+           data: &[u8],
+           now: Date)
+           -> Result<Option<(DeliveryInstructions, I2NPMessage)>, Error> {
+        if self.done.contains_key(&message_id) {
+            return Ok(None);
+        }
+        if !self.partial.contains_key(&message_id) &&
+           self.partial.len() >= MAX_PARTIAL_MESSAGES {
+            self.drop_oldest(now);
+        }
+        let complete = {
+            let partial = self.partial.entry(message_id).or_insert_with(|| {
+                Partial {
+                    instructions: None,
+                    fragments: vec![None; MAX_FRAGMENTS],
+                    last: None,
+                    started: now,
+                }
+            });
+            if partial.fragments[number].is_some() {
+                return Ok(None);
+            }
+            if last {
+                if partial.last.is_some() ||
+                   partial.fragments[number + 1..].iter().any(|fragment| fragment.is_some()) {
+                    return Err(Error::Tunnel(format!("Message {} has fragments after its last",
+                                                     message_id)));
+                }
+                partial.last = Some(number);
+            } else if partial.last.is_some_and(|last| number > last) {
+                return Err(Error::Tunnel(format!("Message {} has fragments after its last",
+                                                 message_id)));
+            }
+            if instructions.is_some() {
+                partial.instructions = instructions;
+            }
+            partial.fragments[number] = Some(data.to_vec());
+
+            partial.complete()
+        };
+        let data = match complete {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        let partial = self.partial.remove(&message_id).unwrap();
+        self.done.insert(message_id, now);
+        let message = I2NPMessage::deserialize(&mut &data[..])?;
+        Ok(Some((partial.instructions.unwrap(), message)))
+    }
+
+    fn drop_oldest(&mut self, now: Date) {
+        let oldest = self.partial
+            .iter()
+            .min_by_key(|&(_, partial)| partial.started)
+            .map(|(&message_id, _)| message_id);
+        if let Some(message_id) = oldest {
+            debug!("Tunnels: too many partial messages, dropping message {}", message_id);
+            self.partial.remove(&message_id);
+            self.done.insert(message_id, now);
+        }
+    }
+
+    /// Gives up on messages that have been waiting too long for the rest of
+    /// their fragments, returning how many
+    pub fn expire(&mut self, now: Date) -> usize {
+        let before = self.partial.len();
+        let done = &mut self.done;
+        self.partial.retain(|&message_id, partial| {
+            if partial.started.millis() + REASSEMBLY_TIMEOUT > now.millis() {
+                return true;
+            }
+            done.insert(message_id, now);
+            false
+        });
+        done.retain(|_, &mut finished| finished.millis() + REASSEMBLY_TIMEOUT > now.millis());
+
+        before - self.partial.len()
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Reassembler {
+        Reassembler::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::i2np::MessageBody;
+    use i2p::test_util::random_hash;
+    use i2p::tunnel::layer::LayerKeys;
+    use super::*;
+
+    fn random_instructions() -> DeliveryInstructions {
+        match thread_rng().gen_range(0, 3) {
+            0 => DeliveryInstructions::Local,
+            1 => DeliveryInstructions::Tunnel(random_hash(), thread_rng().gen()),
+            _ => DeliveryInstructions::Router(random_hash()),
+        }
+    }
+
+    fn data_message(length: usize) -> I2NPMessage {
+        let mut data = vec![0u8; length];
+        thread_rng().fill_bytes(&mut data);
+        I2NPMessage::new(MessageBody::Data(data))
+    }
+
+    fn bytes(message: &I2NPMessage) -> Vec<u8> {
+        let mut data = Vec::new();
+        message.serialize(&mut data).unwrap();
+        data
+    }
+
+    fn random_keys() -> LayerKeys {
+        let mut layer_key = [0u8; 32];
+        let mut iv_key = [0u8; 32];
+        thread_rng().fill_bytes(&mut layer_key);
+        thread_rng().fill_bytes(&mut iv_key);
+        LayerKeys::new(&layer_key, &iv_key).unwrap()
+    }
+
+    /// Sends tunnel messages through an outbound tunnel: the creator takes
+    /// the hops' layers off in advance and each hop puts its own back
+    fn through_tunnel(hops: &[LayerKeys], tunnel_messages: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        tunnel_messages.into_iter()
+            .map(|message| {
+                let mut message = message;
+                for keys in hops.iter().rev() {
+                    message = keys.decrypt(&message).unwrap();
+                }
+                for keys in hops {
+                    message = keys.encrypt(&message).unwrap();
+                }
+                message
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let hops = [random_keys(), random_keys(), random_keys()];
+        for _ in 0..50 {
+            let count = thread_rng().gen_range(1, 5);
+            let messages: Vec<(DeliveryInstructions, I2NPMessage)> = (0..count)
+                .map(|_| (random_instructions(), data_message(thread_rng().gen_range(0, 8000))))
+                .collect();
+            let mut tunnel_messages = through_tunnel(&hops, fragment(&messages).unwrap());
+            for message in &tunnel_messages {
+                assert_eq!(TUNNEL_DATA_LENGTH, message.len());
+            }
+
+            // In any order, with some sent twice
+            thread_rng().shuffle(&mut tunnel_messages);
+            let duplicates: Vec<Vec<u8>> = tunnel_messages.iter()
+                .filter(|_| thread_rng().gen_weighted_bool(3))
+                .cloned()
+                .collect();
+            tunnel_messages.extend(duplicates);
+            let mut reassembler = Reassembler::new();
+            let mut received: Vec<(DeliveryInstructions, I2NPMessage)> = Vec::new();
+            for tunnel_message in tunnel_messages {
+                received.extend(reassembler.receive(&tunnel_message, Date::now()).unwrap());
+            }
+
+            assert_eq!(0, reassembler.pending());
+            assert_eq!(messages.len(), received.len());
+            for (instructions, message) in &messages {
+                assert!(received.iter().any(|(received_instructions, received)| {
+                    received_instructions == instructions && bytes(received) == bytes(message)
+                }));
+            }
+        }
+    }
+
+    #[test]
+    fn test_packing() {
+        // Small messages share a tunnel message
+        let messages: Vec<(DeliveryInstructions, I2NPMessage)> =
+            (0..5).map(|_| (DeliveryInstructions::Local, data_message(100))).collect();
+        let tunnel_messages = fragment(&messages).unwrap();
+        assert_eq!(1, tunnel_messages.len());
+
+        // One that just fits doesn't get fragmented
+        let overhead = bytes(&data_message(0)).len() + 3;
+        let whole = [(DeliveryInstructions::Local,
+                      data_message(MAX_PAYLOAD_LENGTH - overhead))];
+        let tunnel_messages = fragment(&whole).unwrap();
+        assert_eq!(1, tunnel_messages.len());
+        let zero = IV_LENGTH + CHECKSUM_LENGTH;
+        assert_eq!(0, tunnel_messages[0][zero]);
+        assert_eq!(DELIVERY_LOCAL << 5, tunnel_messages[0][zero + 1]);
+
+        // Too big to go through a tunnel
+        let too_big = [(DeliveryInstructions::Local, data_message(MAX_FRAGMENTS * 1000))];
+        assert!(fragment(&too_big).is_err());
+        let just_fits = [(DeliveryInstructions::Local, data_message(60 * 1000))];
+        assert_eq!(61, fragment(&just_fits).unwrap().len());
+    }
+
+    #[test]
+    fn test_max_fragments() {
+        // Every fragment of a local message has the same size header
+        let fragment_length = MAX_PAYLOAD_LENGTH - DeliveryInstructions::Local.header_length(true);
+        let overhead = bytes(&data_message(0)).len();
+        let largest = [(DeliveryInstructions::Local,
+                        data_message(MAX_FRAGMENTS * fragment_length - overhead))];
+        let tunnel_messages = fragment(&largest).unwrap();
+        assert_eq!(MAX_FRAGMENTS, tunnel_messages.len());
+        let mut reassembler = Reassembler::new();
+        let mut received: Vec<(DeliveryInstructions, I2NPMessage)> = Vec::new();
+        for tunnel_message in &tunnel_messages {
+            received.extend(reassembler.receive(tunnel_message, Date::now()).unwrap());
+        }
+        assert_eq!(1, received.len());
+        assert_eq!(bytes(&largest[0].1), bytes(&received[0].1));
+
+        let too_big = data_message(MAX_FRAGMENTS * fragment_length - overhead + 1);
+        assert!(fragment(&[(DeliveryInstructions::Local, too_big)]).is_err());
+    }
+
+    #[test]
+    fn test_partial_limit() {
+        let now = Date::now();
+        let mut reassembler = Reassembler::new();
+        let fragmented: Vec<Vec<Vec<u8>>> = (0..MAX_PARTIAL_MESSAGES + 1)
+            .map(|_| fragment(&[(DeliveryInstructions::Local, data_message(1500))]).unwrap())
+            .collect();
+        for (i, tunnel_messages) in fragmented.iter().enumerate() {
+            assert_eq!(2, tunnel_messages.len());
+            let started = Date::from_millis(now.millis() + i as u64);
+            assert!(reassembler.receive(&tunnel_messages[0], started).unwrap().is_empty());
+        }
+        assert_eq!(MAX_PARTIAL_MESSAGES, reassembler.pending());
+
+        // The first was dropped to make room for the last
+        assert!(reassembler.receive(&fragmented[0][1], now).unwrap().is_empty());
+        assert_eq!(1, reassembler.receive(&fragmented[1][1], now).unwrap().len());
+        assert_eq!(1,
+                   reassembler.receive(&fragmented[MAX_PARTIAL_MESSAGES][1], now)
+                       .unwrap()
+                       .len());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let messages = [(DeliveryInstructions::Router(random_hash()), data_message(3000))];
+        let tunnel_messages = fragment(&messages).unwrap();
+        assert_eq!(4, tunnel_messages.len());
+
+        let now = Date::now();
+        let mut reassembler = Reassembler::new();
+        for tunnel_message in &tunnel_messages[..3] {
+            assert!(reassembler.receive(tunnel_message, now).unwrap().is_empty());
+        }
+        assert_eq!(1, reassembler.pending());
+        assert_eq!(0, reassembler.expire(now));
+        let later = Date::from_millis(now.millis() + REASSEMBLY_TIMEOUT);
+        assert_eq!(1, reassembler.expire(later));
+
+        // The rest turning up late doesn't start it again
+        assert!(reassembler.receive(&tunnel_messages[3], later).unwrap().is_empty());
+        assert_eq!(0, reassembler.pending());
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let messages = [(DeliveryInstructions::Local, data_message(500))];
+        let mut tunnel_message = fragment(&messages).unwrap().pop().unwrap();
+        let mut reassembler = Reassembler::new();
+        tunnel_message[TUNNEL_DATA_LENGTH - 1] ^= 1;
+        assert!(reassembler.receive(&tunnel_message, Date::now()).is_err());
+        assert!(reassembler.receive(&tunnel_message[1..], Date::now()).is_err());
+        assert!(reassembler.receive(&[0u8; TUNNEL_DATA_LENGTH], Date::now()).is_err());
+    }
+}
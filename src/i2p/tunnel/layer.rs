@@ -0,0 +1,116 @@
+//! The layer of encryption each hop puts on a tunnel message.
+//!
+//! A TunnelData message is a 16-byte IV and 1008 bytes of data. Each hop
+//! encrypts the IV with its IV key, encrypts the data in CBC mode with its
+//! layer key and that IV, and then encrypts the IV again, so the IV going
+//! out can't be matched with the one that came in. Inbound tunnels' creators
+//! strip every hop's layer off as the message arrives; outbound tunnels'
+//! creators strip them off in advance, so the endpoint gets it in the clear.
+
+use i2p::crypto::aes;
+use i2p::error::Error;
+use i2p::i2np::tunnel::TUNNEL_DATA_LENGTH;
+
+const IV_LENGTH: usize = aes::BLOCK_LENGTH;
+
+/// One hop's keys
+#[derive(Clone)]
+pub struct LayerKeys {
+    layer_key: Vec<u8>,
+    iv_key: Vec<u8>,
+}
+
+fn check_length(data: &[u8]) -> Result<(), Error> {
+    if data.len() != TUNNEL_DATA_LENGTH {
+        return Err(Error::Crypto(format!("Tunnel data must be {} bytes, not {}",
+                                         TUNNEL_DATA_LENGTH,
+                                         data.len())));
+    }
+
+    Ok(())
+}
+
+impl LayerKeys {
+    pub fn new(layer_key: &[u8], iv_key: &[u8]) -> Result<LayerKeys, Error> {
+        if layer_key.len() != aes::KEY_LENGTH || iv_key.len() != aes::KEY_LENGTH {
+            return Err(Error::Crypto(format!("Tunnel layer keys must be {} bytes",
+                                             aes::KEY_LENGTH)));
+        }
+
+        Ok(LayerKeys {
+            layer_key: layer_key.to_vec(),
+            iv_key: iv_key.to_vec(),
+        })
+    }
+
+    /// Adds our layer, as a hop does
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        check_length(data)?;
+        let iv = aes::ecb_encrypt(&self.iv_key, &data[..IV_LENGTH])?;
+        let encrypted = aes::cbc_encrypt(&self.layer_key, &iv, &data[IV_LENGTH..])?;
+
+        let mut result = aes::ecb_encrypt(&self.iv_key, &iv)?;
+        result.extend(encrypted);
+        Ok(result)
+    }
+
+    /// Takes our layer off, as a tunnel's creator does
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        check_length(data)?;
+        let iv = aes::ecb_decrypt(&self.iv_key, &data[..IV_LENGTH])?;
+        let decrypted = aes::cbc_decrypt(&self.layer_key, &iv, &data[IV_LENGTH..])?;
+
+        let mut result = aes::ecb_decrypt(&self.iv_key, &iv)?;
+        result.extend(decrypted);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{thread_rng, Rng};
+    use super::*;
+
+    fn random_keys() -> LayerKeys {
+        let mut layer_key = [0u8; 32];
+        let mut iv_key = [0u8; 32];
+        thread_rng().fill_bytes(&mut layer_key);
+        thread_rng().fill_bytes(&mut iv_key);
+        LayerKeys::new(&layer_key, &iv_key).unwrap()
+    }
+
+    #[test]
+    fn test_layer() {
+        let keys = random_keys();
+        let mut data = vec![0u8; TUNNEL_DATA_LENGTH];
+        thread_rng().fill_bytes(&mut data);
+
+        let encrypted = keys.encrypt(&data).unwrap();
+        let iv = aes::ecb_encrypt(&keys.iv_key, &data[..16]).unwrap();
+        assert_eq!(aes::ecb_encrypt(&keys.iv_key, &iv).unwrap(), encrypted[..16].to_vec());
+        assert_eq!(aes::cbc_encrypt(&keys.layer_key, &iv, &data[16..]).unwrap(),
+                   encrypted[16..].to_vec());
+        assert_eq!(data, keys.decrypt(&encrypted).unwrap());
+    }
+
+    #[test]
+    fn test_layers() {
+        // A three-hop outbound tunnel: the creator takes all three layers
+        // off, and each hop puts its own back on
+        let hops = [random_keys(), random_keys(), random_keys()];
+        let mut data = vec![7u8; TUNNEL_DATA_LENGTH];
+        data[..16].copy_from_slice(&[1u8; 16]);
+
+        let mut message = data.clone();
+        for keys in hops.iter().rev() {
+            message = keys.decrypt(&message).unwrap();
+        }
+        for keys in hops.iter() {
+            message = keys.encrypt(&message).unwrap();
+        }
+        assert_eq!(data, message);
+
+        assert!(hops[0].encrypt(&data[..1000]).is_err());
+        assert!(LayerKeys::new(&[0u8; 16], &[0u8; 32]).is_err());
+    }
+}
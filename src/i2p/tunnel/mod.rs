@@ -0,0 +1,6 @@
+pub mod build;
+pub mod fragment;
+pub mod layer;
+pub mod participating;
+pub mod pool;
+pub mod profile;
@@ -0,0 +1,647 @@
+//! The tunnels other routers have asked us to be a hop in.
+//!
+//! Each build request gets a reply whether we accept it or not: we say no
+//! if we aren't up, if we're getting requests faster than we can deal with
+//! them, if we're already in as many tunnels as we're configured for, or if
+//! we're using more than our share of the bandwidth. Accepted tunnels last
+//! ten minutes, during which we add our layer of encryption to whatever
+//! comes through and pass it on. As an inbound gateway we fragment the
+//! messages we're given into the tunnel; as an outbound endpoint we
+//! reassemble what comes out and send it where the creator asked.
+
+use i2p::config::Config;
+use i2p::data::crypto::{Hash, PrivateKey};
+use i2p::data::date::Date;
+use i2p::error::Error;
+use i2p::i2np::{I2NPMessage, MessageBody};
+use i2p::i2np::tunnel::{TunnelData, TunnelGateway};
+use i2p::i2np::tunnel_build::{BuildRecords, RecordLayout};
+use i2p::lifecycle::{Lifecycle, RouterState};
+use i2p::transport::bandwidth::{BandwidthLimiter, Direction};
+use i2p::tunnel::build::{BuildRequest, ReceivedRequest, DEFAULT_TUNNEL_LIFETIME, REPLY_ACCEPT,
+                         REPLY_REJECT_BANDWIDTH, REPLY_REJECT_CRITICAL,
+                         REPLY_REJECT_TRANSIENT_OVERLOAD};
+use i2p::tunnel::fragment::{self, DeliveryInstructions, Reassembler};
+use i2p::tunnel::layer::LayerKeys;
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const MAX_TUNNELS_CONFIG: &str = "router.maxParticipatingTunnels";
+const DEFAULT_MAX_TUNNELS: i64 = 10000;
+const SHARE_PERCENTAGE_CONFIG: &str = "router.sharePercentage";
+const DEFAULT_SHARE_PERCENTAGE: i64 = 80;
+
+/// Any more build requests than this in a second, and we're too busy
+const MAX_REQUESTS_PER_SECOND: usize = 50;
+
+/// How often expired tunnels are dropped, in seconds
+const EXPIRE_INTERVAL: u64 = 60;
+
+/// Requests older than this are replays, or so late the creator's given
+/// up. ElGamal request times are rounded down to the hour.
+const MAX_REQUEST_AGE: u64 = 65 * 60 * 1000;
+
+/// How far ahead of us the creator's clock can be
+const MAX_CLOCK_SKEW: u64 = 5 * 60 * 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// Gets messages from anywhere and sends them down the tunnel
+    InboundGateway,
+    Participant,
+    /// Gets the messages out of the tunnel and sends them where they're
+    /// going
+    OutboundEndpoint,
+}
+
+pub struct ParticipatingTunnel {
+    pub receive_tunnel: u32,
+    pub next_tunnel: u32,
+    pub next_ident: Hash,
+    pub role: Role,
+    pub expiration: Date,
+    keys: LayerKeys,
+    /// Only used by outbound endpoints
+    reassembler: Reassembler,
+}
+
+/// What to do with a TunnelData message once we've added our layer
+// Handed straight back to the dispatcher, so the message isn't boxed
+#[allow(clippy::large_enum_variant)]
+pub enum Delivery {
+    /// Send it on to the next hop
+    Forward(Hash, I2NPMessage),
+    /// We're the outbound endpoint, and these are the messages it
+    /// completed, to send where their instructions say
+    Endpoint(Vec<(DeliveryInstructions, I2NPMessage)>),
+}
+
+struct State {
+    tunnels: HashMap<u32, ParticipatingTunnel>,
+    /// When the build requests in the last second came in
+    requests: VecDeque<Instant>,
+}
+
+pub struct ParticipatingTunnels {
+    ident: Hash,
+    private_key: PrivateKey,
+    lifecycle: Arc<Lifecycle>,
+    max_tunnels: usize,
+    share_percentage: u64,
+    max_requests_per_second: usize,
+    bandwidth: RwLock<Option<Arc<BandwidthLimiter>>>,
+    state: Arc<Mutex<State>>,
+    stop_sender: Mutex<Option<Sender<()>>>,
+    expire_thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+fn expire(state: &Mutex<State>, now: Date) -> usize {
+    let mut state = state.lock().unwrap();
+    let before = state.tunnels.len();
+    state.tunnels.retain(|_, tunnel| tunnel.expiration > now);
+    for tunnel in state.tunnels.values_mut() {
+        tunnel.reassembler.expire(now);
+    }
+
+    before - state.tunnels.len()
+}
+
+/// The build message to send on to the next hop, or, from the outbound
+/// endpoint, the reply to send back to the creator through its reply tunnel
+fn next_message(request: &BuildRequest,
+                body: &MessageBody,
+                records: BuildRecords)
+                -> Result<I2NPMessage, Error> {
+    let next_body = match (request.is_outbound_endpoint(), body) {
+        (false, &MessageBody::TunnelBuild(_)) => MessageBody::TunnelBuild(records),
+        (false, &MessageBody::VariableTunnelBuild(_)) => MessageBody::VariableTunnelBuild(records),
+        (false, _) => MessageBody::ShortTunnelBuild(records),
+        (true, &MessageBody::TunnelBuild(_)) => MessageBody::TunnelBuildReply(records),
+        (true, &MessageBody::VariableTunnelBuild(_)) => {
+            MessageBody::VariableTunnelBuildReply(records)
+        }
+        (true, _) => MessageBody::OutboundTunnelBuildReply(records),
+    };
+    let mut message = I2NPMessage::new(next_body);
+    message.message_id = request.send_message_id;
+    if !request.is_outbound_endpoint() {
+        return Ok(message);
+    }
+
+    let gateway = TunnelGateway::new(request.next_tunnel, &message)?;
+    Ok(I2NPMessage::new(MessageBody::TunnelGateway(gateway)))
+}
+
+impl ParticipatingTunnels {
+    pub fn new(config: &Config,
+               ident: Hash,
+               private_key: PrivateKey,
+               lifecycle: Arc<Lifecycle>)
+               -> Result<ParticipatingTunnels, Error> {
+        let max_tunnels = config.i64_value(MAX_TUNNELS_CONFIG, Some(DEFAULT_MAX_TUNNELS)).unwrap();
+        if max_tunnels < 0 {
+            return Err(Error::Configuration(format!("{} can't be negative", MAX_TUNNELS_CONFIG)));
+        }
+        let share_percentage =
+            config.i64_value(SHARE_PERCENTAGE_CONFIG, Some(DEFAULT_SHARE_PERCENTAGE)).unwrap();
+        if !(0..=100).contains(&share_percentage) {
+            return Err(Error::Configuration(format!("{} must be between 0 and 100",
+                                                    SHARE_PERCENTAGE_CONFIG)));
+        }
+
+        Ok(ParticipatingTunnels {
+            ident,
+            private_key,
+            lifecycle,
+            max_tunnels: max_tunnels as usize,
+            share_percentage: share_percentage as u64,
+            max_requests_per_second: MAX_REQUESTS_PER_SECOND,
+            bandwidth: RwLock::new(None),
+            state: Arc::new(Mutex::new(State {
+                tunnels: HashMap::new(),
+                requests: VecDeque::new(),
+            })),
+            stop_sender: Mutex::new(None),
+            expire_thread: Mutex::new(None),
+        })
+    }
+
+    /// Starts expiring tunnels in the background. With a bandwidth limiter,
+    /// we turn down tunnels while we're over our share of its outbound rate.
+    pub fn start(&self, bandwidth: Option<Arc<BandwidthLimiter>>) -> Result<(), Error> {
+        *self.bandwidth.write().unwrap() = bandwidth;
+
+        let (sender, receiver) = mpsc::channel::<()>();
+        let state = self.state.clone();
+        let handle = thread::Builder::new()
+            .name("participating tunnels".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(Duration::from_secs(EXPIRE_INTERVAL)) {
+                    let expired = expire(&state, Date::now());
+                    debug!("Participating: expired {} tunnels", expired);
+                }
+            })?;
+        *self.stop_sender.lock().unwrap() = Some(sender);
+        *self.expire_thread.lock().unwrap() = Some(handle);
+
+        Ok(())
+    }
+
+    /// Stops the background thread and drops every tunnel
+    pub fn stop(&self) {
+        if let Some(sender) = self.stop_sender.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+        if let Some(handle) = self.expire_thread.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+        self.state.lock().unwrap().tunnels.clear();
+    }
+
+    /// How many tunnels we're in
+    pub fn count(&self) -> usize {
+        self.state.lock().unwrap().tunnels.len()
+    }
+
+    pub fn expire(&self, now: Date) -> usize {
+        expire(&self.state, now)
+    }
+
+    /// Whether we're using more than our share of the outbound bandwidth,
+    /// counting everything we send, not just for other routers' tunnels
+    fn over_share(&self) -> bool {
+        match *self.bandwidth.read().unwrap() {
+            Some(ref bandwidth) => {
+                let share = bandwidth.limits(Direction::Outbound).rate * self.share_percentage /
+                            100;
+                bandwidth.stats(Direction::Outbound).average > share
+            }
+            None => false,
+        }
+    }
+
+    fn reply_code(&self, state: &mut State, request: &BuildRequest, now: Instant) -> u8 {
+        while state.requests.front().is_some_and(|&time| now - time >= Duration::from_secs(1)) {
+            state.requests.pop_front();
+        }
+        state.requests.push_back(now);
+
+        match self.lifecycle.state() {
+            RouterState::NETDB_READY |
+            RouterState::EXPL_TUNNELS_READY |
+            RouterState::RUNNING => {}
+            _ => return REPLY_REJECT_CRITICAL,
+        }
+        if state.requests.len() > self.max_requests_per_second {
+            return REPLY_REJECT_TRANSIENT_OVERLOAD;
+        }
+        if state.tunnels.len() >= self.max_tunnels || self.over_share() ||
+           state.tunnels.contains_key(&request.receive_tunnel) {
+            return REPLY_REJECT_BANDWIDTH;
+        }
+
+        REPLY_ACCEPT
+    }
+
+    /// Decides whether to join the tunnel a build request asks us to, and
+    /// returns the message to send on with our reply, and who to send it to
+    pub fn handle_build(&self,
+                        message: &I2NPMessage,
+                        now: Date)
+                        -> Result<(Hash, I2NPMessage), Error> {
+        let (records, layout) = match message.body {
+            MessageBody::TunnelBuild(ref records) => (records, RecordLayout::Fixed),
+            MessageBody::VariableTunnelBuild(ref records) => (records, RecordLayout::Variable),
+            MessageBody::ShortTunnelBuild(ref records) => (records, RecordLayout::Short),
+            _ => {
+                return Err(Error::Tunnel(format!("{:?} isn't a tunnel build request",
+                                                 message.message_type())))
+            }
+        };
+        let received = ReceivedRequest::decrypt(records, layout, &self.ident, &self.private_key)?;
+        let request = &received.request;
+        let request_time = request.request_time.millis();
+        if request_time + MAX_REQUEST_AGE < now.millis() ||
+           request_time > now.millis() + MAX_CLOCK_SKEW {
+            return Err(Error::Tunnel(format!("Build request for tunnel {} made at {}",
+                                             request.receive_tunnel,
+                                             request.request_time.date_string())));
+        }
+
+        let reply = {
+            let mut state = self.state.lock().unwrap();
+            let reply = self.reply_code(&mut state, request, Instant::now());
+            if reply == REPLY_ACCEPT {
+                let role = if request.is_inbound_gateway() {
+                    Role::InboundGateway
+                } else if request.is_outbound_endpoint() {
+                    Role::OutboundEndpoint
+                } else {
+                    Role::Participant
+                };
+                let lifetime = request.expiration.min(DEFAULT_TUNNEL_LIFETIME) as u64 * 1000;
+                state.tunnels.insert(request.receive_tunnel,
+                                     ParticipatingTunnel {
+                                         receive_tunnel: request.receive_tunnel,
+                                         next_tunnel: request.next_tunnel,
+                                         next_ident: request.next_ident.clone(),
+                                         role,
+                                         expiration: Date::from_millis(now.millis() + lifetime),
+                                         keys: LayerKeys::new(&request.layer_key,
+                                                              &request.iv_key)?,
+                                         reassembler: Reassembler::new(),
+                                     });
+            }
+            reply
+        };
+        if reply == REPLY_ACCEPT {
+            debug!("Participating: joined tunnel {}", request.receive_tunnel);
+        } else {
+            debug!("Participating: turned down tunnel {} with code {}",
+                   request.receive_tunnel,
+                   reply);
+        }
+
+        let mut records = records.clone();
+        received.encrypt_reply(&mut records, reply)?;
+        let next = next_message(request, &message.body, records)?;
+
+        Ok((request.next_ident.clone(), next))
+    }
+
+    /// Fragments a message given to us as an inbound gateway, returning
+    /// the TunnelData to send to the next hop
+    pub fn handle_tunnel_gateway(&self,
+                                 gateway: &TunnelGateway,
+                                 now: Date)
+                                 -> Result<(Hash, Vec<I2NPMessage>), Error> {
+        let state = self.state.lock().unwrap();
+        let tunnel = match state.tunnels.get(&gateway.tunnel_id) {
+            Some(tunnel) if tunnel.expiration > now => tunnel,
+            _ => return Err(Error::Tunnel(format!("No tunnel {}", gateway.tunnel_id))),
+        };
+        if tunnel.role != Role::InboundGateway {
+            return Err(Error::Tunnel(format!("TunnelGateway for tunnel {}, which we're not the \
+                                              gateway for",
+                                             tunnel.receive_tunnel)));
+        }
+
+        let mut messages: Vec<I2NPMessage> = Vec::new();
+        for tunnel_message in fragment::fragment(&[(DeliveryInstructions::Local,
+                                                    gateway.message()?)])? {
+            let next = TunnelData {
+                tunnel_id: tunnel.next_tunnel,
+                data: tunnel.keys.encrypt(&tunnel_message)?,
+            };
+            messages.push(I2NPMessage::new(MessageBody::TunnelData(next)));
+        }
+
+        Ok((tunnel.next_ident.clone(), messages))
+    }
+
+    /// Adds our layer to a message coming through one of our tunnels
+    pub fn handle_tunnel_data(&self,
+                              tunnel_data: &TunnelData,
+                              now: Date)
+                              -> Result<Delivery, Error> {
+        let mut state = self.state.lock().unwrap();
+        let tunnel = match state.tunnels.get_mut(&tunnel_data.tunnel_id) {
+            Some(tunnel) if tunnel.expiration > now => tunnel,
+            _ => return Err(Error::Tunnel(format!("No tunnel {}", tunnel_data.tunnel_id))),
+        };
+        if tunnel.role == Role::InboundGateway {
+            return Err(Error::Tunnel(format!("TunnelData for inbound gateway {}",
+                                             tunnel.receive_tunnel)));
+        }
+
+        let data = tunnel.keys.encrypt(&tunnel_data.data)?;
+        if tunnel.role == Role::OutboundEndpoint {
+            return Ok(Delivery::Endpoint(tunnel.reassembler.receive(&data, now)?));
+        }
+        let next = TunnelData {
+            tunnel_id: tunnel.next_tunnel,
+            data,
+        };
+
+        Ok(Delivery::Forward(tunnel.next_ident.clone(),
+                             I2NPMessage::new(MessageBody::TunnelData(next))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::crypto::curve25519;
+    use i2p::data::crypto::PublicKey;
+    use i2p::i2np::{DeliveryStatus, MessageType};
+    use i2p::i2np::tunnel::TUNNEL_DATA_LENGTH;
+    use i2p::test_util::random_hash;
+    use i2p::tunnel::build::{HopConfig, PendingBuild, FLAG_INBOUND_GATEWAY,
+                             FLAG_OUTBOUND_ENDPOINT};
+    use rand::{thread_rng, Rng};
+    use super::*;
+
+    /// Our router, and how its startup's gone
+    fn router(state: RouterState) -> (ParticipatingTunnels, PublicKey) {
+        let lifecycle = Arc::new(Lifecycle::new());
+        let steps = [RouterState::STARTING_1,
+                     RouterState::STARTING_2,
+                     RouterState::STARTING_3,
+                     RouterState::NETDB_READY];
+        for &step in steps.iter() {
+            if lifecycle.state() != state {
+                lifecycle.transition(step).unwrap();
+            }
+        }
+
+        let mut private_key = vec![0u8; 32];
+        thread_rng().fill_bytes(&mut private_key);
+        let public_key = curve25519::x25519_public_key(&private_key).unwrap();
+        let participating = ParticipatingTunnels::new(&Config::default(),
+                                                      random_hash(),
+                                                      PrivateKey::X25519(private_key
+                                                          .into_boxed_slice()),
+                                                      lifecycle)
+            .unwrap();
+
+        (participating, PublicKey::X25519(public_key.into_boxed_slice()))
+    }
+
+    /// A short build request asking us to join a tunnel
+    fn build_request(participating: &ParticipatingTunnels,
+                     public_key: &PublicKey,
+                     receive_tunnel: u32,
+                     flags: u8)
+                     -> (PendingBuild, BuildRequest, I2NPMessage) {
+        let request = BuildRequest::new(receive_tunnel,
+                                        participating.ident.clone(),
+                                        receive_tunnel + 1,
+                                        random_hash(),
+                                        flags)
+            .unwrap();
+        let mut configs = [HopConfig {
+                               ident: participating.ident.clone(),
+                               encryption_key: public_key.clone(),
+                               request,
+                           }];
+        let (pending, records) = PendingBuild::new(&mut configs, RecordLayout::Short, 4).unwrap();
+        let message = I2NPMessage::new(MessageBody::ShortTunnelBuild(records));
+
+        (pending, configs[0].request.clone(), message)
+    }
+
+    fn reply(participating: &ParticipatingTunnels,
+             public_key: &PublicKey,
+             receive_tunnel: u32)
+             -> u8 {
+        let (pending, request, message) =
+            build_request(participating, public_key, receive_tunnel, 0);
+        let (next_ident, next) = participating.handle_build(&message, Date::now()).unwrap();
+        assert_eq!(request.next_ident, next_ident);
+        assert_eq!(request.send_message_id, next.message_id);
+        match next.body {
+            MessageBody::ShortTunnelBuild(ref records) => {
+                pending.decrypt_replies(records).unwrap()[0]
+            }
+            _ => panic!("Expected a ShortTunnelBuild, got {:?}", next.message_type()),
+        }
+    }
+
+    #[test]
+    fn test_accept() {
+        let (participating, public_key) = router(RouterState::NETDB_READY);
+        assert_eq!(REPLY_ACCEPT, reply(&participating, &public_key, 100));
+        assert_eq!(1, participating.count());
+
+        // Not the same tunnel twice
+        assert_eq!(REPLY_REJECT_BANDWIDTH, reply(&participating, &public_key, 100));
+        assert_eq!(1, participating.count());
+    }
+
+    #[test]
+    fn test_rejections() {
+        let (mut participating, public_key) = router(RouterState::NETDB_READY);
+        participating.max_tunnels = 2;
+        assert_eq!(REPLY_ACCEPT, reply(&participating, &public_key, 100));
+        assert_eq!(REPLY_ACCEPT, reply(&participating, &public_key, 200));
+        assert_eq!(REPLY_REJECT_BANDWIDTH, reply(&participating, &public_key, 300));
+
+        // Too many requests at once, until a second later
+        participating.max_requests_per_second = 3;
+        let (_, request, _) = build_request(&participating, &public_key, 400, 0);
+        let now = Instant::now();
+        {
+            let mut state = participating.state.lock().unwrap();
+            state.requests.clear();
+            for _ in 0..3 {
+                assert_eq!(REPLY_REJECT_BANDWIDTH,
+                           participating.reply_code(&mut state, &request, now));
+            }
+            assert_eq!(REPLY_REJECT_TRANSIENT_OVERLOAD,
+                       participating.reply_code(&mut state, &request, now));
+            let later = now + Duration::from_secs(1);
+            assert_eq!(REPLY_REJECT_BANDWIDTH,
+                       participating.reply_code(&mut state, &request, later));
+        }
+        assert_eq!(2, participating.count());
+
+        // Nothing until the NetDB's loaded, or once we're shutting down
+        let (participating, public_key) = router(RouterState::STARTING_3);
+        assert_eq!(REPLY_REJECT_CRITICAL, reply(&participating, &public_key, 100));
+        participating.lifecycle.transition(RouterState::FINAL_SHUTDOWN_1).unwrap();
+        assert_eq!(REPLY_REJECT_CRITICAL, reply(&participating, &public_key, 100));
+        assert_eq!(0, participating.count());
+    }
+
+    #[test]
+    fn test_outbound_endpoint() {
+        let (participating, public_key) = router(RouterState::NETDB_READY);
+        let (pending, request, message) =
+            build_request(&participating, &public_key, 100, FLAG_OUTBOUND_ENDPOINT);
+        let (next_ident, next) = participating.handle_build(&message, Date::now()).unwrap();
+        assert_eq!(request.next_ident, next_ident);
+
+        // The reply goes to the creator's reply tunnel
+        let gateway = match next.body {
+            MessageBody::TunnelGateway(ref gateway) => gateway.clone(),
+            _ => panic!("Expected a TunnelGateway, got {:?}", next.message_type()),
+        };
+        assert_eq!(request.next_tunnel, gateway.tunnel_id);
+        let reply = gateway.message().unwrap();
+        assert_eq!(MessageType::OutboundTunnelBuildReply, reply.message_type());
+        assert_eq!(request.send_message_id, reply.message_id);
+        let (records, _) = reply.body.build_records().unwrap();
+        assert_eq!(vec![REPLY_ACCEPT], pending.decrypt_replies(records).unwrap());
+
+        // What comes out of the tunnel is for us to deliver, once the
+        // creator's taken our layer off in advance
+        let keys = LayerKeys::new(&request.layer_key, &request.iv_key).unwrap();
+        let to = DeliveryInstructions::Router(random_hash());
+        let status = I2NPMessage::new(MessageBody::DeliveryStatus(DeliveryStatus {
+            message_id: 1,
+            timestamp: Date::now(),
+        }));
+        let mut tunnel_messages = fragment::fragment(&[(to.clone(), status)]).unwrap();
+        let tunnel_data = TunnelData {
+            tunnel_id: 100,
+            data: keys.decrypt(&tunnel_messages.pop().unwrap()).unwrap(),
+        };
+        match participating.handle_tunnel_data(&tunnel_data, Date::now()).unwrap() {
+            Delivery::Endpoint(mut messages) => {
+                assert_eq!(1, messages.len());
+                let (instructions, message) = messages.pop().unwrap();
+                assert_eq!(to, instructions);
+                assert_eq!(MessageType::DeliveryStatus, message.message_type());
+            }
+            Delivery::Forward(..) => panic!("Expected the endpoint to deliver"),
+        }
+
+        // But only once
+        match participating.handle_tunnel_data(&tunnel_data, Date::now()).unwrap() {
+            Delivery::Endpoint(messages) => assert!(messages.is_empty()),
+            Delivery::Forward(..) => panic!("Expected the endpoint to deliver"),
+        }
+    }
+
+    #[test]
+    fn test_inbound_gateway() {
+        let (participating, public_key) = router(RouterState::NETDB_READY);
+        let (_, request, message) =
+            build_request(&participating, &public_key, 100, FLAG_INBOUND_GATEWAY);
+        participating.handle_build(&message, Date::now()).unwrap();
+
+        // Fragmented and encrypted with our layer for the next hop
+        let data = I2NPMessage::new(MessageBody::Data(vec![3u8; 2000]));
+        let gateway = TunnelGateway::new(100, &data).unwrap();
+        let (next_ident, next) = participating.handle_tunnel_gateway(&gateway, Date::now())
+            .unwrap();
+        assert_eq!(request.next_ident, next_ident);
+        assert_eq!(3, next.len());
+        let keys = LayerKeys::new(&request.layer_key, &request.iv_key).unwrap();
+        let mut reassembler = Reassembler::new();
+        let mut received = Vec::new();
+        for message in &next {
+            match message.body {
+                MessageBody::TunnelData(ref next) => {
+                    assert_eq!(101, next.tunnel_id);
+                    let data = keys.decrypt(&next.data).unwrap();
+                    received.extend(reassembler.receive(&data, Date::now()).unwrap());
+                }
+                _ => panic!("Expected TunnelData, got {:?}", message.message_type()),
+            }
+        }
+        assert_eq!(1, received.len());
+        assert_eq!(DeliveryInstructions::Local, received[0].0);
+        assert_eq!(data.message_id, received[0].1.message_id);
+
+        // Gateways don't take TunnelData, and only gateways take messages
+        let tunnel_data = TunnelData {
+            tunnel_id: 100,
+            data: vec![1; TUNNEL_DATA_LENGTH],
+        };
+        assert!(participating.handle_tunnel_data(&tunnel_data, Date::now()).is_err());
+        let (_, _, message) = build_request(&participating, &public_key, 200, 0);
+        participating.handle_build(&message, Date::now()).unwrap();
+        let gateway = TunnelGateway::new(200, &data).unwrap();
+        assert!(participating.handle_tunnel_gateway(&gateway, Date::now()).is_err());
+    }
+
+    #[test]
+    fn test_tunnel_data() {
+        let (participating, public_key) = router(RouterState::NETDB_READY);
+        let (_, request, message) = build_request(&participating, &public_key, 100, 0);
+        participating.handle_build(&message, Date::now()).unwrap();
+
+        let mut data = vec![0u8; TUNNEL_DATA_LENGTH];
+        thread_rng().fill_bytes(&mut data);
+        let tunnel_data = TunnelData {
+            tunnel_id: 100,
+            data,
+        };
+        let keys = LayerKeys::new(&request.layer_key, &request.iv_key).unwrap();
+        match participating.handle_tunnel_data(&tunnel_data, Date::now()).unwrap() {
+            Delivery::Forward(next_ident, next) => {
+                assert_eq!(request.next_ident, next_ident);
+                match next.body {
+                    MessageBody::TunnelData(ref next) => {
+                        assert_eq!(101, next.tunnel_id);
+                        assert_eq!(keys.encrypt(&tunnel_data.data).unwrap(), next.data);
+                    }
+                    _ => panic!("Expected TunnelData, got {:?}", next.message_type()),
+                }
+            }
+            Delivery::Endpoint(..) => panic!("Expected a participant to forward"),
+        }
+
+        let unknown = TunnelData {
+            tunnel_id: 200,
+            data: tunnel_data.data.clone(),
+        };
+        assert!(participating.handle_tunnel_data(&unknown, Date::now()).is_err());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let (participating, public_key) = router(RouterState::NETDB_READY);
+        let (_, _, message) = build_request(&participating, &public_key, 100, 0);
+        let now = Date::now();
+        participating.handle_build(&message, now).unwrap();
+
+        let tunnel_data = TunnelData {
+            tunnel_id: 100,
+            data: vec![1; TUNNEL_DATA_LENGTH],
+        };
+        let later = Date::from_millis(now.millis() + 10 * 60 * 1000);
+        assert!(participating.handle_tunnel_data(&tunnel_data, later).is_err());
+
+        assert_eq!(0, participating.expire(Date::from_millis(now.millis() + 9 * 60 * 1000)));
+        assert_eq!(1, participating.expire(later));
+        assert_eq!(0, participating.count());
+
+        // Stale requests are dropped without a reply
+        let much_later = Date::from_millis(now.millis() + 2 * 60 * 60 * 1000);
+        assert!(participating.handle_build(&message, much_later).is_err());
+    }
+}
@@ -0,0 +1,1424 @@
+//! The tunnels we build for ourselves.
+//!
+//! Tunnels come in pools, one inbound and one outbound for each purpose:
+//! the exploratory pools, for the router's own traffic, and each client
+//! destination's. A pool keeps `quantity` tunnels in use and
+//! `backup_quantity` spares, builds replacements before they expire, and
+//! drops tunnels that fail their tests: a DeliveryStatus sent out through
+//! one of the pool's outbound tunnels and back in through one of its
+//! inbound tunnels.
+//!
+//! Inbound tunnels' build messages go out through one of our outbound
+//! tunnels and come back to us from their last hop. Outbound tunnels'
+//! replies come from their endpoint in through one of our inbound tunnels.
+//! Until there are tunnels to use, at startup, build messages go straight to
+//! the first hop and replies come to a zero-hop reply tunnel, for which
+//! we're the gateway. Messages coming out of our inbound
+//! tunnels are reassembled here; messages for our outbound tunnels are
+//! fragmented with `Tunnel::tunnel_messages()`.
+//!
+//! The `PeerSelector` that picks each tunnel's hops is told how every hop
+//! answered, which builds went unanswered, and how much each tunnel carried,
+//! so that it can learn which routers are worth asking.
+
+use i2p::config::Config;
+use i2p::data::crypto::{Hash, PublicKeyType};
+use i2p::data::date::Date;
+use i2p::data::mapping::Mapping;
+use i2p::data::router_info::RouterInfo;
+use i2p::error::Error;
+use i2p::i2np::{DeliveryStatus, I2NPMessage, MessageBody};
+use i2p::i2np::tunnel::{TunnelData, TunnelGateway};
+use i2p::i2np::tunnel_build::RecordLayout;
+use i2p::lifecycle::{Lifecycle, RouterState};
+use i2p::tunnel::build::{BuildRequest, HopConfig, PendingBuild, DEFAULT_TUNNEL_LIFETIME,
+                         FLAG_INBOUND_GATEWAY, FLAG_OUTBOUND_ENDPOINT, REPLY_ACCEPT};
+use i2p::tunnel::fragment::{self, DeliveryInstructions, Reassembler};
+use i2p::tunnel::layer::LayerKeys;
+use rand::{thread_rng, Rng};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The most hops a tunnel can have. Build messages hold eight records, and
+/// longer tunnels are slow and unreliable anyway.
+pub const MAX_HOPS: u8 = 7;
+
+/// Build messages have at least this many records, so they don't give away
+/// how short the tunnel is
+const MIN_RECORDS: usize = 4;
+
+/// How long we wait for a build reply, in milliseconds
+const BUILD_TIMEOUT: u64 = 13 * 1000;
+
+/// How long before a tunnel expires we build its replacement
+const REBUILD_MARGIN: u64 = 90 * 1000;
+
+/// How often each outbound tunnel is tested, along with an inbound one
+const TEST_INTERVAL: u64 = 60 * 1000;
+
+/// How long a test message has to come back
+const TEST_TIMEOUT: u64 = 15 * 1000;
+
+/// Tunnels are dropped once they've failed this many tests in a row
+const MAX_TEST_FAILURES: u32 = 2;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum PoolId {
+    Exploratory,
+    /// A client destination's pools, by the destination's hash
+    Client(Hash),
+}
+
+/// How many tunnels a pool keeps, and how long they are
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PoolSettings {
+    /// Hops, not counting us
+    pub length: u8,
+    /// How much the length is randomized. A positive variance adds up to
+    /// that many hops; a negative one adds or takes away up to that many.
+    pub length_variance: i8,
+    pub quantity: u8,
+    pub backup_quantity: u8,
+}
+
+impl PoolSettings {
+    pub fn exploratory() -> PoolSettings {
+        PoolSettings {
+            length: 2,
+            length_variance: 0,
+            quantity: 2,
+            backup_quantity: 0,
+        }
+    }
+
+    pub fn client() -> PoolSettings {
+        PoolSettings {
+            length: 3,
+            length_variance: 0,
+            quantity: 2,
+            backup_quantity: 0,
+        }
+    }
+
+    /// Reads `<prefix>.length`, `<prefix>.lengthVariance`,
+    /// `<prefix>.quantity` and `<prefix>.backupQuantity`, keeping the
+    /// defaults for any that aren't set
+    fn read<F>(prefix: &str, defaults: PoolSettings, value: F) -> Result<PoolSettings, Error>
+        where F: Fn(&str, i64) -> Result<i64, Error>
+    {
+        let max_hops = MAX_HOPS as i64;
+        let read = |name: &str, default: i64, min: i64, max: i64| {
+            let key = format!("{}.{}", prefix, name);
+            let setting = value(&key, default)?;
+            if setting < min || setting > max {
+                return Err(Error::Configuration(format!("{} must be between {} and {}",
+                                                        key,
+                                                        min,
+                                                        max)));
+            }
+            Ok(setting)
+        };
+
+        Ok(PoolSettings {
+            length: read("length", defaults.length as i64, 0, max_hops)? as u8,
+            length_variance: read("lengthVariance",
+                                  defaults.length_variance as i64,
+                                  -max_hops,
+                                  max_hops)? as i8,
+            quantity: read("quantity", defaults.quantity as i64, 1, 16)? as u8,
+            backup_quantity: read("backupQuantity", defaults.backup_quantity as i64, 0, 16)? as u8,
+        })
+    }
+
+    /// The exploratory pool settings, from `router.inboundPool.*` or
+    /// `router.outboundPool.*`
+    pub fn from_config(config: &Config, direction: Direction) -> Result<PoolSettings, Error> {
+        let prefix = match direction {
+            Direction::Inbound => "router.inboundPool",
+            Direction::Outbound => "router.outboundPool",
+        };
+        PoolSettings::read(prefix,
+                           PoolSettings::exploratory(),
+                           |key, default| Ok(config.i64_value(key, Some(default)).unwrap()))
+    }
+
+    /// A client's pool settings, from its `inbound.*` or `outbound.*`
+    /// options
+    pub fn from_options(options: &Mapping, direction: Direction) -> Result<PoolSettings, Error> {
+        let prefix = match direction {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        };
+        PoolSettings::read(prefix, PoolSettings::client(), |key, default| {
+            match options.get(key) {
+                Some(value) => {
+                    value.parse::<i64>()
+                        .map_err(|_| Error::Configuration(format!("Bad {}: {}", key, value)))
+                }
+                None => Ok(default),
+            }
+        })
+    }
+
+    /// How many hops the next tunnel gets
+    pub fn hop_count<R: Rng>(&self, rng: &mut R) -> usize {
+        let variance = self.length_variance as i64;
+        let adjustment = if variance > 0 {
+            rng.gen_range(0, variance + 1)
+        } else if variance < 0 {
+            rng.gen_range(variance, -variance + 1)
+        } else {
+            0
+        };
+
+        (self.length as i64 + adjustment).max(0).min(MAX_HOPS as i64) as usize
+    }
+}
+
+/// One of a tunnel's hops other than us
+#[derive(Clone)]
+pub struct Hop {
+    pub ident: Hash,
+    pub receive_tunnel: u32,
+    keys: LayerKeys,
+}
+
+#[derive(Clone)]
+pub struct Tunnel {
+    /// For an inbound tunnel, the ID we get its messages on; for an
+    /// outbound one, the ID its first hop does. No two of our tunnels have
+    /// the same ID.
+    pub id: u32,
+    pub pool: PoolId,
+    pub direction: Direction,
+    /// In tunnel order, so an inbound tunnel's gateway is first and an
+    /// outbound tunnel's endpoint is last. Zero-hop tunnels have none.
+    pub hops: Vec<Hop>,
+    pub expiration: Date,
+    /// When we last sent a test through it
+    last_test: Option<Date>,
+    test_failures: u32,
+    created: Date,
+    /// How much we've sent or received through it
+    bytes: u64,
+}
+
+impl Tunnel {
+    pub fn is_zero_hop(&self) -> bool {
+        self.hops.is_empty()
+    }
+
+    /// Where an inbound tunnel's messages go in: its gateway, and the ID the
+    /// gateway gets them on. We're the gateway of zero-hop tunnels.
+    pub fn gateway(&self, us: &Hash) -> (Hash, u32) {
+        match self.hops.first() {
+            Some(hop) => (hop.ident.clone(), hop.receive_tunnel),
+            None => (us.clone(), self.id),
+        }
+    }
+
+    /// Takes every hop's layer off, which is what we do to TunnelData that
+    /// comes out of an inbound tunnel and, in advance, to what we send into
+    /// an outbound one
+    pub fn remove_layers(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut data = data.to_vec();
+        for hop in self.hops.iter().rev() {
+            data = hop.keys.decrypt(&data)?;
+        }
+
+        Ok(data)
+    }
+
+    /// Fragments messages to send out through an outbound tunnel, returning
+    /// the TunnelData for its first hop
+    pub fn tunnel_messages(&self,
+                           messages: &[(DeliveryInstructions, I2NPMessage)])
+                           -> Result<(Hash, Vec<I2NPMessage>), Error> {
+        let first_hop = match self.hops.first() {
+            Some(hop) if self.direction == Direction::Outbound => hop.ident.clone(),
+            _ => {
+                return Err(Error::Tunnel(format!("Can't send through {:?} tunnel {} with {} \
+                                                  hops",
+                                                 self.direction,
+                                                 self.id,
+                                                 self.hops.len())))
+            }
+        };
+        let mut tunnel_messages: Vec<I2NPMessage> = Vec::new();
+        for tunnel_message in fragment::fragment(messages)? {
+            let tunnel_data = TunnelData {
+                tunnel_id: self.id,
+                data: self.remove_layers(&tunnel_message)?,
+            };
+            tunnel_messages.push(I2NPMessage::new(MessageBody::TunnelData(tunnel_data)));
+        }
+
+        Ok((first_hop, tunnel_messages))
+    }
+}
+
+/// Chooses the routers our tunnels go through
+pub trait PeerSelector: Send + Sync {
+    /// Up to `count` different routers for a tunnel in the given pool, never
+    /// including us. Fewer means we don't know enough routers yet.
+    fn select(&self, pool: &PoolId, count: usize) -> Vec<Arc<RouterInfo>>;
+
+    /// A hop's answer to one of our build requests, and how long the build
+    /// took, in milliseconds
+    fn build_reply(&self, _peer: &Hash, _reply: u8, _latency: u64) {}
+
+    /// A hop in a build that never came back
+    fn build_timed_out(&self, _peer: &Hash) {}
+
+    /// A hop in a tunnel we've finished with, how much went through the
+    /// tunnel, and how long it lasted, in milliseconds
+    fn tunnel_finished(&self, _peer: &Hash, _bytes: u64, _lifetime: u64) {}
+}
+
+/// A tunnel build message, and how to send it
+pub struct BuildMessage {
+    /// The first hop of the tunnel being built
+    pub to: Arc<RouterInfo>,
+    /// One of our outbound tunnels to send it through, if we have one
+    pub outbound: Option<Tunnel>,
+    pub message: I2NPMessage,
+}
+
+/// A DeliveryStatus to send out through one of our outbound tunnels to the
+/// gateway of one of our inbound tunnels
+pub struct TunnelTest {
+    pub outbound: Tunnel,
+    pub gateway: Hash,
+    pub tunnel_id: u32,
+    pub message: I2NPMessage,
+}
+
+struct Pool {
+    settings: PoolSettings,
+    tunnels: Vec<Tunnel>,
+}
+
+struct PendingTunnel {
+    tunnel: Tunnel,
+    build: PendingBuild,
+    /// The zero-hop tunnel an outbound tunnel's build reply comes back
+    /// through, when there was no inbound tunnel for it
+    reply_tunnel: Option<u32>,
+    deadline: Date,
+}
+
+struct PendingTest {
+    pool: PoolId,
+    outbound: u32,
+    inbound: u32,
+    deadline: Date,
+}
+
+struct State {
+    pools: HashMap<(PoolId, Direction), Pool>,
+    /// Builds waiting for a reply, by the reply's message ID
+    pending: HashMap<u32, PendingTunnel>,
+    /// Tests waiting to come back, by the DeliveryStatus message ID
+    tests: HashMap<u32, PendingTest>,
+    /// For our inbound tunnels, by tunnel ID
+    reassemblers: HashMap<u32, Reassembler>,
+}
+
+impl State {
+    fn tunnels(&self, direction: Direction) -> Vec<&Tunnel> {
+        self.pools
+            .iter()
+            .filter(|&(key, _)| key.1 == direction)
+            .flat_map(|(_, pool)| pool.tunnels.iter())
+            .collect()
+    }
+
+    fn tunnel_mut(&mut self, pool: &PoolId, direction: Direction, id: u32) -> Option<&mut Tunnel> {
+        match self.pools.get_mut(&(pool.clone(), direction)) {
+            Some(pool) => pool.tunnels.iter_mut().find(|tunnel| tunnel.id == id),
+            None => None,
+        }
+    }
+
+    /// A random tunnel ID that none of our tunnels, or tunnels being built,
+    /// are using
+    fn new_tunnel_id(&self) -> u32 {
+        loop {
+            let id: u32 = thread_rng().gen();
+            let in_use = id == 0 ||
+                         self.pools
+                .values()
+                .any(|pool| pool.tunnels.iter().any(|tunnel| tunnel.id == id)) ||
+                         self.pending
+                .values()
+                .any(|pending| pending.tunnel.id == id || pending.reply_tunnel == Some(id));
+            if !in_use {
+                return id;
+            }
+        }
+    }
+
+    /// One of a pool's tunnels with hops, or failing that one of the
+    /// exploratory tunnels, for a build to go out or come back through
+    fn build_tunnel(&self, pool: &PoolId, direction: Direction) -> Option<Tunnel> {
+        for pool_id in &[pool.clone(), PoolId::Exploratory] {
+            let tunnels: Vec<&Tunnel> = match self.pools.get(&(pool_id.clone(), direction)) {
+                Some(pool) => pool.tunnels.iter().filter(|tunnel| !tunnel.is_zero_hop()).collect(),
+                None => continue,
+            };
+            if let Some(tunnel) = thread_rng().choose(&tunnels) {
+                return Some((*tunnel).clone());
+            }
+        }
+
+        None
+    }
+
+    fn exploratory_ready(&self) -> bool {
+        [Direction::Inbound, Direction::Outbound].iter().all(|&direction| {
+            self.pools
+                .get(&(PoolId::Exploratory, direction))
+                .is_some_and(|pool| !pool.tunnels.is_empty())
+        })
+    }
+}
+
+pub struct TunnelPools {
+    ident: Hash,
+    selector: Box<dyn PeerSelector>,
+    lifecycle: Arc<Lifecycle>,
+    state: Mutex<State>,
+}
+
+impl TunnelPools {
+    /// Sets up the exploratory pools. Nothing's built until the first call
+    /// to `maintain()`.
+    pub fn new(ident: Hash,
+               inbound: PoolSettings,
+               outbound: PoolSettings,
+               selector: Box<dyn PeerSelector>,
+               lifecycle: Arc<Lifecycle>)
+               -> TunnelPools {
+        let mut pools = HashMap::new();
+        for &(direction, settings) in [(Direction::Inbound, inbound),
+                                       (Direction::Outbound, outbound)]
+            .iter() {
+            pools.insert((PoolId::Exploratory, direction),
+                         Pool {
+                             settings,
+                             tunnels: Vec::new(),
+                         });
+        }
+
+        TunnelPools {
+            ident,
+            selector,
+            lifecycle,
+            state: Mutex::new(State {
+                pools,
+                pending: HashMap::new(),
+                tests: HashMap::new(),
+                reassemblers: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Adds pools for a client destination, which are built on the next
+    /// call to `maintain()`
+    pub fn add_client(&self, destination: Hash, inbound: PoolSettings, outbound: PoolSettings) {
+        let mut state = self.state.lock().unwrap();
+        for &(direction, settings) in [(Direction::Inbound, inbound),
+                                       (Direction::Outbound, outbound)]
+            .iter() {
+            state.pools.insert((PoolId::Client(destination.clone()), direction),
+                               Pool {
+                                   settings,
+                                   tunnels: Vec::new(),
+                               });
+        }
+    }
+
+    /// Drops a client's pools and their tunnels
+    pub fn remove_client(&self, destination: &Hash) {
+        let pool = PoolId::Client(destination.clone());
+        let mut state = self.state.lock().unwrap();
+        state.pools.remove(&(pool.clone(), Direction::Inbound));
+        state.pools.remove(&(pool.clone(), Direction::Outbound));
+        state.pending.retain(|_, pending| pending.tunnel.pool != pool);
+        state.tests.retain(|_, test| test.pool != pool);
+    }
+
+    /// How many tunnels a pool has built
+    pub fn tunnel_count(&self, pool: &PoolId, direction: Direction) -> usize {
+        let state = self.state.lock().unwrap();
+        state.pools.get(&(pool.clone(), direction)).map_or(0, |pool| pool.tunnels.len())
+    }
+
+    /// One of a pool's tunnels to use, leaving the spares that expire
+    /// soonest alone
+    pub fn select_tunnel(&self, pool: &PoolId, direction: Direction) -> Option<Tunnel> {
+        let state = self.state.lock().unwrap();
+        let pool = state.pools.get(&(pool.clone(), direction))?;
+        let mut tunnels: Vec<&Tunnel> = pool.tunnels.iter().collect();
+        tunnels.sort_by_key(|tunnel| Reverse(tunnel.expiration));
+        tunnels.truncate(pool.settings.quantity as usize);
+
+        thread_rng().choose(&tunnels).map(|&tunnel| tunnel.clone())
+    }
+
+    /// Drops expired tunnels, builds, and tests, and tunnels that keep
+    /// failing their tests, then starts building whatever the pools are
+    /// short of. Returns the build messages to send.
+    pub fn maintain(&self, now: Date) -> Vec<BuildMessage> {
+        let mut state = self.state.lock().unwrap();
+
+        let timed_out: Vec<u32> = state.pending
+            .iter()
+            .filter(|&(_, pending)| pending.deadline <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in timed_out {
+            let pending = state.pending.remove(&id).unwrap();
+            info!("Tunnels: build of {:?} {:?} tunnel {} timed out",
+                  pending.tunnel.pool,
+                  pending.tunnel.direction,
+                  pending.tunnel.id);
+            for hop in &pending.tunnel.hops {
+                self.selector.build_timed_out(&hop.ident);
+            }
+        }
+
+        let failed: Vec<u32> = state.tests
+            .iter()
+            .filter(|&(_, test)| test.deadline <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in failed {
+            let test = state.tests.remove(&id).unwrap();
+            debug!("Tunnels: test of tunnels {} and {} failed", test.outbound, test.inbound);
+            for &(direction, tunnel_id) in [(Direction::Outbound, test.outbound),
+                                            (Direction::Inbound, test.inbound)]
+                .iter() {
+                if let Some(tunnel) = state.tunnel_mut(&test.pool, direction, tunnel_id) {
+                    tunnel.test_failures += 1;
+                }
+            }
+        }
+
+        let mut finished: Vec<Tunnel> = Vec::new();
+        for (&(ref pool_id, direction), pool) in state.pools.iter_mut() {
+            let (kept, dropped): (Vec<Tunnel>, Vec<Tunnel>) = pool.tunnels
+                .drain(..)
+                .partition(|tunnel| {
+                    if tunnel.test_failures >= MAX_TEST_FAILURES {
+                        info!("Tunnels: dropping {:?} {:?} tunnel {}, which keeps failing \
+                               tests",
+                              pool_id,
+                              direction,
+                              tunnel.id);
+                        return false;
+                    }
+                    tunnel.expiration > now
+                });
+            pool.tunnels = kept;
+            finished.extend(dropped);
+        }
+        for tunnel in finished {
+            let lifetime = now.millis().saturating_sub(tunnel.created.millis());
+            for hop in &tunnel.hops {
+                self.selector.tunnel_finished(&hop.ident, tunnel.bytes, lifetime);
+            }
+        }
+        let inbound: Vec<u32> = state.tunnels(Direction::Inbound)
+            .iter()
+            .map(|tunnel| tunnel.id)
+            .collect();
+        state.reassemblers.retain(|id, _| inbound.contains(id));
+        for reassembler in state.reassemblers.values_mut() {
+            reassembler.expire(now);
+        }
+
+        let mut messages: Vec<BuildMessage> = Vec::new();
+        let keys: Vec<(PoolId, Direction)> = state.pools.keys().cloned().collect();
+        for (pool_id, direction) in keys {
+            let (wanted, lasting) = {
+                let pool = &state.pools[&(pool_id.clone(), direction)];
+                let lasting = pool.tunnels
+                    .iter()
+                    .filter(|tunnel| tunnel.expiration.millis() > now.millis() + REBUILD_MARGIN)
+                    .count();
+                ((pool.settings.quantity + pool.settings.backup_quantity) as usize, lasting)
+            };
+            let building = state.pending
+                .values()
+                .filter(|pending| {
+                    pending.tunnel.pool == pool_id && pending.tunnel.direction == direction
+                })
+                .count();
+            for _ in (lasting + building)..wanted {
+                match self.build(&mut state, &pool_id, direction, now) {
+                    Ok(Some(message)) => messages.push(message),
+                    Ok(None) => {}
+                    Err(error) => {
+                        warn!("Tunnels: can't build {:?} {:?} tunnel: {}",
+                              pool_id,
+                              direction,
+                              error);
+                        break;
+                    }
+                }
+            }
+        }
+
+        self.check_ready(&state);
+        messages
+    }
+
+    /// Starts building a tunnel for a pool, returning the build message.
+    /// Zero-hop tunnels are built straight away.
+    fn build(&self,
+             state: &mut State,
+             pool_id: &PoolId,
+             direction: Direction,
+             now: Date)
+             -> Result<Option<BuildMessage>, Error> {
+        let hop_count = state.pools[&(pool_id.clone(), direction)]
+            .settings
+            .hop_count(&mut thread_rng());
+        let id = state.new_tunnel_id();
+        let lifetime = DEFAULT_TUNNEL_LIFETIME as u64 * 1000;
+        let mut tunnel = Tunnel {
+            id,
+            pool: pool_id.clone(),
+            direction,
+            hops: Vec::new(),
+            expiration: Date::from_millis(now.millis() + lifetime),
+            last_test: None,
+            test_failures: 0,
+            created: now,
+            bytes: 0,
+        };
+        if hop_count == 0 {
+            state.pools.get_mut(&(pool_id.clone(), direction)).unwrap().tunnels.push(tunnel);
+            return Ok(None);
+        }
+
+        let peers = self.selector.select(pool_id, hop_count);
+        if peers.len() < hop_count {
+            return Err(Error::Tunnel(format!("Only have {} of the {} routers needed",
+                                             peers.len(),
+                                             hop_count)));
+        }
+        let mut receive_tunnels: Vec<u32> = Vec::with_capacity(hop_count);
+        for i in 0..hop_count {
+            receive_tunnels.push(if i == 0 && direction == Direction::Outbound {
+                id
+            } else {
+                thread_rng().gen_range(1, u32::MAX)
+            });
+        }
+        // Where the last hop sends the build message. We only show up as
+        // the next hop of our own inbound tunnels' last hops.
+        let mut outbound: Option<Tunnel> = None;
+        let mut reply_tunnel: Option<u32> = None;
+        let reply_to = match direction {
+            Direction::Inbound => {
+                outbound = state.build_tunnel(pool_id, Direction::Outbound);
+                (self.ident.clone(), id)
+            }
+            Direction::Outbound => {
+                match state.build_tunnel(pool_id, Direction::Inbound) {
+                    Some(inbound) => inbound.gateway(&self.ident),
+                    None => {
+                        let mut zero_hop = state.new_tunnel_id();
+                        while zero_hop == id {
+                            zero_hop = state.new_tunnel_id();
+                        }
+                        reply_tunnel = Some(zero_hop);
+                        (self.ident.clone(), zero_hop)
+                    }
+                }
+            }
+        };
+
+        let mut configs: Vec<HopConfig> = Vec::with_capacity(hop_count);
+        for (i, peer) in peers.iter().enumerate() {
+            let (next_ident, next_tunnel) = if i + 1 < hop_count {
+                (peers[i + 1].hash()?, receive_tunnels[i + 1])
+            } else {
+                reply_to.clone()
+            };
+            let flags = match direction {
+                Direction::Inbound if i == 0 => FLAG_INBOUND_GATEWAY,
+                Direction::Outbound if i + 1 == hop_count => FLAG_OUTBOUND_ENDPOINT,
+                _ => 0,
+            };
+            let ident = peer.hash()?;
+            let request = BuildRequest::new(receive_tunnels[i],
+                                            ident.clone(),
+                                            next_tunnel,
+                                            next_ident,
+                                            flags)?;
+            configs.push(HopConfig {
+                ident,
+                encryption_key: peer.identity().public_key().clone(),
+                request,
+            });
+        }
+
+        let layout = if configs.iter()
+            .all(|config| config.encryption_key.get_type() == PublicKeyType::X25519) {
+            RecordLayout::Short
+        } else {
+            RecordLayout::Variable
+        };
+        let (build, records) = PendingBuild::new(&mut configs,
+                                                 layout,
+                                                 hop_count.max(MIN_RECORDS))?;
+        for config in &configs {
+            tunnel.hops.push(Hop {
+                ident: config.ident.clone(),
+                receive_tunnel: config.request.receive_tunnel,
+                keys: LayerKeys::new(&config.request.layer_key, &config.request.iv_key)?,
+            });
+        }
+        debug!("Tunnels: building {:?} {:?} tunnel {} with {} hops",
+               pool_id,
+               direction,
+               id,
+               hop_count);
+
+        let reply_id = configs[hop_count - 1].request.send_message_id;
+        state.pending.insert(reply_id,
+                             PendingTunnel {
+                                 tunnel,
+                                 build,
+                                 reply_tunnel,
+                                 deadline: Date::from_millis(now.millis() + BUILD_TIMEOUT),
+                             });
+        let body = match layout {
+            RecordLayout::Short => MessageBody::ShortTunnelBuild(records),
+            _ => MessageBody::VariableTunnelBuild(records),
+        };
+
+        Ok(Some(BuildMessage {
+            to: peers[0].clone(),
+            outbound,
+            message: I2NPMessage::new(body),
+        }))
+    }
+
+    /// Tells the router once there are exploratory tunnels both ways
+    fn check_ready(&self, state: &State) {
+        match self.lifecycle.state() {
+            RouterState::STARTING_3 |
+            RouterState::NETDB_READY if state.exploratory_ready() => {
+                if let Err(error) = self.lifecycle.exploratory_tunnels_ready() {
+                    error!("Tunnels: {}", error);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Reads the replies to one of our builds, adding the tunnel to its
+    /// pool if every hop said yes. Returns false if the message isn't a
+    /// reply to one of our builds.
+    pub fn handle_build_reply(&self, message: &I2NPMessage, now: Date) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+        let pending = match state.pending.remove(&message.message_id) {
+            Some(pending) => pending,
+            None => return Ok(false),
+        };
+        let records = match message.body.build_records() {
+            Some((records, _)) => records,
+            None => {
+                return Err(Error::Tunnel(format!("{:?} isn't a tunnel build reply",
+                                                 message.message_type())))
+            }
+        };
+        let replies = pending.build.decrypt_replies(records)?;
+        let mut tunnel = pending.tunnel;
+        let latency = now.millis().saturating_sub(pending.deadline.millis() - BUILD_TIMEOUT);
+        for (hop, &reply) in tunnel.hops.iter().zip(replies.iter()) {
+            self.selector.build_reply(&hop.ident, reply, latency);
+        }
+        if replies.iter().any(|&reply| reply != REPLY_ACCEPT) {
+            info!("Tunnels: {:?} {:?} tunnel {} turned down: {:?}",
+                  tunnel.pool,
+                  tunnel.direction,
+                  tunnel.id,
+                  replies);
+            return Ok(true);
+        }
+
+        info!("Tunnels: built {:?} {:?} tunnel {}", tunnel.pool, tunnel.direction, tunnel.id);
+        tunnel.created = now;
+        tunnel.expiration = Date::from_millis(now.millis() +
+                                              DEFAULT_TUNNEL_LIFETIME as u64 * 1000);
+        // The client may have gone while it was being built
+        if let Some(pool) = state.pools.get_mut(&(tunnel.pool.clone(), tunnel.direction)) {
+            pool.tunnels.push(tunnel);
+        }
+        self.check_ready(&state);
+
+        Ok(true)
+    }
+
+    /// Unwraps a message sent to one of the zero-hop tunnels we're the
+    /// gateway for, to be handled as if it came straight to us. Returns
+    /// None if the tunnel isn't one of ours.
+    pub fn handle_tunnel_gateway(&self,
+                                 gateway: &TunnelGateway)
+                                 -> Result<Option<I2NPMessage>, Error> {
+        let state = self.state.lock().unwrap();
+        let ours = state.pending
+            .values()
+            .any(|pending| pending.reply_tunnel == Some(gateway.tunnel_id)) ||
+                   state.tunnels(Direction::Inbound)
+            .iter()
+            .any(|tunnel| tunnel.id == gateway.tunnel_id && tunnel.is_zero_hop());
+        if !ours {
+            return Ok(None);
+        }
+
+        Ok(Some(gateway.message()?))
+    }
+
+    /// Takes the layers off TunnelData coming out of one of our inbound
+    /// tunnels, returning the messages it completes. Returns None if the
+    /// tunnel isn't one of ours.
+    pub fn handle_tunnel_data(&self,
+                              tunnel_data: &TunnelData,
+                              now: Date)
+                              -> Result<Option<Vec<I2NPMessage>>, Error> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let data = {
+            let tunnel = state.pools
+                .iter_mut()
+                .filter(|&(key, _)| key.1 == Direction::Inbound)
+                .flat_map(|(_, pool)| pool.tunnels.iter_mut())
+                .find(|tunnel| tunnel.id == tunnel_data.tunnel_id);
+            match tunnel {
+                Some(tunnel) if tunnel.expiration > now => {
+                    tunnel.bytes += tunnel_data.data.len() as u64;
+                    tunnel.remove_layers(&tunnel_data.data)?
+                }
+                _ => return Ok(None),
+            }
+        };
+
+        let reassembler = state.reassemblers
+            .entry(tunnel_data.tunnel_id)
+            .or_default();
+        let mut messages: Vec<I2NPMessage> = Vec::new();
+        for (instructions, message) in reassembler.receive(&data, now)? {
+            match instructions {
+                DeliveryInstructions::Local => messages.push(message),
+                _ => {
+                    debug!("Tunnels: dropping {:?} message {} for {:?} from tunnel {}",
+                           message.message_type(),
+                           message.message_id,
+                           instructions,
+                           tunnel_data.tunnel_id)
+                }
+            }
+        }
+
+        Ok(Some(messages))
+    }
+
+    /// Counts what we've sent out through one of our outbound tunnels
+    pub fn record_sent(&self, tunnel_id: u32, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let tunnel = state.pools
+            .iter_mut()
+            .filter(|&(key, _)| key.1 == Direction::Outbound)
+            .flat_map(|(_, pool)| pool.tunnels.iter_mut())
+            .find(|tunnel| tunnel.id == tunnel_id);
+        if let Some(tunnel) = tunnel {
+            tunnel.bytes += bytes;
+        }
+    }
+
+    /// Tests for every outbound tunnel that's due one, each paired with an
+    /// inbound tunnel from the same pool
+    pub fn tests(&self, now: Date) -> Vec<TunnelTest> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let mut tests: Vec<TunnelTest> = Vec::new();
+        let pool_ids: Vec<PoolId> = state.pools
+            .keys()
+            .filter(|key| key.1 == Direction::Outbound)
+            .map(|key| key.0.clone())
+            .collect();
+        for pool_id in pool_ids {
+            let inbound: Vec<(u32, (Hash, u32), bool)> =
+                match state.pools.get(&(pool_id.clone(), Direction::Inbound)) {
+                    Some(pool) => {
+                        pool.tunnels
+                            .iter()
+                            .map(|tunnel| {
+                                (tunnel.id, tunnel.gateway(&self.ident), tunnel.is_zero_hop())
+                            })
+                            .collect()
+                    }
+                    None => continue,
+                };
+            let outbound = state.pools.get_mut(&(pool_id.clone(), Direction::Outbound)).unwrap();
+            for tunnel in outbound.tunnels.iter_mut() {
+                let due = tunnel.last_test
+                    .is_none_or(|tested| tested.millis() + TEST_INTERVAL <= now.millis());
+                let &(inbound_id, ref gateway, zero_hop) = match thread_rng().choose(&inbound) {
+                    Some(inbound) if due => inbound,
+                    _ => continue,
+                };
+                // There's nothing to test
+                if zero_hop && tunnel.is_zero_hop() {
+                    continue;
+                }
+
+                tunnel.last_test = Some(now);
+                let status = DeliveryStatus {
+                    message_id: thread_rng().gen(),
+                    timestamp: now,
+                };
+                state.tests.insert(status.message_id,
+                                   PendingTest {
+                                       pool: pool_id.clone(),
+                                       outbound: tunnel.id,
+                                       inbound: inbound_id,
+                                       deadline: Date::from_millis(now.millis() + TEST_TIMEOUT),
+                                   });
+                tests.push(TunnelTest {
+                    outbound: tunnel.clone(),
+                    gateway: gateway.0.clone(),
+                    tunnel_id: gateway.1,
+                    message: I2NPMessage::new(MessageBody::DeliveryStatus(status)),
+                });
+            }
+        }
+
+        tests
+    }
+
+    /// Passes the tunnels a test was for, if the DeliveryStatus is from one
+    /// of our tests
+    pub fn handle_delivery_status(&self, status: &DeliveryStatus, now: Date) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let test = match state.tests.remove(&status.message_id) {
+            Some(test) => test,
+            None => return false,
+        };
+        debug!("Tunnels: test of tunnels {} and {} took {}ms",
+               test.outbound,
+               test.inbound,
+               now.millis().saturating_sub(status.timestamp.millis()));
+        for &(direction, tunnel_id) in [(Direction::Outbound, test.outbound),
+                                        (Direction::Inbound, test.inbound)]
+            .iter() {
+            if let Some(tunnel) = state.tunnel_mut(&test.pool, direction, tunnel_id) {
+                tunnel.test_failures = 0;
+            }
+        }
+
+        true
+    }
+
+    /// Forgets every tunnel, and every build and test in progress
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        for pool in state.pools.values_mut() {
+            pool.tunnels.clear();
+        }
+        state.pending.clear();
+        state.tests.clear();
+        state.reassemblers.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::SigningPublicKeyType;
+    use i2p::data::private_keys::PrivateKeys;
+    use i2p::tunnel::participating::{Delivery, ParticipatingTunnels};
+    use super::*;
+
+    /// Routers to build tunnels through, each with its own participating
+    /// tunnels, and what the pools have said about them
+    struct Network {
+        routers: Vec<Arc<RouterInfo>>,
+        participating: HashMap<Hash, ParticipatingTunnels>,
+        replies: Mutex<Vec<u8>>,
+        timeouts: Mutex<usize>,
+    }
+
+    impl PeerSelector for Arc<Network> {
+        fn select(&self, _pool: &PoolId, count: usize) -> Vec<Arc<RouterInfo>> {
+            self.routers.iter().take(count).cloned().collect()
+        }
+
+        fn build_reply(&self, _peer: &Hash, reply: u8, _latency: u64) {
+            self.replies.lock().unwrap().push(reply);
+        }
+
+        fn build_timed_out(&self, _peer: &Hash) {
+            *self.timeouts.lock().unwrap() += 1;
+        }
+    }
+
+    fn running_lifecycle(state: RouterState) -> Arc<Lifecycle> {
+        let lifecycle = Arc::new(Lifecycle::new());
+        let steps = [RouterState::STARTING_1, RouterState::STARTING_2, RouterState::STARTING_3];
+        for &step in steps.iter() {
+            lifecycle.transition(step).unwrap();
+        }
+        if state == RouterState::NETDB_READY {
+            lifecycle.netdb_ready().unwrap();
+        }
+        lifecycle
+    }
+
+    /// Routers that have got as far as `state` in starting up
+    fn network(size: usize, state: RouterState) -> Arc<Network> {
+        let mut routers: Vec<Arc<RouterInfo>> = Vec::new();
+        let mut participating: HashMap<Hash, ParticipatingTunnels> = HashMap::new();
+        for _ in 0..size {
+            let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                             &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+                .unwrap();
+            let router_info = RouterInfo::new(&keys, Date::now(), Vec::new(), Mapping::new())
+                .unwrap();
+            let hash = router_info.hash().unwrap();
+            participating.insert(hash.clone(),
+                                 ParticipatingTunnels::new(&Config::default(),
+                                                           hash,
+                                                           keys.private_key().clone(),
+                                                           running_lifecycle(state))
+                                     .unwrap());
+            routers.push(Arc::new(router_info));
+        }
+
+        Arc::new(Network {
+            routers,
+            participating,
+            replies: Mutex::new(Vec::new()),
+            timeouts: Mutex::new(0),
+        })
+    }
+
+    fn settings(length: u8, quantity: u8) -> PoolSettings {
+        PoolSettings {
+            length,
+            length_variance: 0,
+            quantity,
+            backup_quantity: 0,
+        }
+    }
+
+    fn us() -> Hash {
+        Hash::SHA256(vec![1u8; 32].into_boxed_slice())
+    }
+
+    /// Passes a build message down the tunnel and back to us, going out
+    /// through our outbound tunnel and back in through our inbound tunnel
+    /// if the build uses them
+    fn deliver(network: &Network, pools: &TunnelPools, build: BuildMessage) {
+        let mut to = build.to.hash().unwrap();
+        let mut message = match build.outbound {
+            Some(ref tunnel) => {
+                let instructions = DeliveryInstructions::Router(to.clone());
+                let mut delivered =
+                    through_outbound(network, tunnel, &[(instructions.clone(), build.message)]);
+                assert_eq!(1, delivered.len());
+                let (delivered_to, message) = delivered.pop().unwrap();
+                assert_eq!(instructions, delivered_to);
+                message
+            }
+            None => build.message,
+        };
+        while to != us() {
+            let (next_ident, next) = network.participating[&to]
+                .handle_build(&message, Date::now())
+                .unwrap();
+            to = next_ident;
+            message = next;
+            let received = match message.body {
+                MessageBody::TunnelGateway(ref gateway) if to != us() => {
+                    Some(through_inbound(network, pools, &to, gateway))
+                }
+                _ => None,
+            };
+            if let Some(mut received) = received {
+                assert_eq!(1, received.len());
+                to = us();
+                message = received.pop().unwrap();
+            }
+        }
+
+        let reply = match message.body {
+            MessageBody::TunnelGateway(ref gateway) => {
+                pools.handle_tunnel_gateway(gateway).unwrap().unwrap()
+            }
+            _ => message,
+        };
+        assert!(pools.handle_build_reply(&reply, Date::now()).unwrap());
+    }
+
+    fn tunnel_data(message: &I2NPMessage) -> &TunnelData {
+        match message.body {
+            MessageBody::TunnelData(ref tunnel_data) => tunnel_data,
+            _ => panic!("Expected TunnelData, got {:?}", message.message_type()),
+        }
+    }
+
+    /// Gives a message to the gateway of one of our inbound tunnels,
+    /// returning what comes out of the tunnel to us
+    fn through_inbound(network: &Network,
+                       pools: &TunnelPools,
+                       gateway_ident: &Hash,
+                       gateway: &TunnelGateway)
+                       -> Vec<I2NPMessage> {
+        let (mut to, mut messages) = network.participating[gateway_ident]
+            .handle_tunnel_gateway(gateway, Date::now())
+            .unwrap();
+        while to != us() {
+            let mut next_to = to.clone();
+            messages = messages.iter()
+                .map(|message| {
+                    match network.participating[&to]
+                        .handle_tunnel_data(tunnel_data(message), Date::now())
+                        .unwrap() {
+                        Delivery::Forward(next_ident, next) => {
+                            next_to = next_ident;
+                            next
+                        }
+                        Delivery::Endpoint(..) => panic!("Expected a participant to forward"),
+                    }
+                })
+                .collect();
+            to = next_to;
+        }
+
+        let mut received: Vec<I2NPMessage> = Vec::new();
+        for message in &messages {
+            received.extend(pools.handle_tunnel_data(tunnel_data(message), Date::now())
+                .unwrap()
+                .unwrap());
+        }
+        received
+    }
+
+    /// Sends messages out through one of our outbound tunnels, returning
+    /// what comes out of the endpoint
+    fn through_outbound(network: &Network,
+                        tunnel: &Tunnel,
+                        messages: &[(DeliveryInstructions, I2NPMessage)])
+                        -> Vec<(DeliveryInstructions, I2NPMessage)> {
+        let (mut to, mut tunnel_messages) = tunnel.tunnel_messages(messages).unwrap();
+        let mut delivered: Vec<(DeliveryInstructions, I2NPMessage)> = Vec::new();
+        while !tunnel_messages.is_empty() {
+            let mut next_messages: Vec<I2NPMessage> = Vec::new();
+            let mut next_to = to.clone();
+            for message in &tunnel_messages {
+                match network.participating[&to]
+                    .handle_tunnel_data(tunnel_data(message), Date::now())
+                    .unwrap() {
+                    Delivery::Forward(next_ident, next) => {
+                        next_to = next_ident;
+                        next_messages.push(next);
+                    }
+                    Delivery::Endpoint(messages) => delivered.extend(messages),
+                }
+            }
+            to = next_to;
+            tunnel_messages = next_messages;
+        }
+
+        delivered
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut options = Mapping::new();
+        options.insert("inbound.length", "1");
+        options.insert("inbound.lengthVariance", "-1");
+        options.insert("inbound.backupQuantity", "1");
+        let inbound = PoolSettings::from_options(&options, Direction::Inbound).unwrap();
+        assert_eq!(PoolSettings {
+                       length: 1,
+                       length_variance: -1,
+                       quantity: 2,
+                       backup_quantity: 1,
+                   },
+                   inbound);
+        assert_eq!(PoolSettings::client(),
+                   PoolSettings::from_options(&options, Direction::Outbound).unwrap());
+        for _ in 0..20 {
+            assert!(inbound.hop_count(&mut thread_rng()) <= 2);
+        }
+
+        options.insert("outbound.length", "8");
+        assert!(PoolSettings::from_options(&options, Direction::Outbound).is_err());
+        options.insert("outbound.length", "three");
+        assert!(PoolSettings::from_options(&options, Direction::Outbound).is_err());
+    }
+
+    #[test]
+    fn test_zero_hop() {
+        let lifecycle = running_lifecycle(RouterState::STARTING_3);
+        let pools = TunnelPools::new(us(),
+                                     settings(0, 2),
+                                     settings(0, 1),
+                                     Box::new(network(0, RouterState::NETDB_READY)),
+                                     lifecycle.clone());
+        assert!(pools.maintain(Date::now()).is_empty());
+        assert_eq!(2, pools.tunnel_count(&PoolId::Exploratory, Direction::Inbound));
+        assert_eq!(1, pools.tunnel_count(&PoolId::Exploratory, Direction::Outbound));
+        assert_eq!(RouterState::EXPL_TUNNELS_READY, lifecycle.state());
+
+        // Messages sent to our zero-hop inbound tunnels come straight to us
+        let tunnel = pools.select_tunnel(&PoolId::Exploratory, Direction::Inbound).unwrap();
+        assert_eq!((us(), tunnel.id), tunnel.gateway(&us()));
+        let status = I2NPMessage::new(MessageBody::DeliveryStatus(DeliveryStatus {
+            message_id: 1,
+            timestamp: Date::now(),
+        }));
+        let gateway = TunnelGateway::new(tunnel.id, &status).unwrap();
+        assert_eq!(status.message_id,
+                   pools.handle_tunnel_gateway(&gateway).unwrap().unwrap().message_id);
+        let other = TunnelGateway::new(tunnel.id ^ 1, &status).unwrap();
+        assert!(pools.handle_tunnel_gateway(&other).unwrap().is_none());
+
+        // Nothing to test
+        assert!(pools.tests(Date::now()).is_empty());
+    }
+
+    #[test]
+    fn test_build() {
+        let network = network(3, RouterState::NETDB_READY);
+        let lifecycle = running_lifecycle(RouterState::NETDB_READY);
+        let pools = TunnelPools::new(us(),
+                                     settings(2, 1),
+                                     settings(3, 1),
+                                     Box::new(network.clone()),
+                                     lifecycle.clone());
+        let now = Date::now();
+        let messages = pools.maintain(now);
+        assert_eq!(2, messages.len());
+        // Nothing more until these time out
+        assert!(pools.maintain(now).is_empty());
+        assert_eq!(RouterState::NETDB_READY, lifecycle.state());
+
+        // With no tunnels yet, builds go straight to the first hop, and
+        // replies come back to a zero-hop tunnel
+        for build in messages {
+            assert!(build.outbound.is_none());
+            assert_eq!(network.routers[0].hash().unwrap(), build.to.hash().unwrap());
+            deliver(&network, &pools, build);
+        }
+        assert_eq!(1, pools.tunnel_count(&PoolId::Exploratory, Direction::Inbound));
+        assert_eq!(1, pools.tunnel_count(&PoolId::Exploratory, Direction::Outbound));
+        assert_eq!(RouterState::RUNNING, lifecycle.state());
+        assert_eq!(vec![REPLY_ACCEPT; 5], *network.replies.lock().unwrap());
+
+        // What we send through the outbound tunnel comes out of the
+        // endpoint as it went in
+        let outbound = pools.select_tunnel(&PoolId::Exploratory, Direction::Outbound).unwrap();
+        assert_eq!(3, outbound.hops.len());
+        let to = DeliveryInstructions::Router(us());
+        let data = I2NPMessage::new(MessageBody::Data(vec![5u8; 3000]));
+        let delivered = through_outbound(&network, &outbound, &[(to.clone(), data)]);
+        assert_eq!(1, delivered.len());
+        assert_eq!(to, delivered[0].0);
+
+        // And what goes into the inbound tunnel's gateway comes out to us
+        let inbound = pools.select_tunnel(&PoolId::Exploratory, Direction::Inbound).unwrap();
+        let data = I2NPMessage::new(MessageBody::Data(vec![6u8; 3000]));
+        let gateway = TunnelGateway::new(inbound.hops[0].receive_tunnel, &data).unwrap();
+        let received = through_inbound(&network, &pools, &inbound.hops[0].ident, &gateway);
+        assert_eq!(1, received.len());
+        assert_eq!(data.message_id, received[0].message_id);
+    }
+
+    /// Pools with exploratory tunnels built both ways, and client pools
+    /// still to build
+    fn built_pools(network: &Arc<Network>) -> TunnelPools {
+        let pools = TunnelPools::new(us(),
+                                     settings(2, 1),
+                                     settings(2, 1),
+                                     Box::new(network.clone()),
+                                     running_lifecycle(RouterState::NETDB_READY));
+        for build in pools.maintain(Date::now()) {
+            deliver(network, &pools, build);
+        }
+        pools.add_client(us(), settings(2, 1), settings(2, 1));
+        pools
+    }
+
+    fn client_build(pools: &TunnelPools, direction: Direction) -> BuildMessage {
+        let mut builds = pools.maintain(Date::now());
+        assert_eq!(2, builds.len());
+        // Inbound builds have the outbound tunnel to go through
+        let index = match direction {
+            Direction::Inbound => builds.iter().position(|build| build.outbound.is_some()),
+            Direction::Outbound => builds.iter().position(|build| build.outbound.is_none()),
+        };
+        builds.remove(index.unwrap())
+    }
+
+    #[test]
+    fn test_inbound_build_through_outbound_tunnel() {
+        let network = network(3, RouterState::NETDB_READY);
+        let pools = built_pools(&network);
+        let exploratory = pools.select_tunnel(&PoolId::Exploratory, Direction::Outbound)
+            .unwrap();
+
+        let build = client_build(&pools, Direction::Inbound);
+        assert_eq!(exploratory.id, build.outbound.as_ref().unwrap().id);
+        deliver(&network, &pools, build);
+        assert_eq!(1, pools.tunnel_count(&PoolId::Client(us()), Direction::Inbound));
+    }
+
+    #[test]
+    fn test_outbound_build_reply_through_inbound_tunnel() {
+        let network = network(3, RouterState::NETDB_READY);
+        let pools = built_pools(&network);
+        let inbound = pools.select_tunnel(&PoolId::Exploratory, Direction::Inbound).unwrap();
+
+        // The endpoint sends the reply to our inbound tunnel's gateway
+        // rather than to us
+        let build = client_build(&pools, Direction::Outbound);
+        let mut to = build.to.hash().unwrap();
+        let mut message = build.message;
+        let gateway = loop {
+            let (next_ident, next) = network.participating[&to]
+                .handle_build(&message, Date::now())
+                .unwrap();
+            assert!(next_ident != us());
+            to = next_ident;
+            message = next;
+            if let MessageBody::TunnelGateway(ref gateway) = message.body {
+                break gateway.clone();
+            }
+        };
+        assert_eq!(inbound.hops[0].ident, to);
+        assert_eq!(inbound.hops[0].receive_tunnel, gateway.tunnel_id);
+        assert!(pools.handle_tunnel_gateway(&gateway).unwrap().is_none());
+
+        let mut received = through_inbound(&network, &pools, &to, &gateway);
+        assert_eq!(1, received.len());
+        assert!(pools.handle_build_reply(&received.pop().unwrap(), Date::now()).unwrap());
+        assert_eq!(1, pools.tunnel_count(&PoolId::Client(us()), Direction::Outbound));
+    }
+
+    #[test]
+    fn test_rebuild() {
+        // Routers that won't join tunnels yet
+        let network = network(1, RouterState::STARTING_3);
+        let pools = TunnelPools::new(us(),
+                                     settings(0, 1),
+                                     settings(1, 1),
+                                     Box::new(network.clone()),
+                                     running_lifecycle(RouterState::NETDB_READY));
+        let now = Date::now();
+        let mut messages = pools.maintain(now);
+        assert_eq!(1, messages.len());
+
+        // Turned down
+        deliver(&network, &pools, messages.pop().unwrap());
+        assert_eq!(0, pools.tunnel_count(&PoolId::Exploratory, Direction::Outbound));
+        assert!(network.replies.lock().unwrap().iter().all(|&reply| reply != REPLY_ACCEPT));
+
+        // Tried again, but never heard back
+        let later = Date::from_millis(now.millis() + 1000);
+        assert_eq!(1, pools.maintain(later).len());
+        assert!(pools.maintain(later).is_empty());
+        let later = Date::from_millis(later.millis() + BUILD_TIMEOUT);
+        assert_eq!(1, pools.maintain(later).len());
+        assert_eq!(1, *network.timeouts.lock().unwrap());
+
+        // Replaced shortly before it expires
+        let lifetime = DEFAULT_TUNNEL_LIFETIME as u64 * 1000;
+        let nearly_expired = Date::from_millis(now.millis() + lifetime - REBUILD_MARGIN);
+        pools.maintain(nearly_expired);
+        assert_eq!(2, pools.tunnel_count(&PoolId::Exploratory, Direction::Inbound));
+        pools.maintain(Date::from_millis(now.millis() + lifetime));
+        assert_eq!(1, pools.tunnel_count(&PoolId::Exploratory, Direction::Inbound));
+    }
+
+    #[test]
+    fn test_tunnel_tests() {
+        let network = network(1, RouterState::NETDB_READY);
+        let pools = TunnelPools::new(us(),
+                                     settings(0, 1),
+                                     settings(1, 1),
+                                     Box::new(network.clone()),
+                                     running_lifecycle(RouterState::NETDB_READY));
+        let now = Date::now();
+        for build in pools.maintain(now) {
+            deliver(&network, &pools, build);
+        }
+        let outbound = pools.select_tunnel(&PoolId::Exploratory, Direction::Outbound).unwrap();
+        let inbound = pools.select_tunnel(&PoolId::Exploratory, Direction::Inbound).unwrap();
+
+        // Out through the outbound tunnel, back to our zero-hop inbound one
+        let mut tests = pools.tests(now);
+        assert_eq!(1, tests.len());
+        let test = tests.pop().unwrap();
+        assert_eq!(outbound.id, test.outbound.id);
+        assert_eq!((test.gateway.clone(), test.tunnel_id), inbound.gateway(&us()));
+        assert!(pools.tests(now).is_empty());
+        let to = DeliveryInstructions::Tunnel(test.gateway.clone(), test.tunnel_id);
+        let mut delivered =
+            through_outbound(&network, &test.outbound, &[(to.clone(), test.message)]);
+        assert_eq!(1, delivered.len());
+        let (instructions, message) = delivered.pop().unwrap();
+        assert_eq!(to, instructions);
+        let gateway = TunnelGateway::new(test.tunnel_id, &message).unwrap();
+        let message = pools.handle_tunnel_gateway(&gateway).unwrap().unwrap();
+        let status = match message.body {
+            MessageBody::DeliveryStatus(ref status) => status.clone(),
+            _ => panic!("Expected a DeliveryStatus, got {:?}", message.message_type()),
+        };
+        assert!(pools.handle_delivery_status(&status, now));
+        assert!(!pools.handle_delivery_status(&status, now));
+
+        // Two failures in a row and it's gone
+        let mut time = now.millis();
+        for _ in 0..2 {
+            time += TEST_INTERVAL;
+            assert_eq!(1, pools.tests(Date::from_millis(time)).len());
+            pools.maintain(Date::from_millis(time + TEST_TIMEOUT));
+        }
+        let remaining = pools.select_tunnel(&PoolId::Exploratory, Direction::Outbound);
+        assert!(remaining.is_none_or(|tunnel| tunnel.id != outbound.id));
+    }
+
+    #[test]
+    fn test_clients() {
+        let pools = TunnelPools::new(us(),
+                                     settings(0, 1),
+                                     settings(0, 1),
+                                     Box::new(network(0, RouterState::NETDB_READY)),
+                                     running_lifecycle(RouterState::NETDB_READY));
+        let client = PoolId::Client(us());
+        pools.add_client(us(), settings(0, 3), settings(0, 2));
+        pools.maintain(Date::now());
+        assert_eq!(3, pools.tunnel_count(&client, Direction::Inbound));
+        assert_eq!(2, pools.tunnel_count(&client, Direction::Outbound));
+        assert_eq!(1, pools.tunnel_count(&PoolId::Exploratory, Direction::Inbound));
+
+        pools.remove_client(&us());
+        assert_eq!(0, pools.tunnel_count(&client, Direction::Inbound));
+        assert!(pools.select_tunnel(&client, Direction::Outbound).is_none());
+    }
+}
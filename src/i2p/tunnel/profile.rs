@@ -0,0 +1,605 @@
+//! What we've learned about other routers from building tunnels through
+//! them, and how we use it to choose hops.
+//!
+//! Every router we ask to join a tunnel gets a profile: how often it
+//! accepted, turned us down or never answered, how long replies took, and
+//! the best throughput we've seen through it. The counts are halved every
+//! hour, so that old behaviour fades. The profiles are sorted into tiers:
+//! routers with more capacity than most are high capacity, the quickest of
+//! those are fast, routers that keep letting us down are failing, and the
+//! rest are standard. Client tunnels come from the fast tiers first, and
+//! exploratory tunnels from the standard tier, so we keep learning about
+//! routers we don't know yet. No tunnel has two routers in the same /16 or
+//! the same family, or shares either with us.
+//!
+//! Profiles are kept under the router directory, in `peerProfiles`, so they
+//! survive a restart.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use i2p::data::crypto::Hash;
+use i2p::data::date::Date;
+use i2p::data::encoding::{base64_decode, base64_encode};
+use i2p::data::router_info::RouterInfo;
+use i2p::error::Error;
+use i2p::fs::hashed_storage::{HashedStorage, Storable};
+use i2p::tunnel::build::REPLY_ACCEPT;
+use i2p::tunnel::pool::{PeerSelector, PoolId};
+use rand::{thread_rng, Rng};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+const PROFILE_DIRECTORY: &str = "peerProfiles";
+
+const PROFILE_KIND: &str = "profile";
+
+/// How often the counts are halved, in milliseconds
+const DECAY_INTERVAL: u64 = 60 * 60 * 1000;
+
+/// Profiles of routers we haven't built through in this long are dropped
+const PROFILE_EXPIRATION: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/// How much each new build reply moves the average latency
+const LATENCY_WEIGHT: f64 = 0.25;
+
+/// Routers aren't failing until they've let us down this many times
+const MIN_FAILURES: u32 = 5;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub accepted: u32,
+    pub rejected: u32,
+    pub timed_out: u32,
+    /// The moving average of how long builds through the router took, in
+    /// milliseconds. Zero until there's been a reply.
+    pub latency: u32,
+    /// The most we've seen a tunnel through the router carry, in bytes a
+    /// second
+    pub throughput: u32,
+    pub last_updated: Date,
+}
+
+impl Profile {
+    /// Roughly how many tunnels we can expect the router to take: one for
+    /// each it accepted, less half for each it turned down and one for each
+    /// it never answered
+    pub fn capacity(&self) -> f64 {
+        self.accepted as f64 - self.rejected as f64 / 2.0 - self.timed_out as f64
+    }
+
+    /// Whether the router turns down or ignores most of what we ask it
+    pub fn is_failing(&self) -> bool {
+        let failures = self.rejected + self.timed_out;
+        failures >= MIN_FAILURES && self.accepted * 4 < failures
+    }
+
+    fn decay(&mut self) {
+        self.accepted /= 2;
+        self.rejected /= 2;
+        self.timed_out /= 2;
+    }
+}
+
+impl Storable for Profile {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<usize, Error> {
+        writer.write_u32::<BigEndian>(self.accepted)?;
+        writer.write_u32::<BigEndian>(self.rejected)?;
+        writer.write_u32::<BigEndian>(self.timed_out)?;
+        writer.write_u32::<BigEndian>(self.latency)?;
+        writer.write_u32::<BigEndian>(self.throughput)?;
+
+        Ok(20 + self.last_updated.serialize(writer)?)
+    }
+
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Profile, Error> {
+        Ok(Profile {
+            accepted: reader.read_u32::<BigEndian>()?,
+            rejected: reader.read_u32::<BigEndian>()?,
+            timed_out: reader.read_u32::<BigEndian>()?,
+            latency: reader.read_u32::<BigEndian>()?,
+            throughput: reader.read_u32::<BigEndian>()?,
+            last_updated: Date::deserialize(reader)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tier {
+    Fast,
+    HighCapacity,
+    Standard,
+    Failing,
+}
+
+fn storage_key(hash: &Hash) -> String {
+    let Hash::SHA256(ref data) = *hash;
+    base64_encode(data)
+}
+
+struct State {
+    profiles: HashMap<Hash, Profile>,
+    /// Routers without a tier are standard
+    tiers: HashMap<Hash, Tier>,
+    /// Changed since the last save
+    dirty: HashSet<Hash>,
+    last_decay: Date,
+}
+
+/// Everyone's profiles, and the tiers they're in
+pub struct Profiles {
+    storage: HashedStorage<Profile>,
+    state: RwLock<State>,
+}
+
+impl Profiles {
+    pub fn new(router_dir: &Path) -> Result<Profiles, Error> {
+        Ok(Profiles {
+            storage: HashedStorage::new(router_dir, PROFILE_DIRECTORY, PROFILE_KIND, false)?,
+            state: RwLock::new(State {
+                profiles: HashMap::new(),
+                tiers: HashMap::new(),
+                dirty: HashSet::new(),
+                last_decay: Date::now(),
+            }),
+        })
+    }
+
+    /// Reads in the stored profiles, deleting any that are too old or can't
+    /// be read, and sorts them into tiers. Returns how many there were.
+    pub fn load(&self, now: Date) -> Result<usize, Error> {
+        let mut discarded: Vec<String> = Vec::new();
+        {
+            let mut state = self.state.write().unwrap();
+            for result in self.storage.iter() {
+                let (key, profile) = match result {
+                    Ok(entry) => entry,
+                    Err(error) => {
+                        warn!("Profiles: skipping unreadable profile: {}", error);
+                        continue;
+                    }
+                };
+                let hash = match base64_decode(&key) {
+                    Ok(data) if data.len() == 32 => Hash::SHA256(data.into_boxed_slice()),
+                    _ => {
+                        discarded.push(key);
+                        continue;
+                    }
+                };
+                if profile.last_updated.millis() + PROFILE_EXPIRATION <= now.millis() {
+                    discarded.push(key);
+                    continue;
+                }
+                state.profiles.insert(hash, profile);
+            }
+        }
+        for key in discarded {
+            self.storage.remove(&key)?;
+        }
+        self.reorganize(now);
+
+        Ok(self.state.read().unwrap().profiles.len())
+    }
+
+    /// Writes out the profiles that have changed, and deletes the ones that
+    /// are too old
+    pub fn save(&self, now: Date) -> Result<(), Error> {
+        let (changed, expired) = {
+            let mut state = self.state.write().unwrap();
+            let expired: Vec<Hash> = state.profiles
+                .iter()
+                .filter(|&(_, profile)| {
+                    profile.last_updated.millis() + PROFILE_EXPIRATION <= now.millis()
+                })
+                .map(|(hash, _)| hash.clone())
+                .collect();
+            for hash in &expired {
+                state.profiles.remove(hash);
+                state.tiers.remove(hash);
+            }
+            let dirty: Vec<Hash> = state.dirty.drain().collect();
+            let changed: Vec<(Hash, Profile)> = dirty.into_iter()
+                .filter_map(|hash| {
+                    state.profiles.get(&hash).cloned().map(|profile| (hash, profile))
+                })
+                .collect();
+            (changed, expired)
+        };
+
+        for (hash, profile) in changed {
+            self.storage.store(&storage_key(&hash), &profile)?;
+        }
+        for hash in expired {
+            self.storage.remove(&storage_key(&hash))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn profile(&self, hash: &Hash) -> Option<Profile> {
+        self.state.read().unwrap().profiles.get(hash).cloned()
+    }
+
+    pub fn tier(&self, hash: &Hash) -> Tier {
+        self.state.read().unwrap().tiers.get(hash).cloned().unwrap_or(Tier::Standard)
+    }
+
+    fn update<F>(&self, hash: &Hash, now: Date, update: F)
+        where F: FnOnce(&mut Profile)
+    {
+        let mut state = self.state.write().unwrap();
+        {
+            let profile = state.profiles.entry(hash.clone()).or_default();
+            update(profile);
+            profile.last_updated = now;
+        }
+        state.dirty.insert(hash.clone());
+    }
+
+    /// Records a router's answer to a build request, and how long the
+    /// build took, in milliseconds
+    pub fn build_reply(&self, hash: &Hash, accepted: bool, latency: u64, now: Date) {
+        self.update(hash, now, |profile| {
+            if !accepted {
+                profile.rejected += 1;
+                return;
+            }
+            profile.accepted += 1;
+            profile.latency = if profile.latency == 0 {
+                latency as u32
+            } else {
+                (profile.latency as f64 * (1.0 - LATENCY_WEIGHT) +
+                 latency as f64 * LATENCY_WEIGHT) as u32
+            };
+        });
+    }
+
+    pub fn build_timed_out(&self, hash: &Hash, now: Date) {
+        self.update(hash, now, |profile| profile.timed_out += 1);
+    }
+
+    /// Records how much a tunnel through the router carried, in bytes, over
+    /// its lifetime, in milliseconds
+    pub fn tunnel_throughput(&self, hash: &Hash, bytes: u64, lifetime: u64, now: Date) {
+        let throughput = (bytes * 1000 / lifetime.max(1)) as u32;
+        self.update(hash, now, |profile| {
+            profile.throughput = profile.throughput.max(throughput);
+        });
+    }
+
+    /// Halves the counts if it's time, and sorts the routers into tiers
+    /// again
+    pub fn reorganize(&self, now: Date) {
+        let mut state = self.state.write().unwrap();
+        if state.last_decay.millis() + DECAY_INTERVAL <= now.millis() {
+            for profile in state.profiles.values_mut() {
+                profile.decay();
+            }
+            let hashes: Vec<Hash> = state.profiles.keys().cloned().collect();
+            state.dirty.extend(hashes);
+            state.last_decay = now;
+        }
+
+        let mut capacities: Vec<f64> = state.profiles
+            .values()
+            .filter(|profile| !profile.is_failing())
+            .map(Profile::capacity)
+            .collect();
+        capacities.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median_capacity = capacities.get(capacities.len() / 2).cloned().unwrap_or(0.0);
+        let high_capacity = |profile: &Profile| {
+            !profile.is_failing() && profile.capacity() > 0.0 &&
+            profile.capacity() >= median_capacity
+        };
+
+        let mut speeds: Vec<u32> = state.profiles
+            .values()
+            .filter(|profile| high_capacity(profile))
+            .map(|profile| profile.throughput)
+            .collect();
+        speeds.sort();
+        let median_speed = speeds.get(speeds.len() / 2).cloned().unwrap_or(0);
+
+        let tiers: HashMap<Hash, Tier> = state.profiles
+            .iter()
+            .map(|(hash, profile)| {
+                let tier = if profile.is_failing() {
+                    Tier::Failing
+                } else if !high_capacity(profile) {
+                    Tier::Standard
+                } else if profile.throughput > 0 && profile.throughput >= median_speed {
+                    Tier::Fast
+                } else {
+                    Tier::HighCapacity
+                };
+                (hash.clone(), tier)
+            })
+            .collect();
+        state.tiers = tiers;
+    }
+}
+
+/// The networks and families routers already in a tunnel are in
+#[derive(Default)]
+struct Neighbourhood {
+    subnets: HashSet<Vec<u8>>,
+    families: HashSet<String>,
+}
+
+/// Each address's /16, or /32 for IPv6, with the IP version first
+fn subnets(router_info: &RouterInfo) -> Vec<Vec<u8>> {
+    router_info.addresses()
+        .iter()
+        .filter_map(|address| address.options.get("host"))
+        .filter_map(|host| host.parse::<IpAddr>().ok())
+        .map(|ip| match ip {
+            IpAddr::V4(ip) => vec![4, ip.octets()[0], ip.octets()[1]],
+            IpAddr::V6(ip) => {
+                let octets = ip.octets();
+                vec![6, octets[0], octets[1], octets[2], octets[3]]
+            }
+        })
+        .collect()
+}
+
+impl Neighbourhood {
+    fn contains(&self, router_info: &RouterInfo) -> bool {
+        subnets(router_info).iter().any(|subnet| self.subnets.contains(subnet)) ||
+        router_info.options().get("family").is_some_and(|family| self.families.contains(family))
+    }
+
+    fn add(&mut self, router_info: &RouterInfo) {
+        self.subnets.extend(subnets(router_info));
+        if let Some(family) = router_info.options().get("family") {
+            self.families.insert(family.clone());
+        }
+    }
+}
+
+/// Chooses hops by tier, and keeps their profiles up to date
+pub struct TieredPeers {
+    profiles: Arc<Profiles>,
+    ident: Hash,
+    /// Ours, once it's published
+    router_info: Arc<RwLock<Option<RouterInfo>>>,
+    /// The routers there are to choose from
+    candidates: Box<dyn Fn() -> Vec<Arc<RouterInfo>> + Send + Sync>,
+}
+
+impl TieredPeers {
+    pub fn new(profiles: Arc<Profiles>,
+               ident: Hash,
+               router_info: Arc<RwLock<Option<RouterInfo>>>,
+               candidates: Box<dyn Fn() -> Vec<Arc<RouterInfo>> + Send + Sync>)
+               -> TieredPeers {
+        TieredPeers {
+            profiles,
+            ident,
+            router_info,
+            candidates,
+        }
+    }
+}
+
+impl PeerSelector for TieredPeers {
+    fn select(&self, pool: &PoolId, count: usize) -> Vec<Arc<RouterInfo>> {
+        let order = match *pool {
+            PoolId::Exploratory => [Tier::Standard, Tier::HighCapacity, Tier::Fast],
+            PoolId::Client(_) => [Tier::Fast, Tier::HighCapacity, Tier::Standard],
+        };
+        let mut neighbourhood = Neighbourhood::default();
+        if let Some(ref router_info) = *self.router_info.read().unwrap() {
+            neighbourhood.add(router_info);
+        }
+
+        let mut tiers: Vec<Vec<Arc<RouterInfo>>> = vec![Vec::new(); order.len()];
+        for candidate in (self.candidates)() {
+            let hash = match candidate.hash() {
+                Ok(hash) => hash,
+                Err(_) => continue,
+            };
+            if hash == self.ident {
+                continue;
+            }
+            let tier = self.profiles.tier(&hash);
+            if let Some(index) = order.iter().position(|&t| t == tier) {
+                tiers[index].push(candidate);
+            }
+        }
+
+        let mut selected: Vec<Arc<RouterInfo>> = Vec::with_capacity(count);
+        for mut peers in tiers {
+            thread_rng().shuffle(&mut peers);
+            for peer in peers {
+                if selected.len() == count {
+                    return selected;
+                }
+                if !neighbourhood.contains(&peer) {
+                    neighbourhood.add(&peer);
+                    selected.push(peer);
+                }
+            }
+        }
+
+        selected
+    }
+
+    fn build_reply(&self, peer: &Hash, reply: u8, latency: u64) {
+        self.profiles.build_reply(peer, reply == REPLY_ACCEPT, latency, Date::now());
+    }
+
+    fn build_timed_out(&self, peer: &Hash) {
+        self.profiles.build_timed_out(peer, Date::now());
+    }
+
+    fn tunnel_finished(&self, peer: &Hash, bytes: u64, lifetime: u64) {
+        self.profiles.tunnel_throughput(peer, bytes, lifetime, Date::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use i2p::data::crypto::{PublicKeyType, SigningPublicKeyType};
+    use i2p::data::mapping::Mapping;
+    use i2p::data::private_keys::PrivateKeys;
+    use i2p::data::router_info::{RouterAddress, SupportedTransports};
+    use i2p::test_util::random_hash;
+    use super::*;
+    use tempdir::TempDir;
+
+    fn router(host: &str, family: Option<&str>) -> Arc<RouterInfo> {
+        let keys = PrivateKeys::generate(&PublicKeyType::ElGamal,
+                                         &SigningPublicKeyType::EdDSA_SHA512_Ed25519)
+            .unwrap();
+        let mut address_options = Mapping::new();
+        address_options.insert("host", host);
+        let address = RouterAddress {
+            cost: 10,
+            expiration: None,
+            transport_style: SupportedTransports::NTCPV4,
+            options: address_options,
+        };
+        let mut options = Mapping::new();
+        options.insert("caps", "R");
+        if let Some(family) = family {
+            options.insert("family", family);
+        }
+        Arc::new(RouterInfo::new(&keys, Date::now(), vec![address], options).unwrap())
+    }
+
+    fn profiles(data_dir: &TempDir) -> Arc<Profiles> {
+        Arc::new(Profiles::new(data_dir.path()).unwrap())
+    }
+
+    #[test]
+    fn test_profile() {
+        let profile = Profile {
+            accepted: 10,
+            rejected: 4,
+            timed_out: 1,
+            latency: 800,
+            throughput: 5000,
+            last_updated: Date::from_millis(1234),
+        };
+        assert_eq!(7.0, profile.capacity());
+        assert!(!profile.is_failing());
+
+        let mut data: Vec<u8> = Vec::new();
+        assert_eq!(28, profile.serialize(&mut data).unwrap());
+        assert_eq!(profile, Profile::deserialize(&mut &data[..]).unwrap());
+
+        let failing = Profile {
+            accepted: 1,
+            rejected: 3,
+            timed_out: 2,
+            ..Profile::default()
+        };
+        assert!(failing.is_failing());
+    }
+
+    #[test]
+    fn test_tiers() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let profiles = profiles(&data_dir);
+        let now = Date::now();
+        let (fast, high_capacity, standard, failing, unknown) =
+            (random_hash(), random_hash(), random_hash(), random_hash(), random_hash());
+        for _ in 0..10 {
+            profiles.build_reply(&fast, true, 500, now);
+            profiles.build_reply(&high_capacity, true, 2000, now);
+        }
+        profiles.tunnel_throughput(&fast, 10 * 1024 * 1024, 600 * 1000, now);
+        profiles.tunnel_throughput(&high_capacity, 1024, 600 * 1000, now);
+        profiles.build_reply(&standard, true, 1000, now);
+        for _ in 0..5 {
+            profiles.build_reply(&failing, false, 0, now);
+            profiles.build_timed_out(&failing, now);
+        }
+        profiles.reorganize(now);
+
+        assert_eq!(Tier::Fast, profiles.tier(&fast));
+        assert_eq!(Tier::HighCapacity, profiles.tier(&high_capacity));
+        assert_eq!(Tier::Standard, profiles.tier(&standard));
+        assert_eq!(Tier::Failing, profiles.tier(&failing));
+        assert_eq!(Tier::Standard, profiles.tier(&unknown));
+        assert_eq!(500, profiles.profile(&fast).unwrap().latency);
+
+        // An hour on, the counts have halved
+        profiles.reorganize(Date::from_millis(now.millis() + DECAY_INTERVAL + 1000));
+        assert_eq!(5, profiles.profile(&fast).unwrap().accepted);
+        assert_eq!(2, profiles.profile(&failing).unwrap().timed_out);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let now = Date::now();
+        let (kept, old) = (random_hash(), random_hash());
+        {
+            let profiles = profiles(&data_dir);
+            profiles.build_reply(&kept, true, 700, now);
+            profiles.build_timed_out(&old, Date::from_millis(now.millis() - PROFILE_EXPIRATION));
+            profiles.save(Date::from_millis(now.millis() - 1000)).unwrap();
+        }
+        assert!(data_dir.path().join(PROFILE_DIRECTORY).is_dir());
+
+        let profiles = profiles(&data_dir);
+        assert_eq!(1, profiles.load(now).unwrap());
+        assert_eq!(700, profiles.profile(&kept).unwrap().latency);
+        assert!(profiles.profile(&old).is_none());
+        assert!(!profiles.storage.contains(&storage_key(&old)).unwrap());
+    }
+
+    #[test]
+    fn test_selection() {
+        let data_dir = TempDir::new("i2pd-test").unwrap();
+        let profiles = profiles(&data_dir);
+        let us = router("10.0.0.1", Some("example"));
+        let candidates = vec![us.clone(),
+                              router("10.0.1.1", None),
+                              router("10.1.0.1", Some("example")),
+                              router("10.2.0.1", Some("other")),
+                              router("10.2.0.2", None),
+                              router("10.3.0.1", None),
+                              router("10.4.0.1", None)];
+        let (failing, fast) = (candidates[5].clone(), candidates[6].clone());
+        let now = Date::now();
+        for _ in 0..5 {
+            profiles.build_timed_out(&failing.hash().unwrap(), now);
+        }
+        profiles.build_reply(&fast.hash().unwrap(), true, 100, now);
+        profiles.tunnel_throughput(&fast.hash().unwrap(), 1024 * 1024, 60 * 1000, now);
+        profiles.reorganize(now);
+        assert_eq!(Tier::Fast, profiles.tier(&fast.hash().unwrap()));
+
+        let available = candidates.clone();
+        let selector = TieredPeers::new(profiles.clone(),
+                                        us.hash().unwrap(),
+                                        Arc::new(RwLock::new(Some((*us).clone()))),
+                                        Box::new(move || available.clone()));
+        for _ in 0..20 {
+            // Not us, nothing in our /16 or family, only one of 10.2/16, and
+            // not the failing router
+            let hops = selector.select(&PoolId::Exploratory, 5);
+            assert_eq!(2, hops.len());
+            let hashes: Vec<Hash> = hops.iter().map(|hop| hop.hash().unwrap()).collect();
+            assert!(hashes.contains(&fast.hash().unwrap()));
+            assert!(!hashes.contains(&failing.hash().unwrap()));
+            assert!(hops.iter().all(|hop| subnets(hop)[0][1..3] == [10, 2] ||
+                                     hop.hash().unwrap() == fast.hash().unwrap()));
+
+            // Client tunnels start with the fast tier
+            let hops = selector.select(&PoolId::Client(random_hash()), 1);
+            assert_eq!(fast.hash().unwrap(), hops[0].hash().unwrap());
+        }
+
+        // What the pools tell the selector ends up in the profiles
+        let peer = candidates[1].hash().unwrap();
+        selector.build_reply(&peer, REPLY_ACCEPT, 300);
+        selector.build_reply(&peer, REPLY_ACCEPT + 30, 300);
+        selector.build_timed_out(&peer);
+        let profile = profiles.profile(&peer).unwrap();
+        assert_eq!((1, 1, 1), (profile.accepted, profile.rejected, profile.timed_out));
+    }
+}
@@ -0,0 +1,24 @@
+#[cfg(test)]
+extern crate base64;
+extern crate byteorder;
+#[macro_use]
+extern crate clap;
+extern crate flate2;
+extern crate gcrypt;
+extern crate libc;
+extern crate linked_hash_map;
+#[macro_use]
+extern crate log;
+extern crate log4rs;
+extern crate native_tls;
+extern crate rand;
+extern crate serde;
+extern crate serde_yaml;
+#[cfg(test)]
+extern crate tempdir;
+extern crate time;
+extern crate vec_map;
+extern crate walkdir;
+extern crate yaml_rust;
+
+pub mod i2p;
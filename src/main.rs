@@ -1,38 +1,23 @@
-#![feature(box_syntax)]
-#![feature(plugin, custom_derive)]
-
-#[cfg(test)]
-extern crate base64;
-extern crate byteorder;
-#[macro_use]
-extern crate clap;
-extern crate gcrypt;
+extern crate i2pd_rs;
 extern crate libc;
-extern crate linked_hash_map;
 #[macro_use]
 extern crate log;
-extern crate log4rs;
-#[cfg(test)]
-extern crate mockers;
-extern crate rand;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-extern crate serde_yaml;
-#[cfg(test)]
-extern crate tempdir;
-extern crate time;
-extern crate vec_map;
-extern crate walkdir;
-extern crate yaml_rust;
-
-mod i2p;
-
-use i2p::config::Config;
-use i2p::logging;
-use i2p::router::Router;
-use std::error::Error;
+
+use i2pd_rs::i2p::config::Config;
+use i2pd_rs::i2p::logging;
+use i2pd_rs::i2p::lifecycle::RouterState;
+use i2pd_rs::i2p::router::Router;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Set when we get SIGINT or SIGTERM
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_signal: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
 
 fn main() {
     let config: Config = match Config::new() {
@@ -51,6 +36,29 @@ fn main() {
     if let Err(error) = logging::initialize(&config_dir) {
         panic!("Error initializing logging: {}", error);
     }
-    
-    info!("{:?}", Router::new(config));
+
+    let mut router = match Router::new(config) {
+        Ok(router) => router,
+        Err(error) => {
+            error!("Error creating router: {}", error);
+            exit(1);
+        }
+    };
+
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+
+    if let Err(error) = router.run() {
+        error!("Error starting router: {}", error);
+        exit(1);
+    }
+
+    while !STOP_REQUESTED.load(Ordering::SeqCst) && router.state() != RouterState::STOPPED {
+        thread::sleep(Duration::from_millis(500));
+    }
+    info!("Shutting down");
+    router.shutdown();
 }